@@ -0,0 +1,23 @@
+//! Measures the bytes read per second from bitcoind's `blk*.dat` files (during the initial sync):
+//! ```
+//! cargo bench --features bench --bench blocks_dir
+//! ```
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+fn read_blocks(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let blocks = electrs::bench_blocks(dir.path(), 256);
+    let size = electrs::bench_read_blocks(&blocks);
+
+    let mut group = c.benchmark_group("blocks_dir");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(size as u64));
+    group.bench_function("read_256MB", |b| {
+        b.iter(|| electrs::bench_read_blocks(&blocks))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, read_blocks);
+criterion_main!(benches);
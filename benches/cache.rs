@@ -0,0 +1,26 @@
+//! Measures the transaction cache lookups per second from 8 reader threads, using the sharded
+//! layout and the previous single-lock one:
+//! ```
+//! cargo bench --features bench --bench cache
+//! ```
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+const READERS: usize = 8;
+const LOOKUPS: usize = 100_000;
+
+fn cache_readers(c: &mut Criterion) {
+    let cache = electrs::bench_cache(10_000);
+
+    let mut group = c.benchmark_group("cache_readers");
+    group.throughput(Throughput::Elements((READERS * LOOKUPS) as u64));
+    for (name, sharded) in [("single_lock", false), ("sharded", true)] {
+        group.bench_function(name, |b| {
+            b.iter(|| electrs::bench_cache_readers(&cache, READERS, LOOKUPS, sharded))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, cache_readers);
+criterion_main!(benches);
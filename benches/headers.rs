@@ -0,0 +1,26 @@
+//! Measures the headers serialized per second for `blockchain.block.headers` responses (of 2016
+//! headers each):
+//! ```
+//! cargo bench --features bench --bench headers
+//! ```
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+fn headers_hex(c: &mut Criterion) {
+    let headers = electrs::bench_headers(2016 * 4);
+
+    let mut group = c.benchmark_group("headers_hex");
+    group.throughput(Throughput::Elements(2016));
+    group.bench_function("2016_headers", |b| {
+        let mut start_height = 0;
+        b.iter(|| {
+            // different heights, so each response's headers are read again
+            start_height = (start_height + 2016) % (2016 * 3);
+            electrs::bench_headers_hex(&headers, start_height)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, headers_hex);
+criterion_main!(benches);
@@ -0,0 +1,26 @@
+//! Measures the rows built per second while indexing a mainnet block:
+//! ```
+//! cargo bench --features bench --bench index_block
+//! ```
+
+use bitcoin_test_data::blocks::mainnet_702861;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+fn index_block(c: &mut Criterion) {
+    let block = mainnet_702861().to_vec();
+    let rows = electrs::bench_index_block(block.clone());
+
+    let mut group = c.benchmark_group("index_block");
+    group.throughput(Throughput::Elements(rows as u64));
+    group.bench_function("mainnet_702861", |b| {
+        b.iter_batched(
+            || block.clone(),
+            electrs::bench_index_block,
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, index_block);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+//! Measures the time to find the statuses affected by a new block, which shouldn't depend on the
+//! total number of subscriptions:
+//! ```
+//! cargo bench --features bench --bench subscriptions
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+fn affected(c: &mut Criterion) {
+    let mut group = c.benchmark_group("affected");
+    for subscribed in [1_000, 100_000] {
+        let subs = electrs::bench_subscriptions(subscribed);
+        // the block touches 100 subscribed scripthashes (and their outpoints)
+        assert_eq!(electrs::bench_affected(&subs), 100);
+        group.bench_function(BenchmarkId::from_parameter(subscribed), |b| {
+            b.iter(|| electrs::bench_affected(&subs))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, affected);
+criterion_main!(benches);
@@ -1,3 +1,37 @@
+use std::process::Command;
+
 fn main() {
-    configure_me_codegen::build_script_auto().unwrap_or_else(|error| error.report_and_exit())
+    configure_me_codegen::build_script_auto().unwrap_or_else(|error| error.report_and_exit());
+
+    // Build information, exported via metrics and `server.version` (e.g. for fleet management)
+    let commit = git_commit().unwrap_or_else(|| "unknown".to_owned()); // e.g. building from a source tarball
+    println!("cargo:rustc-env=ELECTRS_GIT_COMMIT={}", commit);
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc_version =
+        output(Command::new(rustc).arg("--version")).unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=ELECTRS_RUSTC_VERSION={}", rustc_version);
+}
+
+/// Abbreviated commit hash, or `None` outside a git repository (or if git is not installed)
+fn git_commit() -> Option<String> {
+    let git_dir = output(Command::new("git").args(["rev-parse", "--git-dir"]))?;
+    // rerun after a checkout or a commit (the index is updated by both)
+    for name in ["HEAD", "index"] {
+        let path = std::path::Path::new(&git_dir).join(name);
+        if path.exists() {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+    // `--exclude=*` ignores the tags, so only the commit hash is returned
+    output(Command::new("git").args(["describe", "--always", "--abbrev=12", "--exclude=*"]))
+}
+
+fn output(command: &mut Command) -> Option<String> {
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.trim().to_owned()).filter(|s| !s.is_empty())
 }
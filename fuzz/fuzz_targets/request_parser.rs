@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary bytes must result in an error response (or be ignored), never in a panic.
+fuzz_target!(|data: &[u8]| {
+    electrs::fuzz_request(data);
+});
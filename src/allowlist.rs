@@ -0,0 +1,178 @@
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::{
+    custom_chain::AddressDecoder,
+    metrics::{Counter, Metrics},
+    types::ScriptHash,
+};
+
+/// Returned for scripthashes outside `scripthash_allowlist_file` (see `RpcError::NotServed`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct NotServed;
+
+impl std::fmt::Display for NotServed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "scripthash not served")
+    }
+}
+
+impl std::error::Error for NotServed {}
+
+/// The scripthashes served in personal-server privacy mode (see `scripthash_allowlist_file`),
+/// re-read on `SIGHUP`. The index is not affected (all scripthashes are still indexed).
+pub(crate) struct Allowlist {
+    file: Option<PathBuf>,
+    addresses: AddressDecoder,
+    scripthashes: RwLock<HashSet<ScriptHash>>,
+    refused: Counter,
+}
+
+/// Parse a hex scripthash or an address per line (skipping empty lines and `#` comments).
+pub(crate) fn parse(contents: &str, addresses: &AddressDecoder) -> Result<HashSet<ScriptHash>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            if let Ok(scripthash) = ScriptHash::from_str(line) {
+                return Ok(scripthash);
+            }
+            let script = addresses
+                .script_pubkey(line)
+                .with_context(|| format!("line {}: invalid scripthash or address", n))?;
+            Ok(ScriptHash::new(&script))
+        })
+        .collect()
+}
+
+impl Allowlist {
+    /// Fails if `file` can't be loaded (so electrs doesn't start without the expected list).
+    pub(crate) fn new(
+        file: Option<PathBuf>,
+        addresses: AddressDecoder,
+        metrics: &Metrics,
+    ) -> Result<Self> {
+        let allowlist = Self {
+            file,
+            addresses,
+            scripthashes: RwLock::default(),
+            refused: metrics.counter(
+                "rpc_not_served",
+                "# of requests refused by `scripthash_allowlist_file`",
+                "method",
+            ),
+        };
+        allowlist.load()?;
+        Ok(allowlist)
+    }
+
+    fn load(&self) -> Result<()> {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let scripthashes = parse(&contents, &self.addresses)
+            .with_context(|| format!("invalid scripthash_allowlist_file {}", path.display()))?;
+        info!(
+            "serving only {} scripthashes (from {})",
+            scripthashes.len(),
+            path.display()
+        );
+        *self.scripthashes.write() = scripthashes;
+        Ok(())
+    }
+
+    /// Re-read the list (e.g. on `SIGHUP`). On failure, the previous list is kept.
+    pub(crate) fn reload(&self) {
+        if let Err(e) = self.load() {
+            warn!("keeping the previous scripthash allowlist: {:#}", e);
+        }
+    }
+
+    pub(crate) fn allows(&self, scripthash: &ScriptHash) -> bool {
+        self.file.is_none() || self.scripthashes.read().contains(scripthash)
+    }
+
+    /// Fails with `NotServed` if `scripthash` is not allowed (counted per RPC method).
+    pub(crate) fn check(&self, method: &str, scripthash: &ScriptHash) -> Result<()> {
+        if self.allows(scripthash) {
+            return Ok(());
+        }
+        self.refused.inc(method);
+        Err(NotServed.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Allowlist, NotServed};
+    use crate::{custom_chain::AddressDecoder, metrics::Metrics, types::ScriptHash};
+
+    use bitcoin::{Address, Network};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_allowlist() {
+        let metrics = || Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let regtest = || AddressDecoder::Network(Network::Regtest);
+        let address = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let by_address = ScriptHash::new(
+            &Address::from_str(address)
+                .unwrap()
+                .assume_checked()
+                .script_pubkey(),
+        );
+        let by_hex = ScriptHash::from_str(&"11".repeat(32)).unwrap();
+        let other = ScriptHash::from_str(&"22".repeat(32)).unwrap();
+
+        // all scripthashes are served by default
+        let allowlist = Allowlist::new(None, regtest(), &metrics()).unwrap();
+        assert!(allowlist.check("get_history", &other).is_ok());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("allowlist.txt");
+        let contents = format!("# my wallet\n{}\n\n  {}  \n", by_hex, address);
+        std::fs::write(&path, contents).unwrap();
+        let allowlist = Allowlist::new(Some(path.clone()), regtest(), &metrics()).unwrap();
+        assert!(allowlist.check("get_history", &by_hex).is_ok());
+        assert!(allowlist.check("get_history", &by_address).is_ok());
+        let err = allowlist.check("subscribe", &other).unwrap_err();
+        assert_eq!(err.downcast_ref::<NotServed>(), Some(&NotServed));
+        #[cfg(feature = "metrics")]
+        assert_eq!(allowlist.refused.get("subscribe"), 1);
+
+        // an invalid list is refused on startup, and ignored on reload
+        std::fs::write(&path, format!("{}\nnot-an-address\n", other)).unwrap();
+        let err = Allowlist::new(Some(path.clone()), regtest(), &metrics()).unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"), "{:#}", err);
+        allowlist.reload();
+        assert!(allowlist.check("get_balance", &by_hex).is_ok());
+
+        // addresses of other networks are refused
+        let mainnet = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        std::fs::write(&path, mainnet).unwrap();
+        assert!(Allowlist::new(Some(path.clone()), regtest(), &metrics()).is_err());
+
+        // a valid list replaces the previous one
+        std::fs::write(&path, format!("{}\n", other)).unwrap();
+        allowlist.reload();
+        assert!(allowlist.check("get_balance", &other).is_ok());
+        assert!(allowlist.check("get_balance", &by_hex).is_err());
+        assert!(allowlist.check("get_balance", &by_address).is_err());
+
+        // a missing file keeps the previous list too
+        std::fs::remove_file(&path).unwrap();
+        allowlist.reload();
+        assert!(allowlist.check("get_balance", &other).is_ok());
+        assert!(allowlist.check("get_balance", &by_hex).is_err());
+    }
+}
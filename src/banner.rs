@@ -0,0 +1,197 @@
+use parking_lot::Mutex;
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Values of the banner's template variables (e.g. `{index_height}`)
+pub(crate) struct Vars<'a> {
+    pub version: &'a str,
+    pub index_height: usize,
+    pub daemon_version: &'a str,
+    pub connections: usize,
+    pub uptime: Duration,
+    pub tip_age: Duration,
+}
+
+impl Vars<'_> {
+    fn get(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "version" => self.version.to_owned(),
+            "index_height" => self.index_height.to_string(),
+            "daemon_version" => self.daemon_version.to_owned(),
+            "connections" => self.connections.to_string(),
+            "uptime" => format_uptime(self.uptime),
+            "tip_age" => format_uptime(self.tip_age),
+            _ => return None,
+        })
+    }
+}
+
+/// Server banner, optionally loaded from `banner_file` (which is re-read when modified)
+pub(crate) struct Banner {
+    default: String,
+    file: Option<PathBuf>,
+    cache: Mutex<Option<(SystemTime, String)>>, // file's modification time and contents
+    started: Instant,
+}
+
+impl Banner {
+    pub(crate) fn new(default: String, file: Option<PathBuf>) -> Self {
+        Self {
+            default,
+            file,
+            cache: Mutex::new(None),
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Returns the banner, with its template variables expanded.
+    pub(crate) fn get(&self, vars: &Vars) -> String {
+        match self.load() {
+            Some(template) => expand(&template, vars),
+            None => expand(&self.default, vars),
+        }
+    }
+
+    /// Drop the cached banner file contents (e.g. on SIGHUP).
+    pub(crate) fn reload(&self) {
+        *self.cache.lock() = None;
+    }
+
+    // Returns `None` if the banner file is not configured or can't be read
+    fn load(&self) -> Option<String> {
+        let path = self.file.as_ref()?;
+        let mut cache = self.cache.lock();
+        let mtime = match fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(mtime) => mtime,
+            Err(e) => {
+                debug!(
+                    "using default banner: {} is not available: {}",
+                    path.display(),
+                    e
+                );
+                *cache = None;
+                return None;
+            }
+        };
+        if let Some((cached_mtime, contents)) = cache.as_ref() {
+            if *cached_mtime == mtime {
+                return Some(contents.clone());
+            }
+        }
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                *cache = Some((mtime, contents.clone()));
+                Some(contents)
+            }
+            Err(e) => {
+                warn!(
+                    "using default banner: failed to read {}: {}",
+                    path.display(),
+                    e
+                );
+                *cache = None;
+                None
+            }
+        }
+    }
+}
+
+/// Replace known `{name}` variables - the rest of the template is left as is.
+fn expand(template: &str, vars: &Vars) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest
+            .find('}')
+            .and_then(|end| Some((vars.get(&rest[1..end])?, end)));
+        match value {
+            Some((value, end)) => {
+                result.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let minutes = uptime.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else {
+        format!("{}h {}m", hours, minutes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Banner, Vars};
+    use std::time::Duration;
+
+    fn vars() -> Vars<'static> {
+        Vars {
+            version: "0.11.0",
+            index_height: 840000,
+            daemon_version: "/Satoshi:27.0.0/",
+            connections: 12,
+            uptime: Duration::from_secs(((2 * 24 + 3) * 60 + 4) * 60 + 5),
+            tip_age: Duration::from_secs(12 * 60),
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let banner = Banner::new(
+            "electrs {version} at {index_height}, {daemon_version} ({connections} clients, up {uptime})"
+                .to_owned(),
+            None,
+        );
+        assert_eq!(
+            banner.get(&vars()),
+            "electrs 0.11.0 at 840000, /Satoshi:27.0.0/ (12 clients, up 2d 3h 4m)"
+        );
+
+        // unknown variables and unbalanced braces are left as is
+        let banner = Banner::new("{unknown} {{version}} {version".to_owned(), None);
+        assert_eq!(banner.get(&vars()), "{unknown} {0.11.0} {version");
+
+        let banner = Banner::new("last block {tip_age} ago".to_owned(), None);
+        assert_eq!(banner.get(&vars()), "last block 0h 12m ago");
+    }
+
+    #[test]
+    fn test_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("banner.txt");
+        let banner = Banner::new("default {version}".to_owned(), Some(path.clone()));
+
+        // missing file falls back to the default banner
+        assert_eq!(banner.get(&vars()), "default 0.11.0");
+
+        std::fs::write(&path, "maintenance at {index_height}").unwrap();
+        assert_eq!(banner.get(&vars()), "maintenance at 840000");
+
+        // cached contents are dropped on reload
+        banner.reload();
+        assert!(banner.cache.lock().is_none());
+        assert_eq!(banner.get(&vars()), "maintenance at 840000");
+        assert!(banner.cache.lock().is_some());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(banner.get(&vars()), "default 0.11.0");
+    }
+}
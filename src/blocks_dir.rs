@@ -0,0 +1,536 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    block::Header as BlockHeader, consensus::deserialize, hashes::Hash, merkle_tree, p2p::Magic,
+    BlockHash, TxMerkleNode,
+};
+use bitcoin_slices::{bsl, Visit, Visitor};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    chain::Chain,
+    types::{bsl_txid, SerBlock},
+};
+
+/// Blocks this close to the synced tip are fetched from bitcoind, since their files may still be
+/// written (and they are more likely to be reorged).
+pub(crate) const TIP_MARGIN: usize = 100;
+
+const HEADER_SIZE: usize = 80;
+const MAX_BLOCK_SIZE: u32 = 4_000_000; // serialized blocks are limited by the block weight
+
+/// Where a block is stored, found by scanning the `blk*.dat` files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Location {
+    file: usize, // into `BlocksDir::files`
+    offset: u64, // of the serialized block (following its magic and size)
+    size: u32,
+}
+
+/// Reads blocks directly from bitcoind's `blocks` directory (for the initial sync, when running
+/// on the same machine), instead of downloading them via P2P.
+pub(crate) struct BlocksDir {
+    dir: PathBuf,
+    magic: Magic,
+    xor_key: Option<[u8; 8]>, // bitcoind 28.0+ obfuscates its block files (using `xor.dat`)
+    files: Vec<PathBuf>,
+    locations: HashMap<BlockHash, Location>,
+    scanned: bool,
+    scanned_files: usize,        // the earlier files hold only indexed blocks
+    open: Option<(usize, File)>, // the last read file
+    read_blocks: usize,
+}
+
+impl BlocksDir {
+    pub(crate) fn new(dir: PathBuf, magic: Magic) -> Self {
+        Self {
+            dir,
+            magic,
+            xor_key: None,
+            files: vec![],
+            locations: HashMap::new(),
+            scanned: false,
+            scanned_files: 0,
+            open: None,
+            read_blocks: 0,
+        }
+    }
+
+    pub(crate) fn is_scanned(&self) -> bool {
+        self.scanned
+    }
+
+    /// The number of blocks which were read from the files.
+    pub(crate) fn read_blocks(&self) -> usize {
+        self.read_blocks
+    }
+
+    /// Locate the blocks of `headers` above `height` (except the last `TIP_MARGIN` ones).
+    /// Blocks which are not part of `headers` (e.g. stale ones) are skipped, and files whose
+    /// trailing data is truncated or corrupt are scanned until that point.
+    ///
+    /// bitcoind appends the blocks to its files (roughly) in the order of their heights, so the
+    /// files are scanned from the last one, until a file holding only indexed blocks is found
+    /// (e.g. after a restart, only the files written since the last sync are scanned). A block
+    /// which is not located is fetched from bitcoind.
+    pub(crate) fn scan(&mut self, headers: &Chain, height: usize) -> Result<()> {
+        self.scanned = true;
+        let max_height = headers.height().saturating_sub(TIP_MARGIN);
+        if max_height <= height {
+            return Ok(()); // nothing to read
+        }
+        self.xor_key = read_xor_key(&self.dir)?;
+        self.files = list_blk_files(&self.dir)?;
+        info!(
+            "scanning block files at {} for blocks [{}..{}]",
+            self.dir.display(),
+            height + 1,
+            max_height
+        );
+        for (file, path) in self.files.iter().enumerate().rev() {
+            let reader = BufReader::new(
+                File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+            );
+            let mut locations = vec![];
+            let (mut indexed, mut unindexed) = (false, false);
+            let result = scan_file(reader, self.magic, self.xor_key, |hash, offset, size| {
+                if let Some(h) = headers.get_block_height(&hash) {
+                    indexed |= h <= height;
+                    unindexed |= h > height;
+                    if h > height && h <= max_height {
+                        locations.push((hash, Location { file, offset, size }));
+                    }
+                }
+            });
+            if let Err(e) = result {
+                warn!("skipping the rest of {}: {}", path.display(), e);
+            }
+            self.locations.extend(locations);
+            self.scanned_files += 1;
+            if indexed && !unindexed {
+                break;
+            }
+        }
+        info!(
+            "located {} of {} blocks in {} of {} block files",
+            self.locations.len(),
+            max_height - height,
+            self.scanned_files,
+            self.files.len(),
+        );
+        Ok(())
+    }
+
+    /// Read a located block (verifying its hash and merkle root), returning `None` if it should
+    /// be fetched from bitcoind instead.
+    pub(crate) fn read(&mut self, blockhash: BlockHash) -> Option<SerBlock> {
+        let location = *self.locations.get(&blockhash)?;
+        let result = self.read_at(location).and_then(|block| {
+            verify_block(&block, blockhash)?;
+            Ok(block)
+        });
+        match result {
+            Ok(block) => {
+                self.read_blocks += 1;
+                Some(block)
+            }
+            Err(e) => {
+                warn!("invalid block {} at {:?}: {:#}", blockhash, location, e);
+                None
+            }
+        }
+    }
+
+    fn read_at(&mut self, location: Location) -> Result<SerBlock> {
+        let file = match &mut self.open {
+            Some((index, file)) if *index == location.file => file,
+            open => {
+                let path = &self.files[location.file];
+                let file = File::open(path)
+                    .with_context(|| format!("failed to open {}", path.display()))?;
+                &mut open.insert((location.file, file)).1
+            }
+        };
+        let mut block = vec![0u8; location.size as usize];
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.read_exact(&mut block)?;
+        unxor(&mut block, self.xor_key, location.offset);
+        Ok(block)
+    }
+}
+
+fn read_xor_key(dir: &Path) -> Result<Option<[u8; 8]>> {
+    let path = dir.join("xor.dat");
+    let key: [u8; 8] = match fs::read(&path) {
+        Ok(data) => data
+            .try_into()
+            .map_err(|data: Vec<u8>| anyhow!("invalid {} size: {}", path.display(), data.len()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    Ok(Some(key).filter(|key| *key != [0; 8]))
+}
+
+/// The `blkNNNNN.dat` files (ordered by their number)
+fn list_blk_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to list {}", dir.display()))? {
+        let path = entry?.path();
+        let number = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("blk")?.strip_suffix(".dat"))
+            .and_then(|number| number.parse::<u32>().ok());
+        if let Some(number) = number {
+            files.push((number, path));
+        }
+    }
+    files.sort_unstable();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// XOR `data` (read at `offset` of its file) with bitcoind's obfuscation key.
+fn unxor(data: &mut [u8], key: Option<[u8; 8]>, offset: u64) {
+    if let Some(mut key) = key {
+        key.rotate_left((offset % 8) as usize); // aligned with `data`
+        let mut chunks = data.chunks_exact_mut(key.len());
+        let k = u64::from_ne_bytes(key);
+        for chunk in &mut chunks {
+            let value = u64::from_ne_bytes(chunk.try_into().unwrap()) ^ k;
+            chunk.copy_from_slice(&value.to_ne_bytes());
+        }
+        for (byte, k) in chunks.into_remainder().iter_mut().zip(key) {
+            *byte ^= k;
+        }
+    }
+}
+
+fn block_hash(block: &[u8]) -> Option<BlockHash> {
+    let header: BlockHeader = deserialize(block.get(..HEADER_SIZE)?).ok()?;
+    Some(header.block_hash())
+}
+
+/// Collects the block's transaction ids, as merkle tree leaves
+#[derive(Default)]
+struct MerkleLeaves(Vec<TxMerkleNode>);
+
+impl Visitor for MerkleLeaves {
+    fn visit_block_begin(&mut self, total_transactions: usize) {
+        self.0.reserve_exact(total_transactions);
+    }
+
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        self.0
+            .push(TxMerkleNode::from_raw_hash(bsl_txid(tx).to_raw_hash()));
+        ControlFlow::Continue(())
+    }
+}
+
+/// Check the block's hash and merkle root (so a corrupted file is not indexed).
+fn verify_block(block: &[u8], blockhash: BlockHash) -> Result<()> {
+    let header: BlockHeader = deserialize(block.get(..HEADER_SIZE).context("truncated header")?)?;
+    ensure!(
+        header.block_hash() == blockhash,
+        "unexpected header {}",
+        header.block_hash()
+    );
+    let mut leaves = MerkleLeaves::default();
+    let parsed = bsl::Block::visit(block, &mut leaves)
+        .map_err(|e| anyhow!("failed to parse block: {:?}", e))?;
+    ensure!(
+        parsed.remaining().is_empty(),
+        "{} trailing bytes",
+        parsed.remaining().len()
+    );
+    ensure!(
+        merkle_tree::calculate_root(leaves.0.into_iter()) == Some(header.merkle_root),
+        "invalid merkle root"
+    );
+    Ok(())
+}
+
+/// Call `func` with the hash, offset and size of each block in a `blk*.dat` file.
+///
+/// The scan stops at the end of the written data (bitcoind pre-allocates its files using zeroes),
+/// and fails on a truncated or corrupt block (after visiting the preceding ones).
+fn scan_file(
+    mut reader: BufReader<File>,
+    magic: Magic,
+    xor_key: Option<[u8; 8]>,
+    mut func: impl FnMut(BlockHash, u64, u32),
+) -> Result<()> {
+    let file_size = reader.get_ref().metadata()?.len();
+    let mut offset = 0u64;
+    loop {
+        let mut prefix = [0u8; 8 + HEADER_SIZE];
+        let n = read_full(&mut reader, &mut prefix)?;
+        unxor(&mut prefix[..n], xor_key, offset);
+        if n < 8 || prefix[..4] == [0; 4] {
+            return Ok(()); // end of data
+        }
+        ensure!(
+            prefix[..4] == magic.to_bytes(),
+            "unexpected magic {:02x?} at offset {}",
+            &prefix[..4],
+            offset
+        );
+        let size = u32::from_le_bytes(prefix[4..8].try_into().unwrap());
+        let block_offset = offset + 8;
+        ensure!(
+            (HEADER_SIZE as u32..=MAX_BLOCK_SIZE).contains(&size),
+            "invalid block size {} at offset {}",
+            size,
+            offset
+        );
+        ensure!(
+            n == prefix.len() && block_offset + u64::from(size) <= file_size,
+            "truncated block at offset {}",
+            offset
+        );
+        let hash = block_hash(&prefix[8..]).expect("invalid header size");
+        func(hash, block_offset, size);
+        reader.seek_relative(i64::from(size) - HEADER_SIZE as i64)?;
+        offset = block_offset + u64::from(size);
+    }
+}
+
+/// Like `read_exact()`, but returns the number of bytes read before reaching EOF.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(n)
+}
+
+/// Write blocks as bitcoind does (for tests and benchmarks).
+#[cfg(any(test, feature = "bench"))]
+pub(crate) fn write_blk_file(
+    path: &Path,
+    magic: Magic,
+    xor_key: Option<[u8; 8]>,
+    blocks: &[&[u8]],
+) -> io::Result<()> {
+    let mut data = vec![];
+    for block in blocks {
+        data.extend_from_slice(&magic.to_bytes());
+        data.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        data.extend_from_slice(block);
+    }
+    unxor(&mut data, xor_key, 0);
+    fs::write(path, data)
+}
+
+/// A chain of `count` headers following the genesis block, with their serialized blocks (each
+/// one has a coinbase transaction, whose output script takes `size` bytes).
+#[cfg(any(test, feature = "bench"))]
+fn synthetic_blocks(count: usize, salt: u32, size: usize) -> (Chain, Vec<Vec<u8>>) {
+    use crate::chain::NewHeader;
+    use bitcoin::{
+        absolute::LockTime, consensus::serialize, transaction::Version, Amount, Block, Network,
+        OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+    };
+
+    let mut chain = Chain::new(Network::Regtest);
+    let genesis = chain.get_block_header(0).unwrap();
+    let mut headers = vec![];
+    let mut blocks = vec![];
+    for height in 1..=count {
+        let coinbase = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(height.to_le_bytes().to_vec()),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::from_bytes(vec![0x6a; size]),
+            }],
+        };
+        let header = BlockHeader {
+            prev_blockhash: headers.last().map_or(chain.tip(), NewHeader::hash),
+            merkle_root: TxMerkleNode::from_raw_hash(coinbase.compute_txid().to_raw_hash()),
+            time: height as u32 + salt,
+            ..genesis
+        };
+        blocks.push(serialize(&Block {
+            header,
+            txdata: vec![coinbase],
+        }));
+        headers.push(NewHeader::from((header, height)));
+    }
+    chain.update(headers);
+    (chain, blocks)
+}
+
+/// Synthetic `blk*.dat` files, used by `benches/blocks_dir.rs`
+#[cfg(feature = "bench")]
+pub struct BenchBlocks {
+    dir: PathBuf,
+    chain: Chain,
+    count: usize,
+}
+
+/// Write `count` blocks of 1 MB into `dir` (obfuscated, in 128 MB files like bitcoind's), and
+/// `TIP_MARGIN` more blocks (which are not read).
+#[cfg(feature = "bench")]
+pub fn bench_blocks(dir: &Path, count: usize) -> BenchBlocks {
+    let magic = bitcoin::Network::Regtest.magic();
+    let key = [1, 2, 3, 4, 5, 6, 7, 8];
+    fs::write(dir.join("xor.dat"), key).expect("failed to write xor.dat");
+    let (chain, blocks) = synthetic_blocks(count + TIP_MARGIN, 0, 1 << 20);
+    for (i, chunk) in blocks.chunks(128).enumerate() {
+        let chunk: Vec<&[u8]> = chunk.iter().map(Vec::as_slice).collect();
+        let path = dir.join(format!("blk{:05}.dat", i));
+        write_blk_file(&path, magic, Some(key), &chunk).expect("failed to write block file");
+    }
+    BenchBlocks {
+        dir: dir.to_owned(),
+        chain,
+        count,
+    }
+}
+
+/// Scan the files and read their blocks (verifying them), returning the number of read bytes
+#[cfg(feature = "bench")]
+pub fn bench_read_blocks(blocks: &BenchBlocks) -> usize {
+    let mut blocks_dir = BlocksDir::new(blocks.dir.clone(), bitcoin::Network::Regtest.magic());
+    blocks_dir.scan(&blocks.chain, 0).expect("scan failed");
+    (1..=blocks.count)
+        .map(|height| {
+            let blockhash = blocks.chain.get_block_hash(height).unwrap();
+            blocks_dir.read(blockhash).expect("missing block").len()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{synthetic_blocks, write_blk_file, BlocksDir, TIP_MARGIN};
+    use bitcoin::{BlockHash, Network};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    fn hash(block: &[u8]) -> BlockHash {
+        super::block_hash(block).unwrap()
+    }
+
+    #[test]
+    fn test_blocks_dir() {
+        let count = TIP_MARGIN + 20;
+        let (chain, blocks) = synthetic_blocks(count, 0, 1000);
+        let (_, stale) = synthetic_blocks(3, 1_000_000, 1000);
+        let magic = Network::Regtest.magic();
+        let key = [1, 2, 3, 4, 5, 6, 7, 8];
+        let xor_key = Some(key);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("xor.dat"), key).unwrap();
+        std::fs::write(dir.path().join("rev00000.dat"), b"undo data").unwrap();
+
+        // blocks are stored out of order, along with stale ones
+        let files: [Vec<&[u8]>; 4] = [
+            vec![&blocks[1], &blocks[0], &stale[0], &blocks[2]],
+            blocks[3..12].iter().map(Vec::as_slice).collect(),
+            blocks[12..20].iter().map(Vec::as_slice).collect(),
+            blocks[20..].iter().map(Vec::as_slice).collect(),
+        ];
+        for (i, file) in files.iter().enumerate() {
+            let path = dir.path().join(format!("blk{:05}.dat", i));
+            write_blk_file(&path, magic, xor_key, file).unwrap();
+        }
+        let path = |i| dir.path().join(format!("blk{:05}.dat", i));
+
+        // bitcoind pre-allocates its files using zeroes
+        let mut file = OpenOptions::new().append(true).open(path(0)).unwrap();
+        file.write_all(&[0u8; 100]).unwrap();
+        // corrupt the magic of the second file's last block
+        let mut data = std::fs::read(path(1)).unwrap();
+        let offset = data.len() - blocks[11].len() - 8;
+        data[offset] ^= 0xFF;
+        std::fs::write(path(1), data).unwrap();
+        // corrupt a transaction of the third file's first block (keeping its header)
+        let mut data = std::fs::read(path(2)).unwrap();
+        data[8 + blocks[12].len() - 10] ^= 0xFF;
+        std::fs::write(path(2), data).unwrap();
+        // truncate the third file's last block
+        let len = std::fs::metadata(path(2)).unwrap().len();
+        let file = OpenOptions::new().write(true).open(path(2)).unwrap();
+        file.set_len(len - 10).unwrap();
+
+        let mut blocks_dir = BlocksDir::new(dir.path().to_owned(), magic);
+        blocks_dir.scan(&chain, 1).unwrap();
+        assert!(blocks_dir.is_scanned());
+        assert_eq!(blocks_dir.scanned_files, 4);
+
+        assert_eq!(blocks_dir.read(hash(&blocks[0])), None); // already indexed
+        assert_eq!(blocks_dir.read(hash(&stale[0])), None);
+        for block in &blocks[1..11] {
+            assert_eq!(blocks_dir.read(hash(block)).as_ref(), Some(block));
+        }
+        assert_eq!(blocks_dir.read(hash(&blocks[11])), None); // corrupt
+        assert_eq!(blocks_dir.read(hash(&blocks[12])), None); // invalid merkle root
+        for block in &blocks[13..19] {
+            assert_eq!(blocks_dir.read(hash(block)).as_ref(), Some(block));
+        }
+        assert_eq!(blocks_dir.read(hash(&blocks[19])), None); // truncated
+        for block in &blocks[20..count - TIP_MARGIN] {
+            assert_eq!(blocks_dir.read(hash(block)).as_ref(), Some(block));
+        }
+        for block in &blocks[count - TIP_MARGIN..] {
+            assert_eq!(blocks_dir.read(hash(block)), None); // may still be written
+        }
+        assert_eq!(blocks_dir.read_blocks(), count - TIP_MARGIN - 4);
+
+        // files modified after the scan are detected
+        std::fs::write(path(0), b"").unwrap();
+        assert_eq!(blocks_dir.read(hash(&blocks[1])), None);
+    }
+
+    #[test]
+    fn test_scan_last_files() {
+        let count = TIP_MARGIN + 40;
+        let (chain, blocks) = synthetic_blocks(count, 0, 100);
+        let (_, stale) = synthetic_blocks(10, 1_000_000, 100);
+        let magic = Network::Regtest.magic();
+        let dir = tempfile::tempdir().unwrap();
+        let mut files: Vec<Vec<&[u8]>> = blocks
+            .chunks(10)
+            .map(|chunk| chunk.iter().map(Vec::as_slice).collect())
+            .collect();
+        // a file holding only stale blocks doesn't stop the scan
+        files.insert(3, stale.iter().map(Vec::as_slice).collect());
+        for (i, file) in files.iter().enumerate() {
+            let path = dir.path().join(format!("blk{:05}.dat", i));
+            write_blk_file(&path, magic, None, file).unwrap();
+        }
+
+        // after a restart, the files holding only indexed blocks are not scanned
+        let mut blocks_dir = BlocksDir::new(dir.path().to_owned(), magic);
+        blocks_dir.scan(&chain, 20).unwrap();
+        assert_eq!(blocks_dir.scanned_files, files.len() - 1);
+        assert_eq!(blocks_dir.read(hash(&blocks[19])), None); // already indexed
+        for block in &blocks[20..40] {
+            assert_eq!(blocks_dir.read(hash(block)).as_ref(), Some(block));
+        }
+
+        // a file holding both indexed and new blocks is scanned, along with the preceding one
+        let mut blocks_dir = BlocksDir::new(dir.path().to_owned(), magic);
+        blocks_dir.scan(&chain, 15).unwrap();
+        assert_eq!(blocks_dir.scanned_files, files.len());
+        for block in &blocks[15..40] {
+            assert_eq!(blocks_dir.read(hash(block)).as_ref(), Some(block));
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::Result;
+use bitcoin::Txid;
+use serde_json::{json, Value};
+
+use std::collections::VecDeque;
+
+/// At most this many transactions are tracked per connection (the oldest ones are forgotten)
+pub(crate) const MAX_TRACKED_BROADCASTS: usize = 100;
+
+/// Where a transaction is found by the tracker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Location {
+    Mempool,
+    Confirmed(usize), // at this height
+    Missing,
+}
+
+struct Tracked {
+    txid: Txid,
+    seen: bool, // in the tracked mempool (it may be broadcast before the mempool is synced)
+}
+
+/// Transactions broadcast by a connection which opted in (using
+/// `blockchain.transaction.track_broadcasts`), until they are confirmed or evicted.
+#[derive(Default)]
+pub(crate) struct Broadcasts {
+    txs: VecDeque<Tracked>,
+}
+
+impl Broadcasts {
+    pub(crate) fn add(&mut self, txid: Txid) {
+        if self.txs.iter().any(|tracked| tracked.txid == txid) {
+            return;
+        }
+        if self.txs.len() == MAX_TRACKED_BROADCASTS {
+            let oldest = self.txs.pop_front().expect("empty broadcasts");
+            debug!("stopped tracking {} (too many broadcasts)", oldest.txid);
+        }
+        self.txs.push_back(Tracked { txid, seen: false });
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Returns the transactions which were confirmed or evicted (and stops tracking them), with
+    /// their `blockchain.transaction.status_changed` notification params.
+    pub(crate) fn update(
+        &mut self,
+        mut locate: impl FnMut(Txid) -> Result<Location>,
+    ) -> Result<Vec<(Txid, Value)>> {
+        let mut changed = vec![];
+        let mut result = Ok(());
+        self.txs.retain_mut(|tracked| {
+            if result.is_err() {
+                return true;
+            }
+            let status = match locate(tracked.txid) {
+                Ok(Location::Mempool) => {
+                    tracked.seen = true;
+                    return true;
+                }
+                Ok(Location::Confirmed(height)) => {
+                    json!({"txid": tracked.txid, "status": "confirmed", "height": height})
+                }
+                // evicted or replaced (a transaction is missing before the mempool is synced)
+                Ok(Location::Missing) if tracked.seen => {
+                    json!({"txid": tracked.txid, "status": "evicted"})
+                }
+                Ok(Location::Missing) => return true,
+                Err(e) => {
+                    result = Err(e);
+                    return true;
+                }
+            };
+            changed.push((tracked.txid, status));
+            false
+        });
+        result.map(|()| changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Broadcasts, Location, MAX_TRACKED_BROADCASTS};
+    use bitcoin::{hashes::Hash, Txid};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn txid(i: usize) -> Txid {
+        Txid::hash(&i.to_le_bytes())
+    }
+
+    #[test]
+    fn test_broadcasts() {
+        let mut broadcasts = Broadcasts::default();
+        let mut locations = HashMap::new();
+        let update = |broadcasts: &mut Broadcasts, locations: &HashMap<Txid, Location>| {
+            broadcasts
+                .update(|txid| Ok(locations[&txid]))
+                .unwrap()
+                .into_iter()
+                .map(|(_txid, status)| status)
+                .collect::<Vec<_>>()
+        };
+        for i in 0..3 {
+            broadcasts.add(txid(i));
+            broadcasts.add(txid(i)); // tracked once
+            locations.insert(txid(i), Location::Missing);
+        }
+        // not synced into the mempool yet
+        assert!(update(&mut broadcasts, &locations).is_empty());
+
+        locations.insert(txid(0), Location::Mempool);
+        locations.insert(txid(1), Location::Confirmed(102));
+        assert_eq!(
+            update(&mut broadcasts, &locations),
+            vec![json!({"txid": txid(1), "status": "confirmed", "height": 102})]
+        );
+
+        locations.insert(txid(0), Location::Missing); // replaced
+        assert_eq!(
+            update(&mut broadcasts, &locations),
+            vec![json!({"txid": txid(0), "status": "evicted"})]
+        );
+        assert_eq!(broadcasts.txs.len(), 1);
+
+        // the oldest transactions are forgotten
+        for i in 3..MAX_TRACKED_BROADCASTS + 3 {
+            broadcasts.add(txid(i));
+        }
+        assert_eq!(broadcasts.txs.len(), MAX_TRACKED_BROADCASTS);
+        assert_eq!(broadcasts.txs[0].txid, txid(3));
+
+        let err = broadcasts
+            .update(|_txid| anyhow::bail!("DB failure"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "DB failure");
+        assert_eq!(broadcasts.txs.len(), MAX_TRACKED_BROADCASTS);
+    }
+}
@@ -1,43 +1,728 @@
-use bitcoin::Txid;
-use parking_lot::RwLock;
+use anyhow::Result;
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    hashes::Hash,
+    Block, BlockHash, Txid,
+};
+use parking_lot::{Mutex, RwLock};
+use serde_json::Value;
 
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use crate::metrics::{self, Histogram, Metrics};
+use crate::{
+    chain::Chain,
+    metrics::{self, Counter, Gauge, Histogram, Metrics},
+    types::{ScriptHash, ScriptHashRow, SerBlock, SerTransaction, Touched},
+};
 
+/// Immutable data fetched from bitcoind (each kind has its own byte budget)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Raw transactions (by txid)
+    Tx,
+    /// Verbose `getrawtransaction` responses (by txid)
+    TxInfo,
+    /// Block transaction IDs, for merkle proofs (by block hash)
+    BlockTxids,
+    /// Raw blocks, for `blockchain.block.get` (by block hash)
+    Block,
+}
+
+impl Kind {
+    const ALL: [Kind; 4] = [Kind::Tx, Kind::TxInfo, Kind::BlockTxids, Kind::Block];
+
+    fn label(self) -> &'static str {
+        match self {
+            Kind::Tx => "tx",
+            Kind::TxInfo => "tx_info",
+            Kind::BlockTxids => "block_txids",
+            Kind::Block => "block",
+        }
+    }
+}
+
+/// The byte budget of each cache kind (see `cache_*_mb`) - the oldest entries are evicted when
+/// exceeded, and `0` disables caching the kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheBudgets {
+    pub tx: usize,
+    pub tx_info: usize,
+    pub block_txids: usize,
+    pub block: usize,
+}
+
+impl Default for CacheBudgets {
+    fn default() -> Self {
+        Self {
+            tx: 256 << 20,
+            tx_info: 64 << 20,
+            block_txids: 64 << 20,
+            block: 64 << 20,
+        }
+    }
+}
+
+impl CacheBudgets {
+    fn max_bytes(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Tx => self.tx,
+            Kind::TxInfo => self.tx_info,
+            Kind::BlockTxids => self.block_txids,
+            Kind::Block => self.block,
+        }
+    }
+}
+
+struct Entry {
+    data: Box<[u8]>,
+    // the block confirming the data, if shallow enough to be reorged (`None` if it's deeper)
+    anchor: Option<BlockHash>,
+}
+
+/// The number of shards per kind (the keys are hashes, so their first byte selects the shard)
+const SHARDS: usize = 16;
+
+#[derive(Default)]
+struct Queue {
+    order: VecDeque<[u8; 32]>, // in insertion order (for eviction)
+    bytes: usize,
+}
+
+/// The entries of a single kind: lookups only take their shard's read lock, while insertions
+/// are serialized by the eviction queue's lock (so the byte budget is enforced across shards).
+#[derive(Default)]
+struct Entries {
+    shards: [RwLock<HashMap<[u8; 32], Entry>>; SHARDS],
+    queue: Mutex<Queue>,
+}
+
+impl Entries {
+    fn shard(&self, key: &[u8; 32]) -> &RwLock<HashMap<[u8; 32], Entry>> {
+        &self.shards[key[0] as usize % SHARDS]
+    }
+}
+
+/// Confirmed data fetched from bitcoind, keyed by (kind, hash).
+///
+/// Only data confirmed by final blocks (see `Chain::is_final`) is cached unconditionally (a reorg
+/// disconnecting them clears the cache). Shallower data keeps its block hash, and is ignored after
+/// the block is reorged out of the chain.
 pub(crate) struct Cache {
-    txs: Arc<RwLock<HashMap<Txid, Box<[u8]>>>>,
+    kinds: [Entries; 4],
+    max_bytes: [usize; 4],
 
     // stats
     txs_size: Histogram,
+    hits: Counter,
+    misses: Counter,
+    bytes: Gauge,
 }
 
 impl Cache {
     pub fn new(metrics: &Metrics) -> Self {
+        Self::with_budgets(metrics, CacheBudgets::default())
+    }
+
+    pub(crate) fn with_budgets(metrics: &Metrics, budgets: CacheBudgets) -> Self {
         Cache {
-            txs: Default::default(),
+            kinds: Default::default(),
+            max_bytes: Kind::ALL.map(|kind| budgets.max_bytes(kind)),
             txs_size: metrics.histogram_vec(
                 "cache_txs_size",
                 "Cached transactions' size (in bytes)",
                 "type",
                 metrics::default_size_buckets(),
             ),
+            hits: metrics.counter(
+                "daemon_cache_hits",
+                "# of lookups found in the daemon cache",
+                "kind",
+            ),
+            misses: metrics.counter(
+                "daemon_cache_misses",
+                "# of lookups missing from the daemon cache",
+                "kind",
+            ),
+            bytes: metrics.gauge(
+                "daemon_cache_size",
+                "Total size of the daemon cache's entries (in bytes)",
+                "kind",
+            ),
+        }
+    }
+
+    /// Returns `None` if the block is not in `chain`, otherwise the entries' anchor
+    fn anchor(&self, chain: &Chain, blockhash: BlockHash) -> Option<Option<BlockHash>> {
+        let height = chain.get_block_height(&blockhash)?;
+        match chain.is_final(height) {
+            true => Some(None),
+            false => Some(Some(blockhash)),
         }
     }
 
-    pub fn add_tx(&self, txid: Txid, f: impl FnOnce() -> Box<[u8]>) {
-        self.txs.write().entry(txid).or_insert_with(|| {
+    fn insert(&self, kind: Kind, key: [u8; 32], data: Box<[u8]>, anchor: Option<BlockHash>) {
+        let max_bytes = self.max_bytes[kind as usize];
+        if data.len() > max_bytes {
+            return;
+        }
+        let entries = &self.kinds[kind as usize];
+        let mut queue = entries.queue.lock();
+        queue.bytes += data.len();
+        match entries
+            .shard(&key)
+            .write()
+            .insert(key, Entry { data, anchor })
+        {
+            Some(prev) => queue.bytes -= prev.data.len(), // e.g. after a reorg
+            None => queue.order.push_back(key),
+        }
+        while queue.bytes > max_bytes {
+            let oldest = queue.order.pop_front().expect("missing entries");
+            let evicted = entries.shard(&oldest).write().remove(&oldest);
+            queue.bytes -= evicted.expect("missing entry").data.len();
+        }
+        self.bytes.set(kind.label(), queue.bytes as f64);
+    }
+
+    /// Doesn't update the hit/miss stats
+    fn lookup<T>(
+        &self,
+        kind: Kind,
+        chain: &Chain,
+        key: &[u8; 32],
+        f: impl FnOnce(&[u8]) -> Option<T>,
+    ) -> Option<T> {
+        self.kinds[kind as usize]
+            .shard(key)
+            .read()
+            .get(key)
+            .filter(|entry| match entry.anchor {
+                Some(blockhash) => chain.get_block_height(&blockhash).is_some(),
+                None => true,
+            })
+            .and_then(|entry| f(&entry.data))
+    }
+
+    fn get<T>(
+        &self,
+        kind: Kind,
+        chain: &Chain,
+        key: &[u8; 32],
+        f: impl FnOnce(&[u8]) -> Option<T>,
+    ) -> Option<T> {
+        let result = self.lookup(kind, chain, key, f);
+        match result {
+            Some(_) => self.hits.inc(kind.label()),
+            None => self.misses.inc(kind.label()),
+        }
+        result
+    }
+
+    /// Drop all entries (e.g. after a reorg disconnecting final blocks)
+    pub(crate) fn clear(&self) {
+        for kind in Kind::ALL {
+            let entries = &self.kinds[kind as usize];
+            let mut queue = entries.queue.lock();
+            for shard in &entries.shards {
+                shard.write().clear();
+            }
+            *queue = Queue::default();
+            self.bytes.set(kind.label(), 0.0);
+        }
+    }
+
+    /// Cache a transaction confirmed by `blockhash` (if it is in `chain`)
+    pub fn add_tx(
+        &self,
+        chain: &Chain,
+        blockhash: BlockHash,
+        txid: Txid,
+        f: impl FnOnce() -> SerTransaction,
+    ) {
+        if let Some(anchor) = self.anchor(chain, blockhash) {
             let tx = f();
             self.txs_size.observe("serialized", tx.len() as f64);
-            tx
-        });
+            self.insert(Kind::Tx, txid.to_byte_array(), tx, anchor);
+        }
     }
 
-    pub fn get_tx<F, T>(&self, txid: &Txid, f: F) -> Option<T>
+    pub fn get_tx<F, T>(&self, chain: &Chain, txid: &Txid, f: F) -> Option<T>
     where
         F: FnOnce(&[u8]) -> T,
     {
-        self.txs.read().get(txid).map(|tx_bytes| f(tx_bytes))
+        self.get(Kind::Tx, chain, txid.as_byte_array(), |tx| Some(f(tx)))
+    }
+
+    /// Returns the cached transaction, or the one returned by `fetch` (caching it if confirmed).
+    /// Concurrent misses of the same txid are not serialized here (which would block query
+    /// workers): identical `blockchain.transaction.get` calls are coalesced into a single fetch
+    /// by `electrum::coalesce`, whose waiters don't block.
+    pub(crate) fn get_or_fetch_tx<T>(
+        &self,
+        chain: &Chain,
+        txid: Txid,
+        fetch: impl FnOnce() -> Result<Option<(Option<BlockHash>, SerTransaction)>>,
+        f: impl Fn(&[u8]) -> T,
+    ) -> Result<Option<T>> {
+        if let Some(result) = self.get_tx(chain, &txid, &f) {
+            return Ok(Some(result));
+        }
+        let fetched = fetch()?;
+        Ok(fetched.map(|(blockhash, tx)| {
+            let result = f(&tx);
+            if let Some(blockhash) = blockhash {
+                self.add_tx(chain, blockhash, txid, move || tx);
+            }
+            result
+        }))
+    }
+
+    /// Cache a verbose transaction confirmed by `blockhash` (if it is in `chain`)
+    pub(crate) fn add_tx_info(
+        &self,
+        chain: &Chain,
+        blockhash: BlockHash,
+        txid: Txid,
+        info: &Value,
+    ) {
+        if let Some(anchor) = self.anchor(chain, blockhash) {
+            let data = serde_json::to_vec(info)
+                .expect("invalid JSON")
+                .into_boxed_slice();
+            self.insert(Kind::TxInfo, txid.to_byte_array(), data, anchor);
+        }
+    }
+
+    /// The number of confirmations is updated using `chain`
+    pub(crate) fn get_tx_info(&self, chain: &Chain, txid: &Txid) -> Option<Value> {
+        self.get(Kind::TxInfo, chain, txid.as_byte_array(), |data| {
+            let mut info: Value = serde_json::from_slice(data).ok()?;
+            let blockhash: BlockHash = serde_json::from_value(info["blockhash"].clone()).ok()?;
+            let height = chain.get_block_height(&blockhash)?;
+            if let Some(confirmations) = info.get_mut("confirmations") {
+                *confirmations = (chain.height() - height + 1).into();
+            }
+            Some(info)
+        })
+    }
+
+    /// Cache the transaction IDs of `blockhash` (if it is in `chain`)
+    pub(crate) fn add_block_txids(&self, chain: &Chain, blockhash: BlockHash, txids: &[Txid]) {
+        if let Some(anchor) = self.anchor(chain, blockhash) {
+            let data: Vec<u8> = txids.iter().flat_map(|txid| txid.to_byte_array()).collect();
+            self.insert(
+                Kind::BlockTxids,
+                blockhash.to_byte_array(),
+                data.into(),
+                anchor,
+            );
+        }
+    }
+
+    pub(crate) fn get_block_txids(
+        &self,
+        chain: &Chain,
+        blockhash: &BlockHash,
+    ) -> Option<Vec<Txid>> {
+        self.get(Kind::BlockTxids, chain, blockhash.as_byte_array(), |data| {
+            let txids = data.chunks_exact(32);
+            Some(txids.map(|txid| Txid::from_slice(txid).unwrap()).collect())
+        })
+    }
+
+    /// Cache a serialized block (if it is in `chain`)
+    pub(crate) fn add_block(&self, chain: &Chain, blockhash: BlockHash, block: SerBlock) {
+        if let Some(anchor) = self.anchor(chain, blockhash) {
+            self.insert(Kind::Block, blockhash.to_byte_array(), block.into(), anchor);
+        }
+    }
+
+    /// Cache the txids and transactions of a newly published block, so the first requests after
+    /// it (e.g. merkle proofs and the subscribed statuses' updates) don't fetch them from bitcoind.
+    /// The transactions are skipped if the block would take more than a quarter of their budget
+    /// (evicting most of the cached ones). Returns `false` if the block is not in `chain`.
+    pub(crate) fn prefetch_block(&self, chain: &Chain, blockhash: BlockHash, data: &[u8]) -> bool {
+        if self.anchor(chain, blockhash).is_none() {
+            return false; // e.g. reorged out
+        }
+        let block: Block = deserialize(data).expect("invalid block");
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+        self.add_block_txids(chain, blockhash, &txids);
+        if data.len() <= self.max_bytes[Kind::Tx as usize] / 4 {
+            for (tx, txid) in block.txdata.iter().zip(txids) {
+                self.add_tx(chain, blockhash, txid, || serialize(tx).into());
+            }
+        }
+        true
+    }
+
+    pub(crate) fn get_block<T>(
+        &self,
+        chain: &Chain,
+        blockhash: &BlockHash,
+        f: impl FnOnce(&[u8]) -> T,
+    ) -> Option<T> {
+        self.get(Kind::Block, chain, blockhash.as_byte_array(), |block| {
+            Some(f(block))
+        })
+    }
+}
+
+/// Bound the memory used by `EmptyScriptHashes` (it is cleared when full)
+const MAX_EMPTY_SCRIPTHASHES: usize = 100_000;
+
+/// Scripthashes known to have no history (e.g. due to wallets' gap limit scanning), so their
+/// statuses are returned without any DB lookups.
+///
+/// The published blocks and mempool changes invalidate the scripthashes whose funding prefixes
+/// were touched (or all of them, if unknown), so a scripthash with history is never reported as
+/// empty. Each invalidation starts a new epoch, and only statuses computed within the current
+/// epoch are inserted (so a concurrent invalidation is not missed).
+pub(crate) struct EmptyScriptHashes {
+    state: Mutex<EmptyState>,
+    stats: Counter,
+}
+
+#[derive(Default)]
+struct EmptyState {
+    epoch: u64,
+    scripthashes: HashSet<ScriptHash>,
+}
+
+impl EmptyScriptHashes {
+    pub(crate) fn new(metrics: &Metrics) -> Self {
+        Self {
+            state: Mutex::default(),
+            stats: metrics.counter(
+                "empty_scripthashes",
+                "Lookups and invalidations of scripthashes known to have no history",
+                "type",
+            ),
+        }
+    }
+
+    /// Returns `true` if `scripthash` is known to have no history.
+    pub(crate) fn contains(&self, scripthash: &ScriptHash) -> bool {
+        let found = self.state.lock().scripthashes.contains(scripthash);
+        self.stats.inc(if found { "hit" } else { "miss" });
+        found
+    }
+
+    /// To be called before computing a status (and passed to `insert()`).
+    pub(crate) fn epoch(&self) -> u64 {
+        self.state.lock().epoch
+    }
+
+    /// Mark `scripthash` as empty, if no invalidation happened since `epoch`.
+    pub(crate) fn insert(&self, scripthash: ScriptHash, epoch: u64) {
+        let mut state = self.state.lock();
+        if state.epoch != epoch {
+            return; // the status may be stale
+        }
+        if state.scripthashes.len() >= MAX_EMPTY_SCRIPTHASHES {
+            state.scripthashes.clear();
+            self.stats.inc("cleared");
+        }
+        state.scripthashes.insert(scripthash);
+    }
+
+    /// Invalidate the scripthashes funded by the touched prefixes (`None` if unknown).
+    pub(crate) fn invalidate(&self, confirmed: Option<&Touched>, mempool: Option<&Touched>) {
+        let mut state = self.state.lock();
+        state.epoch += 1;
+        let (Some(confirmed), Some(mempool)) = (confirmed, mempool) else {
+            if !state.scripthashes.is_empty() {
+                state.scripthashes.clear();
+                self.stats.inc("cleared");
+            }
+            return;
+        };
+        state.scripthashes.retain(|scripthash| {
+            let prefix = ScriptHashRow::scan_prefix(*scripthash);
+            let touched = confirmed.funding.contains(&prefix) || mempool.funding.contains(&prefix);
+            if touched {
+                self.stats.inc("invalidated");
+            }
+            !touched
+        });
+    }
+}
+
+/// Cached transactions (and the previous single-lock layout holding the same ones), used by
+/// `benches/cache.rs`
+#[cfg(feature = "bench")]
+pub struct BenchCache {
+    chain: Chain,
+    sharded: Cache,
+    single: RwLock<HashMap<Txid, Box<[u8]>>>,
+}
+
+#[cfg(feature = "bench")]
+pub fn bench_cache(entries: usize) -> BenchCache {
+    let chain = Chain::new(bitcoin::Network::Regtest);
+    let genesis = chain.tip();
+    let sharded = Cache::new(&Metrics::unserved());
+    let single = RwLock::new(HashMap::new());
+    for n in 0..entries {
+        let txid = bench_txid(n);
+        sharded.add_tx(&chain, genesis, txid, || vec![0; 250].into());
+        single
+            .write()
+            .insert(txid, vec![0u8; 250].into_boxed_slice());
+    }
+    BenchCache {
+        chain,
+        sharded,
+        single,
+    }
+}
+
+#[cfg(feature = "bench")]
+fn bench_txid(n: usize) -> Txid {
+    let mut bytes = [0u8; 32];
+    bytes[..8].copy_from_slice(&(n as u64).to_le_bytes());
+    Txid::from_byte_array(bytes)
+}
+
+/// Look up `lookups` cached transactions from each of `readers` threads (using the sharded layout,
+/// or the previous single-lock one), returning the number of found bytes. Both exclude the
+/// hit/miss stats (which are unchanged), but check the entries' anchor.
+#[cfg(feature = "bench")]
+pub fn bench_cache_readers(
+    cache: &BenchCache,
+    readers: usize,
+    lookups: usize,
+    sharded: bool,
+) -> usize {
+    let entries = cache.single.read().len();
+    let genesis = cache.chain.tip();
+    let lookup = |txid: &Txid| -> usize {
+        if sharded {
+            let len = |tx: &[u8]| Some(tx.len());
+            let tx = cache
+                .sharded
+                .lookup(Kind::Tx, &cache.chain, txid.as_byte_array(), len);
+            tx.unwrap_or(0)
+        } else {
+            let map = cache.single.read();
+            let tx = map
+                .get(txid)
+                .filter(|_| cache.chain.get_block_height(&genesis).is_some());
+            tx.map_or(0, |tx| tx.len())
+        }
+    };
+    std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..readers)
+            .map(|thread| {
+                scope.spawn(move || {
+                    (0..lookups)
+                        .map(|i| lookup(&bench_txid((i * 7919 + thread) % entries)))
+                        .sum::<usize>()
+                })
+            })
+            .collect();
+        threads.into_iter().map(|t| t.join().unwrap()).sum()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, CacheBudgets, EmptyScriptHashes, Kind};
+    use crate::{
+        chain::Chain,
+        metrics::Metrics,
+        types::{ScriptHash, ScriptHashRow, Touched},
+    };
+    use bitcoin::{
+        blockdata::constants::genesis_block, consensus::serialize, hashes::Hash, BlockHash,
+        Network, ScriptBuf, Txid,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn scripthash(n: u8) -> ScriptHash {
+        ScriptHash::new(&ScriptBuf::from_bytes(vec![n]))
+    }
+
+    #[test]
+    fn test_cache_budget() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut cache = Cache::new(&metrics);
+        cache.max_bytes[Kind::Tx as usize] = 100;
+        let chain = Chain::new(Network::Regtest);
+        let genesis = chain.tip();
+        let txid = |n: u8| Txid::from_byte_array([n; 32]);
+        let get = |n: u8| cache.get_tx(&chain, &txid(n), |tx| tx.to_vec());
+
+        // blocks which are not in the chain are not cached
+        let stale = BlockHash::from_byte_array([1; 32]);
+        cache.add_tx(&chain, stale, txid(1), || vec![1; 40].into());
+        assert_eq!(get(1), None);
+
+        for n in 1..=3 {
+            cache.add_tx(&chain, genesis, txid(n), || vec![n; 40].into());
+        }
+        // the oldest entry is evicted
+        assert_eq!(get(1), None);
+        assert_eq!(get(2), Some(vec![2; 40]));
+        assert_eq!(get(3), Some(vec![3; 40]));
+        // entries larger than the budget are skipped
+        cache.add_tx(&chain, genesis, txid(4), || vec![4; 101].into());
+        assert_eq!(get(4), None);
+        assert_eq!(get(2), Some(vec![2; 40]));
+
+        // other kinds have their own budget
+        cache.add_block_txids(&chain, genesis, &[txid(5), txid(6), txid(7), txid(8)]);
+        assert_eq!(
+            cache.get_block_txids(&chain, &genesis),
+            Some(vec![txid(5), txid(6), txid(7), txid(8)])
+        );
+        assert_eq!(get(3), Some(vec![3; 40]));
+
+        cache.clear();
+        assert_eq!(get(3), None);
+        assert_eq!(cache.get_block_txids(&chain, &genesis), None);
+
+        // a zero budget disables caching the kind
+        let budgets = CacheBudgets {
+            block_txids: 0,
+            ..CacheBudgets::default()
+        };
+        let cache = Cache::with_budgets(&Metrics::unserved(), budgets);
+        cache.add_block_txids(&chain, genesis, &[txid(5)]);
+        assert_eq!(cache.get_block_txids(&chain, &genesis), None);
+        cache.add_tx(&chain, genesis, txid(1), || vec![1; 40].into());
+        assert_eq!(
+            cache.get_tx(&chain, &txid(1), |tx| tx.to_vec()),
+            Some(vec![1; 40])
+        );
+    }
+
+    #[test]
+    fn test_prefetch_block() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut cache = Cache::new(&metrics);
+        let chain = Chain::new(Network::Regtest);
+        let block = genesis_block(Network::Regtest);
+        let (blockhash, data) = (block.block_hash(), serialize(&block));
+        let coinbase = block.txdata[0].compute_txid();
+        let get = |cache: &Cache| cache.get_tx(&chain, &coinbase, |tx| tx.to_vec());
+
+        // blocks which are not in the chain are skipped
+        let stale = BlockHash::from_byte_array([1; 32]);
+        assert!(!cache.prefetch_block(&chain, stale, &data));
+
+        assert!(cache.prefetch_block(&chain, blockhash, &data));
+        assert_eq!(
+            cache.get_block_txids(&chain, &blockhash),
+            Some(vec![coinbase])
+        );
+        assert_eq!(get(&cache), Some(serialize(&block.txdata[0])));
+
+        // the transactions of a block taking most of their budget are skipped
+        cache.clear();
+        cache.max_bytes[Kind::Tx as usize] = 3 * data.len();
+        assert!(cache.prefetch_block(&chain, blockhash, &data));
+        assert_eq!(
+            cache.get_block_txids(&chain, &blockhash),
+            Some(vec![coinbase])
+        );
+        assert_eq!(get(&cache), None);
+    }
+
+    fn txid(n: usize) -> Txid {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&(n as u64).to_le_bytes());
+        Txid::from_byte_array(bytes)
+    }
+
+    #[test]
+    fn test_cache_concurrent_budget() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut cache = Cache::new(&metrics);
+        cache.max_bytes[Kind::Tx as usize] = 1000;
+        let chain = Chain::new(Network::Regtest);
+        let genesis = chain.tip();
+
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let (cache, chain) = (&cache, &chain);
+                scope.spawn(move || {
+                    for i in 0..500 {
+                        let n = (thread * 500 + i) % 300; // some keys are re-inserted
+                        let size = 1 + n % 50;
+                        cache.add_tx(chain, genesis, txid(n), || vec![n as u8; size].into());
+                        if let Some(tx) = cache.get_tx(chain, &txid(n), |tx| tx.to_vec()) {
+                            assert_eq!(tx, vec![n as u8; size]);
+                        }
+                    }
+                });
+            }
+        });
+
+        // the queue accounts for all the sharded entries, within the budget
+        let entries = &cache.kinds[Kind::Tx as usize];
+        let queue = entries.queue.lock();
+        let shards = entries.shards.iter().map(|shard| shard.read());
+        let (count, bytes) = shards.fold((0, 0), |(count, bytes), shard| {
+            let size: usize = shard.values().map(|entry| entry.data.len()).sum();
+            (count + shard.len(), bytes + size)
+        });
+        assert_eq!(queue.order.len(), count);
+        assert_eq!(queue.bytes, bytes);
+        assert!(bytes <= 1000);
+    }
+
+    #[test]
+    fn test_cache_fetch_tx() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let cache = Cache::new(&metrics);
+        let chain = Chain::new(Network::Regtest);
+        let genesis = chain.tip();
+        let fetches = AtomicUsize::new(0);
+        let fetch = || {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(Some((Some(genesis), vec![1, 2, 3].into())))
+        };
+        for _ in 0..2 {
+            let tx = cache.get_or_fetch_tx(&chain, txid(1), fetch, |tx| tx.to_vec());
+            assert_eq!(tx.unwrap(), Some(vec![1, 2, 3]));
+        }
+        // confirmed transactions are cached
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // failed and unconfirmed fetches are not cached
+        let fetch = || anyhow::bail!("failed");
+        let err = cache.get_or_fetch_tx(&chain, txid(2), fetch, |tx| tx.to_vec());
+        assert!(err.is_err());
+        let fetch = || Ok(Some((None, vec![4].into())));
+        let tx = cache.get_or_fetch_tx(&chain, txid(2), fetch, |tx| tx.to_vec());
+        assert_eq!(tx.unwrap(), Some(vec![4]));
+        assert_eq!(cache.get_tx(&chain, &txid(2), |tx| tx.to_vec()), None);
+    }
+
+    #[test]
+    fn test_empty_scripthashes() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let empty = EmptyScriptHashes::new(&metrics);
+        let epoch = empty.epoch();
+        empty.insert(scripthash(1), epoch);
+        empty.insert(scripthash(2), epoch);
+        assert!(empty.contains(&scripthash(1)));
+        assert!(!empty.contains(&scripthash(3)));
+
+        // only the funded scripthashes are invalidated
+        let mut mempool = Touched::default();
+        mempool.add_funding([ScriptHashRow::scan_prefix(scripthash(1))].into_iter());
+        empty.invalidate(Some(&Touched::default()), Some(&mempool));
+        assert!(!empty.contains(&scripthash(1)));
+        assert!(empty.contains(&scripthash(2)));
+
+        // a status computed before an invalidation is not inserted
+        empty.insert(scripthash(3), epoch);
+        assert!(!empty.contains(&scripthash(3)));
+
+        // unknown touched prefixes invalidate everything
+        empty.invalidate(None, Some(&Touched::default()));
+        assert!(!empty.contains(&scripthash(2)));
     }
 }
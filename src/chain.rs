@@ -1,7 +1,30 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::ops::Range;
+use std::sync::Arc;
 
+use anyhow::{Context, Result};
 use bitcoin::blockdata::block::Header as BlockHeader;
-use bitcoin::{BlockHash, Network};
+use bitcoin::consensus::Params;
+use bitcoin::consensus::{serialize, Encodable};
+use bitcoin::hashes::Hash;
+use bitcoin::hex::DisplayHex;
+use bitcoin::{BlockHash, Network, TxMerkleNode};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::{
+    config::Config,
+    limits,
+    merkle::{Proof, UpperTree},
+};
+
+/// Headers more than this far (in seconds) ahead of the current time are logged (bitcoind's
+/// `MAX_FUTURE_BLOCK_TIME`)
+const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60;
+
+/// A header's timestamp must be above the median of its previous headers' timestamps
+const MEDIAN_TIME_SPAN: usize = 11;
 
 /// A new header found, to be added to the chain at specific height
 pub(crate) struct NewHeader {
@@ -26,39 +49,480 @@ impl NewHeader {
     pub(crate) fn hash(&self) -> BlockHash {
         self.hash
     }
+
+    pub(crate) fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+}
+
+/// Bitcoin Core's checkpoints (https://github.com/bitcoin/bitcoin/blob/v0.21.0/src/chainparams.cpp)
+const BITCOIN_CHECKPOINTS: &[(usize, &str)] = &[
+    (
+        11111,
+        "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d",
+    ),
+    (
+        33333,
+        "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6",
+    ),
+    (
+        74000,
+        "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20",
+    ),
+    (
+        105000,
+        "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97",
+    ),
+    (
+        134444,
+        "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe",
+    ),
+    (
+        168000,
+        "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763",
+    ),
+    (
+        193000,
+        "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317",
+    ),
+    (
+        210000,
+        "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e",
+    ),
+    (
+        216116,
+        "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e",
+    ),
+    (
+        225430,
+        "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932",
+    ),
+    (
+        250000,
+        "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214",
+    ),
+    (
+        279000,
+        "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40",
+    ),
+    (
+        295000,
+        "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983",
+    ),
+];
+
+const TESTNET_CHECKPOINTS: &[(usize, &str)] = &[(
+    546,
+    "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70",
+)];
+
+/// Known blocks of a network's chain: headers contradicting them are refused, so a bogus chain
+/// (e.g. from a malicious or broken bitcoind) is not indexed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Checkpoints(Vec<(usize, BlockHash)>); // sorted by height
+
+impl Checkpoints {
+    pub(crate) fn new(mut checkpoints: Vec<(usize, BlockHash)>) -> Self {
+        checkpoints.sort_unstable();
+        Self(checkpoints)
+    }
+
+    /// The built-in checkpoints (there are none for regtest and signet).
+    pub(crate) fn for_network(network: Network) -> Self {
+        let checkpoints = match network {
+            Network::Bitcoin => BITCOIN_CHECKPOINTS,
+            Network::Testnet => TESTNET_CHECKPOINTS,
+            _ => &[],
+        };
+        Self::new(
+            checkpoints
+                .iter()
+                .map(|(height, hash)| (*height, hash.parse().expect("invalid checkpoint")))
+                .collect(),
+        )
+    }
+
+    /// Returns the first checkpoint contradicted by the chain (with the chain's block hash at its
+    /// height). Checkpoints above the chain's tip are not contradicted.
+    pub(crate) fn contradicted_by(&self, chain: &Chain) -> Option<(usize, BlockHash, BlockHash)> {
+        self.find(|height| chain.get_block_hash(height))
+    }
+
+    /// Like `contradicted_by()`, for new headers (which are checked before updating a chain).
+    pub(crate) fn contradicted_by_headers(
+        &self,
+        headers: &[NewHeader],
+    ) -> Option<(usize, BlockHash, BlockHash)> {
+        let first = headers.first()?.height;
+        self.find(|height| Some(headers.get(height.checked_sub(first)?)?.hash))
+    }
+
+    fn find(
+        &self,
+        get_hash: impl Fn(usize) -> Option<BlockHash>,
+    ) -> Option<(usize, BlockHash, BlockHash)> {
+        self.0.iter().find_map(|&(height, expected)| {
+            get_hash(height)
+                .filter(|hash| *hash != expected)
+                .map(|hash| (height, expected, hash))
+        })
+    }
+}
+
+impl std::fmt::Display for Checkpoints {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        let list: Vec<String> = self
+            .0
+            .iter()
+            .map(|(height, hash)| format!("{}:{}", height, hash))
+            .collect();
+        write!(f, "{}", list.join(","))
+    }
+}
+
+/// Best block's height and serialized header (used by `blockchain.headers.subscribe`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TipHeader {
+    height: usize,
+    hash: BlockHash,
+    raw: [u8; BlockHeader::SIZE],
+}
+
+impl TipHeader {
+    fn new(height: usize, hash: BlockHash, header: &BlockHeader) -> Self {
+        let raw = serialize(header).try_into().expect("invalid header size");
+        Self { height, hash, raw }
+    }
+
+    pub(crate) fn hash(&self) -> BlockHash {
+        self.hash
+    }
+
+    /// `{"height": ..., "hex": ...}`, as specified by the Electrum protocol
+    pub(crate) fn to_json(&self) -> Value {
+        json!({"height": self.height, "hex": self.raw.to_lower_hex_string()})
+    }
+}
+
+/// Persisted headers of the indexed chain, used for the headers evicted from memory (see
+/// `Chain::set_header_store`).
+pub(crate) trait HeaderStore: Send + Sync {
+    fn header_at(&self, height: usize) -> Option<BlockHeader>;
+
+    /// The height of a stored block (possibly a stale one)
+    fn height_of(&self, blockhash: &BlockHash) -> Option<usize>;
+
+    /// Call `func` for each stored header at `heights` (ordered by height)
+    fn for_each_header(&self, heights: Range<usize>, func: &mut dyn FnMut(BlockHeader));
+}
+
+/// The number of evicted headers (and of their heights) cached in memory
+const CACHED_HEADERS: usize = 10_000;
+
+/// A small cache, dropping its least recently used half when full
+struct Lru<K, V> {
+    entries: HashMap<K, (V, u64)>, // with the entry's last use
+    uses: u64,
+}
+
+impl<K: Eq + std::hash::Hash, V: Copy> Lru<K, V> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.uses += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        *used = self.uses;
+        Some(*value)
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.entries.len() >= CACHED_HEADERS {
+            let mut uses: Vec<u64> = self.entries.values().map(|(_, used)| *used).collect();
+            let median = *uses.select_nth_unstable(CACHED_HEADERS / 2).1;
+            self.entries.retain(|_, (_, used)| *used > median);
+        }
+        self.uses += 1;
+        self.entries.insert(key, (value, self.uses));
+    }
+}
+
+/// The headers evicted from memory (shared by the chain's clones)
+struct Evicted {
+    store: Arc<dyn HeaderStore>,
+    recent: usize, // the number of indexed headers kept in memory
+    headers: Mutex<Lru<usize, (BlockHash, BlockHeader)>>,
+    heights: Mutex<Lru<BlockHash, usize>>,
+}
+
+impl Evicted {
+    fn get(&self, height: usize) -> Option<(BlockHash, BlockHeader)> {
+        if let Some(entry) = self.headers.lock().get(&height) {
+            return Some(entry);
+        }
+        let header = self.store.header_at(height)?;
+        let entry = (header.block_hash(), header);
+        self.headers.lock().insert(height, entry);
+        Some(entry)
+    }
+
+    fn height(&self, blockhash: &BlockHash) -> Option<usize> {
+        let cached = self.heights.lock().get(blockhash);
+        let height = match cached {
+            Some(height) => height,
+            None => {
+                let height = self.store.height_of(blockhash)?;
+                self.heights.lock().insert(*blockhash, height);
+                height
+            }
+        };
+        // stale blocks' heights are kept in the store
+        (self.get(height)?.0 == *blockhash).then_some(height)
+    }
+
+    /// Forget the cached headers from `height` (replaced by a reorg)
+    fn invalidate(&self, height: usize) {
+        self.headers.lock().entries.retain(|h, _| *h < height);
+    }
+}
+
+/// The number of headers in each of `HeaderList`'s chunks
+const CHUNK_SIZE: usize = 2016;
+
+/// The number of `HeaderList`'s height shards
+const HEIGHT_SHARDS: usize = 64;
+
+/// The in-memory headers, whose storage is shared by the chain's clones (e.g. the synced, the
+/// indexed and the published chains): the headers are stored in chunks and their heights in
+/// shards, so a clone copies only their pointers, and an update copies only the chunks and
+/// shards it modifies (if they are still shared).
+#[derive(Clone)]
+struct HeaderList {
+    base: usize,  // the height of the first chunk's first header
+    first: usize, // the height of the first header (the preceding ones were evicted)
+    end: usize,   // the height following the last header
+    chunks: VecDeque<Arc<Vec<(BlockHash, BlockHeader)>>>,
+    heights: Vec<Arc<HashMap<BlockHash, usize>>>, // sharded by the block hash
+}
+
+impl HeaderList {
+    fn new(first: usize) -> Self {
+        Self {
+            base: first,
+            first,
+            end: first,
+            chunks: VecDeque::new(),
+            heights: (0..HEIGHT_SHARDS).map(|_| Arc::default()).collect(),
+        }
+    }
+
+    /// The number of headers
+    fn len(&self) -> usize {
+        self.end - self.first
+    }
+
+    fn get(&self, height: usize) -> Option<&(BlockHash, BlockHeader)> {
+        if height < self.first || height >= self.end {
+            return None;
+        }
+        let offset = height - self.base;
+        Some(&self.chunks[offset / CHUNK_SIZE][offset % CHUNK_SIZE])
+    }
+
+    fn last(&self) -> Option<&(BlockHash, BlockHeader)> {
+        self.get(self.end.checked_sub(1)?)
+    }
+
+    fn shard(blockhash: &BlockHash) -> usize {
+        usize::from(blockhash.as_byte_array()[0]) % HEIGHT_SHARDS
+    }
+
+    fn height(&self, blockhash: &BlockHash) -> Option<usize> {
+        self.heights[Self::shard(blockhash)].get(blockhash).copied()
+    }
+
+    fn push(&mut self, hash: BlockHash, header: BlockHeader) {
+        if (self.end - self.base) % CHUNK_SIZE == 0 {
+            self.chunks
+                .push_back(Arc::new(Vec::with_capacity(CHUNK_SIZE)));
+        }
+        let chunk = self.chunks.back_mut().expect("missing chunk");
+        Arc::make_mut(chunk).push((hash, header));
+        let heights = Arc::make_mut(&mut self.heights[Self::shard(&hash)]);
+        assert!(heights.insert(hash, self.end).is_none());
+        self.end += 1;
+    }
+
+    #[cfg(test)]
+    fn count_heights(&self) -> usize {
+        self.heights.iter().map(|heights| heights.len()).sum()
+    }
+
+    fn remove_heights(&mut self, heights: Range<usize>) {
+        for height in heights {
+            let (hash, _header) = *self.get(height).expect("missing header");
+            let heights = Arc::make_mut(&mut self.heights[Self::shard(&hash)]);
+            assert!(heights.remove(&hash).is_some());
+        }
+    }
+
+    /// Remove the headers from `height` (which must not be below `first`)
+    fn truncate(&mut self, height: usize) {
+        if height >= self.end {
+            return;
+        }
+        self.remove_heights(height..self.end);
+        let offset = height - self.base;
+        self.chunks.truncate(offset.div_ceil(CHUNK_SIZE));
+        if offset % CHUNK_SIZE != 0 {
+            let chunk = self.chunks.back_mut().expect("missing chunk");
+            Arc::make_mut(chunk).truncate(offset % CHUNK_SIZE);
+        }
+        self.end = height;
+    }
+
+    /// Remove the headers below `height` (dropping their chunks, once all of them are removed)
+    fn evict(&mut self, height: usize) {
+        self.remove_heights(self.first..height);
+        self.first = height;
+        while self.first - self.base >= CHUNK_SIZE {
+            self.chunks.pop_front();
+            self.base += CHUNK_SIZE;
+        }
+        for heights in &mut self.heights {
+            if heights.capacity() > 2 * heights.len() {
+                Arc::make_mut(heights).shrink_to_fit();
+            }
+        }
+    }
 }
 
 /// Current blockchain headers' list
+///
+/// By default, all the headers are kept in memory. Otherwise (see `set_header_store`), the
+/// headers below the last indexed ones are read from the store (via a small cache).
+#[derive(Clone)]
 pub struct Chain {
-    headers: Vec<(BlockHash, BlockHeader)>,
-    heights: HashMap<BlockHash, usize>,
+    network: Network,
+    check_pow: bool, // see `custom_skip_pow_check`
+    headers: HeaderList,
+    evicted: Option<Arc<Evicted>>,
+    tip_header: TipHeader,      // updated on each change of the best block
+    final_depth: Option<usize>, // see `is_final()`
+    last_header_tree: Arc<Mutex<Option<HeaderTree>>>, // shared by the clones (see `header_proof()`)
+}
+
+/// The merkle tree of the block hashes up to a `cp_height` (clients usually keep using the same
+/// checkpoint, so it is built only once)
+struct HeaderTree {
+    cp_hash: BlockHash, // the block hash at `cp_height` (changed by a reorg below it)
+    tree: Arc<UpperTree>,
 }
 
 impl Chain {
     // create an empty chain
     pub fn new(network: Network) -> Self {
         let genesis = bitcoin::blockdata::constants::genesis_block(network);
+        Self::with_genesis(network, genesis.header)
+    }
+
+    /// Create an empty chain of the configured network (or custom chain, see `network`).
+    pub(crate) fn for_config(config: &Config) -> Self {
+        let mut chain = Self::with_genesis(config.network, config.genesis());
+        chain.check_pow = config
+            .custom_chain
+            .as_ref()
+            .is_none_or(|custom| custom.check_pow);
+        chain
+    }
+
+    // create an empty chain, starting from a custom genesis block (using `network` parameters)
+    fn with_genesis(network: Network, genesis: BlockHeader) -> Self {
         let genesis_hash = genesis.block_hash();
+        let mut headers = HeaderList::new(0);
+        headers.push(genesis_hash, genesis); // genesis header @ zero height
         Self {
-            headers: vec![(genesis_hash, genesis.header)],
-            heights: std::iter::once((genesis_hash, 0)).collect(), // genesis header @ zero height
+            network,
+            check_pow: true,
+            headers,
+            evicted: None,
+            tip_header: TipHeader::new(0, genesis_hash, &genesis),
+            final_depth: None,
+            last_header_tree: Arc::default(),
         }
     }
 
+    /// Blocks buried under at least `depth` blocks are assumed to never be reorged (`None` - no
+    /// block is assumed final), so the data they confirm may be cached without their block hash.
+    pub(crate) fn set_final_depth(&mut self, depth: Option<usize>) {
+        self.final_depth = depth;
+    }
+
+    pub(crate) fn final_depth(&self) -> Option<usize> {
+        self.final_depth
+    }
+
+    /// Whether the block at `height` is deep enough to be assumed immutable (see `set_final_depth`)
+    pub(crate) fn is_final(&self, height: usize) -> bool {
+        self.final_depth
+            .is_some_and(|depth| height + depth <= self.height())
+    }
+
+    /// Keep in memory only the last `recent` headers of the indexed chain (see `evict_headers`),
+    /// reading the older ones from `store`.
+    pub(crate) fn set_header_store(&mut self, store: Arc<dyn HeaderStore>, recent: usize) {
+        assert!(recent > 0, "the indexed tip must be kept in memory");
+        self.evicted = Some(Arc::new(Evicted {
+            store,
+            recent,
+            headers: Mutex::new(Lru::new()),
+            heights: Mutex::new(Lru::new()),
+        }));
+    }
+
+    /// Whether `evict_headers()` would evict some headers (e.g. to avoid copying a shared chain).
+    pub(crate) fn has_evictable_headers(&self, indexed_height: usize) -> bool {
+        self.evicted.as_ref().is_some_and(|evicted| {
+            (indexed_height + 1).saturating_sub(evicted.recent) > self.headers.first
+        })
+    }
+
+    /// Evict the headers below the last `recent` ones of the indexed chain (ending at the given
+    /// height and block hash) from memory, if this chain contains its tip (otherwise they may not
+    /// be stored yet).
+    pub(crate) fn evict_headers(&mut self, indexed_height: usize, indexed_tip: BlockHash) {
+        if !self.has_evictable_headers(indexed_height)
+            || self.get_block_hash(indexed_height) != Some(indexed_tip)
+        {
+            return;
+        }
+        let recent = self.evicted.as_ref().expect("missing header store").recent;
+        self.headers.evict(indexed_height + 1 - recent);
+    }
+
     pub(crate) fn drop_last_headers(&mut self, n: usize) {
         if n == 0 {
             return;
         }
         let new_height = self.height().saturating_sub(n);
-        self.update(vec![NewHeader::from((
-            self.headers[new_height].1,
-            new_height,
-        ))]);
+        let header = self.get_block_header(new_height).expect("missing header");
+        self.update(vec![NewHeader::from((header, new_height))]);
     }
 
-    /// Load the chain from a collection of headers, up to the given tip
+    /// Load the chain from a collection of headers, up to the given tip.
+    ///
+    /// If the tip is not connected to genesis via the stored headers (e.g. due to a partially
+    /// written DB), the chain is rolled back to the highest header that is, so the missing
+    /// blocks will be re-indexed by the next sync.
     pub(crate) fn load(&mut self, headers: impl Iterator<Item = BlockHeader>, tip: BlockHash) {
-        let genesis_hash = self.headers[0].0;
+        let genesis_hash = self.get_block_hash(0).expect("missing genesis");
 
         let header_map: HashMap<BlockHash, BlockHeader> =
             headers.map(|h| (h.block_hash(), h)).collect();
@@ -67,48 +531,336 @@ impl Chain {
         while blockhash != genesis_hash {
             let header = match header_map.get(&blockhash) {
                 Some(header) => header,
-                None => panic!("missing header {} while loading from DB", blockhash),
+                None => {
+                    // the missing header is above genesis, so the stored tip is even higher
+                    let tip_height = new_headers.len() + 1;
+                    new_headers = longest_chain(&header_map, genesis_hash);
+                    warn!(
+                        "DB tip {} (height >= {}) is inconsistent with the stored headers \
+                         (missing header {}): rolling back to height {}, \
+                         the following blocks will be re-indexed",
+                        tip,
+                        tip_height,
+                        blockhash,
+                        new_headers.len(),
+                    );
+                    break;
+                }
             };
             blockhash = header.prev_blockhash;
             new_headers.push(header);
         }
+        let tip = new_headers.first().map_or(genesis_hash, |h| h.block_hash());
         info!("loading {} headers, tip={}", new_headers.len(), tip);
         let new_headers = new_headers.into_iter().rev().copied(); // order by height
         self.update(new_headers.zip(1..).map(NewHeader::from).collect())
     }
 
+    /// Load only the last `recent` indexed headers (up to the given tip) from the header store.
+    ///
+    /// Returns `false` if they are missing or inconsistent, so all the headers should be loaded
+    /// via `load()`.
+    pub(crate) fn load_recent(&mut self, tip: BlockHash) -> bool {
+        let evicted = Arc::clone(self.evicted.as_ref().expect("missing header store"));
+        let Some(height) = evicted.store.height_of(&tip) else {
+            return false;
+        };
+        let start = (height + 1).saturating_sub(evicted.recent);
+        let mut new_headers: Vec<NewHeader> = Vec::with_capacity(height + 1 - start);
+        for height in start..=height {
+            let Some(header) = evicted.store.header_at(height) else {
+                return false;
+            };
+            if new_headers
+                .last()
+                .is_some_and(|prev| prev.hash != header.prev_blockhash)
+            {
+                return false;
+            }
+            new_headers.push(NewHeader::from((header, height)));
+        }
+        let genesis_hash = self.get_block_hash(0);
+        if new_headers.last().map(NewHeader::hash) != Some(tip)
+            || (start == 0 && new_headers.first().map(NewHeader::hash) != genesis_hash)
+        {
+            return false;
+        }
+        info!("loading {} recent headers, tip={}", new_headers.len(), tip);
+        self.headers = HeaderList::new(start);
+        self.update(new_headers);
+        true
+    }
+
+    /// Extend the chain by the longest chain of the given headers which is connected to its tip
+    /// (e.g. the headers synced before a restart, whose blocks were not indexed yet).
+    pub(crate) fn extend(&mut self, headers: impl Iterator<Item = BlockHeader>) {
+        let header_map: HashMap<BlockHash, BlockHeader> =
+            headers.map(|h| (h.block_hash(), h)).collect();
+        let new_headers = longest_chain(&header_map, self.tip());
+        let new_headers = new_headers.into_iter().rev().copied(); // order by height
+        self.update(
+            new_headers
+                .zip(self.height() + 1..)
+                .map(NewHeader::from)
+                .collect(),
+        )
+    }
+
+    /// Verify that the new headers are connected to this chain (and to each other), and that
+    /// their proof-of-work is valid for the chain's network (difficulty adjustments are left to
+    /// bitcoind, which sends only the headers it has validated). The proof-of-work of a custom
+    /// chain is not verified if `custom_skip_pow_check` is set.
+    ///
+    /// Their timestamps must also be above the median time past (as required by consensus). A
+    /// timestamp more than 2 hours ahead of the current time is only logged, since it depends on
+    /// the local clock (a skewed one would stall the sync, while bitcoind has already accepted
+    /// the header using its own clock) - the derived times are clamped instead (see `tip_age()`).
+    pub(crate) fn verify(&self, headers: &[NewHeader]) -> Result<()> {
+        self.verify_at(headers, limits::unix_now())
+    }
+
+    /// Like `verify()`, at the given UNIX time (used by tests to control the clock)
+    fn verify_at(&self, headers: &[NewHeader], now: u64) -> Result<()> {
+        let params = Params::new(self.network);
+        let (mut prev, mut times) = match headers.first() {
+            Some(first) => {
+                let prev = self
+                    .get_block_hash(first.height.wrapping_sub(1))
+                    .with_context(|| format!("header {} is above the tip", first.hash))?;
+                let times = (first.height.saturating_sub(MEDIAN_TIME_SPAN)..first.height)
+                    .map(|height| {
+                        let header = self.get_block_header(height).with_context(|| {
+                            format!("missing header at height {} (for its timestamp)", height)
+                        })?;
+                        Ok(header.time)
+                    })
+                    .collect::<Result<VecDeque<u32>>>()?;
+                (prev, times)
+            }
+            None => return Ok(()),
+        };
+        for h in headers {
+            ensure!(
+                h.header.prev_blockhash == prev,
+                "header {} at height {} doesn't point to {}",
+                h.hash,
+                h.height,
+                prev
+            );
+            if self.check_pow {
+                let target = h.header.target();
+                ensure!(
+                    target <= params.max_attainable_target,
+                    "header {} at height {} has a target above the {} limit",
+                    h.hash,
+                    h.height,
+                    self.network
+                );
+                if let Err(e) = h.header.validate_pow(target) {
+                    bail!(
+                        "header {} at height {} has invalid proof-of-work: {}",
+                        h.hash,
+                        h.height,
+                        e
+                    );
+                }
+            }
+            let median_time_past = median(&times);
+            ensure!(
+                h.header.time > median_time_past,
+                "header {} at height {} has timestamp {} (not above the median time past {})",
+                h.hash,
+                h.height,
+                h.header.time,
+                median_time_past
+            );
+            if u64::from(h.header.time) > now + MAX_FUTURE_DRIFT {
+                warn!(
+                    "header {} at height {} has timestamp {} (more than {} seconds in the future: \
+                    is the local clock correct?)",
+                    h.hash, h.height, h.header.time, MAX_FUTURE_DRIFT
+                );
+            }
+            if times.len() == MEDIAN_TIME_SPAN {
+                times.pop_front();
+            }
+            times.push_back(h.header.time);
+            prev = h.hash;
+        }
+        Ok(())
+    }
+
+    /// The height of the last block shared with `other` (e.g. the fork point of a reorg)
+    pub(crate) fn fork_height(&self, other: &Chain) -> usize {
+        let mut height = self.height().min(other.height());
+        while height > 0 && self.get_block_hash(height) != other.get_block_hash(height) {
+            height -= 1;
+        }
+        height
+    }
+
+    /// The headers of this chain after its fork point with `other` (up to `limit` of them), e.g.
+    /// the synced headers whose blocks are not indexed yet.
+    pub(crate) fn new_headers(&self, other: &Chain, limit: usize) -> Vec<NewHeader> {
+        let fork = (0..=self.height().min(other.height()))
+            .rev()
+            .find(|&height| other.get_block_hash(height) == self.get_block_hash(height))
+            .expect("chains with different genesis blocks");
+        (fork + 1..=self.height())
+            .take(limit)
+            .map(|height| {
+                let (hash, header) = self.get(height).expect("missing header");
+                NewHeader {
+                    header,
+                    hash,
+                    height,
+                }
+            })
+            .collect()
+    }
+
+    fn get(&self, height: usize) -> Option<(BlockHash, BlockHeader)> {
+        if height < self.headers.first {
+            return self.evicted.as_ref()?.get(height);
+        }
+        self.headers.get(height).copied()
+    }
+
     /// Get the block hash at specified height (if exists)
     pub(crate) fn get_block_hash(&self, height: usize) -> Option<BlockHash> {
-        self.headers.get(height).map(|(hash, _header)| *hash)
+        self.get(height).map(|(hash, _header)| hash)
     }
 
     /// Get the block header at specified height (if exists)
-    pub(crate) fn get_block_header(&self, height: usize) -> Option<&BlockHeader> {
-        self.headers.get(height).map(|(_hash, header)| header)
+    pub(crate) fn get_block_header(&self, height: usize) -> Option<BlockHeader> {
+        self.get(height).map(|(_hash, header)| header)
+    }
+
+    /// Get the block headers at the specified heights (truncated to the available ones)
+    pub(crate) fn get_block_headers(
+        &self,
+        heights: Range<usize>,
+    ) -> impl ExactSizeIterator<Item = BlockHeader> + '_ {
+        let end = heights.end.min(self.height() + 1);
+        let start = heights.start.min(end);
+        (start..end).map(|height| self.get_block_header(height).expect("missing header"))
+    }
+
+    /// Serialize the block headers at the specified heights (truncated to the available ones)
+    /// into a single hex string, allocated only once.
+    pub(crate) fn get_block_headers_hex(&self, heights: Range<usize>) -> String {
+        let headers = self.get_block_headers(heights);
+        let mut result = String::with_capacity(headers.len() * BlockHeader::SIZE * 2);
+        let mut buf = [0u8; BlockHeader::SIZE];
+        for header in headers {
+            header
+                .consensus_encode(&mut &mut buf[..])
+                .expect("in-memory writers don't error");
+            write!(result, "{:x}", buf.as_hex()).expect("in-memory writers don't error");
+        }
+        result
+    }
+
+    /// Merkle proof for the block hash at `height`, within the tree of the block hashes up to
+    /// `cp_height` (see `cp_height` in Electrum protocol). Returns the proof and the tree's root.
+    pub(crate) fn header_proof(
+        &self,
+        height: usize,
+        cp_height: usize,
+    ) -> Option<(Proof, TxMerkleNode)> {
+        if height > cp_height || cp_height > self.height() {
+            return None;
+        }
+        let cp_hash = self.get_block_hash(cp_height)?;
+        let tree = self.header_tree(cp_height, cp_hash)?;
+        let subtree = tree.subtree(height);
+        let mut hashes = Vec::with_capacity(subtree.len());
+        if !self.for_each_block_hash(subtree, &mut |hash| hashes.push(hash)) {
+            return None;
+        }
+        Some(tree.proof(height, hashes))
+    }
+
+    /// The merkle tree of the block hashes up to `cp_height` (cached for the next proofs)
+    fn header_tree(&self, cp_height: usize, cp_hash: BlockHash) -> Option<Arc<UpperTree>> {
+        if let Some(cached) = self.last_header_tree.lock().as_ref() {
+            if cached.cp_hash == cp_hash {
+                return Some(Arc::clone(&cached.tree));
+            }
+        }
+        let tree = UpperTree::new(cp_height + 1, |func| {
+            self.for_each_block_hash(0..cp_height + 1, func);
+        })?;
+        let tree = Arc::new(tree);
+        *self.last_header_tree.lock() = Some(HeaderTree {
+            cp_hash,
+            tree: Arc::clone(&tree),
+        });
+        Some(tree)
+    }
+
+    /// Call `func` for each block hash at `heights` (as a merkle tree's leaf), returning whether
+    /// all of them are found.
+    fn for_each_block_hash(
+        &self,
+        heights: Range<usize>,
+        func: &mut dyn FnMut(TxMerkleNode),
+    ) -> bool {
+        let to_node = |hash: BlockHash| TxMerkleNode::from_raw_hash(hash.to_raw_hash());
+        let evicted_end = self.headers.first.clamp(heights.start, heights.end);
+        if heights.start < evicted_end {
+            // slower path: the evicted headers are read (and hashed) in bulk
+            let evicted = self.evicted.as_ref().expect("missing header store");
+            let mut count = 0;
+            evicted
+                .store
+                .for_each_header(heights.start..evicted_end, &mut |header| {
+                    count += 1;
+                    func(to_node(header.block_hash()))
+                });
+            if count != evicted_end - heights.start {
+                warn!("missing stored headers below height {}", evicted_end);
+                return false;
+            }
+        }
+        for height in evicted_end..heights.end {
+            let (hash, _header) = self.headers.get(height).expect("missing header");
+            func(to_node(*hash));
+        }
+        true
     }
 
     /// Get the block height given the specified hash (if exists)
     pub(crate) fn get_block_height(&self, blockhash: &BlockHash) -> Option<usize> {
-        self.heights.get(blockhash).copied()
+        match self.headers.height(blockhash) {
+            Some(height) => Some(height),
+            None => self
+                .evicted
+                .as_ref()?
+                .height(blockhash)
+                .filter(|height| *height < self.headers.first),
+        }
     }
 
     /// Update the chain with a list of new headers (possibly a reorg)
     pub(crate) fn update(&mut self, headers: Vec<NewHeader>) {
         if let Some(first_height) = headers.first().map(|h| h.height) {
-            for (hash, _header) in self.headers.drain(first_height..) {
-                assert!(self.heights.remove(&hash).is_some());
+            if first_height < self.headers.first {
+                // a reorg below the in-memory headers
+                self.headers = HeaderList::new(first_height);
+                if let Some(evicted) = &self.evicted {
+                    evicted.invalidate(first_height);
+                }
             }
+            self.headers.truncate(first_height);
             for (h, height) in headers.into_iter().zip(first_height..) {
                 assert_eq!(h.height, height);
                 assert_eq!(h.hash, h.header.block_hash());
-                assert!(self.heights.insert(h.hash, h.height).is_none());
-                self.headers.push((h.hash, h.header));
+                self.headers.push(h.hash, h.header);
             }
-            info!(
-                "chain updated: tip={}, height={}",
-                self.headers.last().unwrap().0,
-                self.headers.len() - 1
-            );
+            let (hash, header) = self.headers.last().expect("empty chain");
+            self.tip_header = TipHeader::new(self.height(), *hash, header);
+            info!("chain updated: tip={}, height={}", hash, self.height());
         }
     }
 
@@ -117,22 +869,39 @@ impl Chain {
         self.headers.last().expect("empty chain").0
     }
 
+    /// Best block's height and serialized header
+    pub(crate) fn tip_header(&self) -> &TipHeader {
+        &self.tip_header
+    }
+
+    /// Seconds since the best block's timestamp at the given UNIX time (0 if the timestamp is in
+    /// the future, which is allowed by consensus for up to 2 hours)
+    pub(crate) fn tip_age(&self, now: u64) -> u64 {
+        let (_hash, header) = self.headers.last().expect("empty chain");
+        now.saturating_sub(header.time.into())
+    }
+
+    /// Best block's timestamp, clamped to the given UNIX time
+    pub(crate) fn tip_time(&self, now: u64) -> u64 {
+        now - self.tip_age(now)
+    }
+
     /// Number of blocks (excluding genesis block)
     pub(crate) fn height(&self) -> usize {
-        self.headers.len() - 1
+        self.headers.end - 1
     }
 
     /// List of block hashes for efficient fork detection and block/header sync
     /// see https://en.bitcoin.it/wiki/Protocol_documentation#getblocks
     pub(crate) fn locator(&self) -> Vec<BlockHash> {
         let mut result = vec![];
-        let mut index = self.headers.len() - 1;
+        let mut index = self.height();
         let mut step = 1;
         loop {
             if result.len() >= 10 {
                 step *= 2;
             }
-            result.push(self.headers[index].0);
+            result.push(self.get_block_hash(index).expect("missing header"));
             if index == 0 {
                 break;
             }
@@ -142,13 +911,204 @@ impl Chain {
     }
 }
 
+/// A read-only snapshot of the indexed chain.
+///
+/// The indexer publishes a new snapshot after indexing each batch of blocks, so holding a view
+/// doesn't block it (and the view is not modified by it).
+#[derive(Clone)]
+pub struct ChainView {
+    chain: Arc<Chain>,
+}
+
+impl ChainView {
+    pub(crate) fn new(chain: Arc<Chain>) -> Self {
+        Self { chain }
+    }
+
+    /// Best block's height and hash
+    pub fn tip(&self) -> (usize, BlockHash) {
+        (self.chain.height(), self.chain.tip())
+    }
+
+    /// The block header at the specified height (if exists)
+    pub fn header_at(&self, height: usize) -> Option<BlockHeader> {
+        self.chain.get_block_header(height)
+    }
+
+    /// The height of the specified block (if it is part of the chain)
+    pub fn height_of(&self, blockhash: &BlockHash) -> Option<usize> {
+        self.chain.get_block_height(blockhash)
+    }
+
+    /// The last `n` blocks' heights, hashes and headers (starting from the tip)
+    pub fn iter_recent(
+        &self,
+        n: usize,
+    ) -> impl Iterator<Item = (usize, BlockHash, BlockHeader)> + '_ {
+        let end = self.chain.height() + 1;
+        (end.saturating_sub(n)..end).rev().map(|height| {
+            let (hash, header) = self.chain.get(height).expect("missing header");
+            (height, hash, header)
+        })
+    }
+}
+
+/// The median of the given timestamps (as computed by bitcoind, 0 if there are none)
+fn median(times: &VecDeque<u32>) -> u32 {
+    let mut sorted: Vec<u32> = times.iter().copied().collect();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied().unwrap_or(0)
+}
+
+/// Find the longest chain of headers connected to `root` (ordered from its tip, excluding `root`)
+fn longest_chain(
+    header_map: &HashMap<BlockHash, BlockHeader>,
+    root: BlockHash,
+) -> Vec<&BlockHeader> {
+    let mut children: HashMap<BlockHash, Vec<&BlockHeader>> = HashMap::new();
+    for header in header_map.values() {
+        children
+            .entry(header.prev_blockhash)
+            .or_default()
+            .push(header);
+    }
+    let mut best: Option<(usize, &BlockHeader)> = None;
+    let mut stack: Vec<(usize, &BlockHeader)> = children
+        .get(&root)
+        .into_iter()
+        .flatten()
+        .map(|header| (1, *header))
+        .collect();
+    while let Some((height, header)) = stack.pop() {
+        if best.is_none_or(|(best_height, _)| height > best_height) {
+            best = Some((height, header));
+        }
+        let next = children.get(&header.block_hash()).into_iter().flatten();
+        stack.extend(next.map(|child| (height + 1, *child)));
+    }
+    let mut result = Vec::with_capacity(best.map_or(0, |(height, _)| height));
+    let mut next = best.map(|(_, header)| header);
+    while let Some(header) = next {
+        result.push(header);
+        next = match header.prev_blockhash {
+            prev if prev == root => None,
+            prev => header_map.get(&prev),
+        };
+    }
+    result
+}
+
+/// Stores the headers of the indexed chain (like `DBStore`), used by tests and benchmarks
+#[cfg(any(test, feature = "bench"))]
+#[derive(Default)]
+pub(crate) struct MapStore {
+    headers: parking_lot::RwLock<std::collections::BTreeMap<usize, BlockHeader>>,
+    heights: parking_lot::RwLock<HashMap<BlockHash, usize>>,
+}
+
+#[cfg(any(test, feature = "bench"))]
+impl MapStore {
+    pub(crate) fn write(&self, headers: &[NewHeader]) {
+        for h in headers {
+            self.headers.write().insert(h.height, h.header);
+            self.heights.write().insert(h.hash, h.height);
+        }
+    }
+}
+
+#[cfg(any(test, feature = "bench"))]
+impl HeaderStore for MapStore {
+    fn header_at(&self, height: usize) -> Option<BlockHeader> {
+        self.headers.read().get(&height).copied()
+    }
+
+    fn height_of(&self, blockhash: &BlockHash) -> Option<usize> {
+        self.heights.read().get(blockhash).copied()
+    }
+
+    fn for_each_header(&self, heights: Range<usize>, func: &mut dyn FnMut(BlockHeader)) {
+        self.headers
+            .read()
+            .range(heights)
+            .for_each(|(_, h)| func(*h));
+    }
+}
+
+/// A regtest chain of `n` synthetic headers (used by tests and benchmarks)
+#[cfg(any(test, feature = "bench"))]
+pub(crate) fn synthetic_chain(n: u32) -> Chain {
+    let mut chain = Chain::new(Network::Regtest);
+    let mut prev = chain.get_block_header(0).unwrap();
+    let headers = (1..=n).map(|nonce| {
+        prev = BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            nonce,
+            ..prev
+        };
+        NewHeader::from((prev, nonce as usize))
+    });
+    chain.update(headers.collect());
+    chain
+}
+
+/// Synthetic headers (and a header store holding them), used by `tests/memory.rs` and
+/// `benches/headers.rs`
+#[cfg(feature = "bench")]
+pub struct BenchHeaders {
+    full: Chain,
+    store: Arc<MapStore>,
+}
+
+#[cfg(feature = "bench")]
+pub fn bench_headers(count: u32) -> BenchHeaders {
+    let full = synthetic_chain(count);
+    let store = Arc::new(MapStore::default());
+    store.write(&full.new_headers(&Chain::new(Network::Regtest), usize::MAX));
+    BenchHeaders { full, store }
+}
+
+/// Sync a new chain with `headers` (2000 headers at a time, like the index), keeping only the
+/// last `recent` ones in memory (if set). The chain is returned, so its memory can be measured.
+#[cfg(feature = "bench")]
+pub fn bench_sync_headers(headers: &BenchHeaders, recent: Option<usize>) -> Box<dyn std::any::Any> {
+    let mut chain = Chain::new(Network::Regtest);
+    if let Some(recent) = recent {
+        chain.set_header_store(Arc::clone(&headers.store) as _, recent);
+    }
+    loop {
+        let new_headers = headers.full.new_headers(&chain, 2000);
+        if new_headers.is_empty() {
+            break Box::new(chain);
+        }
+        chain.update(new_headers);
+        chain.evict_headers(chain.height(), chain.tip());
+    }
+}
+
+/// Serialize the hex of 2016 headers starting at `start_height` (like a `blockchain.block.headers`
+/// response), returning its length.
+#[cfg(feature = "bench")]
+pub fn bench_headers_hex(headers: &BenchHeaders, start_height: usize) -> usize {
+    headers
+        .full
+        .get_block_headers_hex(start_height..start_height + 2016)
+        .len()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Chain, NewHeader};
+    use super::{
+        synthetic_chain, Chain, ChainView, Checkpoints, MapStore, NewHeader, HEIGHT_SHARDS,
+    };
+    use crate::merkle::Proof;
     use bitcoin::blockdata::block::Header as BlockHeader;
-    use bitcoin::consensus::deserialize;
+    use bitcoin::consensus::{deserialize, encode::serialize_hex};
+    use bitcoin::hashes::Hash;
+    use bitcoin::BlockHash;
     use bitcoin::Network::Regtest;
     use hex_lit::hex;
+    use serde_json::json;
+    use std::sync::Arc;
 
     #[test]
     fn test_genesis() {
@@ -195,6 +1155,11 @@ hex!("000000200030d7f9c11ef35b89a0eefb9a5e449909339b5e7854d99804ea8d6a49bf900a03
                 regtest.update(update);
                 assert_eq!(regtest.tip(), tip);
                 assert_eq!(regtest.height(), height);
+                assert_eq!(regtest.tip_header().hash(), tip);
+                assert_eq!(
+                    regtest.tip_header().to_json(),
+                    json!({"height": height, "hex": serialize_hex(chunk.last().unwrap())})
+                );
             }
             assert_eq!(regtest.tip(), headers.last().unwrap().block_hash());
             assert_eq!(regtest.height(), headers.len());
@@ -210,17 +1175,27 @@ hex!("000000200030d7f9c11ef35b89a0eefb9a5e449909339b5e7854d99804ea8d6a49bf900a03
 
         // test getters
         for (header, height) in headers.iter().zip(1usize..) {
-            assert_eq!(regtest.get_block_header(height), Some(header));
+            assert_eq!(regtest.get_block_header(height), Some(*header));
             assert_eq!(regtest.get_block_hash(height), Some(header.block_hash()));
             assert_eq!(regtest.get_block_height(&header.block_hash()), Some(height));
         }
 
+        let range: Vec<_> = regtest.get_block_headers(3..6).collect();
+        assert_eq!(range, headers[2..5].to_vec());
+        assert_eq!(regtest.get_block_headers(9..100).count(), 2); // truncated
+        assert_eq!(regtest.get_block_headers(100..200).count(), 0);
+
         // test chain shortening
         for i in (0..=headers.len()).rev() {
             let hash = regtest.get_block_hash(i).unwrap();
             assert_eq!(regtest.get_block_height(&hash), Some(i));
             assert_eq!(regtest.height(), i);
             assert_eq!(regtest.tip(), hash);
+            let header = regtest.get_block_header(i).unwrap();
+            assert_eq!(
+                regtest.tip_header().to_json(),
+                json!({"height": i, "hex": serialize_hex(&header)})
+            );
             regtest.drop_last_headers(1);
         }
         assert_eq!(regtest.height(), 0);
@@ -258,4 +1233,547 @@ hex!("000000200030d7f9c11ef35b89a0eefb9a5e449909339b5e7854d99804ea8d6a49bf900a03
                 .unwrap()
         );
     }
+
+    /// A chain of `n` synthetic headers (linked by their `prev_blockhash`)
+    #[test]
+    fn test_heights_on_reorg() {
+        let mut chain = synthetic_chain(10);
+        let stale: Vec<_> = (8..=10).map(|h| chain.get_block_hash(h).unwrap()).collect();
+
+        // replace the last 3 blocks by 2 other blocks
+        let mut prev = chain.get_block_header(7).unwrap();
+        let new_headers: Vec<_> = (8..=9)
+            .map(|height| {
+                prev = BlockHeader {
+                    prev_blockhash: prev.block_hash(),
+                    nonce: 100 + height as u32,
+                    ..prev
+                };
+                NewHeader::from((prev, height))
+            })
+            .collect();
+        let new_hashes: Vec<_> = new_headers.iter().map(NewHeader::hash).collect();
+        chain.update(new_headers);
+        assert_eq!(chain.height(), 9);
+        assert_eq!(chain.headers.count_heights(), chain.headers.len()); // stale blocks are removed
+        for hash in &stale {
+            assert_eq!(chain.get_block_height(hash), None);
+        }
+        assert_eq!(chain.get_block_height(&new_hashes[0]), Some(8));
+        assert_eq!(chain.get_block_height(&new_hashes[1]), Some(9));
+
+        // a published view is not affected by later updates
+        let view = ChainView::new(std::sync::Arc::new(chain.clone()));
+        chain.drop_last_headers(5);
+        assert_eq!(chain.height(), 4);
+        assert_eq!(chain.headers.count_heights(), 5);
+        assert_eq!(chain.get_block_height(&new_hashes[0]), None);
+        assert_eq!(view.tip(), (9, new_hashes[1]));
+        assert_eq!(view.height_of(&new_hashes[0]), Some(8));
+        assert_eq!(view.height_of(&stale[0]), None);
+        assert_eq!(
+            view.header_at(9).map(|h| h.block_hash()),
+            Some(new_hashes[1])
+        );
+        assert_eq!(view.header_at(10), None);
+    }
+
+    #[test]
+    fn test_shared_headers() {
+        let chain = synthetic_chain(5000); // in 3 chunks
+        let tip = chain.get_block_header(5000).unwrap();
+        let header = BlockHeader {
+            prev_blockhash: chain.tip(),
+            nonce: 0,
+            ..tip
+        };
+        let mut clone = chain.clone();
+        clone.update(vec![NewHeader::from((header, 5001))]);
+        assert_eq!(clone.get_block_height(&header.block_hash()), Some(5001));
+        assert_eq!(chain.get_block_height(&header.block_hash()), None);
+        assert_eq!(chain.height(), 5000);
+
+        // only the last chunk and a single shard of heights are copied
+        let (a, b) = (&chain.headers, &clone.headers);
+        let shared_chunks = a.chunks.iter().zip(&b.chunks);
+        let shared_heights = a.heights.iter().zip(&b.heights);
+        assert_eq!(shared_chunks.filter(|(x, y)| Arc::ptr_eq(x, y)).count(), 2);
+        assert_eq!(
+            shared_heights.filter(|(x, y)| Arc::ptr_eq(x, y)).count(),
+            HEIGHT_SHARDS - 1
+        );
+    }
+
+    /// Mine `n` headers with a valid (regtest) proof-of-work on top of `height`, each `step`
+    /// seconds after its previous header
+    fn mine(chain: &Chain, height: usize, n: usize, step: u32) -> Vec<NewHeader> {
+        let mut prev = chain.get_block_header(height).unwrap();
+        (height + 1..=height + n)
+            .map(|height| {
+                prev = BlockHeader {
+                    prev_blockhash: prev.block_hash(),
+                    time: prev.time + step,
+                    nonce: 0,
+                    ..prev
+                };
+                while prev.validate_pow(prev.target()).is_err() {
+                    prev.nonce += 1;
+                }
+                NewHeader::from((prev, height))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_headers() {
+        let chain = Chain::new(Regtest);
+        let headers = mine(&chain, 0, 5, 1);
+        chain.verify(&headers).unwrap();
+        chain.verify(&[]).unwrap();
+
+        let mut chain = chain;
+        chain.update(headers);
+        chain.verify(&mine(&chain, 5, 2, 1)).unwrap();
+        chain.verify(&mine(&chain, 3, 3, 2)).unwrap(); // a reorg
+
+        // invalid proof-of-work
+        let mut invalid = mine(&chain, 5, 3, 1);
+        let header = &mut invalid[1].header;
+        while header.validate_pow(header.target()).is_ok() {
+            header.nonce += 1;
+        }
+        invalid[1] = NewHeader::from((*header, 7));
+        let err = chain.verify(&invalid).unwrap_err().to_string();
+        assert!(
+            err.contains("at height 7 has invalid proof-of-work"),
+            "{}",
+            err
+        );
+
+        // the target is above regtest's limit
+        let mut easy = mine(&chain, 5, 1, 1);
+        easy[0].header.bits = bitcoin::CompactTarget::from_consensus(0x2100ffff);
+        easy[0] = NewHeader::from((easy[0].header, 6));
+        let err = chain.verify(&easy).unwrap_err().to_string();
+        assert!(
+            err.contains("has a target above the regtest limit"),
+            "{}",
+            err
+        );
+
+        // headers must be connected to the chain, and to each other
+        let mut unlinked = mine(&chain, 5, 3, 1);
+        unlinked.remove(1);
+        let err = chain.verify(&unlinked).unwrap_err().to_string();
+        assert!(err.contains("at height 8 doesn't point to"), "{}", err);
+        let other = mine(&chain, 4, 1, 3);
+        let mut disconnected = mine(&chain, 5, 1, 1);
+        disconnected[0] = NewHeader::from((other[0].header, 6));
+        let err = chain.verify(&disconnected).unwrap_err().to_string();
+        assert!(err.contains("at height 6 doesn't point to"), "{}", err);
+        let above = vec![NewHeader::from((other[0].header, 10))];
+        assert!(chain
+            .verify(&above)
+            .unwrap_err()
+            .to_string()
+            .contains("is above the tip"));
+    }
+
+    #[test]
+    fn test_custom_genesis() {
+        let mut genesis = bitcoin::constants::genesis_block(Regtest).header;
+        genesis.time += 1;
+        let mut chain = Chain::with_genesis(Regtest, genesis);
+        assert_eq!(chain.height(), 0);
+        assert_eq!(chain.tip(), genesis.block_hash());
+        assert_ne!(chain.tip(), Chain::new(Regtest).tip());
+
+        let mut header = mine(&chain, 0, 1, 1)[0].header;
+        while header.validate_pow(header.target()).is_ok() {
+            header.nonce += 1;
+        }
+        let invalid = vec![NewHeader::from((header, 1))];
+        assert!(chain.verify(&invalid).is_err());
+
+        // the proof-of-work check may be disabled for custom chains
+        chain.check_pow = false;
+        chain.verify(&invalid).unwrap();
+        chain.update(invalid);
+        assert_eq!(chain.tip(), header.block_hash());
+    }
+
+    /// Re-mine the header at `index` with the given timestamp (relinking the following headers)
+    fn retime(headers: &mut [NewHeader], index: usize, time: u32) {
+        headers[index].header.time = time;
+        for i in index..headers.len() {
+            let mut header = headers[i].header;
+            if i > index {
+                header.prev_blockhash = headers[i - 1].hash;
+            }
+            while header.validate_pow(header.target()).is_err() {
+                header.nonce += 1;
+            }
+            headers[i] = NewHeader::from((header, headers[i].height));
+        }
+    }
+
+    #[test]
+    fn test_verify_timestamps() {
+        let mut chain = Chain::new(Regtest);
+        chain.update(mine(&chain, 0, 20, 600));
+        let tip_time = chain.get_block_header(20).unwrap().time;
+        let now = u64::from(tip_time) + 300;
+        chain.verify_at(&mine(&chain, 20, 3, 1), now).unwrap();
+
+        // the median time past is the timestamp 6 blocks back (of the 11 last ones)
+        let median_time_past = chain.get_block_header(15).unwrap().time;
+        let mut headers = mine(&chain, 20, 3, 1);
+        retime(&mut headers, 0, median_time_past + 1); // backwards, but allowed
+        chain.verify_at(&headers, now).unwrap();
+        retime(&mut headers, 0, median_time_past);
+        let err = chain.verify_at(&headers, now).unwrap_err().to_string();
+        assert!(
+            err.contains("at height 21 has timestamp") && err.contains("median time past"),
+            "{}",
+            err
+        );
+
+        // the median time past includes the previous new headers
+        let mut headers = mine(&chain, 20, 12, 1000);
+        let now = u64::from(headers[11].header.time);
+        chain.verify_at(&headers, now).unwrap();
+        let median_time_past = headers[5].header.time;
+        retime(&mut headers, 11, median_time_past);
+        let err = chain.verify_at(&headers, now).unwrap_err().to_string();
+        assert!(err.contains("at height 32 has timestamp"), "{}", err);
+
+        // future timestamps are only logged (the local clock may be skewed)
+        let now = u64::from(tip_time);
+        let mut headers = mine(&chain, 20, 2, 1);
+        retime(&mut headers, 1, tip_time + 2 * 60 * 60);
+        chain.verify_at(&headers, now).unwrap();
+        retime(&mut headers, 1, tip_time + 2 * 60 * 60 + 1);
+        chain.verify_at(&headers, now).unwrap();
+        chain.verify_at(&headers, 0).unwrap(); // e.g. a clock reset to 1970
+
+        // a future-dated tip is clamped by the derived values
+        retime(&mut headers, 1, tip_time + 60 * 60);
+        chain.update(headers);
+        assert_eq!(chain.tip_age(now), 0);
+        assert_eq!(chain.tip_time(now), now);
+        assert_eq!(chain.tip_age(now + 2 * 60 * 60), 60 * 60);
+        assert_eq!(chain.tip_time(now + 2 * 60 * 60), now + 60 * 60);
+    }
+
+    #[test]
+    fn test_verify_missing_timestamps() {
+        let (chain, store) = evicted_chain(&synthetic_chain(100), 10);
+        store.headers.write().clear(); // e.g. a corrupted DB
+        let headers = mine(&chain, 94, 2, 1);
+        let err = chain.verify(&headers).unwrap_err().to_string();
+        assert!(err.contains("missing header at height 84"), "{}", err);
+    }
+
+    #[test]
+    fn test_new_headers() {
+        let mut chain = Chain::new(Regtest);
+        chain.update(mine(&chain, 0, 5, 1));
+        let mut headers = chain.clone();
+        headers.update(mine(&headers, 3, 4, 2)); // a reorg of 2 blocks, extending the chain by 2
+
+        let new: Vec<_> = headers.new_headers(&chain, 100);
+        let heights: Vec<_> = new.iter().map(NewHeader::height).collect();
+        assert_eq!(heights, vec![4, 5, 6, 7]);
+        assert_eq!(new[0].hash(), headers.get_block_hash(4).unwrap());
+        assert_eq!(headers.new_headers(&chain, 2).len(), 2); // limited
+        assert!(chain.new_headers(&chain, 100).is_empty());
+        assert_eq!(chain.new_headers(&headers, 100).len(), 2); // the stale blocks
+
+        // extend by the longest chain of the given headers which is connected to the tip
+        let mut extended = chain.clone();
+        let stale = mine(&chain, 5, 3, 3).into_iter().map(|h| h.header);
+        let unrelated = mine(&headers, 7, 2, 3).into_iter().map(|h| h.header);
+        let longest = mine(&chain, 5, 4, 4);
+        let tip = longest.last().unwrap().hash();
+        extended.extend(
+            stale
+                .chain(unrelated)
+                .chain(longest.into_iter().map(|h| h.header)),
+        );
+        assert_eq!(extended.height(), 9);
+        assert_eq!(extended.tip(), tip);
+        assert_eq!(extended.get_block_hash(5), chain.get_block_hash(5));
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut chain = Chain::new(Regtest);
+        chain.update(mine(&chain, 0, 5, 1));
+        let fork = mine(&chain, 2, 3, 2);
+        let hash = |height| chain.get_block_hash(height).unwrap();
+
+        let checkpoints = Checkpoints::new(vec![(4, hash(4)), (2, hash(2)), (10, fork[0].hash())]);
+        assert_eq!(checkpoints.contradicted_by(&chain), None); // above the tip
+        assert_eq!(
+            checkpoints.contradicted_by_headers(&fork),
+            Some((4, hash(4), fork[1].hash()))
+        );
+        assert_eq!(checkpoints.contradicted_by_headers(&fork[..1]), None);
+        assert_eq!(checkpoints.contradicted_by_headers(&[]), None);
+
+        let mut forked = chain.clone();
+        forked.update(fork);
+        assert_eq!(
+            checkpoints.contradicted_by(&forked),
+            Some((4, hash(4), forked.get_block_hash(4).unwrap()))
+        );
+        assert_eq!(Checkpoints::default().contradicted_by(&forked), None);
+
+        assert_eq!(Checkpoints::default().to_string(), "none");
+        let checkpoints = Checkpoints::new(vec![(2, hash(2))]);
+        assert_eq!(checkpoints.to_string(), format!("2:{}", hash(2)));
+
+        let mainnet = Checkpoints::for_network(bitcoin::Network::Bitcoin);
+        assert!(mainnet.to_string().starts_with(
+            "11111:0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d,"
+        ));
+        assert_eq!(Checkpoints::for_network(Regtest), Checkpoints::default());
+    }
+
+    #[test]
+    fn test_iter_recent() {
+        let view = ChainView::new(std::sync::Arc::new(synthetic_chain(5)));
+        let recent: Vec<_> = view.iter_recent(3).map(|(height, ..)| height).collect();
+        assert_eq!(recent, vec![5, 4, 3]);
+        for (height, hash, header) in view.iter_recent(3) {
+            assert_eq!(header.block_hash(), hash);
+            assert_eq!(view.height_of(&hash), Some(height));
+        }
+        assert_eq!(view.iter_recent(100).count(), 6); // including genesis
+        assert_eq!(view.iter_recent(0).count(), 0);
+    }
+
+    #[test]
+    fn test_final_depth() {
+        let mut chain = synthetic_chain(10);
+        assert!(!chain.is_final(0)); // by default, no block is final
+        chain.set_final_depth(Some(6));
+        assert!(chain.is_final(0));
+        assert!(chain.is_final(4));
+        assert!(!chain.is_final(5));
+        assert!(!chain.is_final(10));
+        assert!(!chain.is_final(11)); // beyond the tip
+
+        // the depth is kept when the chain is copied and updated
+        let mut copy = chain.clone();
+        copy.drop_last_headers(2);
+        assert!(copy.is_final(2));
+        assert!(!copy.is_final(3));
+    }
+
+    #[test]
+    fn test_headers_hex() {
+        let chain = synthetic_chain(10);
+        let hex = |height| serialize_hex(&chain.get_block_header(height).unwrap());
+
+        assert_eq!(chain.get_block_headers_hex(0..1), hex(0)); // genesis
+        assert_eq!(chain.get_block_headers_hex(0..2), hex(0) + &hex(1));
+        assert_eq!(chain.get_block_headers_hex(9..11), hex(9) + &hex(10)); // up to the tip
+        assert_eq!(chain.get_block_headers_hex(10..2016), hex(10)); // truncated at the tip
+        assert_eq!(chain.get_block_headers_hex(11..12), "");
+        assert_eq!(chain.get_block_headers_hex(5..5), "");
+        let all = chain.get_block_headers_hex(0..11);
+        assert_eq!(all.len(), 11 * 160);
+        assert_eq!(all.capacity(), all.len()); // allocated once
+    }
+
+    #[test]
+    fn test_header_proof() {
+        use bitcoin::{merkle_tree, TxMerkleNode};
+
+        let chain = synthetic_chain(10);
+        for cp_height in 0..=10 {
+            let hashes = (0..=cp_height).map(|height| {
+                TxMerkleNode::from_raw_hash(chain.get_block_hash(height).unwrap().to_raw_hash())
+            });
+            let expected_root = merkle_tree::calculate_root(hashes).unwrap();
+            for height in 0..=cp_height {
+                let (proof, root) = chain.header_proof(height, cp_height).unwrap();
+                assert_eq!(root, expected_root);
+                assert_eq!(proof.position(), height);
+            }
+            assert!(chain.header_proof(cp_height + 1, cp_height).is_none());
+        }
+        assert!(chain.header_proof(0, 11).is_none()); // beyond the tip
+        let (proof, root) = chain.header_proof(0, 0).unwrap(); // genesis only
+        assert!(proof.to_hex().is_empty());
+        assert_eq!(
+            root.to_raw_hash(),
+            chain.get_block_hash(0).unwrap().to_raw_hash()
+        );
+    }
+
+    #[test]
+    fn test_header_proof_cache() {
+        use bitcoin::{merkle_tree, TxMerkleNode};
+
+        let expected_root = |chain: &Chain, cp_height| {
+            let hashes = (0..=cp_height).map(|height| {
+                TxMerkleNode::from_raw_hash(chain.get_block_hash(height).unwrap().to_raw_hash())
+            });
+            merkle_tree::calculate_root(hashes).unwrap()
+        };
+        let mut chain = synthetic_chain(200);
+        let cp_hash = chain.get_block_hash(150).unwrap();
+        let tree = chain.header_tree(150, cp_hash).unwrap();
+        // shared by the chain's clones
+        let clone = chain.clone();
+        assert!(Arc::ptr_eq(
+            &tree,
+            &clone.header_tree(150, cp_hash).unwrap()
+        ));
+        for height in [0, 63, 64, 100, 150] {
+            let (proof, root) = clone.header_proof(height, 150).unwrap();
+            let (expected_proof, _) = Proof::with_root(
+                (0..=150)
+                    .map(|h| {
+                        TxMerkleNode::from_raw_hash(chain.get_block_hash(h).unwrap().to_raw_hash())
+                    })
+                    .collect(),
+                height,
+            );
+            assert_eq!(root, expected_root(&chain, 150));
+            assert_eq!(proof.to_hex(), expected_proof.to_hex());
+        }
+
+        // a reorg above `cp_height` keeps the tree
+        chain.update(mine(&chain, 180, 30, 1));
+        assert!(Arc::ptr_eq(
+            &tree,
+            &chain.header_tree(150, cp_hash).unwrap()
+        ));
+
+        // a reorg below it doesn't
+        chain.update(mine(&chain, 100, 120, 1));
+        let (_proof, root) = chain.header_proof(120, 150).unwrap();
+        assert_eq!(root, expected_root(&chain, 150));
+        assert_ne!(root, expected_root(&clone, 150));
+    }
+
+    /// A chain keeping only the last `recent` headers of `full` in memory
+    fn evicted_chain(full: &Chain, recent: usize) -> (Chain, Arc<MapStore>) {
+        let store = Arc::new(MapStore::default());
+        let mut chain = Chain::new(Regtest);
+        chain.set_header_store(Arc::clone(&store) as _, recent);
+        let new_headers = full.new_headers(&chain, usize::MAX);
+        store.write(&[NewHeader::from((chain.get_block_header(0).unwrap(), 0))]);
+        store.write(&new_headers);
+        chain.update(new_headers);
+        chain.evict_headers(full.height(), full.tip());
+        (chain, store)
+    }
+
+    fn assert_same_chain(chain: &Chain, full: &Chain) {
+        assert_eq!(chain.height(), full.height());
+        assert_eq!(chain.tip(), full.tip());
+        for height in 0..=full.height() + 1 {
+            let hash = full.get_block_hash(height);
+            assert_eq!(chain.get_block_hash(height), hash);
+            assert_eq!(
+                chain.get_block_header(height),
+                full.get_block_header(height)
+            );
+            if let Some(hash) = hash {
+                assert_eq!(chain.get_block_height(&hash), Some(height));
+            }
+        }
+        let all = 0..full.height() + 1;
+        assert!(chain
+            .get_block_headers(all.clone())
+            .eq(full.get_block_headers(all.clone())));
+        assert_eq!(
+            chain.get_block_headers_hex(all.clone()),
+            full.get_block_headers_hex(all)
+        );
+        for (height, cp_height) in [
+            (0, 0),
+            (5, 50),
+            (5, full.height()),
+            (full.height(), full.height()),
+        ] {
+            let (proof, root) = chain.header_proof(height, cp_height).unwrap();
+            let (expected_proof, expected_root) = full.header_proof(height, cp_height).unwrap();
+            assert_eq!(root, expected_root);
+            assert_eq!(proof.to_hex(), expected_proof.to_hex());
+        }
+        assert_eq!(chain.locator(), full.locator());
+        let view = ChainView::new(Arc::new(chain.clone()));
+        let full_view = ChainView::new(Arc::new(full.clone()));
+        assert!(view.iter_recent(30).eq(full_view.iter_recent(30)));
+    }
+
+    #[test]
+    fn test_evicted_headers() {
+        let full = synthetic_chain(100);
+        let (mut chain, store) = evicted_chain(&full, 10);
+        assert_eq!(chain.headers.first, 91);
+        assert_eq!(chain.headers.len(), 10);
+        assert_eq!(chain.headers.count_heights(), 10);
+        assert_same_chain(&chain, &full);
+        assert_eq!(chain.get_block_height(&BlockHash::all_zeros()), None);
+
+        // the synced headers are evicted only if they contain the indexed tip
+        let mut headers = chain.clone();
+        headers.drop_last_headers(1);
+        headers.evict_headers(100, full.tip());
+        assert_eq!(headers.headers.first, 91);
+
+        // new blocks
+        let longer = {
+            let mut longer = full.clone();
+            let mut prev = longer.get_block_header(100).unwrap();
+            let new_headers = (101..=105).map(|height| {
+                prev = BlockHeader {
+                    prev_blockhash: prev.block_hash(),
+                    nonce: height as u32,
+                    ..prev
+                };
+                NewHeader::from((prev, height))
+            });
+            longer.update(new_headers.collect());
+            longer
+        };
+        let new_headers = longer.new_headers(&chain, usize::MAX);
+        store.write(&new_headers);
+        chain.update(new_headers);
+        chain.evict_headers(longer.height(), longer.tip());
+        assert_eq!(chain.headers.first, 96);
+        assert_same_chain(&chain, &longer);
+
+        // a reorg below the in-memory headers
+        let mut reorged = longer.clone();
+        let mut prev = reorged.get_block_header(49).unwrap();
+        let new_headers: Vec<_> = (50..=60)
+            .map(|height| {
+                prev = BlockHeader {
+                    prev_blockhash: prev.block_hash(),
+                    nonce: 1000 + height as u32,
+                    ..prev
+                };
+                NewHeader::from((prev, height))
+            })
+            .collect();
+        reorged.update(new_headers);
+        let new_headers = reorged.new_headers(&chain, usize::MAX);
+        assert_eq!(new_headers.first().unwrap().height(), 50);
+        chain.update(new_headers); // before the new blocks are stored
+        assert_eq!(chain.headers.first, 50);
+        let stale = longer.get_block_hash(55).unwrap();
+        assert_eq!(chain.get_block_height(&stale), None);
+        store.write(&reorged.new_headers(&longer, usize::MAX));
+        chain.evict_headers(reorged.height(), reorged.tip());
+        assert_eq!(chain.headers.first, 51);
+        assert_eq!(chain.get_block_height(&stale), None);
+        assert_same_chain(&chain, &reorged);
+    }
 }
@@ -0,0 +1,139 @@
+use parking_lot::Mutex;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::metrics::{Counter, Metrics};
+
+/// Shares the result of a computation between concurrent callers using the same key.
+///
+/// The result is dropped as soon as the first caller completes it (nothing is cached),
+/// and a failure is never shared: each waiting caller retries the computation by itself.
+/// Waiting callers don't block their thread (e.g. a query pool worker, which may be needed to
+/// complete the shared computation): they are passed the result when it is ready.
+pub(crate) struct Coalescer<K, V> {
+    inflight: Mutex<HashMap<K, Vec<Waiter<V>>>>,
+    coalesced: Counter,
+}
+
+/// Called with the shared result (or `None`, if the computation failed)
+type Waiter<V> = Box<dyn FnOnce(Option<V>) + Send>;
+
+/// How a computation was joined (see `Coalescer::join`)
+pub(crate) enum Join<'a, K: Eq + Hash, V: Clone, T> {
+    /// No identical computation is in flight, so the caller computes it (with its item)
+    Lead(Leader<'a, K, V>, T),
+    /// The item is passed to the waiter when the computation in flight is done
+    Wait,
+}
+
+/// Removes the in-flight entry (and passes the result to the waiters) when dropped - even if
+/// the computation panics.
+pub(crate) struct Leader<'a, K: Eq + Hash, V: Clone> {
+    coalescer: &'a Coalescer<K, V>,
+    key: Option<K>,
+    label: &'a str,
+    result: Option<V>,
+}
+
+impl<K: Eq + Hash, V: Clone> Leader<'_, K, V> {
+    /// Share the computation's result with the waiters
+    pub fn share(&mut self, value: &V) {
+        self.result = Some(value.clone());
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> Drop for Leader<'_, K, V> {
+    fn drop(&mut self) {
+        let key = self.key.take().expect("missing key");
+        let waiters = self.coalescer.inflight.lock().remove(&key);
+        let waiters = waiters.expect("missing in-flight entry");
+        if self.result.is_some() {
+            self.coalescer
+                .coalesced
+                .inc_by(self.label, waiters.len() as u64);
+        }
+        for waiter in waiters {
+            waiter(self.result.clone());
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Coalescer<K, V> {
+    pub fn new(metrics: &Metrics) -> Self {
+        Self {
+            inflight: Mutex::default(),
+            coalesced: metrics.counter(
+                "rpc_coalesced",
+                "# of RPC requests answered by sharing an identical in-flight request",
+                "method",
+            ),
+        }
+    }
+
+    /// Join the computation of `key`. If it is in flight, `wait` is later passed `item` and the
+    /// computation's result (`None` if it failed, so the caller retries by itself) - from the
+    /// thread which completes it, so `wait` should be cheap. Otherwise, the caller computes it.
+    pub fn join<'a, T: Send + 'static>(
+        &'a self,
+        key: K,
+        label: &'a str,
+        item: T,
+        wait: impl FnOnce(T, Option<V>) + Send + 'static,
+    ) -> Join<'a, K, V, T> {
+        let mut inflight = self.inflight.lock();
+        match inflight.get_mut(&key) {
+            Some(waiters) => {
+                waiters.push(Box::new(move |result| wait(item, result)));
+                Join::Wait
+            }
+            None => {
+                inflight.insert(key.clone(), vec![]);
+                let leader = Leader {
+                    coalescer: self,
+                    key: Some(key),
+                    label,
+                    result: None,
+                };
+                Join::Lead(leader, item)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Coalescer, Join};
+    use crate::metrics::Metrics;
+
+    use crossbeam_channel::unbounded;
+
+    #[test]
+    fn test_failures_are_not_shared() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let coalescer = Coalescer::<u32, u32>::new(&metrics);
+        let (tx, rx) = unbounded();
+        let join = |item| {
+            let tx = tx.clone();
+            coalescer.join(1, "test", item, move |item, result| {
+                tx.send((item, result)).unwrap()
+            })
+        };
+        let Join::Lead(leader, "leader") = join("leader") else {
+            panic!("not leading")
+        };
+        assert!(matches!(join("waiter"), Join::Wait)); // doesn't block
+        assert!(rx.try_recv().is_err());
+        drop(leader); // failed
+        assert_eq!(rx.try_recv().unwrap(), ("waiter", None)); // so it is computed again
+
+        let Join::Lead(mut leader, "waiter") = join("waiter") else {
+            panic!("not leading")
+        };
+        assert!(matches!(join("other"), Join::Wait));
+        leader.share(&42);
+        drop(leader);
+        assert_eq!(rx.try_recv().unwrap(), ("other", Some(42)));
+        assert!(coalescer.inflight.lock().is_empty());
+    }
+}
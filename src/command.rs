@@ -0,0 +1,821 @@
+use anyhow::{Context, Result};
+
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    blocks_dir::BlocksDir,
+    chain::Chain,
+    config::Config,
+    daemon::{self, Daemon, Node},
+    db::{DBStore, StoreOptions},
+    dump::{HeadersDump, ScriptHashDump, StoredHeaders},
+    index::{Index, IndexOptions},
+    metrics::Metrics,
+    migration::Layout,
+    replay,
+    signals::ExitFlag,
+    thread::Supervisor,
+    types::ScriptHash,
+};
+
+/// Offline commands (e.g. `electrs db compact`), which exit instead of starting the server.
+/// Only `scripthash-dump` (if available), `bench-index` and `replay` connect to bitcoind.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Command {
+    /// Compact the given column families (all of them, if empty)
+    DbCompact { cfs: Vec<String> },
+    /// Print the DB properties of each column family (and its exact number of keys, if `exact`)
+    DbStats { exact: bool },
+    /// Print the indexed tip (and its header), using a secondary DB instance
+    DbTip { json: bool },
+    /// Print `count` indexed headers starting at height `from` (the last ones, if `None`), using
+    /// a secondary DB instance
+    DbHeaders {
+        from: Option<usize>,
+        count: usize,
+        json: bool,
+    },
+    /// Print the index rows of a scripthash (and its verified history), using a read-only DB
+    ScriptHashDump { scripthash: ScriptHash, json: bool },
+    /// Index the first `blocks` blocks into a temporary DB, printing the indexing timings
+    BenchIndex { blocks: usize },
+    /// Re-index the chain events recorded in a `replay_log` file into a temporary DB, and compare
+    /// it with a clean index of the final chain
+    Replay { log: PathBuf },
+}
+
+const USAGE: &str = "usage: electrs [OPTIONS] db compact [--cf <name>]... | db stats [--exact] | \
+                     db tip [--json] | db headers [--from <height>] [--count <n>] [--json] | \
+                     scripthash-dump <scripthash> [--json] | bench-index --blocks <n> | \
+                     replay <log>";
+
+/// The number of headers printed by `db headers` (unless `--count` is given)
+const DEFAULT_HEADERS_COUNT: usize = 10;
+
+impl Command {
+    /// Parse the positional arguments (following the options), returning `None` if there are none.
+    pub(crate) fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Option<Command>> {
+        let args = args
+            .into_iter()
+            .map(|arg| {
+                arg.into_string()
+                    .map_err(|arg| anyhow!("invalid argument: {:?}", arg))
+            })
+            .collect::<Result<Vec<String>>>()?;
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let command = match args.as_slice() {
+            [] => return Ok(None),
+            ["db", "stats", flags @ ..] => {
+                let mut exact = false;
+                for &flag in flags {
+                    match flag {
+                        "--exact" => exact = true,
+                        _ => bail!("unexpected argument: {}\n{}", flag, USAGE),
+                    }
+                }
+                Command::DbStats { exact }
+            }
+            ["db", "tip", flags @ ..] => {
+                let mut json = false;
+                for &flag in flags {
+                    match flag {
+                        "--json" => json = true,
+                        _ => bail!("unexpected argument: {}\n{}", flag, USAGE),
+                    }
+                }
+                Command::DbTip { json }
+            }
+            ["db", "headers", flags @ ..] => {
+                let (mut from, mut count, mut json) = (None, DEFAULT_HEADERS_COUNT, false);
+                let mut flags = flags.iter();
+                while let Some(&flag) = flags.next() {
+                    let mut value = |name: &str| -> Result<usize> {
+                        let value = flags.next().with_context(|| format!("missing {}", name))?;
+                        value
+                            .parse()
+                            .with_context(|| format!("invalid {}: {}", name, value))
+                    };
+                    match flag {
+                        "--from" => from = Some(value("--from")?),
+                        "--count" => count = value("--count")?,
+                        "--json" => json = true,
+                        _ => bail!("unexpected argument: {}\n{}", flag, USAGE),
+                    }
+                }
+                Command::DbHeaders { from, count, json }
+            }
+            ["db", "compact", flags @ ..] => {
+                let mut cfs = vec![];
+                let mut flags = flags.iter();
+                while let Some(&flag) = flags.next() {
+                    match (flag, flags.next()) {
+                        ("--cf", Some(&name)) => {
+                            if !DBStore::column_families().contains(&name) {
+                                bail!(
+                                    "unknown column family: {} (expected one of: {})",
+                                    name,
+                                    DBStore::column_families().join(", ")
+                                );
+                            }
+                            cfs.push(name.to_owned());
+                        }
+                        _ => bail!("unexpected argument: {}\n{}", flag, USAGE),
+                    }
+                }
+                Command::DbCompact { cfs }
+            }
+            ["scripthash-dump", scripthash, flags @ ..] => {
+                let scripthash = ScriptHash::from_str(scripthash)
+                    .map_err(|e| anyhow!("invalid scripthash {}: {}", scripthash, e))?;
+                let mut json = false;
+                for &flag in flags {
+                    match flag {
+                        "--json" => json = true,
+                        _ => bail!("unexpected argument: {}\n{}", flag, USAGE),
+                    }
+                }
+                Command::ScriptHashDump { scripthash, json }
+            }
+            ["bench-index", flags @ ..] => {
+                let mut blocks = None;
+                let mut flags = flags.iter();
+                while let Some(&flag) = flags.next() {
+                    match (flag, flags.next()) {
+                        ("--blocks", Some(&value)) => {
+                            let value = value
+                                .parse()
+                                .with_context(|| format!("invalid --blocks: {}", value))?;
+                            blocks = Some(value);
+                        }
+                        _ => bail!("unexpected argument: {}\n{}", flag, USAGE),
+                    }
+                }
+                let blocks = blocks.with_context(|| format!("missing --blocks\n{}", USAGE))?;
+                Command::BenchIndex { blocks }
+            }
+            ["replay", log] => Command::Replay {
+                log: PathBuf::from(log),
+            },
+            _ => bail!("unknown command: {}\n{}", args.join(" "), USAGE),
+        };
+        Ok(Some(command))
+    }
+
+    pub(crate) fn run(&self, config: &Config, out: &mut dyn Write) -> Result<()> {
+        match self {
+            Command::ScriptHashDump { scripthash, json } => {
+                return scripthash_dump(config, *scripthash, *json, out)
+            }
+            Command::DbTip { json } => {
+                return headers_dump(config, *json, out, HeadersDump::tip);
+            }
+            Command::DbHeaders { from, count, json } => {
+                return headers_dump(config, *json, out, |headers| {
+                    HeadersDump::range(headers, *from, *count)
+                });
+            }
+            Command::BenchIndex { blocks } => return bench_index(config, *blocks, out),
+            Command::Replay { log } => return replay_log(config, log, out),
+            _ => (),
+        }
+        let store = DBStore::open_existing(
+            &Layout::new(&config.db_path).active()?,
+            config.db_log_dir.as_deref(),
+            config.db_parallelism,
+        )
+        .context("failed to open DB (is electrs running?)")?;
+        match self {
+            Command::DbCompact { cfs } => {
+                let cfs: Vec<&str> = match cfs.as_slice() {
+                    [] => DBStore::column_families().to_vec(),
+                    cfs => cfs.iter().map(String::as_str).collect(),
+                };
+                let before = store.cf_sizes()?;
+                for cf in &cfs {
+                    writeln!(out, "compacting {}...", cf)?;
+                    store.compact_cf(cf)?;
+                }
+                let after = store.cf_sizes()?;
+                writeln!(out, "{:<10} {:>15} {:>15}", "CF", "before", "after")?;
+                for ((cf, before), (_, after)) in before.iter().zip(after.iter()) {
+                    if cfs.contains(cf) {
+                        writeln!(out, "{:<10} {:>15} {:>15}", cf, before, after)?;
+                    }
+                }
+                let total = |sizes: &[(&str, u64)]| sizes.iter().map(|(_, s)| s).sum::<u64>();
+                writeln!(
+                    out,
+                    "{:<10} {:>15} {:>15}",
+                    "total",
+                    total(&before),
+                    total(&after)
+                )?;
+            }
+            Command::DbStats { exact } => {
+                writeln!(out, "{:<10} {:<45} {:>15}", "CF", "property", "value")?;
+                for (cf, property, value) in store.get_properties() {
+                    writeln!(out, "{:<10} {:<45} {:>15}", cf, property, value)?;
+                }
+                if *exact {
+                    writeln!(out)?;
+                    writeln!(
+                        out,
+                        "{:<10} {:>15} {:>15}",
+                        "CF", "estimated keys", "exact keys"
+                    )?;
+                    let estimated = store.estimate_num_keys();
+                    for ((cf, estimated), (_, exact)) in
+                        estimated.into_iter().zip(store.count_keys()?)
+                    {
+                        writeln!(out, "{:<10} {:>15} {:>15}", cf, estimated, exact)?;
+                    }
+                }
+            }
+            Command::ScriptHashDump { .. }
+            | Command::DbTip { .. }
+            | Command::DbHeaders { .. }
+            | Command::BenchIndex { .. }
+            | Command::Replay { .. } => unreachable!("handled above"),
+        }
+        Ok(())
+    }
+}
+
+/// Dump the index rows of `scripthash` (next to a running electrs, if needed), verifying them
+/// using bitcoind if it is reachable.
+fn scripthash_dump(
+    config: &Config,
+    scripthash: ScriptHash,
+    json: bool,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let store = DBStore::open_read_only(
+        &Layout::new(&config.db_path).active()?,
+        config.db_log_dir.as_deref(),
+        config.db_parallelism,
+    )?;
+    store.check_network(config.network, config.genesis().block_hash())?;
+    let metrics = Metrics::unserved(); // not scraped
+    let index = Index::load(
+        store,
+        Chain::for_config(config),
+        &metrics,
+        &IndexOptions {
+            batch_size: config.index_batch_size,
+            lookup_limit: None, // all the rows are dumped
+            merkle_cache: config.merkle_cache,
+            script_types: config.index_script_types.clone(),
+            recent_headers: None, // read-only DB (and a single lookup)
+            ..IndexOptions::default()
+        },
+    )
+    .context("failed to load index")?;
+    let supervisor = Supervisor::new();
+    let daemon = daemon::probe(config)
+        .and_then(|_| Daemon::connect(config, &ExitFlag::new(), &metrics, &supervisor));
+    let daemon = match &daemon {
+        Ok(daemon) => Ok(daemon as &dyn Node),
+        Err(err) => Err(anyhow!("{:#}", err)),
+    };
+    let dump = ScriptHashDump::collect(&index, daemon, scripthash, &metrics)?;
+    if json {
+        serde_json::to_writer_pretty(&mut *out, &dump)?;
+        writeln!(out)?;
+    } else {
+        dump.write_text(out)?;
+    }
+    Ok(())
+}
+
+/// Index the first `count` blocks of bitcoind's chain into a temporary DB (deleted afterwards),
+/// reading them from `daemon_blocks_dir` if set (otherwise, they are fetched from bitcoind), and
+/// print the indexing timings as JSON (see `TimingsReport`).
+fn bench_index(config: &Config, count: usize, out: &mut dyn Write) -> Result<()> {
+    let db_path = std::env::temp_dir().join(format!("electrs-bench-{}", std::process::id()));
+    let result = (|| {
+        let store = DBStore::open(
+            &db_path,
+            &StoreOptions {
+                log_dir: None,
+                auto_reindex: true, // e.g. left by a previous run
+                ..StoreOptions::for_config(config)
+            },
+        )?;
+        let metrics = Metrics::unserved(); // not scraped
+        let mut index = Index::load(
+            store,
+            Chain::for_config(config),
+            &metrics,
+            &IndexOptions {
+                batch_size: config.index_batch_size,
+                merkle_cache: config.merkle_cache,
+                script_types: config.index_script_types.clone(),
+                ..IndexOptions::default()
+            },
+        )
+        .context("failed to load index")?;
+        if let Some(dir) = &config.daemon_blocks_dir {
+            index.set_blocks_dir(BlocksDir::new(dir.clone(), config.magic));
+        }
+        let exit_flag = ExitFlag::new();
+        let supervisor = Supervisor::new();
+        let daemon = Daemon::connect(config, &exit_flag, &metrics, &supervisor)?;
+        let mut headers = Chain::for_config(config);
+        while headers.height() < count {
+            let new_headers = daemon.get_new_headers(&headers)?;
+            if new_headers.is_empty() {
+                break; // bitcoind's chain is shorter
+            }
+            headers.update(new_headers);
+        }
+        let (timings, elapsed) = index.bench(&daemon, &headers, count)?;
+        serde_json::to_writer_pretty(&mut *out, &timings.report(elapsed))?;
+        writeln!(out)?;
+        Ok(())
+    })();
+    if db_path.exists() {
+        if let Err(err) = std::fs::remove_dir_all(&db_path) {
+            warn!("failed to remove {}: {}", db_path.display(), err);
+        }
+    }
+    result
+}
+
+/// Replay the chain events recorded in `log` (fetching their blocks from bitcoind, which must
+/// have all of them) into a temporary DB, and compare it with a clean index of the final chain
+/// (indexed into another temporary DB). Both are created next to the configured DB (e.g.
+/// `bitcoin.replay-<pid>`), since they may be as large as it. The DBs' digests are printed as
+/// JSON (see `ReplayReport`), failing if the replayed DB is missing rows of the clean one.
+fn replay_log(config: &Config, log: &Path, out: &mut dyn Write) -> Result<()> {
+    let records = replay::read(log, config.magic)?;
+    let mut db_path = config.db_path.clone().into_os_string();
+    db_path.push(format!(".replay-{}", std::process::id()));
+    let db_path = PathBuf::from(db_path);
+    let result = (|| {
+        let metrics = Metrics::unserved(); // not scraped
+        let clean_metrics = Metrics::unserved();
+        let open = |name: &str, metrics: &Metrics| -> Result<Index> {
+            let store = DBStore::open(
+                &db_path.join(name),
+                &StoreOptions {
+                    log_dir: None,
+                    auto_reindex: true, // e.g. left by a previous run
+                    ..StoreOptions::for_config(config)
+                },
+            )?;
+            Index::load(
+                store,
+                Chain::for_config(config),
+                metrics,
+                &IndexOptions {
+                    batch_size: config.index_batch_size,
+                    merkle_cache: config.merkle_cache,
+                    script_types: config.index_script_types.clone(),
+                    ..IndexOptions::default()
+                },
+            )
+            .context("failed to load index")
+        };
+        let replayed = open("replayed", &metrics)?;
+        let clean = open("clean", &clean_metrics)?;
+        let exit_flag = ExitFlag::new();
+        let supervisor = Supervisor::new();
+        let daemon = Daemon::connect(config, &exit_flag, &metrics, &supervisor)?;
+        let report = replay::replay(
+            &records,
+            &daemon,
+            Chain::for_config(config),
+            &replayed,
+            &clean,
+            &exit_flag,
+        )?;
+        serde_json::to_writer_pretty(&mut *out, &report)?;
+        writeln!(out)?;
+        report.check()
+    })();
+    if db_path.exists() {
+        if let Err(err) = std::fs::remove_dir_all(&db_path) {
+            warn!("failed to remove {}: {}", db_path.display(), err);
+        }
+    }
+    result
+}
+
+/// Dump the indexed tip and headers (next to a running electrs, if needed), using a RocksDB
+/// secondary instance (whose files are deleted afterwards).
+fn headers_dump(
+    config: &Config,
+    json: bool,
+    out: &mut dyn Write,
+    collect: impl FnOnce(&StoredHeaders) -> HeadersDump,
+) -> Result<()> {
+    static SECONDARY_ID: AtomicUsize = AtomicUsize::new(0);
+    let secondary_path = std::env::temp_dir().join(format!(
+        "electrs-secondary-{}-{}",
+        std::process::id(),
+        SECONDARY_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = (|| {
+        let db_path = Layout::new(&config.db_path).active()?;
+        let store = DBStore::open_secondary(&db_path, &secondary_path, config.db_parallelism)?;
+        store.check_network(config.network, config.genesis().block_hash())?;
+        let dump = collect(&StoredHeaders::new(&store, config.genesis())?);
+        if json {
+            serde_json::to_writer_pretty(&mut *out, &dump)?;
+            writeln!(out)?;
+        } else {
+            dump.write_text(out)?;
+        }
+        Ok(())
+    })();
+    if secondary_path.exists() {
+        if let Err(err) = std::fs::remove_dir_all(&secondary_path) {
+            warn!("failed to remove {}: {}", secondary_path.display(), err);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+    use crate::{
+        config::Config,
+        db::{DBStore, StoreOptions, WriteBatch},
+        types::{HeaderRow, PrefixLen, ScriptHash, ScriptTypes, SerializedHashPrefixRow},
+    };
+    use bitcoin::{block::Header as BlockHeader, hashes::Hash};
+    use serde_json::{json, Value};
+    use std::ffi::OsString;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Option<Command>> {
+        Command::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&[]).unwrap(), None);
+        assert_eq!(
+            parse(&["db", "stats"]).unwrap(),
+            Some(Command::DbStats { exact: false })
+        );
+        assert_eq!(
+            parse(&["db", "stats", "--exact"]).unwrap(),
+            Some(Command::DbStats { exact: true })
+        );
+        assert!(parse(&["db", "stats", "--json"]).is_err());
+        assert_eq!(
+            parse(&["db", "compact"]).unwrap(),
+            Some(Command::DbCompact { cfs: vec![] })
+        );
+        assert_eq!(
+            parse(&["db", "compact", "--cf", "funding", "--cf", "txid"]).unwrap(),
+            Some(Command::DbCompact {
+                cfs: vec!["funding".to_owned(), "txid".to_owned()]
+            })
+        );
+        let err = |args: &[&str]| parse(args).unwrap_err().to_string();
+        assert!(err(&["db"]).starts_with("unknown command: db\n"));
+        assert!(err(&["serve"]).starts_with("unknown command: serve\n"));
+        assert!(err(&["db", "compact", "--cf"]).starts_with("unexpected argument: --cf\n"));
+        assert!(err(&["db", "compact", "--cf", "foo"]).starts_with("unknown column family: foo"));
+
+        assert_eq!(
+            parse(&["db", "tip", "--json"]).unwrap(),
+            Some(Command::DbTip { json: true })
+        );
+        assert_eq!(
+            parse(&["db", "headers"]).unwrap(),
+            Some(Command::DbHeaders {
+                from: None,
+                count: 10,
+                json: false
+            })
+        );
+        assert_eq!(
+            parse(&["db", "headers", "--from", "5", "--count", "2", "--json"]).unwrap(),
+            Some(Command::DbHeaders {
+                from: Some(5),
+                count: 2,
+                json: true
+            })
+        );
+        assert!(err(&["db", "tip", "--foo"]).starts_with("unexpected argument: --foo\n"));
+        assert_eq!(err(&["db", "headers", "--from"]), "missing --from");
+        assert!(err(&["db", "headers", "--count", "x"]).starts_with("invalid --count: x"));
+
+        let scripthash = "a".repeat(64);
+        assert_eq!(
+            parse(&["scripthash-dump", &scripthash, "--json"]).unwrap(),
+            Some(Command::ScriptHashDump {
+                scripthash: scripthash.parse().unwrap(),
+                json: true
+            })
+        );
+        assert!(err(&["scripthash-dump"]).starts_with("unknown command: scripthash-dump\n"));
+        assert!(err(&["scripthash-dump", "xyz"]).starts_with("invalid scripthash xyz"));
+        assert!(err(&["scripthash-dump", &scripthash, "--foo"])
+            .starts_with("unexpected argument: --foo\n"));
+
+        assert_eq!(
+            parse(&["bench-index", "--blocks", "1000"]).unwrap(),
+            Some(Command::BenchIndex { blocks: 1000 })
+        );
+        assert!(err(&["bench-index"]).starts_with("missing --blocks\n"));
+        assert!(err(&["bench-index", "--blocks", "x"]).starts_with("invalid --blocks: x"));
+        assert!(err(&["bench-index", "--blocks"]).starts_with("unexpected argument: --blocks\n"));
+
+        assert_eq!(
+            parse(&["replay", "/tmp/replay.log"]).unwrap(),
+            Some(Command::Replay {
+                log: "/tmp/replay.log".into()
+            })
+        );
+        assert!(err(&["replay"]).starts_with("unknown command: replay\n"));
+    }
+
+    #[test]
+    fn test_headers_dump() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder()
+            .db_dir(dir.path())
+            .auth("user", "pass")
+            .build()
+            .unwrap();
+        let run = |command: Command| {
+            let mut out = vec![];
+            command.run(&config, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let run_json = |command: Command| -> Value { serde_json::from_str(&run(command)).unwrap() };
+        let headers_json = |from, count| {
+            run_json(Command::DbHeaders {
+                from,
+                count,
+                json: true,
+            })
+        };
+
+        let genesis = config.genesis();
+        let mut headers = vec![genesis];
+        for i in 1..=5u32 {
+            let prev = headers.last().unwrap();
+            headers.push(BlockHeader {
+                prev_blockhash: prev.block_hash(),
+                time: prev.time + i,
+                ..*prev
+            });
+        }
+        let row = |height: usize| {
+            let header = headers[height];
+            let hash = header.block_hash().to_byte_array();
+            (height, hash, HeaderRow::new(header).to_db_row())
+        };
+        let store = DBStore::open(
+            &config.db_path,
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        store
+            .check_network(config.network, config.genesis().block_hash())
+            .unwrap();
+        let output = run(Command::DbTip { json: false });
+        assert!(output.contains("indexed tip: none"), "{}", output);
+        assert!(output.contains("warning: no indexed tip"), "{}", output);
+
+        // the DB is used by a running electrs meanwhile
+        store.write(&WriteBatch {
+            tip_row: headers[4].block_hash().to_byte_array(),
+            header_rows: headers[1..5]
+                .iter()
+                .map(|h| HeaderRow::new(*h).to_db_row())
+                .collect(),
+            height_rows: (1..5).map(row).collect(),
+            ..Default::default()
+        });
+        store.flush(); // the initial sync is over
+        store.write(&WriteBatch {
+            tip_row: headers[5].block_hash().to_byte_array(),
+            header_rows: vec![HeaderRow::new(headers[5]).to_db_row()],
+            height_rows: vec![row(5)],
+            ..Default::default()
+        }); // not flushed (only in the WAL)
+        let tip = run_json(Command::DbTip { json: true });
+        assert_eq!(tip["tip"], json!(headers[5].block_hash()));
+        assert_eq!(tip["height"], 5);
+        assert_eq!(tip["height_rows"], true);
+        assert_eq!(tip["headers"][0]["height"], 5);
+        assert_eq!(
+            tip["headers"][0]["prev_blockhash"],
+            json!(headers[4].block_hash())
+        );
+        assert_eq!(tip["headers"][0]["bits"], "1d00ffff");
+        assert_eq!(tip["headers"][0]["nonce"], genesis.nonce);
+        assert_eq!(tip["warnings"], json!([]));
+
+        let dump = headers_json(Some(0), 3);
+        let heights: Vec<_> = dump["headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|h| h["height"].clone())
+            .collect();
+        assert_eq!(heights, vec![json!(0), json!(1), json!(2)]);
+        assert_eq!(dump["headers"][0]["blockhash"], json!(genesis.block_hash()));
+        assert_eq!(headers_json(None, 2)["headers"][0]["height"], 4); // the last ones
+        assert_eq!(
+            headers_json(Some(4), 10)["headers"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        ); // up to the tip
+        assert_eq!(headers_json(Some(7), 1)["headers"], json!([]));
+
+        let output = run(Command::DbHeaders {
+            from: Some(5),
+            count: 1,
+            json: false,
+        });
+        let expected = format!(
+            "indexed tip: {} at height 5\nheaders: 1\n  height=5 block={}\n",
+            headers[5].block_hash(),
+            headers[5].block_hash()
+        );
+        assert!(output.starts_with(&expected), "{}", output);
+        assert!(!output.contains("warning"), "{}", output);
+
+        // headers indexed by an older version are walked from the tip
+        store.clear_height_rows();
+        let dump = headers_json(Some(0), 10);
+        assert_eq!(dump["height_rows"], false);
+        assert_eq!(dump["height"], 5);
+        assert_eq!(dump["headers"].as_array().unwrap().len(), 6);
+        assert_eq!(dump["warnings"], json!([]));
+        drop(store);
+
+        // a broken chain of height rows
+        let store = DBStore::open(
+            &config.db_path,
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        store.write_height_rows(std::iter::empty()); // sets `has_height_rows()`
+        let mut bad = row(4);
+        bad.2 = HeaderRow::new(BlockHeader {
+            time: 0,
+            ..headers[4]
+        })
+        .to_db_row();
+        store.write(&WriteBatch {
+            tip_row: headers[5].block_hash().to_byte_array(),
+            height_rows: vec![bad],
+            ..Default::default()
+        });
+        let dump = headers_json(Some(1), 5);
+        let warnings = dump["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 1, "{}", dump); // the header at height 4 is replaced
+        assert!(warnings[0]
+            .as_str()
+            .unwrap()
+            .starts_with("prev-hash mismatch at height 5: "));
+        drop(store);
+
+        // a gap in the height rows
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder()
+            .db_dir(dir.path())
+            .auth("user", "pass")
+            .build()
+            .unwrap();
+        let store = DBStore::open(
+            &config.db_path,
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        store.write(&WriteBatch {
+            tip_row: headers[5].block_hash().to_byte_array(),
+            height_rows: [1, 2, 4, 5].into_iter().map(row).collect(),
+            ..Default::default()
+        });
+        store.flush();
+        let mut out = vec![];
+        let command = Command::DbHeaders {
+            from: Some(2),
+            count: 3,
+            json: true,
+        };
+        command.run(&config, &mut out).unwrap();
+        let dump: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(dump["warnings"], json!(["gap: missing header at height 3"]));
+        assert_eq!(dump["headers"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder()
+            .db_dir(dir.path())
+            .auth("user", "pass")
+            .daemon_rpc_addr(([127, 0, 0, 1], 1).into()) // bitcoind is unreachable
+            .build()
+            .unwrap();
+        let run = |command: Command| {
+            let mut out = vec![];
+            command
+                .run(&config, &mut out)
+                .map(|()| String::from_utf8(out).unwrap())
+        };
+        // the DB is not created
+        let err = run(Command::DbStats { exact: false }).unwrap_err();
+        assert!(format!("{:#}", err).contains("no DB found"), "{:#}", err);
+        assert!(!config.db_path.exists());
+
+        {
+            let store = DBStore::open(
+                &config.db_path,
+                &StoreOptions {
+                    auto_reindex: true,
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
+            store.write(&WriteBatch {
+                txid_rows: (0..100u8)
+                    .map(|i| SerializedHashPrefixRow::from_key(&[i; 12]).unwrap())
+                    .collect(),
+                ..Default::default()
+            });
+            store.flush(); // the rows are written to SST files
+
+            // the DB is locked while it is used
+            let err = run(Command::DbStats { exact: false }).unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("is electrs running?"),
+                "{:#}",
+                err
+            );
+
+            // ... but it can be read meanwhile
+            let output = run(Command::ScriptHashDump {
+                scripthash: ScriptHash::all_zeros(),
+                json: true,
+            })
+            .unwrap();
+            let dump: serde_json::Value = serde_json::from_str(&output).unwrap();
+            assert_eq!(dump["tip"]["height"], 0);
+            assert_eq!(dump["funding"], serde_json::json!([]));
+            assert_eq!(dump["verified"], serde_json::Value::Null);
+            assert!(dump["daemon_error"].is_string(), "{}", dump);
+        }
+
+        let output = run(Command::DbCompact {
+            cfs: vec!["txid".to_owned()],
+        })
+        .unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "compacting txid...");
+        assert!(lines[1].starts_with("CF"));
+        assert!(lines[2].starts_with("txid "));
+        assert!(lines[3].starts_with("total "));
+        assert_eq!(lines.len(), 4);
+
+        let output = run(Command::DbStats { exact: false }).unwrap();
+        assert!(output.starts_with("CF"));
+        assert!(output
+            .lines()
+            .any(|line| line.starts_with("txid ") && line.contains("rocksdb.estimate-num-keys")));
+        assert!(!output.contains("exact keys"));
+
+        let output = run(Command::DbStats { exact: true }).unwrap();
+        let counts: Vec<&str> = output
+            .lines()
+            .skip_while(|line| !line.contains("exact keys"))
+            .collect();
+        assert!(counts.len() > 1, "{}", output);
+        let txid = counts
+            .iter()
+            .find(|line| line.starts_with("txid "))
+            .unwrap();
+        let values: Vec<&str> = txid.split_whitespace().collect();
+        assert_eq!(values, ["txid", "100", "100"]);
+        let funding = counts
+            .iter()
+            .find(|line| line.starts_with("funding "))
+            .unwrap();
+        assert!(funding.ends_with(" 0"), "{}", funding);
+    }
+}
@@ -1,19 +1,40 @@
+use anyhow::{Context, Result};
+use bitcoin::hashes::{sha256, Hash};
 use bitcoin::p2p::Magic;
-use bitcoin::Network;
+use bitcoin::{bech32, block::Header as BlockHeader, BlockHash, Network};
 use bitcoincore_rpc::Auth;
 use dirs_next::home_dir;
+use serde::{Deserialize, Deserializer};
 
 use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::net::SocketAddr;
+use std::fs::{self, File, OpenOptions};
+use std::io;
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use std::env::consts::{ARCH, OS};
 use std::time::Duration;
 
+use crate::{
+    cache::CacheBudgets,
+    chain::Checkpoints,
+    command::Command,
+    custom_chain::{AddressDecoder, AddressPrefixes, CustomChain},
+    daemon,
+    flush::FlushPolicy,
+    logger, maintenance,
+    proxy::{DaemonAddr, Proxy, RpcTls},
+    resolver::Resolver,
+    socket::{Keepalive, TcpOptions},
+    types::{PrefixLen, ScriptType, ScriptTypes},
+};
+
 pub const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Abbreviated git commit hash of this build ("unknown" if built outside a git repository)
+pub const ELECTRS_GIT_COMMIT: &str = env!("ELECTRS_GIT_COMMIT");
 const DEFAULT_SERVER_ADDRESS: [u8; 4] = [127, 0, 0, 1]; // by default, serve on IPv4 localhost
 
 mod internal {
@@ -22,6 +43,7 @@ mod internal {
 }
 
 /// A simple error type representing invalid UTF-8 input.
+#[derive(Debug)]
 pub struct InvalidUtf8(OsString);
 
 impl fmt::Display for InvalidUtf8 {
@@ -79,22 +101,465 @@ impl ResolvAddr {
         }
     }
 
-    /// Resolves the address, but prints error and exits in case of failure.
-    fn resolve_or_exit(self) -> SocketAddr {
-        self.resolve().unwrap_or_else(|err| {
-            eprintln!("Error: {}", err);
-            std::process::exit(1)
+    /// Resolves the address, converting the failure into an error.
+    fn resolve_any(self) -> Result<SocketAddr> {
+        self.resolve().map_err(|err| anyhow!("{}", err))
+    }
+
+    /// Strips the scheme of an `http://addr:port` or `https://addr:port` URL (bitcoind's
+    /// JSONRPC address may be specified either way), returning whether TLS is used.
+    fn strip_rpc_scheme(self) -> Result<(Self, bool)> {
+        let (addr, tls) = match (
+            self.0.strip_prefix("http://"),
+            self.0.strip_prefix("https://"),
+        ) {
+            (Some(addr), _) => (addr.trim_end_matches('/'), false),
+            (_, Some(addr)) => (addr.trim_end_matches('/'), true),
+            (None, None) => return Ok((self, false)),
+        };
+        ensure!(
+            !addr.is_empty() && !addr.contains(['/', '@']),
+            "daemon_rpc_addr {} must be 'http(s)://addr:port' (without a path or credentials)",
+            self.0
+        );
+        Ok((ResolvAddr(addr.to_owned()), tls))
+    }
+}
+
+/// Newtype for a list of addresses, parsed from a comma-separated `String`
+///
+/// Config files may also specify the list as a TOML array of strings.
+pub struct ResolvAddrList(Vec<ResolvAddr>);
+
+impl ResolvAddrList {
+    fn split(list: &str) -> Self {
+        let addrs = list
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| ResolvAddr(addr.to_owned()))
+            .collect();
+        ResolvAddrList(addrs)
+    }
+
+    /// Resolves all the addresses, failing if any of them can't be resolved.
+    fn resolve_all(self) -> Result<Vec<SocketAddr>> {
+        self.0.into_iter().map(ResolvAddr::resolve_any).collect()
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArg for ResolvAddrList {
+    type Error = InvalidUtf8;
+
+    fn parse_arg(arg: &OsStr) -> std::result::Result<Self, Self::Error> {
+        Self::parse_owned_arg(arg.to_owned())
+    }
+
+    fn parse_owned_arg(arg: OsString) -> std::result::Result<Self, Self::Error> {
+        arg.into_string()
+            .map_err(InvalidUtf8)
+            .map(|list| Self::split(&list))
+    }
+
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(
+            writer,
+            "a comma-separated list of network addresses (will be resolved if needed)"
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for ResolvAddrList {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum List {
+            Joined(String),
+            Array(Vec<String>),
+        }
+
+        Ok(match List::deserialize(deserializer)? {
+            List::Joined(list) => Self::split(&list),
+            List::Array(addrs) => ResolvAddrList(addrs.into_iter().map(ResolvAddr).collect()),
         })
     }
 }
 
-/// This newtype implements `ParseArg` for `Network`.
+/// Newtype for a list of output script types, parsed from a comma-separated `String` (or `all`)
+///
+/// Config files may also specify the list as a TOML array of strings.
+pub struct ScriptTypeList(ScriptTypes);
+
+impl FromStr for ScriptTypeList {
+    type Err = String;
+
+    fn from_str(list: &str) -> std::result::Result<Self, Self::Err> {
+        let names: Vec<&str> = list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        Self::from_names(&names)
+    }
+}
+
+impl ScriptTypeList {
+    fn from_names<S: AsRef<str>>(names: &[S]) -> std::result::Result<Self, String> {
+        if names.len() == 1 && names[0].as_ref() == "all" {
+            return Ok(ScriptTypeList(ScriptTypes::all()));
+        }
+        if names.is_empty() {
+            return Err("empty script types list".to_owned());
+        }
+        let types = names
+            .iter()
+            .map(|name| name.as_ref().parse::<ScriptType>())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(ScriptTypeList(ScriptTypes::only(types)))
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for ScriptTypeList {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(
+            writer,
+            "either 'all' or a comma-separated list of 'p2pk', 'p2pkh', 'p2sh', 'p2wpkh', 'p2wsh', 'p2tr', 'other' and 'op_return'"
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for ScriptTypeList {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum List {
+            Joined(String),
+            Array(Vec<String>),
+        }
+
+        match List::deserialize(deserializer)? {
+            List::Joined(list) => list.parse(),
+            List::Array(names) => Self::from_names(&names),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Newtype for a list of checkpoints, parsed from a comma-separated list of `height:blockhash`
+/// pairs (or `none`)
+pub struct CheckpointList(Checkpoints);
+
+impl FromStr for CheckpointList {
+    type Err = String;
+
+    fn from_str(list: &str) -> std::result::Result<Self, Self::Err> {
+        if list.trim() == "none" {
+            return Ok(CheckpointList(Checkpoints::default()));
+        }
+        let checkpoints = list
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                let (height, hash) = item
+                    .split_once(':')
+                    .ok_or_else(|| format!("checkpoint '{}' is not 'height:blockhash'", item))?;
+                let height = height
+                    .parse()
+                    .map_err(|e| format!("invalid checkpoint height '{}': {}", height, e))?;
+                let hash = hash
+                    .parse()
+                    .map_err(|e| format!("invalid checkpoint block hash '{}': {}", hash, e))?;
+                Ok((height, hash))
+            })
+            .collect::<std::result::Result<Vec<_>, String>>()?;
+        if checkpoints.is_empty() {
+            return Err("empty checkpoints list (use 'none' to disable them)".to_owned());
+        }
+        Ok(CheckpointList(Checkpoints::new(checkpoints)))
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for CheckpointList {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(
+            writer,
+            "either 'none' or a comma-separated list of 'height:blockhash' pairs"
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for CheckpointList {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for maintenance::Window {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "a daily 'HH:MM-HH:MM' time window")
+    }
+}
+
+impl<'de> Deserialize<'de> for maintenance::Window {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// An IP network (e.g. `10.0.0.0/8`), or a single IP address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8, // in bits
+}
+
+impl IpNet {
+    /// IPv4-mapped IPv6 addresses are matched as IPv4 ones.
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        fn matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+            let shift = u32::from(bits - prefix);
+            net.checked_shr(shift) == ip.checked_shr(shift)
+        }
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => matches(net.into(), ip.into(), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(net: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix) = net
+            .split_once('/')
+            .map_or((net, None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("invalid IP address '{}': {}", addr, e))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => bits,
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("invalid prefix length '{}' of {}", prefix, addr))?,
+        };
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Newtype for a list of IP networks, parsed from a comma-separated `String`
+///
+/// Config files may also specify the list as a TOML array of strings.
+pub struct IpNetList(Vec<IpNet>);
+
+impl IpNetList {
+    fn from_nets<S: AsRef<str>>(nets: &[S]) -> std::result::Result<Self, String> {
+        let nets = nets
+            .iter()
+            .map(|net| net.as_ref().trim())
+            .filter(|net| !net.is_empty())
+            .map(str::parse)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(IpNetList(nets))
+    }
+}
+
+impl FromStr for IpNetList {
+    type Err = String;
+
+    fn from_str(list: &str) -> std::result::Result<Self, Self::Err> {
+        Self::from_nets(&list.split(',').collect::<Vec<_>>())
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for IpNetList {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(
+            writer,
+            "a comma-separated list of IP networks (e.g. '10.0.0.0/8,::1')"
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetList {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum List {
+            Joined(String),
+            Array(Vec<String>),
+        }
+
+        match List::deserialize(deserializer)? {
+            List::Joined(list) => list.parse(),
+            List::Array(nets) => Self::from_nets(&nets),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+/// An Electrum RPC listener's settings (see `electrum_rpc_listeners`), after the unspecified ones
+/// are set to the global values
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    pub max_connections: Option<usize>, // enforced in addition to the global limits
+    pub max_connections_per_ip: Option<usize>,
+    pub disabled_methods: Vec<String>,
+    pub banner_file: Option<PathBuf>,
+    pub block_get_allow: Vec<IpNet>,
+    pub status_debug_allow: Vec<IpNet>,
+    pub admin: bool, // `electrs.*` methods are allowed (only from localhost)
+}
+
+impl ListenerConfig {
+    /// A listener using the global settings
+    fn new(addr: SocketAddr, config: &internal::prelude::Config) -> Self {
+        let nets = |list: &Option<IpNetList>| list.as_ref().map_or_else(Vec::new, |l| l.0.clone());
+        Self {
+            addr,
+            max_connections: None,
+            max_connections_per_ip: None,
+            disabled_methods: vec![],
+            banner_file: config.banner_file.clone(),
+            block_get_allow: nets(&config.block_get_allow),
+            status_debug_allow: nets(&config.status_debug_allow),
+            admin: true,
+        }
+    }
+}
+
+/// A single `electrum_rpc_listeners` entry (its unspecified settings default to the global ones)
 #[derive(Deserialize)]
-pub struct BitcoinNetwork(Network);
+#[serde(deny_unknown_fields)]
+struct ListenerEntry {
+    addr: ResolvAddr,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    #[serde(default)]
+    disabled_methods: Vec<String>,
+    banner_file: Option<PathBuf>,
+    block_get_allow: Option<IpNetList>,
+    status_debug_allow: Option<IpNetList>,
+    #[serde(default)]
+    tls: bool,
+    admin: Option<bool>,
+}
+
+impl ListenerEntry {
+    fn resolve(self, config: &internal::prelude::Config) -> Result<ListenerConfig> {
+        let addr = self.addr.resolve_any()?;
+        ensure!(
+            !self.tls,
+            "electrum_rpc_listeners {} enables TLS, which is not supported: please use a \
+            TLS-terminating proxy (see doc/config.md)",
+            addr
+        );
+        if let Some(method) = self
+            .disabled_methods
+            .iter()
+            .find(|method| !crate::electrum::is_method(method))
+        {
+            bail!(
+                "electrum_rpc_listeners {} disables an unknown method: {}",
+                addr,
+                method
+            );
+        }
+        let defaults = ListenerConfig::new(addr, config);
+        let limit = |max: Option<usize>| max.filter(|max| *max > 0);
+        Ok(ListenerConfig {
+            addr,
+            max_connections: limit(self.max_connections),
+            max_connections_per_ip: limit(self.max_connections_per_ip),
+            disabled_methods: self.disabled_methods,
+            banner_file: self.banner_file.or(defaults.banner_file),
+            block_get_allow: self
+                .block_get_allow
+                .map_or(defaults.block_get_allow, |l| l.0),
+            status_debug_allow: self
+                .status_debug_allow
+                .map_or(defaults.status_debug_allow, |l| l.0),
+            admin: self.admin.unwrap_or(defaults.admin),
+        })
+    }
+}
+
+/// Newtype for a list of Electrum RPC listeners, parsed from a JSON array of objects
+///
+/// Config files should specify the list as TOML `[[electrum_rpc_listeners]]` tables.
+pub struct ListenerList(Vec<ListenerEntry>);
+
+impl FromStr for ListenerList {
+    type Err = String;
+
+    fn from_str(list: &str) -> std::result::Result<Self, Self::Err> {
+        serde_json::from_str(list)
+            .map(ListenerList)
+            .map_err(|e| format!("invalid listeners list: {}", e))
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for ListenerList {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(
+            writer,
+            "a JSON array of listener objects (e.g. '[{{\"addr\": \"0.0.0.0:50001\", \"disabled_methods\": [\"blockchain.transaction.broadcast\"]}}]')"
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenerList {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::deserialize(deserializer).map(ListenerList)
+    }
+}
+
+/// This type implements `ParseArg` for `Network`, or a custom chain (see `custom_*` options).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitcoinNetwork {
+    Known(Network),
+    Custom,
+}
 
 impl Default for BitcoinNetwork {
     fn default() -> Self {
-        BitcoinNetwork(Network::Bitcoin)
+        BitcoinNetwork::Known(Network::Bitcoin)
     }
 }
 
@@ -102,7 +567,10 @@ impl FromStr for BitcoinNetwork {
     type Err = <Network as FromStr>::Err;
 
     fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
-        Network::from_str(string).map(BitcoinNetwork)
+        match string {
+            "custom" => Ok(BitcoinNetwork::Custom),
+            _ => Network::from_str(string).map(BitcoinNetwork::Known),
+        }
     }
 }
 
@@ -110,14 +578,111 @@ impl ::configure_me::parse_arg::ParseArgFromStr for BitcoinNetwork {
     fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
         write!(
             writer,
-            "either 'bitcoin', 'testnet', 'testnet4', 'regtest' or 'signet'"
+            "either 'bitcoin', 'testnet', 'testnet4', 'regtest', 'signet' or 'custom'"
         )
     }
 }
 
-impl From<BitcoinNetwork> for Network {
-    fn from(network: BitcoinNetwork) -> Network {
-        network.0
+impl<'de> Deserialize<'de> for BitcoinNetwork {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Logs' output format
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Plain,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for LogFormat {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'plain' or 'json'")
+    }
+}
+
+/// What to do when the indexing thread fails
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnIndexFailure {
+    #[default]
+    Exit,
+    ServeStale,
+}
+
+impl FromStr for OnIndexFailure {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "exit" => Ok(OnIndexFailure::Exit),
+            "serve-stale" => Ok(OnIndexFailure::ServeStale),
+            _ => Err(format!("unknown index failure policy: {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for OnIndexFailure {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'exit' or 'serve-stale'")
+    }
+}
+
+/// Write options used by the DB after the initial sync (during it, the WAL is disabled)
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Durability {
+    /// Write to the WAL and fsync it on every write
+    #[default]
+    Sync,
+    /// Write to the WAL, and fsync it periodically
+    Wal,
+    /// Don't use the WAL (memtables are lost on a crash)
+    None,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "sync" => Ok(Durability::Sync),
+            "wal" => Ok(Durability::Wal),
+            "none" => Ok(Durability::None),
+            _ => Err(format!("unknown DB durability mode: {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for Durability {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "one of 'sync', 'wal' or 'none'")
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for PrefixLen {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "one of 6, 8 or 10")
     }
 }
 
@@ -125,27 +690,82 @@ impl From<BitcoinNetwork> for Network {
 #[derive(Debug)]
 pub struct Config {
     // See below for the documentation of each field:
-    pub network: Network,
+    pub network: Network, // `Regtest` for custom chains (whose parameters are below)
+    pub custom_chain: Option<CustomChain>,
     pub db_path: PathBuf,
     pub db_log_dir: Option<PathBuf>,
     pub db_parallelism: u8,
+    pub db_durability: Durability,
     pub daemon_auth: SensitiveAuth,
-    pub daemon_rpc_addr: SocketAddr,
-    pub daemon_p2p_addr: SocketAddr,
-    pub electrum_rpc_addr: SocketAddr,
+    pub daemon_rpc_addr: DaemonAddr,
+    pub daemon_rpc_tls: Option<RpcTls>, // for an `https://` `daemon_rpc_addr`
+    pub daemon_p2p_addr: DaemonAddr,
+    pub daemon_blocks_dir: Option<PathBuf>,
+    pub electrum_rpc_addrs: Vec<SocketAddr>,
+    pub electrum_rpc_listeners: Vec<ListenerConfig>, // one per `electrum_rpc_addrs` entry
+    pub server_hostname: Option<String>,
+    pub announce_tcp_port: Option<u16>,
+    pub announce_ssl_port: Option<u16>,
+    pub announce_ws_port: Option<u16>,
     pub monitoring_addr: SocketAddr,
+    pub http_addr: Option<SocketAddr>,
+    pub http_cors_origin: Option<String>,
     pub wait_duration: Duration,
     pub jsonrpc_timeout: Duration,
+    pub fee_cache_max_age: Duration,
     pub index_batch_size: usize,
     pub index_lookup_limit: Option<usize>,
+    pub max_reorg_depth: Option<usize>,
+    pub assume_final_depth: Option<usize>,
+    pub allow_deep_reorg: bool,
+    pub checkpoints: Checkpoints,
+    pub maintenance_window: Option<maintenance::Window>,
+    pub flush_policy: FlushPolicy,
+    pub defer_initial_compaction: bool,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_concurrent_requests: usize,
+    pub max_subscriptions: Option<usize>,
+    pub block_get_allow: Vec<IpNet>,
+    pub status_debug_allow: Vec<IpNet>,
+    pub server_password: Option<SensitivePassword>,
+    pub query_threads: usize,
+    pub max_send_queue_bytes: usize,
+    pub max_request_bytes: usize,
+    pub max_broadcast_bytes: usize,
+    pub send_timeout: Duration,
+    pub tcp: TcpOptions,
+    pub max_upload_rate: Option<usize>,      // in bytes per second
+    pub max_peer_upload_rate: Option<usize>, // in bytes per second
+    pub ban_file: Option<PathBuf>,
     pub reindex_last_blocks: usize,
+    pub headers_in_memory: Option<usize>,
     pub auto_reindex: bool,
+    pub repair_db: bool,
+    pub shadow_migration: bool,
+    pub shadow_db: Option<PathBuf>,
+    pub shadow_db_sample_rate: u64,
+    pub shadow_db_max_per_sec: u32,
+    pub merkle_cache: bool,
+    pub prefetch_blocks: usize,
+    pub cache_budgets: CacheBudgets,
+    pub index_script_types: ScriptTypes,
+    pub index_prefix_len: PrefixLen,
     pub ignore_mempool: bool,
     pub sync_once: bool,
+    pub on_index_failure: OnIndexFailure,
     pub skip_block_download_wait: bool,
     pub disable_electrum_rpc: bool,
     pub server_banner: String,
+    pub banner_file: Option<PathBuf>,
+    pub scripthash_allowlist_file: Option<PathBuf>,
+    pub event_socket: Option<PathBuf>,
+    pub event_watch_file: Option<PathBuf>,
+    pub event_queue_size: usize,
+    pub replay_log: Option<PathBuf>,
+    pub replay_log_max_bytes: u64,
     pub magic: Magic,
+    pub(crate) command: Option<Command>, // run instead of the server (e.g. `electrs db compact`)
 }
 
 pub struct SensitiveAuth(pub Auth);
@@ -169,6 +789,153 @@ impl fmt::Debug for SensitiveAuth {
     }
 }
 
+/// Electrum RPC clients' password (see `server_password`).
+///
+/// Only its hash is kept, so it is never printed, and it is compared in constant time.
+#[derive(Clone)]
+pub struct SensitivePassword(sha256::Hash);
+
+impl SensitivePassword {
+    pub(crate) fn new(password: &str) -> Self {
+        Self(sha256::Hash::hash(password.as_bytes()))
+    }
+
+    /// Compare the hashes (instead of the passwords), so the duration depends neither on the
+    /// matching prefix nor on the password's length.
+    pub(crate) fn matches(&self, password: &str) -> bool {
+        let other = sha256::Hash::hash(password.as_bytes());
+        let diff = self
+            .0
+            .as_byte_array()
+            .iter()
+            .zip(other.as_byte_array())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        diff == 0
+    }
+}
+
+impl fmt::Debug for SensitivePassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<sensitive>")
+    }
+}
+
+/// bitcoind's host name is resolved on each connection, or by a SOCKS5 proxy
+fn daemon_addr(
+    addr: Option<ResolvAddr>,
+    default_port: u16,
+    proxy: Option<&str>,
+) -> Result<DaemonAddr> {
+    let default_addr = SocketAddr::from((DEFAULT_SERVER_ADDRESS, default_port));
+    match proxy {
+        None => addr.map_or(Ok(default_addr.into()), |addr| {
+            DaemonAddr::parse(&addr.0, Resolver::default())
+        }),
+        Some(proxy) => {
+            let addr = addr.map_or_else(|| default_addr.to_string(), |addr| addr.0);
+            DaemonAddr::proxied(&addr, Proxy::parse(proxy)?)
+        }
+    }
+}
+
+/// bitcoind's certificate must be pinned for an `https://` JSONRPC address (and the pinning
+/// options are refused otherwise, so they can't be ignored by mistake).
+fn daemon_rpc_tls(
+    https: bool,
+    ca_file: Option<PathBuf>,
+    cert_sha256: Option<&str>,
+) -> Result<Option<RpcTls>> {
+    match (ca_file, cert_sha256) {
+        (Some(_), Some(_)) => {
+            bail!("daemon_rpc_ca_file and daemon_rpc_cert_sha256 can't be specified together")
+        }
+        (None, None) if https => bail!(
+            "daemon_rpc_addr uses TLS: please pin bitcoind's certificate using \
+            daemon_rpc_ca_file or daemon_rpc_cert_sha256"
+        ),
+        (None, None) => Ok(None),
+        _ if !https => bail!(
+            "daemon_rpc_ca_file and daemon_rpc_cert_sha256 require an 'https://addr:port' \
+            daemon_rpc_addr"
+        ),
+        (Some(path), None) => Ok(Some(RpcTls::CaFile(path))),
+        (None, Some(fingerprint)) => RpcTls::cert_sha256(fingerprint).map(Some),
+    }
+}
+
+/// The parameters of a custom chain are required for `network = "custom"` (and refused otherwise,
+/// so they can't be set by mistake for a built-in network).
+fn custom_chain(config: &internal::prelude::Config) -> Result<Option<CustomChain>> {
+    let options = [
+        ("custom_genesis_hash", config.custom_genesis_hash.is_some()),
+        (
+            "custom_genesis_header",
+            config.custom_genesis_header.is_some(),
+        ),
+        (
+            "custom_daemon_rpc_port",
+            config.custom_daemon_rpc_port.is_some(),
+        ),
+        (
+            "custom_daemon_p2p_port",
+            config.custom_daemon_p2p_port.is_some(),
+        ),
+        ("custom_p2pkh_prefix", config.custom_p2pkh_prefix.is_some()),
+        ("custom_p2sh_prefix", config.custom_p2sh_prefix.is_some()),
+        ("custom_bech32_hrp", config.custom_bech32_hrp.is_some()),
+    ];
+    if config.network != BitcoinNetwork::Custom {
+        let set: Vec<&str> = options
+            .iter()
+            .filter(|(_, is_set)| *is_set)
+            .map(|(name, _)| *name)
+            .chain(
+                config
+                    .custom_skip_pow_check
+                    .then_some("custom_skip_pow_check"),
+            )
+            .collect();
+        ensure!(
+            set.is_empty(),
+            "{} can be set only for network = 'custom'",
+            set.join(", ")
+        );
+        return Ok(None);
+    }
+    let missing: Vec<&str> = std::iter::once(("magic", config.magic.is_some()))
+        .chain(options)
+        .filter(|(_, is_set)| !is_set)
+        .map(|(name, _)| name)
+        .collect();
+    ensure!(
+        missing.is_empty(),
+        "network = 'custom' requires {}",
+        missing.join(", ")
+    );
+    let genesis_hash = config.custom_genesis_hash.as_deref().unwrap_or_default();
+    let genesis_hash = BlockHash::from_str(genesis_hash).map_err(|error| {
+        anyhow!(
+            "custom_genesis_hash '{}' is not a valid block hash: {}",
+            genesis_hash,
+            error
+        )
+    })?;
+    let hrp = config.custom_bech32_hrp.as_deref().unwrap_or_default();
+    let prefixes = AddressPrefixes {
+        p2pkh: config.custom_p2pkh_prefix.unwrap_or_default(),
+        p2sh: config.custom_p2sh_prefix.unwrap_or_default(),
+        bech32_hrp: bech32::Hrp::parse(hrp)
+            .map_err(|error| anyhow!("custom_bech32_hrp '{}' is invalid: {}", hrp, error))?,
+    };
+    CustomChain::new(
+        genesis_hash,
+        config.custom_genesis_header.as_deref().unwrap_or_default(),
+        prefixes,
+        !config.custom_skip_pow_check,
+    )
+    .map(Some)
+}
+
 /// Returns default daemon directory
 fn default_daemon_dir() -> PathBuf {
     let mut home = home_dir().unwrap_or_else(|| {
@@ -194,11 +961,85 @@ impl Config {
     pub fn from_args() -> Config {
         use internal::prelude::ResultExt;
 
-        let (mut config, _args) =
+        let (config, args) =
             internal::prelude::Config::including_optional_config_files(default_config_files())
                 .unwrap_or_exit();
+        let command = Command::parse(args).unwrap_or_else(|e| {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(1);
+        });
+
+        if config.version {
+            println!("v{}", ELECTRS_VERSION);
+            std::process::exit(0);
+        }
+
+        let log_filters = config.log_filters.clone();
+        let log_format = config.log_format;
+        let log_file = config.log_file.clone().map(|path| logger::FileOutput {
+            path,
+            max_size: match config.log_rotate_size_mb {
+                0 => None,
+                size_mb => Some(size_mb << 20),
+            },
+            keep: config.log_rotate_keep,
+            console: config.log_console,
+        });
+        let print_config = config.print_config;
+        let check_config = config.check_config;
+        let check_daemon = config.check_daemon;
+
+        let mut config = Self::from_parsed(config).unwrap_or_else(|e| {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(1);
+        });
+        if print_config {
+            // `SensitiveAuth` makes sure that the RPC password is not printed
+            println!("{:#?}", config);
+            std::process::exit(0);
+        }
+        if check_config {
+            let valid = config.check(check_daemon);
+            std::process::exit(if valid { 0 } else { 1 });
+        }
+        if command.is_some() {
+            config.command = command; // offline commands don't require bitcoind
+            return config;
+        }
+        let errors = config.validate();
+        if !errors.is_empty() {
+            for e in errors {
+                eprintln!("Error: {:#}", e);
+            }
+            std::process::exit(1);
+        }
+        eprintln!(
+            "Starting electrs {} (commit {}) on {} {} with {:?}",
+            ELECTRS_VERSION, ELECTRS_GIT_COMMIT, ARCH, OS, config
+        );
+        if let Err(e) = logger::init(log_format, log_filters.as_deref(), log_file) {
+            eprintln!("Error: failed to open log file: {}", e);
+            std::process::exit(1);
+        }
+
+        config
+    }
+
+    /// Create a builder, for configuring electrs programmatically (e.g. when embedding it).
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
 
-        let db_subdir = match config.network {
+    /// Post-processes the parsed options (without the command-line only ones)
+    fn from_parsed(mut config: internal::prelude::Config) -> Result<Config> {
+        let custom_chain = custom_chain(&config)?;
+        // custom chains use regtest's consensus parameters (e.g. the proof-of-work limit)
+        let network = match config.network {
+            BitcoinNetwork::Known(network) => network,
+            BitcoinNetwork::Custom => Network::Regtest,
+        };
+        let db_subdir = match network {
+            _ if custom_chain.is_some() => "custom",
             Network::Bitcoin => "bitcoin",
             Network::Testnet => "testnet",
             Network::Testnet4 => "testnet4",
@@ -208,28 +1049,28 @@ impl Config {
 
         config.db_dir.push(db_subdir);
 
-        let default_daemon_rpc_port = match config.network {
+        let default_daemon_rpc_port = match network {
             Network::Bitcoin => 8332,
             Network::Testnet => 18332,
             Network::Testnet4 => 48332,
             Network::Regtest => 18443,
             Network::Signet => 38332,
         };
-        let default_daemon_p2p_port = match config.network {
+        let default_daemon_p2p_port = match network {
             Network::Bitcoin => 8333,
             Network::Testnet => 18333,
             Network::Testnet4 => 48333,
             Network::Regtest => 18444,
             Network::Signet => 38333,
         };
-        let default_electrum_port = match config.network {
+        let default_electrum_port = match network {
             Network::Bitcoin => 50001,
             Network::Testnet => 60001,
             Network::Testnet4 => 40001,
             Network::Regtest => 60401,
             Network::Signet => 60601,
         };
-        let default_monitoring_port = match config.network {
+        let default_monitoring_port = match network {
             Network::Bitcoin => 4224,
             Network::Testnet => 14224,
             Network::Testnet4 => 44224,
@@ -238,41 +1079,86 @@ impl Config {
         };
 
         let magic = match config.magic {
-            Some(magic_hex) => magic_hex.parse().unwrap_or_else(|error| {
-                eprintln!(
-                    "Error: magic '{}' is not a valid hex string: {}",
-                    magic_hex, error
-                );
-                std::process::exit(1);
-            }),
-            None => config.network.magic(),
+            Some(magic_hex) => magic_hex.parse().map_err(|error| {
+                anyhow!("magic '{}' is not a valid hex string: {}", magic_hex, error)
+            })?,
+            None => network.magic(),
         };
 
-        let daemon_rpc_addr: SocketAddr = config.daemon_rpc_addr.map_or(
-            (DEFAULT_SERVER_ADDRESS, default_daemon_rpc_port).into(),
-            ResolvAddr::resolve_or_exit,
+        let (daemon_rpc_addr, daemon_rpc_https) = match config
+            .daemon_rpc_addr
+            .map(ResolvAddr::strip_rpc_scheme)
+            .transpose()?
+        {
+            Some((addr, https)) => (Some(addr), https),
+            None => (None, false),
+        };
+        let daemon_rpc_tls = daemon_rpc_tls(
+            daemon_rpc_https,
+            config.daemon_rpc_ca_file,
+            config.daemon_rpc_cert_sha256.as_deref(),
+        )?;
+        let daemon_rpc_addr = daemon_addr(
+            daemon_rpc_addr,
+            config
+                .custom_daemon_rpc_port
+                .unwrap_or(default_daemon_rpc_port),
+            config.daemon_rpc_proxy.as_deref(),
+        )?;
+        let daemon_p2p_addr = daemon_addr(
+            config.daemon_p2p_addr,
+            config
+                .custom_daemon_p2p_port
+                .unwrap_or(default_daemon_p2p_port),
+            config.daemon_p2p_proxy.as_deref(),
+        )?;
+        let electrum_rpc_listeners: Vec<ListenerConfig> = match (
+            config.electrum_rpc_addr.take(),
+            config.electrum_rpc_listeners.take(),
+        ) {
+            (Some(_), Some(_)) => {
+                bail!("electrum_rpc_addr and electrum_rpc_listeners can't be specified together")
+            }
+            (_, Some(list)) => list
+                .0
+                .into_iter()
+                .map(|entry| entry.resolve(&config))
+                .collect::<Result<_>>()?,
+            (addrs, None) => addrs
+                .map_or(
+                    Ok(vec![(DEFAULT_SERVER_ADDRESS, default_electrum_port).into()]),
+                    ResolvAddrList::resolve_all,
+                )?
+                .into_iter()
+                .map(|addr| ListenerConfig::new(addr, &config))
+                .collect(),
+        };
+        ensure!(
+            !electrum_rpc_listeners.is_empty(),
+            "electrum_rpc_addr must not be empty"
         );
-        let daemon_p2p_addr: SocketAddr = config.daemon_p2p_addr.map_or(
-            (DEFAULT_SERVER_ADDRESS, default_daemon_p2p_port).into(),
-            ResolvAddr::resolve_or_exit,
+        let electrum_rpc_addrs: Vec<SocketAddr> = electrum_rpc_listeners
+            .iter()
+            .map(|listener| listener.addr)
+            .collect();
+        #[cfg(not(feature = "metrics"))]
+        ensure!(
+            config.monitoring_addr.is_none(),
+            "enable \"metrics\" feature to specify monitoring_addr"
         );
-        let electrum_rpc_addr: SocketAddr = config.electrum_rpc_addr.map_or(
-            (DEFAULT_SERVER_ADDRESS, default_electrum_port).into(),
-            ResolvAddr::resolve_or_exit,
-        );
-        #[cfg(not(feature = "metrics"))]
-        {
-            if config.monitoring_addr.is_some() {
-                eprintln!("Error: enable \"metrics\" feature to specify monitoring_addr");
-                std::process::exit(1);
-            }
-        }
-        let monitoring_addr: SocketAddr = config.monitoring_addr.map_or(
-            (DEFAULT_SERVER_ADDRESS, default_monitoring_port).into(),
-            ResolvAddr::resolve_or_exit,
+        let monitoring_addr: SocketAddr = config.monitoring_addr.map_or(
+            Ok((DEFAULT_SERVER_ADDRESS, default_monitoring_port).into()),
+            ResolvAddr::resolve_any,
+        )?;
+        #[cfg(not(feature = "http"))]
+        ensure!(
+            config.http_addr.is_none(),
+            "enable \"http\" feature to specify http_addr"
         );
+        let http_addr = config.http_addr.map(ResolvAddr::resolve_any).transpose()?;
 
-        match config.network {
+        match network {
+            _ if custom_chain.is_some() => (), // `daemon_dir` should point to the chain's directory
             Network::Bitcoin => (),
             Network::Testnet => config.daemon_dir.push("testnet3"),
             Network::Testnet4 => config.daemon_dir.push("testnet4"),
@@ -280,105 +1166,488 @@ impl Config {
             Network::Signet => config.daemon_dir.push("signet"),
         }
 
-        let mut deprecated_options_used = false;
-
-        if config.timestamp {
-            eprintln!(
-                "Error: `timestamp` is deprecated, timestamps on logs is (and was) always \
-                enabled, please remove this option."
-            );
-            deprecated_options_used = true;
-        }
-
-        if config.verbose > 0 {
-            eprintln!("Error: please use `log_filters` to set logging verbosity",);
-            deprecated_options_used = true;
-        }
-
-        if deprecated_options_used {
-            std::process::exit(1);
-        }
+        ensure!(
+            !config.timestamp,
+            "`timestamp` is deprecated, timestamps on logs is (and was) always \
+            enabled, please remove this option."
+        );
+        ensure!(
+            config.verbose == 0,
+            "please use `log_filters` to set logging verbosity"
+        );
 
         let daemon_dir = &config.daemon_dir;
-        let daemon_auth = SensitiveAuth(match (config.auth, config.cookie_file) {
-            (None, None) => Auth::CookieFile(daemon_dir.join(".cookie")),
-            (None, Some(cookie_file)) => Auth::CookieFile(cookie_file),
-            (Some(auth), None) => {
+        let user_pass = match (config.daemon_rpc_user, config.daemon_rpc_password) {
+            (None, None) => None,
+            (Some(user), Some(password)) => Some(Auth::UserPass(user, password)),
+            _ => bail!("daemon_rpc_user and daemon_rpc_password must be specified together"),
+        };
+        let server_password = match config.server_password.take() {
+            Some(password) if password.is_empty() => bail!("server_password must not be empty"),
+            password => password.as_deref().map(SensitivePassword::new),
+        };
+
+        let daemon_auth = SensitiveAuth(match (config.auth, config.cookie_file, user_pass) {
+            (None, None, None) => Auth::CookieFile(daemon_dir.join(".cookie")),
+            (None, Some(cookie_file), None) => Auth::CookieFile(cookie_file),
+            (Some(auth), None, None) => {
                 let parts: Vec<&str> = auth.splitn(2, ':').collect();
-                if parts.len() != 2 {
-                    eprintln!("Error: auth cookie doesn't contain colon");
-                    std::process::exit(1);
-                }
+                ensure!(parts.len() == 2, "auth cookie doesn't contain colon");
                 Auth::UserPass(parts[0].to_owned(), parts[1].to_owned())
             }
-            (Some(_), Some(_)) => {
-                eprintln!("Error: ambiguous configuration - auth and cookie_file can't be specified at the same time");
-                std::process::exit(1);
+            (None, None, Some(user_pass)) => user_pass,
+            (Some(_), Some(_), _) => {
+                bail!("ambiguous configuration - auth and cookie_file can't be specified at the same time");
+            }
+            (_, _, Some(_)) => {
+                bail!("ambiguous configuration - daemon_rpc_user can't be specified with auth or cookie_file");
             }
         });
 
-        let log_filters = config.log_filters;
-
         let index_lookup_limit = match config.index_lookup_limit {
             0 => None,
             _ => Some(config.index_lookup_limit),
         };
+        let max_reorg_depth = match config.max_reorg_depth {
+            0 => None,
+            _ => Some(config.max_reorg_depth),
+        };
+        let assume_final_depth = match config.assume_final_depth {
+            0 => None,
+            _ => Some(config.assume_final_depth),
+        };
+        let headers_in_memory = match config.headers_in_memory_k {
+            0 => None,
+            _ => Some(config.headers_in_memory_k * 1000),
+        };
+        let max_connections = match config.max_connections {
+            0 => None,
+            _ => Some(config.max_connections),
+        };
+        let max_connections_per_ip = match config.max_connections_per_ip {
+            0 => None,
+            _ => Some(config.max_connections_per_ip),
+        };
+        let flush_policy = FlushPolicy {
+            blocks: match config.flush_every_blocks {
+                0 => None,
+                blocks => Some(blocks),
+            },
+            interval: match config.flush_interval_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            bytes: match config.flush_max_pending_mb {
+                0 => None,
+                mb => Some(mb.saturating_mul(1 << 20)),
+            },
+        };
+        let max_subscriptions = match config.max_subscriptions {
+            0 => None,
+            _ => Some(config.max_subscriptions),
+        };
+        let upload_rate = |kb: usize| match kb {
+            0 => None,
+            _ => Some(kb * (1 << 10)),
+        };
+        let query_threads = config
+            .query_threads
+            .filter(|&n| n > 0)
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
 
-        if config.jsonrpc_timeout_secs <= config.wait_duration_secs {
-            eprintln!(
-                "Error: jsonrpc_timeout_secs ({}) must be higher than wait_duration_secs ({})",
-                config.jsonrpc_timeout_secs, config.wait_duration_secs
-            );
-            std::process::exit(1);
-        }
-
-        if config.version {
-            println!("v{}", ELECTRS_VERSION);
-            std::process::exit(0);
-        }
+        ensure!(
+            config.jsonrpc_timeout_secs > config.wait_duration_secs,
+            "jsonrpc_timeout_secs ({}) must be higher than wait_duration_secs ({})",
+            config.jsonrpc_timeout_secs,
+            config.wait_duration_secs
+        );
+        // otherwise, every peer would be disconnected on its first response
+        ensure!(
+            config.max_send_queue_mb > 0,
+            "max_send_queue_mb must be positive"
+        );
+        // otherwise, every request would be refused (disconnecting its peer)
+        ensure!(
+            config.max_request_size_mb > 0,
+            "max_request_size_mb must be positive"
+        );
+        ensure!(
+            config.event_watch_file.is_none() || config.event_socket.is_some(),
+            "event_watch_file requires event_socket"
+        );
+        // otherwise, the DB may stay in bulk-import mode (without a WAL) until the next restart
+        ensure!(
+            !config.defer_initial_compaction || config.maintenance_window.is_some(),
+            "defer_initial_compaction requires maintenance_window"
+        );
 
-        let config = Config {
-            network: config.network,
+        Ok(Config {
+            network,
+            custom_chain,
             db_path: config.db_dir,
             db_log_dir: config.db_log_dir,
             db_parallelism: config.db_parallelism,
+            db_durability: config.db_durability,
             daemon_auth,
             daemon_rpc_addr,
+            daemon_rpc_tls,
             daemon_p2p_addr,
-            electrum_rpc_addr,
+            daemon_blocks_dir: config.daemon_blocks_dir,
+            electrum_rpc_addrs,
+            electrum_rpc_listeners,
+            server_hostname: config.server_hostname,
+            announce_tcp_port: config.announce_tcp_port,
+            announce_ssl_port: config.announce_ssl_port,
+            announce_ws_port: config.announce_ws_port,
             monitoring_addr,
+            http_addr,
+            http_cors_origin: config.http_cors_origin,
             wait_duration: Duration::from_secs(config.wait_duration_secs),
             jsonrpc_timeout: Duration::from_secs(config.jsonrpc_timeout_secs),
+            fee_cache_max_age: Duration::from_secs(config.fee_cache_max_age_secs),
             index_batch_size: config.index_batch_size,
             index_lookup_limit,
+            max_reorg_depth,
+            assume_final_depth,
+            allow_deep_reorg: config.allow_deep_reorg,
+            checkpoints: config
+                .checkpoints
+                .map_or_else(|| Checkpoints::for_network(network), |list| list.0),
+            maintenance_window: config.maintenance_window,
+            flush_policy,
+            defer_initial_compaction: config.defer_initial_compaction,
+            max_connections,
+            max_connections_per_ip,
+            max_concurrent_requests: config.max_concurrent_requests.max(1),
+            max_subscriptions,
+            block_get_allow: config.block_get_allow.map_or_else(Vec::new, |list| list.0),
+            status_debug_allow: config
+                .status_debug_allow
+                .map_or_else(Vec::new, |list| list.0),
+            server_password,
+            query_threads,
+            max_send_queue_bytes: config.max_send_queue_mb * (1 << 20),
+            max_request_bytes: config.max_request_size_mb * (1 << 20),
+            max_broadcast_bytes: config.max_broadcast_size_mb * (1 << 20),
+            send_timeout: Duration::from_secs(config.send_timeout_secs),
+            tcp: TcpOptions {
+                nodelay: config.tcp_nodelay,
+                keepalive: (config.tcp_keepalive_idle_secs > 0).then(|| Keepalive {
+                    idle: Duration::from_secs(config.tcp_keepalive_idle_secs),
+                    interval: Duration::from_secs(config.tcp_keepalive_interval_secs),
+                    count: config.tcp_keepalive_count,
+                }),
+                backlog: config.tcp_listen_backlog,
+            },
+            max_upload_rate: upload_rate(config.max_upload_rate_kb),
+            max_peer_upload_rate: upload_rate(config.max_peer_upload_rate_kb),
+            ban_file: config.ban_file,
             reindex_last_blocks: config.reindex_last_blocks,
+            headers_in_memory,
             auto_reindex: config.auto_reindex,
+            repair_db: config.repair_db,
+            shadow_migration: config.shadow_migration,
+            shadow_db: config.shadow_db,
+            shadow_db_sample_rate: config.shadow_db_sample_rate.max(1),
+            shadow_db_max_per_sec: config.shadow_db_max_per_sec,
+            merkle_cache: config.merkle_cache,
+            prefetch_blocks: config.prefetch_blocks,
+            cache_budgets: CacheBudgets {
+                tx: config.cache_tx_mb * (1 << 20),
+                tx_info: config.cache_tx_info_mb * (1 << 20),
+                block_txids: config.cache_block_txids_mb * (1 << 20),
+                block: config.cache_block_mb * (1 << 20),
+            },
+            index_script_types: config
+                .index_script_types
+                .map_or_else(ScriptTypes::all, |list| list.0),
+            index_prefix_len: config.index_prefix_len,
             ignore_mempool: config.ignore_mempool,
             sync_once: config.sync_once,
+            on_index_failure: config.on_index_failure,
             skip_block_download_wait: config.skip_block_download_wait,
             disable_electrum_rpc: config.disable_electrum_rpc,
             server_banner: config.server_banner,
+            banner_file: config.banner_file,
+            scripthash_allowlist_file: config.scripthash_allowlist_file,
+            event_socket: config.event_socket,
+            event_watch_file: config.event_watch_file,
+            event_queue_size: config.event_queue_size.max(1),
+            replay_log: config.replay_log,
+            replay_log_max_bytes: config.replay_log_max_mb.max(1).saturating_mul(1 << 20),
             magic,
-        };
-        eprintln!(
-            "Starting electrs {} on {} {} with {:?}",
-            ELECTRS_VERSION, ARCH, OS, config
-        );
-        let mut builder = env_logger::Builder::from_default_env();
-        builder.default_format().format_timestamp_millis();
-        if let Some(log_filters) = &log_filters {
-            builder.parse_filters(log_filters);
+            command: None,
+        })
+    }
+
+    /// The genesis block header of the configured chain
+    pub(crate) fn genesis(&self) -> BlockHeader {
+        self.custom_chain.as_ref().map_or_else(
+            || bitcoin::constants::genesis_block(self.network).header,
+            |custom| custom.genesis,
+        )
+    }
+
+    /// Decodes the addresses of the configured chain (e.g. in `scripthash_allowlist_file`)
+    pub(crate) fn address_decoder(&self) -> AddressDecoder {
+        match &self.custom_chain {
+            Some(custom) => AddressDecoder::Custom(custom.prefixes.clone()),
+            None => AddressDecoder::Network(self.network),
         }
-        builder.init();
+    }
 
-        config
+    /// Checks that the configured paths are usable.
+    ///
+    /// Done on every startup, and by `--check-config` (which also checks the listening addresses
+    /// and bitcoind connectivity).
+    pub(crate) fn validate(&self) -> Vec<anyhow::Error> {
+        let mut results = vec![check_dir(&self.db_path).context("invalid db_dir")];
+        if let Some(db_log_dir) = &self.db_log_dir {
+            results.push(check_dir(db_log_dir).context("invalid db_log_dir"));
+        }
+        if let Auth::CookieFile(path) = &self.daemon_auth.0 {
+            results.push(check_cookie_file(path));
+        }
+        if let Some(dir) = &self.daemon_blocks_dir {
+            let result = fs::read_dir(dir).map(drop);
+            results.push(
+                result.with_context(|| format!("invalid daemon_blocks_dir {}", dir.display())),
+            );
+        }
+        results.into_iter().filter_map(Result::err).collect()
+    }
+
+    /// Validates the configuration and prints a summary (returns `false` if there are errors).
+    fn check(&self, check_daemon: bool) -> bool {
+        println!("electrs {} configuration:", ELECTRS_VERSION);
+        match &self.custom_chain {
+            Some(custom) => println!(
+                "  network:           custom (genesis {})",
+                custom.genesis_hash()
+            ),
+            None => println!("  network:           {}", self.network),
+        }
+        println!("  db_dir:            {}", self.db_path.display());
+        println!("  daemon_auth:       {:?}", self.daemon_auth);
+        println!("  daemon_rpc_addr:   {}", self.daemon_rpc_addr);
+        if let Some(tls) = &self.daemon_rpc_tls {
+            println!("  daemon_rpc_tls:    {:?}", tls);
+        }
+        println!("  daemon_p2p_addr:   {}", self.daemon_p2p_addr);
+        if let Some(dir) = &self.daemon_blocks_dir {
+            println!("  daemon_blocks_dir: {}", dir.display());
+        }
+
+        let mut errors = self.validate();
+        if self.disable_electrum_rpc {
+            println!("  electrum_rpc_addr: disabled");
+        } else {
+            for addr in &self.electrum_rpc_addrs {
+                println!("  electrum_rpc_addr: {}", addr);
+                errors.extend(check_bind(*addr).err());
+            }
+        }
+        if cfg!(feature = "metrics") {
+            println!("  monitoring_addr:   {}", self.monitoring_addr);
+            errors.extend(check_bind(self.monitoring_addr).err());
+        }
+        if let Some(http_addr) = self.http_addr {
+            println!("  http_addr:         {}", http_addr);
+            errors.extend(check_bind(http_addr).err());
+        }
+        if check_daemon {
+            match daemon::probe(self) {
+                Ok(summary) => println!("  bitcoind:          {}", summary),
+                Err(e) => errors.push(e.context("bitcoind check failed")),
+            }
+        }
+
+        for e in &errors {
+            eprintln!("Error: {:#}", e);
+        }
+        if errors.is_empty() {
+            println!("configuration is valid");
+        } else {
+            println!("configuration is invalid ({} error(s))", errors.len());
+        }
+        errors.is_empty()
+    }
+}
+
+/// Builds a [`Config`] from options named as in `electrs --help` (without reading config files).
+///
+/// The options are parsed and validated as if they were passed via the command line, so the
+/// defaults (e.g. the network-specific ports) are the same. `ELECTRS_*` environment variables
+/// are applied too.
+#[derive(Clone, Default)]
+pub struct ConfigBuilder {
+    args: Vec<OsString>,
+    auth: Option<String>,            // can't be passed as an argument
+    server_password: Option<String>, // can't be passed as an argument
+}
+
+impl ConfigBuilder {
+    /// Set an option (e.g. `option("db_dir", "/path/to/db")`)
+    pub fn option(mut self, name: &str, value: impl AsRef<OsStr>) -> Self {
+        let mut arg = OsString::from(format!("--{}=", name.replace('_', "-")));
+        arg.push(value);
+        self.args.push(arg);
+        self
+    }
+
+    /// Enable a switch (e.g. `flag("sync_once")`)
+    pub fn flag(mut self, name: &str) -> Self {
+        self.args
+            .push(OsString::from(format!("--{}", name.replace('_', "-"))));
+        self
+    }
+
+    pub fn network(self, network: Network) -> Self {
+        self.option("network", network.to_string())
+    }
+
+    pub fn db_dir(self, path: impl AsRef<Path>) -> Self {
+        self.option("db_dir", path.as_ref())
+    }
+
+    pub fn daemon_dir(self, path: impl AsRef<Path>) -> Self {
+        self.option("daemon_dir", path.as_ref())
+    }
+
+    pub fn daemon_rpc_addr(self, addr: SocketAddr) -> Self {
+        self.option("daemon_rpc_addr", addr.to_string())
+    }
+
+    pub fn daemon_p2p_addr(self, addr: SocketAddr) -> Self {
+        self.option("daemon_p2p_addr", addr.to_string())
+    }
+
+    pub fn electrum_rpc_addr(self, addr: SocketAddr) -> Self {
+        self.option("electrum_rpc_addr", addr.to_string())
+    }
+
+    pub fn monitoring_addr(self, addr: SocketAddr) -> Self {
+        self.option("monitoring_addr", addr.to_string())
+    }
+
+    pub fn http_addr(self, addr: SocketAddr) -> Self {
+        self.option("http_addr", addr.to_string())
+    }
+
+    /// Use JSONRPC user and password (instead of the cookie file)
+    pub fn auth(mut self, user: &str, password: &str) -> Self {
+        self.auth = Some(format!("{}:{}", user, password));
+        self
+    }
+
+    /// Require Electrum RPC clients to authenticate using this password
+    pub fn server_password(mut self, password: &str) -> Self {
+        self.server_password = Some(password.to_owned());
+        self
+    }
+
+    /// Parse and validate the options
+    pub fn build(self) -> Result<Config> {
+        let program = OsString::from("electrs");
+        let args = std::iter::once(program).chain(self.args);
+        let (mut config, _args) = internal::prelude::Config::custom_args_and_optional_files(
+            args,
+            std::iter::empty::<PathBuf>(),
+        )
+        .map_err(|e| anyhow!("{}", e))?;
+        if self.auth.is_some() {
+            config.auth = self.auth;
+        }
+        if self.server_password.is_some() {
+            config.server_password = self.server_password;
+        }
+        let config = Config::from_parsed(config)?;
+        let errors = config.validate();
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
+        }
+        Ok(config)
+    }
+}
+
+/// Checks that `path` is a writable directory, or that it can be created (RocksDB creates it,
+/// but not its parents).
+fn check_dir(path: &Path) -> Result<()> {
+    let dir = if path.exists() {
+        path
+    } else {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    };
+    let metadata =
+        fs::metadata(dir).with_context(|| format!("{} is not accessible", dir.display()))?;
+    ensure!(metadata.is_dir(), "{} is not a directory", dir.display());
+    let probe = dir.join(format!(".electrs-check-{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .with_context(|| format!("{} is not writable", dir.display()))?;
+    fs::remove_file(&probe).with_context(|| format!("failed to remove {}", probe.display()))
+}
+
+fn check_cookie_file(path: &Path) -> Result<()> {
+    if let Err(e) = File::open(path) {
+        // e.g. `daemon_dir = "~/.bitcoin/testnet3"` results in `~/.bitcoin/testnet3/testnet3/.cookie`
+        let dir = path.parent();
+        let network_subdir = dir.and_then(Path::file_name);
+        if network_subdir.is_some()
+            && network_subdir == dir.and_then(Path::parent).and_then(Path::file_name)
+        {
+            bail!(
+                "failed to open bitcoind cookie file {}: {} (`daemon_dir` should point to the base data directory of bitcoind, without the network subdirectory)",
+                path.display(),
+                e
+            );
+        }
+        bail!(
+            "failed to open bitcoind cookie file {}: {}",
+            path.display(),
+            e
+        );
+    }
+    Ok(())
+}
+
+fn check_bind(addr: SocketAddr) -> Result<()> {
+    match TcpListener::bind(addr) {
+        Ok(_listener) => Ok(()),
+        // e.g. when validating the configuration before restarting electrs
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            eprintln!(
+                "Warning: {} is already in use (possibly by a running electrs)",
+                addr
+            );
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to bind {}", addr)),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Auth, SensitiveAuth};
-    use std::path::Path;
+    use super::{
+        check_cookie_file, check_dir, internal, Auth, CheckpointList, Config, Durability, IpNet,
+        IpNetList, OnIndexFailure, PrefixLen, ResolvAddrList, ScriptTypeList, SensitiveAuth,
+        SensitivePassword,
+    };
+    use crate::proxy::RpcTls;
+    use bitcoin::Network;
+    use configure_me::parse_arg::ParseArg;
+    use std::ffi::OsStr;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    fn addrs(list: ResolvAddrList) -> Vec<String> {
+        list.0.into_iter().map(|addr| addr.0).collect()
+    }
 
     #[test]
     fn test_auth_debug() {
@@ -397,4 +1666,631 @@ mod tests {
             "UserPass(\"user\", \"<sensitive>\")"
         );
     }
+
+    #[test]
+    fn test_server_password() {
+        let password = SensitivePassword::new("hunter2");
+        assert!(password.matches("hunter2"));
+        assert!(!password.matches("hunter"));
+        assert!(!password.matches("hunter22"));
+        assert!(!password.matches(""));
+        assert_eq!(format!("{:?}", password), "<sensitive>");
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("electrs.toml");
+        let parse = |contents: &str| {
+            std::fs::write(&file, contents).unwrap();
+            let args = ["electrs"];
+            let (config, _args) =
+                internal::prelude::Config::custom_args_and_optional_files(args, [&file]).unwrap();
+            Config::from_parsed(config)
+        };
+        let config = parse("").unwrap();
+        assert!(config.server_password.is_none()); // disabled by default
+
+        let config = parse("server_password = \"hunter2\"\n").unwrap();
+        assert!(config.server_password.as_ref().unwrap().matches("hunter2"));
+        // redacted by `--print-config`
+        assert!(!format!("{:?}", config).contains("hunter2"));
+
+        let err = parse("server_password = \"\"\n").unwrap_err();
+        assert!(err.to_string().contains("server_password"), "{}", err);
+
+        // can't be passed as an argument (to avoid leaking it via `ps`)
+        let args = ["electrs", "--server-password=hunter2"];
+        assert!(internal::prelude::Config::custom_args_and_optional_files(
+            args,
+            std::iter::empty::<PathBuf>()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_check_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        // existing directory, or a missing one (created by RocksDB)
+        check_dir(dir.path()).unwrap();
+        check_dir(&dir.path().join("bitcoin")).unwrap();
+
+        let err = check_dir(&dir.path().join("missing").join("bitcoin")).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("missing is not accessible"),
+            "{:#}",
+            err
+        );
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let err = check_dir(&file.join("bitcoin")).unwrap_err();
+        assert!(
+            err.to_string().ends_with("file is not a directory"),
+            "{}",
+            err
+        );
+
+        // the probe file is removed
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_check_cookie_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cookie = dir.path().join("testnet3").join(".cookie");
+        let err = check_cookie_file(&cookie).unwrap_err();
+        assert!(!err.to_string().contains("daemon_dir"), "{}", err);
+
+        let cookie = dir.path().join("testnet3").join("testnet3").join(".cookie");
+        let err = check_cookie_file(&cookie).unwrap_err();
+        assert!(err.to_string().contains("daemon_dir"), "{}", err);
+
+        let cookie = dir.path().join(".cookie");
+        std::fs::write(&cookie, "user:pass").unwrap();
+        check_cookie_file(&cookie).unwrap();
+    }
+
+    #[test]
+    fn test_addr_list() {
+        let list = ResolvAddrList::parse_arg(OsStr::new("127.0.0.1:50001")).unwrap();
+        assert_eq!(addrs(list), vec!["127.0.0.1:50001"]);
+
+        let list = ResolvAddrList::parse_arg(OsStr::new("127.0.0.1:50001, [::1]:50001,")).unwrap();
+        assert_eq!(addrs(list), vec!["127.0.0.1:50001", "[::1]:50001"]);
+
+        #[derive(Deserialize)]
+        struct File {
+            addr: ResolvAddrList,
+        }
+        let file: File =
+            configure_me::toml::from_str("addr = \"localhost:1,localhost:2\"").unwrap();
+        assert_eq!(addrs(file.addr), vec!["localhost:1", "localhost:2"]);
+        let file: File =
+            configure_me::toml::from_str("addr = [\"localhost:1\", \"localhost:2\"]").unwrap();
+        assert_eq!(addrs(file.addr), vec!["localhost:1", "localhost:2"]);
+    }
+
+    #[test]
+    fn test_script_type_list() {
+        let types = |list: &str| list.parse::<ScriptTypeList>().map(|l| l.0.to_string());
+        assert_eq!(types("all").unwrap(), "all");
+        assert_eq!(types("p2tr, p2wpkh,").unwrap(), "p2wpkh,p2tr");
+        assert_eq!(
+            types("p2pk,p2pkh,p2sh,p2wpkh,p2wsh,p2tr,other").unwrap(),
+            "all"
+        );
+        // `OP_RETURN` outputs are indexed only if selected explicitly
+        assert_eq!(
+            types("p2pk,p2pkh,p2sh,p2wpkh,p2wsh,p2tr,other,op_return").unwrap(),
+            "p2pk,p2pkh,p2sh,p2wpkh,p2wsh,p2tr,other,op_return"
+        );
+        assert!(types("").is_err());
+        assert!(types("p2wpkh,bech32").is_err());
+
+        #[derive(Deserialize)]
+        struct File {
+            types: ScriptTypeList,
+        }
+        let file: File = configure_me::toml::from_str("types = [\"p2sh\", \"p2wpkh\"]").unwrap();
+        assert_eq!(file.types.0.to_string(), "p2sh,p2wpkh");
+        assert!(configure_me::toml::from_str::<File>("types = [\"p2pkh\", \"all\"]").is_err());
+    }
+
+    #[test]
+    fn test_ip_nets() {
+        let net = |net: &str| net.parse::<IpNet>().unwrap();
+        let ip = |ip: &str| ip.parse().unwrap();
+        assert_eq!(net("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(net("::1").to_string(), "::1/128");
+        assert!(net("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!net("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(net("10.0.0.0/8").contains(ip("::ffff:10.0.0.1"))); // IPv4-mapped
+        assert!(!net("10.0.0.0/8").contains(ip("::1")));
+        assert!(net("192.168.1.7").contains(ip("192.168.1.7")));
+        assert!(!net("192.168.1.7").contains(ip("192.168.1.8")));
+        assert!(net("0.0.0.0/0").contains(ip("1.2.3.4")));
+        assert!(net("::/0").contains(ip("2001:db8::1")));
+        assert!(net("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+
+        let list = |list: &str| list.parse::<IpNetList>().map(|l| l.0.len());
+        assert_eq!(list("10.0.0.0/8, ::1,"), Ok(2));
+        assert_eq!(list(""), Ok(0));
+        assert!(list("10.0.0.0/8,localhost").is_err());
+
+        #[derive(Deserialize)]
+        struct File {
+            nets: IpNetList,
+        }
+        let file: File = configure_me::toml::from_str("nets = [\"10.0.0.0/8\", \"::1\"]").unwrap();
+        assert_eq!(file.nets.0, vec![net("10.0.0.0/8"), net("::1")]);
+    }
+
+    #[test]
+    fn test_checkpoint_list() {
+        let checkpoints = |list: &str| list.parse::<CheckpointList>().map(|l| l.0.to_string());
+        let hash = "0000000000000000000000000000000000000000000000000000000000000001";
+        assert_eq!(checkpoints("none").unwrap(), "none");
+        assert_eq!(
+            checkpoints(&format!("20:{}, 10:{},", hash, hash)).unwrap(),
+            format!("10:{},20:{}", hash, hash)
+        );
+        assert!(checkpoints("").is_err());
+        assert!(checkpoints(hash).is_err());
+        assert!(checkpoints(&format!("-1:{}", hash)).is_err());
+        assert!(checkpoints("10:0001").is_err());
+    }
+
+    // Environment variables are process-wide, so all env-related checks are kept in one test.
+    #[test]
+    fn test_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("electrs.toml");
+        std::fs::write(
+            &file,
+            "db_dir = \"/from/file\"\nindex_batch_size = 3\nwait_duration_secs = 7\n",
+        )
+        .unwrap();
+        let parse = |args: &[&str]| {
+            let args = std::iter::once("electrs").chain(args.iter().copied());
+            internal::prelude::Config::custom_args_and_optional_files(args, [&file])
+                .map(|(config, _args)| config)
+        };
+
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.db_dir, PathBuf::from("/from/file"));
+        assert_eq!(config.index_batch_size, 3);
+        assert_eq!(config.wait_duration_secs, 7);
+        assert_eq!(config.jsonrpc_timeout_secs, 15); // default
+
+        std::env::set_var("ELECTRS_DB_DIR", "/from/env");
+        std::env::set_var("ELECTRS_INDEX_BATCH_SIZE", "5");
+        std::env::set_var("ELECTRS_ELECTRUM_RPC_ADDR", "127.0.0.1:1,127.0.0.1:2");
+        let config = parse(&["--db-dir", "/from/args"]).unwrap();
+        assert_eq!(config.db_dir, PathBuf::from("/from/args"));
+        assert_eq!(config.index_batch_size, 5);
+        assert_eq!(config.wait_duration_secs, 7);
+        assert_eq!(
+            addrs(config.electrum_rpc_addr.unwrap()),
+            vec!["127.0.0.1:1", "127.0.0.1:2"]
+        );
+
+        std::env::set_var("ELECTRS_INDEX_BATCH_SIZE", "many");
+        let err = parse(&[]).err().expect("invalid env var must fail");
+        assert!(
+            err.to_string().contains("ELECTRS_INDEX_BATCH_SIZE"),
+            "{}",
+            err
+        );
+
+        for var in [
+            "ELECTRS_DB_DIR",
+            "ELECTRS_INDEX_BATCH_SIZE",
+            "ELECTRS_ELECTRUM_RPC_ADDR",
+        ] {
+            std::env::remove_var(var);
+        }
+
+        // the builder's options are parsed like command-line arguments (without config files)
+        let config = Config::builder()
+            .network(Network::Regtest)
+            .db_dir(dir.path())
+            .auth("user", "pass")
+            .flag("sync_once")
+            .build()
+            .unwrap();
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.db_path, dir.path().join("regtest"));
+        assert_eq!(config.daemon_rpc_addr.to_string(), "127.0.0.1:18443");
+        assert_eq!(
+            config.electrum_rpc_addrs,
+            vec!["127.0.0.1:60401".parse().unwrap()]
+        );
+        assert_eq!(config.index_batch_size, 10); // default
+        assert!(config.sync_once);
+        assert_eq!(config.on_index_failure, OnIndexFailure::Exit); // default
+        assert_eq!(config.db_durability, Durability::Sync); // default
+        assert!(matches!(config.daemon_auth.0, Auth::UserPass(..)));
+        let config = Config::builder()
+            .db_dir(dir.path())
+            .auth("user", "pass")
+            .option("on_index_failure", "serve-stale")
+            .option("db_durability", "wal")
+            .build()
+            .unwrap();
+        assert_eq!(config.on_index_failure, OnIndexFailure::ServeStale);
+        assert_eq!(config.db_durability, Durability::Wal);
+        assert_eq!(config.index_prefix_len, PrefixLen::Eight); // default
+        let config = Config::builder()
+            .db_dir(dir.path())
+            .auth("user", "pass")
+            .option("index_prefix_len", "6")
+            .build()
+            .unwrap();
+        assert_eq!(config.index_prefix_len, PrefixLen::Six);
+        let err = Config::builder()
+            .db_dir(dir.path())
+            .auth("user", "pass")
+            .option("index_prefix_len", "7")
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("index-prefix-len"), "{}", err);
+        let err = Config::builder()
+            .db_dir(dir.path())
+            .option("jsonrpc_timeout_secs", "1")
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("jsonrpc_timeout_secs"), "{}", err);
+        let err = Config::builder()
+            .db_dir(dir.path())
+            .auth("user", "pass")
+            .flag("defer_initial_compaction")
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("maintenance_window"), "{}", err);
+        let config = Config::builder()
+            .db_dir(dir.path())
+            .auth("user", "pass")
+            .flag("defer_initial_compaction")
+            .option("maintenance_window", "04:00-06:00")
+            .build()
+            .unwrap();
+        assert!(config.defer_initial_compaction);
+    }
+
+    #[test]
+    fn test_tcp_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("electrs.toml");
+        let parse = |contents: &str| {
+            std::fs::write(&file, contents).unwrap();
+            let args = ["electrs"];
+            let (config, _args) =
+                internal::prelude::Config::custom_args_and_optional_files(args, [&file]).unwrap();
+            Config::from_parsed(config).unwrap().tcp
+        };
+
+        let tcp = parse("");
+        assert!(tcp.nodelay);
+        assert_eq!(tcp.backlog, 128);
+        let keepalive = tcp.keepalive.unwrap();
+        assert_eq!(keepalive.idle, Duration::from_secs(600));
+        assert_eq!(keepalive.interval, Duration::from_secs(60));
+        assert_eq!(keepalive.count, 5);
+
+        let tcp = parse(
+            "tcp_nodelay = false\ntcp_keepalive_idle_secs = 30\ntcp_keepalive_interval_secs = 5\n\
+            tcp_keepalive_count = 3\ntcp_listen_backlog = 1024\n",
+        );
+        assert!(!tcp.nodelay);
+        assert_eq!(tcp.backlog, 1024);
+        let keepalive = tcp.keepalive.unwrap();
+        assert_eq!(keepalive.idle, Duration::from_secs(30));
+        assert_eq!(keepalive.interval, Duration::from_secs(5));
+        assert_eq!(keepalive.count, 3);
+
+        assert_eq!(parse("tcp_keepalive_idle_secs = 0\n").keepalive, None);
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("electrs.toml");
+        let parse = |contents: &str| {
+            std::fs::write(&file, contents).unwrap();
+            let args = ["electrs"];
+            let (config, _args) =
+                internal::prelude::Config::custom_args_and_optional_files(args, [&file]).unwrap();
+            Config::from_parsed(config)
+        };
+        let config = parse("max_send_queue_mb = 1\n").unwrap();
+        assert_eq!(config.max_send_queue_bytes, 1 << 20);
+        let err = parse("max_send_queue_mb = 0\n").unwrap_err();
+        assert_eq!(err.to_string(), "max_send_queue_mb must be positive");
+
+        let config = parse("max_request_size_mb = 1\n").unwrap();
+        assert_eq!(config.max_request_bytes, 1 << 20);
+        let err = parse("max_request_size_mb = 0\n").unwrap_err();
+        assert_eq!(err.to_string(), "max_request_size_mb must be positive");
+    }
+
+    #[test]
+    fn test_electrum_rpc_listeners() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("electrs.toml");
+        let parse = |contents: &str, args: &[&str]| -> anyhow::Result<Config> {
+            std::fs::write(&file, contents).unwrap();
+            let args = std::iter::once("electrs").chain(args.iter().copied());
+            let (config, _args) =
+                internal::prelude::Config::custom_args_and_optional_files(args, [&file])
+                    .map_err(|e| anyhow!("{}", e))?;
+            Config::from_parsed(config)
+        };
+        let err = |contents: &str, args: &[&str]| parse(contents, args).unwrap_err().to_string();
+        let net = |net: &str| net.parse::<IpNet>().unwrap();
+
+        // a listener per address, using the global settings
+        let config = parse(
+            "electrum_rpc_addr = \"127.0.0.1:50001,127.0.0.1:50002\"\n\
+            block_get_allow = \"10.0.0.0/8\"\nbanner_file = \"/etc/banner\"\n",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.electrum_rpc_listeners.len(), 2);
+        for (listener, addr) in config
+            .electrum_rpc_listeners
+            .iter()
+            .zip(&config.electrum_rpc_addrs)
+        {
+            assert_eq!(listener.addr, *addr);
+            assert_eq!(listener.max_connections, None);
+            assert!(listener.disabled_methods.is_empty());
+            assert_eq!(listener.banner_file, Some(PathBuf::from("/etc/banner")));
+            assert_eq!(listener.block_get_allow, vec![net("10.0.0.0/8")]);
+            assert!(listener.admin);
+        }
+
+        // the listeners' own settings override the global ones
+        let config = parse(
+            "block_get_allow = \"10.0.0.0/8\"\nbanner_file = \"/etc/banner\"\n\
+            [[electrum_rpc_listeners]]\n\
+            addr = \"127.0.0.1:50001\"\n\
+            disabled_methods = [\"blockchain.scripthash.get_stats\"]\n\
+            [[electrum_rpc_listeners]]\n\
+            addr = \"127.0.0.1:50002\"\n\
+            max_connections = 100\n\
+            max_connections_per_ip = 4\n\
+            disabled_methods = [\"blockchain.transaction.broadcast\", \"server.banner\"]\n\
+            banner_file = \"/etc/public-banner\"\n\
+            block_get_allow = []\n\
+            status_debug_allow = \"::1\"\n\
+            admin = false\n",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            config.electrum_rpc_addrs,
+            vec![
+                "127.0.0.1:50001".parse().unwrap(),
+                "127.0.0.1:50002".parse().unwrap()
+            ]
+        );
+        let [wallets, public] = &config.electrum_rpc_listeners[..] else {
+            panic!("expected 2 listeners");
+        };
+        assert_eq!(wallets.max_connections, None);
+        assert_eq!(wallets.max_connections_per_ip, None);
+        assert_eq!(
+            wallets.disabled_methods,
+            ["blockchain.scripthash.get_stats"]
+        );
+        assert_eq!(wallets.banner_file, Some(PathBuf::from("/etc/banner")));
+        assert_eq!(wallets.block_get_allow, vec![net("10.0.0.0/8")]);
+        assert!(wallets.status_debug_allow.is_empty());
+        assert!(wallets.admin);
+        assert_eq!(public.max_connections, Some(100));
+        assert_eq!(public.max_connections_per_ip, Some(4));
+        assert_eq!(
+            public.disabled_methods,
+            ["blockchain.transaction.broadcast", "server.banner"]
+        );
+        assert_eq!(
+            public.banner_file,
+            Some(PathBuf::from("/etc/public-banner"))
+        );
+        assert!(public.block_get_allow.is_empty());
+        assert_eq!(public.status_debug_allow, vec![net("::1")]);
+        assert!(!public.admin);
+
+        // the command line uses a JSON array
+        let config = parse(
+            "",
+            &[
+                "--electrum-rpc-listeners",
+                r#"[{"addr": "127.0.0.1:50003", "disabled_methods": ["server.ping"]}]"#,
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.electrum_rpc_listeners.len(), 1);
+        assert_eq!(
+            config.electrum_rpc_listeners[0].disabled_methods,
+            ["server.ping"]
+        );
+
+        let listener = |settings: &str| {
+            format!(
+                "[[electrum_rpc_listeners]]\naddr = \"127.0.0.1:50001\"\n{}",
+                settings
+            )
+        };
+        assert!(
+            err(&listener("disabled_methods = [\"server.unknown\"]\n"), &[])
+                .contains("unknown method: server.unknown")
+        );
+        assert!(err(&listener("tls = true\n"), &[]).contains("not supported"));
+        assert!(parse(&listener("max_conections = 1\n"), &[]).is_err());
+        assert!(
+            err(&listener(""), &["--electrum-rpc-addr", "127.0.0.1:50002"])
+                .contains("can't be specified together")
+        );
+    }
+
+    #[test]
+    fn test_daemon_rpc_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("electrs.toml");
+        let parse = |contents: &str, args: &[&str]| {
+            std::fs::write(&file, contents).unwrap();
+            let args = std::iter::once("electrs").chain(args.iter().copied());
+            let (config, _args) =
+                internal::prelude::Config::custom_args_and_optional_files(args, [&file]).unwrap();
+            Config::from_parsed(config)
+        };
+        let err = |contents: &str, args: &[&str]| parse(contents, args).unwrap_err().to_string();
+
+        let config = parse(
+            "daemon_rpc_user = \"user\"\ndaemon_rpc_password = \"pass\"\n",
+            &["--daemon-rpc-addr", "http://127.0.0.1:8332/"],
+        )
+        .unwrap();
+        assert_eq!(config.daemon_rpc_addr.to_string(), "127.0.0.1:8332");
+        assert_eq!(
+            format!("{:?}", config.daemon_auth),
+            "UserPass(\"user\", \"<sensitive>\")"
+        );
+        let config = parse("", &["--daemon-rpc-addr", "127.0.0.1:8332"]).unwrap();
+        assert_eq!(config.daemon_rpc_addr.to_string(), "127.0.0.1:8332");
+
+        assert!(config.daemon_rpc_tls.is_none());
+
+        let fingerprint = "9B:9F:25:51:2F:40:9B:75:F9:9A:58:7C:C1:62:C0:2C:\
+            49:8E:5B:E7:6B:19:99:8A:F1:BA:CD:26:E5:11:30:51";
+        let config = parse(
+            &format!("daemon_rpc_cert_sha256 = \"{}\"\n", fingerprint),
+            &["--daemon-rpc-addr", "https://bitcoind.lan:8332/"],
+        )
+        .unwrap();
+        assert_eq!(config.daemon_rpc_addr.to_string(), "bitcoind.lan:8332");
+        assert_eq!(
+            config.daemon_rpc_tls,
+            Some(
+                RpcTls::cert_sha256(
+                    "9b9f25512f409b75f99a587cc162c02c498e5be76b19998af1bacd26e5113051"
+                )
+                .unwrap()
+            )
+        );
+        let config = parse(
+            "daemon_rpc_ca_file = \"/etc/electrs/bitcoind-ca.pem\"\n",
+            &["--daemon-rpc-addr", "https://127.0.0.1:8332"],
+        )
+        .unwrap();
+        assert_eq!(
+            config.daemon_rpc_tls,
+            Some(RpcTls::CaFile("/etc/electrs/bitcoind-ca.pem".into()))
+        );
+        assert!(err("", &["--daemon-rpc-addr", "https://127.0.0.1:8332"])
+            .contains("please pin bitcoind's certificate"));
+        assert!(err(
+            "daemon_rpc_ca_file = \"ca.pem\"\n",
+            &["--daemon-rpc-addr", "http://127.0.0.1:8332"]
+        )
+        .contains("require an 'https://addr:port' daemon_rpc_addr"));
+        assert!(err(
+            "daemon_rpc_ca_file = \"ca.pem\"\ndaemon_rpc_cert_sha256 = \"00\"\n",
+            &["--daemon-rpc-addr", "https://127.0.0.1:8332"]
+        )
+        .contains("can't be specified together"));
+        assert!(err(
+            "daemon_rpc_cert_sha256 = \"9b9f\"\n",
+            &["--daemon-rpc-addr", "https://127.0.0.1:8332"]
+        )
+        .contains("invalid SHA-256 fingerprint"));
+        assert!(
+            err("", &["--daemon-rpc-addr", "http://127.0.0.1:8332/wallet/w"])
+                .contains("without a path")
+        );
+        assert!(err("", &["--daemon-rpc-user", "user"]).contains("specified together"));
+        assert!(err(
+            "daemon_rpc_user = \"user\"\ndaemon_rpc_password = \"pass\"\nauth = \"a:b\"\n",
+            &[]
+        )
+        .contains("ambiguous configuration"));
+    }
+
+    #[test]
+    fn test_custom_network() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("electrs.toml");
+        let parse = |contents: &str, args: &[&str]| {
+            std::fs::write(&file, contents).unwrap();
+            let args = std::iter::once("electrs").chain(args.iter().copied());
+            let (config, _args) =
+                internal::prelude::Config::custom_args_and_optional_files(args, [&file]).unwrap();
+            Config::from_parsed(config)
+        };
+        let err = |contents: &str, args: &[&str]| parse(contents, args).unwrap_err().to_string();
+
+        let mut genesis = bitcoin::constants::genesis_block(Network::Regtest).header;
+        genesis.time += 1; // a made-up genesis block
+        let contents = format!(
+            "network = \"custom\"\n\
+            magic = \"0a0b0c0d\"\n\
+            custom_genesis_hash = \"{}\"\n\
+            custom_genesis_header = \"{}\"\n\
+            custom_daemon_rpc_port = 18888\n\
+            custom_daemon_p2p_port = 18889\n\
+            custom_p2pkh_prefix = 63\n\
+            custom_p2sh_prefix = 125\n\
+            custom_bech32_hrp = \"nigiri\"\n",
+            genesis.block_hash(),
+            bitcoin::consensus::encode::serialize_hex(&genesis),
+        );
+        let config = parse(&contents, &["--db-dir", "/db", "--daemon-dir", "/bitcoin"]).unwrap();
+        let custom = config.custom_chain.as_ref().unwrap();
+        assert_eq!(custom.genesis, genesis);
+        assert_eq!(custom.prefixes.p2pkh, 63);
+        assert!(custom.check_pow);
+        assert_eq!(config.genesis(), genesis);
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.magic.to_string(), "0a0b0c0d");
+        assert_eq!(config.db_path, PathBuf::from("/db/custom"));
+        assert_eq!(config.daemon_rpc_addr.to_string(), "127.0.0.1:18888");
+        assert_eq!(config.daemon_p2p_addr.to_string(), "127.0.0.1:18889");
+        assert_eq!(
+            format!("{:?}", config.daemon_auth),
+            "CookieFile(\"/bitcoin/.cookie\")"
+        );
+        let config = parse(&contents, &["--custom-skip-pow-check"]).unwrap();
+        assert!(!config.custom_chain.unwrap().check_pow);
+
+        // all the parameters are required
+        for line in contents.lines().skip(1) {
+            let partial = contents.replace(&format!("{}\n", line), "");
+            let name = line.split(' ').next().unwrap();
+            let e = err(&partial, &[]);
+            assert!(e.contains("requires") && e.contains(name), "{}", e);
+        }
+        let mismatch = contents.replace("custom_genesis_hash = \"", "custom_genesis_hash = \"00");
+        assert!(err(&mismatch, &[]).contains("custom_genesis_hash"));
+        let other = genesis.block_hash().to_string();
+        let mismatch = contents.replace(&other, &"11".repeat(32));
+        assert!(err(&mismatch, &[]).contains("but custom_genesis_hash is"));
+
+        // built-in networks are unchanged, and refuse the custom chain's parameters
+        let config = parse("network = \"regtest\"", &[]).unwrap();
+        assert_eq!(config.custom_chain, None);
+        assert_eq!(
+            config.genesis(),
+            bitcoin::constants::genesis_block(Network::Regtest).header
+        );
+        let e = err(
+            "network = \"regtest\"\ncustom_p2pkh_prefix = 63\n",
+            &["--custom-skip-pow-check"],
+        );
+        assert!(
+            e.contains("custom_p2pkh_prefix, custom_skip_pow_check can be set only"),
+            "{}",
+            e
+        );
+    }
 }
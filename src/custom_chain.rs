@@ -0,0 +1,170 @@
+use anyhow::{bail, ensure, Context, Result};
+use bitcoin::{
+    address::NetworkUnchecked, base58, bech32, block::Header as BlockHeader, hashes::Hash,
+    hex::FromHex, Address, BlockHash, Network, PubkeyHash, ScriptBuf, WitnessProgram,
+    WitnessVersion,
+};
+
+use std::str::FromStr;
+
+/// Address prefixes of a custom chain (as in bitcoind's `CChainParams`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressPrefixes {
+    pub p2pkh: u8,
+    pub p2sh: u8,
+    pub bech32_hrp: bech32::Hrp,
+}
+
+/// Parameters of a chain unknown to electrs (`network = "custom"`), e.g. a custom signet or a
+/// regtest-like chain of an integration framework.
+///
+/// The chain's genesis must match bitcoind's (checked on startup, like for the built-in networks).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomChain {
+    pub genesis: BlockHeader,
+    pub prefixes: AddressPrefixes,
+    pub check_pow: bool, // if `false`, the headers' proof-of-work is not verified
+}
+
+impl CustomChain {
+    /// `genesis_hash` must match the header's hash (so a mistyped header is refused).
+    pub(crate) fn new(
+        genesis_hash: BlockHash,
+        genesis_header: &str,
+        prefixes: AddressPrefixes,
+        check_pow: bool,
+    ) -> Result<Self> {
+        let bytes = Vec::<u8>::from_hex(genesis_header)
+            .context("custom_genesis_header is not a valid hex string")?;
+        let genesis: BlockHeader = bitcoin::consensus::deserialize(&bytes)
+            .context("custom_genesis_header is not a valid block header")?;
+        ensure!(
+            genesis.block_hash() == genesis_hash,
+            "custom_genesis_header's hash is {}, but custom_genesis_hash is {}",
+            genesis.block_hash(),
+            genesis_hash
+        );
+        ensure!(
+            genesis.prev_blockhash == BlockHash::all_zeros(),
+            "custom_genesis_header must not point to a previous block"
+        );
+        ensure!(
+            prefixes.p2pkh != prefixes.p2sh,
+            "custom_p2pkh_prefix and custom_p2sh_prefix must be different"
+        );
+        Ok(Self {
+            genesis,
+            prefixes,
+            check_pow,
+        })
+    }
+
+    pub(crate) fn genesis_hash(&self) -> BlockHash {
+        self.genesis.block_hash()
+    }
+}
+
+/// Decodes the addresses used in place of scripthashes (e.g. in `scripthash_allowlist_file`),
+/// using the prefixes of the configured chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum AddressDecoder {
+    Network(Network),
+    Custom(AddressPrefixes),
+}
+
+impl AddressDecoder {
+    pub(crate) fn script_pubkey(&self, address: &str) -> Result<ScriptBuf> {
+        let prefixes = match self {
+            AddressDecoder::Network(network) => {
+                let address = Address::<NetworkUnchecked>::from_str(address)
+                    .context("invalid address")?
+                    .require_network(*network)
+                    .context("address of another network")?;
+                return Ok(address.script_pubkey());
+            }
+            AddressDecoder::Custom(prefixes) => prefixes,
+        };
+        if let Ok((hrp, version, program)) = bech32::segwit::decode(address) {
+            ensure!(hrp == prefixes.bech32_hrp, "address of another network");
+            let version = WitnessVersion::try_from(version).context("invalid witness version")?;
+            let program =
+                WitnessProgram::new(version, &program).context("invalid witness program")?;
+            return Ok(ScriptBuf::new_witness_program(&program));
+        }
+        let data = base58::decode_check(address).context("invalid address")?;
+        ensure!(data.len() == 21, "invalid address length");
+        match data[0] {
+            prefix if prefix == prefixes.p2pkh => Ok(ScriptBuf::new_p2pkh(
+                &PubkeyHash::from_slice(&data[1..]).expect("20 bytes"),
+            )),
+            prefix if prefix == prefixes.p2sh => Ok(ScriptBuf::new_p2sh(
+                &bitcoin::ScriptHash::from_slice(&data[1..]).expect("20 bytes"),
+            )),
+            _ => bail!("address of another network"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AddressDecoder, AddressPrefixes, CustomChain};
+    use bitcoin::{
+        bech32::Hrp, consensus::encode::serialize_hex, constants::genesis_block, hashes::Hash,
+        Address, BlockHash, Network, PubkeyHash, ScriptBuf, ScriptHash, WPubkeyHash,
+        WitnessProgram, WitnessVersion,
+    };
+
+    fn regtest_prefixes() -> AddressPrefixes {
+        AddressPrefixes {
+            p2pkh: 111,
+            p2sh: 196,
+            bech32_hrp: Hrp::parse("bcrt").unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_genesis() {
+        let genesis = genesis_block(Network::Regtest).header;
+        let hex = serialize_hex(&genesis);
+        let chain =
+            CustomChain::new(genesis.block_hash(), &hex, regtest_prefixes(), false).unwrap();
+        assert_eq!(chain.genesis, genesis);
+
+        let err = CustomChain::new(BlockHash::all_zeros(), &hex, regtest_prefixes(), false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("custom_genesis_hash"), "{}", err);
+        assert!(CustomChain::new(genesis.block_hash(), "00", regtest_prefixes(), false).is_err());
+        assert!(CustomChain::new(genesis.block_hash(), "zz", regtest_prefixes(), false).is_err());
+    }
+
+    #[test]
+    fn test_custom_addresses() {
+        let scripts = [
+            ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([1; 20])),
+            ScriptBuf::new_p2sh(&ScriptHash::from_byte_array([2; 20])),
+            ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([3; 20])),
+            ScriptBuf::new_witness_program(
+                &WitnessProgram::new(WitnessVersion::V1, &[4; 32]).unwrap(),
+            ),
+        ];
+        // the regtest prefixes decode regtest addresses like `bitcoin::Address`
+        let custom = AddressDecoder::Custom(regtest_prefixes());
+        let regtest = AddressDecoder::Network(Network::Regtest);
+        let other = AddressDecoder::Custom(AddressPrefixes {
+            p2pkh: 0x3f,
+            p2sh: 0x7d,
+            bech32_hrp: Hrp::parse("nigiri").unwrap(),
+        });
+        for script in &scripts {
+            let address = Address::from_script(script, Network::Regtest)
+                .unwrap()
+                .to_string();
+            assert_eq!(&custom.script_pubkey(&address).unwrap(), script);
+            assert_eq!(&regtest.script_pubkey(&address).unwrap(), script);
+            // other prefixes refuse them
+            assert!(other.script_pubkey(&address).is_err(), "{}", address);
+        }
+        assert!(custom.script_pubkey("not an address").is_err());
+    }
+}
@@ -17,14 +17,16 @@ use crate::{
     chain::{Chain, NewHeader},
     config::Config,
     metrics::Metrics,
-    p2p::Connection,
+    p2p::{self, BlockTimeout, Connection, Connector},
+    proxy::{DaemonAddr, HttpTransport},
     signals::ExitFlag,
+    thread::Supervisor,
     types::SerBlock,
 };
 
 enum PollResult {
     Done(Result<()>),
-    Retry,
+    Retry(String), // the reason for retrying
 }
 
 fn rpc_poll(client: &mut Client, skip_block_download_wait: bool) -> PollResult {
@@ -36,7 +38,7 @@ fn rpc_poll(client: &mut Client, skip_block_download_wait: bool) -> PollResult {
             }
             let left_blocks = info.headers - info.blocks;
             if info.initial_block_download || left_blocks > 0 {
-                info!(
+                let reason = format!(
                     "waiting for {} blocks to download{}",
                     left_blocks,
                     if info.initial_block_download {
@@ -45,15 +47,17 @@ fn rpc_poll(client: &mut Client, skip_block_download_wait: bool) -> PollResult {
                         ""
                     }
                 );
-                return PollResult::Retry;
+                info!("{}", reason);
+                return PollResult::Retry(reason);
             }
             PollResult::Done(Ok(()))
         }
         Err(err) => {
             if let Some(e) = extract_bitcoind_error(&err) {
-                if e.code == -28 {
-                    debug!("waiting for RPC warmup: {}", e.message);
-                    return PollResult::Retry;
+                if e.code == RPC_IN_WARMUP {
+                    let reason = format!("waiting for RPC warmup: {}", e.message);
+                    debug!("{}", reason);
+                    return PollResult::Retry(reason);
                 }
             }
             PollResult::Done(Err(err).context("daemon not available"))
@@ -80,28 +84,138 @@ fn read_cookie(path: &Path) -> Result<(String, String)> {
 }
 
 fn rpc_connect(config: &Config) -> Result<Client> {
-    let rpc_url = format!("http://{}", config.daemon_rpc_addr);
-    // Allow RPC calls to take longer before timing out.
-    // See https://github.com/romanz/electrs/issues/495 for more details.
-    let builder = jsonrpc::simple_http::SimpleHttpTransport::builder()
-        .url(&rpc_url)?
-        .timeout(config.jsonrpc_timeout);
-    let builder = match config.daemon_auth.get_auth() {
-        Auth::None => builder,
-        Auth::UserPass(user, pass) => builder.auth(user, Some(pass)),
-        Auth::CookieFile(path) => {
-            let (user, pass) = read_cookie(&path)?;
-            builder.auth(user, Some(pass))
+    let auth = match config.daemon_auth.get_auth() {
+        Auth::None => None,
+        Auth::UserPass(user, pass) => Some((user, pass)),
+        Auth::CookieFile(path) => Some(read_cookie(&path)?),
+    };
+    let client = match &config.daemon_rpc_addr {
+        DaemonAddr::Direct(addr) if config.daemon_rpc_tls.is_none() => {
+            let rpc_url = format!("http://{}", addr);
+            // Allow RPC calls to take longer before timing out.
+            // See https://github.com/romanz/electrs/issues/495 for more details.
+            let builder = jsonrpc::simple_http::SimpleHttpTransport::builder()
+                .url(&rpc_url)?
+                .timeout(config.jsonrpc_timeout);
+            let builder = match auth {
+                None => builder,
+                Some((user, pass)) => builder.auth(user, Some(pass)),
+            };
+            jsonrpc::Client::with_transport(builder.build())
+        }
+        // the connections are kept open, and the host is re-resolved when reconnecting
+        addr => {
+            let transport = HttpTransport::new(addr.clone(), config.jsonrpc_timeout, auth);
+            let transport = match &config.daemon_rpc_tls {
+                Some(tls) => transport.with_tls(tls)?,
+                None => transport,
+            };
+            jsonrpc::Client::with_transport(transport)
         }
     };
-    Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(
-        builder.build(),
-    )))
+    Ok(Client::from_jsonrpc(client))
+}
+
+/// Checks bitcoind RPC connectivity and network (used by `--check-config --check-daemon`).
+pub(crate) fn probe(config: &Config) -> Result<String> {
+    let rpc = rpc_connect(config)?;
+    if let Some(custom) = &config.custom_chain {
+        // custom chains may be named differently by bitcoind, so their genesis block is compared
+        let genesis = rpc.get_block_hash(0).context("getblockhash failed")?;
+        ensure!(
+            genesis == custom.genesis_hash(),
+            "bitcoind's genesis block is {}, but electrs is configured for {}",
+            genesis,
+            custom.genesis_hash()
+        );
+        let height = rpc.get_block_count().context("getblockcount failed")?;
+        return Ok(format!("custom chain at height {}", height));
+    }
+    let info = rpc
+        .get_blockchain_info()
+        .context("getblockchaininfo failed")?;
+    ensure!(
+        info.chain == config.network,
+        "bitcoind is running on {}, but electrs is configured for {}",
+        info.chain,
+        config.network
+    );
+    Ok(format!("{} at height {}", info.chain, info.blocks))
+}
+
+/// bitcoind interface, used for indexing and for answering queries
+/// (implemented by [`Daemon`], and by a scriptable stub for tests).
+pub(crate) trait Node: Send + Sync {
+    /// bitcoind's user agent (e.g. `/Satoshi:27.0.0/`)
+    fn subversion(&self) -> &str;
+
+    fn estimate_fee(&self, nblocks: u16) -> Result<Option<Amount>>;
+
+    fn get_relay_fee(&self) -> Result<Amount>;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid>;
+
+    fn submitpackage(&self, txs: &[Transaction]) -> Result<Value>;
+
+    fn get_transaction_info(&self, txid: &Txid, blockhash: Option<BlockHash>) -> Result<Value>;
+
+    fn get_transaction_hex(&self, txid: &Txid, blockhash: Option<BlockHash>) -> Result<Value> {
+        use bitcoin::consensus::serde::{hex::Lower, Hex, With};
+
+        let tx = self.get_transaction(txid, blockhash)?;
+        #[derive(serde::Serialize)]
+        #[serde(transparent)]
+        struct TxAsHex(#[serde(with = "With::<Hex<Lower>>")] Transaction);
+        serde_json::to_value(TxAsHex(tx)).map_err(Into::into)
+    }
+
+    fn get_transaction(&self, txid: &Txid, blockhash: Option<BlockHash>) -> Result<Transaction>;
+
+    fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>>;
+
+    /// Serialized block, fetched via RPC (so it doesn't wait for the P2P connection).
+    fn get_block(&self, blockhash: BlockHash) -> Result<SerBlock>;
+
+    /// Block hash at `height` in the best chain (or `None` if it is above the tip).
+    fn get_block_hash(&self, height: usize) -> Result<Option<BlockHash>>;
+
+    fn get_best_block_hash(&self) -> Result<BlockHash>;
+
+    fn get_mempool_info(&self) -> Result<json::GetMempoolInfoResult>;
+
+    fn get_mempool_txids(&self) -> Result<Vec<Txid>>;
+
+    /// Missing entries (e.g. replaced transactions) are returned as `None`, while other errors
+    /// fail the whole call (so they are not mistaken for missing entries).
+    fn get_mempool_entries(
+        &self,
+        txids: &[Txid],
+    ) -> Result<Vec<Option<json::GetMempoolEntryResult>>>;
+
+    /// Missing transactions (e.g. replaced ones) are returned as `None`, while other errors fail
+    /// the whole call (so they are not mistaken for missing transactions).
+    fn get_mempool_transactions(&self, txids: &[Txid]) -> Result<Vec<Option<Transaction>>>;
+
+    /// Headers following the chain's tip (or its fork point, on reorg).
+    fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>>;
+
+    /// The height of the first block whose data is kept, if bitcoind is pruned.
+    fn get_prune_height(&self) -> Result<Option<usize>>;
+
+    /// Call `func` on each of the blocks (in the given order), failing with [`BlockNotAvailable`]
+    /// if bitcoind doesn't have one of them (`func` is not called on the following blocks).
+    fn for_blocks(
+        &self,
+        blockhashes: Vec<BlockHash>,
+        func: &mut dyn FnMut(BlockHash, SerBlock),
+    ) -> Result<()>;
 }
 
 pub struct Daemon {
     p2p: Mutex<Connection>,
+    connector: Connector,
     rpc: Client,
+    subversion: String,
 }
 
 impl Daemon {
@@ -109,6 +223,7 @@ impl Daemon {
         config: &Config,
         exit_flag: &ExitFlag,
         metrics: &Metrics,
+        supervisor: &Supervisor,
     ) -> Result<Self> {
         let mut rpc = rpc_connect(config)?;
 
@@ -121,12 +236,15 @@ impl Daemon {
                     result.context("bitcoind RPC polling failed")?;
                     break; // on success, finish polling
                 }
-                PollResult::Retry => {
+                PollResult::Retry(reason) => {
+                    metrics.health().update(|s| s.daemon_error = Some(reason));
                     std::thread::sleep(std::time::Duration::from_secs(1)); // wait a bit before polling
                 }
             }
         }
 
+        metrics.health().update(|s| s.daemon_error = None);
+
         let network_info = rpc.get_network_info()?;
         if network_info.version < 21_00_00 {
             bail!("electrs requires bitcoind 0.21+");
@@ -136,18 +254,48 @@ impl Daemon {
         }
         let info = rpc.get_blockchain_info()?;
         if info.pruned {
-            bail!("electrs requires non-pruned bitcoind node");
+            // pruned blocks are reported as missing (stalling the index), instead of failing here
+            warn!("electrs requires non-pruned bitcoind node: pruned blocks can't be indexed");
         }
 
-        let p2p = Mutex::new(Connection::connect(
-            config.daemon_p2p_addr,
+        let connector = Connector::new(
+            config.daemon_p2p_addr.clone(),
             metrics,
             config.magic,
-        )?);
-        Ok(Self { p2p, rpc })
+            supervisor,
+        );
+        let p2p = Mutex::new(connector.connect()?);
+        Ok(Self {
+            p2p,
+            connector,
+            rpc,
+            subversion: network_info.subversion,
+        })
+    }
+
+    pub(crate) fn new_block_notification(&self) -> Receiver<()> {
+        self.p2p.lock().new_block_notification()
+    }
+
+    /// Replace the p2p connection if it was lost (e.g. since bitcoind was restarted).
+    /// The new block notifications are received via the new connection's `new_block_notification()`.
+    pub(crate) fn reconnect_p2p(&self) -> Result<()> {
+        let mut p2p = self.p2p.lock();
+        if !p2p.is_connected() {
+            *p2p = self.connector.connect()?;
+            info!("p2p reconnected to {}", self.connector.address());
+        }
+        Ok(())
+    }
+}
+
+impl Node for Daemon {
+    /// bitcoind's user agent (e.g. `/Satoshi:27.0.0/`)
+    fn subversion(&self) -> &str {
+        &self.subversion
     }
 
-    pub(crate) fn estimate_fee(&self, nblocks: u16) -> Result<Option<Amount>> {
+    fn estimate_fee(&self, nblocks: u16) -> Result<Option<Amount>> {
         let res = self.rpc.estimate_smart_fee(nblocks, None);
         if let Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(RpcError {
             code: -32603,
@@ -159,7 +307,7 @@ impl Daemon {
         Ok(res.context("failed to estimate fee")?.fee_rate)
     }
 
-    pub(crate) fn get_relay_fee(&self) -> Result<Amount> {
+    fn get_relay_fee(&self) -> Result<Amount> {
         Ok(self
             .rpc
             .get_network_info()
@@ -167,24 +315,20 @@ impl Daemon {
             .relay_fee)
     }
 
-    pub(crate) fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
         self.rpc
             .send_raw_transaction(tx)
             .context("failed to broadcast transaction")
     }
 
-    pub(crate) fn submitpackage(&self, txs: &[Transaction]) -> Result<Value> {
+    fn submitpackage(&self, txs: &[Transaction]) -> Result<Value> {
         let package: Vec<String> = txs.iter().map(serialize_hex).collect();
         self.rpc
             .call("submitpackage", &[json!(package)])
             .context("failed to submitpackage package")
     }
 
-    pub(crate) fn get_transaction_info(
-        &self,
-        txid: &Txid,
-        blockhash: Option<BlockHash>,
-    ) -> Result<Value> {
+    fn get_transaction_info(&self, txid: &Txid, blockhash: Option<BlockHash>) -> Result<Value> {
         // No need to parse the resulting JSON, just return it as-is to the client.
         self.rpc
             .call(
@@ -194,31 +338,13 @@ impl Daemon {
             .context("failed to get transaction info")
     }
 
-    pub(crate) fn get_transaction_hex(
-        &self,
-        txid: &Txid,
-        blockhash: Option<BlockHash>,
-    ) -> Result<Value> {
-        use bitcoin::consensus::serde::{hex::Lower, Hex, With};
-
-        let tx = self.get_transaction(txid, blockhash)?;
-        #[derive(serde::Serialize)]
-        #[serde(transparent)]
-        struct TxAsHex(#[serde(with = "With::<Hex<Lower>>")] Transaction);
-        serde_json::to_value(TxAsHex(tx)).map_err(Into::into)
-    }
-
-    pub(crate) fn get_transaction(
-        &self,
-        txid: &Txid,
-        blockhash: Option<BlockHash>,
-    ) -> Result<Transaction> {
+    fn get_transaction(&self, txid: &Txid, blockhash: Option<BlockHash>) -> Result<Transaction> {
         self.rpc
             .get_raw_transaction(txid, blockhash.as_ref())
             .context("failed to get transaction")
     }
 
-    pub(crate) fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
+    fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
         Ok(self
             .rpc
             .get_block_info(&blockhash)
@@ -226,87 +352,188 @@ impl Daemon {
             .tx)
     }
 
-    pub(crate) fn get_mempool_info(&self) -> Result<json::GetMempoolInfoResult> {
+    fn get_block(&self, blockhash: BlockHash) -> Result<SerBlock> {
+        let hex = match self.rpc.get_block_hex(&blockhash) {
+            Ok(hex) => hex,
+            Err(e) if extract_bitcoind_error(&e).is_some_and(is_block_not_available) => {
+                return Err(e).context(BlockNotAvailable(blockhash));
+            }
+            Err(e) => return Err(e).context("failed to get block"),
+        };
+        SerBlock::from_hex(&hex).context("invalid block hex")
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<Option<BlockHash>> {
+        match self.rpc.get_block_hash(height as u64) {
+            Ok(blockhash) => Ok(Some(blockhash)),
+            // RPC_INVALID_PARAMETER is returned when the height is out of range
+            Err(e) if extract_bitcoind_error(&e).is_some_and(|e| e.code == -8) => Ok(None),
+            Err(e) => Err(e).context("failed to get block hash"),
+        }
+    }
+
+    fn get_best_block_hash(&self) -> Result<BlockHash> {
+        self.rpc
+            .get_best_block_hash()
+            .context("failed to get best block hash")
+    }
+
+    fn get_mempool_info(&self) -> Result<json::GetMempoolInfoResult> {
         self.rpc
             .get_mempool_info()
             .context("failed to get mempool info")
     }
 
-    pub(crate) fn get_mempool_txids(&self) -> Result<Vec<Txid>> {
+    fn get_mempool_txids(&self) -> Result<Vec<Txid>> {
         self.rpc
             .get_raw_mempool()
             .context("failed to get mempool txids")
     }
 
-    pub(crate) fn get_mempool_entries(
+    fn get_mempool_entries(
         &self,
         txids: &[Txid],
     ) -> Result<Vec<Option<json::GetMempoolEntryResult>>> {
         let results = batch_request(self.rpc.get_jsonrpc_client(), "getmempoolentry", txids)?;
-        Ok(results
+        results
             .into_iter()
-            .map(|r| match r?.result::<json::GetMempoolEntryResult>() {
-                Ok(entry) => Some(entry),
-                Err(err) => {
-                    debug!("failed to get mempool entry: {}", err); // probably due to RBF
-                    None
-                }
-            })
-            .collect())
+            .map(|r| batch_item(r, "getmempoolentry"))
+            .collect()
     }
 
-    pub(crate) fn get_mempool_transactions(
-        &self,
-        txids: &[Txid],
-    ) -> Result<Vec<Option<Transaction>>> {
+    fn get_mempool_transactions(&self, txids: &[Txid]) -> Result<Vec<Option<Transaction>>> {
         let results = batch_request(self.rpc.get_jsonrpc_client(), "getrawtransaction", txids)?;
-        Ok(results
+        results
             .into_iter()
-            .map(|r| -> Option<Transaction> {
-                let tx_hex = match r?.result::<String>() {
-                    Ok(tx_hex) => Some(tx_hex),
-                    Err(err) => {
-                        debug!("failed to get mempool tx: {}", err); // probably due to RBF
-                        None
-                    }
-                }?;
+            .map(|r| -> Result<Option<Transaction>> {
+                let Some(tx_hex) = batch_item::<String>(r, "getrawtransaction")? else {
+                    return Ok(None);
+                };
                 let tx_bytes = match Vec::from_hex(&tx_hex) {
-                    Ok(tx_bytes) => Some(tx_bytes),
+                    Ok(tx_bytes) => tx_bytes,
                     Err(err) => {
                         warn!("got non-hex transaction {}: {}", tx_hex, err);
-                        None
+                        return Ok(None);
                     }
-                }?;
+                };
                 match deserialize(&tx_bytes) {
-                    Ok(tx) => Some(tx),
+                    Ok(tx) => Ok(Some(tx)),
                     Err(err) => {
                         warn!("got invalid tx {}: {}", tx_hex, err);
-                        None
+                        Ok(None)
                     }
                 }
             })
-            .collect())
+            .collect()
     }
 
-    pub(crate) fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
-        self.p2p.lock().get_new_headers(chain)
+    fn get_prune_height(&self) -> Result<Option<usize>> {
+        let info = self
+            .rpc
+            .get_blockchain_info()
+            .context("failed to get blockchain info")?;
+        Ok(info.pruned.then(|| info.prune_height.unwrap_or(0) as usize))
     }
 
-    pub(crate) fn for_blocks<B, F>(&self, blockhashes: B, func: F) -> Result<()>
-    where
-        B: IntoIterator<Item = BlockHash>,
-        F: FnMut(BlockHash, SerBlock),
-    {
-        self.p2p.lock().for_blocks(blockhashes, func)
+    fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
+        self.p2p.lock().get_new_headers(chain)
     }
 
-    pub(crate) fn new_block_notification(&self) -> Receiver<()> {
-        self.p2p.lock().new_block_notification()
+    fn for_blocks(
+        &self,
+        blockhashes: Vec<BlockHash>,
+        func: &mut dyn FnMut(BlockHash, SerBlock),
+    ) -> Result<()> {
+        if blockhashes.is_empty() {
+            return Ok(()); // don't wait for the connection (e.g. while it is used for indexing)
+        }
+        let mut done = 0;
+        let result = self
+            .p2p
+            .lock()
+            .for_blocks(blockhashes.iter().copied(), |hash, block| {
+                done += 1;
+                func(hash, block)
+            });
+        match result {
+            // bitcoind doesn't reply `notfound` for the blocks it doesn't have (e.g. pruned ones),
+            // so the rest are fetched via RPC (failing with `BlockNotAvailable` if needed)
+            Err(e) if e.downcast_ref::<BlockTimeout>().is_some() => {
+                warn!("{}, fetching it via RPC", e);
+                for &hash in &blockhashes[done..] {
+                    func(hash, self.get_block(hash)?);
+                }
+                Ok(())
+            }
+            result => result,
+        }
     }
 }
 
 pub(crate) type RpcError = bitcoincore_rpc::jsonrpc::error::RpcError;
 
+/// bitcoind doesn't have the block's data (e.g. since it was pruned, or it is not downloaded yet),
+/// so fetching it may succeed only after bitcoind's configuration is fixed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct BlockNotAvailable(pub(crate) BlockHash);
+
+impl std::fmt::Display for BlockNotAvailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "block {} is not available", self.0)
+    }
+}
+
+impl std::error::Error for BlockNotAvailable {}
+
+impl BlockNotAvailable {
+    /// The missing block, if `err` was caused by bitcoind not having it
+    pub(crate) fn find(err: &anyhow::Error) -> Option<BlockHash> {
+        // also found as a context (e.g. wrapping bitcoind's RPC error)
+        err.downcast_ref::<BlockNotAvailable>()
+            .map(|missing| missing.0)
+    }
+}
+
+/// Returned by `getblock` for pruned (or not yet downloaded) blocks
+fn is_block_not_available(err: &RpcError) -> bool {
+    err.code == RPC_MISC_ERROR
+        && (err.message.starts_with("Block not available")
+            || err.message.starts_with("Block not found on disk"))
+}
+
+/// Returned for miscellaneous failures (e.g. a block which is not available)
+const RPC_MISC_ERROR: i32 = -1;
+/// Returned for unknown transactions (e.g. ones which are not in the mempool)
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+/// Returned while bitcoind is starting up (e.g. loading its block index)
+const RPC_IN_WARMUP: i32 = -28;
+
+/// Returns `true` if `err` is caused by bitcoind being temporarily unavailable (e.g. a lost
+/// connection, a timeout or RPC warmup), so the request may succeed if retried.
+pub(crate) fn is_transient(err: &anyhow::Error) -> bool {
+    fn is_transient_jsonrpc(err: &jsonrpc::Error) -> bool {
+        match err {
+            jsonrpc::Error::Transport(_) => true,
+            jsonrpc::Error::Rpc(err) => err.code == RPC_IN_WARMUP,
+            _ => false,
+        }
+    }
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<bitcoincore_rpc::Error>() {
+            return match err {
+                bitcoincore_rpc::Error::JsonRpc(err) => is_transient_jsonrpc(err),
+                bitcoincore_rpc::Error::Io(_) => true,
+                _ => false,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<jsonrpc::Error>() {
+            return is_transient_jsonrpc(err);
+        }
+        // the p2p connection was lost
+        cause.is::<p2p::Disconnected>() || cause.is::<crossbeam_channel::RecvError>()
+    })
+}
+
 pub(crate) fn extract_bitcoind_error(err: &bitcoincore_rpc::Error) -> Option<&RpcError> {
     use bitcoincore_rpc::{
         jsonrpc::error::Error::Rpc as ServerError, Error::JsonRpc as JsonRpcError,
@@ -317,6 +544,26 @@ pub(crate) fn extract_bitcoind_error(err: &bitcoincore_rpc::Error) -> Option<&Rp
     }
 }
 
+/// Missing items are returned as `None` (other errors are returned, so they are not mistaken for
+/// missing items).
+fn batch_item<T>(response: Option<jsonrpc::Response>, name: &str) -> Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let response = match response {
+        Some(response) => response,
+        None => return Ok(None),
+    };
+    match response.result::<T>() {
+        Ok(item) => Ok(Some(item)),
+        Err(jsonrpc::Error::Rpc(err)) if err.code == RPC_INVALID_ADDRESS_OR_KEY => {
+            debug!("{} failed: {}", name, err.message); // probably due to RBF
+            Ok(None)
+        }
+        Err(err) => Err(err).with_context(|| format!("{} failed", name)),
+    }
+}
+
 fn batch_request<T>(
     client: &jsonrpc::Client,
     name: &str,
@@ -339,6 +586,85 @@ where
             assert_eq!(items.len(), values.len());
             Ok(values)
         }
-        Err(err) => bail!("batch {} request failed: {}", name, err),
+        Err(err) => Err(err).with_context(|| format!("batch {} request failed", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_item, is_block_not_available, is_transient, BlockNotAvailable, RpcError};
+    use crate::p2p::Disconnected;
+    use anyhow::Context;
+    use bitcoin::{hashes::Hash, BlockHash};
+    use bitcoincore_rpc::jsonrpc;
+    use serde_json::{json, value::RawValue};
+
+    fn response(result: Option<&str>, code: Option<i32>) -> jsonrpc::Response {
+        jsonrpc::Response {
+            result: result.map(|r| RawValue::from_string(r.to_owned()).unwrap()),
+            error: code.map(|code| RpcError {
+                code,
+                message: "failed".to_owned(),
+                data: None,
+            }),
+            id: json!(0),
+            jsonrpc: Some("2.0".to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_batch_item() {
+        let item = batch_item::<String>(Some(response(Some("\"00\""), None)), "test");
+        assert_eq!(item.unwrap(), Some("00".to_owned()));
+        assert_eq!(batch_item::<String>(None, "test").unwrap(), None);
+
+        // unknown items (e.g. replaced transactions) are missing
+        let item = batch_item::<String>(Some(response(None, Some(-5))), "test");
+        assert_eq!(item.unwrap(), None);
+
+        // other errors are not mistaken for missing items
+        let err = batch_item::<String>(Some(response(None, Some(-28))), "test").unwrap_err();
+        assert!(is_transient(&err));
+        let err = batch_item::<String>(Some(response(None, Some(-1))), "test").unwrap_err();
+        assert!(!is_transient(&err));
+    }
+
+    #[test]
+    fn test_block_not_available() {
+        let error = |code, message: &str| RpcError {
+            code,
+            message: message.to_owned(),
+            data: None,
+        };
+        assert!(is_block_not_available(&error(
+            -1,
+            "Block not available (pruned data)"
+        )));
+        assert!(is_block_not_available(&error(
+            -1,
+            "Block not found on disk"
+        )));
+        assert!(!is_block_not_available(&error(-5, "Block not found")));
+        assert!(!is_block_not_available(&error(-1, "Internal bug detected")));
+
+        let blockhash = BlockHash::hash(b"block");
+        let err = Err::<(), _>(BlockNotAvailable(blockhash))
+            .context("failed to index blocks")
+            .unwrap_err();
+        assert_eq!(BlockNotAvailable::find(&err), Some(blockhash));
+        assert!(!is_transient(&err));
+        assert_eq!(BlockNotAvailable::find(&anyhow::anyhow!("failed")), None);
+    }
+
+    #[test]
+    fn test_p2p_disconnected() {
+        let err = Err::<(), _>(crossbeam_channel::RecvError)
+            .context(Disconnected)
+            .context("failed to get new headers")
+            .unwrap_err();
+        assert!(is_transient(&err));
+        let err = anyhow::anyhow!("failed to get block").context(Disconnected);
+        assert!(is_transient(&err));
+        assert!(!is_transient(&anyhow::anyhow!("unexpected magic")));
     }
 }
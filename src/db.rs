@@ -1,10 +1,22 @@
 use anyhow::{Context, Result};
+use bitcoin::{
+    block::Header as BlockHeader, consensus::deserialize, hashes::Hash, BlockHash, Network,
+};
 use rust_rocksdb as rocksdb;
 
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use crate::types::{HashPrefix, SerializedHashPrefixRow, SerializedHeaderRow};
+use crate::chain::HeaderStore;
+use crate::config::Durability;
+use crate::types::{
+    HashPrefix, HashPrefixRow, PrefixLen, RowPrefix, ScriptTypes, SerializedHashPrefixRow,
+    SerializedHeaderRow,
+};
+
+pub(crate) type SerializedBlockHash = [u8; 32];
 
 #[derive(Default)]
 pub(crate) struct WriteBatch {
@@ -13,21 +25,79 @@ pub(crate) struct WriteBatch {
     pub(crate) funding_rows: Vec<SerializedHashPrefixRow>,
     pub(crate) spending_rows: Vec<SerializedHashPrefixRow>,
     pub(crate) txid_rows: Vec<SerializedHashPrefixRow>,
+    pub(crate) merkle_rows: Vec<(SerializedBlockHash, Vec<u8>)>, // block's txids (32 bytes each)
+    pub(crate) height_rows: Vec<(usize, SerializedBlockHash, SerializedHeaderRow)>, // (height, hash, header)
+    // in-memory prefixes of the funding and spending rows (not written, used for `Touched`),
+    // collected only if `collect_prefixes` is set
+    pub(crate) collect_prefixes: bool,
+    pub(crate) funding_prefixes: Vec<HashPrefix>,
+    pub(crate) spending_prefixes: Vec<HashPrefix>,
 }
 
+/// Typical numbers of indexed inputs and outputs per transaction, used to pre-size the batch
+const INPUTS_PER_TX_HINT: usize = 2;
+const OUTPUTS_PER_TX_HINT: usize = 3;
+
 impl WriteBatch {
+    /// Grow the rows' vectors once for a block with `txs` transactions (instead of doubling them
+    /// repeatedly while the block is indexed).
+    pub(crate) fn reserve_block(&mut self, txs: usize) {
+        self.header_rows.reserve(1);
+        self.height_rows.reserve(1);
+        self.txid_rows.reserve(txs);
+        self.funding_rows.reserve(txs * OUTPUTS_PER_TX_HINT);
+        self.spending_rows.reserve(txs * INPUTS_PER_TX_HINT);
+        if self.collect_prefixes {
+            self.funding_prefixes.reserve(txs * OUTPUTS_PER_TX_HINT);
+            self.spending_prefixes.reserve(txs * INPUTS_PER_TX_HINT);
+        }
+    }
+
+    /// The size of the written rows (in bytes, see `flush_max_pending_mb`)
+    pub(crate) fn size(&self) -> usize {
+        let prefix_rows = |rows: &[SerializedHashPrefixRow]| -> usize {
+            rows.iter().map(|row| row.as_ref().len()).sum()
+        };
+        let header_size = std::mem::size_of::<SerializedHeaderRow>();
+        let merkle_size: usize = self
+            .merkle_rows
+            .iter()
+            .map(|(hash, txids)| hash.len() + txids.len())
+            .sum();
+        prefix_rows(&self.funding_rows)
+            + prefix_rows(&self.spending_rows)
+            + prefix_rows(&self.txid_rows)
+            + self.header_rows.len() * header_size
+            + self.height_rows.len() * (std::mem::size_of::<SerializedBlockHash>() + header_size)
+            + merkle_size
+    }
+
     pub(crate) fn sort(&mut self) {
         self.header_rows.sort_unstable();
         self.funding_rows.sort_unstable();
         self.spending_rows.sort_unstable();
         self.txid_rows.sort_unstable();
+        self.merkle_rows.sort_unstable();
+        self.height_rows.sort_unstable();
     }
 }
 
 /// RocksDB wrapper for index storage
+///
+/// Crash consistency invariants (the WAL is disabled during bulk import):
+/// - each `WriteBatch` contains its tip row, and all the column families are flushed atomically,
+///   so a persisted tip implies that all its rows are persisted too.
+/// - `Config::compacted` is persisted only after the data column families are flushed to disk.
+/// - on shutdown, unsynced WAL writes are synced (and unflushed memtables are flushed if the WAL
+///   is disabled).
 pub struct DBStore {
     db: rocksdb::DB,
     bulk_import: AtomicBool,
+    durability: Durability,
+    unsynced_wal: AtomicBool, // written since the last `sync_wal()` call (using `Durability::Wal`)
+    read_only: bool,          // see `open_read_only()`
+    prefix_len: PrefixLen,    // of the hash prefix rows (and of RocksDB's prefix extractor)
+    total_order_scans: bool,  // without the DB's prefix extractor (see `open_reader()`)
 }
 
 const CONFIG_CF: &str = "config";
@@ -35,11 +105,34 @@ const HEADERS_CF: &str = "headers";
 const TXID_CF: &str = "txid";
 const FUNDING_CF: &str = "funding";
 const SPENDING_CF: &str = "spending";
+const MERKLE_CF: &str = "merkle"; // used only if `merkle_cache` is enabled
+const HEIGHTS_CF: &str = "heights"; // indexed headers by height, and their heights by block hash
+
+const COLUMN_FAMILIES: &[&str] = &[
+    CONFIG_CF,
+    HEADERS_CF,
+    TXID_CF,
+    FUNDING_CF,
+    SPENDING_CF,
+    MERKLE_CF,
+    HEIGHTS_CF,
+];
 
-const COLUMN_FAMILIES: &[&str] = &[CONFIG_CF, HEADERS_CF, TXID_CF, FUNDING_CF, SPENDING_CF];
+/// Limit the number of file descriptors used by RocksDB
+pub(crate) const MAX_OPEN_FILES: i32 = 16;
 
 const CONFIG_KEY: &str = "C";
+/// Stored in `CONFIG_CF` (and in `HEADERS_CF` before format 1)
 const TIP_KEY: &[u8] = b"T";
+/// Stored in `CONFIG_CF` (see `compact_next_slice`)
+const MAINTENANCE_KEY: &[u8] = b"M";
+/// Stored in `CONFIG_CF` (see `set_totals`)
+const TOTALS_KEY: &[u8] = b"S";
+/// Each column family is compacted incrementally in slices, by the first byte of their keys
+const SLICES_PER_CF: usize = 256;
+/// The value of header rows written by the header sync, before their blocks are indexed (the
+/// indexed blocks' header rows have an empty value)
+const SYNCED_HEADER: &[u8] = b"S";
 
 // Taken from https://github.com/facebook/rocksdb/blob/master/include/rocksdb/db.h#L654-L689
 const DB_PROPERTIES: &[&str] = &[
@@ -82,24 +175,115 @@ const DB_PROPERTIES: &[&str] = &[
 struct Config {
     compacted: bool,
     format: u64,
+    #[serde(default)]
+    merkle_cache: bool,
+    #[serde(default)]
+    script_types: ScriptTypes,
+    /// `None` for DBs created before it was stored
+    #[serde(default)]
+    network: Option<Network>,
+    /// `false` for DBs indexed before `HEIGHTS_CF` was written (see `write_height_rows`)
+    #[serde(default)]
+    height_rows: bool,
+    #[serde(default)]
+    prefix_len: PrefixLen,
+    /// The chain's genesis block hash (distinguishing custom chains, which are all stored as
+    /// `Regtest`), `None` for DBs created before it was stored
+    #[serde(default)]
+    genesis: Option<BlockHash>,
+}
+
+/// The progress of the incremental compaction (see `DBStore::compact_next_slice`)
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Maintenance {
+    /// The next slice to compact (`SLICES_PER_CF` slices per column family, in
+    /// `COLUMN_FAMILIES` order)
+    next_slice: usize,
+    /// The UNIX time of the last compacted slice
+    last_run: u64,
 }
 
-const CURRENT_FORMAT: u64 = 0;
+/// Format 1 moved the tip from `HEADERS_CF` into `CONFIG_CF` (see `migrate_tip`), format 2
+/// added `HEIGHTS_CF` (see `migrate_heights`), and format 3 added the output index to the
+/// funding rows (see `migrate_funding_vouts`)
+const CURRENT_FORMAT: u64 = 3;
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             compacted: false,
             format: CURRENT_FORMAT,
+            merkle_cache: false,
+            script_types: ScriptTypes::all(),
+            network: None,
+            height_rows: true,
+            prefix_len: PrefixLen::default(),
+            genesis: None,
+        }
+    }
+}
+
+/// `HEIGHTS_CF` key of the header at `height`
+fn header_key(height: usize) -> [u8; 5] {
+    let mut key = [b'H'; 5];
+    key[1..].copy_from_slice(&u32::try_from(height).expect("invalid height").to_be_bytes());
+    key
+}
+
+/// `HEIGHTS_CF` key of the height of `blockhash`
+fn height_key(blockhash: &SerializedBlockHash) -> [u8; 33] {
+    let mut key = [b'B'; 33];
+    key[1..].copy_from_slice(blockhash);
+    key
+}
+
+/// The settings of a DB opened for indexing (see `DBStore::open`)
+#[derive(Clone, Debug)]
+pub(crate) struct StoreOptions {
+    pub(crate) log_dir: Option<PathBuf>,
+    pub(crate) auto_reindex: bool, // otherwise, an incompatible DB fails to open
+    pub(crate) parallelism: u8,
+    pub(crate) script_types: ScriptTypes,
+    pub(crate) prefix_len: PrefixLen,
+}
+
+impl StoreOptions {
+    pub(crate) fn for_config(config: &crate::config::Config) -> Self {
+        Self {
+            log_dir: config.db_log_dir.clone(),
+            auto_reindex: config.auto_reindex,
+            parallelism: config.db_parallelism,
+            script_types: config.index_script_types.clone(),
+            prefix_len: config.index_prefix_len,
         }
     }
 }
 
-fn default_opts(parallelism: u8) -> rocksdb::Options {
+/// Without re-indexing (e.g. for tests)
+impl Default for StoreOptions {
+    fn default() -> Self {
+        Self {
+            log_dir: None,
+            auto_reindex: false,
+            parallelism: 1,
+            script_types: ScriptTypes::all(),
+            prefix_len: PrefixLen::default(),
+        }
+    }
+}
+
+/// Whether opening the DB failed due to corrupted files (see `DBStore::open_or_repair`)
+fn is_corruption(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<rocksdb::Error>())
+        .any(|err| err.kind() == rocksdb::ErrorKind::Corruption)
+}
+
+fn default_opts(parallelism: u8, prefix_len: PrefixLen) -> rocksdb::Options {
     let mut block_opts = rocksdb::BlockBasedOptions::default();
     block_opts.set_checksum_type(rocksdb::ChecksumType::CRC32c);
     // Limit block cache to reduce memory and file handle pressure
-    let cache = rocksdb::Cache::new_lru_cache(256 << 20).expect("failed to create cache"); // 256MB cache
+    let cache = rocksdb::Cache::new_lru_cache(256 << 20); // 256MB cache
     block_opts.set_block_cache(&cache);
 
     let mut opts = rocksdb::Options::default();
@@ -107,30 +291,44 @@ fn default_opts(parallelism: u8) -> rocksdb::Options {
     opts.set_max_subcompactions(parallelism.into());
 
     opts.set_keep_log_file_num(10);
-    opts.set_max_open_files(16);
+    opts.set_max_open_files(MAX_OPEN_FILES);
     opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
     opts.set_compression_type(rocksdb::DBCompressionType::Zstd);
     opts.set_target_file_size_base(256 << 20);
     opts.set_write_buffer_size(256 << 20);
     opts.set_disable_auto_compactions(false); // for initial bulk load
     opts.set_advise_random_on_open(false); // bulk load uses sequential I/O
-    opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(8));
+    opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(
+        prefix_len.bytes(),
+    ));
     opts.set_block_based_table_factory(&block_opts);
     opts
 }
 
 impl DBStore {
-    fn create_cf_descriptors(parallelism: u8) -> Vec<rocksdb::ColumnFamilyDescriptor> {
+    fn create_cf_descriptors(
+        parallelism: u8,
+        prefix_len: PrefixLen,
+    ) -> Vec<rocksdb::ColumnFamilyDescriptor> {
         COLUMN_FAMILIES
             .iter()
-            .map(|&name| rocksdb::ColumnFamilyDescriptor::new(name, default_opts(parallelism)))
+            .map(|&name| {
+                rocksdb::ColumnFamilyDescriptor::new(name, default_opts(parallelism, prefix_len))
+            })
             .collect()
     }
 
-    fn open_internal(path: &Path, log_dir: Option<&Path>, parallelism: u8) -> Result<Self> {
-        let mut db_opts = default_opts(parallelism);
+    fn open_internal(
+        path: &Path,
+        log_dir: Option<&Path>,
+        parallelism: u8,
+        prefix_len: PrefixLen,
+    ) -> Result<Self> {
+        let mut db_opts = default_opts(parallelism, prefix_len);
         db_opts.create_if_missing(true);
         db_opts.create_missing_column_families(true);
+        // background flushes (when a memtable is full) must not persist a tip without its rows
+        db_opts.set_atomic_flush(true);
         if let Some(d) = log_dir {
             db_opts.set_db_log_dir(d);
         }
@@ -138,7 +336,7 @@ impl DBStore {
         let db = rocksdb::DB::open_cf_descriptors(
             &db_opts,
             path,
-            Self::create_cf_descriptors(parallelism),
+            Self::create_cf_descriptors(parallelism, prefix_len),
         )
         .with_context(|| format!("failed to open DB: {}", path.display()))?;
         let live_files = db.live_files()?;
@@ -152,6 +350,11 @@ impl DBStore {
         let store = DBStore {
             db,
             bulk_import: AtomicBool::new(true),
+            durability: Durability::default(),
+            unsynced_wal: AtomicBool::new(false),
+            read_only: false,
+            prefix_len,
+            total_order_scans: false,
         };
         Ok(store)
     }
@@ -165,17 +368,29 @@ impl DBStore {
     }
 
     /// Opens a new RocksDB at the specified location.
-    pub fn open(
-        path: &Path,
-        log_dir: Option<&Path>,
-        auto_reindex: bool,
-        parallelism: u8,
-    ) -> Result<Self> {
-        let mut store = Self::open_internal(path, log_dir, parallelism)?;
+    pub(crate) fn open(path: &Path, options: &StoreOptions) -> Result<Self> {
+        let StoreOptions {
+            ref log_dir,
+            auto_reindex,
+            parallelism,
+            ref script_types,
+            prefix_len,
+        } = *options;
+        let log_dir = log_dir.as_deref();
+        let mut store = Self::open_internal(path, log_dir, parallelism, prefix_len)?;
         let config = store.get_config();
         debug!("DB {:?}", config);
         let mut config = config.unwrap_or_default(); // use default config when DB is empty
 
+        if config.format == 0 && !store.is_legacy_format() {
+            store.migrate_tip(&mut config);
+        }
+        if config.format == 1 {
+            store.migrate_heights(&mut config);
+        }
+        if config.format == 2 {
+            store.migrate_funding_vouts(&mut config);
+        }
         let reindex_cause = if store.is_legacy_format() {
             Some("legacy format".to_owned())
         } else if config.format != CURRENT_FORMAT {
@@ -183,6 +398,16 @@ impl DBStore {
                 "unsupported format {} != {}",
                 config.format, CURRENT_FORMAT
             ))
+        } else if config.script_types != *script_types && store.get_tip().is_some() {
+            Some(format!(
+                "changed index_script_types ({} != {})",
+                config.script_types, script_types
+            ))
+        } else if config.prefix_len != prefix_len && store.get_tip().is_some() {
+            Some(format!(
+                "changed index_prefix_len ({} != {})",
+                config.prefix_len, prefix_len
+            ))
         } else {
             None
         };
@@ -197,22 +422,476 @@ impl DBStore {
             );
             // close DB before deletion
             drop(store);
-            rocksdb::DB::destroy(&default_opts(parallelism), path).with_context(|| {
+            let opts = default_opts(parallelism, prefix_len);
+            rocksdb::DB::destroy(&opts, path).with_context(|| {
                 format!(
                     "re-index required but the old database ({}) can not be deleted",
                     path.display()
                 )
             })?;
-            store = Self::open_internal(path, log_dir, parallelism)?;
+            store = Self::open_internal(path, log_dir, parallelism, prefix_len)?;
             config = Config::default(); // re-init config after dropping DB
         }
         if config.compacted {
             store.start_compactions();
         }
+        config.script_types = script_types.clone();
+        config.prefix_len = prefix_len;
         store.set_config(config);
         Ok(store)
     }
 
+    /// Like `open()`, but a DB failing to open due to corruption is repaired (see `repair_db`):
+    /// its rows are verified after the repair, and its tip is rolled back to the highest height
+    /// whose rows pass verification (so the following blocks are re-indexed). If the repair
+    /// fails, the DB is re-indexed from scratch (unless `auto_reindex` is disabled).
+    pub(crate) fn open_or_repair(path: &Path, options: &StoreOptions) -> Result<Self> {
+        let (log_dir, parallelism, prefix_len) = (
+            options.log_dir.as_deref(),
+            options.parallelism,
+            options.prefix_len,
+        );
+        let open = || Self::open(path, options);
+        let err = match open() {
+            Err(err) if is_corruption(&err) => err,
+            result => return result,
+        };
+        error!("DB at {} is corrupted: {:#}", path.display(), err);
+        let repaired = Self::repair(path, log_dir, parallelism, prefix_len).and_then(|lost| {
+            let store = open()?;
+            let verified = if lost.is_empty() {
+                store.roll_back_unverified()
+            } else {
+                // their rows may belong to any height, so re-indexing the last blocks won't help
+                Err(anyhow!(
+                    "{} corrupted table files were moved into {}: {:?}",
+                    lost.len(),
+                    path.join("lost").display(),
+                    lost
+                ))
+            };
+            if let Err(err) = verified {
+                error!("removing the tip of the repaired DB, since its rows are incomplete");
+                store.clear_tip();
+                return Err(err);
+            }
+            Ok(store)
+        });
+        let err = match repaired {
+            Ok(store) => return Ok(store),
+            Err(err) => err,
+        };
+        error!("DB repair failed: {:#}", err);
+        if !options.auto_reindex {
+            bail!("re-index required due to failed DB repair: {:#}", err);
+        }
+        error!(
+            "re-indexing from scratch, going to delete {}",
+            path.display()
+        );
+        rocksdb::DB::destroy(&default_opts(parallelism, prefix_len), path).with_context(|| {
+            format!(
+                "re-index required but the corrupted database ({}) can not be deleted",
+                path.display()
+            )
+        })?;
+        open()
+    }
+
+    /// Run RocksDB's repair routine, returning the corrupted table files it dropped (moved into
+    /// the `lost/` subdirectory).
+    fn repair(
+        path: &Path,
+        log_dir: Option<&Path>,
+        parallelism: u8,
+        prefix_len: PrefixLen,
+    ) -> Result<Vec<std::path::PathBuf>> {
+        let lost_dir = path.join("lost");
+        let lost_tables = || -> Vec<std::path::PathBuf> {
+            let entries = std::fs::read_dir(&lost_dir).into_iter().flatten().flatten();
+            entries
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "sst"))
+                .collect()
+        };
+        let before = lost_tables();
+        let mut opts = default_opts(parallelism, prefix_len);
+        if let Some(d) = log_dir {
+            opts.set_db_log_dir(d);
+        }
+        warn!("repairing DB at {} (this may take a while)", path.display());
+        rocksdb::DB::repair(&opts, path).context("DB repair failed")?;
+        let lost: Vec<_> = lost_tables()
+            .into_iter()
+            .filter(|path| !before.contains(path))
+            .collect();
+        warn!(
+            "DB at {} repaired ({} table files dropped)",
+            path.display(),
+            lost.len()
+        );
+        Ok(lost)
+    }
+
+    fn clear_tip(&self) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        self.db
+            .delete_cf_opt(self.config_cf(), TIP_KEY, &opts)
+            .expect("failed to remove the tip");
+    }
+
+    /// Scan all the rows of a repaired DB (verifying their checksums), and roll its tip back to
+    /// the highest height up to which each indexed block has a header (linked to the previous
+    /// one) and `TXID_CF` rows (each block has at least its coinbase transaction).
+    fn roll_back_unverified(&self) -> Result<()> {
+        let tip: BlockHash = match self.get_tip() {
+            Some(row) => deserialize(&row).context("invalid tip")?,
+            None => {
+                warn!("repaired DB has no tip, nothing to verify");
+                return Ok(());
+            }
+        };
+        ensure!(
+            self.has_height_rows(),
+            "repaired DB has no {} rows, so its blocks' rows can't be verified",
+            HEIGHTS_CF
+        );
+        let tip_height = self
+            .height_of(&tip)
+            .with_context(|| format!("missing height of tip {}", tip))?;
+        warn!(
+            "verifying all DB rows (tip {} at height {})",
+            tip, tip_height
+        );
+        let mut has_txids = vec![false; tip_height + 1];
+        for &name in COLUMN_FAMILIES {
+            let cf = self.db.cf_handle(name).expect("missing CF");
+            let mut opts = rocksdb::ReadOptions::default();
+            opts.set_verify_checksums(true);
+            opts.fill_cache(false);
+            let mut rows = 0u64;
+            for item in self
+                .db
+                .iterator_cf_opt(cf, opts, rocksdb::IteratorMode::Start)
+            {
+                let (key, _value) = item.with_context(|| format!("failed to scan {} CF", name))?;
+                if name == TXID_CF {
+                    let height = SerializedHashPrefixRow::from_key(&key)
+                        .map(|row| HashPrefixRow::from_db_row(row).height())
+                        .with_context(|| format!("invalid {} row: {:?}", name, key))?;
+                    if let Some(found) = has_txids.get_mut(height) {
+                        *found = true;
+                    }
+                }
+                rows += 1;
+            }
+            warn!("verified {} rows in {} CF", rows, name);
+        }
+        let mut verified = 0;
+        let mut prev = self.header_at(0); // genesis is not indexed (so it may be missing)
+        for (height, has_txids) in has_txids.into_iter().enumerate().skip(1) {
+            let header = match self.header_at(height) {
+                Some(header) => header,
+                None => break,
+            };
+            let linked = prev.is_none_or(|prev| prev.block_hash() == header.prev_blockhash);
+            if !linked || !has_txids {
+                break;
+            }
+            verified = height;
+            prev = Some(header);
+        }
+        let verified_tip = self
+            .header_at(verified)
+            .map(|header| header.block_hash())
+            .filter(|_| verified > 0)
+            .context("no indexed block passed verification")?;
+        if verified_tip == tip {
+            warn!("all rows up to tip {} passed verification", tip);
+            return Ok(());
+        }
+        warn!(
+            "rolling back the tip from height {} to {} ({}): the following blocks will be re-indexed",
+            tip_height, verified, verified_tip
+        );
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        self.db
+            .put_cf_opt(
+                self.config_cf(),
+                TIP_KEY,
+                verified_tip.to_byte_array(),
+                &opts,
+            )
+            .context("failed to roll back the tip")
+    }
+
+    /// Opens an existing DB for offline maintenance (see `electrs db`), without checking its format
+    /// (so it is never re-indexed). Fails if the DB is used by another process.
+    pub(crate) fn open_existing(
+        path: &Path,
+        log_dir: Option<&Path>,
+        parallelism: u8,
+    ) -> Result<Self> {
+        if !path.join("CURRENT").exists() {
+            bail!("no DB found at {}", path.display());
+        }
+        let store = Self::open_internal(path, log_dir, parallelism, PrefixLen::default())?;
+        match store.get_config().map(|config| config.prefix_len) {
+            // reopened with the DB's prefix extractor
+            Some(prefix_len) if prefix_len != store.prefix_len => {
+                drop(store);
+                Self::open_internal(path, log_dir, parallelism, prefix_len)
+            }
+            _ => Ok(store),
+        }
+    }
+
+    /// Opens an existing DB for reading only (see `electrs scripthash-dump`). It may be used by a
+    /// running electrs meanwhile, so its rows are read as of the time it was opened.
+    pub(crate) fn open_read_only(
+        path: &Path,
+        log_dir: Option<&Path>,
+        parallelism: u8,
+    ) -> Result<Self> {
+        Self::open_reader(path, log_dir, parallelism, None)
+    }
+
+    /// The index settings stored by an indexed DB at `path` (see `shadow_migration`), or `None`
+    /// if there is none (or if it can't be served as is, e.g. due to an unsupported format).
+    pub(crate) fn stored_settings(
+        path: &Path,
+        log_dir: Option<&Path>,
+        parallelism: u8,
+    ) -> Option<(ScriptTypes, PrefixLen)> {
+        let store = Self::open_read_only(path, log_dir, parallelism).ok()?;
+        store.get_tip()?;
+        let config = store.get_config()?;
+        Some((config.script_types, config.prefix_len))
+    }
+
+    /// Opens an existing DB as a RocksDB secondary instance (see `electrs db tip`), whose own
+    /// files are kept at `secondary_path`. Unlike `open_read_only()`, it follows the recent
+    /// writes of a running electrs (up to the time it was opened).
+    pub(crate) fn open_secondary(
+        path: &Path,
+        secondary_path: &Path,
+        parallelism: u8,
+    ) -> Result<Self> {
+        Self::open_reader(path, None, parallelism, Some(secondary_path))
+    }
+
+    /// The DB is opened once, using the default prefix extractor: a DB indexed with another
+    /// `index_prefix_len` is scanned in total order (skipping its prefix bloom filters).
+    fn open_reader(
+        path: &Path,
+        log_dir: Option<&Path>,
+        parallelism: u8,
+        secondary_path: Option<&Path>,
+    ) -> Result<Self> {
+        if !path.join("CURRENT").exists() {
+            bail!("no DB found at {}", path.display());
+        }
+        let extractor_len = PrefixLen::default();
+        let mut db_opts = default_opts(parallelism, extractor_len);
+        if let Some(d) = log_dir {
+            db_opts.set_db_log_dir(d);
+        }
+        let cfs = Self::create_cf_descriptors(parallelism, extractor_len);
+        let db = match secondary_path {
+            None => rocksdb::DB::open_cf_descriptors_read_only(&db_opts, path, cfs, false),
+            Some(secondary_path) => {
+                db_opts.set_max_open_files(-1); // required by secondary instances
+                rocksdb::DB::open_cf_descriptors_as_secondary(&db_opts, path, secondary_path, cfs)
+            }
+        }
+        .with_context(|| format!("failed to open DB for reading: {}", path.display()))?;
+        let mut store = DBStore {
+            db,
+            bulk_import: AtomicBool::new(false),
+            durability: Durability::default(),
+            unsynced_wal: AtomicBool::new(false),
+            read_only: true,
+            prefix_len: extractor_len,
+            total_order_scans: false,
+        };
+        if let Some(config) = store.get_config() {
+            store.prefix_len = config.prefix_len;
+            store.total_order_scans = config.prefix_len != extractor_len;
+            ensure!(
+                config.format == CURRENT_FORMAT,
+                "DB at {} has format {} != {} (run electrs to upgrade it)",
+                path.display(),
+                config.format,
+                CURRENT_FORMAT
+            );
+        }
+        Ok(store)
+    }
+
+    /// Move the tip of a format 0 DB from `HEADERS_CF` into `CONFIG_CF` (so the headers CF
+    /// contains only header rows), atomically with the format bump.
+    fn migrate_tip(&self, config: &mut Config) {
+        let headers_cf = self.headers_cf();
+        let tip = self.db.get_cf(headers_cf, TIP_KEY).expect("get_tip failed");
+        config.format = 1;
+        let mut db_batch = rocksdb::WriteBatch::default();
+        if let Some(tip) = &tip {
+            db_batch.put_cf(self.config_cf(), TIP_KEY, tip);
+            db_batch.delete_cf(headers_cf, TIP_KEY);
+        }
+        let value = serde_json::to_vec(&config).expect("failed to serialize config");
+        db_batch.put_cf(self.config_cf(), CONFIG_KEY, value);
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        self.db
+            .write_opt(db_batch, &opts)
+            .expect("DB migration failed");
+        info!(
+            "moved the tip into {} CF (format {})",
+            CONFIG_CF, config.format
+        );
+    }
+
+    /// Upgrade a format 1 DB, whose `HEIGHTS_CF` rows may be missing (so they are written again
+    /// if `headers_in_memory_k` is enabled). The config is written by `open()`.
+    fn migrate_heights(&self, config: &mut Config) {
+        config.format = 2;
+        config.height_rows = false;
+        info!("added {} CF (format {})", HEIGHTS_CF, config.format);
+    }
+
+    /// Upgrade a format 2 DB in place: its funding rows don't store the output index, so their
+    /// blocks are scanned entirely (as before) until the DB is re-indexed. The config is written
+    /// by `open()`.
+    fn migrate_funding_vouts(&self, config: &mut Config) {
+        config.format = 3;
+        info!(
+            "new {} rows store the output index (format {}), re-index to add it to the existing ones",
+            FUNDING_CF, config.format
+        );
+    }
+
+    /// The names of the DB column families
+    pub(crate) fn column_families() -> &'static [&'static str] {
+        COLUMN_FAMILIES
+    }
+
+    /// The total size of each column family's SST files (in bytes)
+    pub(crate) fn cf_sizes(&self) -> Result<Vec<(&'static str, u64)>> {
+        let live_files = self.db.live_files()?;
+        Ok(COLUMN_FAMILIES
+            .iter()
+            .map(|&name| {
+                let size = live_files
+                    .iter()
+                    .filter(|f| f.column_family_name == name)
+                    .map(|f| f.size as u64)
+                    .sum();
+                (name, size)
+            })
+            .collect())
+    }
+
+    /// Compact the whole key range of a column family (blocking until it is done)
+    pub(crate) fn compact_cf(&self, name: &str) -> Result<()> {
+        let cf = self
+            .db
+            .cf_handle(name)
+            .with_context(|| format!("unknown column family: {}", name))?;
+        self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+        Ok(())
+    }
+
+    /// Compact the next slice of the DB's key space (resuming where the last call has stopped,
+    /// also across restarts), returning the number of bytes reclaimed by the compaction.
+    pub(crate) fn compact_next_slice(&self, now: u64) -> u64 {
+        let mut progress = self.get_maintenance().unwrap_or_default();
+        let slice = progress.next_slice % (COLUMN_FAMILIES.len() * SLICES_PER_CF);
+        let name = COLUMN_FAMILIES[slice / SLICES_PER_CF];
+        let first = (slice % SLICES_PER_CF) as u8; // the first byte of the slice's keys
+        let cf = self.db.cf_handle(name).expect("missing CF");
+        let before = self.live_size(cf);
+        let start = [first];
+        let end = first.checked_add(1).map(|next| [next]);
+        self.db
+            .compact_range_cf(cf, Some(&start[..]), end.as_ref().map(|end| &end[..]));
+        let reclaimed = before.saturating_sub(self.live_size(cf));
+        debug!(
+            "compacted {} slice {:02x}: reclaimed {} bytes",
+            name, first, reclaimed
+        );
+        progress.next_slice = slice + 1;
+        progress.last_run = now;
+        self.set_maintenance(progress);
+        reclaimed
+    }
+
+    /// The UNIX time of the last compacted slice (see `compact_next_slice`)
+    pub(crate) fn last_maintenance(&self) -> Option<u64> {
+        self.get_maintenance().map(|progress| progress.last_run)
+    }
+
+    /// Returns `true` until the initial sync is done (and the DB is fully compacted).
+    pub(crate) fn is_bulk_import(&self) -> bool {
+        self.bulk_import.load(Ordering::Relaxed)
+    }
+
+    fn live_size(&self, cf: &rocksdb::ColumnFamily) -> u64 {
+        self.db
+            .property_int_value_cf(cf, "rocksdb.live-sst-files-size")
+            .expect("failed to get property")
+            .unwrap_or_default()
+    }
+
+    /// Record whether blocks' txids are stored (from now on) in the DB.
+    ///
+    /// Blocks indexed while it was disabled are not stored, so their txids are fetched from bitcoind.
+    pub(crate) fn set_merkle_cache(&self, enabled: bool) {
+        let mut config = self.get_config().unwrap_or_default();
+        if config.merkle_cache == enabled {
+            return;
+        }
+        if enabled && self.get_tip().is_some() {
+            warn!("merkle_cache enabled: blocks which are already indexed will be fetched from bitcoind (re-index to store them)");
+        }
+        config.merkle_cache = enabled;
+        self.set_config(config);
+    }
+
+    /// Make sure the DB is not used with another network, or another custom chain (and record
+    /// them, if they are not stored yet).
+    pub(crate) fn check_network(&self, network: Network, genesis: BlockHash) -> Result<()> {
+        let mut config = self.get_config().unwrap_or_default();
+        let before = (config.network, config.genesis);
+        match config.network {
+            Some(stored) => ensure!(
+                stored == network,
+                "DB at {} was created for {} network, but electrs is configured for {}",
+                self.db.path().display(),
+                stored,
+                network
+            ),
+            None => config.network = Some(network),
+        }
+        match config.genesis {
+            Some(stored) => ensure!(
+                stored == genesis,
+                "DB at {} was created for the chain with genesis {}, but electrs is configured \
+                 for genesis {}",
+                self.db.path().display(),
+                stored,
+                genesis
+            ),
+            None => config.genesis = Some(genesis),
+        }
+        if before != (config.network, config.genesis) {
+            self.set_config(config);
+        }
+        Ok(())
+    }
+
     fn config_cf(&self) -> &rocksdb::ColumnFamily {
         self.db.cf_handle(CONFIG_CF).expect("missing CONFIG_CF")
     }
@@ -233,60 +912,194 @@ impl DBStore {
         self.db.cf_handle(HEADERS_CF).expect("missing HEADERS_CF")
     }
 
+    fn merkle_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(MERKLE_CF).expect("missing MERKLE_CF")
+    }
+
+    fn heights_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(HEIGHTS_CF).expect("missing HEIGHTS_CF")
+    }
+
+    /// The length of the hash prefix rows' prefixes
+    pub(crate) fn prefix_len(&self) -> PrefixLen {
+        self.prefix_len
+    }
+
     pub(crate) fn iter_funding(
         &self,
-        prefix: HashPrefix,
+        prefix: RowPrefix,
     ) -> impl Iterator<Item = SerializedHashPrefixRow> + '_ {
         self.iter_prefix_cf(self.funding_cf(), prefix)
     }
 
     pub(crate) fn iter_spending(
         &self,
-        prefix: HashPrefix,
+        prefix: RowPrefix,
     ) -> impl Iterator<Item = SerializedHashPrefixRow> + '_ {
         self.iter_prefix_cf(self.spending_cf(), prefix)
     }
 
     pub(crate) fn iter_txid(
         &self,
-        prefix: HashPrefix,
+        prefix: RowPrefix,
     ) -> impl Iterator<Item = SerializedHashPrefixRow> + '_ {
         self.iter_prefix_cf(self.txid_cf(), prefix)
     }
 
-    fn iter_cf<const N: usize>(
+    fn iter_cf(
         &self,
         cf: &rocksdb::ColumnFamily,
         readopts: rocksdb::ReadOptions,
-        prefix: Option<HashPrefix>,
-    ) -> impl Iterator<Item = [u8; N]> + '_ {
+        prefix: Option<RowPrefix>,
+    ) -> impl Iterator<Item = SerializedHashPrefixRow> + '_ {
         DBIterator::new(self.db.raw_iterator_cf_opt(cf, readopts), prefix)
     }
 
     fn iter_prefix_cf(
         &self,
         cf: &rocksdb::ColumnFamily,
-        prefix: HashPrefix,
+        prefix: RowPrefix,
     ) -> impl Iterator<Item = SerializedHashPrefixRow> + '_ {
+        debug_assert_eq!(prefix.as_bytes().len(), self.prefix_len.bytes());
         let mut opts = rocksdb::ReadOptions::default();
-        opts.set_prefix_same_as_start(true); // requires .set_prefix_extractor() above.
+        if self.total_order_scans {
+            opts.set_total_order_seek(true); // the scan is bounded by `DBIterator`
+        } else {
+            opts.set_prefix_same_as_start(true); // requires .set_prefix_extractor() above.
+        }
         opts.fill_cache(false); // Don't fill block cache for iteration to reduce memory pressure
         opts.set_background_purge_on_iterator_cleanup(true); // Clean up resources promptly
         self.iter_cf(cf, opts, Some(prefix))
     }
 
-    pub(crate) fn iter_headers(&self) -> impl Iterator<Item = SerializedHeaderRow> + '_ {
+    /// Returns the stored header rows, and whether their blocks are indexed.
+    /// A failed scan or a malformed row (e.g. a corrupted DB) is returned as an error.
+    pub(crate) fn iter_headers(
+        &self,
+    ) -> impl Iterator<Item = Result<(SerializedHeaderRow, bool)>> + '_ {
+        let mut opts = rocksdb::ReadOptions::default();
+        opts.fill_cache(false);
+        self.db
+            .iterator_cf_opt(self.headers_cf(), opts, rocksdb::IteratorMode::Start)
+            .map(|item| -> Result<(SerializedHeaderRow, bool)> {
+                let (key, value) = item.context("headers scan failed")?;
+                let row = key
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| anyhow!("invalid header row ({}-byte key)", key.len()))?;
+                Ok((row, value.as_ref() != SYNCED_HEADER))
+            })
+    }
+
+    /// All the rows of a column family (in key order), e.g. for comparing two DBs
+    pub(crate) fn iter_rows(
+        &self,
+        name: &str,
+    ) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + '_ {
+        let cf = self.db.cf_handle(name).expect("missing CF");
         let mut opts = rocksdb::ReadOptions::default();
         opts.fill_cache(false);
-        self.iter_cf(self.headers_cf(), opts, None)
+        self.db
+            .iterator_cf_opt(cf, opts, rocksdb::IteratorMode::Start)
+            .map(|item| item.expect("DB scan failed"))
+    }
+
+    /// Persist the header rows synced before their blocks are indexed (so they are not
+    /// downloaded again after a restart). The tip is updated only by indexing their blocks.
+    pub(crate) fn write_headers(&self, rows: &[SerializedHeaderRow]) {
+        let mut db_batch = rocksdb::WriteBatch::default();
+        let headers_cf = self.headers_cf();
+        for key in rows {
+            db_batch.put_cf(headers_cf, key, SYNCED_HEADER);
+        }
+        let (sync, disable_wal) = self.write_options();
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(sync);
+        opts.disable_wal(disable_wal);
+        self.db.write_opt(db_batch, &opts).unwrap();
+        if (sync, disable_wal) == (false, false) {
+            self.unsynced_wal.store(true, Ordering::Relaxed);
+        }
     }
 
     pub(crate) fn get_tip(&self) -> Option<Vec<u8>> {
         self.db
-            .get_cf(self.headers_cf(), TIP_KEY)
+            .get_cf(self.config_cf(), TIP_KEY)
             .expect("get_tip failed")
     }
 
+    /// Whether all the indexed headers have `HEIGHTS_CF` rows (see `write_height_rows`).
+    pub(crate) fn has_height_rows(&self) -> bool {
+        self.get_config().is_some_and(|config| config.height_rows)
+    }
+
+    /// Delete the `HEIGHTS_CF` rows, which are not written anymore (since `headers_in_memory_k`
+    /// is disabled). They are written again for the whole chain if it is re-enabled.
+    pub(crate) fn drop_height_rows(&self) {
+        if self.read_only {
+            return; // e.g. `scripthash-dump`
+        }
+        let mut config = self.get_config().unwrap_or_default();
+        if config.height_rows {
+            config.height_rows = false;
+            self.set_config(config); // before the rows are deleted
+        }
+        let heights_cf = self.heights_cf();
+        let mut rows = self
+            .db
+            .iterator_cf(heights_cf, rocksdb::IteratorMode::Start);
+        if rows.next().is_none() {
+            return;
+        }
+        drop(rows);
+        info!("deleting {} rows (not used by this config)", HEIGHTS_CF);
+        // the keys start with either `b'B'` or `b'H'`
+        self.db
+            .delete_range_cf(heights_cf, [0u8], [0xFFu8])
+            .expect("DB delete failed");
+        self.db
+            .compact_range_cf(heights_cf, None::<&[u8]>, None::<&[u8]>);
+    }
+
+    /// Mark the DB as indexed by a version which didn't write `HEIGHTS_CF` rows.
+    #[cfg(test)]
+    pub(crate) fn clear_height_rows(&self) {
+        let mut config = self.get_config().unwrap_or_default();
+        config.height_rows = false;
+        self.set_config(config);
+    }
+
+    /// Write the `HEIGHTS_CF` rows of the indexed chain, for a DB indexed before they were
+    /// written with each block. Done once (fsynced), so `has_height_rows()` returns `true`.
+    pub(crate) fn write_height_rows(
+        &self,
+        rows: impl Iterator<Item = (usize, SerializedBlockHash, SerializedHeaderRow)>,
+    ) {
+        let heights_cf = self.heights_cf();
+        let mut rows = rows.peekable();
+        let mut count = 0;
+        while rows.peek().is_some() {
+            let mut db_batch = rocksdb::WriteBatch::default();
+            for (height, hash, header) in rows.by_ref().take(10_000) {
+                db_batch.put_cf(heights_cf, header_key(height), header);
+                db_batch.put_cf(heights_cf, height_key(&hash), (height as u32).to_be_bytes());
+                count += 1;
+            }
+            self.db.write(db_batch).expect("DB write failed");
+        }
+        let mut config = self.get_config().unwrap_or_default();
+        config.height_rows = true;
+        self.set_config(config); // synced, so the rows above are persisted too
+        info!("wrote {} rows into {} CF", count, HEIGHTS_CF);
+    }
+
+    /// Returns the serialized txids of a block (if stored by `merkle_cache`).
+    pub(crate) fn get_block_txids(&self, blockhash: SerializedBlockHash) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.merkle_cf(), blockhash)
+            .expect("get_block_txids failed")
+    }
+
     pub(crate) fn write(&self, batch: &WriteBatch) {
         let mut db_batch = rocksdb::WriteBatch::default();
         let funding_cf = self.funding_cf();
@@ -305,33 +1118,102 @@ impl DBStore {
         for key in &batch.header_rows {
             db_batch.put_cf(headers_cf, key, b"");
         }
-        db_batch.put_cf(headers_cf, TIP_KEY, batch.tip_row);
+        db_batch.put_cf(self.config_cf(), TIP_KEY, batch.tip_row);
+        let merkle_cf = self.merkle_cf();
+        for (key, value) in &batch.merkle_rows {
+            db_batch.put_cf(merkle_cf, key, value);
+        }
+        let heights_cf = self.heights_cf();
+        for (height, hash, header) in &batch.height_rows {
+            db_batch.put_cf(heights_cf, header_key(*height), header);
+            db_batch.put_cf(heights_cf, height_key(hash), (*height as u32).to_be_bytes());
+        }
 
+        let (sync, disable_wal) = self.write_options();
         let mut opts = rocksdb::WriteOptions::new();
-        let bulk_import = self.bulk_import.load(Ordering::Relaxed);
-        opts.set_sync(!bulk_import);
-        opts.disable_wal(bulk_import);
+        opts.set_sync(sync);
+        opts.disable_wal(disable_wal);
         self.db.write_opt(db_batch, &opts).unwrap();
+        if (sync, disable_wal) == (false, false) {
+            self.unsynced_wal.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Set the write options used after the initial sync.
+    pub(crate) fn set_durability(&mut self, durability: Durability) {
+        info!("DB durability: {:?}", durability);
+        self.durability = durability;
+    }
+
+    /// Returns `(sync, disable_wal)` write options (the WAL is always disabled during bulk import).
+    fn write_options(&self) -> (bool, bool) {
+        if self.bulk_import.load(Ordering::Relaxed) {
+            return (false, true);
+        }
+        match self.durability {
+            Durability::Sync => (true, false),
+            Durability::Wal => (false, false),
+            Durability::None => (false, true),
+        }
+    }
+
+    /// Sync the WAL to disk (if it was written using `Durability::Wal` since the previous call),
+    /// called at the end of each sync.
+    pub(crate) fn sync_wal(&self) {
+        if self.unsynced_wal.swap(false, Ordering::Relaxed) {
+            self.db.flush_wal(true).expect("WAL sync failed");
+        }
     }
 
+    /// Flush all the memtables to SST files (atomically), waiting for the flush to finish.
+    /// RocksDB syncs the new SST files (and the MANIFEST) before the flush is completed.
+    fn flush_cfs(&self) -> Result<(), rocksdb::Error> {
+        let cfs: Vec<&rocksdb::ColumnFamily> = COLUMN_FAMILIES
+            .iter()
+            .map(|name| self.db.cf_handle(name).expect("missing CF"))
+            .collect();
+        let mut opts = rocksdb::FlushOptions::default();
+        opts.set_wait(true);
+        self.db.flush_cfs_opt(&cfs, &opts)
+    }
+
+    /// Flush the memtables, performing the initial full compaction if it wasn't done yet.
     pub(crate) fn flush(&self) {
-        debug!("flushing DB column families");
+        self.compact_initial();
+        self.flush_memtables();
+    }
+
+    /// Perform the full compaction ending the initial bulk import (enabling auto-compactions and
+    /// the WAL), returning `false` if it was already done.
+    pub(crate) fn compact_initial(&self) -> bool {
         let mut config = self.get_config().unwrap_or_default();
+        if config.compacted {
+            return false;
+        }
+        // the rows written without WAL must be persisted before `compacted` is set
+        self.flush_cfs().expect("CF flush failed");
         for name in COLUMN_FAMILIES {
+            info!("starting {} compaction", name);
             let cf = self.db.cf_handle(name).expect("missing CF");
-            self.db.flush_cf(cf).expect("CF flush failed");
-        }
-        if !config.compacted {
-            for name in COLUMN_FAMILIES {
-                info!("starting {} compaction", name);
-                let cf = self.db.cf_handle(name).expect("missing CF");
-                self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
-            }
-            config.compacted = true;
-            self.set_config(config);
-            info!("finished full compaction");
-            self.start_compactions();
+            self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
         }
+        config.compacted = true;
+        self.set_config(config);
+        info!("finished full compaction");
+        self.start_compactions();
+        true
+    }
+
+    /// Flush the memtables into SST files (without the initial full compaction).
+    pub(crate) fn flush_memtables(&self) {
+        debug!("flushing DB column families");
+        self.flush_cfs().expect("CF flush failed");
+        let keys: Vec<String> = self
+            .estimate_num_keys()
+            .into_iter()
+            .map(|(cf, keys)| format!("{}={}", cf, keys))
+            .collect();
+        info!("DB keys (estimated): {}", keys.join(", "));
         if log_enabled!(log::Level::Trace) {
             let stats = self
                 .db
@@ -357,6 +1239,45 @@ impl DBStore {
         })
     }
 
+    /// The estimated number of keys in each column family (updated by RocksDB on flushes and
+    /// compactions, so it may be inaccurate if the same keys are overwritten or deleted)
+    pub(crate) fn estimate_num_keys(&self) -> Vec<(&'static str, u64)> {
+        COLUMN_FAMILIES
+            .iter()
+            .map(|&name| {
+                let cf = self.db.cf_handle(name).expect("missing CF");
+                let keys = self
+                    .db
+                    .property_int_value_cf(cf, "rocksdb.estimate-num-keys")
+                    .expect("failed to get property")
+                    .unwrap_or_default();
+                (name, keys)
+            })
+            .collect()
+    }
+
+    /// The exact number of keys in each column family (scanning the whole DB)
+    pub(crate) fn count_keys(&self) -> Result<Vec<(&'static str, u64)>> {
+        COLUMN_FAMILIES
+            .iter()
+            .map(|&name| {
+                let cf = self.db.cf_handle(name).expect("missing CF");
+                let mut opts = rocksdb::ReadOptions::default();
+                opts.fill_cache(false);
+                let mut iter = self.db.raw_iterator_cf_opt(cf, opts);
+                iter.seek_to_first();
+                let mut keys = 0;
+                while iter.valid() {
+                    keys += 1;
+                    iter.next();
+                }
+                iter.status()
+                    .with_context(|| format!("failed to scan {} column family", name))?;
+                Ok((name, keys))
+            })
+            .collect()
+    }
+
     fn start_compactions(&self) {
         self.bulk_import.store(false, Ordering::Relaxed);
         for name in COLUMN_FAMILIES {
@@ -369,6 +1290,9 @@ impl DBStore {
     }
 
     fn set_config(&self, config: Config) {
+        if self.read_only {
+            return; // e.g. a network which is not recorded yet
+        }
         let mut opts = rocksdb::WriteOptions::default();
         opts.set_sync(true);
         opts.disable_wal(false);
@@ -378,6 +1302,53 @@ impl DBStore {
             .expect("DB::put failed");
     }
 
+    fn set_maintenance(&self, progress: Maintenance) {
+        if self.read_only {
+            return;
+        }
+        let value = serde_json::to_vec(&progress).expect("failed to serialize maintenance");
+        self.db
+            .put_cf(self.config_cf(), MAINTENANCE_KEY, value)
+            .expect("DB::put failed");
+    }
+
+    fn get_maintenance(&self) -> Option<Maintenance> {
+        self.db
+            .get_cf(self.config_cf(), MAINTENANCE_KEY)
+            .expect("DB::get failed")
+            .map(|value| serde_json::from_slice(&value).expect("failed to deserialize maintenance"))
+    }
+
+    /// Persist the monotonic counters' values (see `Totals`), failing without affecting the
+    /// index (so the counters may only lag behind after a crash).
+    pub(crate) fn set_totals(&self, totals: &BTreeMap<String, u64>) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let value = serde_json::to_vec(totals).expect("failed to serialize totals");
+        self.db
+            .put_cf(self.config_cf(), TOTALS_KEY, value)
+            .context("failed to persist totals")
+    }
+
+    /// The persisted counters' values (empty if none were persisted, or if they are invalid)
+    pub(crate) fn get_totals(&self) -> BTreeMap<String, u64> {
+        let value = match self.db.get_cf(self.config_cf(), TOTALS_KEY) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("failed to read totals: {}", e);
+                None
+            }
+        };
+        value
+            .and_then(|value| {
+                serde_json::from_slice(&value)
+                    .map_err(|e| warn!("ignoring invalid totals: {}", e))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+
     fn get_config(&self) -> Option<Config> {
         self.db
             .get_cf(self.config_cf(), CONFIG_KEY)
@@ -386,16 +1357,48 @@ impl DBStore {
     }
 }
 
-struct DBIterator<'a, const N: usize> {
+/// Headers evicted from the in-memory chain are read from `HEIGHTS_CF`.
+impl HeaderStore for DBStore {
+    fn header_at(&self, height: usize) -> Option<BlockHeader> {
+        let row = self
+            .db
+            .get_pinned_cf(self.heights_cf(), header_key(height))
+            .expect("get_header failed")?;
+        Some(deserialize(&row).expect("invalid header"))
+    }
+
+    fn height_of(&self, blockhash: &BlockHash) -> Option<usize> {
+        let key = height_key(blockhash.as_byte_array());
+        let row = self
+            .db
+            .get_pinned_cf(self.heights_cf(), key)
+            .expect("get_height failed")?;
+        Some(u32::from_be_bytes(row.as_ref().try_into().expect("invalid height")) as usize)
+    }
+
+    fn for_each_header(&self, heights: Range<usize>, func: &mut dyn FnMut(BlockHeader)) {
+        let mut opts = rocksdb::ReadOptions::default();
+        opts.fill_cache(false);
+        opts.set_iterate_upper_bound(header_key(heights.end));
+        let mode =
+            rocksdb::IteratorMode::From(&header_key(heights.start), rocksdb::Direction::Forward);
+        for item in self.db.iterator_cf_opt(self.heights_cf(), opts, mode) {
+            let (_key, value) = item.expect("DB scan failed");
+            func(deserialize(&value).expect("invalid header"));
+        }
+    }
+}
+
+struct DBIterator<'a> {
     raw: rocksdb::DBRawIterator<'a>,
-    prefix: Option<HashPrefix>,
+    prefix: Option<RowPrefix>,
     done: bool,
 }
 
-impl<'a, const N: usize> DBIterator<'a, N> {
-    fn new(mut raw: rocksdb::DBRawIterator<'a>, prefix: Option<HashPrefix>) -> Self {
+impl<'a> DBIterator<'a> {
+    fn new(mut raw: rocksdb::DBRawIterator<'a>, prefix: Option<RowPrefix>) -> Self {
         match prefix {
-            Some(key) => raw.seek(key),
+            Some(key) => raw.seek(key.as_bytes()),
             None => raw.seek_to_first(),
         };
         Self {
@@ -406,8 +1409,8 @@ impl<'a, const N: usize> DBIterator<'a, N> {
     }
 }
 
-impl<const N: usize> Iterator for DBIterator<'_, N> {
-    type Item = [u8; N];
+impl Iterator for DBIterator<'_> {
+    type Item = SerializedHashPrefixRow;
 
     fn next(&mut self) -> Option<Self::Item> {
         while !self.done {
@@ -419,17 +1422,17 @@ impl<const N: usize> Iterator for DBIterator<'_, N> {
                 }
             };
             let prefix_match = match self.prefix {
-                Some(key_prefix) => key.starts_with(&key_prefix),
+                Some(key_prefix) => key.starts_with(key_prefix.as_bytes()),
                 None => true,
             };
             if !prefix_match {
                 break; // prefix mismatch
             }
-            let result: Option<[u8; N]> = key.try_into().ok();
+            let result = SerializedHashPrefixRow::from_key(key);
             self.raw.next();
             match result {
                 Some(value) => return Some(value),
-                None => continue, // skip keys with size != N
+                None => continue, // skip keys of other sizes
             }
         }
         self.done = true;
@@ -440,26 +1443,49 @@ impl<const N: usize> Iterator for DBIterator<'_, N> {
 impl Drop for DBStore {
     fn drop(&mut self) {
         info!("closing DB at {}", self.db.path().display());
+        let result = match self.write_options() {
+            (_, true) => self.flush_cfs(), // the memtables are not backed by the WAL
+            (false, false) => self.db.flush_wal(true), // e.g. `Durability::Wal`
+            (true, false) => Ok(()),       // each write is synced
+        };
+        if let Err(e) = result {
+            warn!("failed to persist DB writes on shutdown: {}", e);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{rocksdb, DBStore, WriteBatch, CURRENT_FORMAT};
+    use super::{
+        rocksdb, DBStore, Maintenance, PrefixLen, RowPrefix, ScriptTypes, SerializedHashPrefixRow,
+        StoreOptions, WriteBatch, COLUMN_FAMILIES, CURRENT_FORMAT, SLICES_PER_CF, SYNCED_HEADER,
+        TIP_KEY, TOTALS_KEY, TXID_CF,
+    };
+    use crate::{
+        chain::HeaderStore,
+        config::Durability,
+        types::{HeaderRow, ScriptType, TxidRow},
+    };
+    use bitcoin::{blockdata::constants::genesis_block, hashes::Hash, BlockHash, Network, Txid};
+    use std::collections::BTreeMap;
     use std::ffi::{OsStr, OsString};
+    use std::io::{BufRead, BufReader, Write};
+    use std::ops::Range;
     use std::path::Path;
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::Ordering;
 
     #[test]
     fn test_reindex_new_format() {
         let dir = tempfile::tempdir().unwrap();
         {
-            let store = DBStore::open(dir.path(), None, false, 1).unwrap();
+            let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
             let mut config = store.get_config().unwrap();
             config.format += 1;
             store.set_config(config);
         };
         assert_eq!(
-            DBStore::open(dir.path(), None, false, 1)
+            DBStore::open(dir.path(), &StoreOptions::default())
                 .err()
                 .unwrap()
                 .to_string(),
@@ -470,7 +1496,14 @@ mod tests {
             )
         );
         {
-            let store = DBStore::open(dir.path(), None, true, 1).unwrap();
+            let store = DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    auto_reindex: true,
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
             store.flush();
             let config = store.get_config().unwrap();
             assert_eq!(config.format, CURRENT_FORMAT);
@@ -488,24 +1521,453 @@ mod tests {
             db.put(b"F", b"").unwrap(); // insert legacy DB compaction marker (in 'default' column family)
         };
         assert_eq!(
-            DBStore::open(dir.path(), None, false, 1)
+            DBStore::open(dir.path(), &StoreOptions::default())
                 .err()
                 .unwrap()
                 .to_string(),
             format!("re-index required due to legacy format",)
         );
         {
-            let store = DBStore::open(dir.path(), None, true, 1).unwrap();
+            let store = DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    auto_reindex: true,
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
             store.flush();
             let config = store.get_config().unwrap();
             assert_eq!(config.format, CURRENT_FORMAT);
         }
     }
 
+    #[test]
+    fn test_migrate_funding_vouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let tip = [7u8; 32];
+        let old = [1, 2, 3, 4, 5, 6, 7, 8, 10, 0, 0, 0]; // prefix and height
+        {
+            // create a format 2 DB, whose funding rows don't store the output index
+            let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+            store.write(&WriteBatch {
+                tip_row: tip,
+                funding_rows: vec![row(&old)],
+                ..Default::default()
+            });
+            let mut config = store.get_config().unwrap();
+            config.format = 2;
+            store.set_config(config);
+            store.flush();
+        }
+        // it is upgraded without re-indexing, and its funding rows are still found
+        let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+        assert_eq!(store.get_config().unwrap().format, CURRENT_FORMAT);
+        assert_eq!(store.get_tip().unwrap(), tip);
+        let new = [1, 2, 3, 4, 5, 6, 7, 8, 11, 0, 0, 0, 2, 0, 0]; // with output index 2
+        store.write(&WriteBatch {
+            tip_row: tip,
+            funding_rows: vec![row(&new)],
+            ..Default::default()
+        });
+        let rows: Vec<_> = store.iter_funding(prefix(&old[..8])).collect();
+        assert_eq!(rows, vec![row(&old), row(&new)]);
+    }
+
+    #[test]
+    fn test_migrate_tip() {
+        let dir = tempfile::tempdir().unwrap();
+        let tip = [7u8; 32];
+        let row = [1u8; 80];
+        {
+            // create a format 0 DB, storing its tip in the headers CF
+            let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+            let headers_cf = store.headers_cf();
+            store.db.put_cf(headers_cf, row, b"").unwrap();
+            store
+                .db
+                .put_cf(headers_cf, [2u8; 80], SYNCED_HEADER)
+                .unwrap();
+            store.db.put_cf(headers_cf, TIP_KEY, tip).unwrap();
+            let mut config = store.get_config().unwrap();
+            config.format = 0;
+            store.set_config(config);
+            store.flush();
+        }
+        let err = DBStore::open_read_only(dir.path(), None, 1).err().unwrap();
+        assert!(
+            err.to_string().contains("run electrs to upgrade it"),
+            "{}",
+            err
+        );
+        {
+            // the tip is preserved without re-indexing
+            let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+            assert_eq!(store.get_config().unwrap().format, CURRENT_FORMAT);
+            assert_eq!(store.get_tip().unwrap(), tip);
+            let headers: Vec<_> = store.iter_headers().collect::<anyhow::Result<_>>().unwrap();
+            assert_eq!(headers, vec![(row, true), ([2u8; 80], false)]);
+            store.flush();
+        }
+        let store = DBStore::open_read_only(dir.path(), None, 1).unwrap();
+        assert_eq!(store.get_tip().unwrap(), tip);
+        let headers_cf = store.headers_cf();
+        assert_eq!(store.db.get_cf(headers_cf, TIP_KEY).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_header_row() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+        let headers_cf = store.headers_cf();
+        store.db.put_cf(headers_cf, [1u8; 80], b"").unwrap();
+        store.db.put_cf(headers_cf, [2u8; 79], b"").unwrap();
+        let mut rows = store.iter_headers();
+        assert_eq!(rows.next().unwrap().unwrap(), ([1u8; 80], true));
+        let err = rows.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("invalid header row"), "{}", err);
+    }
+
+    #[test]
+    fn test_reindex_script_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let segwit = ScriptTypes::only([ScriptType::P2wpkh, ScriptType::P2tr]);
+        {
+            // an empty DB doesn't need to be re-indexed
+            let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+            drop(store);
+            let store = DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    script_types: segwit.clone(),
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(store.get_config().unwrap().script_types, segwit);
+            store.write(&WriteBatch::default()); // sets the tip
+        }
+        assert_eq!(
+            DBStore::open(dir.path(), &StoreOptions::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            "re-index required due to changed index_script_types (p2wpkh,p2tr != all)"
+        );
+        {
+            let store = DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    script_types: segwit.clone(),
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
+            assert!(store.get_tip().is_some());
+        }
+        {
+            let store = DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    auto_reindex: true,
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
+            assert!(store.get_tip().is_none());
+            assert_eq!(store.get_config().unwrap().script_types, ScriptTypes::all());
+        }
+    }
+
+    #[test]
+    fn test_reindex_prefix_len() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    prefix_len: PrefixLen::Six,
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(store.prefix_len(), PrefixLen::Six);
+            store.write(&WriteBatch {
+                txid_rows: vec![row(b"abcdef    "), row(b"abcdeg    ")],
+                ..Default::default()
+            });
+        }
+        assert_eq!(
+            DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    prefix_len: PrefixLen::Ten,
+                    ..StoreOptions::default()
+                }
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "re-index required due to changed index_prefix_len (6 != 10)"
+        );
+        {
+            // maintenance commands use the DB's prefix length (scanned in total order, since
+            // the DB is opened with the default prefix extractor)
+            let store = DBStore::open_read_only(dir.path(), None, 1).unwrap();
+            assert_eq!(store.prefix_len(), PrefixLen::Six);
+            assert_eq!(store.iter_txid(prefix(b"abcdef")).count(), 1);
+            assert_eq!(store.iter_txid(prefix(b"abcdeg")).count(), 1);
+        }
+        {
+            let store = DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    auto_reindex: true,
+                    prefix_len: PrefixLen::Ten,
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
+            assert!(store.get_tip().is_none());
+            assert_eq!(store.get_config().unwrap().prefix_len, PrefixLen::Ten);
+            assert_eq!(store.iter_txid(prefix(b"abcdef    ")).count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_check_network() {
+        let genesis = |network| genesis_block(network).block_hash();
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+            assert_eq!(store.get_config().unwrap().network, None);
+            // an empty DB is configured
+            store
+                .check_network(Network::Testnet, genesis(Network::Testnet))
+                .unwrap();
+            let config = store.get_config().unwrap();
+            assert_eq!(config.network, Some(Network::Testnet));
+            assert_eq!(config.genesis, Some(genesis(Network::Testnet)));
+        }
+        let store = DBStore::open(
+            dir.path(),
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        store
+            .check_network(Network::Testnet, genesis(Network::Testnet))
+            .unwrap();
+        let err = store
+            .check_network(Network::Bitcoin, genesis(Network::Bitcoin))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "DB at {} was created for testnet network, but electrs is configured for bitcoin",
+                dir.path().display()
+            )
+        );
+
+        // custom chains are all stored as regtest, so they are distinguished by their genesis
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+        let (custom1, custom2) = (
+            BlockHash::from_byte_array([1; 32]),
+            BlockHash::from_byte_array([2; 32]),
+        );
+        store.check_network(Network::Regtest, custom1).unwrap();
+        store.check_network(Network::Regtest, custom1).unwrap();
+        let err = store.check_network(Network::Regtest, custom2).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "DB at {} was created for the chain with genesis {}, but electrs is configured \
+                 for genesis {}",
+                dir.path().display(),
+                custom1,
+                custom2
+            )
+        );
+
+        // the genesis of a DB created before it was stored is recorded
+        let mut config = store.get_config().unwrap();
+        config.genesis = None;
+        store.set_config(config);
+        store.check_network(Network::Regtest, custom2).unwrap();
+        assert_eq!(store.get_config().unwrap().genesis, Some(custom2));
+    }
+
+    #[test]
+    fn test_durability() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = DBStore::open(
+            dir.path(),
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        for durability in [Durability::Sync, Durability::Wal, Durability::None] {
+            store.set_durability(durability);
+            assert_eq!(store.write_options(), (false, true)); // no WAL during bulk import
+        }
+
+        store.start_compactions(); // the initial sync is over
+        store.set_durability(Durability::Sync);
+        assert_eq!(store.write_options(), (true, false));
+        store.set_durability(Durability::None);
+        assert_eq!(store.write_options(), (false, true));
+        store.set_durability(Durability::Wal);
+        assert_eq!(store.write_options(), (false, false));
+
+        // the WAL is synced after being written
+        store.write(&WriteBatch {
+            txid_rows: vec![row(&[1; 12])],
+            ..Default::default()
+        });
+        assert!(store.unsynced_wal.load(Ordering::Relaxed));
+        store.sync_wal();
+        assert!(!store.unsynced_wal.load(Ordering::Relaxed));
+    }
+
+    fn row(key: &[u8]) -> SerializedHashPrefixRow {
+        SerializedHashPrefixRow::from_key(key).unwrap()
+    }
+
+    fn prefix(bytes: &[u8]) -> RowPrefix {
+        RowPrefix::new(bytes, PrefixLen::try_from(bytes.len()).unwrap())
+    }
+
+    const CRASH_BATCHES: u8 = 10;
+    const CRASH_MARKER: &str = "crash point reached";
+
+    fn crash_batch(i: u8) -> WriteBatch {
+        WriteBatch {
+            tip_row: [i; 32],
+            funding_rows: (0..100u8)
+                .map(|j| row(&[i, j, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Executed in a child process (spawned by `test_crash_consistency`), which is killed after
+    /// reaching `ELECTRS_CRASH_POINT`.
+    #[test]
+    #[ignore]
+    fn crash_child() {
+        let (Ok(path), Ok(point)) = (
+            std::env::var("ELECTRS_CRASH_DB"),
+            std::env::var("ELECTRS_CRASH_POINT"),
+        ) else {
+            return; // not spawned by `test_crash_consistency`
+        };
+        let store = DBStore::open(
+            Path::new(&path),
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        (0..CRASH_BATCHES).for_each(|i| store.write(&crash_batch(i)));
+        match point.as_str() {
+            "written" => (),
+            "flushed" => store.flush(),
+            "written_after_flush" => {
+                store.flush();
+                (CRASH_BATCHES..2 * CRASH_BATCHES).for_each(|i| store.write(&crash_batch(i)));
+            }
+            _ => panic!("unknown crash point: {}", point),
+        }
+        println!("{}", CRASH_MARKER);
+        std::io::stdout().flush().unwrap();
+        loop {
+            std::thread::sleep(std::time::Duration::from_secs(1)); // until killed
+        }
+    }
+
+    #[test]
+    fn test_crash_consistency() {
+        for (point, expected_tip, compacted) in [
+            ("written", None, false), // the WAL is disabled during bulk import
+            ("flushed", Some(CRASH_BATCHES - 1), true),
+            ("written_after_flush", Some(2 * CRASH_BATCHES - 1), true), // synced writes
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut child = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "db::tests::crash_child",
+                    "--exact",
+                    "--ignored",
+                    "--nocapture",
+                ])
+                .env("ELECTRS_CRASH_DB", dir.path())
+                .env("ELECTRS_CRASH_POINT", point)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            let stdout = BufReader::new(child.stdout.take().unwrap());
+            let reached = stdout
+                .lines()
+                .any(|line| line.unwrap().ends_with(CRASH_MARKER));
+            assert!(reached, "{}: child failed", point);
+            child.kill().unwrap(); // SIGKILL (no shutdown)
+            child.wait().unwrap();
+
+            let store = DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    auto_reindex: true,
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
+            let tip = store.get_tip().map(|tip| tip[0]);
+            assert_eq!(tip, expected_tip, "{}", point);
+            assert_eq!(
+                store.get_config().unwrap().compacted,
+                compacted,
+                "{}",
+                point
+            );
+            // the rows are persisted iff their batch's tip is persisted
+            let rows = |i: u8| {
+                (0..100u8)
+                    .map(|j| {
+                        store
+                            .iter_funding(prefix(&[i, j, 0, 0, 0, 0, 0, 0]))
+                            .count()
+                    })
+                    .sum::<usize>()
+            };
+            for i in 0..2 * CRASH_BATCHES {
+                let expected = if tip.is_some_and(|tip| i <= tip) {
+                    100
+                } else {
+                    0
+                };
+                assert_eq!(rows(i), expected, "{}: batch {}", point, i);
+            }
+        }
+    }
+
     #[test]
     fn test_db_prefix_scan() {
         let dir = tempfile::tempdir().unwrap();
-        let store = DBStore::open(dir.path(), None, true, 1).unwrap();
+        let store = DBStore::open(
+            dir.path(),
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
 
         let items = [
             *b"ab          ",
@@ -519,18 +1981,348 @@ mod tests {
         ];
 
         store.write(&WriteBatch {
-            txid_rows: items.to_vec(),
+            txid_rows: items.iter().map(|key| row(key)).collect(),
+            ..Default::default()
+        });
+
+        let rows = store.iter_txid(prefix(b"abcdefgh"));
+        let expected: Vec<_> = items[1..5].iter().map(|key| row(key)).collect();
+        assert_eq!(rows.collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_merkle_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(
+            dir.path(),
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(!store.get_config().unwrap().merkle_cache);
+        store.set_merkle_cache(true);
+        assert!(store.get_config().unwrap().merkle_cache);
+
+        let txids = [[1u8; 32], [2u8; 32]].concat();
+        store.write(&WriteBatch {
+            merkle_rows: vec![([0xAB; 32], txids.clone())],
+            ..Default::default()
+        });
+        assert_eq!(store.get_block_txids([0xAB; 32]), Some(txids));
+        assert_eq!(store.get_block_txids([0xCD; 32]), None);
+
+        // the flag is persisted
+        drop(store);
+        let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+        assert!(store.get_config().unwrap().merkle_cache);
+    }
+
+    /// A DB with 5 indexed blocks, with `TXID_CF` rows only at `txid_heights`
+    fn repair_fixture(path: &Path, txid_heights: Range<usize>) -> Vec<BlockHash> {
+        let store = DBStore::open(
+            path,
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        let mut headers = vec![genesis_block(Network::Regtest).header];
+        for height in 1..=5 {
+            let prev = headers[height - 1];
+            let header = bitcoin::block::Header {
+                prev_blockhash: prev.block_hash(),
+                ..prev
+            };
+            headers.push(header);
+            let hash = header.block_hash().to_byte_array();
+            let txid = Txid::from_byte_array([height as u8; 32]);
+            store.write(&WriteBatch {
+                tip_row: hash,
+                header_rows: vec![HeaderRow::new(header).to_db_row()],
+                height_rows: vec![(height, hash, HeaderRow::new(header).to_db_row())],
+                txid_rows: txid_heights
+                    .contains(&height)
+                    .then(|| TxidRow::row(txid, height, PrefixLen::default()).to_db_row())
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            });
+        }
+        store.flush();
+        headers.iter().map(|header| header.block_hash()).collect()
+    }
+
+    fn truncate_files(dir: &Path, name: impl Fn(&str) -> bool) -> usize {
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if name(path.file_name().unwrap().to_str().unwrap()) {
+                let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+                let len = file.metadata().unwrap().len();
+                file.set_len(len / 2).unwrap();
+                count += 1;
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn test_repair_truncated_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |auto_reindex| {
+            DBStore::open_or_repair(
+                dir.path(),
+                &StoreOptions {
+                    auto_reindex: auto_reindex,
+                    ..StoreOptions::default()
+                },
+            )
+        };
+        repair_fixture(dir.path(), 1..6);
+        assert!(truncate_files(dir.path(), |name| name.ends_with(".sst")) > 0);
+        let err = DBStore::open(
+            dir.path(),
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .err()
+        .unwrap();
+        assert!(super::is_corruption(&err), "{:#}", err);
+
+        // the dropped rows may belong to any height, so all the blocks must be re-indexed
+        let err = open(false).err().unwrap();
+        assert!(
+            err.to_string()
+                .starts_with("re-index required due to failed DB repair: "),
+            "{:#}",
+            err
+        );
+        assert!(dir.path().join("lost").read_dir().unwrap().next().is_some());
+        {
+            // the repaired DB is not used as-is
+            let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+            assert!(store.get_tip().is_none());
+        }
+
+        repair_fixture(dir.path(), 1..6);
+        assert!(truncate_files(dir.path(), |name| name.ends_with(".sst")) > 0);
+        let store = open(true).unwrap(); // re-indexed from scratch
+        assert!(store.get_tip().is_none());
+        assert_eq!(store.iter_txid(prefix(&[1; 8])).count(), 0);
+    }
+
+    #[test]
+    fn test_repair_roll_back() {
+        let dir = tempfile::tempdir().unwrap();
+        // the rows of the last 2 blocks are missing
+        let blocks = repair_fixture(dir.path(), 1..4);
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .starts_with("MANIFEST-")
+            {
+                let mut data = std::fs::read(&path).unwrap();
+                let mid = data.len() / 2;
+                data[mid..mid + 4].iter_mut().for_each(|b| *b ^= 0xff); // bit flips
+                std::fs::write(&path, data).unwrap();
+            }
+        }
+        let err = DBStore::open(dir.path(), &StoreOptions::default())
+            .err()
+            .unwrap();
+        assert!(super::is_corruption(&err), "{:#}", err);
+
+        let store = DBStore::open_or_repair(dir.path(), &StoreOptions::default()).unwrap();
+        let tip: BlockHash = bitcoin::consensus::deserialize(&store.get_tip().unwrap()).unwrap();
+        assert_eq!(tip, blocks[3]); // the following blocks will be re-indexed
+        assert_eq!(store.iter_txid(prefix(&[3; 8])).count(), 1);
+        drop(store);
+
+        // the repaired DB is used as usual afterwards
+        let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+        assert_eq!(store.get_tip().unwrap(), blocks[3].to_byte_array());
+    }
+
+    #[test]
+    fn test_height_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(
+            dir.path(),
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(store.has_height_rows()); // written for each indexed block
+        let mut config = store.get_config().unwrap();
+        config.height_rows = false; // e.g. a DB indexed by an older version
+        store.set_config(config);
+        assert!(!store.has_height_rows());
+
+        let genesis = genesis_block(Network::Regtest).header;
+        let next = bitcoin::block::Header {
+            prev_blockhash: genesis.block_hash(),
+            ..genesis
+        };
+        let row = |height, header: bitcoin::block::Header| {
+            let hash = header.block_hash();
+            (
+                height,
+                hash.to_byte_array(),
+                HeaderRow::new(header).to_db_row(),
+            )
+        };
+        store.write_height_rows([row(0, genesis)].into_iter());
+        store.write(&WriteBatch {
+            height_rows: vec![row(1, next)],
             ..Default::default()
         });
+        drop(store);
 
-        let rows = store.iter_txid(*b"abcdefgh");
-        assert_eq!(rows.collect::<Vec<_>>(), items[1..5]);
+        let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+        assert!(store.has_height_rows());
+        assert_eq!(store.header_at(0), Some(genesis));
+        assert_eq!(store.header_at(1), Some(next));
+        assert_eq!(store.header_at(2), None);
+        assert_eq!(store.height_of(&next.block_hash()), Some(1));
+        assert_eq!(store.height_of(&bitcoin::BlockHash::all_zeros()), None);
+        let mut headers = vec![];
+        store.for_each_header(1..5, &mut |header| headers.push(header));
+        assert_eq!(headers, vec![next]);
+
+        // the rows are deleted when they are not used
+        store.drop_height_rows();
+        assert!(!store.has_height_rows());
+        assert_eq!(store.header_at(0), None);
+        assert_eq!(store.height_of(&next.block_hash()), None);
+        store.drop_height_rows(); // nothing to delete
+        drop(store);
+
+        // a format 1 DB may have missing rows
+        let open = || DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+        let store = open();
+        store.write_height_rows([row(0, genesis)].into_iter());
+        let mut config = store.get_config().unwrap();
+        config.format = 1;
+        store.set_config(config);
+        drop(store);
+        let store = open();
+        let config = store.get_config().unwrap();
+        assert_eq!(config.format, CURRENT_FORMAT);
+        assert!(!config.height_rows);
+    }
+
+    #[test]
+    fn test_totals() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+        let totals: BTreeMap<String, u64> = [("blocks_indexed".to_owned(), 123)].into();
+        {
+            let store = open();
+            assert!(store.get_totals().is_empty());
+            store.set_totals(&totals).unwrap();
+        }
+        {
+            let store = open();
+            assert_eq!(store.get_totals(), totals);
+            // invalid values are ignored (the counters restart from zero)
+            store
+                .db
+                .put_cf(store.config_cf(), TOTALS_KEY, b"invalid")
+                .unwrap();
+            assert!(store.get_totals().is_empty());
+            store.set_totals(&totals).unwrap();
+        }
+        let store = DBStore::open_read_only(dir.path(), None, 1).unwrap();
+        assert_eq!(store.get_totals(), totals);
+        store.set_totals(&BTreeMap::new()).unwrap(); // not persisted
+        assert_eq!(store.get_totals(), totals);
+    }
+
+    #[test]
+    fn test_compact_next_slice() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || {
+            DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    auto_reindex: true,
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap()
+        };
+        let rows: Vec<SerializedHashPrefixRow> = (0..100u8)
+            .map(|i| row(&[0, i, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]))
+            .collect();
+        let txid_slice = COLUMN_FAMILIES
+            .iter()
+            .position(|cf| *cf == TXID_CF)
+            .unwrap()
+            * SLICES_PER_CF;
+        {
+            let store = open();
+            assert!(store.is_bulk_import());
+            store.flush(); // the initial compaction
+            assert!(!store.is_bulk_import());
+            assert_eq!(store.last_maintenance(), None);
+
+            // the same rows are written twice, so the compaction of their slice reclaims space
+            for _ in 0..2 {
+                store.write(&WriteBatch {
+                    txid_rows: rows.clone(),
+                    ..Default::default()
+                });
+                store.flush();
+            }
+            store.set_maintenance(Maintenance {
+                next_slice: txid_slice,
+                last_run: 0,
+            });
+            assert!(store.compact_next_slice(1000) > 0);
+            assert_eq!(store.last_maintenance(), Some(1000));
+            assert_eq!(store.compact_next_slice(1001), 0); // the next slice is empty
+        }
+        // the progress is kept across restarts, and wraps around after the last slice
+        let store = open();
+        let progress = store.get_maintenance().unwrap();
+        assert_eq!(progress.next_slice, txid_slice + 2);
+        assert_eq!(progress.last_run, 1001);
+        let last = COLUMN_FAMILIES.len() * SLICES_PER_CF - 1;
+        store.set_maintenance(Maintenance {
+            next_slice: last,
+            last_run: 0,
+        });
+        store.compact_next_slice(1002);
+        assert_eq!(store.get_maintenance().unwrap().next_slice, last + 1);
+        store.compact_next_slice(1003);
+        assert_eq!(store.get_maintenance().unwrap().next_slice, 1);
+        // compactions don't change the stored rows
+        let keys = store.count_keys().unwrap();
+        assert!(keys.contains(&(TXID_CF, rows.len() as u64)), "{:?}", keys);
     }
 
     #[test]
     fn test_db_log_in_same_dir() {
         let dir1 = tempfile::tempdir().unwrap();
-        let _store = DBStore::open(dir1.path(), None, true, 1).unwrap();
+        let _store = DBStore::open(
+            dir1.path(),
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
 
         // LOG file is created in dir1
         let dir_files = list_log_files(dir1.path());
@@ -538,7 +2330,15 @@ mod tests {
 
         let dir2 = tempfile::tempdir().unwrap();
         let dir3 = tempfile::tempdir().unwrap();
-        let _store = DBStore::open(dir2.path(), Some(dir3.path()), true, 1).unwrap();
+        let _store = DBStore::open(
+            dir2.path(),
+            &StoreOptions {
+                log_dir: Some(dir3.path().to_owned()),
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
 
         // *_LOG file is not created in dir2, but in dir3
         let dir_files = list_log_files(dir2.path());
@@ -0,0 +1,456 @@
+use anyhow::{Context, Result};
+use bitcoin::blockdata::block::Header as BlockHeader;
+use bitcoin::{consensus::deserialize, hex::DisplayHex, BlockHash, OutPoint, TxMerkleNode};
+use parking_lot::RwLock;
+use serde_json::{json, Value};
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::ops::Range;
+
+use crate::{
+    cache::Cache,
+    chain::{Chain, HeaderStore},
+    daemon::Node,
+    db::DBStore,
+    index::Index,
+    mempool::Mempool,
+    metrics::Metrics,
+    status::{Balance, ScriptHashStatus},
+    types::{HashPrefixRow, HeaderRow, ScriptHash, ScriptTypes},
+};
+
+/// A single index row, with its decoded fields
+#[derive(Serialize)]
+struct Row {
+    key: String,    // the looked up scripthash, outpoint or txid
+    row: String,    // the serialized row (hex)
+    prefix: String, // the key's hash prefix (hex)
+    height: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vout: Option<u32>, // the funded output's index (funding rows since DB format 3)
+    blockhash: Option<BlockHash>, // `None` if the row is above the indexed tip
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verified: Option<bool>, // whether the block contains a matching transaction (if checked)
+}
+
+impl Row {
+    fn new(key: String, row: HashPrefixRow, chain: &Chain, verified: Option<bool>) -> Self {
+        Self {
+            key,
+            row: row.to_db_row().as_ref().to_lower_hex_string(),
+            prefix: row.prefix().to_lower_hex_string(),
+            height: row.height(),
+            vout: row.vout(),
+            blockhash: chain.get_block_hash(row.height()),
+            verified,
+        }
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let blockhash = self
+            .blockhash
+            .map_or_else(|| "above tip".to_owned(), |hash| hash.to_string());
+        let verified = match self.verified {
+            Some(true) => "verified",
+            Some(false) => "prefix collision",
+            None => "unverified",
+        };
+        let vout = self
+            .vout
+            .map_or_else(String::new, |vout| format!(" vout={}", vout));
+        writeln!(
+            out,
+            "  {} prefix={} height={}{} block={} ({})\n    key={}",
+            self.row, self.prefix, self.height, vout, blockhash, verified, self.key
+        )
+    }
+}
+
+/// The scripthash's history, as computed from the transactions fetched from bitcoind
+#[derive(Serialize)]
+struct Verified {
+    history: Vec<Value>,
+    balance: Balance,
+    status: Option<String>,
+}
+
+/// Everything the index contains about a single scripthash (see `electrs scripthash-dump`)
+#[derive(Serialize)]
+pub(crate) struct ScriptHashDump {
+    scripthash: String,
+    tip: Value,
+    funding: Vec<Row>,
+    spending: Vec<Row>, // rows of the funded outpoints (requires bitcoind)
+    txid: Vec<Row>,     // rows of the history transactions (requires bitcoind)
+    verified: Option<Verified>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daemon_error: Option<String>,
+}
+
+impl ScriptHashDump {
+    /// Collect the index rows of `scripthash`. If bitcoind is available, its confirmed history is
+    /// also computed (so the rows of other scripthashes sharing its prefix are found). Mempool
+    /// transactions are not included.
+    ///
+    /// `metrics` should not be used by other components (the status sync registers its own).
+    pub(crate) fn collect(
+        index: &Index,
+        daemon: Result<&dyn Node>,
+        scripthash: ScriptHash,
+        metrics: &Metrics,
+    ) -> Result<Self> {
+        let chain = index.chain();
+        let key = scripthash.to_string();
+        let funding_rows: Vec<HashPrefixRow> = index.funding_rows(scripthash).collect();
+        let daemon = match daemon {
+            Ok(daemon) => daemon,
+            Err(err) => {
+                let funding = funding_rows
+                    .into_iter()
+                    .map(|row| Row::new(key.clone(), row, &chain, None))
+                    .collect();
+                return Ok(Self {
+                    scripthash: key,
+                    tip: tip_json(&chain),
+                    funding,
+                    spending: vec![],
+                    txid: vec![],
+                    verified: None,
+                    daemon_error: Some(format!("{:#}", err)),
+                });
+            }
+        };
+
+        let mempool = RwLock::new(Mempool::new(metrics, ScriptTypes::all())); // kept empty
+        let cache = Cache::new(metrics);
+        let mut status = ScriptHashStatus::new(scripthash);
+        status.sync(index, chain.clone(), &mempool, daemon, &cache, true)?;
+        let (funding_heights, spending_heights) = status.confirmed_heights(&chain);
+
+        let funding = funding_rows
+            .into_iter()
+            .map(|row| {
+                let verified = funding_heights.contains(&row.height());
+                Row::new(key.clone(), row, &chain, Some(verified))
+            })
+            .collect();
+        let outpoints: BTreeSet<OutPoint> = status.funded_outpoints(&chain).into_iter().collect();
+        let spending = outpoints
+            .into_iter()
+            .flat_map(|outpoint| {
+                index
+                    .spending_rows(outpoint)
+                    .map(move |row| (outpoint, row))
+            })
+            .map(|(outpoint, row)| {
+                let verified = spending_heights.contains(&row.height());
+                Row::new(outpoint.to_string(), row, &chain, Some(verified))
+            })
+            .collect();
+        let history = status.get_history();
+        let txid = history
+            .iter()
+            .flat_map(|entry| index.txid_rows(entry.txid()).map(move |row| (entry, row)))
+            .map(|(entry, row)| {
+                let verified = entry.height() == Some(row.height());
+                Row::new(entry.txid().to_string(), row, &chain, Some(verified))
+            })
+            .collect();
+        let verified = Verified {
+            history: history.iter().map(|e| e.to_value_with_pos()).collect(),
+            balance: status.get_balance(&chain),
+            status: status.statushash().map(|hash| hash.to_string()),
+        };
+        Ok(Self {
+            scripthash: key,
+            tip: tip_json(&chain),
+            funding,
+            spending,
+            txid,
+            verified: Some(verified),
+            daemon_error: None,
+        })
+    }
+
+    pub(crate) fn write_text(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "scripthash: {}", self.scripthash)?;
+        writeln!(
+            out,
+            "indexed tip: {} at height {}",
+            self.tip["blockhash"].as_str().unwrap_or_default(),
+            self.tip["height"]
+        )?;
+        let sections = [
+            ("funding", &self.funding),
+            ("spending", &self.spending),
+            ("txid", &self.txid),
+        ];
+        for (name, rows) in sections {
+            writeln!(out, "{} rows: {}", name, rows.len())?;
+            for row in rows {
+                row.write_text(out)?;
+            }
+        }
+        let verified = match &self.verified {
+            Some(verified) => verified,
+            None => {
+                let err = self.daemon_error.as_deref().unwrap_or_default();
+                return writeln!(out, "history not verified (bitcoind unavailable: {})", err);
+            }
+        };
+        writeln!(out, "history: {} transactions", verified.history.len())?;
+        for entry in &verified.history {
+            writeln!(
+                out,
+                "  {} height={} pos={}",
+                entry["tx_hash"].as_str().unwrap_or_default(),
+                entry["height"],
+                entry["tx_pos"]
+            )?;
+        }
+        let balance = &verified.balance;
+        writeln!(
+            out,
+            "balance: confirmed={} immature={} (sat)",
+            balance.confirmed().to_sat(),
+            balance.immature().to_sat()
+        )?;
+        writeln!(
+            out,
+            "status: {}",
+            verified.status.as_deref().unwrap_or("null")
+        )
+    }
+}
+
+fn tip_json(chain: &Chain) -> Value {
+    json!({"height": chain.height(), "blockhash": chain.tip()})
+}
+
+/// The indexed headers of a DB, resolved by height without loading the index
+/// (see `electrs db tip` and `electrs db headers`)
+pub(crate) struct StoredHeaders<'a> {
+    store: &'a DBStore,
+    genesis: BlockHeader,
+    tip: Option<BlockHash>,
+    walked: Option<Vec<BlockHeader>>, // ordered by height, for DBs without `HEIGHTS_CF` rows
+    warnings: Vec<String>,
+}
+
+impl<'a> StoredHeaders<'a> {
+    /// Fails if the stored tip or header rows are invalid (e.g. a corrupted DB)
+    pub(crate) fn new(store: &'a DBStore, genesis: BlockHeader) -> Result<Self> {
+        let tip: Option<BlockHash> = store
+            .get_tip()
+            .map(|row| deserialize(&row).context("invalid tip"))
+            .transpose()?;
+        let mut warnings = vec![];
+        let walked = if store.has_height_rows() {
+            None
+        } else {
+            // walk the indexed headers from the tip down to genesis (like `Chain::load`)
+            let mut headers: HashMap<BlockHash, BlockHeader> = HashMap::new();
+            for row in store.iter_headers() {
+                let (row, indexed) = row?;
+                if indexed {
+                    let header = HeaderRow::from_db_row(row).header;
+                    headers.insert(header.block_hash(), header);
+                }
+            }
+            let mut walked = vec![];
+            let mut blockhash = tip.unwrap_or_else(|| genesis.block_hash());
+            while blockhash != genesis.block_hash() {
+                match headers.get(&blockhash) {
+                    Some(header) => {
+                        walked.push(*header);
+                        blockhash = header.prev_blockhash;
+                    }
+                    None => {
+                        warnings.push(format!(
+                            "missing header {} ({} headers below the tip): heights are unknown",
+                            blockhash,
+                            walked.len()
+                        ));
+                        walked.clear();
+                        break;
+                    }
+                }
+            }
+            walked.push(genesis);
+            walked.reverse();
+            Some(walked)
+        };
+        Ok(Self {
+            store,
+            genesis,
+            tip,
+            walked,
+            warnings,
+        })
+    }
+
+    fn header_at(&self, height: usize) -> Option<BlockHeader> {
+        if height == 0 {
+            return Some(self.genesis); // not indexed
+        }
+        match &self.walked {
+            Some(walked) => walked.get(height).copied(),
+            None => self.store.header_at(height),
+        }
+    }
+
+    fn tip_height(&self) -> Option<usize> {
+        let tip = self.tip?;
+        match &self.walked {
+            Some(walked) => walked
+                .last()
+                .filter(|header| header.block_hash() == tip)
+                .map(|_| walked.len() - 1),
+            None => self.store.height_of(&tip),
+        }
+    }
+}
+
+/// A decoded block header
+#[derive(Serialize)]
+struct HeaderInfo {
+    height: usize,
+    blockhash: BlockHash,
+    version: i32,
+    prev_blockhash: BlockHash,
+    merkle_root: TxMerkleNode,
+    time: u32,
+    bits: String, // compact target (hex)
+    nonce: u32,
+}
+
+impl HeaderInfo {
+    fn new(height: usize, header: &BlockHeader) -> Self {
+        Self {
+            height,
+            blockhash: header.block_hash(),
+            version: header.version.to_consensus(),
+            prev_blockhash: header.prev_blockhash,
+            merkle_root: header.merkle_root,
+            time: header.time,
+            bits: format!("{:08x}", header.bits.to_consensus()),
+            nonce: header.nonce,
+        }
+    }
+
+    fn write_text(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(
+            out,
+            "  height={} block={}\n    version={:#010x} prev={} merkle_root={}\n    \
+             time={} bits={} nonce={}",
+            self.height,
+            self.blockhash,
+            self.version,
+            self.prev_blockhash,
+            self.merkle_root,
+            self.time,
+            self.bits,
+            self.nonce
+        )
+    }
+}
+
+/// The indexed tip and a range of indexed headers, with the inconsistencies found in them
+#[derive(Serialize)]
+pub(crate) struct HeadersDump {
+    tip: Option<BlockHash>,
+    height: Option<usize>, // `None` if the tip's height can't be resolved
+    height_rows: bool,     // whether the headers are read by height (or walked from the tip)
+    headers: Vec<HeaderInfo>,
+    warnings: Vec<String>,
+}
+
+impl HeadersDump {
+    /// Collect the indexed tip's header (see `electrs db tip`).
+    pub(crate) fn tip(headers: &StoredHeaders) -> Self {
+        let heights = match headers.tip_height() {
+            Some(height) => height..height + 1,
+            None => 0..0,
+        };
+        Self::collect(headers, heights)
+    }
+
+    /// Collect the indexed headers at `heights`, up to the tip (see `electrs db headers`).
+    pub(crate) fn range(headers: &StoredHeaders, from: Option<usize>, count: usize) -> Self {
+        let end = headers.tip_height().map_or(0, |height| height + 1);
+        let start = from.unwrap_or_else(|| end.saturating_sub(count));
+        let end = end.min(start.saturating_add(count));
+        Self::collect(headers, start..end.max(start))
+    }
+
+    fn collect(headers: &StoredHeaders, heights: Range<usize>) -> Self {
+        let mut warnings = headers.warnings.clone();
+        let height = headers.tip_height();
+        match (headers.tip, height) {
+            (None, _) => warnings.push("no indexed tip (the DB is empty)".to_owned()),
+            (Some(tip), None) => warnings.push(format!("tip {} has no stored height", tip)),
+            (Some(tip), Some(height)) => {
+                match headers.header_at(height).map(|header| header.block_hash()) {
+                    Some(hash) if hash == tip => (),
+                    Some(hash) => warnings.push(format!(
+                        "tip {} doesn't match the header at height {} ({})",
+                        tip, height, hash
+                    )),
+                    None => warnings.push(format!("missing tip header at height {}", height)),
+                }
+            }
+        }
+        let mut prev = heights
+            .start
+            .checked_sub(1)
+            .and_then(|height| headers.header_at(height));
+        let mut infos = vec![];
+        for height in heights {
+            let header = match headers.header_at(height) {
+                Some(header) => header,
+                None => {
+                    warnings.push(format!("gap: missing header at height {}", height));
+                    prev = None;
+                    continue;
+                }
+            };
+            if let Some(prev) = prev.filter(|prev| prev.block_hash() != header.prev_blockhash) {
+                warnings.push(format!(
+                    "prev-hash mismatch at height {}: {} != {} (the header at height {})",
+                    height,
+                    header.prev_blockhash,
+                    prev.block_hash(),
+                    height - 1
+                ));
+            }
+            infos.push(HeaderInfo::new(height, &header));
+            prev = Some(header);
+        }
+        Self {
+            tip: headers.tip,
+            height,
+            height_rows: headers.walked.is_none(),
+            headers: infos,
+            warnings,
+        }
+    }
+
+    pub(crate) fn write_text(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        match (self.tip, self.height) {
+            (Some(tip), Some(height)) => {
+                writeln!(out, "indexed tip: {} at height {}", tip, height)?
+            }
+            (Some(tip), None) => writeln!(out, "indexed tip: {} at unknown height", tip)?,
+            (None, _) => writeln!(out, "indexed tip: none")?,
+        }
+        writeln!(out, "headers: {}", self.headers.len())?;
+        for header in &self.headers {
+            header.write_text(out)?;
+        }
+        for warning in &self.warnings {
+            writeln!(out, "warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
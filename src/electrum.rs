@@ -1,42 +1,252 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bitcoin::{
-    consensus::{deserialize, encode::serialize_hex},
-    hashes::hex::FromHex,
+    consensus::{
+        encode::{deserialize_hex, serialize_hex, FromHexError},
+        DecodeError,
+    },
     hex::DisplayHex,
-    BlockHash, Transaction, Txid,
+    BlockHash, OutPoint, Transaction, Txid,
 };
 use crossbeam_channel::Receiver;
+use parking_lot::Mutex;
 use rayon::prelude::*;
 use serde_derive::Deserialize;
 use serde_json::{self, json, Value};
 
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::iter::FromIterator;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::{
+    allowlist::{Allowlist, NotServed},
+    banner,
+    broadcasts::Broadcasts,
     cache::Cache,
-    config::{Config, ELECTRS_VERSION},
-    daemon::{self, extract_bitcoind_error, Daemon},
+    chain::{Chain, ChainView},
+    coalesce::{Coalescer, Join},
+    config::{Config, IpNet, SensitivePassword, ELECTRS_GIT_COMMIT, ELECTRS_VERSION},
+    daemon::{self, extract_bitcoind_error, Daemon, Node},
+    events::EventSink,
+    features::{Announce, Features},
+    fees::FeeCache,
+    limits::{self, Limits},
+    listener::ListenerPolicy,
+    mempool::MempoolSummary,
     merkle::Proof,
     metrics::{self, Histogram, Metrics},
+    outgoing::{write_element, Items, Outgoing},
+    outpoints::{OutpointSubscriptions, Spender},
+    replay::ReplayLog,
+    shadow_db::ShadowDb,
+    shrink::Shrink,
     signals::Signal,
-    status::ScriptHashStatus,
-    tracker::Tracker,
+    status::{Balance, HistoryEntry, ScriptHashStatus},
+    subscriptions::{Affected, Subscriptions},
+    thread::{Deferred, Supervisor},
+    tracker::{Generations, State, Tracker},
     types::ScriptHash,
 };
 
-const PROTOCOL_VERSION: &str = "1.4";
+/// Supported Electrum protocol versions (negotiated per connection using `server.version`)
+const PROTOCOL_MIN: &str = "1.4";
+const PROTOCOL_MAX: &str = "1.4.2";
 const UNKNOWN_FEE: isize = -1; // (allowed by Electrum protocol)
+/// Raw blocks are returned in chunks (of this size, in bytes), so a single response doesn't
+/// exceed `max_send_queue_mb`
+const BLOCK_CHUNK_SIZE: usize = 1 << 20;
+/// Unauthenticated connections are closed after this many failures (see `server_password`)
+pub(crate) const MAX_AUTH_FAILURES: usize = 3;
+/// Requests using longer method names are invalid (the longest supported one has 40 bytes)
+const MAX_METHOD_LEN: usize = 64;
+/// Requests having more params are invalid (lists are passed as a single param)
+const MAX_PARAMS: usize = 16;
+/// Scripthashes subscribed by a single connection if `max_subscriptions` is not set (so a
+/// pathological client can't grow the server's memory usage without bounds)
+const MAX_SUBSCRIPTIONS: usize = 1_000_000;
+/// Txids looked up by a single batched `blockchain.transaction.get_height` request (each lookup
+/// may fetch a block's txids from bitcoind)
+const MAX_HEIGHT_TXIDS: usize = 100;
 
 const UNSUBSCRIBED_QUERY_MESSAGE: &str = "your wallet uses less efficient method of querying electrs, consider contacting the developer of your wallet. Reason:";
 
+/// Subscription whose notifications supersede the previous ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Topic {
+    Headers,
+    ScriptHash(ScriptHash),
+    Transaction(Txid), // a tracked broadcast (notified once)
+    Outpoint(OutPoint),
+    MempoolSummary, // incremental (so its notifications are never superseded)
+}
+
+/// The tip sent to a client subscribed to headers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SubscribedTip {
+    hash: BlockHash,
+    syncing: bool, // sent during initial sync (so it was flagged with the sync progress)
+}
+
 /// Per-client Electrum protocol state
-#[derive(Default)]
 pub struct Client {
-    tip: Option<BlockHash>,
+    id: usize,
+    addr: Option<SocketAddr>,
+    tip: Option<SubscribedTip>,
     scripthashes: HashMap<ScriptHash, ScriptHashStatus>,
+    subscriptions: Subscriptions,          // shared by all clients
+    violations: usize, // invalid requests, since the last `take_violations()` call
+    unsubscribed: Vec<ScriptHash>, // since the last `take_unsubscribed()` call
+    protocol: Option<Version>, // negotiated by `server.version`
+    persistent: bool,  // subscriptions require a persistent connection (e.g. not HTTP)
+    broadcasts: Mutex<Option<Broadcasts>>, // `None` unless `track_broadcasts` was called
+    outpoints: OutpointSubscriptions,
+    mempool_summary: Option<MempoolSummary>, // the last one sent by `mempool.subscribe_summary`
+    authenticated: bool, // by `server.authenticate` (used only if `server_password` is set)
+    auth_failures: usize, // wrong passwords and calls made before authenticating
+    first_line: bool,    // the next line is the connection's first one (may be the password)
+    policy: Arc<ListenerPolicy>, // of the listener which accepted the connection
+}
+
+impl Client {
+    fn new(
+        id: usize,
+        addr: Option<SocketAddr>,
+        subscriptions: Subscriptions,
+        policy: Arc<ListenerPolicy>,
+    ) -> Self {
+        Self {
+            id,
+            addr,
+            tip: None,
+            scripthashes: HashMap::new(),
+            subscriptions,
+            violations: 0,
+            unsubscribed: vec![],
+            protocol: None,
+            persistent: true,
+            broadcasts: Mutex::new(None),
+            outpoints: OutpointSubscriptions::default(),
+            mempool_summary: None,
+            authenticated: false,
+            auth_failures: 0,
+            first_line: true,
+            policy,
+        }
+    }
+
+    pub(crate) fn policy(&self) -> &Arc<ListenerPolicy> {
+        &self.policy
+    }
+
+    pub(crate) fn take_violations(&mut self) -> usize {
+        std::mem::take(&mut self.violations)
+    }
+
+    /// Scripthashes unsubscribed by the client, whose sent notifications should be forgotten
+    pub(crate) fn take_unsubscribed(&mut self) -> Vec<ScriptHash> {
+        std::mem::take(&mut self.unsubscribed)
+    }
+
+    /// Subscribe to new headers (after `blockchain.headers.subscribe` was answered by
+    /// [`Rpc::handle_cheap_request`]).
+    pub(crate) fn subscribe_headers(&mut self, tip: SubscribedTip) {
+        self.tip = Some(tip);
+    }
+
+    /// Negotiate the protocol version used by the following requests (see `server.version`).
+    fn negotiate(&mut self, request: &VersionRequest) -> Result<&Version> {
+        let protocol = match request {
+            VersionRequest::Single(exact) => negotiate_version(exact, exact),
+            VersionRequest::MinMax(min, max) => negotiate_version(min, max),
+        }?;
+        Ok(self.protocol.insert(protocol))
+    }
+
+    /// The negotiated protocol version (`PROTOCOL_MIN` until `server.version` is called).
+    fn protocol(&self) -> Version {
+        self.protocol
+            .clone()
+            .unwrap_or_else(|| parse_version(PROTOCOL_MIN).expect("invalid PROTOCOL_MIN"))
+    }
+
+    /// Methods introduced by a newer protocol version than the negotiated one are not found.
+    /// Requests sent before `server.version` may use any method (as before the negotiation was
+    /// supported), so existing clients which don't negotiate keep working.
+    fn supports(&self, method: &Method) -> bool {
+        match (&self.protocol, method.since) {
+            (Some(protocol), Some(since)) => {
+                *protocol >= parse_version(since).expect("invalid version")
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns `true` if the client may make the call (counting a failure otherwise), since only
+    /// `server.authenticate` is allowed before authenticating.
+    fn authorize(&mut self, password: Option<&SensitivePassword>, params: &Params) -> bool {
+        if password.is_none() || self.authenticated || matches!(params, Params::Authenticate(_)) {
+            return true;
+        }
+        self.auth_failures += 1;
+        false
+    }
+
+    /// Authenticate the following calls if `password` is correct (always, if none is required).
+    fn authenticate(&mut self, password: Option<&SensitivePassword>, given: &str) -> Result<()> {
+        if password.is_none_or(|password| password.matches(given)) {
+            self.authenticated = true;
+            return Ok(());
+        }
+        self.auth_failures += 1;
+        bail!("invalid password");
+    }
+
+    /// Returns `true` if the connection should be closed due to too many authentication failures.
+    pub(crate) fn auth_failed(&self) -> bool {
+        !self.authenticated && self.auth_failures >= MAX_AUTH_FAILURES
+    }
+
+    /// Returns `true` if the client may call `method` (i.e. it's not disabled by its listener,
+    /// and its access requirements are met).
+    fn allows(&self, method: &Method, password_required: bool) -> bool {
+        if self.policy.disables(method.name) {
+            return false;
+        }
+        match method.access {
+            Access::Any => true,
+            Access::Local => self.is_local(),
+            Access::BlockGet => self.is_in(&self.policy.block_get_allow),
+            Access::Password => password_required,
+        }
+    }
+
+    // Administrative methods are allowed only for local clients
+    fn is_local(&self) -> bool {
+        self.addr.is_some_and(|addr| addr.ip().is_loopback())
+    }
+
+    // Transactions broadcast by this connection are tracked only if it opted in
+    fn track_broadcast(&self, txid: Txid) {
+        if let Some(broadcasts) = self.broadcasts.lock().as_mut() {
+            broadcasts.add(txid);
+        }
+    }
+
+    // Access-controlled methods are allowed only for clients in the configured networks
+    fn is_in(&self, nets: &[IpNet]) -> bool {
+        self.addr
+            .is_some_and(|addr| nets.iter().any(|net| net.contains(addr.ip())))
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.subscriptions.remove(self.id, self.scripthashes.keys());
+    }
 }
 
 #[derive(Deserialize)]
@@ -48,6 +258,25 @@ struct Request {
     params: Value,
 }
 
+impl Request {
+    /// Reject requests which can't be valid (e.g. random noise), before their method is looked up
+    /// (or logged) and their params are parsed.
+    fn check(&self) -> Result<(), &'static str> {
+        if self.method.len() > MAX_METHOD_LEN {
+            return Err("method name is too long");
+        }
+        let params = match &self.params {
+            Value::Array(params) => params.len(),
+            Value::Object(params) => params.len(),
+            _ => 0,
+        };
+        if params > MAX_PARAMS {
+            return Err("too many params");
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum Requests {
@@ -55,6 +284,15 @@ enum Requests {
     Batch(Vec<Request>),
 }
 
+impl Requests {
+    fn check(&self) -> Result<(), &'static str> {
+        match self {
+            Requests::Single(request) => request.check(),
+            Requests::Batch(batch) => batch.iter().try_for_each(Request::check),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq)]
 #[serde(untagged)]
 enum VersionRequest {
@@ -78,6 +316,132 @@ impl From<&TxGetArgs> for (Txid, bool) {
     }
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TxHeightArgs {
+    Txid((Txid,)),
+    Txids((Vec<Txid>,)), // batched
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HeaderArgs {
+    Height((usize,)),
+    HeightCheckpoint((usize, usize)), // with `cp_height`
+}
+
+impl From<&HeaderArgs> for (usize, usize) {
+    fn from(args: &HeaderArgs) -> Self {
+        match args {
+            HeaderArgs::Height((height,)) => (*height, 0),
+            HeaderArgs::HeightCheckpoint((height, cp_height)) => (*height, *cp_height),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HeadersArgs {
+    Range((usize, usize)),
+    RangeCheckpoint((usize, usize, usize)), // with `cp_height`
+}
+
+impl From<&HeadersArgs> for (usize, usize, usize) {
+    fn from(args: &HeadersArgs) -> Self {
+        match args {
+            HeadersArgs::Range((start_height, count)) => (*start_height, *count, 0),
+            HeadersArgs::RangeCheckpoint((start_height, count, cp_height)) => {
+                (*start_height, *count, *cp_height)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(untagged)]
+enum BlockId {
+    Height(usize),
+    Hash(BlockHash),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockGetArgs {
+    Block((BlockId,)),
+    BlockRaw((BlockId, bool)),
+    BlockRawChunk((BlockId, bool, usize)),
+}
+
+impl From<&BlockGetArgs> for (BlockId, bool, usize) {
+    fn from(args: &BlockGetArgs) -> Self {
+        match args {
+            BlockGetArgs::Block((block,)) => (*block, true, 0),
+            BlockGetArgs::BlockRaw((block, raw)) => (*block, *raw, 0),
+            BlockGetArgs::BlockRawChunk((block, raw, chunk)) => (*block, *raw, *chunk),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HistoryArgs {
+    ScriptHash((ScriptHash,)),
+    ScriptHashTxPos(ScriptHash, bool), // `tx_pos` extension
+    ScriptHashTxPosStatusDebug(ScriptHash, bool, bool), // `status_debug` extension
+}
+
+impl HistoryArgs {
+    fn status_debug(&self) -> bool {
+        matches!(self, HistoryArgs::ScriptHashTxPosStatusDebug(_, _, true))
+    }
+}
+
+impl From<&HistoryArgs> for (ScriptHash, bool) {
+    fn from(args: &HistoryArgs) -> Self {
+        match args {
+            HistoryArgs::ScriptHash((scripthash,)) => (*scripthash, false),
+            HistoryArgs::ScriptHashTxPos(scripthash, tx_pos)
+            | HistoryArgs::ScriptHashTxPosStatusDebug(scripthash, tx_pos, _) => {
+                (*scripthash, *tx_pos)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SubscribeArgs {
+    ScriptHash((ScriptHash,)),
+    ScriptHashStatusDebug(ScriptHash, bool), // `status_debug` extension
+}
+
+impl From<&SubscribeArgs> for (ScriptHash, bool) {
+    fn from(args: &SubscribeArgs) -> Self {
+        match args {
+            SubscribeArgs::ScriptHash((scripthash,)) => (*scripthash, false),
+            SubscribeArgs::ScriptHashStatusDebug(scripthash, status_debug) => {
+                (*scripthash, *status_debug)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MerkleArgs {
+    Txid((Txid, usize)),
+    TxidPos((Txid, usize, usize)), // `tx_pos` extension
+}
+
+impl From<&MerkleArgs> for (Txid, usize, Option<usize>) {
+    fn from(args: &MerkleArgs) -> Self {
+        match args {
+            MerkleArgs::Txid((txid, height)) => (*txid, *height, None),
+            MerkleArgs::TxidPos((txid, height, tx_pos)) => (*txid, *height, Some(*tx_pos)),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum BroadcastArgs {
@@ -115,9 +479,29 @@ enum RpcError {
     BadRequest(anyhow::Error),
     DaemonError(daemon::RpcError),
     UnavailableIndex,
+    ServerFull,
+    TemporaryError,         // bitcoind is temporarily unavailable
+    AuthenticationRequired, // see `server_password`
+    NotServed,              // see `scripthash_allowlist_file`
 }
 
 impl RpcError {
+    fn from_error(err: anyhow::Error) -> Self {
+        if daemon::is_transient(&err) {
+            return RpcError::TemporaryError;
+        }
+        if err.downcast_ref::<NotServed>().is_some() {
+            return RpcError::NotServed;
+        }
+        match err
+            .downcast_ref::<bitcoincore_rpc::Error>()
+            .and_then(extract_bitcoind_error)
+        {
+            Some(e) => RpcError::DaemonError(e.clone()),
+            None => RpcError::BadRequest(err),
+        }
+    }
+
     fn to_value(&self) -> Value {
         match self {
             RpcError::Standard(err) => match err {
@@ -133,11 +517,67 @@ impl RpcError {
                 }
             },
             RpcError::BadRequest(err) => json!({"code": 1, "message": err.to_string()}),
-            RpcError::DaemonError(err) => json!({"code": 2, "message": err.message}),
+            RpcError::DaemonError(err) => {
+                let code = Rejection::parse(err).map_or(2, Rejection::code);
+                json!({"code": code, "message": err.message})
+            }
             RpcError::UnavailableIndex => {
                 // Internal JSON-RPC error (https://www.jsonrpc.org/specification#error_object)
                 json!({"code": -32603, "message": "unavailable index"})
             }
+            RpcError::ServerFull => {
+                // Implementation-defined server error (https://www.jsonrpc.org/specification#error_object)
+                json!({"code": -32000, "message": "server full, try another server"})
+            }
+            RpcError::TemporaryError => {
+                // the request failed (instead of returning a partial result), so it can be retried
+                json!({"code": -32603, "message": "temporary server error, retry"})
+            }
+            RpcError::AuthenticationRequired => {
+                // Implementation-defined server error (https://www.jsonrpc.org/specification#error_object)
+                json!({"code": -32001, "message": "authentication required"})
+            }
+            RpcError::NotServed => {
+                // Implementation-defined server error (https://www.jsonrpc.org/specification#error_object)
+                json!({"code": -32002, "message": "scripthash not served"})
+            }
+        }
+    }
+}
+
+/// Common reasons for bitcoind to reject a broadcast transaction, returned using distinct error
+/// codes (instead of the generic daemon error code), so wallets can handle them programmatically.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rejection {
+    TxSize,          // larger than the standard transaction size limit
+    MinRelayFee,     // fee rate is too low (for relay, or for the current mempool)
+    MempoolConflict, // spends an output already spent by a mempool transaction
+    MempoolChain,    // too many unconfirmed ancestors or descendants
+}
+
+impl Rejection {
+    fn parse(err: &daemon::RpcError) -> Option<Self> {
+        const RPC_VERIFY_REJECTED: i32 = -26;
+        if err.code != RPC_VERIFY_REJECTED {
+            return None;
+        }
+        // bitcoind's reject reason may be followed by details (e.g. "min relay fee not met, 100 < 141")
+        let reason = err.message.split(',').next()?.trim();
+        match reason {
+            "tx-size" => Some(Rejection::TxSize),
+            "min relay fee not met" | "mempool min fee not met" => Some(Rejection::MinRelayFee),
+            "txn-mempool-conflict" => Some(Rejection::MempoolConflict),
+            "too-long-mempool-chain" => Some(Rejection::MempoolChain),
+            _ => None,
+        }
+    }
+
+    fn code(self) -> i32 {
+        match self {
+            Rejection::TxSize => 10,
+            Rejection::MinRelayFee => 11,
+            Rejection::MempoolConflict => 12,
+            Rejection::MempoolChain => 13,
         }
     }
 }
@@ -149,13 +589,34 @@ pub struct Rpc {
     rpc_duration: Histogram,
     daemon: Daemon,
     signal: Signal,
-    banner: String,
-    port: u16,
+    limits: Limits,
+    global: Arc<ListenerPolicy>, // for clients not accepted by a listener (e.g. via HTTP)
+    listeners: Vec<Arc<ListenerPolicy>>,
+    connections: AtomicUsize,
+    announce: Announce,
+    features: Mutex<Value>, // cached `server.features` response
+    max_concurrent_requests: usize,
+    max_subscriptions: usize, // per connection
+    subscriptions: Subscriptions,
+    allowlist: Allowlist,
+    shadow_db: Option<ShadowDb>, // compared with the served answers (see `shadow_db`)
+    events: Option<EventSink>,   // see `event_socket`
+    replay_log: Option<ReplayLog>, // see `replay_log`
+    password: Option<SensitivePassword>, // required from clients, if set
+    fees: FeeCache,
+    coalescer: Coalescer<(Generations, String), Reply>, // not coalesced across chain/mempool updates
 }
 
 impl Rpc {
     /// Perform initial index sync (may take a while on first run).
-    pub fn new(config: &Config, metrics: Metrics) -> Result<Self> {
+    pub(crate) fn new(
+        config: &Config,
+        metrics: Metrics,
+        limits: Limits,
+        listeners: Vec<Arc<ListenerPolicy>>,
+        signal: Signal,
+        supervisor: &Supervisor,
+    ) -> Result<Self> {
         let rpc_duration = metrics.histogram_vec(
             "rpc_duration",
             "RPC duration (in seconds)",
@@ -164,17 +625,61 @@ impl Rpc {
         );
 
         let tracker = Tracker::new(config, metrics)?;
-        let signal = Signal::new();
-        let daemon = Daemon::connect(config, signal.exit_flag(), tracker.metrics())?;
-        let cache = Cache::new(tracker.metrics());
+        let daemon = Daemon::connect(config, signal.exit_flag(), tracker.metrics(), supervisor)?;
+        tracker.check_chain(&daemon)?;
+        let cache = Cache::with_budgets(tracker.metrics(), config.cache_budgets);
+        let coalescer = Coalescer::new(tracker.metrics());
+        let fees = FeeCache::new(tracker.metrics(), config.fee_cache_max_age);
+        let allowlist = Allowlist::new(
+            config.scripthash_allowlist_file.clone(),
+            config.address_decoder(),
+            tracker.metrics(),
+        )?;
+        let shadow_db = config
+            .shadow_db
+            .as_deref()
+            .map(|path| ShadowDb::open(config, path, tracker.metrics()))
+            .transpose()?;
+        let events = config
+            .event_socket
+            .as_deref()
+            .map(|path| {
+                EventSink::new(
+                    path,
+                    config.event_watch_file.clone(),
+                    config.address_decoder(),
+                    config.event_queue_size,
+                    tracker.metrics(),
+                )
+            })
+            .transpose()?;
+        let replay_log = config
+            .replay_log
+            .as_deref()
+            .map(|path| ReplayLog::open(path, config.replay_log_max_bytes, config.magic))
+            .transpose()?;
         Ok(Self {
             tracker,
             cache,
             rpc_duration,
             daemon,
             signal,
-            banner: config.server_banner.clone(),
-            port: config.electrum_rpc_addr.port(),
+            limits,
+            global: Arc::new(ListenerPolicy::global(config)?),
+            listeners,
+            connections: AtomicUsize::new(0),
+            announce: Announce::new(config),
+            features: Mutex::new(Value::Null),
+            max_concurrent_requests: config.max_concurrent_requests,
+            max_subscriptions: config.max_subscriptions.unwrap_or(MAX_SUBSCRIPTIONS),
+            subscriptions: Subscriptions::default(),
+            allowlist,
+            shadow_db,
+            events,
+            replay_log,
+            password: config.server_password.clone(),
+            fees,
+            coalescer,
         })
     }
 
@@ -186,89 +691,453 @@ impl Rpc {
         self.daemon.new_block_notification()
     }
 
-    pub fn sync(&mut self) -> Result<bool> {
-        self.tracker.sync(&self.daemon, self.signal.exit_flag())
+    pub fn sync(&self) -> Result<bool> {
+        self.daemon.reconnect_p2p()?; // e.g. after bitcoind was restarted
+        let result = self
+            .tracker
+            .sync(&self.daemon, self.signal.exit_flag(), &self.cache);
+        self.prefetch();
+        if let Some(events) = &self.events {
+            events.update(&self.tracker);
+        }
+        if let Some(replay_log) = &self.replay_log {
+            replay_log.record(&self.tracker);
+        }
+        result
     }
 
-    pub fn update_client(&self, client: &mut Client) -> Result<Vec<String>> {
+    /// Cache the recently published blocks' data (see `prefetch_blocks`), before the affected
+    /// statuses are updated and notified (so the clients' following requests use the cache too).
+    fn prefetch(&self) {
+        let blocks = self.tracker.take_recent_blocks();
+        if blocks.is_empty() {
+            return;
+        }
         let chain = self.tracker.chain();
-        let mut notifications = client
-            .scripthashes
+        let start = Instant::now();
+        let count = blocks
+            .iter()
+            .filter(|(blockhash, block)| self.cache.prefetch_block(&chain, *blockhash, block))
+            .count();
+        debug!("prefetched {} blocks in {:?}", count, start.elapsed());
+    }
+
+    /// See `Index::maintain()`
+    pub(crate) fn maintain(&self) -> bool {
+        self.tracker.maintain()
+    }
+
+    /// See `Tracker::is_migrating()`
+    pub(crate) fn is_migrating(&self) -> bool {
+        self.tracker.is_migrating()
+    }
+
+    pub(crate) fn has_shadow_db(&self) -> bool {
+        self.shadow_db.is_some()
+    }
+
+    /// See `ShadowDb::run()`
+    pub(crate) fn compare_shadow_db(&self, stop: &Receiver<()>) -> Result<()> {
+        match &self.shadow_db {
+            Some(shadow_db) => shadow_db.run(&self.tracker, &self.daemon, &self.cache, stop),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn state(&self) -> State {
+        self.tracker.state()
+    }
+
+    /// Create a client whose subscriptions are tracked by the shared registry, and whose
+    /// requests are handled using the settings of the listener which accepted it.
+    pub(crate) fn new_client(
+        &self,
+        id: usize,
+        addr: Option<SocketAddr>,
+        policy: Arc<ListenerPolicy>,
+    ) -> Client {
+        Client::new(id, addr, self.subscriptions.clone(), policy)
+    }
+
+    /// Handle a single request (or batch) received via a stateless transport (e.g. HTTP POST),
+    /// returning its response and the number of protocol violations. Subscriptions fail, since
+    /// their notifications can't be sent.
+    #[cfg(feature = "http")]
+    pub(crate) fn handle_stateless_request(&self, addr: SocketAddr, body: &str) -> (String, usize) {
+        let mut client = Client::new(
+            usize::MAX,
+            Some(addr),
+            self.subscriptions.clone(),
+            Arc::clone(&self.global),
+        );
+        client.persistent = false;
+        client.first_line = false; // the password can be sent only using `server.authenticate`
+        let mut responses = vec![];
+        self.handle_requests(&mut client, &[body.to_owned()], |response| {
+            responses.push(response);
+            Ok(())
+        })
+        .expect("responses are collected");
+        // e.g. if the body has no requests
+        let response = responses
+            .pop()
+            .map(Outgoing::into_string)
+            .unwrap_or_else(|| error_msg_no_id(StandardError::InvalidRequest).to_string());
+        (response, client.take_violations())
+    }
+
+    /// Find the subscriptions affected by new blocks and mempool changes since the previous call
+    /// (and the chain snapshot they refer to).
+    pub(crate) fn take_affected(&self) -> (Arc<Chain>, Affected) {
+        let (chain, confirmed, mempool) = self.tracker.take_touched();
+        let affected = self
+            .subscriptions
+            .affected(confirmed.as_ref(), mempool.as_ref());
+        (chain, affected)
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.chain_view().tip().0
+    }
+
+    /// The last indexed chain snapshot
+    pub(crate) fn chain_view(&self) -> ChainView {
+        ChainView::new(self.tracker.chain())
+    }
+
+    /// Update the number of connected peers (shown in the banner)
+    pub(crate) fn set_connections(&self, count: usize) {
+        self.connections.store(count, Ordering::Relaxed);
+    }
+
+    /// Drop cached responses and files' contents, so they are re-read (e.g. on SIGHUP).
+    pub(crate) fn reload(&self) {
+        self.global.reload();
+        self.listeners.iter().for_each(|listener| listener.reload());
+        self.allowlist.reload();
+        if let Some(events) = &self.events {
+            events.reload();
+        }
+        *self.features.lock() = Value::Null;
+    }
+
+    fn bans_list(&self, client: &Client) -> Result<Value> {
+        ensure!(
+            client.is_local(),
+            "admin methods are allowed only from localhost"
+        );
+        let bans: Vec<Value> = self
+            .limits
+            .bans(limits::unix_now())
+            .into_iter()
+            .map(|(ip, until)| json!({"ip": ip, "until": until}))
+            .collect();
+        Ok(json!(bans))
+    }
+
+    fn bans_clear(&self, client: &Client, ips: &Option<Vec<IpAddr>>) -> Result<Value> {
+        ensure!(
+            client.is_local(),
+            "admin methods are allowed only from localhost"
+        );
+        let ips = ips.as_deref().unwrap_or_default();
+        Ok(json!(self.limits.clear(ips)))
+    }
+
+    /// Authorize a refused deep reorg, returning its depth (`null` if none was refused).
+    fn reorg_allow(&self, client: &Client) -> Result<Value> {
+        ensure!(
+            client.is_local(),
+            "admin methods are allowed only from localhost"
+        );
+        Ok(json!(self.tracker.allow_deep_reorg()))
+    }
+
+    /// The index sync state, with the block bitcoind failed to provide (if any).
+    fn index_status(&self, client: &Client) -> Result<Value> {
+        ensure!(
+            client.is_local(),
+            "admin methods are allowed only from localhost"
+        );
+        let state = match self.tracker.state() {
+            State::Syncing => "syncing",
+            State::Synced => "synced",
+            State::Stalled => "stalled",
+        };
+        let missing_block = self.tracker.missing_block().map(|missing| {
+            json!({
+                "height": missing.height,
+                "blockhash": missing.blockhash,
+                "failures": missing.failures,
+                "prune_height": missing.prune_height,
+                "stalled": missing.is_stalled(),
+                "error": missing.to_string(),
+            })
+        });
+        Ok(json!({
+            "state": state,
+            "height": self.height(),
+            "missing_block": missing_block,
+            "migration": self.tracker.migration_progress(),
+        }))
+    }
+
+    /// Request the deferred initial full compaction (see `defer_initial_compaction`), returning
+    /// `false` if the DB is already compacted.
+    fn db_compact(&self, client: &Client) -> Result<Value> {
+        ensure!(
+            client.is_local(),
+            "admin methods are allowed only from localhost"
+        );
+        Ok(json!(self.tracker.request_compaction()))
+    }
+
+    /// The estimated number of keys and the SST files' size of each DB column family.
+    fn db_stats(&self, client: &Client) -> Result<Value> {
+        ensure!(
+            client.is_local(),
+            "admin methods are allowed only from localhost"
+        );
+        let stats: serde_json::Map<String, Value> = self
+            .tracker
+            .db_stats()?
+            .into_iter()
+            .map(|(cf, keys, size)| (cf.to_owned(), json!({"keys": keys, "size": size})))
+            .collect();
+        Ok(Value::Object(stats))
+    }
+
+    fn banner(&self, policy: &ListenerPolicy) -> Result<Value> {
+        let vars = banner::Vars {
+            version: ELECTRS_VERSION,
+            index_height: self.height(),
+            daemon_version: self.daemon.subversion(),
+            connections: self.connections.load(Ordering::Relaxed),
+            uptime: policy.banner.uptime(),
+            tip_age: Duration::from_secs(self.tracker.chain().tip_age(limits::unix_now())),
+        };
+        Ok(json!(policy.banner.get(&vars)))
+    }
+
+    /// Recompute the client's affected statuses (and the new tip, if subscribed to headers).
+    pub(crate) fn update_client(
+        &self,
+        client: &mut Client,
+        chain: &Arc<Chain>,
+        affected: &Affected,
+    ) -> Result<Vec<(Topic, String)>> {
+        // take out the affected statuses (the rest remain valid)
+        let mut statuses: Vec<(ScriptHash, ScriptHashStatus)> = match affected
+            .scripthashes(client.id)
+        {
+            Some(scripthashes) => {
+                let mut scripthashes: HashSet<ScriptHash> = scripthashes.iter().copied().collect();
+                // all the statuses are scanned only after a reorg
+                if affected.has_stale_blocks() {
+                    scripthashes.extend(
+                        client
+                            .scripthashes
+                            .iter()
+                            .filter(|(_, status)| affected.is_reorged(status))
+                            .map(|(scripthash, _)| *scripthash),
+                    );
+                }
+                scripthashes
+                    .iter()
+                    .filter_map(|scripthash| client.scripthashes.remove_entry(scripthash))
+                    .collect()
+            }
+            None => client.scripthashes.drain().collect(),
+        };
+        let subscriptions = &client.subscriptions;
+        let result = statuses
             .par_iter_mut()
-            .filter_map(|(scripthash, status)| -> Option<Result<Value>> {
-                match self
-                    .tracker
-                    .update_scripthash_status(status, &self.daemon, &self.cache)
-                {
-                    Ok(true) => Some(Ok(notification(
-                        "blockchain.scripthash.subscribe",
-                        &[json!(scripthash), json!(status.statushash())],
-                    ))),
+            .filter_map(|(scripthash, status)| -> Option<Result<(Topic, Value)>> {
+                match self.tracker.update_scripthash_status(
+                    status,
+                    Arc::clone(chain),
+                    &self.daemon,
+                    &self.cache,
+                    affected.is_confirmed_touched(scripthash) || affected.is_reorged(status),
+                ) {
+                    Ok(true) => {
+                        // new history may have new confirmed outpoints
+                        let outpoints = self.tracker.get_outpoints(status);
+                        subscriptions.watch(*scripthash, outpoints.into_iter());
+                        Some(Ok((
+                            Topic::ScriptHash(*scripthash),
+                            notification(
+                                "blockchain.scripthash.subscribe",
+                                &[json!(scripthash), json!(status.statushash())],
+                            ),
+                        )))
+                    }
                     Ok(false) => None, // statushash is the same
                     Err(e) => Some(Err(e)),
                 }
             })
-            .collect::<Result<Vec<Value>>>()
-            .context("failed to update status")?;
+            .collect::<Result<Vec<(Topic, Value)>>>();
+        client.scripthashes.extend(statuses); // so they are unsubscribed on disconnection
+        let mut notifications = result.context("failed to update status")?;
+
+        if let Some(broadcasts) = client.broadcasts.get_mut() {
+            if !broadcasts.is_empty() {
+                let changed = broadcasts
+                    .update(|txid| self.tracker.locate_transaction(&self.daemon, txid))
+                    .context("failed to update broadcasts")?;
+                notifications.extend(changed.into_iter().map(|(txid, status)| {
+                    (
+                        Topic::Transaction(txid),
+                        notification("blockchain.transaction.status_changed", &[status]),
+                    )
+                }));
+            }
+        }
+
+        if !client.outpoints.is_empty() {
+            let changed = client
+                .outpoints
+                .update(
+                    |outpoint| affected.is_spending_touched(outpoint),
+                    |blockhash| affected.is_stale(blockhash),
+                    |outpoint| {
+                        affected.resolve_spender(outpoint, |outpoint| {
+                            self.tracker.get_spender(&self.daemon, outpoint)
+                        })
+                    },
+                )
+                .context("failed to update outpoints")?;
+            notifications.extend(changed.into_iter().map(|(outpoint, status)| {
+                (
+                    Topic::Outpoint(outpoint),
+                    notification(
+                        "blockchain.outpoint.subscribe",
+                        &[json!(outpoint.txid), json!(outpoint.vout), status],
+                    ),
+                )
+            }));
+        }
+
+        if let Some(prev) = &client.mempool_summary {
+            // at most a single notification per sync, with the changes since the previous one
+            if self.tracker.generations().mempool != prev.mempool_generation {
+                let summary = self.tracker.mempool_summary();
+                notifications.push((
+                    Topic::MempoolSummary,
+                    notification("mempool.subscribe_summary", &[json!(summary.changes(prev))]),
+                ));
+                client.mempool_summary = Some(summary);
+            }
+        }
 
         if let Some(old_tip) = client.tip {
-            let new_tip = self.tracker.chain().tip();
+            // the end of initial sync is notified even if the tip is unchanged
+            let (new_tip, header) = subscribed_tip(chain, self.tracker.sync_target());
             if old_tip != new_tip {
                 client.tip = Some(new_tip);
-                let height = chain.height();
-                let header = chain.get_block_header(height).unwrap();
-                notifications.push(notification(
-                    "blockchain.headers.subscribe",
-                    &[json!({"hex": serialize_hex(&header), "height": height})],
+                notifications.push((
+                    Topic::Headers,
+                    notification("blockchain.headers.subscribe", &[header]),
                 ));
             }
         }
-        Ok(notifications.into_iter().map(|v| v.to_string()).collect())
+        Ok(notifications
+            .into_iter()
+            .map(|(topic, v)| (topic, v.to_string()))
+            .collect())
     }
 
     fn headers_subscribe(&self, client: &mut Client) -> Result<Value> {
-        let chain = self.tracker.chain();
-        client.tip = Some(chain.tip());
-        let height = chain.height();
-        let header = chain.get_block_header(height).unwrap();
-        Ok(json!({"hex": serialize_hex(header), "height": height}))
+        let (tip, header) = subscribed_tip(&self.tracker.chain(), self.tracker.sync_target());
+        client.tip = Some(tip);
+        Ok(header)
     }
 
-    fn block_header(&self, (height,): (usize,)) -> Result<Value> {
-        let chain = self.tracker.chain();
-        let header = match chain.get_block_header(height) {
+    fn block_header(&self, (height, cp_height): (usize, usize)) -> Result<Value> {
+        let chain = self.tracker.headers();
+        let header = match ChainView::new(Arc::clone(&chain)).header_at(height) {
             None => bail!("no header at {}", height),
             Some(header) => header,
         };
-        Ok(json!(serialize_hex(header)))
+        if cp_height == 0 {
+            return Ok(json!(serialize_hex(&header)));
+        }
+        let (branch, root) = header_proof(&chain, height, cp_height)?;
+        Ok(json!({"branch": branch, "header": serialize_hex(&header), "root": root}))
     }
 
-    fn block_headers(&self, (start_height, count): (usize, usize)) -> Result<Value> {
-        let chain = self.tracker.chain();
+    fn block_headers(
+        &self,
+        (start_height, count, cp_height): (usize, usize, usize),
+    ) -> Result<Reply> {
+        let chain = self.tracker.headers();
         let max_count = 2016usize;
         // return only the available block headers
         let end_height = std::cmp::min(
             chain.height() + 1,
-            start_height + std::cmp::min(count, max_count),
+            start_height.saturating_add(std::cmp::min(count, max_count)),
         );
         let heights = start_height..end_height;
         let count = heights.len();
-        let hex_headers =
-            heights.filter_map(|height| chain.get_block_header(height).map(serialize_hex));
+        let mut result = Outgoing::from(format!(r#"{{"count":{},"hex":""#, count));
+        result.push_items(Arc::new(HeadersHex {
+            chain: Arc::clone(&chain),
+            heights,
+        }));
+        result.push_str(&format!(r#"","max":{}"#, max_count));
+        if cp_height != 0 && count > 0 {
+            // the proof is for the last returned header
+            let (branch, root) = header_proof(&chain, end_height - 1, cp_height)?;
+            result.push_str(&format!(
+                r#","branch":{},"root":{}"#,
+                json!(branch),
+                json!(root)
+            ));
+        }
+        result.push_str("}");
+        Ok(Reply::Streamed(result))
+    }
 
-        Ok(json!({"count": count, "hex": String::from_iter(hex_headers), "max": max_count}))
+    /// Returns a chunk of a block from the indexed chain (see `block_chunk()`), only to clients
+    /// allowed by `block_get_allow`.
+    fn block_get(&self, client: &Client, args: &BlockGetArgs) -> Result<Value> {
+        ensure!(
+            client.is_in(&client.policy.block_get_allow),
+            "blockchain.block.get is not allowed from this address"
+        );
+        let (block, raw, chunk) = args.into();
+        ensure!(raw, "only raw blocks are supported");
+        let chain = self.tracker.chain();
+        let (height, blockhash) = match block {
+            BlockId::Height(height) => match chain.get_block_hash(height) {
+                None => bail!("missing block at {}", height),
+                Some(blockhash) => (height, blockhash),
+            },
+            BlockId::Hash(blockhash) => match chain.get_block_height(&blockhash) {
+                None => bail!("missing block {}", blockhash),
+                Some(height) => (height, blockhash),
+            },
+        };
+        let mut result =
+            self.tracker
+                .get_block(&self.daemon, &self.cache, blockhash, |block| {
+                    block_chunk(block, chunk)
+                })??;
+        result["hash"] = json!(blockhash);
+        result["height"] = json!(height);
+        Ok(result)
     }
 
     fn estimate_fee(&self, (nblocks,): (u16,)) -> Result<Value> {
         Ok(self
-            .daemon
-            .estimate_fee(nblocks)?
+            .fees
+            .estimate_fee(&self.daemon, nblocks)?
             .map(|fee_rate| json!(fee_rate.to_btc()))
             .unwrap_or_else(|| json!(UNKNOWN_FEE)))
     }
 
     fn relayfee(&self) -> Result<Value> {
-        Ok(json!(self.daemon.get_relay_fee()?.to_btc())) // [BTC/kB]
+        Ok(json!(self.fees.relay_fee(&self.daemon)?.to_btc())) // [BTC/kB]
     }
 
     fn scripthash_get_balance(
@@ -276,63 +1145,151 @@ impl Rpc {
         client: &Client,
         (scripthash,): &(ScriptHash,),
     ) -> Result<Value> {
+        self.allowlist.check("get_balance", scripthash)?;
         let balance = match client.scripthashes.get(scripthash) {
             Some(status) => self.tracker.get_balance(status),
             None => {
-                info!(
-                    "{} blockchain.scripthash.get_balance called for unsubscribed scripthash",
-                    UNSUBSCRIBED_QUERY_MESSAGE
-                );
+                if client.persistent {
+                    info!(
+                        "{} blockchain.scripthash.get_balance called for unsubscribed scripthash",
+                        UNSUBSCRIBED_QUERY_MESSAGE
+                    );
+                }
                 self.tracker.get_balance(&self.new_status(*scripthash)?)
             }
         };
         Ok(json!(balance))
     }
 
-    fn scripthash_get_history(
+    fn scripthash_get_stats(
         &self,
         client: &Client,
         (scripthash,): &(ScriptHash,),
     ) -> Result<Value> {
-        let history_entries = match client.scripthashes.get(scripthash) {
-            Some(status) => json!(status.get_history()),
+        self.allowlist.check("get_stats", scripthash)?;
+        let stats = match client.scripthashes.get(scripthash) {
+            Some(status) => self.tracker.get_stats(status),
             None => {
-                info!(
-                    "{} blockchain.scripthash.get_history called for unsubscribed scripthash",
-                    UNSUBSCRIBED_QUERY_MESSAGE
-                );
-                json!(self.new_status(*scripthash)?.get_history())
+                // large histories are truncated (and marked as partial), instead of failing
+                let mut status = ScriptHashStatus::new_partial(*scripthash);
+                self.tracker.update_scripthash_status(
+                    &mut status,
+                    self.tracker.chain(),
+                    &self.daemon,
+                    &self.cache,
+                    true,
+                )?;
+                self.tracker.get_stats(&status)
+            }
+        };
+        Ok(json!(stats))
+    }
+
+    fn scripthash_get_history(&self, client: &Client, args: &HistoryArgs) -> Result<Reply> {
+        let (scripthash, tx_pos) = args.into();
+        self.allowlist.check("get_history", &scripthash)?;
+        if args.status_debug() {
+            return self.scripthash_status_debug(client, scripthash, tx_pos);
+        }
+        let to_value = |entries: Arc<[HistoryEntry]>| {
+            if tx_pos {
+                Ok(Reply::array(Arc::new(WithPos(entries))))
+            } else {
+                Ok(Reply::array(Arc::new(entries)))
+            }
+        };
+        let history_entries = match client.scripthashes.get(&scripthash) {
+            Some(status) => to_value(status.shared_history()),
+            None => {
+                if client.persistent {
+                    info!(
+                        "{} blockchain.scripthash.get_history called for unsubscribed scripthash",
+                        UNSUBSCRIBED_QUERY_MESSAGE
+                    );
+                }
+                to_value(self.new_status(scripthash)?.shared_history())
             }
         };
-        Ok(history_entries)
+        history_entries
     }
 
     fn scripthash_list_unspent(
         &self,
         client: &Client,
         (scripthash,): &(ScriptHash,),
-    ) -> Result<Value> {
+    ) -> Result<Reply> {
+        self.allowlist.check("listunspent", scripthash)?;
         let unspent_entries = match client.scripthashes.get(scripthash) {
             Some(status) => self.tracker.get_unspent(status),
             None => {
-                info!(
-                    "{} blockchain.scripthash.listunspent called for unsubscribed scripthash",
-                    UNSUBSCRIBED_QUERY_MESSAGE
-                );
+                if client.persistent {
+                    info!(
+                        "{} blockchain.scripthash.listunspent called for unsubscribed scripthash",
+                        UNSUBSCRIBED_QUERY_MESSAGE
+                    );
+                }
                 self.tracker.get_unspent(&self.new_status(*scripthash)?)
             }
         };
-        Ok(json!(unspent_entries))
+        Ok(Reply::array(Arc::new(unspent_entries)))
     }
 
-    fn scripthash_subscribe(
-        &self,
-        client: &mut Client,
-        (scripthash,): &(ScriptHash,),
-    ) -> Result<Value> {
-        self.scripthashes_subscribe(client, &[*scripthash])
+    fn scripthash_subscribe(&self, client: &mut Client, args: &SubscribeArgs) -> Result<Value> {
+        let (scripthash, status_debug) = args.into();
+        if status_debug {
+            self.check_status_debug(client)?;
+        }
+        let statushash = self
+            .scripthashes_subscribe(client, &[scripthash])
             .next()
-            .unwrap()
+            .unwrap()?;
+        if !status_debug {
+            return Ok(statushash);
+        }
+        let status = &client.scripthashes[&scripthash];
+        Ok(json!({"status": statushash, "preimage": status.status_preimage()}))
+    }
+
+    /// `blockchain.scripthash.get_history` with the status and its pre-hash string, e.g. to find
+    /// why a client's status differs (`status_debug` extension).
+    fn scripthash_status_debug(
+        &self,
+        client: &Client,
+        scripthash: ScriptHash,
+        tx_pos: bool,
+    ) -> Result<Reply> {
+        self.check_status_debug(client)?;
+        let new_status;
+        let status = match client.scripthashes.get(&scripthash) {
+            Some(status) => status,
+            None => {
+                new_status = self.new_status(scripthash)?;
+                &new_status
+            }
+        };
+        let history = status.get_history();
+        let history = if tx_pos {
+            json!(history
+                .iter()
+                .map(HistoryEntry::with_pos)
+                .collect::<Vec<_>>())
+        } else {
+            json!(history)
+        };
+        Ok(Reply::from(json!({
+            "history": history,
+            "status": status.statushash(),
+            "preimage": status.status_preimage(),
+        })))
+    }
+
+    /// The status' pre-hash string is returned only to `status_debug_allow` networks.
+    fn check_status_debug(&self, client: &Client) -> Result<()> {
+        ensure!(
+            client.is_in(&client.policy.status_debug_allow),
+            "status_debug is not allowed for this connection"
+        );
+        Ok(())
     }
 
     fn scripthash_unsubscribe(
@@ -341,19 +1298,62 @@ impl Rpc {
         (scripthash,): &(ScriptHash,),
     ) -> Result<Value> {
         let removed = client.scripthashes.remove(scripthash).is_some();
+        client.scripthashes.shrink();
+        if removed {
+            client.subscriptions.unsubscribe(client.id, *scripthash);
+            client.unsubscribed.push(*scripthash);
+        }
         Ok(json!(removed))
     }
 
-    fn scripthashes_subscribe<'a>(
+    /// `blockchain.scripthash.subscribe_bulk` returns the statuses in the request's order, where
+    /// failed subscriptions are replaced by their error objects (instead of failing the whole call).
+    fn scripthash_subscribe_bulk(
         &self,
+        client: &mut Client,
+        (scripthashes,): &(Vec<ScriptHash>,),
+    ) -> Result<Value> {
+        let mut failed = 0;
+        let results: Vec<Value> = self
+            .scripthashes_subscribe(client, scripthashes)
+            .map(|result| {
+                result.unwrap_or_else(|err| {
+                    failed += 1;
+                    RpcError::from_error(err).to_value()
+                })
+            })
+            .collect();
+        if failed > 0 {
+            warn!(
+                "failed to subscribe {}/{} scripthashes: {}",
+                failed,
+                results.len(),
+                results.iter().find(|r| r.is_object()).unwrap() // the first error
+            );
+        }
+        Ok(json!(results))
+    }
+
+    /// New subscriptions are added in the request's order, until `max_subscriptions` is reached -
+    /// the following ones fail (but the previous ones are kept).
+    fn scripthashes_subscribe<'a>(
+        &'a self,
         client: &'a mut Client,
         scripthashes: &'a [ScriptHash],
     ) -> impl Iterator<Item = Result<Value>> + 'a {
-        let new_scripthashes: Vec<ScriptHash> = scripthashes
+        let mut unique = HashSet::new();
+        let mut new_scripthashes: Vec<ScriptHash> = scripthashes
             .iter()
             .copied()
             .filter(|scripthash| !client.scripthashes.contains_key(scripthash))
+            .filter(|scripthash| unique.insert(*scripthash))
+            .filter(|scripthash| self.allowlist.allows(scripthash))
             .collect();
+        // don't compute the statuses of scripthashes exceeding the limit
+        new_scripthashes.truncate(
+            self.max_subscriptions
+                .saturating_sub(client.scripthashes.len()),
+        );
 
         let mut results: HashMap<ScriptHash, Result<ScriptHashStatus>> = new_scripthashes
             .into_par_iter()
@@ -361,38 +1361,88 @@ impl Rpc {
             .collect();
 
         scripthashes.iter().map(move |scripthash| {
-            let statushash = match client.scripthashes.entry(*scripthash) {
-                Entry::Occupied(e) => e.get().statushash(),
-                Entry::Vacant(e) => {
-                    let status = results
-                        .remove(scripthash)
-                        .expect("missing scripthash status")?; // return an error for failed subscriptions
-                    e.insert(status).statushash()
-                }
+            if let Some(status) = client.scripthashes.get(scripthash) {
+                return Ok(json!(status.statushash()));
+            }
+            self.allowlist.check("subscribe", scripthash)?;
+            ensure!(
+                client.scripthashes.len() < self.max_subscriptions,
+                "subscription limit exceeded (at most {} scripthashes per connection)",
+                self.max_subscriptions
+            );
+            let status = match results.remove(scripthash) {
+                Some(result) => result?, // return an error for failed subscriptions
+                None => self.new_status(*scripthash)?, // e.g. a duplicate of a failed subscription
             };
+            client.subscriptions.subscribe(client.id, *scripthash);
+            let outpoints = self.tracker.get_outpoints(&status);
+            client
+                .subscriptions
+                .watch(*scripthash, outpoints.into_iter());
+            let statushash = status.statushash();
+            client.scripthashes.insert(*scripthash, status);
             Ok(json!(statushash))
         })
     }
 
+    /// Scripthash history, as returned by `blockchain.scripthash.get_history` (without a client)
+    pub(crate) fn get_history(&self, scripthash: ScriptHash) -> Result<Vec<HistoryEntry>> {
+        Ok(self.new_status(scripthash)?.get_history().to_vec())
+    }
+
+    /// Scripthash balance, as returned by `blockchain.scripthash.get_balance` (without a client)
+    pub(crate) fn get_balance(&self, scripthash: ScriptHash) -> Result<Balance> {
+        Ok(self.tracker.get_balance(&self.new_status(scripthash)?))
+    }
+
     fn new_status(&self, scripthash: ScriptHash) -> Result<ScriptHashStatus> {
-        let mut status = ScriptHashStatus::new(scripthash);
+        if let Some(shadow_db) = &self.shadow_db {
+            shadow_db.sample(scripthash);
+        }
         self.tracker
-            .update_scripthash_status(&mut status, &self.daemon, &self.cache)?;
-        Ok(status)
+            .new_scripthash_status(scripthash, &self.daemon, &self.cache)
     }
 
-    fn transaction_broadcast(&self, (tx_hex,): &(String,)) -> Result<Value> {
-        let txid = self.daemon.broadcast(&tx_from_hex(tx_hex)?)?;
+    /// Opt in to `blockchain.transaction.status_changed` notifications, for the transactions
+    /// broadcast by this connection.
+    fn track_broadcasts(&self, client: &mut Client) -> Result<Value> {
+        client
+            .broadcasts
+            .get_mut()
+            .get_or_insert_with(Broadcasts::default);
+        Ok(json!(true))
+    }
+
+    fn transaction_broadcast(
+        &self,
+        client: &Client,
+        tx: &std::result::Result<Transaction, String>,
+    ) -> Result<Value> {
+        let tx = tx.as_ref().map_err(|err| anyhow!("{}", err))?;
+        let txid = self.daemon.broadcast(tx)?;
+        client.track_broadcast(txid);
         Ok(json!(txid))
     }
 
-    fn transaction_broadcast_package(&self, args: &BroadcastArgs) -> Result<Value> {
+    fn transaction_broadcast_package(
+        &self,
+        client: &Client,
+        args: &BroadcastArgs,
+    ) -> Result<Value> {
         let txs: Vec<Transaction> = args
             .txs()
             .iter()
             .map(|s| tx_from_hex(s))
             .collect::<Result<_>>()?;
         let response = self.daemon.submitpackage(&txs)?;
+        let accepted = response
+            .get("tx-results")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flat_map(|results| results.values())
+            .filter(|tx| tx.get("error").is_none())
+            .filter_map(|tx| tx.get("txid")?.as_str()?.parse().ok());
+        accepted.for_each(|txid| client.track_broadcast(txid));
         if args.verbose() {
             return Ok(response);
         }
@@ -419,40 +1469,77 @@ impl Rpc {
     fn transaction_get(&self, args: &TxGetArgs) -> Result<Value> {
         let (txid, verbose) = args.into();
         if verbose {
-            let blockhash = self
+            return self
                 .tracker
-                .lookup_transaction(&self.daemon, txid)?
-                .map(|(blockhash, _tx)| blockhash);
-            return self.daemon.get_transaction_info(&txid, blockhash);
+                .get_transaction_info(&self.daemon, &self.cache, txid);
         }
         // if the scripthash was subscribed, tx should be cached
-        if let Some(tx_hex) = self
+        let fetch = || {
+            debug!("tx cache miss: txid={}", txid);
+            // use the tracked mempool and the internal index (so bitcoind's `txindex` is not needed)
+            self.tracker.get_raw_transaction(&self.daemon, txid)
+        };
+        let chain = self.tracker.chain();
+        // confirmed transactions are fetched from their block, so they are kept cached
+        let tx_hex = self
             .cache
-            .get_tx(&txid, |tx_bytes| tx_bytes.to_lower_hex_string())
-        {
-            return Ok(json!(tx_hex));
-        }
-        debug!("tx cache miss: txid={}", txid);
-        // use internal index to load confirmed transaction
-        if let Some(tx_hex) = self
-            .tracker
-            .lookup_transaction(&self.daemon, txid)?
-            .map(|(_blockhash, tx)| tx.to_lower_hex_string())
-        {
+            .get_or_fetch_tx(&chain, txid, fetch, |tx| tx.to_lower_hex_string())?;
+        if let Some(tx_hex) = tx_hex {
             return Ok(json!(tx_hex));
         }
-        // load unconfirmed transaction via RPC
+        // load unconfirmed transaction via RPC (e.g. if the mempool is not synced yet)
         Ok(json!(self.daemon.get_transaction_hex(&txid, None)?))
     }
 
-    fn transaction_get_merkle(&self, (txid, height): &(Txid, usize)) -> Result<Value> {
+    /// `{height}` (as in `blockchain.scripthash.get_history`), or `null` if the transaction is
+    /// unknown. The batched form returns the results in the request's order, where failed lookups
+    /// are replaced by their error objects.
+    fn transaction_get_height(&self, args: &TxHeightArgs) -> Result<Value> {
+        let get_height = |txid: Txid| -> Result<Value> {
+            let height = self
+                .tracker
+                .get_tx_height(&self.daemon, &self.cache, txid)?;
+            Ok(height.map_or(Value::Null, |height| json!({ "height": height })))
+        };
+        match args {
+            TxHeightArgs::Txid((txid,)) => get_height(*txid),
+            TxHeightArgs::Txids((txids,)) => Ok(json!(txids
+                .iter()
+                .map(|txid| {
+                    get_height(*txid).unwrap_or_else(|err| RpcError::from_error(err).to_value())
+                })
+                .collect::<Vec<Value>>())),
+        }
+    }
+
+    fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
+        self.tracker
+            .get_block_txids(&self.daemon, &self.cache, blockhash)
+    }
+
+    fn transaction_get_merkle(&self, args: &MerkleArgs) -> Result<Value> {
+        let (txid, height, tx_pos) = args.into();
         let chain = self.tracker.chain();
-        let blockhash = match chain.get_block_hash(*height) {
+        let blockhash = match chain.get_block_hash(height) {
             None => bail!("missing block at {}", height),
             Some(blockhash) => blockhash,
         };
-        let txids = self.daemon.get_block_txids(blockhash)?;
-        match txids.iter().position(|current_txid| *current_txid == *txid) {
+        let txids = self.get_block_txids(blockhash)?;
+        let position = match tx_pos {
+            // no need to search for the transaction, if its position is known
+            Some(tx_pos) => {
+                ensure!(
+                    txids.get(tx_pos) == Some(&txid),
+                    "txid {} is not at position {} in block {}",
+                    txid,
+                    tx_pos,
+                    blockhash
+                );
+                Some(tx_pos)
+            }
+            None => txids.iter().position(|current_txid| *current_txid == txid),
+        };
+        match position {
             None => bail!("missing txid {} in block {}", txid, blockhash),
             Some(position) => {
                 let proof = Proof::create(&txids, position);
@@ -474,75 +1561,208 @@ impl Rpc {
             None => bail!("missing block at {}", height),
             Some(blockhash) => blockhash,
         };
-        let txids = self.daemon.get_block_txids(blockhash)?;
+        let txids = self.get_block_txids(blockhash)?;
         if tx_pos >= txids.len() {
             bail!("invalid tx_pos {} in block at height {}", tx_pos, height);
         }
         let txid: Txid = txids[tx_pos];
         if merkle {
             let proof = Proof::create(&txids, tx_pos);
-            Ok(json!({"tx_id": txid, "merkle": proof.to_hex()}))
+            Ok(json!({"tx_hash": txid, "merkle": proof.to_hex()}))
         } else {
-            Ok(json!({ "tx_id": txid }))
+            Ok(json!(txid))
         }
     }
 
+    fn outpoint_subscribe(&self, client: &mut Client, (txid, vout): &(Txid, u32)) -> Result<Value> {
+        let outpoint = OutPoint::new(*txid, *vout);
+        let spender = self.tracker.get_spender(&self.daemon, outpoint)?;
+        client.outpoints.subscribe(outpoint, spender)?;
+        Ok(Spender::to_value(spender.as_ref()))
+    }
+
+    fn outpoint_unsubscribe(
+        &self,
+        client: &mut Client,
+        (txid, vout): &(Txid, u32),
+    ) -> Result<Value> {
+        let outpoint = OutPoint::new(*txid, *vout);
+        Ok(json!(client.outpoints.unsubscribe(&outpoint)))
+    }
+
+    fn outpoint_get_spender(&self, (txid, vout): &(Txid, u32)) -> Result<Value> {
+        let outpoint = OutPoint::new(*txid, *vout);
+        let spender = self.tracker.get_spender(&self.daemon, outpoint)?;
+        Ok(Spender::to_value(spender.as_ref()))
+    }
+
     fn get_fee_histogram(&self) -> Result<Value> {
-        Ok(json!(self.tracker.fees_histogram()))
+        Ok(self.tracker.fees_histogram())
     }
 
-    fn server_id(&self) -> String {
-        format!("electrs/{}", ELECTRS_VERSION)
+    fn get_mempool_info(&self) -> Result<Value> {
+        Ok(json!(self.tracker.mempool_stats()))
     }
 
-    fn version(&self, (client_id, client_version): &(String, VersionRequest)) -> Result<Value> {
-        match client_version {
-            VersionRequest::Single(exact) => check_between(PROTOCOL_VERSION, exact, exact),
-            VersionRequest::MinMax(min, max) => check_between(PROTOCOL_VERSION, min, max),
+    /// Returns the full summary (the following notifications contain only its changes).
+    fn mempool_subscribe_summary(&self, client: &mut Client) -> Result<Value> {
+        let summary = self.tracker.mempool_summary();
+        let result = json!(summary);
+        client.mempool_summary = Some(summary);
+        Ok(result)
+    }
+
+    fn version(
+        &self,
+        client: &mut Client,
+        (client_id, client_version): &(String, VersionRequest),
+    ) -> Result<Value> {
+        let version = client.negotiate(client_version).with_context(|| {
+            format!("unsupported request {:?} by {}", client_version, client_id)
+        })?;
+        Ok(json!([server_id(), version.to_string()]))
+    }
+
+    /// The advertised extensions depend on the methods allowed for the client.
+    fn features(&self, client: &Client) -> Result<Value> {
+        let mut features = self.features.lock();
+        if features.is_null() {
+            let genesis_hash = self
+                .tracker
+                .chain()
+                .get_block_hash(0)
+                .expect("missing genesis block");
+            *features = Features::new(
+                &self.announce,
+                genesis_hash,
+                (PROTOCOL_MIN, PROTOCOL_MAX),
+                server_id(),
+                vec![], // set below
+            )
+            .to_value();
         }
-        .with_context(|| format!("unsupported request {:?} by {}", client_version, client_id))?;
-        Ok(json!([self.server_id(), PROTOCOL_VERSION]))
+        let mut features = features.clone();
+        let allowed = |method: &Method| client.allows(method, self.password.is_some());
+        let mut extensions = extensions(allowed);
+        if client.is_in(&client.policy.status_debug_allow) {
+            extensions.push("status_debug"); // a parameter of methods allowed to everyone
+        }
+        features["extensions"] = json!(extensions);
+        add_sync_progress(&mut features, self.tracker.sync_target());
+        Ok(features)
     }
 
-    fn features(&self) -> Result<Value> {
-        Ok(json!({
-            "genesis_hash": self.tracker.chain().get_block_hash(0),
-            "hosts": { "tcp_port": self.port },
-            "protocol_max": PROTOCOL_VERSION,
-            "protocol_min": PROTOCOL_VERSION,
-            "pruning": null,
-            "server_version": self.server_id(),
-            "hash_function": "sha256"
-        }))
+    /// Handle a client's requests, passing each response to `respond` as soon as it is ready.
+    ///
+    /// Requests modifying the client's state (e.g. subscriptions) and batches are handled first,
+    /// in order, by the calling thread - so they don't race the notifications (which are sent by
+    /// the same thread). The rest are handled concurrently, so a slow request doesn't delay the
+    /// following ones: their responses may be sent out of order (clients match them by `id`).
+    pub fn handle_requests(
+        &self,
+        client: &mut Client,
+        lines: &[String],
+        mut respond: impl FnMut(Outgoing) -> Result<()>,
+    ) -> Result<()> {
+        let mut concurrent = vec![];
+        for line in lines {
+            if std::mem::take(&mut client.first_line)
+                && self.password.is_some()
+                && !client.authenticated
+                && is_password_line(line)
+            {
+                // the password is not parsed (nor logged) as a request
+                if let Err(err) = client.authenticate(self.password.as_ref(), line) {
+                    warn!("authentication failed: {}", err);
+                    let response = error_msg(&Value::Null, RpcError::BadRequest(err));
+                    respond(response.to_string().into())?;
+                }
+                continue;
+            }
+            let calls = parse_requests(line)
+                .map(Calls::parse)
+                .map_err(error_msg_no_id);
+            if calls.is_err() {
+                client.violations += 1;
+            }
+            match calls {
+                Ok(Calls::Single(Ok(call)))
+                    if !call.params.is_mutating()
+                        && (self.password.is_none() || client.authenticated) =>
+                {
+                    concurrent.push(call)
+                }
+                calls => respond(self.handle_calls(client, calls).into_outgoing())?,
+            }
+        }
+        let client = &*client;
+        crate::thread::unordered_map(
+            concurrent,
+            self.max_concurrent_requests,
+            |call| self.shared_call(client, call),
+            respond,
+        )
     }
 
-    pub fn handle_requests(&self, client: &mut Client, lines: &[String]) -> Vec<String> {
-        lines
-            .iter()
-            .map(|line| {
-                parse_requests(line)
-                    .map(Calls::parse)
-                    .map_err(error_msg_no_id)
-            })
-            .map(|calls| self.handle_calls(client, calls).to_string())
-            .collect()
+    /// Answer a cheap request (e.g. `server.ping`) without accessing the client's state, so it
+    /// doesn't have to wait for the client's (or other clients') requests being handled.
+    /// Returns `None` if the request should be handled by [`Rpc::handle_requests`] instead.
+    /// For `blockchain.headers.subscribe`, the subscribed tip is returned (to be set via
+    /// [`Client::subscribe_headers`]).
+    pub(crate) fn handle_cheap_request(
+        &self,
+        line: &str,
+        policy: &ListenerPolicy,
+    ) -> Option<(String, Option<SubscribedTip>)> {
+        if self.password.is_some() {
+            return None; // the client may not be authenticated
+        }
+        let request = match serde_json::from_str(line) {
+            Ok(Requests::Single(request)) if request.check().is_ok() => request,
+            _ => return None, // batches and invalid requests are handled (and logged) as usual
+        };
+        let call = match Call::parse(request) {
+            Ok(call) if call.params.is_cheap() && !policy.disables(call.method.name) => call,
+            Ok(_) => return None,
+            Err(response) => return Some((response.to_string(), None)),
+        };
+        let mut tip = None;
+        let response = self.observe_call(call, |call| {
+            match &call.params {
+                Params::Banner => self.banner(policy),
+                Params::Donation => Ok(json!("")), // no donation address (the protocol specifies a string)
+                Params::HeadersSubscribe => {
+                    let (subscribed, header) =
+                        subscribed_tip(&self.tracker.chain(), self.tracker.sync_target());
+                    tip = Some(subscribed);
+                    Ok(header)
+                }
+                Params::PeersSubscribe => Ok(json!([])),
+                Params::Ping => Ok(Value::Null),
+                _ => unreachable!("not a cheap call: {}", call.method.name),
+            }
+            .map(Reply::from)
+        });
+        Some((response.into_json(), tip))
     }
 
-    fn handle_calls(&self, client: &mut Client, calls: Result<Calls, Value>) -> Value {
+    fn handle_calls(&self, client: &mut Client, calls: Result<Calls, Value>) -> Response {
         let calls: Calls = match calls {
             Ok(calls) => calls,
-            Err(response) => return response, // JSON parsing failed - the response does not contain request id
+            Err(response) => return response.into(), // JSON parsing failed - the response does not contain request id
         };
 
         match calls {
             Calls::Batch(batch) => {
                 if let Some(result) = self.try_multi_call(client, &batch) {
-                    return json!(result);
+                    return Response::Batch(result);
                 }
-                json!(batch
-                    .into_iter()
-                    .map(|result| self.single_call(client, result))
-                    .collect::<Vec<Value>>())
+                Response::Batch(
+                    batch
+                        .into_iter()
+                        .map(|result| self.single_call(client, result))
+                        .collect(),
+                )
             }
             Calls::Single(result) => self.single_call(client, result),
         }
@@ -552,18 +1772,32 @@ impl Rpc {
         &self,
         client: &mut Client,
         calls: &[Result<Call, Value>],
-    ) -> Option<Vec<Value>> {
+    ) -> Option<Vec<Response>> {
+        if !client.persistent {
+            return None; // subscriptions are rejected by `single_call()`
+        }
+        if self.password.is_some() && !client.authenticated {
+            return None; // the calls are rejected by `single_call()`
+        }
         // exit if any call failed to parse
         let valid_calls = calls
             .iter()
             .map(|result| result.as_ref().ok())
             .collect::<Option<Vec<&Call>>>()?;
+        if valid_calls
+            .iter()
+            .any(|call| client.policy.disables(call.method.name))
+        {
+            return None; // the calls are rejected by `single_call()`
+        }
 
         // only "blockchain.scripthashes.subscribe" are supported
         let scripthashes: Vec<ScriptHash> = valid_calls
             .iter()
             .map(|call| match &call.params {
-                Params::ScriptHashSubscribe((scripthash,)) => Some(*scripthash),
+                Params::ScriptHashSubscribe(SubscribeArgs::ScriptHash((scripthash,))) => {
+                    Some(*scripthash)
+                }
                 _ => None, // exit if any of the calls is not supported
             })
             .collect::<Option<Vec<ScriptHash>>>()?;
@@ -573,152 +1807,566 @@ impl Rpc {
                 .observe_duration("blockchain.scripthash.subscribe:multi", || {
                     self.scripthashes_subscribe(client, &scripthashes)
                         .zip(valid_calls)
-                        .map(|(result, call)| call.response(result))
-                        .collect::<Vec<Value>>()
+                        .map(|(result, call)| call.response(result).into())
+                        .collect::<Vec<Response>>()
                 }),
         )
     }
 
-    fn single_call(&self, client: &mut Client, call: Result<Call, Value>) -> Value {
+    fn single_call(&self, client: &mut Client, call: Result<Call, Value>) -> Response {
         let call = match call {
             Ok(call) => call,
-            Err(response) => return response, // params parsing may fail - the response contains request id
+            Err(response) => return response.into(), // params parsing may fail - the response contains request id
         };
-        self.rpc_duration.observe_duration(&call.method, || {
+        if !client.authorize(self.password.as_ref(), &call.params) {
+            return error_msg(&call.id, RpcError::AuthenticationRequired).into();
+        }
+        if client.policy.disables(call.method.name) {
+            return call.disabled().into();
+        }
+        if !client.supports(call.method) {
+            return call.not_supported(&client.protocol()).into();
+        }
+        if call.params.is_subscription() && !client.persistent {
+            return call.needs_persistent_connection().into();
+        }
+        self.observe_call(call, |call| {
+            match &call.params {
+                Params::HeadersSubscribe => self.headers_subscribe(client),
+                Params::ScriptHashSubscribe(args) => self.scripthash_subscribe(client, args),
+                Params::ScriptHashSubscribeBulk(args) => {
+                    self.scripthash_subscribe_bulk(client, args)
+                }
+                Params::ScriptHashUnsubscribe(args) => self.scripthash_unsubscribe(client, args),
+                Params::OutpointSubscribe(args) => self.outpoint_subscribe(client, args),
+                Params::OutpointUnsubscribe(args) => self.outpoint_unsubscribe(client, args),
+                Params::TrackBroadcasts => self.track_broadcasts(client),
+                Params::MempoolSubscribeSummary => self.mempool_subscribe_summary(client),
+                Params::Version(args) => self.version(client, args),
+                Params::Authenticate((password,)) => client
+                    .authenticate(self.password.as_ref(), password)
+                    .map(|()| json!(true)),
+                params => return self.shared_params(client, params),
+            }
+            .map(Reply::from)
+        })
+    }
+
+    // Handle a call not modifying the client's state (may run concurrently with other calls)
+    fn shared_call(&self, client: &Client, call: Deferred<Call, Outgoing>) {
+        let method = call.item().method;
+        if client.policy.disables(method.name) {
+            return call.complete(|call| Response::from(call.disabled()).into_outgoing());
+        }
+        if !client.supports(method) {
+            return call.complete(|call| {
+                Response::from(call.not_supported(&client.protocol())).into_outgoing()
+            });
+        }
+        let Some(key) = coalesce_key(client, &call.item().params) else {
+            return call.complete(|call| {
+                self.observe_call(call, |call| self.shared_params(client, &call.params))
+                    .into_outgoing()
+            });
+        };
+        let requests = self.tracker.totals().requests;
+        let rpc_duration = self.rpc_duration.clone();
+        let observe = move |method: &str, duration: Duration| {
+            requests.inc();
+            rpc_duration.observe(method, duration.as_secs_f64());
+        };
+        let key = (self.tracker.generations(), key);
+        coalesce(&self.coalescer, key, call, observe, |call, share| {
+            self.observe_call(call, |call| {
+                let reply = self.shared_params(client, &call.params);
+                if let Ok(reply) = &reply {
+                    share(reply);
+                }
+                reply
+            })
+        })
+    }
+
+    fn observe_call(&self, call: Call, func: impl FnOnce(&Call) -> Result<Reply>) -> Response {
+        self.tracker.totals().requests.inc();
+        let start = Instant::now();
+        let response = self.rpc_duration.observe_duration(call.method.name, || {
             if self.tracker.status().is_err() {
                 // Allow only a few RPC (for sync status notification) not requiring index DB being compacted.
                 match &call.params {
                     Params::BlockHeader(_)
                     | Params::BlockHeaders(_)
                     | Params::HeadersSubscribe
+                    | Params::Features
+                    | Params::BansClear(_)
+                    | Params::BansList
+                    | Params::ReorgAllow
+                    | Params::DbCompact
+                    | Params::DbStats
+                    | Params::IndexStatus
+                    | Params::Authenticate(_)
                     | Params::Version(_) => (),
-                    _ => return error_msg(&call.id, RpcError::UnavailableIndex),
+                    _ => return error_msg(&call.id, RpcError::UnavailableIndex).into(),
                 };
             }
-            let result = match &call.params {
-                Params::Banner => Ok(json!(self.banner)),
-                Params::BlockHeader(args) => self.block_header(*args),
-                Params::BlockHeaders(args) => self.block_headers(*args),
-                Params::Donation => Ok(Value::Null),
-                Params::EstimateFee(args) => self.estimate_fee(*args),
-                Params::Features => self.features(),
-                Params::HeadersSubscribe => self.headers_subscribe(client),
-                Params::MempoolFeeHistogram => self.get_fee_histogram(),
-                Params::PeersSubscribe => Ok(json!([])),
-                Params::Ping => Ok(Value::Null),
-                Params::RelayFee => self.relayfee(),
-                Params::ScriptHashGetBalance(args) => self.scripthash_get_balance(client, args),
-                Params::ScriptHashGetHistory(args) => self.scripthash_get_history(client, args),
-                Params::ScriptHashListUnspent(args) => self.scripthash_list_unspent(client, args),
-                Params::ScriptHashSubscribe(args) => self.scripthash_subscribe(client, args),
-                Params::ScriptHashUnsubscribe(args) => self.scripthash_unsubscribe(client, args),
-                Params::TransactionBroadcast(args) => self.transaction_broadcast(args),
-                Params::TransactionBroadcastPackage(args) => {
-                    self.transaction_broadcast_package(args)
-                }
-                Params::TransactionGet(args) => self.transaction_get(args),
-                Params::TransactionGetMerkle(args) => self.transaction_get_merkle(args),
-                Params::TransactionFromPosition(args) => self.transaction_from_pos(*args),
-                Params::Version(args) => self.version(args),
-            };
-            call.response(result)
-        })
+            let result = func(&call);
+            if let Err(err) = &result {
+                self.tracker.metrics().health().daemon_failed(err); // until the next sync
+            }
+            call.reply(result)
+        });
+        let duration = start.elapsed();
+        debug!(
+            method = call.method.name,
+            duration = duration.as_secs_f64();
+            "RPC {} took {:?}", call.method.name, duration
+        );
+        response
+    }
+
+    fn shared_params(&self, client: &Client, params: &Params) -> Result<Reply> {
+        match params {
+            Params::Banner => self.banner(&client.policy),
+            Params::BansClear(args) => self.bans_clear(client, args),
+            Params::BansList => self.bans_list(client),
+            Params::ReorgAllow => self.reorg_allow(client),
+            Params::DbCompact => self.db_compact(client),
+            Params::DbStats => self.db_stats(client),
+            Params::IndexStatus => self.index_status(client),
+            Params::BlockHeader(args) => self.block_header(args.into()),
+            Params::BlockHeaders(args) => return self.block_headers(args.into()),
+            Params::BlockGet(args) => self.block_get(client, args),
+            Params::Donation => Ok(json!("")),
+            Params::EstimateFee(args) => self.estimate_fee(*args),
+            Params::Features => self.features(client),
+            Params::MempoolFeeHistogram => self.get_fee_histogram(),
+            Params::MempoolGetInfo => self.get_mempool_info(),
+            Params::OutpointGetSpender(args) => self.outpoint_get_spender(args),
+            Params::PeersSubscribe => Ok(json!([])),
+            Params::Ping => Ok(Value::Null),
+            Params::RelayFee => self.relayfee(),
+            Params::ScriptHashGetBalance(args) => self.scripthash_get_balance(client, args),
+            Params::ScriptHashGetStats(args) => self.scripthash_get_stats(client, args),
+            Params::ScriptHashGetHistory(args) => return self.scripthash_get_history(client, args),
+            Params::ScriptHashListUnspent(args) => {
+                return self.scripthash_list_unspent(client, args)
+            }
+            Params::TransactionBroadcast(args) => self.transaction_broadcast(client, args),
+            Params::TransactionBroadcastPackage(args) => {
+                self.transaction_broadcast_package(client, args)
+            }
+            Params::TransactionGet(args) => self.transaction_get(args),
+            Params::TransactionGetHeight(args) => self.transaction_get_height(args),
+            Params::TransactionGetMerkle(args) => self.transaction_get_merkle(args),
+            Params::TransactionFromPosition(args) => self.transaction_from_pos(*args),
+            Params::Authenticate(_)
+            | Params::HeadersSubscribe
+            | Params::ScriptHashSubscribe(_)
+            | Params::ScriptHashSubscribeBulk(_)
+            | Params::ScriptHashUnsubscribe(_)
+            | Params::OutpointSubscribe(_)
+            | Params::OutpointUnsubscribe(_)
+            | Params::TrackBroadcasts
+            | Params::MempoolSubscribeSummary
+            | Params::Version(_) => {
+                // `handle_requests()` runs only the non-mutating calls concurrently
+                unreachable!("mutating calls are handled by `Rpc::single_call()` (see `Params::is_mutating()`)")
+            }
+        }
+        .map(Reply::from)
     }
 }
 
 #[derive(Deserialize)]
 enum Params {
+    Authenticate((String,)),
     Banner,
-    BlockHeader((usize,)),
-    BlockHeaders((usize, usize)),
-    TransactionBroadcast((String,)),
+    BansClear(Option<Vec<IpAddr>>),
+    BansList,
+    BlockHeader(HeaderArgs),
+    BlockHeaders(HeadersArgs),
+    BlockGet(BlockGetArgs),
+    /// Decoded while parsing, so the hex is freed before the call runs (the decoding error is
+    /// returned to the client like bitcoind's rejections)
+    #[serde(skip)]
+    TransactionBroadcast(std::result::Result<Transaction, String>),
     TransactionBroadcastPackage(BroadcastArgs),
+    DbCompact,
+    DbStats,
     Donation,
     EstimateFee((u16,)),
     Features,
     HeadersSubscribe,
+    IndexStatus,
     MempoolFeeHistogram,
+    MempoolGetInfo,
+    MempoolSubscribeSummary,
+    OutpointGetSpender((Txid, u32)),
+    OutpointSubscribe((Txid, u32)),
+    OutpointUnsubscribe((Txid, u32)),
     PeersSubscribe,
     Ping,
     RelayFee,
+    ReorgAllow,
     ScriptHashGetBalance((ScriptHash,)),
-    ScriptHashGetHistory((ScriptHash,)),
+    ScriptHashGetStats((ScriptHash,)),
+    ScriptHashGetHistory(HistoryArgs),
     ScriptHashListUnspent((ScriptHash,)),
-    ScriptHashSubscribe((ScriptHash,)),
+    ScriptHashSubscribe(SubscribeArgs),
+    ScriptHashSubscribeBulk((Vec<ScriptHash>,)),
     ScriptHashUnsubscribe((ScriptHash,)),
     TransactionGet(TxGetArgs),
-    TransactionGetMerkle((Txid, usize)),
+    TransactionGetHeight(TxHeightArgs),
+    TransactionGetMerkle(MerkleArgs),
     TransactionFromPosition((usize, usize, bool)),
+    TrackBroadcasts,
     Version((String, VersionRequest)),
 }
 
 impl Params {
-    fn parse(method: &str, params: Value) -> std::result::Result<Params, StandardError> {
-        Ok(match method {
-            "blockchain.block.header" => Params::BlockHeader(convert(params)?),
-            "blockchain.block.headers" => Params::BlockHeaders(convert(params)?),
-            "blockchain.estimatefee" => Params::EstimateFee(convert(params)?),
-            "blockchain.headers.subscribe" => Params::HeadersSubscribe,
-            "blockchain.relayfee" => Params::RelayFee,
-            "blockchain.scripthash.get_balance" => Params::ScriptHashGetBalance(convert(params)?),
-            "blockchain.scripthash.get_history" => Params::ScriptHashGetHistory(convert(params)?),
-            "blockchain.scripthash.listunspent" => Params::ScriptHashListUnspent(convert(params)?),
-            "blockchain.scripthash.subscribe" => Params::ScriptHashSubscribe(convert(params)?),
-            "blockchain.scripthash.unsubscribe" => Params::ScriptHashUnsubscribe(convert(params)?),
-            "blockchain.transaction.broadcast" => Params::TransactionBroadcast(convert(params)?),
-            "blockchain.transaction.broadcast_package" => {
-                Params::TransactionBroadcastPackage(convert(params)?)
-            }
-            "blockchain.transaction.get" => Params::TransactionGet(convert(params)?),
-            "blockchain.transaction.get_merkle" => Params::TransactionGetMerkle(convert(params)?),
-            "blockchain.transaction.id_from_pos" => {
-                Params::TransactionFromPosition(convert(params)?)
-            }
-            "mempool.get_fee_histogram" => Params::MempoolFeeHistogram,
-            "server.banner" => Params::Banner,
-            "server.donation_address" => Params::Donation,
-            "server.features" => Params::Features,
-            "server.peers.subscribe" => Params::PeersSubscribe,
-            "server.ping" => Params::Ping,
-            "server.version" => Params::Version(convert(params)?),
-            _ => {
-                warn!("unknown method {}", method);
-                return Err(StandardError::MethodNotFound);
+    /// Returns `true` if the call is answered quickly, without using the index DB.
+    fn is_cheap(&self) -> bool {
+        matches!(
+            self,
+            Params::Banner
+                | Params::Donation
+                | Params::HeadersSubscribe
+                | Params::PeersSubscribe
+                | Params::Ping
+        )
+    }
+
+    /// Returns `true` if the call modifies the client's state (so it can't run concurrently).
+    fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Params::Authenticate(_)
+                | Params::HeadersSubscribe
+                | Params::ScriptHashSubscribe(_)
+                | Params::ScriptHashSubscribeBulk(_)
+                | Params::ScriptHashUnsubscribe(_)
+                | Params::OutpointSubscribe(_)
+                | Params::OutpointUnsubscribe(_)
+                | Params::TrackBroadcasts
+                | Params::MempoolSubscribeSummary
+                | Params::Version(_)
+        )
+    }
+
+    /// Returns `true` if the call requires a persistent connection (for its notifications).
+    fn is_subscription(&self) -> bool {
+        matches!(
+            self,
+            Params::HeadersSubscribe
+                | Params::ScriptHashSubscribe(_)
+                | Params::ScriptHashSubscribeBulk(_)
+                | Params::ScriptHashUnsubscribe(_)
+                | Params::OutpointSubscribe(_)
+                | Params::OutpointUnsubscribe(_)
+                | Params::TrackBroadcasts
+                | Params::MempoolSubscribeSummary
+        )
+    }
+}
+
+/// Who may call a method (methods which aren't allowed are not advertised either)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    Any,
+    /// Administrative methods, allowed only from localhost
+    Local,
+    /// Allowed only from `block_get_allow` networks
+    BlockGet,
+    /// Enabled only if `server_password` is set
+    Password,
+}
+
+/// A supported method (see [`METHODS`])
+#[derive(Clone, Copy)]
+struct Method {
+    name: &'static str,
+    parse: fn(Value) -> std::result::Result<Params, StandardError>,
+    /// The protocol version which introduced the method (if it is newer than `PROTOCOL_MIN`).
+    /// electrs' own extensions (advertised by `server.features`) are available to all versions.
+    since: Option<&'static str>,
+    /// electrs' protocol extensions provided by the method (advertised by `server.features`)
+    extensions: &'static [&'static str],
+    access: Access,
+}
+
+impl Method {
+    const fn new(
+        name: &'static str,
+        parse: fn(Value) -> std::result::Result<Params, StandardError>,
+    ) -> Self {
+        Self {
+            name,
+            parse,
+            since: None,
+            extensions: &[],
+            access: Access::Any,
+        }
+    }
+
+    const fn since(self, version: &'static str) -> Self {
+        Self {
+            since: Some(version),
+            ..self
+        }
+    }
+
+    const fn extensions(self, extensions: &'static [&'static str]) -> Self {
+        Self { extensions, ..self }
+    }
+
+    const fn access(self, access: Access) -> Self {
+        Self { access, ..self }
+    }
+
+    fn find(name: &str) -> Option<&'static Method> {
+        METHODS.iter().find(|method| method.name == name)
+    }
+}
+
+/// The supported methods, which are advertised by `server.features` (using their `extensions`).
+///
+/// electrs' protocol extensions:
+/// - `tx_pos`: `blockchain.scripthash.get_history` accepts an optional `tx_pos` boolean, adding
+///   confirmed transactions' position within their block, which can be passed as an optional
+///   third argument to `blockchain.transaction.get_merkle`.
+/// - `get_stats`: `blockchain.scripthash.get_stats(scripthash)` returns its usage counts.
+/// - `subscribe_bulk`: `blockchain.scripthash.subscribe_bulk([scripthashes])` subscribes to all the
+///   given scripthashes, returning their statuses (or error objects, for failed subscriptions).
+/// - `get_height`: `blockchain.transaction.get_height(txid)` returns a transaction's `{height}`
+///   (`0` or `-1` if it is unconfirmed), or `null` if it is unknown - using its candidate blocks'
+///   txids, without building a merkle proof. Also accepts an array of (up to 100) txids,
+///   returning an array of results.
+/// - `track_broadcasts`: after `blockchain.transaction.track_broadcasts()`, the transactions
+///   broadcast by the connection are notified once confirmed or evicted, using
+///   `blockchain.transaction.status_changed({txid, status, height?})`.
+/// - `block_get`: `blockchain.block.get(height_or_hash, raw, chunk)` returns a raw block.
+/// - `outpoint_subscribe`: `blockchain.outpoint.subscribe(txid, vout)` returns the outpoint's
+///   spender (`{spender_txid, height}`, or `null` if unspent), notifying its changes using
+///   `blockchain.outpoint.subscribe(txid, vout, spender)`. Also `blockchain.outpoint.get_spender`
+///   and `blockchain.outpoint.unsubscribe`.
+/// - `mempool_summary`: `mempool.subscribe_summary()` returns the mempool's tx count, total vsize,
+///   fee histogram and `mempool_generation`, notifying the histogram bins whose vsize changed
+///   (at most once per sync) using `mempool.subscribe_summary({count, vsize, histogram_changes,
+///   mempool_generation})`.
+/// - `admin`: `electrs.*` administrative methods.
+/// - `status_debug`: `blockchain.scripthash.subscribe(scripthash, true)` and
+///   `blockchain.scripthash.get_history(scripthash, tx_pos, true)` also return the status'
+///   pre-hash string (`preimage`), only for `status_debug_allow` networks (advertised separately,
+///   since these methods are allowed to everyone).
+/// - `authenticate`: `server.authenticate(password)` authenticates the connection.
+const METHODS: &[Method] = &[
+    Method::new("blockchain.block.header", |p| {
+        Ok(Params::BlockHeader(convert(p)?))
+    }),
+    Method::new("blockchain.block.headers", |p| {
+        Ok(Params::BlockHeaders(convert(p)?))
+    }),
+    Method::new("blockchain.block.get", |p| {
+        Ok(Params::BlockGet(convert(p)?))
+    })
+    .extensions(&["block_get"])
+    .access(Access::BlockGet),
+    Method::new("blockchain.estimatefee", |p| {
+        Ok(Params::EstimateFee(convert(p)?))
+    }),
+    Method::new("blockchain.headers.subscribe", |_| {
+        Ok(Params::HeadersSubscribe)
+    }),
+    Method::new("blockchain.outpoint.get_spender", |p| {
+        Ok(Params::OutpointGetSpender(convert(p)?))
+    })
+    .extensions(&["outpoint_subscribe"]),
+    Method::new("blockchain.outpoint.subscribe", |p| {
+        Ok(Params::OutpointSubscribe(convert(p)?))
+    })
+    .extensions(&["outpoint_subscribe"]),
+    Method::new("blockchain.outpoint.unsubscribe", |p| {
+        Ok(Params::OutpointUnsubscribe(convert(p)?))
+    })
+    .extensions(&["outpoint_subscribe"]),
+    Method::new("blockchain.relayfee", |_| Ok(Params::RelayFee)),
+    Method::new("blockchain.scripthash.get_balance", |p| {
+        Ok(Params::ScriptHashGetBalance(convert(p)?))
+    }),
+    Method::new("blockchain.scripthash.get_stats", |p| {
+        Ok(Params::ScriptHashGetStats(convert(p)?))
+    })
+    .extensions(&["get_stats"]),
+    Method::new("blockchain.scripthash.get_history", |p| {
+        Ok(Params::ScriptHashGetHistory(convert(p)?))
+    })
+    .extensions(&["tx_pos"]),
+    Method::new("blockchain.scripthash.listunspent", |p| {
+        Ok(Params::ScriptHashListUnspent(convert(p)?))
+    }),
+    Method::new("blockchain.scripthash.subscribe", |p| {
+        Ok(Params::ScriptHashSubscribe(convert(p)?))
+    }),
+    Method::new("blockchain.scripthash.subscribe_bulk", |p| {
+        Ok(Params::ScriptHashSubscribeBulk(convert(p)?))
+    })
+    .extensions(&["subscribe_bulk"]),
+    Method::new("blockchain.scripthash.unsubscribe", |p| {
+        Ok(Params::ScriptHashUnsubscribe(convert(p)?))
+    })
+    .since("1.4.2"),
+    Method::new("blockchain.transaction.broadcast", parse_broadcast),
+    Method::new("blockchain.transaction.broadcast_package", |p| {
+        Ok(Params::TransactionBroadcastPackage(convert(p)?))
+    }),
+    Method::new("blockchain.transaction.get", |p| {
+        Ok(Params::TransactionGet(convert(p)?))
+    }),
+    Method::new("blockchain.transaction.get_height", |p| match convert(p)? {
+        TxHeightArgs::Txids((txids,)) if txids.len() > MAX_HEIGHT_TXIDS => {
+            warn!("too many txids: {} > {}", txids.len(), MAX_HEIGHT_TXIDS);
+            Err(StandardError::InvalidParams)
+        }
+        args => Ok(Params::TransactionGetHeight(args)),
+    })
+    .extensions(&["get_height"]),
+    Method::new("blockchain.transaction.get_merkle", |p| {
+        Ok(Params::TransactionGetMerkle(convert(p)?))
+    })
+    .extensions(&["tx_pos"]),
+    Method::new("blockchain.transaction.id_from_pos", |p| {
+        Ok(Params::TransactionFromPosition(convert(p)?))
+    }),
+    Method::new("blockchain.transaction.track_broadcasts", |_| {
+        Ok(Params::TrackBroadcasts)
+    })
+    .extensions(&["track_broadcasts"]),
+    Method::new("electrs.bans.clear", |p| Ok(Params::BansClear(convert(p)?)))
+        .extensions(&["admin"])
+        .access(Access::Local),
+    Method::new("electrs.bans.list", |_| Ok(Params::BansList))
+        .extensions(&["admin"])
+        .access(Access::Local),
+    Method::new("electrs.db.compact", |_| Ok(Params::DbCompact))
+        .extensions(&["admin"])
+        .access(Access::Local),
+    Method::new("electrs.db.stats", |_| Ok(Params::DbStats))
+        .extensions(&["admin"])
+        .access(Access::Local),
+    Method::new("electrs.index.status", |_| Ok(Params::IndexStatus))
+        .extensions(&["admin"])
+        .access(Access::Local),
+    Method::new("electrs.reorg.allow", |_| Ok(Params::ReorgAllow))
+        .extensions(&["admin"])
+        .access(Access::Local),
+    Method::new("mempool.get_fee_histogram", |_| {
+        Ok(Params::MempoolFeeHistogram)
+    }),
+    Method::new("mempool.get_info", |_| Ok(Params::MempoolGetInfo)),
+    Method::new("mempool.subscribe_summary", |_| {
+        Ok(Params::MempoolSubscribeSummary)
+    })
+    .extensions(&["mempool_summary"]),
+    Method::new("server.authenticate", |p| {
+        Ok(Params::Authenticate(convert_sensitive(p)?))
+    })
+    .extensions(&["authenticate"])
+    .access(Access::Password),
+    Method::new("server.banner", |_| Ok(Params::Banner)),
+    Method::new("server.donation_address", |_| Ok(Params::Donation)),
+    Method::new("server.features", |_| Ok(Params::Features)),
+    Method::new("server.peers.subscribe", |_| Ok(Params::PeersSubscribe)),
+    Method::new("server.ping", |_| Ok(Params::Ping)),
+    Method::new("server.version", |p| Ok(Params::Version(convert(p)?))),
+];
+
+/// Returns `true` if `name` is a supported method (e.g. for validating `disabled_methods`).
+pub(crate) fn is_method(name: &str) -> bool {
+    Method::find(name).is_some()
+}
+
+/// The extensions of the allowed methods (in `METHODS` order, without duplicates)
+fn extensions(allowed: impl Fn(&Method) -> bool) -> Vec<&'static str> {
+    let mut result = vec![];
+    for method in METHODS.iter().filter(|method| allowed(method)) {
+        for extension in method.extensions {
+            if !result.contains(extension) {
+                result.push(*extension);
             }
-        })
+        }
     }
+    result
 }
 
 struct Call {
     id: Value,
-    method: String,
+    method: &'static Method,
     params: Params,
 }
 
 impl Call {
     fn parse(request: Request) -> Result<Call, Value> {
-        match Params::parse(&request.method, request.params) {
+        let method = match Method::find(&request.method) {
+            Some(method) => method,
+            None => {
+                warn!("unknown method {}", request.method);
+                let err = RpcError::Standard(StandardError::MethodNotFound);
+                return Err(error_msg(&request.id, err));
+            }
+        };
+        match (method.parse)(request.params) {
             Ok(params) => Ok(Call {
                 id: request.id,
-                method: request.method,
+                method,
                 params,
             }),
             Err(e) => Err(error_msg(&request.id, RpcError::Standard(e))),
         }
     }
 
+    /// Subscriptions' notifications can't be sent via a stateless transport (e.g. HTTP).
+    fn needs_persistent_connection(&self) -> Value {
+        let err = anyhow!(
+            "{} requires a persistent connection (e.g. TCP), which HTTP doesn't provide",
+            self.method.name
+        );
+        error_msg(&self.id, RpcError::BadRequest(err))
+    }
+
+    /// Respond as if the method was unknown, since the client's listener disables it.
+    fn disabled(&self) -> Value {
+        debug!("method {} is disabled by the listener", self.method.name);
+        error_msg(&self.id, RpcError::Standard(StandardError::MethodNotFound))
+    }
+
+    /// Respond as if the method was unknown, since the negotiated protocol version is too old.
+    fn not_supported(&self, protocol: &Version) -> Value {
+        debug!(
+            "method {} requires protocol version {} (negotiated {})",
+            self.method.name,
+            self.method.since.unwrap_or(PROTOCOL_MIN),
+            protocol
+        );
+        error_msg(&self.id, RpcError::Standard(StandardError::MethodNotFound))
+    }
+
+    fn reply(&self, result: Result<Reply>) -> Response {
+        match result {
+            Ok(Reply::Streamed(result)) => Response::Result {
+                id: self.id.clone(),
+                result,
+            },
+            Ok(Reply::Value(value)) => self.response(Ok(value)).into(),
+            Err(err) => self.response(Err(err)).into(),
+        }
+    }
+
     fn response(&self, result: Result<Value>) -> Value {
         match result {
             Ok(value) => result_msg(&self.id, value),
             Err(err) => {
-                warn!("RPC {} failed: {:#}", self.method, err);
-                match err
-                    .downcast_ref::<bitcoincore_rpc::Error>()
-                    .and_then(extract_bitcoind_error)
-                {
-                    Some(e) => error_msg(&self.id, RpcError::DaemonError(e.clone())),
-                    None => error_msg(&self.id, RpcError::BadRequest(err)),
-                }
+                warn!(method = self.method.name; "RPC {} failed: {:#}", self.method.name, err);
+                error_msg(&self.id, RpcError::from_error(err))
             }
         }
     }
@@ -751,6 +2399,242 @@ where
     })
 }
 
+/// Moves the transaction's hex out of `params` (instead of copying it like `convert()` does) and
+/// decodes it, so only the decoded transaction is kept until the broadcast runs.
+fn parse_broadcast(params: Value) -> std::result::Result<Params, StandardError> {
+    let (tx_hex,): (String,) = serde_json::from_value(params).map_err(|err| {
+        warn!("invalid broadcast params: {}", err);
+        StandardError::InvalidParams
+    })?;
+    let tx = tx_from_hex(&tx_hex).map_err(|err| err.to_string());
+    Ok(Params::TransactionBroadcast(tx))
+}
+
+/// Like `convert()`, without logging the params (e.g. a password)
+fn convert_sensitive<T>(params: Value) -> std::result::Result<T, StandardError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    serde_json::from_value(params).map_err(|err| {
+        warn!("invalid sensitive params: {}", err);
+        StandardError::InvalidParams
+    })
+}
+
+/// Identical requests (with the same key) may share a single response, if they run concurrently.
+/// Subscribed scripthashes are served from the client's own status, so they are not coalesced.
+fn coalesce_key(client: &Client, params: &Params) -> Option<String> {
+    let unsubscribed = |scripthash: &ScriptHash| !client.scripthashes.contains_key(scripthash);
+    let key = match params {
+        Params::ScriptHashGetBalance((scripthash,)) if unsubscribed(scripthash) => {
+            format!("get_balance:{}", scripthash)
+        }
+        Params::ScriptHashGetStats((scripthash,)) if unsubscribed(scripthash) => {
+            format!("get_stats:{}", scripthash)
+        }
+        Params::ScriptHashGetHistory(args) => {
+            let (scripthash, tx_pos): (ScriptHash, bool) = args.into();
+            if !unsubscribed(&scripthash) || args.status_debug() {
+                return None;
+            }
+            format!("get_history:{}:{}", scripthash, tx_pos)
+        }
+        Params::ScriptHashListUnspent((scripthash,)) if unsubscribed(scripthash) => {
+            format!("listunspent:{}", scripthash)
+        }
+        Params::TransactionGet(args) => {
+            let (txid, verbose): (Txid, bool) = args.into();
+            format!("get:{}:{}", txid, verbose)
+        }
+        Params::TransactionGetHeight(TxHeightArgs::Txid((txid,))) => {
+            format!("get_height:{}", txid)
+        }
+        Params::TransactionGetMerkle(args) => {
+            let (txid, height, tx_pos): (Txid, usize, Option<usize>) = args.into();
+            format!("get_merkle:{}:{}:{:?}", txid, height, tx_pos)
+        }
+        Params::TransactionFromPosition((height, tx_pos, merkle)) => {
+            format!("id_from_pos:{}:{}:{}", height, tx_pos, merkle)
+        }
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Complete `call` using `compute` (which shares its reply), unless an identical call (with the
+/// same key, see `coalesce_key`) is in flight: `call` is then completed with its reply when it
+/// is ready (and `observe`d), without blocking the calling worker meanwhile - the worker may be
+/// needed to complete the shared call.
+fn coalesce(
+    coalescer: &Coalescer<(Generations, String), Reply>,
+    key: (Generations, String),
+    call: Deferred<Call, Outgoing>,
+    observe: impl FnOnce(&str, Duration) + Send + 'static,
+    compute: impl FnOnce(Call, &mut dyn FnMut(&Reply)) -> Response,
+) {
+    let label = call.item().method.name;
+    let start = Instant::now();
+    let wait = move |call: Deferred<Call, Outgoing>, reply: Option<Reply>| match reply {
+        Some(reply) => call.complete(|call| {
+            observe(call.method.name, start.elapsed());
+            call.reply(Ok(reply)).into_outgoing()
+        }),
+        None => call.retry(), // the shared call failed
+    };
+    if let Join::Lead(mut leader, call) = coalescer.join(key, label, call, wait) {
+        call.complete(|call| compute(call, &mut |reply| leader.share(reply)).into_outgoing());
+    }
+}
+
+/// Flag a response with the sync progress during initial sync (so clients can tell the indexed
+/// tip is not bitcoind's best block yet).
+fn add_sync_progress(value: &mut Value, sync_target: Option<usize>) {
+    if let Some(target_height) = sync_target {
+        value["syncing"] = json!(true);
+        value["target_height"] = json!(target_height);
+    }
+}
+
+/// The indexed tip's header, as sent to clients subscribed to headers.
+pub(crate) fn subscribed_tip(chain: &Chain, sync_target: Option<usize>) -> (SubscribedTip, Value) {
+    let tip_header = chain.tip_header();
+    let mut header = tip_header.to_json();
+    add_sync_progress(&mut header, sync_target);
+    let tip = SubscribedTip {
+        hash: tip_header.hash(),
+        syncing: sync_target.is_some(),
+    };
+    (tip, header)
+}
+
+/// Merkle branch and root of a header within the block hashes up to `cp_height` (hex-encoded)
+fn header_proof(chain: &Chain, height: usize, cp_height: usize) -> Result<(Vec<String>, String)> {
+    match chain.header_proof(height, cp_height) {
+        Some((proof, root)) => Ok((proof.to_hex(), format!("{:x}", root))),
+        None => bail!(
+            "header height {} must be <= cp_height {} which must be <= chain height {}",
+            height,
+            cp_height,
+            chain.height()
+        ),
+    }
+}
+
+/// A serialized block's `chunk`-th part (hex-encoded), and the number of chunks to request
+fn block_chunk(block: &[u8], chunk: usize) -> Result<Value> {
+    let chunks = block.len().div_ceil(BLOCK_CHUNK_SIZE);
+    match block.chunks(BLOCK_CHUNK_SIZE).nth(chunk) {
+        None => bail!("chunk {} must be < {}", chunk, chunks),
+        Some(data) => Ok(json!({
+            "size": block.len(),
+            "chunk": chunk,
+            "chunks": chunks,
+            "hex": data.to_lower_hex_string(),
+        })),
+    }
+}
+
+/// A call's result (large results are serialized only while they are sent, see `Outgoing`)
+#[derive(Clone)]
+enum Reply {
+    Value(Value),
+    Streamed(Outgoing), // shared by coalesced calls (without copying its items)
+}
+
+impl Reply {
+    /// A JSON array, whose elements are serialized while it is sent
+    fn array(items: Arc<dyn Items>) -> Self {
+        let mut json = Outgoing::default();
+        json.push_str("[");
+        json.push_items(items);
+        json.push_str("]");
+        Reply::Streamed(json)
+    }
+}
+
+impl From<Value> for Reply {
+    fn from(value: Value) -> Self {
+        Reply::Value(value)
+    }
+}
+
+/// A JSON-RPC response (or a batch of responses)
+enum Response {
+    Value(Value),
+    Result { id: Value, result: Outgoing },
+    Batch(Vec<Response>),
+}
+
+impl From<Value> for Response {
+    fn from(value: Value) -> Self {
+        Response::Value(value)
+    }
+}
+
+impl Response {
+    fn into_outgoing(self) -> Outgoing {
+        match self {
+            Response::Value(value) => value.to_string().into(),
+            Response::Result { id, result } => {
+                let mut msg = Outgoing::from(format!(r#"{{"jsonrpc":"2.0","id":{},"result":"#, id));
+                msg.append(result);
+                msg.push_str("}");
+                msg
+            }
+            Response::Batch(responses) => {
+                let mut msg = Outgoing::from("[".to_owned());
+                for (i, response) in responses.into_iter().enumerate() {
+                    if i > 0 {
+                        msg.push_str(",");
+                    }
+                    msg.append(response.into_outgoing());
+                }
+                msg.push_str("]");
+                msg
+            }
+        }
+    }
+
+    /// Serialize the whole response (e.g. for an HTTP response)
+    fn into_json(self) -> String {
+        self.into_outgoing().into_string()
+    }
+}
+
+/// History entries, serialized with their positions (see `HistoryArgs`)
+struct WithPos(Arc<[HistoryEntry]>);
+
+impl Items for WithPos {
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn write(&self, index: usize, buf: &mut Vec<u8>) {
+        write_element(&self.0[index].with_pos(), index, buf)
+    }
+}
+
+/// `blockchain.block.headers` hex, serialized in batches of `HEADERS_PER_ITEM` headers
+struct HeadersHex {
+    chain: Arc<Chain>,
+    heights: Range<usize>,
+}
+
+const HEADERS_PER_ITEM: usize = 100;
+
+impl Items for HeadersHex {
+    fn count(&self) -> usize {
+        self.heights.len().div_ceil(HEADERS_PER_ITEM)
+    }
+
+    fn write(&self, index: usize, buf: &mut Vec<u8>) {
+        let start = self.heights.start + index * HEADERS_PER_ITEM;
+        let end = std::cmp::min(start + HEADERS_PER_ITEM, self.heights.end);
+        let hex = self.chain.get_block_headers_hex(start..end);
+        buf.extend_from_slice(hex.as_bytes());
+    }
+}
+
 fn notification(method: &str, params: &[Value]) -> Value {
     json!({"jsonrpc": "2.0", "method": method, "params": params})
 }
@@ -763,6 +2647,66 @@ fn error_msg(id: &Value, error: RpcError) -> Value {
     json!({"jsonrpc": "2.0", "id": id, "error": error.to_value()})
 }
 
+/// Sent to clients which are refused due to `max_connections` limit
+pub(crate) fn server_full_msg() -> Value {
+    error_msg(&Value::Null, RpcError::ServerFull)
+}
+
+/// The connection's first line is taken as the password (see `server_password`), unless it's a
+/// JSON-RPC request (or batch).
+fn is_password_line(line: &str) -> bool {
+    !matches!(line.trim_start().chars().next(), Some('{' | '['))
+}
+
+/// Returns `true` if the received line may contain the password (so it must not be logged).
+pub(crate) fn is_sensitive_request(line: &str, first_line: bool) -> bool {
+    (first_line && is_password_line(line)) || line.contains("server.authenticate")
+}
+
+/// Broadcast requests may exceed `max_request_size_mb` (up to `max_broadcast_size_mb`)
+///
+/// `prefix` may be truncated (so a large request is checked before it is received entirely),
+/// in which case its method must precede the truncation point.
+pub(crate) fn is_broadcast_request(prefix: &[u8]) -> bool {
+    use serde::de::{self, Deserializer as _, IgnoredAny, MapAccess, Visitor};
+
+    // stores the request's method, stopping at the first (possibly truncated) key after it
+    struct MethodVisitor<'a>(&'a mut Option<String>);
+
+    impl<'de> Visitor<'de> for MethodVisitor<'_> {
+        type Value = ();
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a JSON-RPC request")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+            while let Some(key) = map.next_key::<String>()? {
+                if key == "method" {
+                    *self.0 = Some(map.next_value()?);
+                    return Ok(());
+                }
+                map.next_value::<IgnoredAny>()?; // e.g. "id" or "jsonrpc"
+            }
+            Err(de::Error::missing_field("method"))
+        }
+    }
+
+    let mut method = None;
+    // fails on the truncated (or skipped) rest of the request, after the method is stored
+    let mut deserializer = serde_json::Deserializer::from_slice(prefix);
+    let _ = deserializer.deserialize_map(MethodVisitor(&mut method));
+    matches!(
+        method.as_deref(),
+        Some("blockchain.transaction.broadcast" | "blockchain.transaction.broadcast_package")
+    ) // a batch is not a broadcast request
+}
+
+/// Sent to clients whose request exceeds `max_request_size_mb` (before disconnecting them)
+pub(crate) fn parse_error_msg() -> Value {
+    error_msg_no_id(StandardError::ParseError)
+}
+
 fn error_msg_no_id(err: StandardError) -> Value {
     error_msg(&Value::Null, RpcError::Standard(err))
 }
@@ -772,7 +2716,13 @@ fn parse_requests(line: &str) -> Result<Requests, StandardError> {
         // parse JSON from str
         Ok(value) => match serde_json::from_value(value) {
             // parse RPC from JSON
-            Ok(requests) => Ok(requests),
+            Ok(requests) => match Requests::check(&requests) {
+                Ok(()) => Ok(requests),
+                Err(reason) => {
+                    warn!("invalid RPC request ({} bytes): {}", line.len(), reason);
+                    Err(StandardError::InvalidRequest)
+                }
+            },
             Err(err) => {
                 warn!("invalid RPC request ({:?}): {}", line, err);
                 Err(StandardError::InvalidRequest)
@@ -785,6 +2735,53 @@ fn parse_requests(line: &str) -> Result<Requests, StandardError> {
     }
 }
 
+/// Parses a received line like `Rpc::handle_requests()` (used by the `request_parser` fuzz target)
+#[cfg(fuzzing)]
+pub fn fuzz_request(data: &[u8]) {
+    if let Ok(line) = std::str::from_utf8(data) {
+        is_sensitive_request(line, true);
+        is_broadcast_request(data);
+        let _ = parse_requests(line).map(Calls::parse);
+    }
+}
+
+/// A synthetic history of `count` confirmed entries (used by `tests/memory.rs`)
+#[cfg(any(test, feature = "bench"))]
+pub fn bench_history(count: usize) -> Arc<[HistoryEntry]> {
+    use bitcoin::hashes::Hash;
+
+    (0..count)
+        .map(|i| HistoryEntry::confirmed(Txid::hash(&i.to_le_bytes()), i / 10, i % 10))
+        .collect()
+}
+
+/// Send a `blockchain.scripthash.get_history` response to `out`, like a peer's outbox (used by
+/// `tests/memory.rs`)
+#[cfg(feature = "bench")]
+pub fn bench_history_response(
+    history: Arc<[HistoryEntry]>,
+    out: &mut dyn std::io::Write,
+) -> std::io::Result<()> {
+    let call = Call {
+        id: json!(1),
+        method: Method::find("blockchain.scripthash.get_history").expect("missing method"),
+        params: Params::Banner, // unused
+    };
+    let mut msg = call
+        .reply(Ok(Reply::array(Arc::new(history))))
+        .into_outgoing();
+    msg.push_str("\n");
+    msg.for_each_chunk(crate::shaper::CHUNK_SIZE, |chunk| out.write_all(chunk))
+}
+
+/// Returned by `server.version` and `server.features` (e.g. "electrs/0.11.0-2f39ceb1a2b3")
+fn server_id() -> String {
+    match ELECTRS_GIT_COMMIT {
+        "unknown" => format!("electrs/{}", ELECTRS_VERSION),
+        commit => format!("electrs/{}-{}", ELECTRS_VERSION, commit),
+    }
+}
+
 fn parse_version(version: &str) -> Result<Version> {
     let result = version
         .split('.')
@@ -793,7 +2790,7 @@ fn parse_version(version: &str) -> Result<Version> {
     Ok(Version(result))
 }
 
-#[derive(PartialOrd, PartialEq, Debug)]
+#[derive(Clone, PartialOrd, Ord, PartialEq, Eq, Debug)]
 struct Version(Vec<usize>);
 
 impl fmt::Display for Version {
@@ -821,15 +2818,31 @@ fn check_between(version_str: &str, min_str: &str, max_str: &str) -> Result<()>
     Ok(())
 }
 
+/// The highest protocol version supported by both electrs and the client (requesting the given
+/// range), e.g. negotiating 1.4.2 when the client supports 1.4 up to 1.6.
+fn negotiate_version(min_str: &str, max_str: &str) -> Result<Version> {
+    let version = parse_version(max_str)?.min(parse_version(PROTOCOL_MAX)?);
+    let version_str = version.to_string();
+    check_between(&version_str, min_str, max_str)?;
+    check_between(&version_str, PROTOCOL_MIN, PROTOCOL_MAX)?;
+    Ok(version)
+}
+
+/// Decode the transaction directly from its hex (without allocating an intermediate buffer).
 fn tx_from_hex(tx_hex: &str) -> Result<Transaction> {
-    let tx_bytes = Vec::from_hex(tx_hex).context("non-hex transaction")?;
-    let tx = deserialize(&tx_bytes).context("invalid transaction")?;
-    Ok(tx)
+    deserialize_hex(tx_hex).map_err(|err| match err {
+        FromHexError::OddLengthString(_) | FromHexError::Decode(DecodeError::Other(_)) => {
+            anyhow!(err).context("non-hex transaction")
+        }
+        FromHexError::Decode(_) => anyhow!(err).context("invalid transaction"),
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stub::{Failure, StubNode};
+    use bitcoin::{absolute::LockTime, hashes::Hash, transaction::Version as TxVersion, Network};
 
     #[test]
     fn test_version() {
@@ -840,6 +2853,17 @@ mod tests {
         assert!(parse_version("1.2").unwrap() < parse_version("1.100").unwrap());
     }
 
+    #[test]
+    fn test_server_id() {
+        let id = server_id();
+        let version = id.strip_prefix("electrs/").unwrap();
+        let (release, commit) = version.rsplit_once('-').unwrap_or((version, "unknown"));
+        assert_eq!(release, ELECTRS_VERSION);
+        assert!(parse_version(release).is_ok());
+        assert_eq!(commit, ELECTRS_GIT_COMMIT);
+        assert!(commit == "unknown" || commit.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
     #[test]
     fn test_between() {
         assert!(check_between("1.4", "1.4", "1.4").is_ok());
@@ -853,6 +2877,396 @@ mod tests {
         assert!(check_between("1.4", "1", "1").is_err());
     }
 
+    #[test]
+    fn test_negotiate_version() {
+        let negotiate = |min, max| negotiate_version(min, max).map(|v| v.to_string());
+        assert_eq!(negotiate("1.4", "1.4").unwrap(), "1.4");
+        assert_eq!(negotiate("1.4.1", "1.4.1").unwrap(), "1.4.1");
+        assert_eq!(negotiate("1.4", "1.4.2").unwrap(), "1.4.2");
+        assert_eq!(negotiate("1.2", "1.6").unwrap(), "1.4.2");
+
+        assert!(negotiate("1.2", "1.3").is_err());
+        assert!(negotiate("1.4.3", "1.6").is_err());
+        assert!(negotiate("1.5", "1.5").is_err());
+        assert!(negotiate("1.4", "x").is_err());
+    }
+
+    #[test]
+    fn test_version_dispatch() {
+        let call = |method: &str| {
+            let params = json!(["0".repeat(64)]);
+            let request = Request {
+                id: json!(1),
+                method: method.to_owned(),
+                params,
+            };
+            Call::parse(request).ok().unwrap()
+        };
+        let unsubscribe = call("blockchain.scripthash.unsubscribe");
+        let balance = call("blockchain.scripthash.get_balance");
+        let not_found = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {"code": -32601, "message": "method not found"},
+        });
+
+        // requests sent before `server.version` may use any method
+        let mut client = Client::new(0, None, Subscriptions::default(), Arc::default());
+        assert_eq!(client.protocol().to_string(), PROTOCOL_MIN);
+        assert!(client.supports(balance.method));
+        assert!(client.supports(unsubscribe.method));
+        assert_eq!(unsubscribe.not_supported(&client.protocol()), not_found);
+
+        let negotiate = |client: &mut Client, min: &str, max: &str| {
+            let request = VersionRequest::MinMax(min.to_owned(), max.to_owned());
+            client.negotiate(&request).map(|v| v.to_string())
+        };
+        assert_eq!(negotiate(&mut client, "1.4", "1.4.1").unwrap(), "1.4.1");
+        assert!(client.supports(balance.method));
+        assert!(!client.supports(unsubscribe.method));
+
+        assert_eq!(negotiate(&mut client, "1.4", "1.6").unwrap(), "1.4.2");
+        assert!(client.supports(balance.method));
+        assert!(client.supports(unsubscribe.method));
+
+        let single = VersionRequest::Single("1.4".to_owned());
+        assert_eq!(client.negotiate(&single).unwrap().to_string(), "1.4");
+        assert!(!client.supports(unsubscribe.method));
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut names: Vec<&str> = METHODS.iter().map(|method| method.name).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), METHODS.len());
+        assert!(Method::find("server.features").is_some());
+        assert!(Method::find("server.unknown").is_none());
+
+        // the default config (no `block_get_allow` networks and no `server_password`)
+        let advertised = |addr: Option<&str>| {
+            let addr = addr.map(|addr| addr.parse().unwrap());
+            let client = Client::new(0, addr, Subscriptions::default(), Arc::default());
+            extensions(|method| client.allows(method, false))
+        };
+        assert_eq!(
+            advertised(Some("1.2.3.4:50001")),
+            [
+                "outpoint_subscribe",
+                "get_stats",
+                "tx_pos",
+                "subscribe_bulk",
+                "get_height",
+                "track_broadcasts",
+                "mempool_summary"
+            ]
+        );
+        assert_eq!(
+            advertised(Some("127.0.0.1:50001")),
+            [
+                "outpoint_subscribe",
+                "get_stats",
+                "tx_pos",
+                "subscribe_bulk",
+                "get_height",
+                "track_broadcasts",
+                "mempool_summary",
+                "admin"
+            ]
+        );
+        assert_eq!(advertised(None), advertised(Some("1.2.3.4:50001")));
+
+        let policy = ListenerPolicy {
+            block_get_allow: vec!["10.0.0.0/8".parse().unwrap()],
+            ..ListenerPolicy::default()
+        };
+        let client = Client::new(
+            0,
+            Some("10.1.2.3:50001".parse().unwrap()),
+            Subscriptions::default(),
+            Arc::new(policy),
+        );
+        assert_eq!(
+            extensions(|method| client.allows(method, true)),
+            [
+                "block_get",
+                "outpoint_subscribe",
+                "get_stats",
+                "tx_pos",
+                "subscribe_bulk",
+                "get_height",
+                "track_broadcasts",
+                "mempool_summary",
+                "authenticate"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disabled_methods() {
+        // two local listeners, disabling different methods
+        let client = |port: u16, disabled: &[&str], admin: bool| {
+            let policy = ListenerPolicy {
+                disabled_methods: disabled.iter().map(|name| name.to_string()).collect(),
+                admin,
+                ..ListenerPolicy::default()
+            };
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            Client::new(0, Some(addr), Subscriptions::default(), Arc::new(policy))
+        };
+        let wallets = client(50001, &["blockchain.scripthash.get_stats"], true);
+        let public = client(
+            50002,
+            &[
+                "blockchain.transaction.track_broadcasts",
+                "mempool.subscribe_summary",
+            ],
+            false,
+        );
+
+        let method = |name| Method::find(name).unwrap();
+        let get_stats = method("blockchain.scripthash.get_stats");
+        let track = method("blockchain.transaction.track_broadcasts");
+        let bans = method("electrs.bans.list");
+        assert!(!wallets.allows(get_stats, false));
+        assert!(wallets.allows(track, false));
+        assert!(wallets.allows(bans, false));
+        assert!(public.allows(get_stats, false));
+        assert!(!public.allows(track, false));
+        assert!(!public.allows(bans, false)); // `admin = false`
+
+        // the disabled methods' extensions are not advertised
+        assert_eq!(
+            extensions(|method| wallets.allows(method, false)),
+            [
+                "outpoint_subscribe",
+                "tx_pos",
+                "subscribe_bulk",
+                "get_height",
+                "track_broadcasts",
+                "mempool_summary",
+                "admin"
+            ]
+        );
+        assert_eq!(
+            extensions(|method| public.allows(method, false)),
+            [
+                "outpoint_subscribe",
+                "get_stats",
+                "tx_pos",
+                "subscribe_bulk",
+                "get_height"
+            ]
+        );
+
+        // and they are answered as unknown methods
+        let request = Request {
+            id: json!(1),
+            method: "blockchain.transaction.track_broadcasts".to_owned(),
+            params: json!([]),
+        };
+        let call = Call::parse(request).ok().unwrap();
+        assert_eq!(
+            call.disabled(),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "error": {"code": -32601, "message": "method not found"},
+            })
+        );
+        assert!(is_method("blockchain.transaction.track_broadcasts"));
+        assert!(!is_method("blockchain.transaction.unknown"));
+    }
+
+    #[test]
+    fn test_authentication() {
+        let call = |method: &str, params| {
+            let request = Request {
+                id: json!(1),
+                method: method.to_owned(),
+                params,
+            };
+            Call::parse(request).ok().unwrap()
+        };
+        let authenticate = call("server.authenticate", json!(["hunter2"]));
+        let balance = call("blockchain.scripthash.get_balance", json!(["0".repeat(64)]));
+        assert!(authenticate.params.is_mutating()); // handled in order, before the next calls
+        let request = Request {
+            id: json!(1),
+            method: "server.authenticate".to_owned(),
+            params: json!([42]),
+        };
+        assert!(Call::parse(request).is_err());
+
+        // disabled by default
+        let mut client = Client::new(0, None, Subscriptions::default(), Arc::default());
+        assert!(client.authorize(None, &balance.params));
+        client.authenticate(None, "anything").unwrap();
+        assert_eq!(client.auth_failures, 0);
+
+        let password = SensitivePassword::new("hunter2");
+        let password = Some(&password);
+
+        // wrong passwords and calls made before authenticating are counted
+        let mut client = Client::new(0, None, Subscriptions::default(), Arc::default());
+        assert!(client.authorize(password, &authenticate.params));
+        assert!(!client.authorize(password, &balance.params));
+        assert_eq!(client.auth_failures, 1);
+        let err = client.authenticate(password, "hunter3").unwrap_err();
+        assert_eq!(err.to_string(), "invalid password");
+        assert_eq!(client.auth_failures, 2);
+        assert!(!client.auth_failed()); // the connection is still open
+        assert!(!client.authorize(password, &balance.params));
+        assert_eq!(client.auth_failures, MAX_AUTH_FAILURES);
+        assert!(client.auth_failed()); // the connection is closed
+
+        // after authenticating, the calls are handled as usual
+        let mut client = Client::new(0, None, Subscriptions::default(), Arc::default());
+        client.authenticate(password, "hunter").unwrap_err();
+        client.authenticate(password, "hunter2").unwrap();
+        for _ in 0..MAX_AUTH_FAILURES {
+            assert!(client.authorize(password, &balance.params));
+        }
+        assert_eq!(client.auth_failures, 1);
+        assert!(!client.auth_failed());
+
+        assert_eq!(
+            error_msg(&json!(1), RpcError::AuthenticationRequired),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "error": {"code": -32001, "message": "authentication required"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_sensitive_requests() {
+        // the password may be sent as the first line
+        assert!(is_password_line("hunter2"));
+        assert!(is_password_line("12345"));
+        assert!(!is_password_line(
+            r#"{"id":1,"method":"server.ping","params":[]}"#
+        ));
+        assert!(!is_password_line(
+            r#" [{"id":1,"method":"server.ping","params":[]}]"#
+        ));
+        assert!(is_sensitive_request("hunter2", true));
+        assert!(!is_sensitive_request("hunter2", false)); // an invalid request
+        let request = r#"{"id":1,"method":"server.authenticate","params":["hunter2"]}"#;
+        assert!(is_sensitive_request(request, true));
+        assert!(is_sensitive_request(request, false));
+        let request = r#"{"id":1,"method":"server.ping","params":[]}"#;
+        assert!(!is_sensitive_request(request, true));
+    }
+
+    #[test]
+    fn test_coalesced_calls() {
+        const CALLS: u64 = 50;
+        let request = |id: u64, params| {
+            let request = Request {
+                id: json!(id),
+                method: "blockchain.transaction.get".to_owned(),
+                params,
+            };
+            Call::parse(request).unwrap()
+        };
+        let client = Client::new(0, None, Subscriptions::default(), Arc::default());
+        let txid = "0".repeat(64);
+        let call_key = |call: &Call| coalesce_key(&client, &call.params).unwrap();
+        // the default arguments are coalesced with the explicit ones
+        let get = call_key(&request(0, json!([txid])));
+        assert_eq!(get, call_key(&request(0, json!([txid, false]))));
+        assert_ne!(get, call_key(&request(0, json!([txid, true]))));
+
+        let mut calls: Vec<Call> = (0..CALLS).map(|id| request(id, json!([txid]))).collect();
+        calls.push(request(CALLS, json!([txid, true])));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let coalescer = Coalescer::new(&metrics);
+        let computed = AtomicUsize::new(0);
+        // two workers (the calling thread only waits for responses)
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        let mut responses = vec![];
+        pool.install(|| {
+            crate::thread::unordered_map(
+                calls,
+                usize::MAX,
+                |call| {
+                    let key = (Generations::default(), call_key(call.item()));
+                    coalesce(
+                        &coalescer,
+                        key,
+                        call,
+                        |_, _| (),
+                        |call, share| {
+                            computed.fetch_add(1, Ordering::SeqCst);
+                            let reply = Reply::from(json!(call_key(&call)));
+                            if call.id != json!(CALLS) {
+                                std::thread::sleep(Duration::from_millis(500));
+                            }
+                            share(&reply);
+                            call.reply(Ok(reply))
+                        },
+                    )
+                },
+                |response| {
+                    let response: Value = serde_json::from_str(&response.into_string())?;
+                    responses.push(response);
+                    Ok(())
+                },
+            )
+        })
+        .unwrap();
+        // the waiting calls don't block a worker, so the other call isn't delayed by them
+        assert_eq!(responses[0]["id"], json!(CALLS));
+        // a single computation is shared by the identical calls
+        assert_eq!(computed.load(Ordering::SeqCst), 2);
+        let mut ids: Vec<u64> = responses
+            .iter()
+            .map(|r| r["id"].as_u64().unwrap())
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (0..=CALLS).collect::<Vec<u64>>());
+        assert!(responses[1..].iter().all(|r| r["result"] == json!(get)));
+    }
+
+    #[test]
+    fn test_stateless_subscriptions() {
+        let call = |method: &str, params| {
+            let request = Request {
+                id: json!(1),
+                method: method.to_owned(),
+                params,
+            };
+            Call::parse(request).ok().unwrap()
+        };
+        let scripthash = json!(["0".repeat(64)]);
+        let subscribe = call("blockchain.scripthash.subscribe", scripthash.clone());
+        assert!(subscribe.params.is_subscription());
+        assert!(call("blockchain.headers.subscribe", json!([]))
+            .params
+            .is_subscription());
+        assert!(!call("blockchain.scripthash.get_balance", scripthash)
+            .params
+            .is_subscription());
+        let outpoint = json!(["0".repeat(64), 0]);
+        assert!(call("blockchain.outpoint.subscribe", outpoint.clone())
+            .params
+            .is_subscription());
+        assert!(!call("blockchain.outpoint.get_spender", outpoint)
+            .params
+            .is_subscription());
+
+        let response = subscribe.needs_persistent_connection();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"]["code"], 1);
+        let msg = response["error"]["message"].as_str().unwrap();
+        assert!(msg.starts_with("blockchain.scripthash.subscribe requires a persistent connection"));
+    }
+
     #[test]
     fn test_requests() {
         assert!(matches!(
@@ -867,4 +3281,355 @@ mod tests {
         assert!(parse_requests(r#"{"id":1,"method":"name","params":[],"unrelated":42}"#).is_ok());
         assert!(parse_requests(r#" { "id" : 1 , "method" : "name" , "params" : [ ] } "#).is_ok());
     }
+
+    #[test]
+    fn test_malformed_requests() {
+        let invalid = |request: Value| {
+            matches!(
+                parse_requests(&request.to_string()),
+                Err(StandardError::InvalidRequest)
+            )
+        };
+        let longest = METHODS.iter().map(|method| method.name.len()).max();
+        assert!(longest.unwrap() <= MAX_METHOD_LEN);
+        assert!(!invalid(
+            json!({"id": 1, "method": "x".repeat(MAX_METHOD_LEN)})
+        ));
+        assert!(invalid(
+            json!({"id": 1, "method": "x".repeat(MAX_METHOD_LEN + 1)})
+        ));
+
+        let params: Vec<usize> = (0..=MAX_PARAMS).collect();
+        let named: serde_json::Map<String, Value> =
+            params.iter().map(|i| (i.to_string(), json!(i))).collect();
+        assert!(!invalid(
+            json!({"id": 1, "method": "m", "params": params[1..]})
+        ));
+        assert!(invalid(json!({"id": 1, "method": "m", "params": params})));
+        assert!(invalid(json!({"id": 1, "method": "m", "params": named})));
+        // a single invalid request fails the whole batch
+        let batch = json!([{"id": 1, "method": "m"}, {"id": 2, "method": "m", "params": params}]);
+        assert!(invalid(batch));
+    }
+
+    #[test]
+    fn test_random_requests() {
+        use bitcoin::secp256k1::rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let valid = r#"{"id": 1, "method": "blockchain.scripthash.get_history", "params": ["00"]}"#;
+        for _ in 0..10_000 {
+            // random noise, or a valid request having some of its bytes replaced
+            let mut data: Vec<u8> = match rng.gen_bool(0.5) {
+                true => (0..rng.gen_range(0..100)).map(|_| rng.gen()).collect(),
+                false => valid.bytes().collect(),
+            };
+            for _ in 0..rng.gen_range(0..4) {
+                let i = rng.gen_range(0..data.len().max(1));
+                if let Some(b) = data.get_mut(i) {
+                    *b = rng.gen();
+                }
+            }
+            let line = match std::str::from_utf8(&data) {
+                Ok(line) => line,
+                Err(_) => continue, // rejected by the server before being parsed
+            };
+            is_sensitive_request(line, true);
+            is_broadcast_request(line.as_bytes());
+            if let Ok(requests) = parse_requests(line) {
+                Calls::parse(requests);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tx_from_hex() {
+        let tx = Transaction {
+            version: TxVersion::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        };
+        let tx_hex = serialize_hex(&tx);
+        assert_eq!(tx_from_hex(&tx_hex).unwrap(), tx);
+
+        let err = |tx_hex: &str| tx_from_hex(tx_hex).unwrap_err().to_string();
+        assert_eq!(err("0"), "non-hex transaction");
+        assert_eq!(err("xy"), "non-hex transaction");
+        assert_eq!(err("0200"), "invalid transaction");
+        assert_eq!(err(&format!("{}00", tx_hex)), "invalid transaction"); // trailing data
+    }
+
+    #[test]
+    fn test_parse_broadcast() {
+        let tx = Transaction {
+            version: TxVersion::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        };
+        let parse = |params: Value| match parse_broadcast(params) {
+            Ok(Params::TransactionBroadcast(tx)) => Some(tx),
+            Ok(_) => unreachable!(),
+            Err(_) => None, // invalid params
+        };
+        assert_eq!(parse(json!([serialize_hex(&tx)])), Some(Ok(tx)));
+        assert_eq!(
+            parse(json!(["0200"])),
+            Some(Err("invalid transaction".into()))
+        );
+        assert_eq!(parse(json!([])), None);
+        assert_eq!(parse(json!([1])), None);
+    }
+
+    #[test]
+    fn test_is_broadcast_request() {
+        let request = |method: &str| {
+            json!({"id": 0, "method": method, "params": ["00".repeat(100)]}).to_string()
+        };
+        let broadcast = request("blockchain.transaction.broadcast");
+        assert!(is_broadcast_request(broadcast.as_bytes()));
+        assert!(is_broadcast_request(
+            request("blockchain.transaction.broadcast_package").as_bytes()
+        ));
+        assert!(!is_broadcast_request(
+            request("blockchain.transaction.get").as_bytes()
+        ));
+        let batch = format!("[{}]", broadcast);
+        assert!(!is_broadcast_request(batch.as_bytes()));
+        assert!(!is_broadcast_request(b"foo"));
+
+        // a truncated request is checked if its method is received
+        assert!(is_broadcast_request(&broadcast.as_bytes()[..100]));
+        let method_end = broadcast.find("broadcast").unwrap() + 3;
+        assert!(!is_broadcast_request(&broadcast.as_bytes()[..method_end]));
+        let params_first = r#"{"params": ["00"], "method": "blockchain.transaction.broadcast"}"#;
+        assert!(is_broadcast_request(params_first.as_bytes()));
+        assert!(!is_broadcast_request(&params_first.as_bytes()[..20]));
+    }
+
+    #[test]
+    fn test_broadcast_rejections() {
+        let node = StubNode::new(Network::Regtest);
+        let tx = Transaction {
+            version: TxVersion::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        };
+        let call = Call {
+            id: json!(7),
+            method: Method::find("blockchain.transaction.broadcast").unwrap(),
+            params: Params::TransactionBroadcast(Ok(tx.clone())),
+        };
+        let cases = [
+            ("tx-size", 10),
+            ("min relay fee not met, 100 < 141", 11),
+            ("mempool min fee not met, 150 < 1024", 11),
+            ("txn-mempool-conflict", 12),
+            (
+                "too-long-mempool-chain, too many unconfirmed ancestors [limit: 25]",
+                13,
+            ),
+            (
+                "non-mandatory-script-verify-flag (Signature must be zero)",
+                2,
+            ),
+        ];
+        for (message, code) in cases {
+            node.fail("sendrawtransaction", Failure::Rejected(message));
+            let err = node.broadcast(&tx).unwrap_err();
+            assert_eq!(
+                call.response(Err(err)),
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": 7,
+                    "error": {"code": code, "message": message},
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_block_get() {
+        // access control (disabled by default)
+        let nets: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()];
+        let client = |addr: Option<&str>| {
+            let addr = addr.map(|addr| addr.parse().unwrap());
+            Client::new(0, addr, Subscriptions::default(), Arc::default())
+        };
+        assert!(client(Some("10.1.2.3:50001")).is_in(&nets));
+        assert!(client(Some("[::1]:50001")).is_in(&nets));
+        assert!(!client(Some("127.0.0.1:50001")).is_in(&nets));
+        assert!(!client(Some("10.1.2.3:50001")).is_in(&[]));
+        assert!(!client(None).is_in(&nets));
+
+        let args = |params| {
+            let (block, raw, chunk) = (&convert::<BlockGetArgs>(params).ok().unwrap()).into();
+            let block = match block {
+                BlockId::Height(height) => json!(height),
+                BlockId::Hash(blockhash) => json!(blockhash),
+            };
+            (block, raw, chunk)
+        };
+        let hash = "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+        assert_eq!(args(json!([5])), (json!(5), true, 0));
+        assert_eq!(args(json!([hash, true])), (json!(hash), true, 0));
+        assert_eq!(args(json!([5, true, 3])), (json!(5), true, 3));
+        assert!(convert::<BlockGetArgs>(json!(["0f91"])).is_err());
+
+        // large blocks are reassembled from their chunks
+        let block: Vec<u8> = (0..4 * BLOCK_CHUNK_SIZE + 1000).map(|i| i as u8).collect();
+        let chunks = block_chunk(&block, 0).unwrap()["chunks"].as_u64().unwrap() as usize;
+        assert_eq!(chunks, 5);
+        let hex: String = (0..chunks)
+            .map(|chunk| {
+                let result = block_chunk(&block, chunk).unwrap();
+                assert_eq!(result["size"], json!(block.len()));
+                result["hex"].as_str().unwrap().to_owned()
+            })
+            .collect();
+        assert_eq!(hex, block.to_lower_hex_string());
+        assert!(block_chunk(&block, chunks).is_err());
+        assert_eq!(block_chunk(&[1, 2], 0).unwrap()["chunks"], json!(1));
+    }
+
+    #[test]
+    fn test_status_debug_args() {
+        let hash = "0".repeat(64);
+        let scripthash: ScriptHash = hash.parse().unwrap();
+        let history = |params| convert::<HistoryArgs>(params).ok().unwrap();
+        for (params, tx_pos, status_debug) in [
+            (json!([hash]), false, false),
+            (json!([hash, true]), true, false),
+            (json!([hash, true, false]), true, false),
+            (json!([hash, false, true]), false, true),
+        ] {
+            let args = history(params);
+            let parsed: (ScriptHash, bool) = (&args).into();
+            assert_eq!(parsed, (scripthash, tx_pos));
+            assert_eq!(args.status_debug(), status_debug);
+        }
+        assert!(convert::<HistoryArgs>(json!([hash, true, true, true])).is_err());
+
+        let subscribe = |params| -> (ScriptHash, bool) {
+            (&convert::<SubscribeArgs>(params).ok().unwrap()).into()
+        };
+        assert_eq!(subscribe(json!([hash])), (scripthash, false));
+        assert_eq!(subscribe(json!([hash, true])), (scripthash, true));
+        assert!(convert::<SubscribeArgs>(json!([hash, "true"])).is_err());
+    }
+
+    #[test]
+    fn test_tx_height_args() {
+        let hash = "0".repeat(64);
+        let txid: Txid = hash.parse().unwrap();
+        match convert::<TxHeightArgs>(json!([hash])).ok().unwrap() {
+            TxHeightArgs::Txid((parsed,)) => assert_eq!(parsed, txid),
+            TxHeightArgs::Txids(_) => panic!("unexpected batch"),
+        }
+        match convert::<TxHeightArgs>(json!([[hash, hash]])).ok().unwrap() {
+            TxHeightArgs::Txids((parsed,)) => assert_eq!(parsed, [txid, txid]),
+            TxHeightArgs::Txid(_) => panic!("unexpected txid"),
+        }
+        assert!(convert::<TxHeightArgs>(json!([hash, hash])).is_err());
+        assert!(convert::<TxHeightArgs>(json!([])).is_err());
+
+        // a batch may trigger a block fetch per txid, so its size is limited
+        let method = Method::find("blockchain.transaction.get_height").unwrap();
+        assert!((method.parse)(json!([vec![&hash; MAX_HEIGHT_TXIDS]])).is_ok());
+        assert!(matches!(
+            (method.parse)(json!([vec![&hash; MAX_HEIGHT_TXIDS + 1]])),
+            Err(StandardError::InvalidParams)
+        ));
+    }
+
+    #[test]
+    fn test_temporary_errors() {
+        let node = StubNode::new(Network::Regtest);
+        let txid = node.get_block_txids(node.tip()).unwrap()[0];
+        let call = Call {
+            id: json!(7),
+            method: Method::find("blockchain.scripthash.get_history").unwrap(),
+            params: Params::Banner, // unused
+        };
+        let temporary = json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "error": {"code": -32603, "message": "temporary server error, retry"},
+        });
+
+        for failure in [Failure::Warmup, Failure::ConnectionRefused] {
+            node.fail("getrawtransaction", failure);
+            let err = node.get_transaction(&txid, None).unwrap_err();
+            assert_eq!(call.response(Err(err)), temporary);
+        }
+        node.fail_block(node.tip());
+        let err = node
+            .for_blocks(vec![node.tip()], &mut |_, _| ())
+            .unwrap_err();
+        assert_eq!(call.response(Err(err)), temporary);
+
+        // unknown transactions are reported as-is
+        node.set_txindex(false);
+        let err = node.get_transaction(&txid, None).unwrap_err();
+        assert_eq!(call.response(Err(err))["error"]["code"], json!(2));
+    }
+
+    #[test]
+    fn test_not_served() {
+        let call = Call {
+            id: json!(3),
+            method: Method::find("blockchain.scripthash.get_history").unwrap(),
+            params: Params::Banner, // unused
+        };
+        let err = anyhow::Error::new(NotServed).context("while handling the request");
+        assert_eq!(
+            call.response(Err(err)),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 3,
+                "error": {"code": -32002, "message": "scripthash not served"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_streamed_response() {
+        let history = bench_history(1000);
+        let call = Call::parse(Request {
+            id: json!(1),
+            method: "blockchain.scripthash.get_history".to_owned(),
+            params: json!(["0".repeat(64)]),
+        })
+        .ok()
+        .unwrap();
+
+        // the entries are serialized while the response is sent (see `tests/memory.rs`)
+        let reply = Reply::array(Arc::new(Arc::clone(&history)));
+        let response = call.reply(Ok(reply)).into_outgoing();
+        assert_eq!(response.as_text(), None);
+        assert_eq!(
+            serde_json::from_str::<Value>(&response.into_string()).unwrap(),
+            call.response(Ok(json!(&history[..])))
+        );
+
+        let reply = Reply::array(Arc::new(WithPos(Arc::clone(&history))));
+        let response: Value = serde_json::from_str(&call.reply(Ok(reply)).into_json()).unwrap();
+        assert_eq!(response["result"][11], history[11].to_value_with_pos());
+        assert_eq!(response["result"][11]["tx_pos"], 1);
+
+        // a batch of streamed responses
+        let reply = Reply::array(Arc::new(vec![1, 2, 3]));
+        let batch = Response::Batch(vec![
+            call.reply(Ok(reply.clone())),
+            json!("error").into(),
+            call.reply(Ok(reply)),
+        ]);
+        let expected = call.response(Ok(json!([1, 2, 3])));
+        assert_eq!(
+            serde_json::from_str::<Value>(&batch.into_json()).unwrap(),
+            json!([expected, "error", expected])
+        );
+    }
 }
@@ -0,0 +1,414 @@
+use anyhow::{Context, Result};
+use bitcoin::{BlockHash, Txid};
+use parking_lot::{Condvar, Mutex, RwLock};
+use serde::Serialize;
+
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::{
+    allowlist,
+    chain::Chain,
+    custom_chain::AddressDecoder,
+    metrics::{Counter, Metrics},
+    tracker::{State, Tracker},
+    types::ScriptHash,
+};
+
+/// How often the writer thread accepts new consumers (and checks whether it should exit)
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Consumers which don't read an event batch in time are disconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Written as a single JSON line (e.g. `{"event":"reorg","depth":2}`)
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    BlockConnected {
+        height: usize,
+        hash: BlockHash,
+    },
+    /// Blocks disconnected from the previous tip (followed by the new chain's blocks)
+    Reorg {
+        depth: usize,
+    },
+    MempoolTxForWatched {
+        scripthash: ScriptHash,
+        txid: Txid,
+        value: u64, // in sats, funding the scripthash
+    },
+    SyncStateChanged {
+        state: &'static str,
+    },
+}
+
+fn state_name(state: State) -> &'static str {
+    match state {
+        State::Syncing => "syncing",
+        State::Synced => "synced",
+        State::Stalled => "stalled",
+    }
+}
+
+/// The events since `prev` (a reorg, if some of its blocks were disconnected, and the new blocks)
+fn chain_events(prev: &Chain, chain: &Chain) -> Vec<Event> {
+    let fork = prev.fork_height(chain);
+    let depth = prev.height() - fork;
+    let reorg = Some(Event::Reorg { depth }).filter(|_| depth > 0);
+    let connected = (fork + 1..=chain.height()).map(|height| Event::BlockConnected {
+        height,
+        hash: chain.get_block_hash(height).expect("missing block hash"),
+    });
+    reorg.into_iter().chain(connected).collect()
+}
+
+/// Bounded, so a slow consumer doesn't block the sync thread (the oldest events are dropped)
+struct Queue {
+    lines: Mutex<VecDeque<String>>,
+    ready: Condvar,
+    capacity: usize,
+    stopped: AtomicBool,
+    events: Counter,
+}
+
+impl Queue {
+    fn push(&self, line: String) {
+        let mut lines = self.lines.lock();
+        if lines.len() >= self.capacity {
+            lines.pop_front();
+            self.events.inc("dropped");
+        }
+        lines.push_back(line);
+        self.ready.notify_one();
+    }
+
+    /// Wait (up to `timeout`) for queued events, and take all of them
+    fn take(&self, timeout: Duration) -> Vec<String> {
+        let mut lines = self.lines.lock();
+        if lines.is_empty() {
+            self.ready.wait_for(&mut lines, timeout);
+        }
+        lines.drain(..).collect()
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.ready.notify_one();
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(unix)]
+fn serve(path: &Path, queue: Arc<Queue>) -> Result<JoinHandle<()>> {
+    use std::io::{ErrorKind, Write};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    // a stale socket is left behind if electrs was killed
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)
+                .with_context(|| format!("failed to remove stale {}", path.display()))?;
+        }
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to bind event_socket {}", path.display()))?;
+    listener.set_nonblocking(true)?;
+    info!("writing events into {}", path.display());
+
+    let path = path.to_owned();
+    Ok(crate::thread::spawn("events", move || {
+        let mut consumers: Vec<UnixStream> = vec![];
+        while !queue.is_stopped() {
+            loop {
+                match listener.accept() {
+                    Ok((stream, _addr)) => {
+                        // only this consumer is refused (the others keep receiving events)
+                        match stream
+                            .set_nonblocking(false)
+                            .and_then(|()| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                        {
+                            Ok(()) => consumers.push(stream),
+                            Err(e) => warn!("failed to configure an event consumer: {}", e),
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => warn!("failed to accept an event consumer: {}", e),
+                }
+            }
+            if consumers.is_empty() {
+                // the queued events are kept for the next consumer
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            let lines = queue.take(POLL_INTERVAL);
+            if lines.is_empty() {
+                continue;
+            }
+            let mut batch = lines.join("\n");
+            batch.push('\n');
+            consumers.retain_mut(|stream| match stream.write_all(batch.as_bytes()) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("disconnecting event consumer: {}", e);
+                    false
+                }
+            });
+            // the events written to none of the consumers are lost
+            let result = if consumers.is_empty() {
+                "dropped"
+            } else {
+                "sent"
+            };
+            queue.events.inc_by(result, lines.len() as u64);
+        }
+        fs::remove_file(&path)
+            .with_context(|| format!("failed to remove event_socket {}", path.display()))
+    }))
+}
+
+#[cfg(not(unix))]
+fn serve(_path: &Path, _queue: Arc<Queue>) -> Result<JoinHandle<()>> {
+    bail!("event_socket is supported only on unix")
+}
+
+/// The scripthashes whose mempool transactions are reported (see `event_watch_file`), re-read
+/// on `SIGHUP`.
+struct Watched {
+    file: Option<PathBuf>,
+    addresses: AddressDecoder,
+    scripthashes: RwLock<HashSet<ScriptHash>>,
+}
+
+impl Watched {
+    fn load(&self) -> Result<()> {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let scripthashes = allowlist::parse(&contents, &self.addresses)
+            .with_context(|| format!("invalid event_watch_file {}", path.display()))?;
+        info!(
+            "watching {} scripthashes (from {})",
+            scripthashes.len(),
+            path.display()
+        );
+        *self.scripthashes.write() = scripthashes;
+        Ok(())
+    }
+}
+
+/// The previously reported state (used only by the sync thread)
+#[derive(Default)]
+struct Reported {
+    chain: Option<Arc<Chain>>,
+    state: Option<State>,
+    mempool: HashSet<(ScriptHash, Txid)>,
+}
+
+/// Writes chain, mempool and sync state events into `event_socket` (one JSON object per line),
+/// for external automation. Events are queued without blocking the sync thread.
+pub(crate) struct EventSink {
+    queue: Arc<Queue>,
+    writer: Option<JoinHandle<()>>,
+    watched: Watched,
+    reported: Mutex<Reported>,
+}
+
+impl EventSink {
+    /// Fails if the socket can't be bound, or `watch_file` can't be loaded.
+    pub(crate) fn new(
+        path: &Path,
+        watch_file: Option<PathBuf>,
+        addresses: AddressDecoder,
+        capacity: usize,
+        metrics: &Metrics,
+    ) -> Result<Self> {
+        let watched = Watched {
+            file: watch_file,
+            addresses,
+            scripthashes: RwLock::default(),
+        };
+        watched.load()?;
+        let queue = Arc::new(Queue {
+            lines: Mutex::default(),
+            ready: Condvar::new(),
+            capacity,
+            stopped: AtomicBool::new(false),
+            events: metrics.counter(
+                "events",
+                "# of events written into `event_socket` (or dropped, if its consumers are slow)",
+                "result",
+            ),
+        });
+        let writer = serve(path, Arc::clone(&queue))?;
+        Ok(Self {
+            queue,
+            writer: Some(writer),
+            watched,
+            reported: Mutex::default(),
+        })
+    }
+
+    /// Re-read `event_watch_file` (e.g. on `SIGHUP`). On failure, the previous list is kept.
+    pub(crate) fn reload(&self) {
+        if let Err(e) = self.watched.load() {
+            warn!("keeping the previous watched scripthashes: {:#}", e);
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        let line = serde_json::to_string(&event).expect("failed to serialize event");
+        self.queue.push(line);
+    }
+
+    /// Emit the events since the previous call (blocks are reported only after the initial sync).
+    pub(crate) fn update(&self, tracker: &Tracker) {
+        let mut reported = self.reported.lock();
+        let state = tracker.state();
+        if reported.state.replace(state) != Some(state) {
+            self.emit(Event::SyncStateChanged {
+                state: state_name(state),
+            });
+        }
+
+        let chain = tracker.chain();
+        if let Some(prev) = reported.chain.replace(Arc::clone(&chain)) {
+            if state != State::Syncing && !Arc::ptr_eq(&prev, &chain) {
+                chain_events(&prev, &chain)
+                    .into_iter()
+                    .for_each(|event| self.emit(event));
+            }
+        }
+
+        let scripthashes = self.watched.scripthashes.read();
+        if scripthashes.is_empty() && reported.mempool.is_empty() {
+            return;
+        }
+        let funding = tracker.mempool_funding(&scripthashes);
+        let mempool: HashSet<(ScriptHash, Txid)> = funding
+            .iter()
+            .map(|(scripthash, txid, _value)| (*scripthash, *txid))
+            .collect();
+        for (scripthash, txid, value) in funding {
+            if !reported.mempool.contains(&(scripthash, txid)) {
+                self.emit(Event::MempoolTxForWatched {
+                    scripthash,
+                    txid,
+                    value: value.to_sat(),
+                });
+            }
+        }
+        reported.mempool = mempool; // removed transactions are reported again if re-added
+    }
+}
+
+impl Drop for EventSink {
+    fn drop(&mut self) {
+        self.queue.stop();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Queue};
+    use crate::metrics::Metrics;
+
+    use serde_json::json;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    #[test]
+    fn test_event_format() {
+        let event = Event::Reorg { depth: 2 };
+        assert_eq!(
+            serde_json::to_value(event).unwrap(),
+            json!({"event": "reorg", "depth": 2})
+        );
+        let event = Event::SyncStateChanged { state: "synced" };
+        assert_eq!(
+            serde_json::to_value(event).unwrap(),
+            json!({"event": "sync_state_changed", "state": "synced"})
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_serve() {
+        use super::serve;
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::net::UnixStream;
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.sock");
+        let metrics = Metrics::unserved();
+        let queue = Arc::new(Queue {
+            lines: Default::default(),
+            ready: Default::default(),
+            capacity: 10,
+            stopped: AtomicBool::new(false),
+            events: metrics.counter("events", "test", "result"),
+        });
+        let writer = serve(&path, Arc::clone(&queue)).unwrap();
+        let wait_for = |result: &str, _count: u64| {
+            #[cfg(feature = "metrics")]
+            {
+                let start = std::time::Instant::now();
+                while queue.events.get(result) != _count {
+                    assert!(start.elapsed() < Duration::from_secs(10), "{}", result);
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        };
+
+        // the events are kept until a consumer is connected
+        queue.push("1".to_owned());
+        let mut consumer = BufReader::new(UnixStream::connect(&path).unwrap());
+        let mut line = String::new();
+        consumer.read_line(&mut line).unwrap();
+        assert_eq!(line, "1\n");
+        wait_for("sent", 1);
+
+        // events which fail to be written are not counted as sent
+        drop(consumer);
+        queue.push("2".to_owned());
+        wait_for("dropped", 1);
+        wait_for("sent", 1);
+
+        queue.stop();
+        writer.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_queue_drops_oldest() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let queue = Queue {
+            lines: Default::default(),
+            ready: Default::default(),
+            capacity: 2,
+            stopped: AtomicBool::new(false),
+            events: metrics.counter("events", "test", "result"),
+        };
+        for i in 0..5 {
+            queue.push(i.to_string());
+        }
+        assert_eq!(queue.take(Duration::ZERO), vec!["3", "4"]);
+        assert!(queue.take(Duration::from_millis(10)).is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use bitcoin::BlockHash;
+use serde::Serialize;
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use crate::config::Config;
+
+/// Ports announced for a single host (unset ones are omitted)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+struct Ports {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssl_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ws_port: Option<u16>,
+}
+
+impl Ports {
+    fn is_empty(&self) -> bool {
+        *self == Ports::default()
+    }
+}
+
+/// How the server should be reached by clients (e.g. when running behind NAT or a TLS proxy)
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Announce {
+    hostname: Option<String>,
+    ports: Ports,
+    listen: Vec<SocketAddr>,
+}
+
+impl Announce {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            hostname: config.server_hostname.clone(),
+            ports: Ports {
+                tcp_port: config.announce_tcp_port,
+                ssl_port: config.announce_ssl_port,
+                ws_port: config.announce_ws_port,
+            },
+            listen: config.electrum_rpc_addrs.clone(),
+        }
+    }
+
+    // Announced ports are used if set - otherwise, the listening TCP port is announced.
+    fn hosts(&self) -> BTreeMap<String, Ports> {
+        let ports = |addr: &SocketAddr| {
+            if self.ports.is_empty() {
+                Ports {
+                    tcp_port: Some(addr.port()),
+                    ..Default::default()
+                }
+            } else {
+                self.ports.clone()
+            }
+        };
+        match (&self.hostname, self.listen.first()) {
+            (Some(hostname), Some(addr)) => BTreeMap::from([(hostname.clone(), ports(addr))]),
+            (Some(hostname), None) => BTreeMap::from([(hostname.clone(), self.ports.clone())]),
+            (None, _) => self
+                .listen
+                .iter()
+                .map(|addr| (addr.ip().to_string(), ports(addr)))
+                .collect(),
+        }
+    }
+}
+
+/// `server.features` response
+#[derive(Serialize)]
+pub(crate) struct Features {
+    extensions: Vec<&'static str>, // of the methods allowed for the client (see `electrum::METHODS`)
+    genesis_hash: BlockHash,
+    hosts: BTreeMap<String, Ports>,
+    protocol_max: &'static str,
+    protocol_min: &'static str,
+    pruning: Option<usize>,
+    server_version: String,
+    hash_function: &'static str,
+}
+
+impl Features {
+    pub(crate) fn new(
+        announce: &Announce,
+        genesis_hash: BlockHash,
+        (protocol_min, protocol_max): (&'static str, &'static str),
+        server_version: String,
+        extensions: Vec<&'static str>,
+    ) -> Self {
+        Self {
+            extensions,
+            genesis_hash,
+            hosts: announce.hosts(),
+            protocol_max,
+            protocol_min,
+            pruning: None, // the whole chain is indexed
+            server_version,
+            hash_function: "sha256",
+        }
+    }
+
+    pub(crate) fn to_value(&self) -> Value {
+        serde_json::to_value(self).expect("invalid features")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Announce, Features, Ports};
+    use bitcoin::{constants::genesis_block, Network};
+
+    fn features(announce: &Announce) -> String {
+        let genesis_hash = genesis_block(Network::Bitcoin).block_hash();
+        Features::new(
+            announce,
+            genesis_hash,
+            ("1.4", "1.4"),
+            "electrs/0.11.0".to_owned(),
+            vec!["tx_pos", "subscribe_bulk", "track_broadcasts"],
+        )
+        .to_value()
+        .to_string()
+    }
+
+    #[test]
+    fn test_default() {
+        let announce = Announce {
+            listen: vec![
+                "127.0.0.1:50001".parse().unwrap(),
+                "[::1]:50002".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            features(&announce),
+            r#"{"extensions":["tx_pos","subscribe_bulk","track_broadcasts"],"genesis_hash":"000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f","hash_function":"sha256","hosts":{"127.0.0.1":{"tcp_port":50001},"::1":{"tcp_port":50002}},"protocol_max":"1.4","protocol_min":"1.4","pruning":null,"server_version":"electrs/0.11.0"}"#
+        );
+    }
+
+    #[test]
+    fn test_announced() {
+        let announce = Announce {
+            hostname: Some("electrum.example.com".to_owned()),
+            ports: Ports {
+                tcp_port: None,
+                ssl_port: Some(50002),
+                ws_port: Some(50004),
+            },
+            listen: vec!["127.0.0.1:50001".parse().unwrap()],
+        };
+        assert_eq!(
+            features(&announce),
+            r#"{"extensions":["tx_pos","subscribe_bulk","track_broadcasts"],"genesis_hash":"000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f","hash_function":"sha256","hosts":{"electrum.example.com":{"ssl_port":50002,"ws_port":50004}},"protocol_max":"1.4","protocol_min":"1.4","pruning":null,"server_version":"electrs/0.11.0"}"#
+        );
+
+        // listening port is announced by default
+        let announce = Announce {
+            ports: Ports::default(),
+            ..announce
+        };
+        assert!(
+            features(&announce).contains(r#""hosts":{"electrum.example.com":{"tcp_port":50001}}"#)
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use anyhow::Result;
+use bitcoin::Amount;
+use parking_lot::Mutex;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::{
+    daemon::{self, Node},
+    metrics::{Counter, Metrics},
+};
+
+/// The relay fee changes rarely (e.g. when bitcoind is restarted using another `-minrelaytxfee`)
+const RELAY_FEE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct Cached<T> {
+    value: T,
+    fetched: Instant,
+}
+
+/// The last fee estimates (per confirmation target) and relay fee fetched from bitcoind, served
+/// while bitcoind is temporarily unavailable (e.g. restarting), so wallets can keep sending.
+pub(crate) struct FeeCache {
+    estimates: Mutex<HashMap<u16, Cached<Option<Amount>>>>,
+    relay_fee: Mutex<Option<Cached<Amount>>>,
+    max_age: Duration, // of the served estimates (zero disables the cache)
+    stale: Counter,
+}
+
+impl FeeCache {
+    pub(crate) fn new(metrics: &Metrics, max_age: Duration) -> Self {
+        Self {
+            estimates: Mutex::default(),
+            relay_fee: Mutex::default(),
+            max_age,
+            stale: metrics.counter(
+                "stale_fees",
+                "# of fee responses served from the cache while bitcoind was unavailable",
+                "method",
+            ),
+        }
+    }
+
+    pub(crate) fn estimate_fee(&self, node: &dyn Node, nblocks: u16) -> Result<Option<Amount>> {
+        self.estimate_fee_at(node, nblocks, Instant::now())
+    }
+
+    pub(crate) fn relay_fee(&self, node: &dyn Node) -> Result<Amount> {
+        self.relay_fee_at(node, Instant::now())
+    }
+
+    /// Like `estimate_fee()`, as if called at `now` (used by tests to control the cache's age)
+    pub(crate) fn estimate_fee_at(
+        &self,
+        node: &dyn Node,
+        nblocks: u16,
+        now: Instant,
+    ) -> Result<Option<Amount>> {
+        match node.estimate_fee(nblocks) {
+            Ok(value) => {
+                let fetched = now;
+                self.estimates
+                    .lock()
+                    .insert(nblocks, Cached { value, fetched });
+                Ok(value)
+            }
+            Err(err) => {
+                let cached = self
+                    .estimates
+                    .lock()
+                    .get(&nblocks)
+                    .map(|c| (c.value, c.fetched));
+                self.fallback("estimatefee", err, cached, self.max_age, now)
+            }
+        }
+    }
+
+    /// Like `relay_fee()`, as if called at `now`
+    pub(crate) fn relay_fee_at(&self, node: &dyn Node, now: Instant) -> Result<Amount> {
+        match node.get_relay_fee() {
+            Ok(value) => {
+                let fetched = now;
+                *self.relay_fee.lock() = Some(Cached { value, fetched });
+                Ok(value)
+            }
+            Err(err) => {
+                let cached = self.relay_fee.lock().as_ref().map(|c| (c.value, c.fetched));
+                let max_age = if self.max_age.is_zero() {
+                    Duration::ZERO
+                } else {
+                    RELAY_FEE_MAX_AGE
+                };
+                self.fallback("relayfee", err, cached, max_age, now)
+            }
+        }
+    }
+
+    /// Serve the cached value, if bitcoind is temporarily unavailable and the value is fresh
+    /// enough (otherwise, fail using the original error).
+    fn fallback<T>(
+        &self,
+        method: &str,
+        err: anyhow::Error,
+        cached: Option<(T, Instant)>,
+        max_age: Duration,
+        now: Instant,
+    ) -> Result<T> {
+        if !daemon::is_transient(&err) {
+            return Err(err);
+        }
+        match cached {
+            Some((value, fetched)) if now.saturating_duration_since(fetched) <= max_age => {
+                let age = now.saturating_duration_since(fetched);
+                warn!(
+                    "serving {} fetched {:.0?} ago, since bitcoind is unavailable: {:#}",
+                    method, age, err
+                );
+                self.stale.inc(method);
+                Ok(value)
+            }
+            _ => Err(err),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// When the indexed rows are flushed from RocksDB's memtables into SST files (see `flush_*`).
+/// Without thresholds, the rows are flushed at the end of each sync which indexed new blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlushPolicy {
+    pub blocks: Option<usize>,
+    pub interval: Option<Duration>,
+    pub bytes: Option<u64>,
+}
+
+impl FlushPolicy {
+    fn has_thresholds(&self) -> bool {
+        self.blocks.is_some() || self.interval.is_some() || self.bytes.is_some()
+    }
+}
+
+/// The reason for a flush (exported as the `trigger` label of `index_flushes` metric)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Trigger {
+    Sync,
+    Blocks,
+    Interval,
+    Bytes,
+}
+
+impl Trigger {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Trigger::Sync => "sync",
+            Trigger::Blocks => "blocks",
+            Trigger::Interval => "interval",
+            Trigger::Bytes => "bytes",
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Tracks the rows written since the last flush, deciding when the next one is due. The current
+/// time is passed by the caller (so tests can control the clock).
+pub(crate) struct Flusher {
+    policy: FlushPolicy,
+    blocks: usize, // written since the last flush
+    bytes: u64,
+    last_flush: Instant,
+}
+
+impl Flusher {
+    pub(crate) fn new(policy: FlushPolicy, now: Instant) -> Self {
+        Self {
+            policy,
+            blocks: 0,
+            bytes: 0,
+            last_flush: now,
+        }
+    }
+
+    /// Record a written batch, returning the trigger of a due flush (if any threshold is reached).
+    pub(crate) fn written(&mut self, blocks: usize, bytes: u64, now: Instant) -> Option<Trigger> {
+        self.blocks += blocks;
+        self.bytes += bytes;
+        self.due(now)
+    }
+
+    /// At the end of a sync, the written rows are flushed unless thresholds are configured (and
+    /// none of them is reached yet).
+    pub(crate) fn sync_done(&self, now: Instant) -> Option<Trigger> {
+        if !self.policy.has_thresholds() {
+            return self.is_pending().then_some(Trigger::Sync);
+        }
+        self.due(now)
+    }
+
+    fn due(&self, now: Instant) -> Option<Trigger> {
+        if !self.is_pending() {
+            return None;
+        }
+        if self
+            .policy
+            .blocks
+            .is_some_and(|blocks| self.blocks >= blocks)
+        {
+            return Some(Trigger::Blocks);
+        }
+        if self.policy.bytes.is_some_and(|bytes| self.bytes >= bytes) {
+            return Some(Trigger::Bytes);
+        }
+        let elapsed = now.saturating_duration_since(self.last_flush);
+        if self
+            .policy
+            .interval
+            .is_some_and(|interval| elapsed >= interval)
+        {
+            return Some(Trigger::Interval);
+        }
+        None
+    }
+
+    /// Returns `true` if some written rows were not flushed yet.
+    pub(crate) fn is_pending(&self) -> bool {
+        self.blocks > 0
+    }
+
+    pub(crate) fn flushed(&mut self, now: Instant) {
+        self.blocks = 0;
+        self.bytes = 0;
+        self.last_flush = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlushPolicy, Flusher, Trigger};
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_flush_on_sync() {
+        let start = Instant::now();
+        let mut flusher = Flusher::new(FlushPolicy::default(), start);
+        assert_eq!(flusher.sync_done(start), None); // nothing to flush
+
+        // without thresholds, the written rows are flushed only at the end of a sync
+        for i in 1..=100 {
+            let now = start + Duration::from_secs(i);
+            assert_eq!(flusher.written(1000, 1 << 30, now), None);
+        }
+        let now = start + Duration::from_secs(101);
+        assert_eq!(flusher.sync_done(now), Some(Trigger::Sync));
+        flusher.flushed(now);
+        assert!(!flusher.is_pending());
+        assert_eq!(flusher.sync_done(now), None);
+    }
+
+    #[test]
+    fn test_flush_thresholds() {
+        let start = Instant::now();
+        let policy = FlushPolicy {
+            blocks: Some(10),
+            interval: Some(Duration::from_secs(60)),
+            bytes: Some(1000),
+        };
+        let mut flusher = Flusher::new(policy, start);
+
+        // synthetic batches, written a second apart
+        let mut now = start;
+        let mut batch = |blocks, bytes| {
+            now += Duration::from_secs(1);
+            (flusher.written(blocks, bytes, now), now)
+        };
+        assert_eq!(batch(4, 100).0, None);
+        assert_eq!(batch(4, 100).0, None);
+        let (trigger, now) = batch(4, 100);
+        assert_eq!(trigger, Some(Trigger::Blocks));
+        flusher.flushed(now);
+
+        assert_eq!(flusher.written(1, 999, now), None);
+        assert_eq!(flusher.written(1, 1, now), Some(Trigger::Bytes));
+        flusher.flushed(now);
+
+        // the interval is checked when rows are written (or at the end of a sync)
+        let later = now + Duration::from_secs(59);
+        assert_eq!(flusher.written(1, 1, later), None);
+        assert_eq!(flusher.sync_done(later), None); // below all thresholds
+        let later = now + Duration::from_secs(60);
+        assert_eq!(flusher.sync_done(later), Some(Trigger::Interval));
+        flusher.flushed(later);
+
+        // an elapsed interval doesn't trigger a flush without written rows
+        let idle = later + Duration::from_secs(3600);
+        assert_eq!(flusher.sync_done(idle), None);
+        assert_eq!(flusher.written(1, 1, idle), Some(Trigger::Interval));
+    }
+}
@@ -0,0 +1,307 @@
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use std::sync::Arc;
+
+use crate::{daemon, index::MissingBlock, tracker::State};
+
+/// Cached state, used for answering health checks
+///
+/// It is updated by the main loop and the sync thread (and while connecting to bitcoind), so
+/// health checks don't access the DB or the daemon.
+#[derive(Clone, Default)]
+pub(crate) struct Health(Arc<Mutex<Status>>);
+
+#[derive(Debug, Default)]
+pub(crate) struct Status {
+    /// `None` before the first sync
+    pub state: Option<State>,
+    pub indexed_height: Option<usize>,
+    /// Best header height, as reported by bitcoind
+    pub daemon_height: Option<usize>,
+    /// Why bitcoind can't be used (if it can't)
+    pub daemon_error: Option<String>,
+    /// Why the index is not updated anymore (see `on_index_failure`)
+    pub index_error: Option<String>,
+    /// The depth of a refused reorg (see `max_reorg_depth`)
+    pub refused_reorg_depth: Option<usize>,
+    /// The height of a checkpoint contradicted by bitcoind's chain (see `checkpoints`)
+    pub contradicted_checkpoint: Option<usize>,
+    /// A block bitcoind failed to provide (the index is stalled after a few retries)
+    pub missing_block: Option<MissingBlock>,
+}
+
+impl Health {
+    pub(crate) fn update(&self, func: impl FnOnce(&mut Status)) {
+        func(&mut self.0.lock())
+    }
+
+    /// Report a failure to use bitcoind (by a sync or a request): if bitcoind is temporarily
+    /// unavailable, the server is not ready until the next successful sync.
+    pub(crate) fn daemon_failed(&self, err: &anyhow::Error) {
+        if daemon::is_transient(err) {
+            self.update(|s| s.daemon_error = Some(format!("{:#}", err)));
+        }
+    }
+
+    /// Report a successful sync (so bitcoind is reachable)
+    pub(crate) fn daemon_synced(&self) {
+        self.update(|s| s.daemon_error = None);
+    }
+
+    /// Returns HTTP status code and JSON body for health check `path` (or `None` for other paths).
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))] // served by the monitoring listener
+    pub(crate) fn respond(&self, path: &str) -> Option<(u16, Value)> {
+        match path {
+            // liveness: the process is up
+            "/healthz" => Some((200, json!({"status": "alive"}))),
+            // readiness: the index is synced and bitcoind is reachable
+            "/readyz" => Some(self.readiness()),
+            _ => None,
+        }
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    fn readiness(&self) -> (u16, Value) {
+        let status = self.0.lock();
+        if let Some(error) = &status.index_error {
+            return (
+                503,
+                json!({"status": "unavailable", "reason": "index failure", "error": error}),
+            );
+        }
+        if let Some(error) = &status.daemon_error {
+            return (
+                503,
+                json!({"status": "unavailable", "reason": "daemon unreachable", "error": error}),
+            );
+        }
+        if let (Some(State::Stalled), Some(missing)) = (status.state, &status.missing_block) {
+            if missing.is_stalled() {
+                return (
+                    503,
+                    json!({
+                        "status": "unavailable",
+                        "reason": "missing block",
+                        "indexed_height": status.indexed_height,
+                        "missing_height": missing.height,
+                        "missing_blockhash": missing.blockhash,
+                        "prune_height": missing.prune_height,
+                        "error": missing.to_string(),
+                    }),
+                );
+            }
+        }
+        match (status.state, status.indexed_height) {
+            (Some(State::Stalled), height) if status.contradicted_checkpoint.is_some() => (
+                503,
+                json!({
+                    "status": "unavailable",
+                    "reason": "checkpoint",
+                    "indexed_height": height,
+                    "checkpoint_height": status.contradicted_checkpoint,
+                }),
+            ),
+            (Some(State::Stalled), height) => (
+                503,
+                json!({
+                    "status": "unavailable",
+                    "reason": "deep reorg",
+                    "indexed_height": height,
+                    "reorg_depth": status.refused_reorg_depth,
+                }),
+            ),
+            (Some(State::Synced), Some(height)) => {
+                (200, json!({"status": "ready", "height": height}))
+            }
+            (Some(State::Syncing), Some(height)) => {
+                let lag = status
+                    .daemon_height
+                    .map(|daemon_height| daemon_height.saturating_sub(height));
+                (
+                    503,
+                    json!({
+                        "status": "unavailable",
+                        "reason": "index lag",
+                        "indexed_height": height,
+                        "daemon_height": status.daemon_height,
+                        "lag": lag,
+                    }),
+                )
+            }
+            _ => (503, json!({"status": "unavailable", "reason": "starting"})),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Health;
+    use crate::{index::MissingBlock, p2p::Disconnected, tracker::State};
+    use bitcoin::{hashes::Hash, BlockHash};
+    use serde_json::json;
+
+    #[test]
+    fn test_endpoints() {
+        let health = Health::default();
+        assert_eq!(health.respond("/metrics"), None);
+        assert_eq!(
+            health.respond("/healthz"),
+            Some((200, json!({"status": "alive"})))
+        );
+        assert_eq!(
+            health.respond("/readyz"),
+            Some((503, json!({"status": "unavailable", "reason": "starting"})))
+        );
+
+        health.update(|s| s.daemon_error = Some("RPC warmup: Loading block index".to_owned()));
+        let (code, body) = health.respond("/readyz").unwrap();
+        assert_eq!(code, 503);
+        assert_eq!(body["reason"], "daemon unreachable");
+        assert_eq!(body["error"], "RPC warmup: Loading block index");
+
+        health.update(|s| {
+            s.daemon_error = None;
+            s.state = Some(State::Syncing);
+            s.indexed_height = Some(100);
+            s.daemon_height = Some(150);
+        });
+        assert_eq!(
+            health.respond("/readyz"),
+            Some((
+                503,
+                json!({
+                    "status": "unavailable",
+                    "reason": "index lag",
+                    "indexed_height": 100,
+                    "daemon_height": 150,
+                    "lag": 50,
+                })
+            ))
+        );
+
+        health.update(|s| {
+            s.state = Some(State::Synced);
+            s.indexed_height = Some(150);
+        });
+        assert_eq!(
+            health.respond("/readyz"),
+            Some((200, json!({"status": "ready", "height": 150})))
+        );
+        health.update(|s| {
+            s.state = Some(State::Stalled);
+            s.refused_reorg_depth = Some(150);
+        });
+        assert_eq!(
+            health.respond("/readyz"),
+            Some((
+                503,
+                json!({
+                    "status": "unavailable",
+                    "reason": "deep reorg",
+                    "indexed_height": 150,
+                    "reorg_depth": 150,
+                })
+            ))
+        );
+        health.update(|s| {
+            s.refused_reorg_depth = None;
+            s.contradicted_checkpoint = Some(100);
+        });
+        assert_eq!(
+            health.respond("/readyz"),
+            Some((
+                503,
+                json!({
+                    "status": "unavailable",
+                    "reason": "checkpoint",
+                    "indexed_height": 150,
+                    "checkpoint_height": 100,
+                })
+            ))
+        );
+        health.update(|s| s.contradicted_checkpoint = None);
+
+        let blockhash = BlockHash::hash(b"block");
+        let mut missing = MissingBlock {
+            height: 120,
+            blockhash,
+            failures: 1,
+            prune_height: Some(130),
+        };
+        health.update(|s| {
+            s.state = Some(State::Synced);
+            s.missing_block = Some(missing.clone()); // still retried
+        });
+        assert_eq!(health.respond("/readyz").unwrap().0, 200);
+        missing.failures = 4;
+        health.update(|s| {
+            s.state = Some(State::Stalled);
+            s.missing_block = Some(missing.clone());
+        });
+        assert_eq!(
+            health.respond("/readyz"),
+            Some((
+                503,
+                json!({
+                    "status": "unavailable",
+                    "reason": "missing block",
+                    "indexed_height": 150,
+                    "missing_height": 120,
+                    "missing_blockhash": blockhash,
+                    "prune_height": 130,
+                    "error": format!(
+                        "block {} at height 120 is not available from bitcoind \
+                        (bitcoind is pruned below height 130: electrs requires a non-pruned node)",
+                        blockhash
+                    ),
+                })
+            ))
+        );
+        health.update(|s| s.missing_block = None);
+
+        // liveness doesn't depend on the sync state
+        assert_eq!(health.respond("/healthz").unwrap().0, 200);
+
+        health.update(|s| s.daemon_error = Some("connection refused".to_owned()));
+        assert_eq!(health.respond("/readyz").unwrap().0, 503);
+
+        health.update(|s| s.index_error = Some("sync thread failed".to_owned()));
+        assert_eq!(
+            health.respond("/readyz"),
+            Some((
+                503,
+                json!({"status": "unavailable", "reason": "index failure", "error": "sync thread failed"})
+            ))
+        );
+    }
+
+    #[test]
+    fn test_daemon_failures() {
+        let health = Health::default();
+        health.update(|s| {
+            s.state = Some(State::Synced);
+            s.indexed_height = Some(150);
+        });
+        assert_eq!(health.respond("/readyz").unwrap().0, 200);
+
+        // bitcoind became unavailable after startup
+        let err = anyhow::Error::new(Disconnected).context("sync failed");
+        health.daemon_failed(&err);
+        let (code, body) = health.respond("/readyz").unwrap();
+        assert_eq!(code, 503);
+        assert_eq!(body["reason"], "daemon unreachable");
+        assert_eq!(
+            body["error"],
+            "sync failed: not connected to bitcoind via p2p"
+        );
+
+        // other failures don't affect the readiness
+        health.daemon_synced();
+        health.daemon_failed(&anyhow::anyhow!("invalid params"));
+        assert_eq!(
+            health.respond("/readyz"),
+            Some((200, json!({"status": "ready", "height": 150})))
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! JSON-RPC over HTTP POST (see `http_addr`), for clients which can't keep a persistent
+//! connection (e.g. scripts and dashboards).
+//!
+//! Each POST body contains a single request (or batch), which is handled by a new stateless
+//! client - so subscriptions are not supported. Requests in flight count towards the connection
+//! limits of their IP address, and protocol violations towards its ban (they are counted across
+//! requests, until they are forgotten by `Limits::expire`).
+
+use anyhow::{Context, Result};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::{
+    electrum,
+    limits::{self, Limits, Refusal},
+    thread::spawn,
+};
+
+/// Returns the response to a request's body (sent from the given address), and the number of
+/// protocol violations it contains (the server runs it using the query pool's workers).
+pub(crate) type Handler = Arc<dyn Fn(SocketAddr, &str) -> (String, usize) + Send + Sync>;
+
+struct Shared {
+    handler: Handler,
+    limits: Limits,
+    cors_origin: Option<String>,
+    max_request: usize, // in bytes
+}
+
+pub(crate) struct HttpServer {
+    server: Arc<Server>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl HttpServer {
+    /// Bind the listening address (before the server is started, so a failure is reported early).
+    pub(crate) fn bind(addr: SocketAddr) -> Result<Server> {
+        match Server::http(addr) {
+            Ok(server) => Ok(server),
+            Err(err) => bail!("failed to bind HTTP JSON-RPC on {}: {}", addr, err),
+        }
+    }
+
+    pub(crate) fn start(
+        server: Server,
+        handler: Handler,
+        limits: Limits,
+        cors_origin: Option<String>,
+        (max_request, threads): (usize, usize), // `threads` requests are handled concurrently
+    ) -> Self {
+        if let Some(addr) = server.server_addr().to_ip() {
+            info!("serving JSON-RPC over HTTP on {}", addr);
+        }
+        let server = Arc::new(server);
+        let shared = Arc::new(Shared {
+            handler,
+            limits,
+            cors_origin,
+            max_request,
+        });
+        let threads = (0..threads.max(1))
+            .map(|_| {
+                let server = Arc::clone(&server);
+                let shared = Arc::clone(&shared);
+                spawn("http", move || {
+                    for request in server.incoming_requests() {
+                        handle(request, &shared)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        Self { server, threads }
+    }
+
+    /// Stop handling new requests, and wait for the ones being handled.
+    pub(crate) fn stop(self) {
+        self.threads.iter().for_each(|_| self.server.unblock());
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("invalid HTTP header")
+}
+
+fn handle(mut request: Request, shared: &Shared) -> Result<()> {
+    let mut headers = vec![];
+    if let Some(origin) = &shared.cors_origin {
+        headers.push(header("Access-Control-Allow-Origin", origin));
+    }
+    let (code, body) = match (request.method(), request.remote_addr().copied()) {
+        (Method::Options, _) => {
+            // CORS preflight
+            headers.push(header("Access-Control-Allow-Methods", "POST, OPTIONS"));
+            headers.push(header("Access-Control-Allow-Headers", "Content-Type"));
+            (204, String::new())
+        }
+        (Method::Post, Some(addr)) => handle_post(&mut request, addr, shared),
+        (Method::Post, None) => (400, String::new()), // e.g. a UNIX socket
+        _ => {
+            headers.push(header("Allow", "POST, OPTIONS"));
+            (405, String::new())
+        }
+    };
+    if code == 200 {
+        headers.push(header("Content-Type", "application/json"));
+    }
+    let response = headers
+        .into_iter()
+        .fold(Response::from_string(body), Response::with_header)
+        .with_status_code(code);
+    if let Err(e) = request.respond(response) {
+        debug!("failed to send HTTP response: {}", e); // e.g. the client has disconnected
+    }
+    Ok(())
+}
+
+fn handle_post(request: &mut Request, addr: SocketAddr, shared: &Shared) -> (u16, String) {
+    let ip = addr.ip();
+    if let Err(refusal) = shared.limits.accept(ip, limits::unix_now()) {
+        debug!("refused HTTP request from {}: {:?}", ip, refusal);
+        return match refusal {
+            Refusal::Banned => (403, String::new()),
+            Refusal::TooManyConnections | Refusal::ServerFull => {
+                (503, electrum::server_full_msg().to_string())
+            }
+        };
+    }
+    let result = read_body(request, shared.max_request).map(|body| {
+        let (response, violations) = (shared.handler)(addr, &body);
+        shared.limits.violations(ip, violations, limits::unix_now());
+        response
+    });
+    shared.limits.release_request(ip);
+    match result {
+        Ok(response) => (200, response),
+        Err(e) => {
+            debug!("invalid HTTP request from {}: {:#}", ip, e);
+            (413, String::new())
+        }
+    }
+}
+
+fn read_body(request: &mut Request, max_request: usize) -> Result<String> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(max_request as u64 + 1)
+        .read_to_string(&mut body)
+        .context("failed to read request")?;
+    ensure!(
+        body.len() <= max_request,
+        "request is larger than {} bytes",
+        max_request
+    );
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handler, HttpServer};
+    use crate::limits::Limits;
+
+    use serde_json::{json, Value};
+
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+
+    /// Returns the status code, headers and body of the response
+    fn send(addr: SocketAddr, method: &str, body: &str) -> (u16, String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = format!(
+            "{} / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let code = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (code, head.to_owned(), body.to_owned())
+    }
+
+    fn start(limits: Limits, cors_origin: Option<&str>) -> (HttpServer, SocketAddr) {
+        // echo the request's method (counting a violation per batch item)
+        let handler: Handler = Arc::new(|_addr, body| {
+            let request: Value = serde_json::from_str(body).unwrap();
+            let violations = request.as_array().map_or(0, Vec::len);
+            (json!({"result": request["method"]}).to_string(), violations)
+        });
+        let server = HttpServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let cors_origin = cors_origin.map(str::to_owned);
+        (
+            HttpServer::start(server, handler, limits, cors_origin, (100, 2)),
+            addr,
+        )
+    }
+
+    #[test]
+    fn test_http_requests() {
+        let limits = Limits::new(None, None, None).unwrap();
+        let (server, addr) = start(limits.clone(), Some("*"));
+
+        let (code, head, body) = send(addr, "POST", r#"{"method": "server.ping"}"#);
+        assert_eq!(code, 200);
+        assert!(head.contains("Content-Type: application/json"));
+        assert!(head.contains("Access-Control-Allow-Origin: *"));
+        assert_eq!(body, r#"{"result":"server.ping"}"#);
+
+        // CORS preflight
+        let (code, head, _) = send(addr, "OPTIONS", "");
+        assert_eq!(code, 204);
+        assert!(head.contains("Access-Control-Allow-Methods: POST, OPTIONS"));
+
+        assert_eq!(send(addr, "GET", "").0, 405);
+        assert_eq!(send(addr, "POST", &"x".repeat(101)).0, 413); // too large
+        assert_eq!(limits.total(), 0); // all requests were released
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_http_limits() {
+        let limits = Limits::new(None, Some(0), None).unwrap();
+        let (server, addr) = start(limits, None);
+        let (code, head, body) = send(addr, "POST", r#"{"method": "server.ping"}"#);
+        assert_eq!(code, 503);
+        assert!(!head.contains("Access-Control-Allow-Origin"));
+        assert!(body.contains("server full"));
+        server.stop();
+
+        // protocol violations are counted towards a ban
+        let limits = Limits::new(None, None, None).unwrap();
+        let (server, addr) = start(limits, None);
+        assert_eq!(send(addr, "POST", "[1, 2, 3]").0, 200);
+        assert_eq!(send(addr, "POST", "[1, 2, 3]").0, 200);
+        assert_eq!(send(addr, "POST", r#"{"method": "server.ping"}"#).0, 200);
+        assert_eq!(send(addr, "POST", "[1, 2, 3, 4]").0, 200); // counted across requests
+        assert_eq!(send(addr, "POST", r#"{"method": "server.ping"}"#).0, 403);
+        server.stop();
+    }
+}
@@ -1,29 +1,62 @@
 use anyhow::{Context, Result};
-use bitcoin::consensus::{deserialize, Decodable, Encodable};
+use bitcoin::consensus::deserialize;
 use bitcoin::hashes::Hash;
 use bitcoin::{BlockHash, OutPoint, Txid};
 use bitcoin_slices::{bsl, Visit, Visitor};
+use parking_lot::{Mutex, RwLock};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
 use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::{
-    chain::{Chain, NewHeader},
-    daemon::Daemon,
+    blocks_dir::BlocksDir,
+    chain::{Chain, Checkpoints, HeaderStore, NewHeader},
+    config::Config,
+    daemon::{BlockNotAvailable, Node},
     db::{DBStore, WriteBatch},
-    metrics::{self, Gauge, Histogram, Metrics},
+    flush::{FlushPolicy, Flusher, Trigger},
+    health::Health,
+    limits,
+    maintenance::{self, Scheduler, Window},
+    metrics::{self, Counter, Gauge, Histogram, Metrics},
     signals::ExitFlag,
+    systemd,
+    totals::Totals,
     types::{
-        bsl_txid, HashPrefixRow, HeaderRow, ScriptHash, ScriptHashRow, SerBlock, SpendingPrefixRow,
-        TxidRow,
+        bsl_txid, HashPrefixRow, HeaderRow, PrefixLen, ScriptHash, ScriptHashRow, ScriptType,
+        ScriptTypes, SerBlock, SpendingPrefixRow, Touched, TxidRow,
     },
 };
 
+/// The number of blocks indexed by a single `sync()` call (like a P2P `headers` message), so
+/// the indexed blocks are published periodically during the initial sync.
+const MAX_BLOCKS_PER_SYNC: usize = 2000;
+
+/// A block which bitcoind doesn't have is retried (on each sync) this many times, before the index
+/// is stalled (it is still retried afterwards, so indexing resumes once the block is available).
+const MISSING_BLOCK_RETRIES: usize = 3;
+
+/// The monotonic counters are persisted at most once per this interval (and on shutdown)
+const TOTALS_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Clone)]
 struct Stats {
     update_duration: Histogram,
+    block_duration: Histogram,
     update_size: Histogram,
     height: Gauge,
+    tip_time: Gauge,
     db_properties: Gauge,
+    db_keys: Gauge,
+    reorg_depth: Gauge,
+    maintenance: Gauge,
+    missing_block: Gauge,
+    flushes: Counter,
+    full_compactions: Counter,
 }
 
 impl Stats {
@@ -35,6 +68,12 @@ impl Stats {
                 "step",
                 metrics::default_duration_buckets(),
             ),
+            block_duration: metrics.histogram_vec(
+                "index_block_duration",
+                "Per-block indexing duration of each phase (in seconds)",
+                "phase",
+                metrics::default_duration_buckets(),
+            ),
             update_size: metrics.histogram_vec(
                 "index_update_size",
                 "Index update size (in bytes)",
@@ -42,7 +81,42 @@ impl Stats {
                 metrics::default_size_buckets(),
             ),
             height: metrics.gauge("index_height", "Indexed block height", "type"),
+            tip_time: metrics.gauge(
+                "index_tip_time",
+                "Indexed tip's block timestamp (a UNIX timestamp, clamped to the current time)",
+                "type",
+            ),
             db_properties: metrics.gauge("index_db_properties", "Index DB properties", "name"),
+            db_keys: metrics.gauge(
+                "index_db_keys",
+                "Estimated number of keys in each index DB column family",
+                "cf",
+            ),
+            reorg_depth: metrics.gauge(
+                "index_reorg_depth",
+                "Depth of chain reorganizations (`refused` is 0 unless the index is stalled)",
+                "type",
+            ),
+            maintenance: metrics.gauge(
+                "index_maintenance",
+                "Scheduled DB maintenance (`last_run` is a UNIX timestamp, `reclaimed_bytes` is reset on each window)",
+                "type",
+            ),
+            missing_block: metrics.gauge(
+                "index_missing_block",
+                "A block bitcoind failed to provide (`height` is 0 if there is none)",
+                "type",
+            ),
+            flushes: metrics.counter(
+                "index_flushes",
+                "# of DB flushes (by their trigger, see `FlushPolicy`)",
+                "trigger",
+            ),
+            full_compactions: metrics.counter(
+                "index_full_compactions",
+                "# of initial full DB compactions (by their trigger)",
+                "trigger",
+            ),
         }
     }
 
@@ -50,8 +124,9 @@ impl Stats {
         self.update_duration.observe_duration(label, f)
     }
 
-    fn observe_size<const N: usize>(&self, label: &str, rows: &[[u8; N]]) {
-        self.update_size.observe(label, (rows.len() * N) as f64);
+    fn observe_size<T: AsRef<[u8]>>(&self, label: &str, rows: &[T]) {
+        let size: usize = rows.iter().map(|row| row.as_ref().len()).sum();
+        self.update_size.observe(label, size as f64);
     }
 
     fn observe_batch(&self, batch: &WriteBatch) {
@@ -59,6 +134,13 @@ impl Stats {
         self.observe_size("write_spending_rows", &batch.spending_rows);
         self.observe_size("write_txid_rows", &batch.txid_rows);
         self.observe_size("write_header_rows", &batch.header_rows);
+        let merkle_size = batch
+            .merkle_rows
+            .iter()
+            .map(|(_, txids)| txids.len())
+            .sum::<usize>();
+        self.update_size
+            .observe("write_merkle_rows", merkle_size as f64);
         debug!(
             "writing {} funding and {} spending rows from {} transactions, {} blocks",
             batch.funding_rows.len(),
@@ -70,6 +152,12 @@ impl Stats {
 
     fn observe_chain(&self, chain: &Chain) {
         self.height.set("tip", chain.height() as f64);
+        let tip_time = chain.tip_time(limits::unix_now());
+        self.tip_time.set("block", tip_time as f64);
+    }
+
+    fn observe_headers(&self, headers: &Chain) {
+        self.height.set("headers", headers.height() as f64);
     }
 
     fn observe_db(&self, store: &DBStore) {
@@ -77,18 +165,205 @@ impl Stats {
             self.db_properties
                 .set(&format!("{}:{}", name, cf), value as f64);
         }
+        for (cf, keys) in store.estimate_num_keys() {
+            self.db_keys.set(cf, keys as f64);
+        }
+    }
+}
+
+/// The number of blocks between the initial sync's summary logs
+const SUMMARY_BLOCKS: usize = 10_000;
+
+/// Indexing work and its duration per phase, summed over blocks (threaded through the indexing
+/// pipeline: the reader thread fetches the blocks and parses them into rows, and the writer
+/// thread sorts and writes them into the DB). Parsing includes building the rows, since both are
+/// done in a single pass over each block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Timings {
+    pub(crate) blocks: usize,
+    pub(crate) bytes: usize, // serialized blocks
+    pub(crate) rows: usize,
+    pub(crate) fetch: Duration,
+    pub(crate) parse: Duration,
+    pub(crate) sort: Duration,
+    pub(crate) write: Duration,
+}
+
+/// The machine-readable summary of indexing `Timings`, printed by `electrs bench-index` (and
+/// logged during the initial sync)
+#[derive(Debug, Serialize)]
+pub(crate) struct TimingsReport {
+    blocks: usize,
+    bytes: usize,
+    rows: usize,
+    elapsed_secs: f64,
+    blocks_per_sec: f64,
+    rows_per_sec: f64,
+    mb_per_sec: f64,
+    /// The total duration of each phase (the reader and writer phases overlap during sync)
+    phases_secs: BTreeMap<&'static str, f64>,
+}
+
+impl Timings {
+    fn add(&mut self, other: &Timings) {
+        self.blocks += other.blocks;
+        self.bytes += other.bytes;
+        self.rows += other.rows;
+        self.fetch += other.fetch;
+        self.parse += other.parse;
+        self.sort += other.sort;
+        self.write += other.write;
+    }
+
+    /// The rates are computed using the `elapsed` wall-clock time.
+    pub(crate) fn report(&self, elapsed: Duration) -> TimingsReport {
+        let secs = elapsed.as_secs_f64();
+        let rate = |count: f64| if secs > 0.0 { count / secs } else { 0.0 };
+        TimingsReport {
+            blocks: self.blocks,
+            bytes: self.bytes,
+            rows: self.rows,
+            elapsed_secs: secs,
+            blocks_per_sec: rate(self.blocks as f64),
+            rows_per_sec: rate(self.rows as f64),
+            mb_per_sec: rate(self.bytes as f64 / 1e6),
+            phases_secs: BTreeMap::from([
+                ("fetch", self.fetch.as_secs_f64()),
+                ("parse", self.parse.as_secs_f64()),
+                ("sort", self.sort.as_secs_f64()),
+                ("write", self.write.as_secs_f64()),
+            ]),
+        }
+    }
+}
+
+/// A block which bitcoind failed to provide (see `BlockNotAvailable`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MissingBlock {
+    pub(crate) height: usize,
+    pub(crate) blockhash: BlockHash,
+    pub(crate) failures: usize,             // by consecutive syncs
+    pub(crate) prune_height: Option<usize>, // bitcoind's first block with data, if it is pruned
+}
+
+impl MissingBlock {
+    /// Returns `true` if the index is stalled (after `MISSING_BLOCK_RETRIES` retries)
+    pub(crate) fn is_stalled(&self) -> bool {
+        self.failures > MISSING_BLOCK_RETRIES
+    }
+
+    fn is_pruned(&self) -> bool {
+        self.prune_height
+            .is_some_and(|prune_height| self.height < prune_height)
+    }
+}
+
+impl std::fmt::Display for MissingBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "block {} at height {} is not available from bitcoind",
+            self.blockhash, self.height
+        )?;
+        match self.prune_height {
+            Some(prune_height) if self.is_pruned() => write!(
+                f,
+                " (bitcoind is pruned below height {}: electrs requires a non-pruned node)",
+                prune_height
+            ),
+            Some(prune_height) => write!(
+                f,
+                " (bitcoind is pruned below height {}, so the block should be available)",
+                prune_height
+            ),
+            None => write!(f, " (bitcoind is not pruned)"),
+        }
+    }
+}
+
+/// The settings of a loaded index (see `Index::load`)
+#[derive(Clone, Debug)]
+pub(crate) struct IndexOptions {
+    pub(crate) batch_size: usize,
+    pub(crate) lookup_limit: Option<usize>,
+    pub(crate) reindex_last_blocks: usize,
+    pub(crate) merkle_cache: bool,
+    pub(crate) script_types: ScriptTypes,
+    pub(crate) recent_headers: Option<usize>, // see `Chain::set_header_store`
+    pub(crate) final_depth: Option<usize>,    // see `Chain::set_final_depth`
+}
+
+impl IndexOptions {
+    pub(crate) fn for_config(config: &Config) -> Self {
+        Self {
+            batch_size: config.index_batch_size,
+            lookup_limit: config.index_lookup_limit,
+            reindex_last_blocks: config.reindex_last_blocks,
+            merkle_cache: config.merkle_cache,
+            script_types: config.index_script_types.clone(),
+            recent_headers: config.headers_in_memory,
+            final_depth: config.assume_final_depth,
+        }
+    }
+}
+
+/// Without lookup limits, caches or evicted headers (e.g. for tests)
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 10,
+            lookup_limit: None,
+            reindex_last_blocks: 0,
+            merkle_cache: false,
+            script_types: ScriptTypes::all(),
+            recent_headers: None,
+            final_depth: None,
+        }
     }
 }
 
 /// Confirmed transactions' address index
 pub struct Index {
-    store: DBStore,
+    store: Arc<DBStore>,
     batch_size: usize,
     lookup_limit: Option<usize>,
-    chain: Chain,
+    chain: RwLock<Arc<Chain>>,   // used by queries, replaced by `publish()`
+    headers: RwLock<Arc<Chain>>, // verified by `sync_headers()`, may be ahead of the indexed chain
+    pending: Mutex<Pending>,
     stats: Stats,
-    is_ready: bool,
-    flush_needed: bool,
+    is_ready: AtomicBool,
+    flusher: Mutex<Flusher>, // the rows written since the last flush
+    defer_compaction: bool,  // the initial full compaction waits for `request_compaction()`
+    compaction_requested: AtomicBool,
+    compacting: Mutex<()>, // the full compaction is triggered by the sync and maintenance threads
+    health: Health,
+    merkle_cache: bool,
+    height_rows: bool, // written only if the headers may be evicted from memory
+    script_types: ScriptTypes,
+    touched: Mutex<Option<Touched>>, // since the last `take_touched()` call (`None` if unknown, e.g. on startup)
+    max_reorg_depth: Option<usize>,
+    deep_reorg_allowed: AtomicBool, // a single deep reorg was authorized by the operator
+    refused_reorg: Mutex<Option<usize>>, // the depth of a refused deep reorg (the index is stalled)
+    checkpoints: Checkpoints,
+    contradicted_checkpoint: Mutex<Option<usize>>, // by bitcoind's headers (the index is stalled)
+    missing_block: Mutex<Option<MissingBlock>>,    // the index is stalled after a few retries
+    deep_reorg_pending: AtomicBool,                // an authorized deep reorg is not published yet
+    deep_reorgs: AtomicUsize, // # of published deep reorgs (or ones disconnecting final blocks)
+    generation: AtomicU64,    // incremented when a new tip is published
+    blocks_dir: Mutex<Option<BlocksDir>>, // used only during the initial sync
+    maintenance: Option<Scheduler>,
+    initial_sync: Mutex<Option<(Instant, Timings)>>, // summarized every `SUMMARY_BLOCKS` blocks
+    prefetch_blocks: usize, // the last blocks retained for `take_recent_blocks()`
+    recent: Mutex<Vec<(BlockHash, SerBlock)>>, // published, but not taken yet
+    totals: Totals,
+    totals_persisted: Mutex<Instant>,
+}
+
+/// Indexed by `sync()` (its rows are written to the DB), but not published yet
+struct Pending {
+    chain: Arc<Chain>,
+    touched: Option<Touched>,
+    recent: VecDeque<(BlockHash, SerBlock)>, // the last indexed blocks (see `prefetch_blocks`)
 }
 
 impl Index {
@@ -96,82 +371,707 @@ impl Index {
         store: DBStore,
         mut chain: Chain,
         metrics: &Metrics,
-        batch_size: usize,
-        lookup_limit: Option<usize>,
-        reindex_last_blocks: usize,
+        options: &IndexOptions,
     ) -> Result<Self> {
-        if let Some(row) = store.get_tip() {
-            let tip = deserialize(&row).expect("invalid tip");
-            let headers = store
-                .iter_headers()
-                .map(|row| HeaderRow::from_db_row(row).header);
-            chain.load(headers, tip);
-            chain.drop_last_headers(reindex_last_blocks);
-        };
+        let IndexOptions {
+            batch_size,
+            lookup_limit,
+            reindex_last_blocks,
+            merkle_cache,
+            ref script_types,
+            recent_headers,
+            final_depth,
+        } = *options;
+        chain.set_final_depth(final_depth);
+        let store = Arc::new(store);
+        store.set_merkle_cache(merkle_cache);
+        if !script_types.is_all() {
+            info!("indexing only {} outputs", script_types);
+        }
+        let tip: Option<BlockHash> = store
+            .get_tip()
+            .map(|row| deserialize(&row).expect("invalid tip"));
+        // with `recent_headers`, only the last indexed headers are loaded (if they are stored)
+        let mut loaded = false;
+        if recent_headers.is_none() {
+            store.drop_height_rows();
+        }
+        if let Some(recent) = recent_headers {
+            if store.has_height_rows() && store.header_at(0).is_none() {
+                // the genesis block is not indexed
+                let genesis = chain.get_block_header(0).expect("missing genesis");
+                let hash = genesis.block_hash().to_byte_array();
+                store.write_height_rows(std::iter::once((
+                    0,
+                    hash,
+                    HeaderRow::new(genesis).to_db_row(),
+                )));
+            }
+            chain.set_header_store(Arc::clone(&store) as _, recent);
+            if let (Some(tip), true) = (tip, store.has_height_rows()) {
+                loaded = chain.load_recent(tip);
+                if !loaded {
+                    warn!("failed to load the recent headers, loading all of them");
+                }
+            }
+        }
+        let mut synced = vec![]; // the headers whose blocks are not indexed yet
+        let mut failed = None; // stops the scan at the first invalid row
+        let indexed = store
+            .iter_headers()
+            .map_while(|row| row.map_err(|e| failed = Some(e)).ok())
+            .filter_map(|(row, indexed)| {
+                let header = HeaderRow::from_db_row(row).header;
+                if !indexed {
+                    synced.push(header);
+                }
+                (indexed && !loaded).then_some(header)
+            });
+        match tip {
+            Some(tip) if !loaded => chain.load(indexed, tip),
+            _ => indexed.for_each(drop), // only synced headers may be stored
+        }
+        if let Some(e) = failed {
+            return Err(e.context("failed to load the stored headers"));
+        }
+        if recent_headers.is_some() && !loaded {
+            if !store.has_height_rows() {
+                // the indexed headers were not written by height (e.g. by older versions)
+                store.write_height_rows((0..=chain.height()).map(|height| {
+                    let header = chain.get_block_header(height).expect("missing header");
+                    let hash = header.block_hash().to_byte_array();
+                    (height, hash, HeaderRow::new(header).to_db_row())
+                }));
+            }
+            chain.evict_headers(chain.height(), chain.tip());
+        }
+        let mut headers = chain.clone();
+        headers.extend(synced.into_iter());
+        chain.drop_last_headers(reindex_last_blocks);
+        let totals = Totals::new(metrics);
+        totals.restore(&store.get_totals());
         let stats = Stats::new(metrics);
         stats.observe_chain(&chain);
+        stats.observe_headers(&headers);
         stats.observe_db(&store);
+        let chain = Arc::new(chain);
         Ok(Index {
             store,
             batch_size,
             lookup_limit,
-            chain,
+            chain: RwLock::new(Arc::clone(&chain)),
+            headers: RwLock::new(Arc::new(headers)),
+            pending: Mutex::new(Pending {
+                chain,
+                touched: Some(Touched::default()),
+                recent: VecDeque::new(),
+            }),
             stats,
-            is_ready: false,
-            flush_needed: false,
+            is_ready: AtomicBool::new(false),
+            flusher: Mutex::new(Flusher::new(FlushPolicy::default(), Instant::now())),
+            defer_compaction: false,
+            compaction_requested: AtomicBool::new(false),
+            compacting: Mutex::new(()),
+            health: metrics.health().clone(),
+            merkle_cache,
+            height_rows: recent_headers.is_some(),
+            script_types: script_types.clone(),
+            touched: Mutex::new(None),
+            max_reorg_depth: None,
+            deep_reorg_allowed: AtomicBool::new(false),
+            refused_reorg: Mutex::new(None),
+            checkpoints: Checkpoints::default(),
+            contradicted_checkpoint: Mutex::new(None),
+            missing_block: Mutex::new(None),
+            deep_reorg_pending: AtomicBool::new(false),
+            deep_reorgs: AtomicUsize::new(0),
+            generation: AtomicU64::new(0),
+            blocks_dir: Mutex::new(None),
+            totals,
+            totals_persisted: Mutex::new(Instant::now()),
+            maintenance: None,
+            initial_sync: Mutex::new(Some((Instant::now(), Timings::default()))),
+            prefetch_blocks: 0,
+            recent: Mutex::default(),
         })
     }
 
-    pub(crate) fn chain(&self) -> &Chain {
-        &self.chain
+    /// Reorgs deeper than `max_depth` blocks are refused (stalling the index), unless authorized
+    /// via `allow_deep_reorg()` (or `allowed` is set, authorizing the next sync's reorg only).
+    pub(crate) fn set_max_reorg_depth(&mut self, max_depth: Option<usize>, allowed: bool) {
+        self.max_reorg_depth = max_depth;
+        self.deep_reorg_allowed = AtomicBool::new(allowed);
+    }
+
+    /// Flush the written rows according to `policy`. If `defer_compaction` is set, the initial
+    /// full compaction is performed only when requested (see `request_compaction()`) or during
+    /// the maintenance window, instead of right after the initial sync.
+    pub(crate) fn set_flush_policy(&mut self, policy: FlushPolicy, defer_compaction: bool) {
+        *self.flusher.get_mut() = Flusher::new(policy, Instant::now());
+        self.defer_compaction = defer_compaction;
+    }
+
+    /// Compact the DB incrementally during the given daily window (see `maintain()`).
+    pub(crate) fn set_maintenance_window(&mut self, window: Window) {
+        if let Some(last_run) = self.store.last_maintenance() {
+            self.stats.maintenance.set("last_run", last_run as f64);
+        }
+        self.maintenance = Some(Scheduler::new(window));
+    }
+
+    /// Retain the last `count` blocks of bitcoind's chain when they are indexed, so their data
+    /// can be cached once they are published (see `take_recent_blocks()`).
+    pub(crate) fn set_prefetch_blocks(&mut self, count: usize) {
+        self.prefetch_blocks = count;
+    }
+
+    /// Read the blocks of the initial sync from bitcoind's block files (if they are found there).
+    pub(crate) fn set_blocks_dir(&mut self, blocks_dir: BlocksDir) {
+        *self.blocks_dir.get_mut() = Some(blocks_dir);
+    }
+
+    /// Refuse headers contradicting `checkpoints` (stalling the index). A loaded chain which
+    /// contradicts them is rolled back below the first contradicted one, so its blocks are
+    /// re-indexed (or refused, if bitcoind's chain contradicts the checkpoint too).
+    pub(crate) fn set_checkpoints(&mut self, checkpoints: Checkpoints) {
+        let pending = self.pending.get_mut();
+        for (name, chain) in [
+            ("synced headers", self.headers.get_mut()),
+            ("indexed chain", &mut pending.chain),
+        ] {
+            if let Some((height, expected, found)) = checkpoints.contradicted_by(chain) {
+                error!(
+                    "the {} contradict checkpoint {} at height {} (found block {}): \
+                    rolling back to height {}",
+                    name,
+                    expected,
+                    height,
+                    found,
+                    height - 1
+                );
+                let stale = chain.height() + 1 - height;
+                Arc::make_mut(chain).drop_last_headers(stale);
+            }
+        }
+        *self.chain.get_mut() = Arc::clone(&pending.chain);
+        self.stats.observe_chain(&pending.chain);
+        self.stats.observe_headers(self.headers.get_mut());
+        self.checkpoints = checkpoints;
+    }
+
+    /// Take over the metrics, the health state and the counters of the `serving` index, which is
+    /// replaced by this one (see `shadow_migration`). The published chain generation is bumped
+    /// and the touched rows are unknown, so all the derived data is recomputed.
+    pub(crate) fn adopt(&mut self, serving: &Index) {
+        self.stats = serving.stats.clone();
+        self.health = serving.health.clone();
+        self.totals = serving.totals.clone();
+        *self.generation.get_mut() = serving.generation() + 1;
+        *self.deep_reorgs.get_mut() = serving.deep_reorgs();
+        *self.touched.get_mut() = None;
+        self.stats.observe_chain(self.chain.get_mut());
+        self.stats.observe_headers(self.headers.get_mut());
+        self.stats.observe_db(&self.store);
+        self.persist_totals(true);
+    }
+
+    /// Authorize the refused deep reorg (applied on the next sync), returning its depth
+    /// (or `None` if the index is not stalled).
+    pub(crate) fn allow_deep_reorg(&self) -> Option<usize> {
+        let depth = *self.refused_reorg.lock();
+        if let Some(depth) = depth {
+            warn!("reorg of {} blocks authorized by the operator", depth);
+            self.deep_reorg_allowed.store(true, Ordering::SeqCst);
+        }
+        depth
+    }
+
+    /// Compact the next DB slice, if `maintenance_window` is set and the current local time is
+    /// inside it. Returns `true` if a slice was compacted.
+    pub(crate) fn maintain(&self) -> bool {
+        let now = limits::unix_now();
+        self.maintain_at(maintenance::local_minute_of_day(now), now)
+    }
+
+    /// Like `maintain()`, at the given minute of the day (used by tests to control the clock)
+    pub(crate) fn maintain_at(&self, minute: u16, now: u64) -> bool {
+        let scheduler = match &self.maintenance {
+            Some(scheduler) if scheduler.is_due(minute) => scheduler,
+            _ => return false,
+        };
+        let deferred = self.is_compaction_deferred();
+        // don't interfere with the initial sync's compaction, or with a reorg being resolved
+        if (self.store.is_bulk_import() && !deferred)
+            || self.is_stalled()
+            || self.is_deep_reorg_pending()
+        {
+            debug!("skipping DB maintenance");
+            return false;
+        }
+        if deferred {
+            self.compact("maintenance_window");
+            return true;
+        }
+        let reclaimed = self
+            .stats
+            .observe_duration("maintenance", || self.store.compact_next_slice(now));
+        let total = scheduler.compacted(reclaimed);
+        self.stats.maintenance.set("last_run", now as f64);
+        self.stats.maintenance.set("reclaimed_bytes", total as f64);
+        true
+    }
+
+    /// Returns `true` if the initial sync is done, but its full compaction is deferred (see
+    /// `set_flush_policy()`).
+    pub(crate) fn is_compaction_deferred(&self) -> bool {
+        self.defer_compaction && self.is_ready() && self.store.is_bulk_import()
+    }
+
+    /// Request the deferred initial full compaction (performed by the next sync), returning
+    /// `false` if the DB is already compacted.
+    pub(crate) fn request_compaction(&self) -> bool {
+        if !self.store.is_bulk_import() {
+            return false;
+        }
+        info!("full compaction requested by the operator");
+        self.compaction_requested.store(true, Ordering::SeqCst);
+        true
+    }
+
+    /// Perform the initial full compaction (also flushing the written rows).
+    fn compact(&self, trigger: &str) {
+        let _compacting = self.compacting.lock(); // a concurrent compaction is not repeated
+        let start = Instant::now();
+        let compacted = self
+            .stats
+            .observe_duration("full_compaction", || self.store.compact_initial());
+        self.flusher.lock().flushed(Instant::now());
+        if !compacted {
+            return;
+        }
+        let duration = start.elapsed();
+        info!(
+            trigger = trigger,
+            duration = duration.as_secs_f64();
+            "full compaction ({}) took {:?}", trigger, duration
+        );
+        self.stats.full_compactions.inc(trigger);
+        self.stats.observe_db(&self.store); // refresh the estimates after compaction
+    }
+
+    /// Flush the written rows into SST files.
+    fn flush(&self, trigger: Trigger) {
+        let start = Instant::now();
+        self.stats
+            .observe_duration("flush", || self.store.flush_memtables());
+        self.flusher.lock().flushed(Instant::now());
+        let duration = start.elapsed();
+        info!(
+            trigger = trigger.label(),
+            duration = duration.as_secs_f64();
+            "DB flush ({}) took {:?}", trigger, duration
+        );
+        self.stats.flushes.inc(trigger.label());
+        self.stats.observe_db(&self.store);
+    }
+
+    /// The underlying DB (e.g. for comparing a replayed index with a clean one)
+    pub(crate) fn store(&self) -> &DBStore {
+        &self.store
+    }
+
+    /// The estimated number of keys and the SST files' size (in bytes) of each column family
+    pub(crate) fn db_stats(&self) -> Result<Vec<(&'static str, u64, u64)>> {
+        let sizes = self.store.cf_sizes()?;
+        Ok(self
+            .store
+            .estimate_num_keys()
+            .into_iter()
+            .zip(sizes)
+            .map(|((cf, keys), (_, size))| (cf, keys, size))
+            .collect())
+    }
+
+    /// Changed only when a new tip is published (by new blocks or a reorg), so data derived from
+    /// the published chain can be reused.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// The number of published reorgs deeper than `max_reorg_depth`, or disconnecting blocks
+    /// assumed to be final (since startup).
+    pub(crate) fn deep_reorgs(&self) -> usize {
+        self.deep_reorgs.load(Ordering::SeqCst)
+    }
+
+    /// Returns `true` if such a reorg was indexed, but not published yet.
+    pub(crate) fn is_deep_reorg_pending(&self) -> bool {
+        self.deep_reorg_pending.load(Ordering::SeqCst)
+    }
+
+    /// Returns `true` if a deep reorg was refused, if bitcoind's headers contradict a checkpoint,
+    /// or if bitcoind doesn't have a block to be indexed (the previous chain is still served).
+    pub(crate) fn is_stalled(&self) -> bool {
+        self.refused_reorg.lock().is_some()
+            || self.contradicted_checkpoint.lock().is_some()
+            || self
+                .missing_block
+                .lock()
+                .as_ref()
+                .is_some_and(MissingBlock::is_stalled)
+    }
+
+    /// The block bitcoind failed to provide on the last sync (if any)
+    pub(crate) fn missing_block(&self) -> Option<MissingBlock> {
+        self.missing_block.lock().clone()
+    }
+
+    /// Track a block which bitcoind failed to provide (`None` after the blocks were fetched),
+    /// logging only its changes.
+    fn check_missing_block(
+        &self,
+        daemon: &dyn Node,
+        new_headers: &[NewHeader],
+        missing: Option<BlockHash>,
+    ) -> Result<()> {
+        let mut prev = self.missing_block.lock();
+        let next = missing.map(|blockhash| -> Result<MissingBlock> {
+            let height = new_headers
+                .iter()
+                .find(|h| h.hash() == blockhash)
+                .map(|h| h.height())
+                .with_context(|| {
+                    format!("bitcoind is missing an unexpected block {}", blockhash)
+                })?;
+            let failures = match &*prev {
+                Some(prev) if prev.blockhash == blockhash => prev.failures + 1,
+                _ => 1,
+            };
+            let prune_height = daemon.get_prune_height().unwrap_or_else(|e| {
+                warn!("{:#}", e);
+                None
+            });
+            Ok(MissingBlock {
+                height,
+                blockhash,
+                failures,
+                prune_height,
+            })
+        });
+        let next = next.transpose()?;
+        match (&*prev, &next) {
+            (_, Some(next)) if next.failures <= MISSING_BLOCK_RETRIES => warn!(
+                "{} (retry {} of {})",
+                next, next.failures, MISSING_BLOCK_RETRIES
+            ),
+            (_, Some(next)) if next.failures == MISSING_BLOCK_RETRIES + 1 => error!(
+                "{}: indexing is stalled until bitcoind provides it (serving the current chain)",
+                next
+            ),
+            (Some(prev), None) => info!(
+                "block {} at height {} is available again",
+                prev.blockhash, prev.height
+            ),
+            _ => (),
+        }
+        let height = next.as_ref().map_or(0, |next| next.height);
+        let failures = next.as_ref().map_or(0, |next| next.failures);
+        self.stats.missing_block.set("height", height as f64);
+        self.stats.missing_block.set("failures", failures as f64);
+        self.health.update(|s| s.missing_block = next.clone());
+        *prev = next;
+        Ok(())
+    }
+
+    /// Stall the index while bitcoind's headers contradict a checkpoint (logged only once).
+    fn check_checkpoint(&self, contradicted: Option<(usize, BlockHash, BlockHash)>) {
+        let mut prev = self.contradicted_checkpoint.lock();
+        let height = contradicted.map(|(height, ..)| height);
+        if *prev == height {
+            return;
+        }
+        match contradicted {
+            Some((height, expected, found)) => error!(
+                "refusing bitcoind's chain: its block {} at height {} contradicts checkpoint {} \
+                (serving the current chain until bitcoind's chain matches the checkpoints)",
+                found, height, expected
+            ),
+            None => info!("bitcoind's chain matches the checkpoints"),
+        }
+        *prev = height;
+        self.health.update(|s| s.contradicted_checkpoint = height);
+    }
+
+    /// Returns `false` if the reorg (disconnecting `depth` blocks of `chain`) must be refused.
+    fn check_reorg(&self, chain: &Chain, depth: usize, fork: BlockHash) -> bool {
+        let mut refused = self.refused_reorg.lock();
+        let too_deep = self
+            .max_reorg_depth
+            .is_some_and(|max_depth| depth > max_depth);
+        // the authorization applies only to the next reorg check (so it can't accept a later,
+        // unrelated deep reorg)
+        let allowed = self.deep_reorg_allowed.swap(false, Ordering::SeqCst);
+        if allowed && !too_deep {
+            info!(
+                "no deep reorg to authorize (reorg of {} blocks after {})",
+                depth, fork
+            );
+        }
+        if too_deep && !allowed {
+            if *refused != Some(depth) {
+                error!(
+                    "refusing a reorg of {} blocks after {} (deeper than max_reorg_depth={}): \
+                    serving the current chain until it is authorized by the operator \
+                    (using `electrs.reorg.allow` RPC or `--allow-deep-reorg` flag)",
+                    depth,
+                    fork,
+                    self.max_reorg_depth.unwrap()
+                );
+            }
+            *refused = Some(depth);
+            self.stats.reorg_depth.set("refused", depth as f64);
+            self.health.update(|s| s.refused_reorg_depth = Some(depth));
+            return false;
+        }
+        let disconnects_final = chain.is_final(chain.height() + 1 - depth);
+        if disconnects_final {
+            // the data confirmed by final blocks was cached without revalidation
+            error!(
+                "a reorg of {} blocks after {} disconnects blocks assumed to be final \
+                (deeper than assume_final_depth={}): the cached data will be dropped",
+                depth,
+                fork,
+                chain.final_depth().unwrap()
+            );
+        }
+        if too_deep || disconnects_final {
+            self.deep_reorg_pending.store(true, Ordering::SeqCst);
+        }
+        if refused.take().is_some() {
+            self.stats.reorg_depth.set("refused", 0.0);
+            self.health.update(|s| s.refused_reorg_depth = None);
+        }
+        if depth > 0 {
+            self.stats.reorg_depth.set("last", depth as f64);
+            self.totals.reorgs.inc();
+        }
+        true
+    }
+
+    /// The monotonic counters, which continue across restarts
+    pub(crate) fn totals(&self) -> &Totals {
+        &self.totals
+    }
+
+    /// Persist the monotonic counters (a single small write), unless they were persisted recently
+    /// and `force` is not set. A failure is only logged, since serving is not affected.
+    pub(crate) fn persist_totals(&self, force: bool) {
+        let mut persisted = self.totals_persisted.lock();
+        if !force && persisted.elapsed() < TOTALS_PERSIST_INTERVAL {
+            return;
+        }
+        if let Err(e) = self.store.set_totals(&self.totals.values()) {
+            warn!("{:#}", e);
+        }
+        *persisted = Instant::now();
+    }
+
+    /// The last published chain: its blocks are fully indexed, and it is not modified by `sync()`
+    /// (so queries should use the same snapshot throughout, to get a consistent view).
+    pub(crate) fn chain(&self) -> Arc<Chain> {
+        Arc::clone(&self.chain.read())
+    }
+
+    /// The best known headers if they extend the published chain (otherwise, e.g. during a
+    /// refused deep reorg, the published chain), so headers are served up to bitcoind's tip
+    /// even while their blocks are being indexed.
+    pub(crate) fn headers(&self) -> Arc<Chain> {
+        let chain = self.chain();
+        let headers = Arc::clone(&self.headers.read());
+        if headers.get_block_hash(chain.height()) == Some(chain.tip()) {
+            headers
+        } else {
+            chain
+        }
+    }
+
+    /// Make the blocks indexed by `sync()` visible to queries (the write lock is held only for the
+    /// pointer swap), and add their touched rows to the ones returned by `take_touched()`.
+    pub(crate) fn publish(&self) {
+        let mut pending = self.pending.lock();
+        let touched = pending.touched.replace(Touched::default());
+        {
+            let mut published = self.touched.lock();
+            *published = match (published.take(), touched) {
+                (Some(mut published), Some(touched)) => {
+                    published.merge(touched);
+                    Some(published).filter(|t| !t.is_full())
+                }
+                _ => None,
+            };
+        }
+        if !pending.recent.is_empty() {
+            let mut recent = self.recent.lock();
+            recent.extend(pending.recent.drain(..));
+            let excess = recent.len().saturating_sub(self.prefetch_blocks);
+            recent.drain(..excess);
+        }
+        let prev = std::mem::replace(&mut *self.chain.write(), Arc::clone(&pending.chain));
+        if prev.tip() != pending.chain.tip() {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+        if self.deep_reorg_pending.swap(false, Ordering::SeqCst) {
+            self.deep_reorgs.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// The recently indexed blocks which were published since the last call (at most
+    /// `prefetch_blocks` ones), to be cached before their notifications are sent.
+    pub(crate) fn take_recent_blocks(&self) -> Vec<(BlockHash, SerBlock)> {
+        std::mem::take(&mut *self.recent.lock())
     }
 
     pub(crate) fn limit_result<T>(&self, entries: impl Iterator<Item = T>) -> Result<Vec<T>> {
+        let (result, truncated) = self.truncate_result(entries);
+        if truncated {
+            bail!(">{} index entries, query may take too long", result.len())
+        }
+        Ok(result)
+    }
+
+    /// Like `limit_result()`, but returns the first entries (and whether more were skipped).
+    pub(crate) fn truncate_result<T>(&self, entries: impl Iterator<Item = T>) -> (Vec<T>, bool) {
         let mut entries = entries.fuse();
         let result: Vec<T> = match self.lookup_limit {
             Some(lookup_limit) => entries.by_ref().take(lookup_limit).collect(),
             None => entries.by_ref().collect(),
         };
-        if entries.next().is_some() {
-            bail!(">{} index entries, query may take too long", result.len())
-        }
-        Ok(result)
+        let truncated = entries.next().is_some();
+        (result, truncated)
+    }
+
+    // The following lookups return only the blocks of the given `chain` snapshot: rows written by
+    // a concurrent `sync()` (above its tip) are skipped, and rows of a concurrently reorged block
+    // are mapped to the snapshot's block at the same height (which doesn't contain them).
+
+    pub(crate) fn filter_by_txid<'a>(
+        &'a self,
+        chain: &'a Chain,
+        txid: Txid,
+    ) -> impl Iterator<Item = BlockHash> + 'a {
+        self.txid_rows(txid)
+            .map(|row| row.height())
+            .filter_map(move |height| chain.get_block_hash(height))
+    }
+
+    pub(crate) fn filter_by_funding<'a>(
+        &'a self,
+        chain: &'a Chain,
+        scripthash: ScriptHash,
+    ) -> impl Iterator<Item = BlockHash> + 'a {
+        self.filter_by_funding_outputs(chain, scripthash)
+            .map(|(blockhash, _vouts)| blockhash)
     }
 
-    pub(crate) fn filter_by_txid(&self, txid: Txid) -> impl Iterator<Item = BlockHash> + '_ {
+    /// Like `filter_by_funding()`, also returning the indices of the outputs which may fund
+    /// `scripthash` in each block (`None` if the block was indexed before they were stored).
+    pub(crate) fn filter_by_funding_outputs<'a>(
+        &'a self,
+        chain: &'a Chain,
+        scripthash: ScriptHash,
+    ) -> impl Iterator<Item = (BlockHash, Option<Vec<u32>>)> + 'a {
+        group_by_height(self.funding_rows(scripthash)).filter_map(move |(height, vouts)| {
+            chain
+                .get_block_hash(height)
+                .map(|blockhash| (blockhash, vouts))
+        })
+    }
+
+    pub(crate) fn filter_by_spending<'a>(
+        &'a self,
+        chain: &'a Chain,
+        outpoint: OutPoint,
+    ) -> impl Iterator<Item = BlockHash> + 'a {
+        self.spending_rows(outpoint)
+            .map(|row| row.height())
+            .filter_map(move |height| chain.get_block_hash(height))
+    }
+
+    // The following lookups return all the matching rows (including the ones above the published
+    // tip, and prefix collisions), e.g. for debugging using `electrs scripthash-dump`.
+
+    pub(crate) fn txid_rows(&self, txid: Txid) -> impl Iterator<Item = HashPrefixRow> + '_ {
         self.store
-            .iter_txid(TxidRow::scan_prefix(txid))
-            .map(|row| HashPrefixRow::from_db_row(row).height())
-            .filter_map(move |height| self.chain.get_block_hash(height))
+            .iter_txid(TxidRow::db_prefix(txid, self.store.prefix_len()))
+            .map(HashPrefixRow::from_db_row)
     }
 
-    pub(crate) fn filter_by_funding(
+    pub(crate) fn funding_rows(
         &self,
         scripthash: ScriptHash,
-    ) -> impl Iterator<Item = BlockHash> + '_ {
+    ) -> impl Iterator<Item = HashPrefixRow> + '_ {
         self.store
-            .iter_funding(ScriptHashRow::scan_prefix(scripthash))
-            .map(|row| HashPrefixRow::from_db_row(row).height())
-            .filter_map(move |height| self.chain.get_block_hash(height))
+            .iter_funding(ScriptHashRow::db_prefix(
+                scripthash,
+                self.store.prefix_len(),
+            ))
+            .map(HashPrefixRow::from_db_row)
     }
 
-    pub(crate) fn filter_by_spending(
+    pub(crate) fn spending_rows(
         &self,
         outpoint: OutPoint,
-    ) -> impl Iterator<Item = BlockHash> + '_ {
+    ) -> impl Iterator<Item = HashPrefixRow> + '_ {
         self.store
-            .iter_spending(SpendingPrefixRow::scan_prefix(outpoint))
-            .map(|row| HashPrefixRow::from_db_row(row).height())
-            .filter_map(move |height| self.chain.get_block_hash(height))
+            .iter_spending(SpendingPrefixRow::db_prefix(
+                outpoint,
+                self.store.prefix_len(),
+            ))
+            .map(HashPrefixRow::from_db_row)
+    }
+
+    /// Returns the block's txids, if stored in the DB (see `merkle_cache`).
+    pub(crate) fn get_block_txids(&self, blockhash: BlockHash) -> Option<Vec<Txid>> {
+        let txids = self.store.get_block_txids(blockhash.to_byte_array())?;
+        Some(
+            txids
+                .chunks_exact(Txid::LEN)
+                .map(|txid| Txid::from_slice(txid).expect("invalid txid"))
+                .collect(),
+        )
     }
 
-    // Return `Ok(true)` when the chain is fully synced and the index is compacted.
-    pub(crate) fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<bool> {
-        let new_headers = self
+    /// Apply `func` on the rows touched by the blocks published since the previous
+    /// `take_touched()` call (or `None` if unknown), without resetting them.
+    pub(crate) fn with_touched<T>(&self, func: impl FnOnce(Option<&Touched>) -> T) -> T {
+        func(self.touched.lock().as_ref())
+    }
+
+    /// Return the rows touched (and the blocks disconnected) by the blocks published since the
+    /// previous call (or `None` if unknown).
+    pub(crate) fn take_touched(&self) -> Option<Touched> {
+        self.touched.lock().replace(Touched::default())
+    }
+
+    // Return `Ok(true)` when the chain is fully synced (and the index is compacted, unless the
+    // compaction is deferred).
+    // The new blocks are visible to queries only after `publish()` is called.
+    // Should not be called concurrently (it is called by a single sync thread).
+    pub(crate) fn sync(&self, daemon: &dyn Node, exit_flag: &ExitFlag) -> Result<bool> {
+        let chain = Arc::clone(&self.pending.lock().chain);
+        let headers = self
             .stats
-            .observe_duration("headers", || daemon.get_new_headers(&self.chain))?;
-        match (new_headers.first(), new_headers.last()) {
+            .observe_duration("headers", || self.sync_headers(daemon, exit_flag))?;
+        let new_headers = headers.new_headers(&chain, MAX_BLOCKS_PER_SYNC);
+        let (first_height, last_height) = match (new_headers.first(), new_headers.last()) {
             (Some(first), Some(last)) => {
+                let depth = (chain.height() + 1).saturating_sub(first.height()); // disconnected blocks
+                let fork = chain
+                    .get_block_hash(first.height() - 1)
+                    .expect("missing fork block");
+                if !self.check_reorg(&chain, depth, fork) {
+                    return Ok(true); // stalled (the current chain is kept)
+                }
                 let count = new_headers.len();
                 info!(
                     "indexing {} blocks: [{}..{}]",
@@ -179,18 +1079,65 @@ impl Index {
                     first.height(),
                     last.height()
                 );
+                self.health
+                    .update(|s| s.daemon_height = Some(headers.height()));
+                (first.height(), last.height())
             }
             _ => {
-                if self.flush_needed {
-                    self.store.flush(); // full compaction is performed on the first flush call
-                    self.flush_needed = false;
+                self.check_missing_block(daemon, &new_headers, None)?; // e.g. reorged out
+                if self.disconnect_stale_tip(daemon, &chain)? {
+                    return Ok(false); // the chain was rolled back
+                }
+                if self.store.is_bulk_import()
+                    && (!self.defer_compaction
+                        || self.compaction_requested.swap(false, Ordering::SeqCst))
+                {
+                    let trigger = match self.defer_compaction {
+                        true => "admin",
+                        false => "initial_sync",
+                    };
+                    self.compact(trigger);
                 }
-                self.is_ready = true;
+                let trigger = self.flusher.lock().sync_done(Instant::now());
+                if let Some(trigger) = trigger {
+                    self.flush(trigger);
+                }
+                self.store.sync_wal();
+                self.persist_totals(false);
+                if let Some(blocks_dir) = self.blocks_dir.lock().take() {
+                    info!(
+                        "{} blocks were read from block files",
+                        blocks_dir.read_blocks()
+                    );
+                }
+                self.is_ready.store(true, Ordering::Relaxed);
+                let height = chain.height();
+                self.health.update(|s| s.daemon_height = Some(height));
                 return Ok(true); // no more blocks to index (done for now)
             }
+        };
+
+        if let Some(blocks_dir) = self.blocks_dir.lock().as_mut() {
+            if !blocks_dir.is_scanned() {
+                // scanned once (the blocks found later are fetched from bitcoind)
+                self.stats
+                    .observe_duration("scan_blocks_dir", || {
+                        blocks_dir.scan(&headers, chain.height())
+                    })
+                    .context("failed to scan daemon_blocks_dir")?;
+            }
         }
 
-        thread::scope(|scope| -> Result<()> {
+        let mut touched = self.pending.lock().touched.take();
+        if let Some(t) = &mut touched {
+            // reorg: statuses with entries in the disconnected blocks are affected
+            t.add_stale_blocks(
+                (first_height..=chain.height()).filter_map(|h| chain.get_block_hash(h)),
+            );
+        }
+        // cleared once the touched rows are unknown (so their prefixes are not collected)
+        let track_touched = &AtomicBool::new(touched.is_some());
+        let result = thread::scope(|scope| -> Result<Option<Touched>> {
             let (tx, rx) = crossbeam_channel::bounded(1);
 
             let chunks = new_headers.chunks(self.batch_size);
@@ -205,8 +1152,9 @@ impl Index {
                                 chunk.first().unwrap().height()
                             )
                         })?;
-                        let batch = index.index_blocks(daemon, chunk)?;
-                        tx.send(batch).context("writer disconnected")?;
+                        let collect_prefixes = track_touched.load(Ordering::Relaxed);
+                        let indexed = index.index_blocks(daemon, chunk, collect_prefixes)?;
+                        tx.send(indexed).context("writer disconnected")?;
                     }
                     Ok(()) // `tx` is dropped, to stop the iteration on `rx`
                 })
@@ -216,38 +1164,205 @@ impl Index {
             let writer = thread::Builder::new()
                 .name("index_write".into())
                 .spawn_scoped(scope, move || {
-                    let stats = &index.stats;
-                    for mut batch in rx {
-                        stats.observe_duration("sort", || batch.sort()); // pre-sort to optimize DB writes
-                        stats.observe_batch(&batch);
-                        stats.observe_duration("write", || index.store.write(&batch));
-                        stats.observe_db(&index.store);
+                    let notifier = systemd::notifier();
+                    let mut height = first_height;
+                    for (mut batch, mut timings) in rx {
+                        index.write_batch(&mut batch, &mut timings);
+                        let trigger = index.flusher.lock().written(
+                            batch.header_rows.len(),
+                            batch.size() as u64,
+                            Instant::now(),
+                        );
+                        if let Some(trigger) = trigger {
+                            index.flush(trigger);
+                        }
+                        if let Some(t) = &mut touched {
+                            t.add_funding(batch.funding_prefixes.iter().copied());
+                            t.add_spending(batch.spending_prefixes.iter().copied());
+                        }
+                        touched = touched.filter(|t| !t.is_full());
+                        if touched.is_none() {
+                            track_touched.store(false, Ordering::Relaxed);
+                        }
+
+                        height += batch.header_rows.len();
+                        index
+                            .totals
+                            .blocks_indexed
+                            .inc_by(batch.header_rows.len() as u64);
+                        notifier.watchdog(); // initial sync may take a few hours
+                        index.summarize_initial_sync(&timings);
+                        if !index.is_ready.load(Ordering::Relaxed) {
+                            notifier.status(&format!(
+                                "Indexing: {} of {} blocks ({:.2}%)",
+                                height,
+                                last_height + 1,
+                                100.0 * height as f64 / (last_height + 1) as f64
+                            ));
+                        }
                     }
+                    touched
                 })
                 .expect("spawn failed");
 
             reader.join().expect("reader thread panic")?;
-            writer.join().expect("writer thread panic");
-            Ok(())
-        })?;
-        self.chain.update(new_headers);
-        self.stats.observe_chain(&self.chain);
-        self.flush_needed = true;
+            Ok(writer.join().expect("writer thread panic"))
+        });
+        let touched = match result {
+            Ok(touched) => touched,
+            Err(e) => match BlockNotAvailable::find(&e) {
+                Some(blockhash) => {
+                    // retried on the next sync (the touched rows are unknown, since the
+                    // preceding blocks may have been written)
+                    self.check_missing_block(daemon, &new_headers, Some(blockhash))?;
+                    return Ok(true);
+                }
+                None => return Err(e),
+            },
+        };
+        self.check_missing_block(daemon, &new_headers, None)?;
+        let mut chain = chain;
+        let indexed = Arc::make_mut(&mut chain); // copied, if already published
+        indexed.update(new_headers);
+        indexed.evict_headers(indexed.height(), indexed.tip());
+        let (height, tip) = (chain.height(), chain.tip());
+        self.stats.observe_chain(&chain);
+        {
+            let mut pending = self.pending.lock();
+            pending.chain = chain;
+            pending.touched = touched;
+        }
+        drop(headers);
+        let mut headers = self.headers.write();
+        if headers.has_evictable_headers(height) {
+            // the synced headers are evicted only if they extend the indexed chain
+            Arc::make_mut(&mut headers).evict_headers(height, tip);
+        }
         Ok(false) // sync is not done
     }
 
-    fn index_blocks(&self, daemon: &Daemon, chunk: &[NewHeader]) -> Result<WriteBatch> {
-        let blockhashes: Vec<BlockHash> = chunk.iter().map(|h| h.hash()).collect();
-        let mut heights = chunk.iter().map(|h| h.height());
+    /// Download the new headers (up to 2000 per P2P `headers` message), verify them and persist
+    /// them (before their blocks are indexed), returning the best known headers.
+    fn sync_headers(&self, daemon: &dyn Node, exit_flag: &ExitFlag) -> Result<Arc<Chain>> {
+        let mut headers = Arc::clone(&self.headers.read());
+        let contradicted = loop {
+            let new_headers = daemon.get_new_headers(&headers)?;
+            if new_headers.is_empty() {
+                break None;
+            }
+            let contradicted = self.checkpoints.contradicted_by_headers(&new_headers);
+            if contradicted.is_some() {
+                break contradicted; // the synced headers are kept
+            }
+            headers
+                .verify(&new_headers)
+                .context("bitcoind sent invalid headers")?;
+            let rows: Vec<_> = new_headers
+                .iter()
+                .map(|h| HeaderRow::new(*h.header()).to_db_row())
+                .collect();
+            self.store.write_headers(&rows);
+            // copied only once, since the headers are published after the loop
+            Arc::make_mut(&mut headers).update(new_headers);
+            self.stats.observe_headers(&headers);
+            exit_flag.poll().context("header sync interrupted")?;
+        };
+        self.check_checkpoint(contradicted);
+        *self.headers.write() = Arc::clone(&headers);
+        Ok(headers)
+    }
+
+    /// Roll back the chain if its tip was disconnected by bitcoind without being replaced by a
+    /// longer chain (e.g. via `invalidateblock`), since no new headers are announced in this case.
+    fn disconnect_stale_tip(&self, daemon: &dyn Node, chain: &Arc<Chain>) -> Result<bool> {
+        let best = daemon.get_best_block_hash()?;
+        let height = match chain.get_block_height(&best) {
+            Some(height) if height < chain.height() => height,
+            _ => {
+                // up-to-date, or `best` is a new block (to be synced next)
+                self.check_reorg(chain, 0, best); // a previously refused reorg is not relevant anymore
+                return Ok(false);
+            }
+        };
+        let stale = chain.height() - height;
+        if !self.check_reorg(chain, stale, best) {
+            return Ok(false); // stalled (the current chain is kept)
+        }
+        warn!("reorg: dropping {} stale blocks after {}", stale, best);
+        {
+            let mut headers = self.headers.write();
+            if let Some(stale) = headers.height().checked_sub(height).filter(|n| *n > 0) {
+                Arc::make_mut(&mut headers).drop_last_headers(stale);
+                self.stats.observe_headers(&headers);
+            }
+        }
+        let mut pending = self.pending.lock();
+        if let Some(t) = &mut pending.touched {
+            t.add_stale_blocks(
+                (height + 1..=chain.height()).filter_map(|h| chain.get_block_hash(h)),
+            );
+        }
+        let mut chain = Arc::clone(chain);
+        Arc::make_mut(&mut chain).drop_last_headers(stale);
+        self.stats.observe_chain(&chain);
+        pending.chain = chain;
+        Ok(true)
+    }
 
-        let mut batch = WriteBatch::default();
+    /// The funding and spending prefixes are collected only if `collect_prefixes` is set (i.e.
+    /// if the touched rows are tracked).
+    fn index_blocks(
+        &self,
+        daemon: &dyn Node,
+        chunk: &[NewHeader],
+        collect_prefixes: bool,
+    ) -> Result<(WriteBatch, Timings)> {
+        let mut batch = WriteBatch {
+            collect_prefixes,
+            ..WriteBatch::default()
+        };
+        let mut timings = Timings::default();
+        let mut blocks_dir = self.blocks_dir.lock();
+        let mut missing = 0; // the blocks preceding `header` which should be fetched from bitcoind
+        for (i, header) in chunk.iter().enumerate() {
+            let start = Instant::now();
+            let block = blocks_dir.as_mut().and_then(|blocks_dir| {
+                self.stats
+                    .observe_duration("read_blocks_dir", || blocks_dir.read(header.hash()))
+            });
+            match block {
+                Some(block) => {
+                    let fetch = start.elapsed();
+                    self.fetch_blocks(daemon, &chunk[i - missing..i], &mut batch, &mut timings)?;
+                    missing = 0;
+                    let (hash, height) = (header.hash(), header.height());
+                    self.index_block(hash, block, height, fetch, &mut batch, &mut timings);
+                }
+                None => missing += 1,
+            }
+        }
+        let missing = &chunk[chunk.len() - missing..];
+        self.fetch_blocks(daemon, missing, &mut batch, &mut timings)?;
+        Ok((batch, timings))
+    }
 
-        daemon.for_blocks(blockhashes, |blockhash, block| {
+    /// A block's fetch duration is measured since the previous one was indexed (since bitcoind
+    /// sends them one after another).
+    fn fetch_blocks(
+        &self,
+        daemon: &dyn Node,
+        headers: &[NewHeader],
+        batch: &mut WriteBatch,
+        timings: &mut Timings,
+    ) -> Result<()> {
+        let blockhashes: Vec<BlockHash> = headers.iter().map(|h| h.hash()).collect();
+        let mut heights = headers.iter().map(|h| h.height());
+        let mut start = Instant::now();
+        daemon.for_blocks(blockhashes, &mut |blockhash, block| {
             let height = heights.next().expect("unexpected block");
-            self.stats.observe_duration("block", || {
-                index_single_block(blockhash, block, height, &mut batch);
-            });
-            self.stats.height.set("tip", height as f64);
+            let fetch = start.elapsed();
+            self.index_block(blockhash, block, height, fetch, batch, timings);
+            start = Instant::now();
         })?;
         let heights: Vec<_> = heights.collect();
         assert!(
@@ -255,69 +1370,667 @@ impl Index {
             "some blocks were not indexed: {:?}",
             heights
         );
-        Ok(batch)
+        Ok(())
+    }
+
+    fn index_block(
+        &self,
+        blockhash: BlockHash,
+        block: SerBlock,
+        height: usize,
+        fetch: Duration,
+        batch: &mut WriteBatch,
+        timings: &mut Timings,
+    ) {
+        // the synced headers' tip is bitcoind's one (so the initial sync's blocks are skipped)
+        if height + self.prefetch_blocks > self.headers.read().height() {
+            let mut pending = self.pending.lock();
+            pending.recent.push_back((blockhash, block.clone()));
+            if pending.recent.len() > self.prefetch_blocks {
+                pending.recent.pop_front();
+            }
+        }
+        let size = block.len();
+        let start = Instant::now();
+        index_single_block(
+            blockhash,
+            block,
+            height,
+            self.merkle_cache,
+            self.height_rows,
+            &self.script_types,
+            self.store.prefix_len(),
+            batch,
+        );
+        let parse = start.elapsed();
+        self.stats
+            .update_duration
+            .observe("block", parse.as_secs_f64());
+        self.stats
+            .block_duration
+            .observe("fetch", fetch.as_secs_f64());
+        self.stats
+            .block_duration
+            .observe("parse", parse.as_secs_f64());
+        timings.blocks += 1;
+        timings.bytes += size;
+        timings.fetch += fetch;
+        timings.parse += parse;
+        self.stats.height.set("tip", height as f64);
+    }
+
+    /// Sort the batch's rows (to optimize the DB write) and write them.
+    fn write_batch(&self, batch: &mut WriteBatch, timings: &mut Timings) {
+        let start = Instant::now();
+        self.stats.observe_duration("sort", || batch.sort());
+        timings.sort += start.elapsed();
+        self.stats.observe_batch(batch);
+        let start = Instant::now();
+        self.stats
+            .observe_duration("write", || self.store.write(batch));
+        timings.write += start.elapsed();
+        self.stats.observe_db(&self.store);
+        timings.rows += batch.funding_rows.len()
+            + batch.spending_rows.len()
+            + batch.txid_rows.len()
+            + batch.header_rows.len();
+    }
+
+    /// Log a summary of the initial sync every `SUMMARY_BLOCKS` indexed blocks.
+    fn summarize_initial_sync(&self, timings: &Timings) {
+        let mut initial_sync = self.initial_sync.lock();
+        if self.is_ready() {
+            *initial_sync = None;
+        }
+        let Some((start, total)) = initial_sync.as_mut() else {
+            return;
+        };
+        let prev = total.blocks / SUMMARY_BLOCKS;
+        total.add(timings);
+        if total.blocks / SUMMARY_BLOCKS == prev {
+            return;
+        }
+        let report = total.report(start.elapsed());
+        info!(
+            blocks = report.blocks,
+            rows_per_sec = report.rows_per_sec,
+            mb_per_sec = report.mb_per_sec,
+            fetch_secs = total.fetch.as_secs_f64(),
+            parse_secs = total.parse.as_secs_f64(),
+            sort_secs = total.sort.as_secs_f64(),
+            write_secs = total.write.as_secs_f64();
+            "initial sync: {} blocks indexed ({:.0} rows/s, {:.1} MB/s), cumulative phases: \
+            fetch={:.1}s parse={:.1}s sort={:.1}s write={:.1}s",
+            report.blocks,
+            report.rows_per_sec,
+            report.mb_per_sec,
+            total.fetch.as_secs_f64(),
+            total.parse.as_secs_f64(),
+            total.sort.as_secs_f64(),
+            total.write.as_secs_f64(),
+        );
+    }
+
+    /// Index the blocks of `headers` (extending the indexed chain) serially, without publishing
+    /// them, returning their timings and the elapsed time, excluding the scan of
+    /// `daemon_blocks_dir` (used by `electrs bench-index`).
+    pub(crate) fn bench(
+        &self,
+        daemon: &dyn Node,
+        headers: &Chain,
+        count: usize,
+    ) -> Result<(Timings, Duration)> {
+        let chain = self.chain();
+        if let Some(blocks_dir) = self.blocks_dir.lock().as_mut() {
+            blocks_dir
+                .scan(headers, chain.height())
+                .context("failed to scan daemon_blocks_dir")?;
+        }
+        let start = Instant::now();
+        let mut total = Timings::default();
+        for chunk in headers.new_headers(&chain, count).chunks(self.batch_size) {
+            let (mut batch, mut timings) = self.index_blocks(daemon, chunk)?;
+            self.write_batch(&mut batch, &mut timings);
+            total.add(&timings);
+        }
+        Ok((total, start.elapsed()))
     }
 
     pub(crate) fn is_ready(&self) -> bool {
-        self.is_ready
+        self.is_ready.load(Ordering::Relaxed)
     }
 }
 
+impl Drop for Index {
+    fn drop(&mut self) {
+        self.persist_totals(true); // e.g. on shutdown
+    }
+}
+
+/// Group the funding rows of each height, which are adjacent (their keys differ only by the output
+/// index), collecting their output indices (`None` if any of them was written without one).
+fn group_by_height(
+    rows: impl Iterator<Item = HashPrefixRow>,
+) -> impl Iterator<Item = (usize, Option<Vec<u32>>)> {
+    let mut rows = rows.peekable();
+    std::iter::from_fn(move || {
+        let first = rows.next()?;
+        let height = first.height();
+        let mut vouts = first.vout().map(|vout| vec![vout]);
+        while let Some(row) = rows.next_if(|row| row.height() == height) {
+            vouts = vouts.zip(row.vout()).map(|(mut vouts, vout)| {
+                vouts.push(vout);
+                vouts
+            });
+        }
+        if let Some(vouts) = &mut vouts {
+            vouts.sort_unstable();
+            vouts.dedup();
+        }
+        Some((height, vouts))
+    })
+}
+
+/// The indexing thread's buffers, reused across blocks (instead of being re-allocated per block)
+#[derive(Default)]
+struct Scratch {
+    inputs: Vec<(OutPoint, bool)>, // (prevout, has empty scriptSig)
+    witnesses: Vec<bool>,          // has non-empty witness
+}
+
+/// The scratch buffers' capacity retained after indexing a block, so a single transaction having
+/// many inputs doesn't keep them large for the rest of the sync.
+const SCRATCH_CAPACITY: usize = 1 << 12;
+
+impl Scratch {
+    fn shrink(&mut self) {
+        self.inputs.shrink_to(SCRATCH_CAPACITY);
+        self.witnesses.shrink_to(SCRATCH_CAPACITY);
+    }
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::default();
+}
+
 fn index_single_block(
     block_hash: BlockHash,
     block: SerBlock,
     height: usize,
+    merkle_cache: bool,
+    height_rows: bool,
+    script_types: &ScriptTypes,
+    prefix_len: PrefixLen,
     batch: &mut WriteBatch,
 ) {
     struct IndexBlockVisitor<'a> {
         batch: &'a mut WriteBatch,
         height: usize,
+        txids: Option<Vec<u8>>, // collected only if `merkle_cache` is enabled
+        script_types: &'a ScriptTypes,
+        prefix_len: PrefixLen,
+        // current transaction's inputs (indexed after its witnesses are visited)
+        scratch: &'a mut Scratch,
     }
 
     impl Visitor for IndexBlockVisitor<'_> {
+        fn visit_block_begin(&mut self, total_transactions: usize) {
+            self.batch.reserve_block(total_transactions);
+            if let Some(txids) = &mut self.txids {
+                txids.reserve_exact(total_transactions * Txid::LEN);
+            }
+        }
+
         fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+            let Scratch { inputs, witnesses } = &mut *self.scratch;
+            for (vin, (prevout, empty_script_sig)) in inputs.drain(..).enumerate() {
+                // skip indexing coinbase transactions' input
+                if prevout.is_null() {
+                    continue;
+                }
+                // skip indexing inputs which can't spend an output of an indexed script type
+                let has_witness = witnesses.get(vin).copied().unwrap_or(false);
+                let spent_types = ScriptType::spent_by(empty_script_sig, has_witness);
+                if self.script_types.contains_any(spent_types) {
+                    let row = SpendingPrefixRow::row(prevout, self.height, self.prefix_len);
+                    self.batch.spending_rows.push(row.to_db_row());
+                    if self.batch.collect_prefixes {
+                        self.batch
+                            .spending_prefixes
+                            .push(SpendingPrefixRow::scan_prefix(prevout));
+                    }
+                }
+            }
+            witnesses.clear();
+
             let txid = bsl_txid(tx);
+            if let Some(txids) = &mut self.txids {
+                txids.extend_from_slice(txid.as_byte_array());
+            }
             self.batch
                 .txid_rows
-                .push(TxidRow::row(txid, self.height).to_db_row());
+                .push(TxidRow::row(txid, self.height, self.prefix_len).to_db_row());
             ControlFlow::Continue(())
         }
 
-        fn visit_tx_out(&mut self, _vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+        fn visit_tx_out(&mut self, vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+            // the script is hashed in place (so its size doesn't affect the memory usage)
             let script = bitcoin::Script::from_bytes(tx_out.script_pubkey());
-            // skip indexing unspendable outputs
-            if !script.is_op_return() {
-                let row = ScriptHashRow::row(ScriptHash::new(script), self.height);
+            // skip indexing the outputs of non-indexed script types (`OP_RETURN` by default)
+            if self.script_types.contains(script) {
+                let scripthash = ScriptHash::new(script);
+                let row = ScriptHashRow::row(scripthash, self.height, vout, self.prefix_len);
                 self.batch.funding_rows.push(row.to_db_row());
+                if self.batch.collect_prefixes {
+                    self.batch
+                        .funding_prefixes
+                        .push(ScriptHashRow::scan_prefix(scripthash));
+                }
             }
             ControlFlow::Continue(())
         }
 
         fn visit_tx_in(&mut self, _vin: usize, tx_in: &bsl::TxIn) -> ControlFlow<()> {
             let prevout: OutPoint = tx_in.prevout().into();
-            // skip indexing coinbase transactions' input
-            if !prevout.is_null() {
-                let row = SpendingPrefixRow::row(prevout, self.height);
-                self.batch.spending_rows.push(row.to_db_row());
-            }
+            self.scratch
+                .inputs
+                .push((prevout, tx_in.script_sig().is_empty()));
             ControlFlow::Continue(())
         }
 
+        fn visit_witness_total_element(&mut self, witness_total: usize) {
+            self.scratch.witnesses.push(witness_total > 0);
+        }
+
         fn visit_block_header(&mut self, header: &bsl::BlockHeader) -> ControlFlow<()> {
-            let header = bitcoin::block::Header::consensus_decode(&mut header.as_ref())
-                .expect("block header was already validated");
+            // the raw header is copied as-is (without decoding and re-encoding it)
             self.batch
                 .header_rows
-                .push(HeaderRow::new(header).to_db_row());
+                .push(HeaderRow::raw_db_row(header.as_ref()));
             ControlFlow::Continue(())
         }
     }
 
-    let mut index_block = IndexBlockVisitor { batch, height };
-    bsl::Block::visit(&block, &mut index_block).expect("core returned invalid block");
+    let txids = SCRATCH.with_borrow_mut(|scratch| {
+        let mut index_block = IndexBlockVisitor {
+            batch: &mut *batch,
+            height,
+            txids: merkle_cache.then(Vec::new),
+            script_types,
+            prefix_len,
+            scratch,
+        };
+        bsl::Block::visit(&block, &mut index_block).expect("core returned invalid block");
+        index_block.scratch.shrink();
+        index_block.txids
+    });
+    if let Some(txids) = txids {
+        batch.merkle_rows.push((block_hash.to_byte_array(), txids));
+    }
+    if height_rows {
+        let header_row = *batch.header_rows.last().expect("missing header row");
+        batch
+            .height_rows
+            .push((height, block_hash.to_byte_array(), header_row));
+    }
+    batch.tip_row = block_hash.to_byte_array(); // as in the consensus encoding
+}
+
+/// Index a single block into a new batch, returning the number of rows built (used by the
+/// `index_block` benchmark)
+#[cfg(feature = "bench")]
+pub fn bench_index_block(block: Vec<u8>) -> usize {
+    let header: bitcoin::block::Header = deserialize(&block[..80]).expect("invalid block");
+    let mut batch = WriteBatch::default();
+    index_single_block(
+        header.block_hash(),
+        block,
+        0,
+        false,
+        false,
+        &ScriptTypes::all(),
+        PrefixLen::default(),
+        &mut batch,
+    );
+    batch.header_rows.len()
+        + batch.funding_rows.len()
+        + batch.spending_rows.len()
+        + batch.txid_rows.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::index_single_block;
+    use crate::{
+        db::WriteBatch,
+        types::{HeaderRow, PrefixLen, ScriptType, ScriptTypes},
+    };
+    use bitcoin::{
+        absolute::LockTime,
+        consensus::{deserialize, serialize},
+        hashes::Hash,
+        transaction::Version,
+        Amount, Block, OutPoint, PubkeyHash, ScriptBuf, ScriptHash, Sequence, Transaction, TxIn,
+        TxOut, Txid, WPubkeyHash, Witness,
+    };
+
+    #[test]
+    fn test_merkle_rows() {
+        let blockhash = "000000000000000002d249a3d89f63ef3fee203adcca7c24008c13fd854513f2";
+        let data = std::fs::read(format!("src/tests/blocks/{}", blockhash)).unwrap();
+        let block: Block = deserialize(&data).unwrap();
+
+        let mut batch = WriteBatch::default();
+        let all = ScriptTypes::all();
+        let len = PrefixLen::default();
+        index_single_block(
+            block.block_hash(),
+            data.clone(),
+            0,
+            false,
+            false,
+            &all,
+            len,
+            &mut batch,
+        );
+        assert!(batch.merkle_rows.is_empty());
+        assert_eq!(
+            batch.header_rows,
+            [HeaderRow::new(block.header).to_db_row()]
+        );
+        assert_eq!(batch.tip_row, block.block_hash().to_byte_array());
+
+        index_single_block(
+            block.block_hash(),
+            data,
+            0,
+            true,
+            false,
+            &all,
+            len,
+            &mut batch,
+        );
+        let (key, value) = &batch.merkle_rows[0];
+        assert_eq!(*key, block.block_hash().to_byte_array());
+        let txids: Vec<Txid> = value
+            .chunks_exact(Txid::LEN)
+            .map(|txid| Txid::from_slice(txid).unwrap())
+            .collect();
+        let expected: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+        assert_eq!(txids, expected);
+    }
 
-    let len = block_hash
-        .consensus_encode(&mut (&mut batch.tip_row as &mut [u8]))
-        .expect("in-memory writers don't error");
-    debug_assert_eq!(len, BlockHash::LEN);
+    #[test]
+    fn test_collect_prefixes() {
+        let blockhash = "000000000000000002d249a3d89f63ef3fee203adcca7c24008c13fd854513f2";
+        let data = std::fs::read(format!("src/tests/blocks/{}", blockhash)).unwrap();
+        let block: Block = deserialize(&data).unwrap();
+
+        let index = |collect_prefixes| {
+            let mut batch = WriteBatch {
+                collect_prefixes,
+                ..WriteBatch::default()
+            };
+            index_single_block(
+                block.block_hash(),
+                data.clone(),
+                0,
+                false,
+                false,
+                &ScriptTypes::all(),
+                PrefixLen::default(),
+                &mut batch,
+            );
+            batch
+        };
+        // the prefixes are collected only if the touched rows are tracked
+        let batch = index(false);
+        assert!(!batch.funding_rows.is_empty() && !batch.spending_rows.is_empty());
+        assert!(batch.funding_prefixes.is_empty() && batch.spending_prefixes.is_empty());
+        let batch = index(true);
+        assert_eq!(batch.funding_prefixes.len(), batch.funding_rows.len());
+        assert_eq!(batch.spending_prefixes.len(), batch.spending_rows.len());
+    }
+
+    #[test]
+    fn test_script_types() {
+        let p2wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20]));
+        let p2pkh = ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([2; 20]));
+        let p2sh = ScriptBuf::new_p2sh(&ScriptHash::from_byte_array([3; 20]));
+        let input = |vout: u32, script_sig: &[u8], witness: &[&[u8]]| TxIn {
+            previous_output: OutPoint::new(Txid::from_byte_array([4; 32]), vout),
+            script_sig: ScriptBuf::from_bytes(script_sig.to_vec()),
+            sequence: Sequence::MAX,
+            witness: Witness::from_slice(witness),
+        };
+        let output = |script_pubkey: &ScriptBuf| TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: script_pubkey.clone(),
+        };
+        let coinbase = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                witness: Witness::from_slice(&[[0u8; 32]]),
+                ..input(0, &[1, 2], &[])
+            }],
+            output: vec![output(&p2pkh)],
+        };
+        let mixed = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![
+                input(0, &[], &[&[5; 72], &[6; 33]]), // native segwit
+                input(1, &[7; 107], &[]),             // legacy
+                input(2, &[0x16; 23], &[&[8; 72]]),   // wrapped segwit
+            ],
+            output: vec![output(&p2wpkh), output(&p2pkh), output(&p2sh)],
+        };
+        let block = Block {
+            header: deserialize(&[0; 80]).unwrap(),
+            txdata: vec![coinbase, mixed],
+        };
+        let data = serialize(&block);
+
+        let index = |script_types: ScriptTypes| {
+            let mut batch = WriteBatch::default();
+            index_single_block(
+                block.block_hash(),
+                data.clone(),
+                0,
+                false,
+                false,
+                &script_types,
+                PrefixLen::default(),
+                &mut batch,
+            );
+            (batch.funding_rows.len(), batch.spending_rows.len())
+        };
+        assert_eq!(index(ScriptTypes::all()), (4, 3));
+        assert_eq!(index(ScriptTypes::only([ScriptType::P2wpkh])), (1, 1));
+        assert_eq!(index(ScriptTypes::only([ScriptType::P2pkh])), (2, 1));
+        assert_eq!(index(ScriptTypes::only([ScriptType::P2sh])), (1, 2));
+        assert_eq!(index(ScriptTypes::only([ScriptType::P2tr])), (0, 1));
+    }
+
+    #[test]
+    fn test_group_by_height() {
+        use super::group_by_height;
+        use crate::types::{HashPrefixRow, ScriptHash, ScriptHashRow, SerializedHashPrefixRow};
+
+        let scripthash = ScriptHash::hash(b"script");
+        let row = |height, vout| ScriptHashRow::row(scripthash, height, vout, PrefixLen::Eight);
+        // written before format 3 (without the output index)
+        let old = |height| {
+            let key = row(height, 0).to_db_row();
+            let key = SerializedHashPrefixRow::from_key(&key.as_ref()[..12]).unwrap();
+            HashPrefixRow::from_db_row(key)
+        };
+        let rows = vec![
+            row(1, 0),
+            row(1, 3),
+            row(1, 3),
+            old(2),
+            row(2, 1),
+            row(5, 0),
+        ];
+        let groups: Vec<_> = group_by_height(rows.into_iter()).collect();
+        assert_eq!(
+            groups,
+            vec![(1, Some(vec![0, 3])), (2, None), (5, Some(vec![0]))]
+        );
+    }
+
+    #[test]
+    fn test_nonstandard_outputs() {
+        use crate::types::{ScriptHash, ScriptHashRow};
+
+        let key = [0x21].into_iter().chain([2; 33]);
+        let fixtures: Vec<(ScriptBuf, ScriptType)> = [
+            (vec![], ScriptType::Other), // empty
+            (
+                // bare 1-of-3 multisig
+                [0x51]
+                    .into_iter()
+                    .chain(key.clone().cycle().take(3 * 34))
+                    .chain([0x53, 0xae])
+                    .collect(),
+                ScriptType::Other,
+            ),
+            (
+                [0x52, 0x20].into_iter().chain([9; 32]).collect(),
+                ScriptType::Other,
+            ), // segwit v2
+            (
+                [0x60, 0x28].into_iter().chain([9; 40]).collect(),
+                ScriptType::Other,
+            ), // segwit v16
+            (vec![0x4c], ScriptType::Other), // truncated OP_PUSHDATA1
+            (vec![0x20, 1, 2], ScriptType::Other), // truncated push
+            (vec![0x61; 10_001], ScriptType::Other), // above bitcoind's `MAX_SCRIPT_SIZE`
+            (vec![0x61; 3_000_000], ScriptType::Other), // a multi-megabyte script
+            (
+                [0x6a, 0x4c, 80].into_iter().chain([1; 80]).collect(),
+                ScriptType::OpReturn,
+            ),
+            (
+                [0x6a].into_iter().chain([1; 20_000]).collect(),
+                ScriptType::OpReturn,
+            ),
+        ]
+        .into_iter()
+        .map(|(bytes, script_type)| (ScriptBuf::from_bytes(bytes), script_type))
+        .collect();
+        for (script, script_type) in &fixtures {
+            assert_eq!(ScriptType::of(script), *script_type, "{:.40}", script);
+        }
+
+        let coinbase = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(vec![1, 2]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: fixtures
+                .iter()
+                .map(|(script, _)| TxOut {
+                    value: Amount::ZERO,
+                    script_pubkey: script.clone(),
+                })
+                .collect(),
+        };
+        let block = Block {
+            header: deserialize(&[0; 80]).unwrap(),
+            txdata: vec![coinbase],
+        };
+        let data = serialize(&block);
+        let len = PrefixLen::default();
+        let index = |script_types: &ScriptTypes| {
+            let data = data.clone();
+            let mut batch = WriteBatch::default();
+            // the scripts are hashed without being copied (see `tests/memory.rs`)
+            index_single_block(
+                block.block_hash(),
+                data,
+                0,
+                false,
+                false,
+                script_types,
+                len,
+                &mut batch,
+            );
+            batch.funding_rows
+        };
+        for script_types in [
+            ScriptTypes::all(),
+            ScriptTypes::only([ScriptType::Other]),
+            ScriptTypes::only([ScriptType::OpReturn]),
+        ] {
+            let expected: Vec<_> = fixtures
+                .iter()
+                .enumerate()
+                .filter(|(_, (script, _))| script_types.contains(script))
+                .map(|(vout, (script, _))| {
+                    ScriptHashRow::row(ScriptHash::new(script), 0, vout, len).to_db_row()
+                })
+                .collect();
+            assert_eq!(index(&script_types), expected, "{}", script_types);
+        }
+        assert_eq!(index(&ScriptTypes::all()).len(), 8); // `OP_RETURN` outputs are skipped
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_db_keys_gauge() {
+        use crate::{
+            db::{DBStore, StoreOptions},
+            types::SerializedHashPrefixRow,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(
+            dir.path(),
+            &StoreOptions {
+                auto_reindex: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        let metrics = crate::metrics::Metrics::new(([127, 0, 0, 1], 0).into()).unwrap();
+        let stats = super::Stats::new(&metrics);
+        let rows = |range: std::ops::Range<u8>, tag: u8| -> Vec<SerializedHashPrefixRow> {
+            range
+                .map(|i| {
+                    SerializedHashPrefixRow::from_key(&[i, tag, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+                        .unwrap()
+                })
+                .collect()
+        };
+
+        store.write(&WriteBatch {
+            txid_rows: rows(0..100, 0),
+            funding_rows: rows(0..10, 0),
+            ..Default::default()
+        });
+        store.flush(); // the first flush performs a full compaction
+        stats.observe_db(&store);
+        assert_eq!(stats.db_keys.get("txid"), 100.0);
+        assert_eq!(stats.db_keys.get("funding"), 10.0);
+        assert_eq!(stats.db_keys.get("spending"), 0.0);
+
+        store.write(&WriteBatch {
+            txid_rows: rows(0..50, 1),
+            spending_rows: rows(0..20, 1),
+            ..Default::default()
+        });
+        store.flush();
+        stats.observe_db(&store);
+        assert_eq!(stats.db_keys.get("txid"), 150.0);
+        assert_eq!(stats.db_keys.get("funding"), 10.0);
+        assert_eq!(stats.db_keys.get("spending"), 20.0);
+    }
 }
@@ -7,22 +7,80 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+mod allowlist;
+mod banner;
+mod blocks_dir;
+mod broadcasts;
 mod cache;
 mod chain;
+mod coalesce;
+mod command;
 mod config;
+mod custom_chain;
 mod daemon;
 mod db;
+mod dump;
 mod electrum;
+mod events;
+mod features;
+mod fees;
+mod flush;
+mod health;
+#[cfg(feature = "http")]
+mod http;
 mod index;
+mod limits;
+mod listener;
+mod logger;
+mod maintenance;
 mod mempool;
 mod merkle;
 mod metrics;
+mod migration;
+mod outbox;
+mod outgoing;
+mod outpoints;
 mod p2p;
+mod proxy;
+mod replay;
+mod resolver;
 mod server;
+mod shadow_db;
+mod shaper;
+mod shrink;
 mod signals;
+mod socket;
 mod status;
+#[cfg(test)]
+mod stub;
+mod subscriptions;
+mod systemd;
 mod thread;
+mod totals;
 mod tracker;
 mod types;
 
-pub use server::run;
+pub use chain::ChainView;
+pub use config::{Config, ConfigBuilder};
+pub use proxy::{DaemonAddr, Proxy};
+pub use server::{run, Handle, Server, StateWatch};
+pub use signals::ExitError;
+pub use status::{Balance, HistoryEntry};
+pub use tracker::State;
+pub use types::ScriptHash;
+
+#[cfg(fuzzing)]
+pub use electrum::fuzz_request;
+
+#[cfg(feature = "bench")]
+pub use blocks_dir::{bench_blocks, bench_read_blocks, BenchBlocks};
+#[cfg(feature = "bench")]
+pub use cache::{bench_cache, bench_cache_readers, BenchCache};
+#[cfg(feature = "bench")]
+pub use chain::{bench_headers, bench_headers_hex, bench_sync_headers, BenchHeaders};
+#[cfg(feature = "bench")]
+pub use electrum::{bench_history, bench_history_response};
+#[cfg(feature = "bench")]
+pub use index::bench_index_block;
+#[cfg(feature = "bench")]
+pub use subscriptions::{bench_affected, bench_subscriptions, BenchSubscriptions};
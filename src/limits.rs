@@ -0,0 +1,443 @@
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{db, shrink::Shrink};
+
+/// Protocol violations (e.g. invalid JSON requests) allowed before an address is banned
+const VIOLATIONS_BEFORE_BAN: usize = 10;
+
+/// First ban lasts 10 minutes, and each subsequent ban doubles its duration (up to a week)
+const MIN_BAN_SECS: u64 = 10 * 60;
+const MAX_BAN_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Violations by stateless (HTTP) requests are forgotten after this long without new ones
+const STATELESS_VIOLATIONS_SECS: u64 = MIN_BAN_SECS;
+
+/// Returns current UNIX time (in seconds)
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// File descriptors needed besides client connections and RocksDB (e.g. bitcoind connections,
+/// monitoring and log files)
+const RESERVED_FDS: u64 = 64;
+
+/// Warn if `max_connections` (together with RocksDB open files) may exceed `RLIMIT_NOFILE`.
+pub(crate) fn check_fd_limit(max_connections: Option<usize>) {
+    let (max_connections, limit) = match (max_connections, fd_limit()) {
+        (Some(max_connections), Some(limit)) => (max_connections as u64, limit),
+        _ => return,
+    };
+    let required = max_connections + db::MAX_OPEN_FILES as u64 + RESERVED_FDS;
+    if required > limit {
+        warn!(
+            "max_connections ({}) + RocksDB max_open_files ({}) + reserved ({}) exceed RLIMIT_NOFILE ({}), consider increasing it (e.g. via `LimitNOFILE` for systemd)",
+            max_connections,
+            db::MAX_OPEN_FILES,
+            RESERVED_FDS,
+            limit
+        );
+    }
+}
+
+#[cfg(unix)]
+fn fd_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `getrlimit` only writes into the provided struct
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)] // `rlim_t` is not `u64` on all platforms
+    (limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+fn fd_limit() -> Option<u64> {
+    None
+}
+
+/// Why an incoming connection was refused
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    Banned,
+    TooManyConnections,
+    ServerFull,
+}
+
+impl Refusal {
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            Refusal::Banned => "banned",
+            Refusal::TooManyConnections => "too_many_connections_per_ip",
+            Refusal::ServerFull => "server_full",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+struct Ban {
+    until: u64, // UNIX time (in seconds)
+    count: u32, // number of times this address was banned
+}
+
+#[derive(Default)]
+struct State {
+    total: usize,
+    active: HashMap<IpAddr, usize>,
+    violations: HashMap<IpAddr, (usize, u64)>, // count, and the time of the last one
+    bans: HashMap<IpAddr, Ban>,
+}
+
+/// Connection limits and bans (shared between the accepting thread and the main loop)
+///
+/// All methods receive the current time explicitly, so the ban expiry logic is testable.
+#[derive(Clone)]
+pub(crate) struct Limits {
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+    ban_file: Option<Arc<PathBuf>>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Limits {
+    /// Load persisted bans from `ban_file` (if it exists).
+    pub(crate) fn new(
+        max_total: Option<usize>,
+        max_per_ip: Option<usize>,
+        ban_file: Option<PathBuf>,
+    ) -> Result<Self> {
+        let mut state = State::default();
+        if let Some(path) = ban_file.as_ref().filter(|path| path.exists()) {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("failed to read ban file {}", path.display()))?;
+            state.bans = serde_json::from_str(&contents)
+                .with_context(|| format!("invalid ban file {}", path.display()))?;
+            info!("loaded {} bans from {}", state.bans.len(), path.display());
+        }
+        Ok(Self {
+            max_total,
+            max_per_ip,
+            ban_file: ban_file.map(Arc::new),
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Register a new connection from `ip`, unless it's banned or there are too many connections.
+    pub(crate) fn accept(&self, ip: IpAddr, now: u64) -> Result<(), Refusal> {
+        let mut state = self.state.lock();
+        if state.bans.get(&ip).is_some_and(|ban| ban.until > now) {
+            return Err(Refusal::Banned);
+        }
+        if self.max_total.is_some_and(|max| state.total >= max) {
+            return Err(Refusal::ServerFull);
+        }
+        let active = state.active.entry(ip).or_default();
+        if self.max_per_ip.is_some_and(|max| *active >= max) {
+            return Err(Refusal::TooManyConnections);
+        }
+        *active += 1;
+        state.total += 1;
+        Ok(())
+    }
+
+    /// Returns the number of active connections.
+    pub(crate) fn total(&self) -> usize {
+        self.state.lock().total
+    }
+
+    /// Unregister a closed connection from `ip`.
+    pub(crate) fn release(&self, ip: IpAddr) {
+        self.release_inner(ip, true) // violations are counted while `ip` is connected
+    }
+
+    /// Unregister a handled stateless (HTTP) request from `ip`, keeping its violations (so they
+    /// are counted across requests, until they are forgotten by [`Limits::expire`]).
+    pub(crate) fn release_request(&self, ip: IpAddr) {
+        self.release_inner(ip, false)
+    }
+
+    fn release_inner(&self, ip: IpAddr, forget_violations: bool) {
+        let state = &mut *self.state.lock();
+        if let Some(active) = state.active.get_mut(&ip) {
+            *active -= 1;
+            state.total -= 1;
+            if *active == 0 {
+                state.active.remove(&ip);
+                state.active.shrink();
+                if forget_violations {
+                    state.violations.remove(&ip);
+                    state.violations.shrink();
+                }
+            }
+        }
+    }
+
+    /// Record protocol violations by `ip`, returning `true` if it got banned.
+    pub(crate) fn violations(&self, ip: IpAddr, count: usize, now: u64) -> bool {
+        if count == 0 {
+            return false;
+        }
+        let mut state = self.state.lock();
+        let (violations, last) = state.violations.entry(ip).or_default();
+        *violations += count;
+        *last = now;
+        if *violations < VIOLATIONS_BEFORE_BAN {
+            return false;
+        }
+        state.violations.remove(&ip);
+        forget_expired(&mut state.bans, now);
+        let count = state
+            .bans
+            .get(&ip)
+            .map_or(0, |ban| ban.count)
+            .saturating_add(1);
+        let duration = ban_duration(count);
+        let ban = Ban {
+            until: now + duration,
+            count,
+        };
+        state.bans.insert(ip, ban);
+        warn!("banned {} for {} seconds (ban #{})", ip, duration, count);
+        self.save(&state.bans);
+        true
+    }
+
+    /// Forget the bans which have expired long ago (called periodically, so the bans of
+    /// departed addresses don't accumulate between new bans), and the old violations of
+    /// addresses without connections (left by stateless requests).
+    pub(crate) fn expire(&self, now: u64) {
+        let state = &mut *self.state.lock();
+        let active = &state.active;
+        state.violations.retain(|ip, (_, last)| {
+            active.contains_key(ip) || last.saturating_add(STATELESS_VIOLATIONS_SECS) > now
+        });
+        state.violations.shrink();
+        if forget_expired(&mut state.bans, now) > 0 {
+            self.save(&state.bans);
+        }
+    }
+
+    /// Returns currently banned addresses, with their ban expiry (UNIX time in seconds).
+    pub(crate) fn bans(&self, now: u64) -> Vec<(IpAddr, u64)> {
+        let state = self.state.lock();
+        let mut bans: Vec<(IpAddr, u64)> = state
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.until > now)
+            .map(|(ip, ban)| (*ip, ban.until))
+            .collect();
+        bans.sort_unstable();
+        bans
+    }
+
+    /// Lift the bans of the specified addresses (or all of them, if `ips` is empty).
+    pub(crate) fn clear(&self, ips: &[IpAddr]) -> usize {
+        let mut state = self.state.lock();
+        let before = state.bans.len();
+        if ips.is_empty() {
+            state.bans.clear();
+        } else {
+            state.bans.retain(|ip, _| !ips.contains(ip));
+        }
+        self.save(&state.bans);
+        before - state.bans.len()
+    }
+
+    fn save(&self, bans: &HashMap<IpAddr, Ban>) {
+        let path = match &self.ban_file {
+            Some(path) => path,
+            None => return,
+        };
+        // write into a temporary file first, so a crash won't leave a truncated ban file
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_string(bans)
+            .context("failed to serialize bans")
+            .and_then(|contents| fs::write(&tmp, contents).context("failed to write"))
+            .and_then(|()| fs::rename(&tmp, path.as_ref()).context("failed to rename"));
+        if let Err(e) = result {
+            warn!("failed to save bans into {}: {:#}", path.display(), e);
+        }
+    }
+}
+
+/// Each ban of the same address lasts twice as long as the previous one, up to `MAX_BAN_SECS`
+/// (`checked_shl()` alone would silently drop the shifted-out bits of a long ban history).
+fn ban_duration(count: u32) -> u64 {
+    1u64.checked_shl(count.saturating_sub(1))
+        .and_then(|factor| MIN_BAN_SECS.checked_mul(factor))
+        .map_or(MAX_BAN_SECS, |duration| duration.min(MAX_BAN_SECS))
+}
+
+// Forget the bans that have expired long ago (so they don't affect the next ban's duration),
+// returning how many were removed.
+fn forget_expired(bans: &mut HashMap<IpAddr, Ban>, now: u64) -> usize {
+    let before = bans.len();
+    bans.retain(|_, ban| ban.until.saturating_add(MAX_BAN_SECS) > now);
+    bans.shrink();
+    before - bans.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ban_duration, Limits, Refusal, MAX_BAN_SECS, MIN_BAN_SECS, STATELESS_VIOLATIONS_SECS,
+        VIOLATIONS_BEFORE_BAN,
+    };
+    use crate::shrink::MIN_CAPACITY;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_per_ip_limit() {
+        let limits = Limits::new(None, Some(2), None).unwrap();
+        let ip1: IpAddr = "10.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "::1".parse().unwrap();
+
+        assert_eq!(limits.accept(ip1, 0), Ok(()));
+        assert_eq!(limits.accept(ip1, 0), Ok(()));
+        assert_eq!(limits.accept(ip1, 0), Err(Refusal::TooManyConnections));
+        assert_eq!(limits.accept(ip2, 0), Ok(()));
+
+        limits.release(ip1);
+        assert_eq!(limits.accept(ip1, 0), Ok(()));
+        assert_eq!(limits.accept(ip1, 0), Err(Refusal::TooManyConnections));
+
+        let unlimited = Limits::new(None, None, None).unwrap();
+        for _ in 0..100 {
+            assert_eq!(unlimited.accept(ip1, 0), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_total_limit() {
+        let limits = Limits::new(Some(3), Some(2), None).unwrap();
+        let ip1: IpAddr = "10.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert_eq!(limits.accept(ip1, 0), Ok(()));
+        assert_eq!(limits.accept(ip1, 0), Ok(()));
+        assert_eq!(limits.accept(ip2, 0), Ok(()));
+        assert_eq!(limits.total(), 3);
+        assert_eq!(limits.accept(ip2, 0), Err(Refusal::ServerFull));
+
+        limits.release(ip1);
+        assert_eq!(limits.total(), 2);
+        assert_eq!(limits.accept(ip2, 0), Ok(()));
+        assert_eq!(limits.accept(ip2, 0), Err(Refusal::ServerFull));
+    }
+
+    #[test]
+    fn test_ban_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let ban_file = dir.path().join("bans.json");
+        let limits = Limits::new(None, None, Some(ban_file.clone())).unwrap();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut now = 1_000_000;
+
+        assert_eq!(limits.accept(ip, now), Ok(()));
+        assert!(!limits.violations(ip, VIOLATIONS_BEFORE_BAN - 1, now));
+        assert!(limits.violations(ip, 1, now));
+        limits.release(ip);
+        assert_eq!(limits.accept(ip, now), Err(Refusal::Banned));
+        assert_eq!(limits.bans(now), vec![(ip, now + MIN_BAN_SECS)]);
+
+        // bans are persisted
+        let reloaded = Limits::new(None, None, Some(ban_file.clone())).unwrap();
+        assert_eq!(reloaded.accept(ip, now), Err(Refusal::Banned));
+
+        // the first ban expires, and the next one lasts twice as long
+        now += MIN_BAN_SECS;
+        assert_eq!(limits.bans(now), vec![]);
+        assert_eq!(limits.accept(ip, now), Ok(()));
+        assert!(limits.violations(ip, VIOLATIONS_BEFORE_BAN, now));
+        assert_eq!(limits.bans(now), vec![(ip, now + 2 * MIN_BAN_SECS)]);
+        assert_eq!(limits.accept(ip, now + MIN_BAN_SECS), Err(Refusal::Banned));
+        assert_eq!(limits.accept(ip, now + 2 * MIN_BAN_SECS), Ok(()));
+
+        // bans can be lifted explicitly
+        assert_eq!(limits.clear(&[]), 1);
+        assert_eq!(limits.accept(ip, now), Ok(()));
+        let reloaded = Limits::new(None, None, Some(ban_file)).unwrap();
+        assert_eq!(reloaded.bans(now), vec![]);
+    }
+
+    #[test]
+    fn test_ban_duration() {
+        assert_eq!(ban_duration(1), MIN_BAN_SECS);
+        assert_eq!(ban_duration(2), 2 * MIN_BAN_SECS);
+        assert_eq!(ban_duration(10), 512 * MIN_BAN_SECS);
+        // clamped, including shifts which would overflow (e.g. a long-lived address)
+        for count in [11, 58, 59, 60, 63, 64, 65, u32::MAX] {
+            assert_eq!(ban_duration(count), MAX_BAN_SECS, "{}", count);
+        }
+    }
+
+    #[test]
+    fn test_departed_addresses() {
+        let limits = Limits::new(None, Some(1), None).unwrap();
+        let capacity = |limits: &Limits| {
+            let state = limits.state.lock();
+            state.active.capacity() + state.violations.capacity() + state.bans.capacity()
+        };
+        let ips: Vec<IpAddr> = (0..10_000u32).map(|i| Ipv4Addr::from(i).into()).collect();
+        let now = 1_000_000;
+        for ip in &ips {
+            assert_eq!(limits.accept(*ip, now), Ok(()));
+            assert!(!limits.violations(*ip, 1, now));
+        }
+        for ip in &ips[..100] {
+            assert!(limits.violations(*ip, VIOLATIONS_BEFORE_BAN, now));
+        }
+        assert!(capacity(&limits) > 2 * ips.len());
+
+        // the state of disconnected addresses is released
+        for ip in &ips {
+            limits.release(*ip);
+        }
+        assert_eq!(limits.total(), 0);
+        assert_eq!(limits.bans(now).len(), 100);
+        limits.expire(now + MIN_BAN_SECS + MAX_BAN_SECS - 1);
+        assert_eq!(limits.state.lock().bans.len(), 100); // still affect the next bans' duration
+        limits.expire(now + MIN_BAN_SECS + MAX_BAN_SECS);
+        assert!(limits.state.lock().bans.is_empty());
+        assert!(capacity(&limits) <= 3 * MIN_CAPACITY);
+    }
+
+    #[test]
+    fn test_stateless_violations() {
+        let limits = Limits::new(None, None, None).unwrap();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut now = 1_000_000;
+
+        // violations are counted across requests
+        for _ in 1..VIOLATIONS_BEFORE_BAN {
+            assert_eq!(limits.accept(ip, now), Ok(()));
+            assert!(!limits.violations(ip, 1, now));
+            limits.release_request(ip);
+        }
+        limits.expire(now);
+        assert_eq!(limits.accept(ip, now), Ok(()));
+        assert!(limits.violations(ip, 1, now));
+        limits.release_request(ip);
+        assert_eq!(limits.accept(ip, now), Err(Refusal::Banned));
+
+        // until they are forgotten
+        now += MIN_BAN_SECS;
+        assert_eq!(limits.accept(ip, now), Ok(()));
+        assert!(!limits.violations(ip, 1, now));
+        limits.release_request(ip);
+        limits.expire(now + STATELESS_VIOLATIONS_SECS);
+        assert!(limits.state.lock().violations.is_empty());
+    }
+}
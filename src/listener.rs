@@ -0,0 +1,99 @@
+use anyhow::Result;
+
+use crate::{
+    banner::Banner,
+    config::{Config, IpNet, ListenerConfig},
+    limits::Limits,
+};
+
+/// Administrative methods (disabled by `admin = false`)
+const ADMIN_PREFIX: &str = "electrs.";
+
+/// An Electrum RPC listener's settings (see `electrum_rpc_listeners`), shared by its connections
+/// so their requests' dispatch and limits consult the listener they were accepted by.
+pub(crate) struct ListenerPolicy {
+    pub limits: Limits, // enforced in addition to the global limits
+    pub disabled_methods: Vec<String>,
+    pub admin: bool,
+    pub banner: Banner,
+    pub block_get_allow: Vec<IpNet>,
+    pub status_debug_allow: Vec<IpNet>,
+}
+
+impl ListenerPolicy {
+    pub(crate) fn new(listener: &ListenerConfig, config: &Config) -> Result<Self> {
+        Ok(Self {
+            limits: Limits::new(
+                listener.max_connections,
+                listener.max_connections_per_ip,
+                None, // bans are global
+            )?,
+            disabled_methods: listener.disabled_methods.clone(),
+            admin: listener.admin,
+            banner: Banner::new(config.server_banner.clone(), listener.banner_file.clone()),
+            block_get_allow: listener.block_get_allow.clone(),
+            status_debug_allow: listener.status_debug_allow.clone(),
+        })
+    }
+
+    /// The global settings, for clients not accepted by a listener (e.g. via HTTP)
+    pub(crate) fn global(config: &Config) -> Result<Self> {
+        Ok(Self {
+            limits: Limits::new(None, None, None)?,
+            disabled_methods: vec![],
+            admin: true,
+            banner: Banner::new(config.server_banner.clone(), config.banner_file.clone()),
+            block_get_allow: config.block_get_allow.clone(),
+            status_debug_allow: config.status_debug_allow.clone(),
+        })
+    }
+
+    /// Disabled methods are handled (and advertised) as if they were unknown.
+    pub(crate) fn disables(&self, method: &str) -> bool {
+        (!self.admin && method.starts_with(ADMIN_PREFIX))
+            || self.disabled_methods.iter().any(|name| name == method)
+    }
+
+    /// Drop the cached banner file contents (e.g. on SIGHUP).
+    pub(crate) fn reload(&self) {
+        self.banner.reload();
+    }
+}
+
+/// No limits, ACLs nor disabled methods
+#[cfg(test)]
+impl Default for ListenerPolicy {
+    fn default() -> Self {
+        Self {
+            limits: Limits::new(None, None, None).unwrap(),
+            disabled_methods: vec![],
+            admin: true,
+            banner: Banner::new(String::new(), None),
+            block_get_allow: vec![],
+            status_debug_allow: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ListenerPolicy;
+
+    #[test]
+    fn test_disables() {
+        let policy = ListenerPolicy::default();
+        assert!(!policy.disables("electrs.bans.list"));
+        assert!(!policy.disables("blockchain.transaction.broadcast"));
+
+        let policy = ListenerPolicy {
+            disabled_methods: vec!["blockchain.transaction.broadcast".to_owned()],
+            admin: false,
+            ..ListenerPolicy::default()
+        };
+        assert!(policy.disables("electrs.bans.list"));
+        assert!(policy.disables("electrs.db.stats"));
+        assert!(policy.disables("blockchain.transaction.broadcast"));
+        assert!(!policy.disables("blockchain.transaction.broadcast_package"));
+        assert!(!policy.disables("server.ping"));
+    }
+}
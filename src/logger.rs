@@ -0,0 +1,298 @@
+use log::kv::{self, Key, VisitSource};
+use parking_lot::Mutex;
+use serde_json::{Map, Number, Value};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use crate::config::LogFormat;
+
+/// Log file output settings
+pub(crate) struct FileOutput {
+    pub path: PathBuf,
+    /// Rotate the file when it exceeds this size (in bytes)
+    pub max_size: Option<u64>,
+    /// Number of rotated files to keep (`electrs.log.1` is the most recent one)
+    pub keep: usize,
+    /// Also write the logs to stderr
+    pub console: bool,
+}
+
+static LOG_FILE: OnceLock<Arc<Mutex<LogFile>>> = OnceLock::new();
+
+/// Initialize the global logger (filtered by `RUST_LOG` or `log_filters`).
+pub(crate) fn init(
+    format: LogFormat,
+    filters: Option<&str>,
+    file_output: Option<FileOutput>,
+) -> io::Result<()> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(output) = file_output {
+        let file = Arc::new(Mutex::new(LogFile::open(
+            output.path,
+            output.max_size,
+            output.keep,
+        )?));
+        let _ = LOG_FILE.set(Arc::clone(&file));
+        builder.target(env_logger::Target::Pipe(Box::new(Tee {
+            file,
+            console: output.console,
+        })));
+    }
+    match format {
+        LogFormat::Plain => {
+            builder.default_format().format_timestamp_millis();
+        }
+        LogFormat::Json => {
+            builder.format(|buf, record| {
+                let timestamp = buf.timestamp_millis().to_string();
+                writeln!(buf, "{}", json_line(&timestamp, record))
+            });
+        }
+    }
+    if let Some(filters) = filters {
+        builder.parse_filters(filters);
+    }
+    builder.init();
+    Ok(())
+}
+
+/// Reopen the log file (e.g. after it was rotated by an external `logrotate`).
+pub(crate) fn reopen() {
+    if let Some(file) = LOG_FILE.get() {
+        // the lock must be released before logging (which writes to the same file)
+        let (path, result) = {
+            let mut file = file.lock();
+            (file.path.clone(), file.reopen())
+        };
+        match result {
+            Ok(()) => info!("reopened log file {}", path.display()),
+            Err(e) => eprintln!("failed to reopen {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Size-based rotating log file
+///
+/// `env_logger` writes each formatted record using a single `write_all()` call (while holding
+/// a lock), so a record is never split between files.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: Option<u64>, keep: usize) -> io::Result<Self> {
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = open_append(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    // electrs.log -> electrs.log.1 -> ... -> electrs.log.{keep} (which is removed)
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.keep).rev() {
+            let src = self.rotated_path(index);
+            if src.exists() {
+                fs::rename(src, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.reopen()
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len() as u64;
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + len > max_size {
+                if let Err(e) = self.rotate() {
+                    eprintln!("failed to rotate {}: {}", self.path.display(), e);
+                }
+            }
+        }
+        self.file.write_all(buf)?;
+        self.size += len;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Write into the log file, and optionally to stderr
+struct Tee {
+    file: Arc<Mutex<LogFile>>,
+    console: bool,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.lock().write_all(buf)?;
+        if self.console {
+            io::stderr().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().flush()
+    }
+}
+
+/// Format a log record as a single-line JSON object, including its structured fields
+/// (e.g. `debug!(peer = id; "...")`).
+fn json_line(timestamp: &str, record: &log::Record) -> String {
+    let mut line = Map::new();
+    line.insert("timestamp".into(), timestamp.into());
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    line.insert("message".into(), record.args().to_string().into());
+    // fields' visitor never fails
+    let _ = record.key_values().visit(&mut Fields(&mut line));
+    Value::Object(line).to_string()
+}
+
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), to_json(&value));
+        Ok(())
+    }
+}
+
+fn to_json(value: &kv::Value) -> Value {
+    if let Some(v) = value.to_bool() {
+        Value::Bool(v)
+    } else if let Some(v) = value.to_u64() {
+        v.into()
+    } else if let Some(v) = value.to_i64() {
+        v.into()
+    } else if let Some(v) = value.to_f64().and_then(Number::from_f64) {
+        Value::Number(v)
+    } else {
+        Value::String(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_line, LogFile};
+    use serde_json::{json, Value};
+    use std::io::Write;
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("electrs.log");
+        let mut file = LogFile::open(path.clone(), Some(100), 3).unwrap();
+        for i in 0..100 {
+            file.write_all(format!("line #{:03} ....................\n", i).as_bytes())
+                .unwrap();
+        }
+        let mut names: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "electrs.log",
+                "electrs.log.1",
+                "electrs.log.2",
+                "electrs.log.3"
+            ]
+        );
+        // each record is written as a whole, and the most recent one is in the current file
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert!(current.ends_with("line #099 ....................\n"));
+        let rotated = std::fs::read_to_string(dir.path().join("electrs.log.1")).unwrap();
+        assert!(rotated.len() <= 100);
+        assert!(rotated.lines().all(|line| line.len() == 30));
+
+        // external rotation, followed by SIGHUP
+        std::fs::rename(&path, dir.path().join("external.log")).unwrap();
+        file.reopen().unwrap();
+        file.write_all(b"after reopen\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "after reopen\n");
+    }
+
+    #[test]
+    fn test_json_line() {
+        let fields: &[(&str, log::kv::Value)] = &[
+            ("peer", 5.into()),
+            ("method", "server.ping".into()),
+            ("duration", 0.25.into()),
+        ];
+        let line = json_line(
+            "2024-01-02T03:04:05.678Z",
+            &log::Record::builder()
+                .args(format_args!("handled {} request", "server.ping"))
+                .level(log::Level::Debug)
+                .target("electrs::electrum")
+                .key_values(&fields)
+                .build(),
+        );
+        assert!(!line.contains('\n'));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "timestamp": "2024-01-02T03:04:05.678Z",
+                "level": "DEBUG",
+                "target": "electrs::electrum",
+                "message": "handled server.ping request",
+                "peer": 5,
+                "method": "server.ping",
+                "duration": 0.25,
+            })
+        );
+
+        let line = json_line(
+            "2024-01-02T03:04:05.678Z",
+            &log::Record::builder()
+                .args(format_args!("multi\nline"))
+                .level(log::Level::Info)
+                .target("electrs::server")
+                .build(),
+        );
+        assert!(!line.contains('\n'));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["message"], "multi\nline");
+        assert_eq!(value["level"], "INFO");
+    }
+}
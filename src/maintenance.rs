@@ -0,0 +1,221 @@
+use parking_lot::Mutex;
+
+use std::fmt;
+use std::str::FromStr;
+
+/// The number of key-space slices compacted during a single window (bounding the nightly work)
+pub(crate) const MAX_SLICES_PER_WINDOW: usize = 256;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// A daily time window in local time (e.g. `04:00-06:00`), which may wrap around midnight
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    start: u16, // in minutes since midnight
+    end: u16,   // exclusive
+}
+
+impl Window {
+    pub(crate) fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Result<u16, String> {
+    let (hours, minutes) = time
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' is not 'HH:MM'", time))?;
+    let hours: u16 = hours
+        .parse()
+        .map_err(|e| format!("invalid hours '{}': {}", hours, e))?;
+    let minutes: u16 = minutes
+        .parse()
+        .map_err(|e| format!("invalid minutes '{}': {}", minutes, e))?;
+    if hours >= 24 || minutes >= 60 {
+        return Err(format!("'{}' is not a valid time of day", time));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(window: &str) -> Result<Self, Self::Err> {
+        let (start, end) = window
+            .split_once('-')
+            .ok_or_else(|| format!("maintenance window '{}' is not 'HH:MM-HH:MM'", window))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(format!("maintenance window '{}' is empty", window));
+        }
+        Ok(Window { start, end })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// The DB slices compacted during the current window
+#[derive(Default)]
+struct Run {
+    active: bool,
+    slices: usize,
+    reclaimed: u64, // in bytes
+}
+
+/// Decides when the DB is compacted incrementally (one slice at a time), so the compaction is
+/// spread over the window's nights instead of hurting queries' latency during peak hours.
+pub(crate) struct Scheduler {
+    window: Window,
+    run: Mutex<Run>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(window: Window) -> Self {
+        Self {
+            window,
+            run: Mutex::default(),
+        }
+    }
+
+    /// Returns `true` if a slice should be compacted at `minute` (of the day, in local time).
+    /// A new run starts when the window is entered, and ends when it is left (or when
+    /// `MAX_SLICES_PER_WINDOW` slices are compacted).
+    pub(crate) fn is_due(&self, minute: u16) -> bool {
+        let mut run = self.run.lock();
+        if !self.window.contains(minute) {
+            if run.active {
+                log_run(&run);
+                *run = Run::default();
+            }
+            return false;
+        }
+        if !run.active {
+            info!("starting DB maintenance (window {})", self.window);
+            run.active = true;
+        }
+        run.slices < MAX_SLICES_PER_WINDOW
+    }
+
+    /// Record a compacted slice, returning the number of bytes reclaimed by the current run.
+    pub(crate) fn compacted(&self, reclaimed: u64) -> u64 {
+        let mut run = self.run.lock();
+        run.slices += 1;
+        run.reclaimed += reclaimed;
+        if run.slices == MAX_SLICES_PER_WINDOW {
+            log_run(&run);
+        }
+        run.reclaimed
+    }
+}
+
+fn log_run(run: &Run) {
+    if run.slices > 0 {
+        info!(
+            "DB maintenance compacted {} slices, reclaiming {} bytes",
+            run.slices, run.reclaimed
+        );
+    }
+}
+
+/// The minute of the day (in local time) of the given UNIX time
+#[cfg(unix)]
+pub(crate) fn local_minute_of_day(unix_secs: u64) -> u16 {
+    let time = unix_secs as libc::time_t;
+    // SAFETY: `tm` is plain data, which `localtime_r` fills (it is thread-safe, unlike `localtime`)
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return utc_minute_of_day(unix_secs);
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u16
+}
+
+/// The minute of the day of the given UNIX time (in UTC, since the local time zone is unknown)
+#[cfg(not(unix))]
+pub(crate) fn local_minute_of_day(unix_secs: u64) -> u16 {
+    utc_minute_of_day(unix_secs)
+}
+
+fn utc_minute_of_day(unix_secs: u64) -> u16 {
+    ((unix_secs / 60) % u64::from(MINUTES_PER_DAY)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scheduler, Window, MAX_SLICES_PER_WINDOW};
+
+    #[test]
+    fn test_window() {
+        let window: Window = "04:00-06:30".parse().unwrap();
+        assert_eq!(window.to_string(), "04:00-06:30");
+        assert!(!window.contains(3 * 60 + 59));
+        assert!(window.contains(4 * 60));
+        assert!(window.contains(6 * 60 + 29));
+        assert!(!window.contains(6 * 60 + 30));
+
+        // wrapping around midnight
+        let window: Window = " 23:00 - 1:00 ".parse().unwrap();
+        assert_eq!(window.to_string(), "23:00-01:00");
+        assert!(window.contains(23 * 60 + 30));
+        assert!(window.contains(0));
+        assert!(!window.contains(60));
+        assert!(!window.contains(12 * 60));
+
+        for invalid in [
+            "",
+            "04:00",
+            "04:00-04:00",
+            "24:00-01:00",
+            "04:60-05:00",
+            "4-5",
+        ] {
+            assert!(invalid.parse::<Window>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let scheduler = Scheduler::new("04:00-06:00".parse().unwrap());
+        assert!(!scheduler.is_due(3 * 60));
+        assert!(scheduler.is_due(4 * 60));
+        assert_eq!(scheduler.compacted(100), 100);
+        assert_eq!(scheduler.compacted(20), 120);
+
+        // the work is bounded per window
+        for _ in 2..MAX_SLICES_PER_WINDOW {
+            assert!(scheduler.is_due(5 * 60));
+            scheduler.compacted(0);
+        }
+        assert!(!scheduler.is_due(5 * 60));
+
+        // leaving the window resets the run
+        assert!(!scheduler.is_due(6 * 60));
+        assert!(scheduler.is_due(4 * 60 + 1));
+        assert_eq!(scheduler.compacted(1), 1);
+    }
+
+    #[test]
+    fn test_minute_of_day() {
+        assert_eq!(super::utc_minute_of_day(0), 0);
+        assert_eq!(
+            super::utc_minute_of_day(86400 + 4 * 3600 + 5 * 60 + 59),
+            245
+        );
+        assert!(super::local_minute_of_day(crate::limits::unix_now()) < 24 * 60);
+    }
+}
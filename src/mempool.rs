@@ -4,16 +4,17 @@ use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::iter::FromIterator;
 use std::ops::Bound;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bitcoin::hashes::Hash;
 use bitcoin::{Amount, OutPoint, Transaction, Txid};
 use serde::ser::{Serialize, SerializeSeq, Serializer};
 
 use crate::{
-    daemon::Daemon,
+    daemon::Node,
     metrics::{Gauge, Metrics},
     signals::ExitFlag,
-    types::ScriptHash,
+    types::{ScriptHash, ScriptHashRow, ScriptTypes, SpendingPrefixRow, Touched},
 };
 
 pub(crate) struct Entry {
@@ -30,9 +31,78 @@ pub(crate) struct Mempool {
     by_funding: BTreeSet<(ScriptHash, Txid)>,
     by_spending: BTreeSet<(OutPoint, Txid)>,
     fees: FeeHistogram,
+    touched: Option<Touched>, // since the last `take_touched()` call (`None` if unknown)
+    total_vsize: u64,
+    total_fees: Amount,
+    last_sync: Option<LastSync>,
+    script_types: ScriptTypes, // of the indexed outputs
+    generation: u64,           // incremented when transactions are added or removed
     // stats
     vsize: Gauge,
     count: Gauge,
+    summary: Gauge,
+    percentiles: Gauge,
+}
+
+struct LastSync {
+    time: SystemTime,
+    full: bool,
+    daemon_count: usize,
+}
+
+/// Summary of the mempool state (exported via metrics and `mempool.get_info`)
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct MempoolStats {
+    /// # of tracked transactions
+    pub count: usize,
+    /// Total vsize of tracked transactions (in vbytes)
+    pub vsize: u64,
+    /// Total fees of tracked transactions (in sats)
+    pub fees: u64,
+    /// `[percentile, fee_rate]` pairs of vsize-weighted fee rate percentiles (in sat/vB,
+    /// rounded down to the lower bound of their fee histogram bin)
+    pub fee_rate_percentiles: Vec<(u8, u64)>,
+    /// Unix timestamp of the last mempool sync (`None` if not synced yet)
+    pub last_sync: Option<u64>,
+    /// Whether the last sync fetched the whole mempool (instead of only the new transactions)
+    pub last_sync_full: Option<bool>,
+    /// # of transactions reported by bitcoind's `getmempoolinfo` during the last sync
+    pub daemon_count: Option<usize>,
+    /// `count - daemon_count`, which should be close to 0 (unless electrs's view drifts)
+    pub count_delta: Option<i64>,
+}
+
+/// The mempool state sent by `mempool.subscribe_summary` (the client's mirror is updated using
+/// [`MempoolSummary::changes`] afterwards)
+#[derive(Clone, Serialize)]
+pub(crate) struct MempoolSummary {
+    pub count: usize,
+    pub vsize: u64,
+    /// In `mempool.get_fee_histogram` format
+    pub histogram: FeeHistogram,
+    pub mempool_generation: u64,
+}
+
+/// A `mempool.subscribe_summary` notification
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct MempoolChanges {
+    pub count: usize,
+    pub vsize: u64,
+    /// `[fee_rate, vsize]` of the histogram bins whose vsize changed (0 if they became empty)
+    pub histogram_changes: Vec<(u64, u64)>,
+    pub mempool_generation: u64,
+}
+
+impl MempoolSummary {
+    /// The changes since `prev` (the summary previously sent to the client)
+    pub(crate) fn changes(&self, prev: &MempoolSummary) -> MempoolChanges {
+        MempoolChanges {
+            count: self.count,
+            vsize: self.vsize,
+            histogram_changes: self.histogram.changes(&prev.histogram),
+            mempool_generation: self.mempool_generation,
+        }
+    }
 }
 
 /// An update to [`Mempool`]'s internal state. This can be fetched
@@ -41,14 +111,18 @@ pub(crate) struct Mempool {
 pub(crate) struct MempoolSyncUpdate {
     new_entries: Vec<Entry>,
     removed_entries: HashSet<Txid>,
+    full: bool,
+    daemon_count: usize,
 }
 
 impl MempoolSyncUpdate {
     /// Poll the bitcoin node and compute a [`MempoolSyncUpdate`] based on the given set of
-    /// `old_txids` which are already cached.
+    /// `old_txids` which are already cached (`full` if the mempool was never synced before).
     pub fn poll(
-        daemon: &Daemon,
+        daemon: &dyn Node,
         old_txids: HashSet<Txid>,
+        full: bool,
+        daemon_count: usize,
         exit_flag: &ExitFlag,
     ) -> Result<MempoolSyncUpdate> {
         let txids = daemon.get_mempool_txids()?;
@@ -80,7 +154,7 @@ impl MempoolSyncUpdate {
             );
             let chunk_entries: Vec<Entry> = txids_chunk
                 .iter()
-                .zip(entries.into_iter().zip(txs.into_iter()))
+                .zip(entries.into_iter().zip(txs))
                 .filter_map(|(txid, (entry, tx))| {
                     let entry = match entry {
                         Some(entry) => entry,
@@ -112,6 +186,8 @@ impl MempoolSyncUpdate {
         let update = MempoolSyncUpdate {
             new_entries,
             removed_entries: to_remove,
+            full,
+            daemon_count,
         };
         Ok(update)
     }
@@ -128,12 +204,18 @@ fn txid_max() -> Txid {
 }
 
 impl Mempool {
-    pub fn new(metrics: &Metrics) -> Self {
+    pub fn new(metrics: &Metrics, script_types: ScriptTypes) -> Self {
         Self {
             entries: Default::default(),
             by_funding: Default::default(),
             by_spending: Default::default(),
             fees: FeeHistogram::default(),
+            touched: None,
+            total_vsize: 0,
+            total_fees: Amount::ZERO,
+            last_sync: None,
+            script_types,
+            generation: 0,
             vsize: metrics.gauge(
                 "mempool_txs_vsize",
                 "Total vsize of mempool transactions (in bytes)",
@@ -144,6 +226,16 @@ impl Mempool {
                 "Total number of mempool transactions",
                 "fee_rate",
             ),
+            summary: metrics.gauge(
+                "mempool_stats",
+                "Mempool summary (fees are in sats, vsize in vbytes)",
+                "type",
+            ),
+            percentiles: metrics.gauge(
+                "mempool_fee_rate_percentiles",
+                "Mempool fee rate percentiles (in sat/vB)",
+                "percentile",
+            ),
         }
     }
 
@@ -151,6 +243,43 @@ impl Mempool {
         &self.fees
     }
 
+    /// Changed only when transactions are added or removed (so derived data can be reused).
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub(crate) fn summary(&self) -> MempoolSummary {
+        MempoolSummary {
+            count: self.entries.len(),
+            vsize: self.total_vsize,
+            histogram: self.fees.clone(),
+            mempool_generation: self.generation,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> MempoolStats {
+        let count = self.entries.len();
+        let last_sync = self.last_sync.as_ref();
+        MempoolStats {
+            count,
+            vsize: self.total_vsize,
+            fees: self.total_fees.to_sat(),
+            fee_rate_percentiles: FeeHistogram::PERCENTILES
+                .iter()
+                .map(|&p| (p, self.fees.percentile(p)))
+                .collect(),
+            last_sync: last_sync.map(|s| {
+                s.time
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            last_sync_full: last_sync.map(|s| s.full),
+            daemon_count: last_sync.map(|s| s.daemon_count),
+            count_delta: last_sync.map(|s| count as i64 - s.daemon_count as i64),
+        }
+    }
+
     pub(crate) fn get(&self, txid: &Txid) -> Option<&Entry> {
         self.entries.get(txid)
     }
@@ -177,10 +306,27 @@ impl Mempool {
             .collect()
     }
 
+    /// The prefixes of the transactions added or removed since the previous `take_touched()` call
+    /// (or `None` if unknown).
+    pub(crate) fn touched(&self) -> Option<&Touched> {
+        self.touched.as_ref()
+    }
+
+    /// Return the prefixes of the transactions added or removed since the previous call
+    /// (or `None` if unknown).
+    pub(crate) fn take_touched(&mut self) -> Option<Touched> {
+        self.touched.replace(Touched::default())
+    }
+
     /// Apply a [`MempoolSyncUpdate`] to the mempool state.
     pub fn apply_sync_update(&mut self, update: MempoolSyncUpdate) {
         let removed = update.removed_entries.len();
         let added = update.new_entries.len();
+        self.last_sync = Some(LastSync {
+            time: SystemTime::now(),
+            full: update.full,
+            daemon_count: update.daemon_count,
+        });
 
         for txid_to_remove in update.removed_entries {
             self.remove_entry(txid_to_remove);
@@ -190,7 +336,12 @@ impl Mempool {
             self.add_entry(entry);
         }
 
-        self.update_metrics();
+        let changed = added + removed > 0;
+        if changed {
+            self.generation += 1;
+        }
+        self.update_metrics(changed);
+        self.touched = self.touched.take().filter(|t| !t.is_full());
 
         debug!(
             "{} mempool txs: {} added, {} removed",
@@ -200,55 +351,97 @@ impl Mempool {
         );
     }
 
-    fn update_metrics(&mut self) {
-        for i in 0..FeeHistogram::BINS {
-            let bin_index = FeeHistogram::BINS - i - 1; // from 63 to 0
-            let (lower, upper) = FeeHistogram::bin_range(bin_index);
-            let label = format!("[{:20.0}, {:20.0})", lower, upper);
-            self.vsize.set(&label, self.fees.vsize[bin_index] as f64);
-            self.count.set(&label, self.fees.count[bin_index] as f64);
+    /// The fee histogram's gauges are updated only if it was `changed`.
+    fn update_metrics(&mut self, changed: bool) {
+        if changed {
+            for i in 0..FeeHistogram::BINS {
+                let bin_index = FeeHistogram::BINS - i - 1; // from 63 to 0
+                let (lower, upper) = FeeHistogram::bin_range(bin_index);
+                let label = format!("[{:20.0}, {:20.0})", lower, upper);
+                self.vsize.set(&label, self.fees.vsize[bin_index] as f64);
+                self.count.set(&label, self.fees.count[bin_index] as f64);
+            }
+        }
+        let stats = self.stats();
+        self.summary.set("count", stats.count as f64);
+        self.summary.set("vsize", stats.vsize as f64);
+        self.summary.set("fees", stats.fees as f64);
+        if let Some(last_sync) = stats.last_sync {
+            self.summary.set("last_sync", last_sync as f64);
+        }
+        if let Some(daemon_count) = stats.daemon_count {
+            self.summary.set("daemon_count", daemon_count as f64);
+        }
+        if let Some(count_delta) = stats.count_delta {
+            self.summary.set("count_delta", count_delta as f64);
+        }
+        if changed {
+            for (percentile, fee_rate) in stats.fee_rate_percentiles {
+                self.percentiles
+                    .set(&format!("p{}", percentile), fee_rate as f64);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn sync(&mut self, daemon: &dyn Node, exit_flag: &ExitFlag) {
+        if let Some(sync_update) = self.poll(daemon, exit_flag) {
+            self.apply_sync_update(sync_update);
         }
     }
 
-    pub fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) {
-        let loaded = match daemon.get_mempool_info() {
-            Ok(info) => info.loaded.unwrap_or(true),
+    /// Poll the bitcoin node for mempool changes (without modifying the mempool, so it can be
+    /// done while serving queries). Returns `None` if the mempool can't be synced now.
+    pub(crate) fn poll(
+        &self,
+        daemon: &dyn Node,
+        exit_flag: &ExitFlag,
+    ) -> Option<MempoolSyncUpdate> {
+        let (loaded, daemon_count) = match daemon.get_mempool_info() {
+            Ok(info) => (info.loaded.unwrap_or(true), info.size),
             Err(e) => {
                 warn!("mempool sync failed: {}", e);
-                return;
+                return None;
             }
         };
         if !loaded {
             warn!("mempool not loaded");
-            return;
+            return None;
         }
 
         let old_txids = HashSet::<Txid>::from_iter(self.entries.keys().copied());
 
-        let poll_result = MempoolSyncUpdate::poll(daemon, old_txids, exit_flag);
+        // the whole mempool is fetched only by the first sync (an empty mempool is synced
+        // incrementally afterwards)
+        let full = self.last_sync.is_none();
+        let poll_result = MempoolSyncUpdate::poll(daemon, old_txids, full, daemon_count, exit_flag);
 
-        let sync_update = match poll_result {
-            Ok(sync_update) => sync_update,
+        match poll_result {
+            Ok(sync_update) => Some(sync_update),
             Err(e) => {
                 warn!("mempool sync failed: {}", e);
-                return;
+                None
             }
-        };
-
-        self.apply_sync_update(sync_update);
+        }
     }
 
     /// Add a transaction entry to the mempool and update the fee histogram.
     fn add_entry(&mut self, entry: Entry) {
+        self.touch(&entry.tx);
         for txi in &entry.tx.input {
             self.by_spending.insert((txi.previous_output, entry.txid));
         }
         for txo in &entry.tx.output {
+            if !self.script_types.contains(&txo.script_pubkey) {
+                continue; // not indexed (for consistency with the confirmed transactions)
+            }
             let scripthash = ScriptHash::new(&txo.script_pubkey);
             self.by_funding.insert((scripthash, entry.txid)); // may have duplicates
         }
 
         self.modify_fee_histogram(entry.fee, entry.vsize as i64);
+        self.total_vsize += entry.vsize;
+        self.total_fees += entry.fee;
 
         assert!(
             self.entries.insert(entry.txid, entry).is_none(),
@@ -259,6 +452,7 @@ impl Mempool {
     /// Remove a transaction entry from the mempool and update the fee histogram.
     fn remove_entry(&mut self, txid: Txid) {
         let entry = self.entries.remove(&txid).expect("missing tx from mempool");
+        self.touch(&entry.tx);
         for txi in entry.tx.input {
             self.by_spending.remove(&(txi.previous_output, txid));
         }
@@ -268,6 +462,23 @@ impl Mempool {
         }
 
         self.modify_fee_histogram(entry.fee, -(entry.vsize as i64));
+        self.total_vsize -= entry.vsize;
+        self.total_fees -= entry.fee;
+    }
+
+    fn touch(&mut self, tx: &Transaction) {
+        if let Some(touched) = &mut self.touched {
+            touched.add_funding(
+                tx.output
+                    .iter()
+                    .map(|txo| ScriptHashRow::scan_prefix(ScriptHash::new(&txo.script_pubkey))),
+            );
+            touched.add_spending(
+                tx.input
+                    .iter()
+                    .map(|txi| SpendingPrefixRow::scan_prefix(txi.previous_output)),
+            );
+        }
     }
 
     /// Apply a change to the fee histogram. Used when transactions are added or
@@ -285,6 +496,7 @@ impl Mempool {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct FeeHistogram {
     /// bins[64-i] contains transactions' statistics inside the fee band of [2**(i-1), 2**i).
     /// bins[64] = [0, 1)
@@ -310,17 +522,39 @@ impl Default for FeeHistogram {
 
 impl FeeHistogram {
     const BINS: usize = 65; // 0..=64
+    const PERCENTILES: [u8; 5] = [10, 25, 50, 75, 90];
 
     fn bin_index(fee: Amount, vsize: u64) -> usize {
         let fee_rate = fee.to_sat() / vsize;
         usize::try_from(fee_rate.leading_zeros()).unwrap()
     }
 
+    /// The bin's label in `mempool.get_fee_histogram` responses
+    fn fee_rate(bin_index: usize) -> u64 {
+        u64::MAX.checked_shr(bin_index as u32).unwrap_or(0)
+    }
+
     fn bin_range(bin_index: usize) -> (u128, u128) {
         let limit = 1u128 << (FeeHistogram::BINS - bin_index - 1);
         (limit / 2, limit)
     }
 
+    /// Returns the lower bound of the bin containing the given (vsize-weighted) percentile.
+    fn percentile(&self, percentile: u8) -> u64 {
+        let total: u64 = self.vsize.iter().sum();
+        let target = total * u64::from(percentile) / 100;
+        let mut cumulative = 0;
+        // iterate from the lowest fee rate bin
+        for bin_index in (0..FeeHistogram::BINS).rev() {
+            cumulative += self.vsize[bin_index];
+            if cumulative > target {
+                let (lower, _upper) = FeeHistogram::bin_range(bin_index);
+                return u64::try_from(lower).unwrap_or(u64::MAX);
+            }
+        }
+        0 // empty histogram
+    }
+
     fn insert(&mut self, bin_index: usize, vsize: u64) {
         // skip transactions with too low fee rate (<1 sat/vB)
         if let Some(bin) = self.vsize.get_mut(bin_index) {
@@ -331,6 +565,14 @@ impl FeeHistogram {
         }
     }
 
+    /// `[fee_rate, vsize]` of the bins whose vsize differs from `prev`'s (from the highest fee rate)
+    fn changes(&self, prev: &FeeHistogram) -> Vec<(u64, u64)> {
+        (0..FeeHistogram::BINS)
+            .filter(|&i| self.vsize[i] != prev.vsize[i])
+            .map(|i| (FeeHistogram::fee_rate(i), self.vsize[i]))
+            .collect()
+    }
+
     fn remove(&mut self, bin_index: usize, vsize: u64) {
         // skip transactions with too low fee rate (<1 sat/vB)
         if let Some(bin) = self.vsize.get_mut(bin_index) {
@@ -355,8 +597,7 @@ impl Serialize for FeeHistogram {
     {
         let mut seq = serializer.serialize_seq(Some(self.vsize.len()))?;
         // https://electrum-protocol.readthedocs.io/en/latest/protocol-methods.html#mempool-get-fee-histogram
-        let fee_rates =
-            (0..FeeHistogram::BINS).map(|i| u64::MAX.checked_shr(i as u32).unwrap_or(0));
+        let fee_rates = (0..FeeHistogram::BINS).map(FeeHistogram::fee_rate);
         fee_rates
             .zip(self.vsize.iter().copied())
             .skip_while(|(_fee_rate, vsize)| *vsize == 0)
@@ -367,9 +608,10 @@ impl Serialize for FeeHistogram {
 
 #[cfg(test)]
 mod tests {
-    use super::FeeHistogram;
+    use super::{FeeHistogram, MempoolSummary};
     use bitcoin::Amount;
     use serde_json::json;
+    use std::collections::{BTreeMap, HashSet};
 
     #[test]
     fn test_histogram() {
@@ -430,4 +672,89 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut hist = FeeHistogram::default();
+        assert_eq!(hist.percentile(50), 0);
+
+        let items = vec![
+            (Amount::from_sat(0), 100),      // [0, 1)
+            (Amount::from_sat(300), 100),    // [2, 4)
+            (Amount::from_sat(1_000), 100),  // [8, 16)
+            (Amount::from_sat(10_000), 100), // [64, 128)
+        ];
+        for (amount, vsize) in items {
+            hist.insert(FeeHistogram::bin_index(amount, vsize), vsize);
+        }
+        assert_eq!(hist.percentile(10), 0);
+        assert_eq!(hist.percentile(25), 2);
+        assert_eq!(hist.percentile(50), 8);
+        assert_eq!(hist.percentile(75), 64);
+        assert_eq!(hist.percentile(90), 64);
+    }
+
+    #[test]
+    fn test_summary_changes() {
+        // the (fee, vsize) of the mempool transactions after each sync
+        let syncs: Vec<Vec<(u64, u64)>> = vec![
+            vec![(1_000, 100), (20, 10)],
+            vec![(1_000, 100), (20, 10), (5_000, 200), (0, 50), (30, 10)],
+            vec![(20, 10), (5_000, 200), (30, 10)], // removed
+            vec![(20, 10), (6_000, 200), (300, 150)], // replaced
+            vec![(20, 10), (6_000, 200), (300, 150), (1, 1), (90_000, 100)],
+            vec![],
+        ];
+        let bin = |(fee, vsize): (u64, u64)| FeeHistogram::bin_index(Amount::from_sat(fee), vsize);
+        let nonzero = |bins: Vec<(u64, u64)>| -> Vec<(u64, u64)> {
+            bins.into_iter().filter(|(_, vsize)| *vsize > 0).collect()
+        };
+
+        // updated incrementally (as by `Mempool::apply_sync_update`)
+        let mut histogram = FeeHistogram::default();
+        let mut txs = HashSet::new();
+        let mut prev = MempoolSummary {
+            count: 0,
+            vsize: 0,
+            histogram: histogram.clone(),
+            mempool_generation: 0,
+        };
+        // the client's mirror (by fee rate)
+        let mut mirror = BTreeMap::<u64, u64>::new();
+        for (i, sync) in syncs.into_iter().enumerate() {
+            let next: HashSet<(u64, u64)> = sync.into_iter().collect();
+            for &tx in txs.difference(&next) {
+                histogram.remove(bin(tx), tx.1);
+            }
+            for &tx in next.difference(&txs) {
+                histogram.insert(bin(tx), tx.1);
+            }
+            txs = next;
+            let summary = MempoolSummary {
+                count: txs.len(),
+                vsize: txs.iter().map(|(_, vsize)| vsize).sum(),
+                histogram: histogram.clone(),
+                mempool_generation: i as u64 + 1,
+            };
+            let changes = summary.changes(&prev);
+            assert!(!changes.histogram_changes.is_empty());
+            assert_eq!(changes.count, txs.len());
+            assert_eq!(changes.mempool_generation, i as u64 + 1);
+            mirror.extend(changes.histogram_changes);
+            prev = summary;
+
+            let mut expected = FeeHistogram::default();
+            for &tx in &txs {
+                expected.insert(bin(tx), tx.1);
+            }
+            let expected: Vec<(u64, u64)> = serde_json::from_value(json!(expected)).unwrap();
+            let mirrored = mirror
+                .iter()
+                .rev()
+                .map(|(&fee_rate, &vsize)| (fee_rate, vsize));
+            assert_eq!(nonzero(mirrored.collect()), nonzero(expected));
+        }
+        assert!(nonzero(mirror.into_iter().collect()).is_empty());
+        assert!(prev.histogram.changes(&FeeHistogram::default()).is_empty());
+    }
 }
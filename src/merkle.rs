@@ -1,5 +1,7 @@
 use bitcoin::{hash_types::TxMerkleNode, hashes::Hash, Txid};
 
+use std::ops::Range;
+
 pub(crate) struct Proof {
     proof: Vec<TxMerkleNode>,
     position: usize,
@@ -7,37 +9,27 @@ pub(crate) struct Proof {
 
 impl Proof {
     pub(crate) fn create(txids: &[Txid], position: usize) -> Self {
-        assert!(position < txids.len());
-        let mut offset = position;
-        let mut hashes: Vec<TxMerkleNode> = txids
+        let hashes: Vec<TxMerkleNode> = txids
             .iter()
             .map(|txid| TxMerkleNode::from_raw_hash(txid.to_raw_hash()))
             .collect();
+        Self::with_root(hashes, position).0
+    }
 
+    /// Create a proof for the given leaves (also used for block hashes, see `cp_height`),
+    /// returning the merkle root too.
+    pub(crate) fn with_root(
+        mut hashes: Vec<TxMerkleNode>,
+        position: usize,
+    ) -> (Self, TxMerkleNode) {
+        assert!(position < hashes.len());
+        let mut offset = position;
         let mut proof = vec![];
         while hashes.len() > 1 {
-            if hashes.len() % 2 != 0 {
-                let last = *hashes.last().unwrap();
-                hashes.push(last);
-            }
-            offset = if offset % 2 == 0 {
-                offset + 1
-            } else {
-                offset - 1
-            };
-            proof.push(hashes[offset]);
+            hashes = parent_level(hashes, offset, &mut proof);
             offset /= 2;
-            hashes = hashes
-                .chunks(2)
-                .map(|pair| {
-                    let left = pair[0];
-                    let right = pair[1];
-                    let input = [&left[..], &right[..]].concat();
-                    TxMerkleNode::hash(&input)
-                })
-                .collect()
         }
-        Self { proof, position }
+        (Self { proof, position }, hashes[0])
     }
 
     pub(crate) fn to_hex(&self) -> Vec<String> {
@@ -52,12 +44,128 @@ impl Proof {
     }
 }
 
+/// Hash the pairs of `hashes` (the last one is paired with itself, if needed), appending the
+/// node paired with `offset` to `proof`.
+fn parent_level(
+    mut hashes: Vec<TxMerkleNode>,
+    offset: usize,
+    proof: &mut Vec<TxMerkleNode>,
+) -> Vec<TxMerkleNode> {
+    if hashes.len() % 2 != 0 {
+        let last = *hashes.last().unwrap();
+        hashes.push(last);
+    }
+    proof.push(hashes[offset ^ 1]);
+    hashes
+        .chunks(2)
+        .map(|pair| hash_pair(pair[0], pair[1]))
+        .collect()
+}
+
+fn hash_pair(left: TxMerkleNode, right: TxMerkleNode) -> TxMerkleNode {
+    let input = [&left[..], &right[..]].concat();
+    TxMerkleNode::hash(&input)
+}
+
+/// Each subtree of this many leaves is hashed when a proof for one of its leaves is created.
+const SUBTREE_DEPTH: u32 = 6;
+
+/// The levels of a large merkle tree (e.g. of the block hashes up to a `cp_height`) above its
+/// subtrees, so a proof needs only its subtree's leaves (instead of all of them).
+pub(crate) struct UpperTree {
+    leaves: usize,
+    levels: Vec<Vec<TxMerkleNode>>, // starting with the subtrees' roots
+}
+
+impl UpperTree {
+    pub(crate) const SUBTREE_LEAVES: usize = 1 << SUBTREE_DEPTH;
+
+    /// Build the tree of `leaves` (passed to the given function in order), returning `None` if
+    /// their number differs.
+    pub(crate) fn new(
+        leaves: usize,
+        for_each_leaf: impl FnOnce(&mut dyn FnMut(TxMerkleNode)),
+    ) -> Option<Self> {
+        let mut count = 0;
+        let mut subtree = Vec::with_capacity(Self::SUBTREE_LEAVES);
+        let mut roots = Vec::with_capacity(leaves.div_ceil(Self::SUBTREE_LEAVES));
+        for_each_leaf(&mut |leaf| {
+            count += 1;
+            subtree.push(leaf);
+            if subtree.len() == Self::SUBTREE_LEAVES {
+                roots.push(subtree_root(std::mem::take(&mut subtree), leaves));
+            }
+        });
+        if count != leaves || leaves == 0 {
+            return None;
+        }
+        if !subtree.is_empty() {
+            roots.push(subtree_root(subtree, leaves));
+        }
+        let mut levels = vec![roots];
+        loop {
+            let last = levels.last().expect("no levels");
+            if last.len() == 1 {
+                break;
+            }
+            let parents = parent_level(last.clone(), 0, &mut vec![]);
+            levels.push(parents);
+        }
+        Some(Self { leaves, levels })
+    }
+
+    /// The leaves of the subtree containing `position`
+    pub(crate) fn subtree(&self, position: usize) -> Range<usize> {
+        let start = position - position % Self::SUBTREE_LEAVES;
+        start..self.leaves.min(start + Self::SUBTREE_LEAVES)
+    }
+
+    /// Create a proof for `position`, given the leaves of its subtree (see `subtree`), returning
+    /// the merkle root too.
+    pub(crate) fn proof(
+        &self,
+        position: usize,
+        mut subtree: Vec<TxMerkleNode>,
+    ) -> (Proof, TxMerkleNode) {
+        assert_eq!(subtree.len(), self.subtree(position).len());
+        let mut offset = position % Self::SUBTREE_LEAVES;
+        let mut proof = vec![];
+        for _ in 0..subtree_depth(self.leaves) {
+            subtree = parent_level(subtree, offset, &mut proof);
+            offset /= 2;
+        }
+        let mut index = position / Self::SUBTREE_LEAVES;
+        debug_assert_eq!(subtree, [self.levels[0][index]]);
+        let (root, levels) = self.levels.split_last().expect("empty tree");
+        for level in levels {
+            // the last node is paired with itself, if needed
+            proof.push(*level.get(index ^ 1).unwrap_or(&level[index]));
+            index /= 2;
+        }
+        (Proof { proof, position }, root[0])
+    }
+}
+
+/// The number of levels below a subtree's root (the whole tree may be smaller)
+fn subtree_depth(leaves: usize) -> u32 {
+    SUBTREE_DEPTH.min(leaves.next_power_of_two().trailing_zeros())
+}
+
+/// The root of a subtree, as hashed within the tree of all `leaves`
+fn subtree_root(mut hashes: Vec<TxMerkleNode>, leaves: usize) -> TxMerkleNode {
+    for _ in 0..subtree_depth(leaves) {
+        hashes = parent_level(hashes, 0, &mut vec![]);
+    }
+    hashes[0]
+}
+
 #[cfg(test)]
 mod tests {
     use bitcoin::{consensus::encode::deserialize, Block, Txid};
     use std::path::Path;
 
-    use super::Proof;
+    use super::{Proof, UpperTree};
+    use bitcoin::{hash_types::TxMerkleNode, hashes::Hash};
 
     #[test]
     fn test_merkle() {
@@ -101,6 +209,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_upper_tree() {
+        let leaf = |i: u32| TxMerkleNode::hash(&i.to_le_bytes());
+        let sizes = [1, 2, 3, 63, 64, 65, 128, 129, 1000, 4097];
+        for leaves in sizes {
+            let hashes: Vec<TxMerkleNode> = (0..leaves as u32).map(leaf).collect();
+            let tree =
+                UpperTree::new(leaves, |func| hashes.iter().copied().for_each(func)).unwrap();
+            for position in [0, 1, 62, 63, 64, 65, leaves / 2, leaves - 1] {
+                if position >= leaves {
+                    continue;
+                }
+                let subtree = hashes[tree.subtree(position)].to_vec();
+                let (proof, root) = tree.proof(position, subtree);
+                let (expected_proof, expected_root) = Proof::with_root(hashes.clone(), position);
+                assert_eq!(root, expected_root, "{} leaves", leaves);
+                assert_eq!(proof.to_hex(), expected_proof.to_hex(), "{} leaves", leaves);
+                assert_eq!(proof.position(), position);
+            }
+        }
+        // a missing leaf
+        assert!(UpperTree::new(3, |func| (0..2).map(leaf).for_each(func)).is_none());
+    }
+
     fn load_block_txids(block_hash_hex: &str) -> Vec<Txid> {
         let path = Path::new("src")
             .join("tests")
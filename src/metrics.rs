@@ -9,37 +9,113 @@ mod metrics_impl {
     use tiny_http::{Header as HttpHeader, Response, Server};
 
     use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Instant;
 
-    use crate::thread::spawn;
+    use crate::{
+        config::{ELECTRS_GIT_COMMIT, ELECTRS_VERSION},
+        health::Health,
+        thread::spawn,
+    };
 
     pub struct Metrics {
         reg: Registry,
+        health: Health,
+        server: Option<Arc<Server>>, // `None` if not served
     }
 
-    impl Metrics {
-        pub fn new(addr: SocketAddr) -> Result<Self> {
-            let reg = Registry::new();
+    /// Encodes the registered metrics (updating the uptime before each scrape)
+    #[derive(Clone)]
+    struct Scraper {
+        reg: Registry,
+        uptime: prometheus::Gauge,
+        started: Instant,
+    }
 
-            #[cfg(feature = "metrics_process")]
-            reg.register(Box::new(ProcessCollector::for_self()))
-                .expect("failed to register ProcessCollector");
+    impl Scraper {
+        fn new(reg: Registry) -> Self {
+            let features = [
+                ("metrics", cfg!(feature = "metrics")),
+                ("metrics_process", cfg!(feature = "metrics_process")),
+                ("http", cfg!(feature = "http")),
+            ];
+            let features: Vec<&str> = features
+                .into_iter()
+                .filter_map(|(name, enabled)| enabled.then_some(name))
+                .collect();
+            let labels = [
+                ("version", ELECTRS_VERSION.to_owned()),
+                ("commit", ELECTRS_GIT_COMMIT.to_owned()),
+                ("rustc", env!("ELECTRS_RUSTC_VERSION").to_owned()),
+                ("features", features.join(",")),
+            ];
+            let opts = prometheus::Opts::new("electrs_build_info", "Build information (always 1)")
+                .const_labels(labels.map(|(k, v)| (k.to_owned(), v)).into());
+            let build_info = prometheus::Gauge::with_opts(opts).unwrap();
+            build_info.set(1.0);
+            reg.register(Box::new(build_info))
+                .expect("failed to register build info");
+
+            let uptime = prometheus::Gauge::new(
+                "electrs_uptime_seconds",
+                "Time since the process has started (in seconds)",
+            )
+            .unwrap();
+            reg.register(Box::new(uptime.clone()))
+                .expect("failed to register uptime");
+            Self {
+                reg,
+                uptime,
+                started: Instant::now(),
+            }
+        }
 
-            let result = Self { reg };
-            let reg = result.reg.clone();
+        fn encode(&self) -> Result<Vec<u8>> {
+            self.uptime.set(self.started.elapsed().as_secs_f64());
+            let mut buffer = vec![];
+            prometheus::TextEncoder::new()
+                .encode(&self.reg.gather(), &mut buffer)
+                .context("failed to encode metrics")?;
+            Ok(buffer)
+        }
+    }
 
+    impl Metrics {
+        pub fn new(addr: SocketAddr) -> Result<Self> {
             let server = match Server::http(addr) {
-                Ok(server) => server,
+                Ok(server) => Arc::new(server),
                 Err(err) => bail!("failed to start HTTP server on {}: {}", addr, err),
             };
+            let mut result = Self::unserved();
+            result.server = Some(Arc::clone(&server));
+
+            #[cfg(feature = "metrics_process")]
+            result
+                .reg
+                .register(Box::new(ProcessCollector::for_self()))
+                .expect("failed to register ProcessCollector");
+
+            let scraper = Scraper::new(result.reg.clone());
+            let health = result.health.clone();
 
             spawn("metrics", move || {
                 let content_type = HttpHeader::from_bytes(&b"Content-Type"[..], TEXT_FORMAT)
                     .expect("failed to create HTTP header for Prometheus text format");
+                let json_type =
+                    HttpHeader::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                        .expect("failed to create HTTP header for JSON");
                 for request in server.incoming_requests() {
-                    let mut buffer = vec![];
-                    prometheus::TextEncoder::new()
-                        .encode(&reg.gather(), &mut buffer)
-                        .context("failed to encode metrics")?;
+                    let path = request.url().split('?').next().unwrap_or_default();
+                    if let Some((code, body)) = health.respond(path) {
+                        let response = Response::from_string(body.to_string())
+                            .with_status_code(code)
+                            .with_header(json_type.clone());
+                        request
+                            .respond(response)
+                            .context("failed to send HTTP response")?;
+                        continue;
+                    }
+                    let buffer = scraper.encode()?;
                     request
                         .respond(Response::from_data(buffer).with_header(content_type.clone()))
                         .context("failed to send HTTP response")?;
@@ -51,6 +127,15 @@ mod metrics_impl {
             Ok(result)
         }
 
+        /// A registry without an HTTP listener (e.g. for an index which is not scraped).
+        pub(crate) fn unserved() -> Self {
+            Self {
+                reg: Registry::new(),
+                health: Health::default(),
+                server: None,
+            }
+        }
+
         pub fn histogram_vec(
             &self,
             name: &str,
@@ -76,6 +161,39 @@ mod metrics_impl {
                 .expect("failed to register Gauge");
             Gauge { gauge }
         }
+
+        pub fn counter(&self, name: &str, desc: &str, label: &str) -> Counter {
+            let name = String::from("electrs_") + name;
+            let opts = prometheus::Opts::new(name, desc);
+            let counter = prometheus::IntCounterVec::new(opts, &[label]).unwrap();
+            self.reg
+                .register(Box::new(counter.clone()))
+                .expect("failed to register Counter");
+            Counter { counter }
+        }
+
+        /// A single (unlabelled) counter, whose value can be read back (e.g. to persist it)
+        pub(crate) fn total(&self, name: &str, desc: &str) -> Total {
+            let name = String::from("electrs_") + name;
+            let counter = prometheus::IntCounter::new(name, desc).unwrap();
+            self.reg
+                .register(Box::new(counter.clone()))
+                .expect("failed to register Total");
+            Total { counter }
+        }
+
+        /// Health state, served via `/healthz` and `/readyz`
+        pub(crate) fn health(&self) -> &Health {
+            &self.health
+        }
+    }
+
+    impl Drop for Metrics {
+        fn drop(&mut self) {
+            if let Some(server) = &self.server {
+                server.unblock(); // stop the HTTP server's thread (closing its listener)
+            }
+        }
     }
 
     #[derive(Clone)]
@@ -87,6 +205,50 @@ mod metrics_impl {
         pub fn set(&self, label: &str, value: f64) {
             self.gauge.with_label_values(&[label]).set(value)
         }
+
+        #[cfg(test)]
+        pub(crate) fn get(&self, label: &str) -> f64 {
+            self.gauge.with_label_values(&[label]).get()
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Counter {
+        counter: prometheus::IntCounterVec,
+    }
+
+    impl Counter {
+        pub fn inc(&self, label: &str) {
+            self.counter.with_label_values(&[label]).inc()
+        }
+
+        pub fn inc_by(&self, label: &str, value: u64) {
+            self.counter.with_label_values(&[label]).inc_by(value)
+        }
+
+        #[cfg(test)]
+        pub(crate) fn get(&self, label: &str) -> u64 {
+            self.counter.with_label_values(&[label]).get()
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Total {
+        counter: prometheus::IntCounter,
+    }
+
+    impl Total {
+        pub fn inc(&self) {
+            self.counter.inc()
+        }
+
+        pub fn inc_by(&self, value: u64) {
+            self.counter.inc_by(value)
+        }
+
+        pub fn get(&self) -> u64 {
+            self.counter.get()
+        }
     }
 
     #[derive(Clone)]
@@ -108,23 +270,61 @@ mod metrics_impl {
                 .observe_closure_duration(func)
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::Scraper;
+        use prometheus::Registry;
+
+        #[test]
+        fn test_build_info() {
+            let scraper = Scraper::new(Registry::new());
+            let text = String::from_utf8(scraper.encode().unwrap()).unwrap();
+            let build_info = text
+                .lines()
+                .find(|line| line.starts_with("electrs_build_info{"))
+                .expect("missing build info");
+            assert!(build_info.ends_with("} 1"), "{}", build_info);
+            let commit = format!("commit=\"{}\"", crate::config::ELECTRS_GIT_COMMIT);
+            assert!(build_info.contains(&commit), "{}", build_info);
+            assert!(
+                build_info.contains("features=\"metrics"), // "metrics_process" may be enabled too
+                "{}",
+                build_info
+            );
+            assert!(build_info.contains("rustc=\"rustc "), "{}", build_info);
+            assert!(text.contains("\nelectrs_uptime_seconds "), "{}", text);
+        }
+    }
 }
 
 #[cfg(feature = "metrics")]
-pub use metrics_impl::{Gauge, Histogram, Metrics};
+pub use metrics_impl::{Counter, Gauge, Histogram, Metrics, Total};
 
 #[cfg(not(feature = "metrics"))]
 mod metrics_fake {
     use anyhow::Result;
 
     use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
 
-    pub struct Metrics {}
+    use crate::health::Health;
+
+    pub struct Metrics {
+        health: Health,
+    }
 
     impl Metrics {
         pub fn new(_addr: SocketAddr) -> Result<Self> {
             debug!("metrics collection is disabled");
-            Ok(Self {})
+            Ok(Self::unserved())
+        }
+
+        pub(crate) fn unserved() -> Self {
+            Self {
+                health: Health::default(),
+            }
         }
 
         pub fn histogram_vec(
@@ -140,6 +340,19 @@ mod metrics_fake {
         pub fn gauge(&self, _name: &str, _desc: &str, _label: &str) -> Gauge {
             Gauge {}
         }
+
+        pub fn counter(&self, _name: &str, _desc: &str, _label: &str) -> Counter {
+            Counter {}
+        }
+
+        pub(crate) fn total(&self, _name: &str, _desc: &str) -> Total {
+            Total::default()
+        }
+
+        /// Health state (not served, since there is no monitoring listener)
+        pub(crate) fn health(&self) -> &Health {
+            &self.health
+        }
     }
 
     #[derive(Clone)]
@@ -149,6 +362,35 @@ mod metrics_fake {
         pub fn set(&self, _label: &str, _value: f64) {}
     }
 
+    #[derive(Clone)]
+    pub struct Counter {}
+
+    impl Counter {
+        pub fn inc(&self, _label: &str) {}
+
+        pub fn inc_by(&self, _label: &str, _value: u64) {}
+    }
+
+    /// Not exported, but still counted (so the persisted values are kept)
+    #[derive(Clone, Default)]
+    pub struct Total {
+        value: Arc<AtomicU64>,
+    }
+
+    impl Total {
+        pub fn inc(&self) {
+            self.inc_by(1)
+        }
+
+        pub fn inc_by(&self, value: u64) {
+            self.value.fetch_add(value, Ordering::Relaxed);
+        }
+
+        pub fn get(&self) -> u64 {
+            self.value.load(Ordering::Relaxed)
+        }
+    }
+
     #[derive(Clone)]
     pub struct Histogram {}
 
@@ -165,7 +407,7 @@ mod metrics_fake {
 }
 
 #[cfg(not(feature = "metrics"))]
-pub use metrics_fake::{Gauge, Histogram, Metrics};
+pub use metrics_fake::{Counter, Gauge, Histogram, Metrics, Total};
 
 pub(crate) fn default_duration_buckets() -> Vec<f64> {
     vec![
@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{index::Index, limits};
+
+/// The DB directories of a network: the authoritative one (served by electrs) is recorded by a
+/// marker file, so a shadow migration can switch to its sibling directory crash-safely.
+///
+/// For `db_dir/bitcoin`, the DBs are `db_dir/bitcoin` and `db_dir/bitcoin.shadow` (alternating
+/// between migrations), the marker is `db_dir/bitcoin.active`, and a replaced DB is archived by
+/// appending `.archived-<UNIX time>` to its name. Without a marker, `db_dir/bitcoin` is
+/// authoritative.
+#[derive(Clone, Debug)]
+pub(crate) struct Layout {
+    base: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+struct Marker {
+    /// The authoritative DB directory's name
+    active: String,
+    /// The previously authoritative DB directory's name, if it is not archived yet
+    archive: Option<String>,
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .expect("invalid DB path")
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl Layout {
+    pub(crate) fn new(db_path: &Path) -> Self {
+        Self {
+            base: db_path.to_owned(),
+        }
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        self.base
+            .with_file_name(format!("{}{}", file_name(&self.base), suffix))
+    }
+
+    fn marker_path(&self) -> PathBuf {
+        self.sibling(".active")
+    }
+
+    fn read_marker(&self) -> Result<Option<Marker>> {
+        let path = self.marker_path();
+        match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .with_context(|| format!("invalid DB marker file {}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    /// Replace the marker atomically: a crash leaves either the previous marker or the new one
+    /// (the temporary file is fsynced before being renamed, and the directory afterwards).
+    fn write_marker(&self, marker: &Marker) -> Result<()> {
+        let path = self.marker_path();
+        let tmp = self.sibling(".active.tmp");
+        let contents = serde_json::to_vec(marker).context("failed to serialize DB marker")?;
+        let mut file = fs::File::create(&tmp)
+            .with_context(|| format!("failed to create {}", tmp.display()))?;
+        file.write_all(&contents)
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("failed to rename {}", tmp.display()))?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("failed to sync {}", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /// The authoritative DB directory
+    pub(crate) fn active(&self) -> Result<PathBuf> {
+        Ok(match self.read_marker()? {
+            Some(marker) => self.base.with_file_name(marker.active),
+            None => self.base.clone(),
+        })
+    }
+
+    /// The directory of the DB to migrate into (the authoritative one's sibling). It may contain
+    /// a partially migrated DB, which is resumed (or re-indexed, if its settings differ).
+    pub(crate) fn shadow(&self) -> Result<PathBuf> {
+        let active = self.active()?;
+        Ok(match active == self.base {
+            true => self.sibling(".shadow"),
+            false => self.base.clone(),
+        })
+    }
+
+    /// Finish a switch interrupted by a crash (archiving the previously authoritative DB), and
+    /// return the authoritative DB directory. Should be called before opening the DB.
+    pub(crate) fn recover(&self) -> Result<PathBuf> {
+        if let Some(archived) = self.archive()? {
+            warn!(
+                "finished an interrupted DB switch: archived the previous DB as {}",
+                archived.display()
+            );
+        }
+        self.active()
+    }
+
+    /// Make `dir` the authoritative DB directory (the commit point of a switch), recording the
+    /// previous one to be archived by `archive()` once it is closed.
+    pub(crate) fn activate(&self, dir: &Path) -> Result<()> {
+        let prev = self.active()?;
+        self.write_marker(&Marker {
+            active: file_name(dir),
+            archive: Some(file_name(&prev)),
+        })
+    }
+
+    /// Rename the previously authoritative DB directory (which must be closed) to an archive,
+    /// returning its new path (or `None` if there is nothing to archive).
+    pub(crate) fn archive(&self) -> Result<Option<PathBuf>> {
+        let marker = match self.read_marker()? {
+            Some(marker) => marker,
+            None => return Ok(None),
+        };
+        let prev = match &marker.archive {
+            Some(prev) => self.base.with_file_name(prev),
+            None => return Ok(None),
+        };
+        // it may have been renamed before a crash (without updating the marker)
+        let archived = match prev.exists() {
+            true => {
+                let name = format!("{}.archived-{}", file_name(&prev), limits::unix_now());
+                let archived = self.base.with_file_name(name);
+                fs::rename(&prev, &archived).with_context(|| {
+                    format!("failed to archive the previous DB {}", prev.display())
+                })?;
+                Some(archived)
+            }
+            false => None,
+        };
+        self.write_marker(&Marker {
+            active: marker.active,
+            archive: None,
+        })?;
+        Ok(archived)
+    }
+}
+
+/// A shadow migration in progress: the index being built in the shadow DB directory (using the
+/// configured settings), while the authoritative DB keeps serving (using its stored settings).
+pub(crate) struct Migration {
+    pub(crate) index: Index,
+    pub(crate) layout: Layout,
+    pub(crate) dir: PathBuf,
+}
+
+/// The progress of a shadow migration, as reported by `electrs.index.status`
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Progress {
+    pub(crate) dir: PathBuf,
+    pub(crate) migrated_height: usize,
+    pub(crate) tip_height: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Layout;
+
+    #[test]
+    fn test_switch_and_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("regtest");
+        let shadow = dir.path().join("regtest.shadow");
+        let layout = Layout::new(&base);
+
+        // without a marker, the configured directory is authoritative
+        assert_eq!(layout.recover().unwrap(), base);
+        assert_eq!(layout.shadow().unwrap(), shadow);
+
+        std::fs::create_dir(&base).unwrap();
+        std::fs::create_dir(&shadow).unwrap();
+        layout.activate(&shadow).unwrap();
+
+        // a crash before archiving: the switch is committed, and finished on startup
+        let layout = Layout::new(&base);
+        assert_eq!(layout.active().unwrap(), shadow);
+        assert_eq!(layout.recover().unwrap(), shadow);
+        assert!(!base.exists());
+        let archived: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("regtest.archived-"))
+            .collect();
+        assert_eq!(archived.len(), 1);
+        assert!(layout.archive().unwrap().is_none()); // nothing is left to archive
+
+        // the next migration uses the configured directory again
+        assert_eq!(layout.shadow().unwrap(), base);
+        std::fs::create_dir(&base).unwrap();
+        layout.activate(&base).unwrap();
+        assert!(layout.archive().unwrap().is_some());
+        assert_eq!(layout.recover().unwrap(), base);
+        assert!(!shadow.exists());
+    }
+}
@@ -0,0 +1,496 @@
+use anyhow::{bail, ensure, Context, Result};
+use parking_lot::{Condvar, Mutex};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    electrum::Topic,
+    metrics::Counter,
+    outgoing::Outgoing,
+    shaper::{Throttle, CHUNK_SIZE},
+    shrink::Shrink,
+};
+
+/// Log (once per connection) when shaping delays a message by more than this duration
+const SHAPING_LOG_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Queue {
+    items: VecDeque<(Option<Topic>, Outgoing)>, // responses have no topic
+    bytes: usize,
+    closed: bool,
+    // the last scripthash notifications which were queued (and sent), to suppress duplicates
+    queued: HashMap<Topic, String>,
+    sent: HashMap<Topic, String>,
+}
+
+impl Queue {
+    // Drop notifications superseded by later ones (only the latest status of a subscription matters).
+    // Returns the number of notifications which were dropped since the peer has already been sent
+    // the same status (e.g. a status which was changed and then reverted).
+    fn coalesce(&mut self, next: Option<Topic>) -> usize {
+        let mut latest: HashSet<Topic> = next.into_iter().collect();
+        let mut items = VecDeque::with_capacity(self.items.len());
+        let mut reverted = 0;
+        for (topic, msg) in self.items.drain(..).rev() {
+            match topic {
+                // each notification contains only the changes since the previous one
+                Some(Topic::MempoolSummary) => items.push_front((topic, msg)),
+                Some(topic) if !latest.insert(topic) => self.bytes -= msg.len(),
+                Some(topic) if self.sent.get(&topic).map(String::as_str) == msg.as_text() => {
+                    self.bytes -= msg.len();
+                    reverted += 1;
+                }
+                _ => items.push_front((topic, msg)),
+            }
+        }
+        self.items = items;
+        reverted
+    }
+}
+
+/// Outgoing messages of a single peer, sent by a dedicated thread (so a peer which doesn't read
+/// its messages can't block the main loop)
+#[derive(Clone)]
+pub(crate) struct Outbox {
+    shared: Arc<(Mutex<Queue>, Condvar)>,
+    max_bytes: usize,
+    disconnects: Counter,
+    suppressed: Counter,
+}
+
+impl Outbox {
+    pub(crate) fn new(max_bytes: usize, disconnects: Counter, suppressed: Counter) -> Self {
+        Self {
+            shared: Arc::default(),
+            max_bytes,
+            disconnects,
+            suppressed,
+        }
+    }
+
+    /// Large responses are serialized only while they are sent (see `Outgoing`)
+    pub(crate) fn push_response(&self, msg: impl Into<Outgoing>) -> Result<()> {
+        self.push(None, msg.into())
+    }
+
+    pub(crate) fn push_notification(&self, topic: Topic, msg: String) -> Result<()> {
+        self.push(Some(topic), msg.into())
+    }
+
+    fn push(&self, topic: Option<Topic>, msg: Outgoing) -> Result<()> {
+        let (queue, cond) = &*self.shared;
+        let mut queue = queue.lock();
+        ensure!(!queue.closed, "peer is disconnected");
+        // only scripthash notifications are de-duplicated (`blockchain.headers.subscribe` may be
+        // answered by the cheap requests' path, so the last header sent to the peer is unknown)
+        if let Some(topic @ Topic::ScriptHash(_)) = topic {
+            // the peer will receive the previously queued status before this one
+            let text = msg.to_string();
+            if queue.queued.get(&topic) == Some(&text) {
+                self.suppressed.inc("unchanged");
+                return Ok(());
+            }
+            queue.queued.insert(topic, text);
+        }
+        if queue.bytes + msg.len() > self.max_bytes {
+            let reverted = queue.coalesce(topic);
+            (0..reverted).for_each(|_| self.suppressed.inc("reverted"));
+            if let Some(topic @ Topic::ScriptHash(_)) = topic {
+                // the previously queued statuses were dropped, and the peer already has this one
+                if queue.sent.get(&topic).map(String::as_str) == msg.as_text() {
+                    self.suppressed.inc("reverted");
+                    return Ok(());
+                }
+            }
+            if queue.bytes + msg.len() > self.max_bytes {
+                self.disconnects.inc("send_queue_overflow");
+                let bytes = std::mem::take(&mut queue.bytes);
+                queue.items.clear(); // the peer is going to be disconnected
+                queue.closed = true;
+                cond.notify_one();
+                bail!("send queue overflow ({} bytes queued)", bytes);
+            }
+        }
+        queue.bytes += msg.len();
+        queue.items.push_back((topic, msg));
+        cond.notify_one();
+        Ok(())
+    }
+
+    /// Forget the notifications of an unsubscribed topic (so a later subscription is notified of
+    /// any status, even if it was sent to the previous subscription).
+    pub(crate) fn forget(&self, topic: Topic) {
+        let (queue, _cond) = &*self.shared;
+        let mut queue = queue.lock();
+        queue.queued.remove(&topic);
+        queue.sent.remove(&topic);
+        queue.queued.shrink();
+        queue.sent.shrink();
+        let mut bytes = queue.bytes;
+        queue.items.retain(|(t, msg)| {
+            let keep = *t != Some(topic);
+            if !keep {
+                bytes -= msg.len();
+            }
+            keep
+        });
+        queue.items.shrink();
+        queue.bytes = bytes;
+    }
+
+    /// Stop queueing messages - the sending thread exits after the queued ones are sent.
+    pub(crate) fn close(&self) {
+        let (queue, cond) = &*self.shared;
+        queue.lock().closed = true;
+        cond.notify_one();
+    }
+
+    // Returns `None` after the outbox is closed and drained
+    fn pop(&self) -> Option<Outgoing> {
+        let (queue, cond) = &*self.shared;
+        let mut queue = queue.lock();
+        loop {
+            if let Some((topic, msg)) = queue.items.pop_front() {
+                queue.items.shrink(); // e.g. after a burst of notifications
+                queue.bytes -= msg.len();
+                if let Some(topic @ Topic::ScriptHash(_)) = topic {
+                    queue.sent.insert(topic, msg.to_string());
+                }
+                return Some(msg);
+            }
+            if queue.closed {
+                return None;
+            }
+            cond.wait(&mut queue);
+        }
+    }
+
+    /// Send queued messages until the outbox is closed, disconnecting the peer if a single write
+    /// takes more than `timeout`. Messages are sent in chunks, at the rate allowed by `throttle`:
+    /// a large response is serialized chunk by chunk, as the peer reads it (and abandoned if the
+    /// peer disconnects).
+    pub(crate) fn send_loop(
+        &self,
+        peer_id: usize,
+        stream: TcpStream,
+        timeout: Duration,
+        throttle: Throttle,
+    ) -> Result<()> {
+        let result = self.send_all(peer_id, &stream, timeout, throttle);
+        if result.is_err() {
+            self.close();
+            if let Err(e) = stream.shutdown(Shutdown::Both) {
+                debug!(peer = peer_id; "{}: failed to shutdown TCP connection {}", peer_id, e)
+            }
+        }
+        result
+    }
+
+    fn send_all(
+        &self,
+        peer_id: usize,
+        mut stream: &TcpStream,
+        timeout: Duration,
+        mut throttle: Throttle,
+    ) -> Result<()> {
+        stream
+            .set_write_timeout(Some(timeout))
+            .context("failed to set write timeout")?;
+        let mut delay_logged = false;
+        while let Some(mut msg) = self.pop() {
+            debug!(peer = peer_id; "{}: send {}", peer_id, msg);
+            msg.push_str("\n");
+            let len = msg.len();
+            let mut delayed = Duration::ZERO;
+            msg.for_each_chunk(CHUNK_SIZE, |chunk| {
+                let delay = throttle.take(chunk.len(), len);
+                if !delay.is_zero() {
+                    std::thread::sleep(delay);
+                    delayed += delay;
+                }
+                if let Err(e) = stream.write_all(chunk) {
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) {
+                        self.disconnects.inc("send_timeout");
+                    }
+                    return Err(e).with_context(|| {
+                        format!("{}: failed to send {:?}", peer_id, msg.to_string())
+                    });
+                }
+                throttle.sent(chunk.len());
+                Ok(())
+            })?;
+            if delayed > SHAPING_LOG_DELAY && !delay_logged {
+                info!(
+                    peer = peer_id;
+                    "{}: upload rate limit delayed a {} bytes message by {:.1}s",
+                    peer_id, len, delayed.as_secs_f64()
+                );
+                delay_logged = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Outbox;
+    use crate::{
+        electrum::Topic,
+        metrics::Metrics,
+        shaper::{Shaper, Throttle, CHUNK_SIZE},
+        shrink::MIN_CAPACITY,
+        types::ScriptHash,
+    };
+    use bitcoin::hashes::Hash;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+
+    fn new_outbox(max_bytes: usize) -> Outbox {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        Outbox::new(
+            max_bytes,
+            metrics.counter("test", "test", "reason"),
+            metrics.counter("suppressed", "test", "reason"),
+        )
+    }
+
+    fn new_throttle(global_rate: Option<usize>, peer_rate: Option<usize>) -> Throttle {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let shaper = Shaper::new(global_rate, peer_rate, &metrics);
+        shaper.throttle(Ipv4Addr::LOCALHOST.into())
+    }
+
+    fn connect() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (server, client)
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let outbox = new_outbox(30);
+        let topic = Topic::ScriptHash(ScriptHash::all_zeros());
+        for i in 0..10 {
+            outbox
+                .push_notification(topic, format!("status{}", i))
+                .unwrap();
+        }
+        outbox
+            .push_notification(Topic::Headers, "tip".to_owned())
+            .unwrap();
+        outbox.push_response("response".to_owned()).unwrap();
+        outbox.close();
+        let mut sent = vec![];
+        while let Some(msg) = outbox.pop() {
+            sent.push(msg.to_string());
+        }
+        // superseded notifications are dropped when the queue is full
+        assert_eq!(sent, vec!["status8", "status9", "tip", "response"]);
+
+        // responses can't be dropped, so the peer is disconnected
+        let outbox = new_outbox(20);
+        outbox.push_response("0123456789".to_owned()).unwrap();
+        outbox.push_response("0123456789".to_owned()).unwrap();
+        assert!(outbox.push_response("0123456789".to_owned()).is_err());
+        assert!(outbox.push_response("".to_owned()).is_err());
+    }
+
+    #[test]
+    fn test_incremental() {
+        // mempool summaries can't be dropped, so a slow reader is disconnected
+        let outbox = new_outbox(30);
+        for i in 0..4 {
+            outbox
+                .push_notification(Topic::MempoolSummary, format!("change{}", i))
+                .unwrap();
+        }
+        assert!(outbox
+            .push_notification(Topic::MempoolSummary, "change4".to_owned())
+            .is_err());
+    }
+
+    fn drain(outbox: &Outbox) -> Vec<String> {
+        std::iter::from_fn(|| outbox.pop())
+            .map(|msg| msg.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_deduplicate() {
+        let outbox = new_outbox(1000);
+        let topic = Topic::ScriptHash(ScriptHash::all_zeros());
+        let other = Topic::ScriptHash(ScriptHash::hash(b"other"));
+        outbox.push_notification(topic, "A".to_owned()).unwrap();
+        outbox.push_notification(topic, "A".to_owned()).unwrap(); // unchanged
+        outbox.push_notification(other, "A".to_owned()).unwrap(); // another scripthash
+        assert_eq!(drain_queued(&outbox), vec!["A", "A"]);
+
+        outbox.push_notification(topic, "A".to_owned()).unwrap(); // already sent
+        outbox.push_notification(topic, "B".to_owned()).unwrap();
+        outbox.push_notification(topic, "A".to_owned()).unwrap(); // changed back
+        outbox
+            .push_notification(Topic::Headers, "tip".to_owned())
+            .unwrap();
+        outbox
+            .push_notification(Topic::Headers, "tip".to_owned())
+            .unwrap(); // headers are not de-duplicated
+        assert_eq!(drain_queued(&outbox), vec!["B", "A", "tip", "tip"]);
+
+        // a new subscription is notified, even if its status was sent before unsubscribing
+        outbox.push_notification(topic, "B".to_owned()).unwrap();
+        outbox.forget(topic); // the queued notification is dropped
+        outbox.push_notification(topic, "A".to_owned()).unwrap();
+        outbox.close();
+        assert_eq!(drain(&outbox), vec!["A"]);
+    }
+
+    #[test]
+    fn test_forget_shrinks() {
+        let outbox = new_outbox(1 << 20);
+        let capacity = |outbox: &Outbox| {
+            let queue = outbox.shared.0.lock();
+            queue.items.capacity() + queue.queued.capacity() + queue.sent.capacity()
+        };
+        let topics: Vec<Topic> = (0..10_000u32)
+            .map(|i| Topic::ScriptHash(ScriptHash::hash(&i.to_le_bytes())))
+            .collect();
+        for topic in &topics {
+            outbox.push_notification(*topic, "A".to_owned()).unwrap();
+        }
+        assert_eq!(drain_queued(&outbox).len(), topics.len());
+        assert!(capacity(&outbox) > 2 * topics.len());
+
+        // unsubscribing releases the memory of the forgotten topics
+        for topic in topics {
+            outbox.forget(topic);
+        }
+        assert!(capacity(&outbox) <= 3 * MIN_CAPACITY);
+    }
+
+    #[test]
+    fn test_coalesce_reverted() {
+        let outbox = new_outbox(10);
+        let topic = Topic::ScriptHash(ScriptHash::all_zeros());
+        outbox.push_notification(topic, "AAAA".to_owned()).unwrap();
+        assert_eq!(drain_queued(&outbox), vec!["AAAA"]);
+        outbox.push_notification(topic, "BBBB".to_owned()).unwrap();
+        outbox.push_notification(topic, "CCCC".to_owned()).unwrap();
+        // the queue is full: "BBBB" and "CCCC" are superseded, and the peer already has "AAAA"
+        outbox.push_notification(topic, "AAAA".to_owned()).unwrap();
+        outbox.push_response("response".to_owned()).unwrap();
+        outbox.close();
+        assert_eq!(drain(&outbox), vec!["response"]);
+    }
+
+    /// Pop the queued messages (without closing the outbox)
+    fn drain_queued(outbox: &Outbox) -> Vec<String> {
+        let mut messages = vec![];
+        while outbox.shared.0.lock().bytes > 0 {
+            messages.push(outbox.pop().unwrap().to_string());
+        }
+        messages
+    }
+
+    #[test]
+    fn test_send() {
+        let (server, client) = connect();
+        let outbox = new_outbox(1000);
+        outbox.push_response("first".to_owned()).unwrap();
+        outbox.push_response("second".to_owned()).unwrap();
+        outbox.close();
+        outbox
+            .send_loop(0, server, Duration::from_secs(10), new_throttle(None, None))
+            .unwrap();
+        let lines: Vec<String> = BufReader::new(client).lines().map(Result::unwrap).collect();
+        assert_eq!(lines, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn test_slow_reader() {
+        let (server, _client) = connect(); // the client doesn't read
+        let outbox = new_outbox(128 << 20);
+        // much more than the (possibly auto-tuned) socket buffers can hold, queued before sending
+        // (so the pushes don't race with the send timeout)
+        let msg = "x".repeat(1000);
+        for _ in 0..64_000 {
+            outbox.push_response(msg.clone()).unwrap();
+        }
+        outbox.close(); // so the sender doesn't wait for more messages
+        let sender = {
+            let outbox = outbox.clone();
+            let throttle = new_throttle(None, None);
+            std::thread::spawn(move || {
+                outbox.send_loop(0, server, Duration::from_millis(100), throttle)
+            })
+        };
+        // a blocked write times out
+        let err = sender.join().unwrap().unwrap_err();
+        assert!(err.to_string().starts_with("0: failed to send"), "{}", err);
+        #[cfg(feature = "metrics")]
+        assert_eq!(outbox.disconnects.get("send_timeout"), 1);
+    }
+
+    /// Send a message to a reading client, returning the elapsed time
+    fn timed_send(outbox: &Outbox, msg: String, throttle: Throttle) -> Duration {
+        let (server, mut client) = connect();
+        outbox.push_response(msg).unwrap();
+        outbox.close();
+        let start = Instant::now();
+        let sender = {
+            let outbox = outbox.clone();
+            std::thread::spawn(move || {
+                outbox.send_loop(0, server, Duration::from_secs(10), throttle)
+            })
+        };
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+        sender.join().unwrap().unwrap();
+        start.elapsed()
+    }
+
+    #[test]
+    fn test_shaping() {
+        // ~200kB (the first 16kB chunk is sent without waiting) at 100kB/s
+        let msg = "x".repeat(200_000);
+        let elapsed = timed_send(
+            &new_outbox(1 << 20),
+            msg.clone(),
+            new_throttle(None, Some(100_000)),
+        );
+        assert!(
+            elapsed > Duration::from_millis(1700) && elapsed < Duration::from_millis(2500),
+            "{:?}",
+            elapsed
+        );
+        // the global limit applies if it is lower
+        let elapsed = timed_send(
+            &new_outbox(1 << 20),
+            msg.clone(),
+            new_throttle(Some(200_000), Some(1_000_000)),
+        );
+        assert!(
+            elapsed > Duration::from_millis(800) && elapsed < Duration::from_millis(1500),
+            "{:?}",
+            elapsed
+        );
+        // small messages are not delayed, even if another connection has exhausted the budget
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let shaper = Shaper::new(Some(1000), None, &metrics);
+        let mut other = shaper.throttle(Ipv4Addr::LOCALHOST.into());
+        assert!(other.take(2 * CHUNK_SIZE, 2 * CHUNK_SIZE) > Duration::from_secs(10));
+        let throttle = shaper.throttle(Ipv4Addr::LOCALHOST.into());
+        let elapsed = timed_send(&new_outbox(1 << 20), "x".repeat(100), throttle);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// A large part of a message (e.g. a long history), serialized only while it is sent - so a
+/// message's memory usage doesn't depend on its length.
+pub(crate) trait Items: Send + Sync {
+    /// The number of items
+    fn count(&self) -> usize;
+
+    /// Append the serialized item at `index` to `buf` (preceded by a separator, if needed)
+    fn write(&self, index: usize, buf: &mut Vec<u8>);
+}
+
+impl<T: serde::Serialize + Send + Sync> Items for Vec<T> {
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn write(&self, index: usize, buf: &mut Vec<u8>) {
+        write_element(&self[index], index, buf)
+    }
+}
+
+impl<T: serde::Serialize + Send + Sync> Items for Arc<[T]> {
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn write(&self, index: usize, buf: &mut Vec<u8>) {
+        write_element(&self[index], index, buf)
+    }
+}
+
+/// Append a JSON array's element (without its brackets)
+pub(crate) fn write_element<T: serde::Serialize + ?Sized>(
+    value: &T,
+    index: usize,
+    buf: &mut Vec<u8>,
+) {
+    if index > 0 {
+        buf.push(b',');
+    }
+    serde_json::to_writer(buf, value).expect("failed to serialize item");
+}
+
+#[derive(Clone)]
+enum Part {
+    Text(String),
+    Items {
+        items: Arc<dyn Items>,
+        len: usize, // estimated from the first item
+    },
+}
+
+/// A message to be sent to a peer (without its newline)
+#[derive(Clone, Default)]
+pub(crate) struct Outgoing {
+    parts: Vec<Part>,
+}
+
+impl Outgoing {
+    pub(crate) fn push_str(&mut self, text: &str) {
+        match self.parts.last_mut() {
+            Some(Part::Text(last)) => last.push_str(text),
+            _ => self.parts.push(Part::Text(text.to_owned())),
+        }
+    }
+
+    pub(crate) fn push_items(&mut self, items: Arc<dyn Items>) {
+        let mut first = vec![];
+        if items.count() > 0 {
+            items.write(0, &mut first);
+        }
+        let len = first.len() * items.count();
+        self.parts.push(Part::Items { items, len });
+    }
+
+    pub(crate) fn append(&mut self, other: Outgoing) {
+        for part in other.parts {
+            match part {
+                Part::Text(text) => self.push_str(&text),
+                part => self.parts.push(part),
+            }
+        }
+    }
+
+    /// The message's length (estimated, if it has serialized items)
+    pub(crate) fn len(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.len(),
+                Part::Items { len, .. } => *len,
+            })
+            .sum()
+    }
+
+    /// The message's text, if it has no serialized items (e.g. a notification)
+    pub(crate) fn as_text(&self) -> Option<&str> {
+        match &self.parts[..] {
+            [] => Some(""),
+            [Part::Text(text)] => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Serialize the message into chunks of (at least) `chunk_size` bytes, except the last one.
+    /// The items are serialized only when `func` returns, so they are not serialized after it
+    /// fails (e.g. if the peer is disconnected).
+    pub(crate) fn for_each_chunk<E>(
+        &self,
+        chunk_size: usize,
+        mut func: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut buf = Vec::with_capacity(std::cmp::min(chunk_size, self.len()) + 1);
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => buf.extend_from_slice(text.as_bytes()),
+                Part::Items { items, .. } => {
+                    for index in 0..items.count() {
+                        items.write(index, &mut buf);
+                        if buf.len() >= chunk_size {
+                            func(&buf)?;
+                            buf.clear();
+                        }
+                    }
+                }
+            }
+            // a large text part is sent in chunks too
+            for chunk in buf.chunks(chunk_size).filter(|c| c.len() == chunk_size) {
+                func(chunk)?;
+            }
+            let sent = buf.len() - buf.len() % chunk_size;
+            buf.drain(..sent);
+        }
+        if !buf.is_empty() {
+            func(&buf)?;
+        }
+        Ok(())
+    }
+
+    /// Serialize the whole message (e.g. for an HTTP response)
+    pub(crate) fn into_string(mut self) -> String {
+        if let [Part::Text(text)] = &mut self.parts[..] {
+            return std::mem::take(text);
+        }
+        let mut buf = Vec::with_capacity(self.len());
+        self.for_each_chunk(1 << 16, |chunk| {
+            buf.extend_from_slice(chunk);
+            Ok::<(), ()>(())
+        })
+        .expect("in-memory writers don't error");
+        String::from_utf8(buf).expect("invalid JSON")
+    }
+}
+
+impl From<String> for Outgoing {
+    fn from(text: String) -> Self {
+        Self {
+            parts: vec![Part::Text(text)],
+        }
+    }
+}
+
+/// The serialized items are not displayed (e.g. when a message is logged)
+impl fmt::Display for Outgoing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => f.write_str(text)?,
+                Part::Items { items, .. } => write!(f, "<{} items>", items.count())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Outgoing;
+    use std::sync::Arc;
+
+    fn split(msg: &Outgoing, chunk_size: usize) -> Vec<String> {
+        let mut chunks = vec![];
+        msg.for_each_chunk(chunk_size, |chunk| {
+            chunks.push(String::from_utf8(chunk.to_vec()).unwrap());
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+        chunks
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut msg = Outgoing::default();
+        msg.push_str("{\"result\":[");
+        msg.push_items(Arc::new((0..1000).collect::<Vec<u32>>()));
+        msg.push_str("]}");
+        let text = format!(
+            "{{\"result\":{}}}",
+            serde_json::to_string(&(0..1000).collect::<Vec<u32>>()).unwrap()
+        );
+        assert_eq!(msg.clone().into_string(), text);
+        assert_eq!(msg.to_string(), "{\"result\":[<1000 items>]}");
+        assert_eq!(msg.as_text(), None);
+        assert_eq!(msg.len(), 11 + 1000 + 2); // estimated from the first item
+
+        let chunks = split(&msg, 100);
+        assert_eq!(chunks.concat(), text);
+        let (last, full) = chunks.split_last().unwrap();
+        assert!(full.iter().all(|chunk| (100..110).contains(&chunk.len())));
+        assert!(last.len() < 100);
+
+        // a large text is sent in chunks too
+        let msg = Outgoing::from("x".repeat(250));
+        assert_eq!(msg.as_text(), Some(&"x".repeat(250)[..]));
+        let lens: Vec<usize> = split(&msg, 100).iter().map(String::len).collect();
+        assert_eq!(lens, vec![100, 100, 50]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut msg = Outgoing::default();
+        msg.push_items(Arc::new(vec![0u8; 1000]));
+        let mut calls = 0;
+        let result = msg.for_each_chunk(10, |_chunk| {
+            calls += 1;
+            match calls {
+                3 => Err("disconnected"),
+                _ => Ok(()),
+            }
+        });
+        // the rest of the items are not serialized
+        assert_eq!(result, Err("disconnected"));
+        assert_eq!(calls, 3);
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::{ensure, Result};
+use bitcoin::{BlockHash, OutPoint, Txid};
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+
+use crate::shrink::Shrink;
+
+/// At most this many outpoints can be subscribed per connection
+pub(crate) const MAX_OUTPOINT_SUBSCRIPTIONS: usize = 1000;
+
+/// The transaction spending an outpoint, found by the tracker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Spender {
+    pub(crate) txid: Txid,
+    pub(crate) block: Option<(usize, BlockHash)>, // `None` for mempool transactions
+}
+
+impl Spender {
+    /// `blockchain.outpoint.subscribe` status: `{spender_txid, height}` (height is 0 for mempool
+    /// transactions), or `null` if the outpoint is unspent.
+    pub(crate) fn to_value(spender: Option<&Spender>) -> Value {
+        match spender {
+            Some(spender) => {
+                let height = spender.block.map_or(0, |(height, _blockhash)| height);
+                json!({"spender_txid": spender.txid, "height": height})
+            }
+            None => Value::Null,
+        }
+    }
+
+    fn blockhash(&self) -> Option<BlockHash> {
+        self.block.map(|(_height, blockhash)| blockhash)
+    }
+}
+
+/// Outpoints subscribed by a connection (using `blockchain.outpoint.subscribe`), with their last
+/// notified spenders.
+#[derive(Default)]
+pub(crate) struct OutpointSubscriptions {
+    spenders: HashMap<OutPoint, Option<Spender>>,
+}
+
+impl OutpointSubscriptions {
+    pub(crate) fn subscribe(&mut self, outpoint: OutPoint, spender: Option<Spender>) -> Result<()> {
+        ensure!(
+            self.spenders.contains_key(&outpoint)
+                || self.spenders.len() < MAX_OUTPOINT_SUBSCRIPTIONS,
+            "too many outpoint subscriptions (at most {} per connection)",
+            MAX_OUTPOINT_SUBSCRIPTIONS
+        );
+        self.spenders.insert(outpoint, spender);
+        Ok(())
+    }
+
+    pub(crate) fn unsubscribe(&mut self, outpoint: &OutPoint) -> bool {
+        let removed = self.spenders.remove(outpoint).is_some();
+        self.spenders.shrink();
+        removed
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.spenders.is_empty()
+    }
+
+    /// Resolve again the outpoints whose spending may have changed (`is_touched`), or whose
+    /// spender's block was disconnected (`is_stale`), returning the changed ones with their new
+    /// `blockchain.outpoint.subscribe` status.
+    pub(crate) fn update(
+        &mut self,
+        is_touched: impl Fn(&OutPoint) -> bool,
+        is_stale: impl Fn(&BlockHash) -> bool,
+        mut resolve: impl FnMut(OutPoint) -> Result<Option<Spender>>,
+    ) -> Result<Vec<(OutPoint, Value)>> {
+        let mut changed = vec![];
+        for (outpoint, spender) in self.spenders.iter_mut() {
+            let stale = spender
+                .and_then(|spender| spender.blockhash())
+                .is_some_and(|blockhash| is_stale(&blockhash));
+            if !stale && !is_touched(outpoint) {
+                continue;
+            }
+            let new_spender = resolve(*outpoint)?;
+            if new_spender != *spender {
+                *spender = new_spender;
+                changed.push((*outpoint, Spender::to_value(spender.as_ref())));
+            }
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutpointSubscriptions, Spender, MAX_OUTPOINT_SUBSCRIPTIONS};
+    use bitcoin::{hashes::Hash, BlockHash, OutPoint, Txid};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn txid(i: usize) -> Txid {
+        Txid::hash(&i.to_le_bytes())
+    }
+
+    fn outpoint(i: usize) -> OutPoint {
+        OutPoint::new(txid(i), 0)
+    }
+
+    #[test]
+    fn test_outpoint_subscriptions() {
+        let mut subs = OutpointSubscriptions::default();
+        let blockhash = BlockHash::hash(b"block");
+        let mut spenders: HashMap<OutPoint, Option<Spender>> = HashMap::new();
+        let mempool = Spender {
+            txid: txid(100),
+            block: None,
+        };
+        let confirmed = Spender {
+            txid: txid(100),
+            block: Some((102, blockhash)),
+        };
+        let update = |subs: &mut OutpointSubscriptions,
+                      spenders: &HashMap<OutPoint, Option<Spender>>,
+                      touched: &[OutPoint],
+                      stale: &[BlockHash]| {
+            let mut resolved = 0;
+            let changed = subs
+                .update(
+                    |op| touched.contains(op),
+                    |blockhash| stale.contains(blockhash),
+                    |op| {
+                        resolved += 1;
+                        Ok(spenders[&op])
+                    },
+                )
+                .unwrap();
+            (changed, resolved)
+        };
+        for i in 0..2 {
+            subs.subscribe(outpoint(i), None).unwrap();
+            spenders.insert(outpoint(i), None);
+        }
+        let touched = [outpoint(0)];
+        assert_eq!(update(&mut subs, &spenders, &touched, &[]), (vec![], 1));
+
+        // only touched outpoints are resolved again
+        spenders.insert(outpoint(0), Some(mempool));
+        spenders.insert(outpoint(1), Some(mempool));
+        let expected = json!({"spender_txid": txid(100), "height": 0});
+        assert_eq!(
+            update(&mut subs, &spenders, &touched, &[]),
+            (vec![(outpoint(0), expected)], 1)
+        );
+
+        spenders.insert(outpoint(0), Some(confirmed));
+        let expected = json!({"spender_txid": txid(100), "height": 102});
+        assert_eq!(
+            update(&mut subs, &spenders, &touched, &[]),
+            (vec![(outpoint(0), expected)], 1)
+        );
+
+        // the spender's block is disconnected (and the spender is evicted)
+        assert!(subs.unsubscribe(&outpoint(1)));
+        assert!(!subs.unsubscribe(&outpoint(1)));
+        spenders.insert(outpoint(0), None);
+        assert_eq!(update(&mut subs, &spenders, &[], &[]), (vec![], 0));
+        assert_eq!(
+            update(&mut subs, &spenders, &[], &[blockhash]),
+            (vec![(outpoint(0), json!(null))], 1)
+        );
+
+        // the number of subscriptions is bounded (re-subscribing is allowed)
+        for i in 0..MAX_OUTPOINT_SUBSCRIPTIONS {
+            subs.subscribe(outpoint(i), None).unwrap();
+        }
+        let err = subs
+            .subscribe(outpoint(MAX_OUTPOINT_SUBSCRIPTIONS), None)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("too many outpoint subscriptions"));
+        subs.subscribe(outpoint(0), None).unwrap();
+
+        let err = subs
+            .update(|_| true, |_| false, |_| anyhow::bail!("DB failure"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "DB failure");
+    }
+}
@@ -18,17 +18,20 @@ use bitcoin::{
     Block, BlockHash,
 };
 use bitcoin_slices::{bsl, Parse};
-use crossbeam_channel::{bounded, select, Receiver, Sender};
+use crossbeam_channel::{bounded, select, Receiver, RecvTimeoutError, Sender, TryRecvError};
 
 use std::io::Write;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::types::SerBlock;
 use crate::{
     chain::{Chain, NewHeader},
     config::ELECTRS_VERSION,
+    daemon::BlockNotAvailable,
     metrics::{default_duration_buckets, default_size_buckets, Histogram, Metrics},
+    proxy::DaemonAddr,
+    thread::Supervisor,
 };
 
 enum Request {
@@ -54,11 +57,28 @@ impl Request {
     }
 }
 
+/// Bitcoin Core doesn't reply to `getdata` for the blocks it doesn't have (e.g. pruned ones), so
+/// a requested block is abandoned if it isn't received within this duration.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A requested block was not received within `BLOCK_TIMEOUT` (so it may be fetched via RPC).
+#[derive(Debug)]
+pub(crate) struct BlockTimeout(pub(crate) BlockHash);
+
+impl std::fmt::Display for BlockTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "block {} was not received via p2p", self.0)
+    }
+}
+
+impl std::error::Error for BlockTimeout {}
+
 pub(crate) struct Connection {
     req_send: Sender<Request>,
-    blocks_recv: Receiver<SerBlock>,
+    blocks_recv: Receiver<Result<SerBlock, BlockHash>>, // or the hash of a `notfound` block
     headers_recv: Receiver<Vec<BlockHeader>>,
     new_block_recv: Receiver<()>,
+    abandoned: usize, // blocks requested before a timeout, which may still be received
 
     blocks_duration: Histogram,
 }
@@ -68,10 +88,13 @@ impl Connection {
     /// https://en.bitcoin.it/wiki/Protocol_documentation#getheaders
     /// Defined as `&mut self` to prevent concurrent invocations (https://github.com/romanz/electrs/pull/526#issuecomment-934685515).
     pub(crate) fn get_new_headers(&mut self, chain: &Chain) -> Result<Vec<NewHeader>> {
-        self.req_send.send(Request::get_new_headers(chain))?;
+        self.req_send
+            .send(Request::get_new_headers(chain))
+            .context(Disconnected)?;
         let headers = self
             .headers_recv
             .recv()
+            .context(Disconnected)
             .context("failed to get new headers")?;
 
         debug!("got {} new headers", headers.len());
@@ -90,7 +113,9 @@ impl Connection {
             .collect())
     }
 
-    /// Request and process the specified blocks (in the specified order).
+    /// Request and process the specified blocks (in the specified order), failing with
+    /// [`BlockNotAvailable`] if the peer replies `notfound` for one of them (or with
+    /// [`BlockTimeout`] if it doesn't reply).
     /// See https://en.bitcoin.it/wiki/Protocol_documentation#getblocks for details.
     /// Defined as `&mut self` to prevent concurrent invocations (https://github.com/romanz/electrs/pull/526#issuecomment-934685515).
     pub(crate) fn for_blocks<B, F>(&mut self, blockhashes: B, mut func: F) -> Result<()>
@@ -103,30 +128,40 @@ impl Connection {
             if blockhashes.is_empty() {
                 return Ok(());
             }
-            self.blocks_duration.observe_duration("request", || {
-                debug!("loading {} blocks", blockhashes.len());
-                self.req_send.send(Request::get_blocks(&blockhashes))
-            })?;
-
-            for hash in blockhashes {
+            self.blocks_duration
+                .observe_duration("request", || {
+                    debug!("loading {} blocks", blockhashes.len());
+                    self.req_send.send(Request::get_blocks(&blockhashes))
+                })
+                .context(Disconnected)?;
+
+            let mut missing = None;
+            for (i, &hash) in blockhashes.iter().enumerate() {
                 let block = self.blocks_duration.observe_duration("response", || {
-                    let block = self
-                        .blocks_recv
-                        .recv()
-                        .with_context(|| format!("failed to get block {}", hash))?;
-                    let header = bsl::BlockHeader::parse(&block[..])
-                        .expect("core returned invalid blockheader")
-                        .parsed_owned();
-                    ensure!(
-                        &header.block_hash_sha2()[..] == hash.as_byte_array(),
-                        "got unexpected block"
-                    );
-                    Ok(block)
-                })?;
-                self.blocks_duration
-                    .observe_duration("process", || func(hash, block));
+                    recv_block(&self.blocks_recv, &mut self.abandoned, hash)
+                });
+                let block = match block {
+                    Err(e) if e.downcast_ref::<BlockTimeout>().is_some() => {
+                        self.abandoned += blockhashes.len() - i; // may still be received later
+                        return Err(e);
+                    }
+                    result => result?,
+                };
+                match block {
+                    // drain the responses, so they are not mixed with the next request's
+                    Ok(_) if missing.is_some() => (),
+                    Ok(block) => self
+                        .blocks_duration
+                        .observe_duration("process", || func(hash, block)),
+                    Err(notfound) => {
+                        missing.get_or_insert(notfound);
+                    }
+                }
+            }
+            match missing {
+                Some(blockhash) => Err(BlockNotAvailable(blockhash).into()),
+                None => Ok(()),
             }
-            Ok(())
         })
     }
 
@@ -135,9 +170,101 @@ impl Connection {
         self.new_block_recv.clone()
     }
 
-    pub(crate) fn connect(address: SocketAddr, metrics: &Metrics, magic: Magic) -> Result<Self> {
-        let recv_conn = TcpStream::connect(address)
-            .with_context(|| format!("p2p failed to connect: {:?}", address))?;
+    /// Returns `false` if the connection was lost (e.g. since bitcoind was restarted).
+    pub(crate) fn is_connected(&self) -> bool {
+        // the notifications are sent using `try_send()`, so they are never pending
+        !matches!(
+            self.new_block_recv.try_recv(),
+            Err(TryRecvError::Disconnected)
+        )
+    }
+}
+
+/// The p2p connection to bitcoind is not available (e.g. it was lost when bitcoind was restarted),
+/// so it should be reconnected.
+#[derive(Debug)]
+pub(crate) struct Disconnected;
+
+impl std::fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "not connected to bitcoind via p2p")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// Opens p2p connections to bitcoind (the first one on startup, and the next ones when the
+/// previous connection is lost), sharing their metrics.
+pub(crate) struct Connector {
+    address: DaemonAddr,
+    magic: Magic,
+    supervisor: Supervisor,
+
+    send_duration: Histogram,
+    recv_duration: Histogram,
+    parse_duration: Histogram,
+    recv_size: Histogram,
+    blocks_duration: Histogram,
+}
+
+impl Connector {
+    pub(crate) fn new(
+        address: DaemonAddr,
+        metrics: &Metrics,
+        magic: Magic,
+        supervisor: &Supervisor,
+    ) -> Self {
+        Self {
+            address,
+            magic,
+            supervisor: supervisor.clone(),
+            send_duration: metrics.histogram_vec(
+                "p2p_send_duration",
+                "Time spent sending p2p messages (in seconds)",
+                "step",
+                default_duration_buckets(),
+            ),
+            recv_duration: metrics.histogram_vec(
+                "p2p_recv_duration",
+                "Time spent receiving p2p messages (in seconds)",
+                "step",
+                default_duration_buckets(),
+            ),
+            parse_duration: metrics.histogram_vec(
+                "p2p_parse_duration",
+                "Time spent parsing p2p messages (in seconds)",
+                "step",
+                default_duration_buckets(),
+            ),
+            recv_size: metrics.histogram_vec(
+                "p2p_recv_size",
+                "Size of p2p messages read (in bytes)",
+                "message",
+                default_size_buckets(),
+            ),
+            blocks_duration: metrics.histogram_vec(
+                "p2p_blocks_duration",
+                "Time spent getting blocks via p2p protocol (in seconds)",
+                "step",
+                default_duration_buckets(),
+            ),
+        }
+    }
+
+    pub(crate) fn address(&self) -> &DaemonAddr {
+        &self.address
+    }
+
+    pub(crate) fn connect(&self) -> Result<Connection> {
+        let address = &self.address;
+        let magic = self.magic;
+        let recv_conn = address
+            .connect(None)
+            .with_context(|| format!("p2p failed to connect: {}", address))
+            .context(Disconnected)?;
+        if let (DaemonAddr::Host { .. }, Ok(addr)) = (address, recv_conn.peer_addr()) {
+            info!("p2p connected to {} using {}", address, addr);
+        }
         let mut send_conn = recv_conn
             .try_clone()
             .context("failed to clone connection")?;
@@ -145,39 +272,14 @@ impl Connection {
         let (tx_send, tx_recv) = bounded::<NetworkMessage>(1);
         let (rx_send, rx_recv) = bounded::<RawNetworkMessage>(1);
 
-        let send_duration = metrics.histogram_vec(
-            "p2p_send_duration",
-            "Time spent sending p2p messages (in seconds)",
-            "step",
-            default_duration_buckets(),
-        );
-        let recv_duration = metrics.histogram_vec(
-            "p2p_recv_duration",
-            "Time spent receiving p2p messages (in seconds)",
-            "step",
-            default_duration_buckets(),
-        );
-        let parse_duration = metrics.histogram_vec(
-            "p2p_parse_duration",
-            "Time spent parsing p2p messages (in seconds)",
-            "step",
-            default_duration_buckets(),
-        );
-        let recv_size = metrics.histogram_vec(
-            "p2p_recv_size",
-            "Size of p2p messages read (in bytes)",
-            "message",
-            default_size_buckets(),
-        );
-        let blocks_duration = metrics.histogram_vec(
-            "p2p_blocks_duration",
-            "Time spent getting blocks via p2p protocol (in seconds)",
-            "step",
-            default_duration_buckets(),
-        );
+        let send_duration = self.send_duration.clone();
+        let recv_duration = self.recv_duration.clone();
+        let parse_duration = self.parse_duration.clone();
+        let recv_size = self.recv_size.clone();
+        let blocks_duration = self.blocks_duration.clone();
 
         let mut buffer = vec![];
-        crate::thread::spawn("p2p_send", move || loop {
+        self.spawn("p2p_send", move || loop {
             use std::net::Shutdown;
             let msg = match send_duration.observe_duration("wait", || tx_recv.recv()) {
                 Ok(msg) => msg,
@@ -201,11 +303,12 @@ impl Connection {
                 send_conn
                     .write_all(buffer.as_slice())
                     .context("p2p failed to send")
+                    .context(Disconnected)
             })?;
         });
 
         let mut stream_reader = std::io::BufReader::new(recv_conn);
-        crate::thread::spawn("p2p_recv", move || loop {
+        self.spawn("p2p_recv", move || loop {
             let start = Instant::now();
             let raw_msg = RawNetworkMessage::consensus_decode(&mut stream_reader);
             {
@@ -231,6 +334,10 @@ impl Connection {
                     debug!("closing p2p_recv thread: connection closed");
                     return Ok(());
                 }
+                Err(encode::Error::Io(e)) => {
+                    let err = anyhow!("failed to recv a message from peer: {}", e);
+                    return Err(err.context(Disconnected));
+                }
                 Err(e) => bail!("failed to recv a message from peer: {}", e),
             };
 
@@ -238,21 +345,21 @@ impl Connection {
         });
 
         let (req_send, req_recv) = bounded::<Request>(1);
-        let (blocks_send, blocks_recv) = bounded::<SerBlock>(10);
+        let (blocks_send, blocks_recv) = bounded::<Result<SerBlock, BlockHash>>(10);
         let (headers_send, headers_recv) = bounded::<Vec<BlockHeader>>(1);
         let (new_block_send, new_block_recv) = bounded::<()>(0);
         let (init_send, init_recv) = bounded::<()>(0);
 
         tx_send.send(build_version_message())?;
 
-        crate::thread::spawn("p2p_loop", move || loop {
+        self.spawn("p2p_loop", move || loop {
             select! {
                 recv(rx_recv) -> result => {
                     let raw_msg = match result {
                         Ok(raw_msg) => raw_msg,
                         Err(_) => {  // p2p_recv is closed, so rx_send is disconnected
                             debug!("closing p2p_loop thread: peer has disconnected");
-                            return Ok(()); // new_block_send is dropped, causing the server to exit
+                            return Ok(()); // new_block_send is dropped, so the sync thread reconnects
                         }
                     };
 
@@ -266,7 +373,7 @@ impl Connection {
                     match msg {
                         ParsedNetworkMessage::Version(version) => {
                             debug!("peer version: {:?}", version);
-                            tx_send.send(NetworkMessage::Verack)?;
+                            tx_send.send(NetworkMessage::Verack).context(Disconnected)?;
                         }
                         ParsedNetworkMessage::Inv(inventory) => {
                             debug!("peer inventory: {:?}", inventory);
@@ -276,12 +383,20 @@ impl Connection {
 
                         },
                         ParsedNetworkMessage::Ping(nonce) => {
-                            tx_send.send(NetworkMessage::Pong(nonce))?; // connection keep-alive
+                            tx_send.send(NetworkMessage::Pong(nonce)).context(Disconnected)?; // connection keep-alive
                         }
                         ParsedNetworkMessage::Verack => {
                             init_send.send(())?; // peer acknowledged our version
                         }
-                        ParsedNetworkMessage::Block(block) => blocks_send.send(block)?,
+                        ParsedNetworkMessage::Block(block) => blocks_send.send(Ok(block))?,
+                        ParsedNetworkMessage::NotFound(inventory) => {
+                            debug!("peer notfound: {:?}", inventory);
+                            for inv in inventory {
+                                if let Inventory::Block(hash) | Inventory::WitnessBlock(hash) = inv {
+                                    blocks_send.send(Err(hash))?; // requested by `for_blocks()`
+                                }
+                            }
+                        }
                         ParsedNetworkMessage::Headers(headers) => headers_send.send(headers)?,
                         ParsedNetworkMessage::Ignored => (),
                     }
@@ -298,21 +413,69 @@ impl Connection {
                         Request::GetNewHeaders(msg) => NetworkMessage::GetHeaders(msg),
                         Request::GetBlocks(inv) => NetworkMessage::GetData(inv),
                     };
-                    tx_send.send(msg)?;
+                    tx_send.send(msg).context(Disconnected)?;
                 }
             }
         });
 
-        init_recv.recv()?; // wait until `verack` is received
+        init_recv.recv().context(Disconnected)?; // wait until `verack` is received
 
         Ok(Connection {
             req_send,
             blocks_recv,
             headers_recv,
             new_block_recv,
+            abandoned: 0,
             blocks_duration,
         })
     }
+
+    /// Like `Supervisor::spawn()`, but losing the connection is not a failure (it is reconnected
+    /// by the next sync, see `Daemon::reconnect_p2p()`).
+    fn spawn<F>(&self, name: &'static str, f: F)
+    where
+        F: 'static + Send + FnOnce() -> Result<()>,
+    {
+        self.supervisor.spawn(name, move || match f() {
+            Err(e) if e.chain().any(|cause| cause.is::<Disconnected>()) => {
+                warn!("closing {} thread: {:#}", name, e);
+                Ok(())
+            }
+            result => result,
+        });
+    }
+}
+
+/// Receive the requested block (or the hash of a `notfound` reply), skipping the late
+/// replies to the requests abandoned after a timeout.
+fn recv_block(
+    blocks_recv: &Receiver<Result<SerBlock, BlockHash>>,
+    abandoned: &mut usize,
+    hash: BlockHash,
+) -> Result<Result<SerBlock, BlockHash>> {
+    loop {
+        let reply = match blocks_recv.recv_timeout(BLOCK_TIMEOUT) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Timeout) => return Err(BlockTimeout(hash).into()),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("failed to get block {}", hash).context(Disconnected));
+            }
+        };
+        let received = match &reply {
+            Ok(block) => {
+                let header = bsl::BlockHeader::parse(&block[..])
+                    .map_err(|e| anyhow!("core returned invalid blockheader: {:?}", e))?
+                    .parsed_owned();
+                BlockHash::from_slice(&header.block_hash_sha2()[..])?
+            }
+            Err(notfound) => *notfound,
+        };
+        if received == hash {
+            return Ok(reply);
+        }
+        ensure!(*abandoned > 0, "got unexpected block");
+        *abandoned -= 1;
+    }
 }
 
 fn build_version_message() -> NetworkMessage {
@@ -350,6 +513,7 @@ impl RawNetworkMessage {
             "version" => ParsedNetworkMessage::Version(Decodable::consensus_decode(&mut raw)?),
             "verack" => ParsedNetworkMessage::Verack,
             "inv" => ParsedNetworkMessage::Inv(Decodable::consensus_decode(&mut raw)?),
+            "notfound" => ParsedNetworkMessage::NotFound(Decodable::consensus_decode(&mut raw)?),
             "block" => ParsedNetworkMessage::Block(self.raw),
             "headers" => {
                 let len = VarInt::consensus_decode(&mut raw)?.0;
@@ -378,6 +542,7 @@ enum ParsedNetworkMessage {
     Version(message_network::VersionMessage),
     Verack,
     Inv(Vec<Inventory>),
+    NotFound(Vec<Inventory>),
     Ping(u64),
     Headers(Vec<BlockHeader>),
     Block(SerBlock),
@@ -0,0 +1,763 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    block::Header as BlockHeader,
+    consensus::encode::{self, deserialize_partial, serialize, Decodable, VarInt},
+    hashes::{sha256, Hash, HashEngine},
+    hex::DisplayHex,
+    p2p::Magic,
+    Amount, BlockHash, Transaction, Txid,
+};
+use bitcoincore_rpc::json;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::Value;
+
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::{
+    chain::{Chain, NewHeader},
+    daemon::Node,
+    db::DBStore,
+    index::Index,
+    mempool::MempoolSummary,
+    signals::ExitFlag,
+    tracker::Tracker,
+    types::SerBlock,
+};
+
+/// The replay log's file header: `MAGIC`, `VERSION` and the network's magic bytes
+const MAGIC: &[u8; 4] = b"ERPL";
+const VERSION: u8 = 1;
+const HEADER_LEN: u64 = 9;
+
+const START_TAG: u8 = 1;
+const BLOCKS_TAG: u8 = 2;
+const MEMPOOL_TAG: u8 = 3;
+
+/// A chain event processed by the tracker (encoded as a tag byte, followed by its fields as
+/// compact-size integers and 32-byte hashes)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Record {
+    /// The indexed chain when the log file was started (each rotated file starts with one)
+    Start { height: usize, tip: BlockHash },
+    /// Blocks disconnected from the tip (starting with it), and the ones connected afterwards
+    Blocks {
+        disconnected: Vec<BlockHash>,
+        connected: Vec<BlockHash>,
+    },
+    /// A mempool sync which changed the tracked transactions
+    Mempool {
+        generation: u64,
+        count: usize,
+        vsize: u64,
+    },
+}
+
+impl Record {
+    fn start(chain: &Chain) -> Self {
+        Record::Start {
+            height: chain.height(),
+            tip: chain.tip(),
+        }
+    }
+
+    /// The blocks since `prev` (`None` if the chain is unchanged)
+    fn blocks(prev: &Chain, chain: &Chain) -> Option<Self> {
+        let fork = prev.fork_height(chain);
+        let hash =
+            |chain: &Chain, height| chain.get_block_hash(height).expect("missing block hash");
+        let disconnected: Vec<BlockHash> = (fork + 1..=prev.height())
+            .rev()
+            .map(|height| hash(prev, height))
+            .collect();
+        let connected: Vec<BlockHash> = (fork + 1..=chain.height())
+            .map(|height| hash(chain, height))
+            .collect();
+        if disconnected.is_empty() && connected.is_empty() {
+            return None;
+        }
+        Some(Record::Blocks {
+            disconnected,
+            connected,
+        })
+    }
+
+    fn mempool(summary: &MempoolSummary) -> Self {
+        Record::Mempool {
+            generation: summary.mempool_generation,
+            count: summary.count,
+            vsize: summary.vsize,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let int = |buf: &mut Vec<u8>, n: u64| buf.extend(serialize(&VarInt(n)));
+        let hashes = |buf: &mut Vec<u8>, hashes: &[BlockHash]| {
+            buf.extend(serialize(&VarInt(hashes.len() as u64)));
+            hashes
+                .iter()
+                .for_each(|hash| buf.extend(hash.as_byte_array()));
+        };
+        match self {
+            Record::Start { height, tip } => {
+                buf.push(START_TAG);
+                int(buf, *height as u64);
+                buf.extend(tip.as_byte_array());
+            }
+            Record::Blocks {
+                disconnected,
+                connected,
+            } => {
+                buf.push(BLOCKS_TAG);
+                hashes(buf, disconnected);
+                hashes(buf, connected);
+            }
+            Record::Mempool {
+                generation,
+                count,
+                vsize,
+            } => {
+                buf.push(MEMPOOL_TAG);
+                int(buf, *generation);
+                int(buf, *count as u64);
+                int(buf, *vsize);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, encode::Error> {
+        let record = match reader.read::<u8>()? {
+            START_TAG => Record::Start {
+                height: reader.read_int()? as usize,
+                tip: reader.read()?,
+            },
+            BLOCKS_TAG => Record::Blocks {
+                disconnected: reader.read_hashes()?,
+                connected: reader.read_hashes()?,
+            },
+            MEMPOOL_TAG => Record::Mempool {
+                generation: reader.read_int()?,
+                count: reader.read_int()? as usize,
+                vsize: reader.read_int()?,
+            },
+            _ => return Err(encode::Error::ParseFailed("unknown record tag")),
+        };
+        Ok(record)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn read<T: Decodable>(&mut self) -> Result<T, encode::Error> {
+        let (value, len) = deserialize_partial(&self.data[self.pos..])?;
+        self.pos += len;
+        Ok(value)
+    }
+
+    fn read_int(&mut self) -> Result<u64, encode::Error> {
+        Ok(self.read::<VarInt>()?.0)
+    }
+
+    fn read_hashes(&mut self) -> Result<Vec<BlockHash>, encode::Error> {
+        let count = self.read_int()?;
+        (0..count).map(|_| self.read()).collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+fn header(magic: Magic) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(VERSION);
+    header.extend(magic.to_bytes());
+    header
+}
+
+/// Read the records of a replay log file. A truncated last record (e.g. if electrs was killed
+/// while writing it) is skipped.
+pub(crate) fn read(path: &Path, magic: Magic) -> Result<Vec<Record>> {
+    let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let (file_header, data) = data.split_at(data.len().min(HEADER_LEN as usize));
+    ensure!(
+        file_header.starts_with(MAGIC) && file_header.len() == HEADER_LEN as usize,
+        "{} is not a replay log",
+        path.display()
+    );
+    ensure!(
+        file_header[4] == VERSION,
+        "unsupported replay log version: {}",
+        file_header[4]
+    );
+    ensure!(
+        file_header == header(magic),
+        "{} was recorded on another network (magic {})",
+        path.display(),
+        file_header[5..].to_lower_hex_string()
+    );
+    let mut reader = Reader { data, pos: 0 };
+    let mut records = vec![];
+    while !reader.is_empty() {
+        let offset = reader.pos;
+        match Record::decode(&mut reader) {
+            Ok(record) => records.push(record),
+            Err(encode::Error::Io(_)) => {
+                warn!(
+                    "skipping a truncated record at offset {} of {}",
+                    HEADER_LEN as usize + offset,
+                    path.display()
+                );
+                break;
+            }
+            Err(e) => bail!(
+                "invalid record at offset {} of {}: {}",
+                HEADER_LEN as usize + offset,
+                path.display(),
+                e
+            ),
+        }
+    }
+    Ok(records)
+}
+
+/// The previously recorded state (used only by the sync thread)
+struct LogState {
+    file: File,
+    written: u64, // into the current file
+    chain: Option<Arc<Chain>>,
+    mempool_generation: Option<u64>,
+}
+
+/// Records the chain events processed by the tracker into `replay_log`, so they can be replayed
+/// by `electrs replay` (see `replay()`). The file is rotated into `<path>.1` when it reaches
+/// `max_bytes`.
+pub(crate) struct ReplayLog {
+    path: PathBuf,
+    max_bytes: u64,
+    magic: Magic,
+    state: Mutex<LogState>,
+}
+
+impl ReplayLog {
+    /// A previous log is rotated (so it is kept after a restart).
+    pub(crate) fn open(path: &Path, max_bytes: u64, magic: Magic) -> Result<Self> {
+        let file = create(path, magic)?;
+        info!("recording chain events into {}", path.display());
+        Ok(Self {
+            path: path.to_owned(),
+            max_bytes,
+            magic,
+            state: Mutex::new(LogState {
+                file,
+                written: HEADER_LEN,
+                chain: None,
+                mempool_generation: None,
+            }),
+        })
+    }
+
+    /// Record the changes since the previous call. Failures are logged, without affecting the
+    /// sync.
+    pub(crate) fn record(&self, tracker: &Tracker) {
+        let mut state = self.state.lock();
+        if let Err(e) = self.append(&mut state, tracker.chain(), &tracker.mempool_summary()) {
+            warn!("failed to write replay log: {:#}", e);
+        }
+    }
+
+    /// The recorded state is updated only after the records are written (so a failed write is
+    /// retried by the next call).
+    fn append(
+        &self,
+        state: &mut LogState,
+        chain: Arc<Chain>,
+        mempool: &MempoolSummary,
+    ) -> Result<()> {
+        let mut records = vec![];
+        match &state.chain {
+            None => records.push(Record::start(&chain)),
+            Some(prev) if !Arc::ptr_eq(prev, &chain) => {
+                records.extend(Record::blocks(prev, &chain))
+            }
+            Some(_) => (),
+        }
+        if state.mempool_generation != Some(mempool.mempool_generation) {
+            records.push(Record::mempool(mempool));
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        if state.written >= self.max_bytes {
+            if let Some(prev) = &state.chain {
+                records.insert(0, Record::start(prev)); // the following records are relative to it
+            }
+            state.file = create(&self.path, self.magic)?;
+            state.written = HEADER_LEN;
+        }
+        let mut buf = vec![];
+        records.iter().for_each(|record| record.encode(&mut buf));
+        // a single write, so a crash leaves at most a truncated last record
+        state
+            .file
+            .write_all(&buf)
+            .with_context(|| format!("failed to write {}", self.path.display()))?;
+        state.written += buf.len() as u64;
+        state.chain = Some(chain);
+        state.mempool_generation = Some(mempool.mempool_generation);
+        Ok(())
+    }
+}
+
+/// Start a new log file, rotating the existing one into `<path>.1`
+fn create(path: &Path, magic: Magic) -> Result<File> {
+    if path.exists() {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(path, &rotated)
+            .with_context(|| format!("failed to rotate {}", path.display()))?;
+    }
+    let mut file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(&header(magic))
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(file)
+}
+
+/// Serves the recorded chain (as of the replayed record), fetching the blocks and the other
+/// queries from `source` (which must have all the recorded blocks, including stale ones).
+struct ReplayNode<'a> {
+    source: &'a dyn Node,
+    chain: Mutex<Chain>,
+}
+
+impl<'a> ReplayNode<'a> {
+    /// Start from the source's best chain, up to the recorded tip.
+    fn new(source: &'a dyn Node, mut chain: Chain, height: usize, tip: BlockHash) -> Result<Self> {
+        while chain.height() < height {
+            let new_headers = source.get_new_headers(&chain)?;
+            if new_headers.is_empty() {
+                break;
+            }
+            chain.update(new_headers);
+        }
+        ensure!(
+            chain.get_block_hash(height) == Some(tip),
+            "the recorded tip {} (at height {}) is not in the node's best chain",
+            tip,
+            height
+        );
+        chain.drop_last_headers(chain.height() - height);
+        Ok(Self {
+            source,
+            chain: Mutex::new(chain),
+        })
+    }
+
+    fn apply(&self, disconnected: &[BlockHash], connected: &[BlockHash]) -> Result<()> {
+        let mut chain = self.chain.lock();
+        let fork = chain
+            .height()
+            .checked_sub(disconnected.len())
+            .context("disconnecting the genesis block")?;
+        for (height, hash) in (fork + 1..=chain.height()).rev().zip(disconnected) {
+            ensure!(
+                chain.get_block_hash(height) == Some(*hash),
+                "disconnected block {} is not at height {}",
+                hash,
+                height
+            );
+        }
+        let new_headers = connected
+            .iter()
+            .zip(fork + 1..)
+            .map(|(hash, height)| {
+                let block = self.source.get_block(*hash)?;
+                let (header, _len) = deserialize_partial::<BlockHeader>(&block)
+                    .with_context(|| format!("invalid block {}", hash))?;
+                ensure!(header.block_hash() == *hash, "unexpected block {}", hash);
+                Ok(NewHeader::from((header, height)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        chain.drop_last_headers(disconnected.len());
+        chain.update(new_headers);
+        Ok(())
+    }
+
+    fn tip(&self) -> (usize, BlockHash) {
+        let chain = self.chain.lock();
+        (chain.height(), chain.tip())
+    }
+}
+
+impl Node for ReplayNode<'_> {
+    fn subversion(&self) -> &str {
+        self.source.subversion()
+    }
+
+    fn estimate_fee(&self, nblocks: u16) -> Result<Option<Amount>> {
+        self.source.estimate_fee(nblocks)
+    }
+
+    fn get_relay_fee(&self) -> Result<Amount> {
+        self.source.get_relay_fee()
+    }
+
+    fn broadcast(&self, _tx: &Transaction) -> Result<Txid> {
+        bail!("broadcasting is not supported while replaying")
+    }
+
+    fn submitpackage(&self, _txs: &[Transaction]) -> Result<Value> {
+        bail!("broadcasting is not supported while replaying")
+    }
+
+    fn get_transaction_info(&self, txid: &Txid, blockhash: Option<BlockHash>) -> Result<Value> {
+        self.source.get_transaction_info(txid, blockhash)
+    }
+
+    fn get_transaction(&self, txid: &Txid, blockhash: Option<BlockHash>) -> Result<Transaction> {
+        self.source.get_transaction(txid, blockhash)
+    }
+
+    fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
+        self.source.get_block_txids(blockhash)
+    }
+
+    fn get_block(&self, blockhash: BlockHash) -> Result<SerBlock> {
+        self.source.get_block(blockhash)
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<Option<BlockHash>> {
+        Ok(self.chain.lock().get_block_hash(height))
+    }
+
+    fn get_best_block_hash(&self) -> Result<BlockHash> {
+        Ok(self.chain.lock().tip())
+    }
+
+    fn get_mempool_info(&self) -> Result<json::GetMempoolInfoResult> {
+        self.source.get_mempool_info()
+    }
+
+    // the mempool is not indexed, so it is not replayed
+    fn get_mempool_txids(&self) -> Result<Vec<Txid>> {
+        Ok(vec![])
+    }
+
+    fn get_mempool_entries(
+        &self,
+        txids: &[Txid],
+    ) -> Result<Vec<Option<json::GetMempoolEntryResult>>> {
+        Ok(vec![None; txids.len()])
+    }
+
+    fn get_mempool_transactions(&self, txids: &[Txid]) -> Result<Vec<Option<Transaction>>> {
+        Ok(vec![None; txids.len()])
+    }
+
+    fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
+        Ok(self.chain.lock().new_headers(chain, usize::MAX))
+    }
+
+    fn get_prune_height(&self) -> Result<Option<usize>> {
+        self.source.get_prune_height()
+    }
+
+    /// Fetched via `getblock`, since stale blocks may not be served via p2p.
+    fn for_blocks(
+        &self,
+        blockhashes: Vec<BlockHash>,
+        func: &mut dyn FnMut(BlockHash, SerBlock),
+    ) -> Result<()> {
+        for blockhash in blockhashes {
+            func(blockhash, self.source.get_block(blockhash)?);
+        }
+        Ok(())
+    }
+}
+
+/// A column family's rows in the replayed and the clean DBs
+#[derive(Debug, Serialize)]
+pub(crate) struct CfDigests {
+    pub name: &'static str,
+    pub rows: u64,
+    pub digest: sha256::Hash,
+    pub clean_rows: u64,
+    pub clean_digest: sha256::Hash,
+    /// Clean rows which are missing from the replayed DB (or have a different value)
+    pub missing: u64,
+    /// Replayed rows which are not in the clean DB (e.g. of disconnected blocks, which are kept)
+    pub stale: u64,
+}
+
+fn hash_row(engine: &mut sha256::HashEngine, (key, value): &(Box<[u8]>, Box<[u8]>)) {
+    for bytes in [key, value] {
+        engine.input(&(bytes.len() as u32).to_le_bytes());
+        engine.input(bytes);
+    }
+}
+
+/// Both iterators are sorted by key (like RocksDB's default comparator).
+fn compare_cf(name: &'static str, replayed: &DBStore, clean: &DBStore) -> CfDigests {
+    let mut replayed_rows = replayed.iter_rows(name).peekable();
+    let mut clean_rows = clean.iter_rows(name).peekable();
+    let (mut engine, mut clean_engine) = (sha256::Hash::engine(), sha256::Hash::engine());
+    let (mut rows, mut clean_count, mut missing, mut stale) = (0, 0, 0, 0);
+    loop {
+        let order = match (replayed_rows.peek(), clean_rows.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((key, _)), Some((clean_key, _))) => key.cmp(clean_key),
+        };
+        if order != Ordering::Greater {
+            let row = replayed_rows.next().expect("missing row");
+            hash_row(&mut engine, &row);
+            rows += 1;
+            if order == Ordering::Less {
+                stale += 1;
+                continue;
+            }
+            let clean_row = clean_rows.next().expect("missing row");
+            hash_row(&mut clean_engine, &clean_row);
+            clean_count += 1;
+            if row.1 != clean_row.1 {
+                missing += 1;
+                stale += 1;
+            }
+        } else {
+            let clean_row = clean_rows.next().expect("missing row");
+            hash_row(&mut clean_engine, &clean_row);
+            clean_count += 1;
+            missing += 1;
+        }
+    }
+    CfDigests {
+        name,
+        rows,
+        digest: sha256::Hash::from_engine(engine),
+        clean_rows: clean_count,
+        clean_digest: sha256::Hash::from_engine(clean_engine),
+        missing,
+        stale,
+    }
+}
+
+/// The result of `replay()`
+#[derive(Debug, Serialize)]
+pub(crate) struct ReplayReport {
+    /// Replayed `Blocks` records
+    pub steps: usize,
+    /// Skipped `Mempool` records (the mempool is not indexed)
+    pub mempool_syncs: usize,
+    pub height: usize,
+    pub tip: BlockHash,
+    /// The config column family is skipped (the tips are compared instead)
+    pub column_families: Vec<CfDigests>,
+}
+
+impl ReplayReport {
+    /// Fails if the replayed DB doesn't contain all the rows of the clean one.
+    pub(crate) fn check(&self) -> Result<()> {
+        let missing: Vec<String> = self
+            .column_families
+            .iter()
+            .filter(|cf| cf.missing > 0)
+            .map(|cf| format!("{} {} rows", cf.missing, cf.name))
+            .collect();
+        ensure!(
+            missing.is_empty(),
+            "the replayed DB is missing {} of the clean DB",
+            missing.join(", ")
+        );
+        Ok(())
+    }
+}
+
+fn sync(index: &Index, node: &dyn Node, exit_flag: &ExitFlag) -> Result<()> {
+    while !index.sync(node, exit_flag)? {}
+    index.publish();
+    Ok(())
+}
+
+/// Re-index the recorded chain events into `replayed` (fetching the blocks from `source`), and
+/// the final chain into `clean`, comparing their DBs' rows. Both indexes must be empty.
+pub(crate) fn replay(
+    records: &[Record],
+    source: &dyn Node,
+    empty: Chain, // of the recorded network
+    replayed: &Index,
+    clean: &Index,
+    exit_flag: &ExitFlag,
+) -> Result<ReplayReport> {
+    let mut records = records.iter();
+    let node = match records.next() {
+        Some(Record::Start { height, tip }) => ReplayNode::new(source, empty, *height, *tip)?,
+        _ => bail!("the replay log doesn't start with the indexed chain"),
+    };
+    sync(replayed, &node, exit_flag).context("failed to index the recorded start")?;
+
+    let (mut steps, mut mempool_syncs) = (0, 0);
+    for record in records {
+        match record {
+            Record::Start { height, tip } => ensure!(
+                node.tip() == (*height, *tip),
+                "the recorded start {} (at height {}) doesn't match the replayed chain",
+                tip,
+                height
+            ),
+            Record::Blocks {
+                disconnected,
+                connected,
+            } => {
+                steps += 1;
+                node.apply(disconnected, connected)
+                    .with_context(|| format!("failed to replay record #{}", steps))?;
+                sync(replayed, &node, exit_flag)?;
+                let chain = replayed.chain();
+                let (height, tip) = node.tip();
+                ensure!(
+                    chain.tip() == tip,
+                    "the replayed tip {} (at height {}) differs from the recorded tip {} (at height {})",
+                    chain.tip(),
+                    chain.height(),
+                    tip,
+                    height
+                );
+            }
+            Record::Mempool { .. } => mempool_syncs += 1,
+        }
+    }
+
+    sync(clean, &node, exit_flag).context("failed to index the final chain")?;
+    let (height, tip) = node.tip();
+    ensure!(
+        clean.chain().tip() == tip,
+        "the clean index's tip {} differs from the recorded tip {}",
+        clean.chain().tip(),
+        tip
+    );
+    let column_families = DBStore::column_families()
+        .iter()
+        .filter(|&&name| name != "config")
+        .map(|&name| compare_cf(name, replayed.store(), clean.store()))
+        .collect();
+    Ok(ReplayReport {
+        steps,
+        mempool_syncs,
+        height,
+        tip,
+        column_families,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, Record, ReplayLog, HEADER_LEN};
+    use crate::{chain::Chain, mempool::Mempool, metrics::Metrics, types::ScriptTypes};
+
+    use bitcoin::{hashes::Hash, BlockHash, Network};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_record_format() {
+        let hash = |n: u8| BlockHash::from_byte_array([n; 32]);
+        let records = vec![
+            Record::Start {
+                height: 100,
+                tip: hash(1),
+            },
+            Record::Blocks {
+                disconnected: vec![hash(1), hash(2)],
+                connected: vec![hash(3), hash(4), hash(5)],
+            },
+            Record::Mempool {
+                generation: 7,
+                count: 1000,
+                vsize: 300_000,
+            },
+        ];
+        let magic = Network::Regtest.magic();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay.log");
+        let mut data = super::header(magic);
+        records.iter().for_each(|record| record.encode(&mut data));
+        assert_eq!(
+            data.len(),
+            9 + (1 + 1 + 32) + (1 + 1 + 64 + 1 + 96) + (1 + 1 + 3 + 5)
+        );
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(read(&path, magic).unwrap(), records);
+
+        // a truncated last record is skipped
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert_eq!(read(&path, magic).unwrap(), records[..2].to_vec());
+
+        let err = read(&path, Network::Bitcoin.magic()).unwrap_err();
+        assert!(err.to_string().contains("another network"), "{}", err);
+        std::fs::write(&path, b"not a log").unwrap();
+        let err = read(&path, magic).unwrap_err();
+        assert!(err.to_string().ends_with("is not a replay log"), "{}", err);
+    }
+
+    #[test]
+    fn test_log_rotation() {
+        let magic = Network::Regtest.magic();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay.log");
+        std::fs::write(&path, b"previous run").unwrap();
+        let log = ReplayLog::open(&path, HEADER_LEN + 1, magic).unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("replay.log.1")).unwrap(),
+            b"previous run"
+        );
+
+        let chain = Arc::new(Chain::new(Network::Regtest));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mempool = Mempool::new(&metrics, ScriptTypes::all()).summary();
+        let mut state = log.state.lock();
+        log.append(&mut state, Arc::clone(&chain), &mempool)
+            .unwrap();
+        let start = Record::Start {
+            height: 0,
+            tip: chain.tip(),
+        };
+        let empty = Record::Mempool {
+            generation: 0,
+            count: 0,
+            vsize: 0,
+        };
+        assert_eq!(read(&path, magic).unwrap(), [start.clone(), empty]);
+
+        // nothing changed (so the log is not rotated)
+        log.append(&mut state, Arc::clone(&chain), &mempool)
+            .unwrap();
+        assert_eq!(read(&path, magic).unwrap().len(), 2);
+
+        // the rotated file starts with the previously recorded chain
+        let mut changed = mempool.clone();
+        changed.mempool_generation = 1;
+        changed.count = 2;
+        log.append(&mut state, chain, &changed).unwrap();
+        let records = read(&path, magic).unwrap();
+        assert_eq!(records[0], start);
+        assert_eq!(
+            records[1],
+            Record::Mempool {
+                generation: 1,
+                count: 2,
+                vsize: 0,
+            }
+        );
+        let rotated = dir.path().join("replay.log.1");
+        assert_eq!(read(&rotated, magic).unwrap().len(), 2);
+    }
+}
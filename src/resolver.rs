@@ -0,0 +1,217 @@
+//! Resolves bitcoind's host name when connecting (so DNS changes are picked up), connecting
+//! to its addresses Happy-Eyeballs-style (RFC 8305): IPv6 first, falling back to IPv4 shortly.
+
+use anyhow::{Context, Result};
+use crossbeam_channel::{unbounded, RecvTimeoutError};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The delay before trying the next address, while the previous attempts are in progress
+const FALLBACK_DELAY: Duration = Duration::from_millis(250);
+
+/// Used if no timeout is specified (so the connecting threads don't linger)
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Resolves host names using the system's resolver, unless they are overridden (like in
+/// `/etc/hosts`, e.g. by tests).
+#[derive(Clone, Default)]
+pub struct Resolver {
+    hosts: Option<Arc<HashMap<String, Vec<IpAddr>>>>,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.hosts {
+            None => write!(f, "Resolver(system)"),
+            Some(hosts) => write!(f, "Resolver({:?})", hosts),
+        }
+    }
+}
+
+impl Resolver {
+    /// Resolve only the given host names (to the given addresses, in order).
+    #[cfg(test)]
+    pub(crate) fn with_hosts<'a>(hosts: impl IntoIterator<Item = (&'a str, Vec<IpAddr>)>) -> Self {
+        let hosts = hosts
+            .into_iter()
+            .map(|(host, ips)| (host.to_owned(), ips))
+            .collect();
+        Self {
+            hosts: Some(Arc::new(hosts)),
+        }
+    }
+
+    pub(crate) fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = match &self.hosts {
+            None => (host, port)
+                .to_socket_addrs()
+                .with_context(|| format!("failed to resolve {}", host))?
+                .collect(),
+            Some(hosts) => hosts
+                .get(host)
+                .with_context(|| format!("failed to resolve {}: unknown host", host))?
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, port))
+                .collect(),
+        };
+        ensure!(!addrs.is_empty(), "no address found for {}", host);
+        Ok(addrs)
+    }
+
+    /// Resolve `host` and connect to one of its addresses (see [`connect_any`]).
+    pub(crate) fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        timeout: Option<Duration>,
+    ) -> Result<TcpStream> {
+        let addrs = self.resolve(host, port)?;
+        let stream = connect_any(&addrs, timeout)
+            .with_context(|| format!("failed to connect to {}:{} ({:?})", host, port, addrs))?;
+        if let Ok(addr) = stream.peer_addr() {
+            debug!("connected to {}:{} using {}", host, port, addr);
+        }
+        Ok(stream)
+    }
+}
+
+/// Order the addresses by preference: IPv6 and IPv4 addresses are interleaved, starting with the
+/// first IPv6 one (keeping the resolver's order within each family).
+fn preference_order(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6());
+    let mut result = Vec::with_capacity(addrs.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return result,
+            (first, second) => result.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Connect to the first responding address: each attempt is given `FALLBACK_DELAY` before the
+/// next one starts concurrently (a failed attempt starts the next one immediately).
+pub(crate) fn connect_any(
+    addrs: &[SocketAddr],
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let timeout = timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    let (tx, rx) = unbounded();
+    let mut pending = 0;
+    let mut last_error = None;
+    for addr in preference_order(addrs) {
+        let tx = tx.clone();
+        thread::Builder::new()
+            .name("connect".to_owned())
+            .spawn(move || {
+                let _ = tx.send(TcpStream::connect_timeout(&addr, timeout)); // may be ignored
+            })?;
+        pending += 1;
+        loop {
+            match rx.recv_timeout(FALLBACK_DELAY) {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    pending -= 1;
+                    last_error = Some(e);
+                    if pending == 0 {
+                        break; // try the next address now
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break, // try the next address meanwhile
+                Err(RecvTimeoutError::Disconnected) => unreachable!("`tx` is not dropped"),
+            }
+        }
+    }
+    drop(tx);
+    while pending > 0 {
+        match rx.recv().expect("missing connection attempt") {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                pending -= 1;
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect_any, preference_order, Resolver};
+
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+    use std::time::{Duration, Instant};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_preference_order() {
+        let addrs = [
+            addr("10.0.0.1:8332"),
+            addr("10.0.0.2:8332"),
+            addr("10.0.0.3:8332"),
+            addr("[fd00::1]:8332"),
+            addr("[fd00::2]:8332"),
+        ];
+        assert_eq!(
+            preference_order(&addrs),
+            vec![addrs[3], addrs[0], addrs[4], addrs[1], addrs[2]]
+        );
+        assert_eq!(preference_order(&addrs[..2]), addrs[..2].to_vec());
+        assert!(preference_order(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let resolver = Resolver::with_hosts([(
+            "bitcoind.local",
+            vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()],
+        )]);
+        assert_eq!(
+            resolver.resolve("bitcoind.local", 8332).unwrap(),
+            vec![addr("127.0.0.1:8332"), addr("[::1]:8332")]
+        );
+        let err = resolver.resolve("unknown.local", 8332).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to resolve unknown.local: unknown host"
+        );
+        let empty = Resolver::with_hosts([("empty.local", vec![])]);
+        let err = empty.resolve("empty.local", 8332).unwrap_err();
+        assert_eq!(err.to_string(), "no address found for empty.local");
+    }
+
+    #[test]
+    fn test_connect_fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // the preferred (IPv6) address is refused, since the listener uses IPv4
+        let ips = vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()];
+        let resolver = Resolver::with_hosts([("bitcoind.local", ips)]);
+
+        // the refused address is skipped (without waiting for the fallback delay)
+        let start = Instant::now();
+        let stream = resolver
+            .connect("bitcoind.local", port, Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(
+            stream.peer_addr().unwrap(),
+            addr(&format!("127.0.0.1:{}", port))
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // all the attempts failed
+        drop(listener);
+        let err = connect_any(&[addr(&format!("127.0.0.1:{}", port))], None).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    }
+}
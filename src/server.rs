@@ -1,54 +1,117 @@
 use anyhow::{Context, Result};
-use crossbeam_channel::{select, unbounded, Sender};
+use crossbeam_channel::{bounded, never, select, unbounded, Receiver, Sender};
+use parking_lot::{Condvar, Mutex};
 use rayon::prelude::*;
 
 use std::{
     collections::hash_map::HashMap,
-    io::{BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, Read, Write},
     iter::once,
-    net::{Shutdown, TcpListener, TcpStream},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    config::Config,
-    electrum::{Client, Rpc},
-    metrics::{self, Metrics},
-    signals::ExitError,
-    thread::spawn,
+    chain::{Chain, ChainView},
+    config::{Config, OnIndexFailure},
+    daemon,
+    electrum::{self, Client, Rpc, SubscribedTip, Topic},
+    health::Health,
+    limits::{self, Limits, Refusal},
+    listener::ListenerPolicy,
+    logger,
+    metrics::{self, Counter, Gauge, Histogram, Metrics},
+    outbox::Outbox,
+    shaper::Shaper,
+    signals::{ExitError, ExitFlag, Signal},
+    socket::{self, TcpOptions},
+    status::{Balance, HistoryEntry},
+    subscriptions::Affected,
+    systemd,
+    thread::{spawn, Failure, Supervisor},
+    tracker::State,
+    types::ScriptHash,
 };
 
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Stop reading a peer's requests while this many of them are not responded yet
+const MAX_PENDING_REQUESTS: usize = 1000;
+
+/// Release the memory of a peer's line buffer after receiving a request larger than this (in bytes)
+const LINE_BUFFER_SHRINK_SIZE: usize = 1 << 16;
+
+/// bitcoind doesn't announce new blocks during its initial block download (i.e. while its tip is
+/// older than this, as its `DEFAULT_MAX_TIP_AGE`), so they are polled more often meanwhile
+const CATCHUP_TIP_AGE: u64 = 24 * 60 * 60;
+
+/// The polling interval while bitcoind is catching up (if `wait_duration` is longer)
+const CATCHUP_WAIT_DURATION: Duration = Duration::from_secs(1);
+
+/// The delay before retrying a sync which failed since bitcoind was unavailable (doubled after
+/// each consecutive failure, up to `MAX_SYNC_RETRY_DELAY`)
+const MIN_SYNC_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_SYNC_RETRY_DELAY: Duration = Duration::from_secs(60);
+
 struct Peer {
     id: usize,
     client: Client,
     stream: TcpStream,
+    pending: Receiver<()>, // a message per request which is not responded yet
+    outbox: Outbox,
 }
 
 impl Peer {
-    fn new(id: usize, stream: TcpStream) -> Self {
-        let client = Client::default();
-        Self { id, client, stream }
+    fn new(rpc: &Rpc, id: usize, conn: Connection) -> Self {
+        let client = rpc.new_client(id, conn.stream.peer_addr().ok(), conn.policy);
+        Self {
+            id,
+            client,
+            stream: conn.stream,
+            pending: conn.pending,
+            outbox: conn.outbox,
+        }
     }
 
-    fn send(&mut self, values: Vec<String>) -> Result<()> {
-        for mut value in values {
-            debug!("{}: send {}", self.id, value);
-            value += "\n";
-            self.stream
-                .write_all(value.as_bytes())
-                .with_context(|| format!("failed to send response: {:?}", value))?;
-        }
-        Ok(())
+    fn notify(&self, notifications: Vec<(Topic, String)>) -> Result<()> {
+        notifications
+            .into_iter()
+            .try_for_each(|(topic, msg)| self.outbox.push_notification(topic, msg))
     }
 
     fn disconnect(self) {
         if let Err(e) = self.stream.shutdown(Shutdown::Both) {
-            warn!("{}: failed to shutdown TCP connection {}", self.id, e)
+            warn!(peer = self.id; "{}: failed to shutdown TCP connection {}", self.id, e)
         }
     }
 }
 
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.outbox.close(); // the queued messages are still sent (unless disconnected)
+    }
+}
+
 pub fn run() -> Result<()> {
-    let result = serve();
+    let config = Config::from_args();
+    if let Some(command) = &config.command {
+        return command
+            .run(&config, &mut io::stdout())
+            .context("electrs failed");
+    }
+    let result = Server::new(config, Signal::new()).and_then(Server::serve);
+    systemd::notifier().stopping();
+    ignore_exit(result).context("electrs failed")
+}
+
+/// Exiting due to a signal (or [`Handle::shutdown`]) is not a failure
+fn ignore_exit(result: Result<()>) -> Result<()> {
     if let Err(e) = &result {
         for cause in e.chain() {
             if cause.downcast_ref::<ExitError>().is_some() {
@@ -57,193 +120,1746 @@ pub fn run() -> Result<()> {
             }
         }
     }
-    result.context("electrs failed")
+    result
 }
 
-fn serve() -> Result<()> {
-    let config = Config::from_args();
-    let metrics = Metrics::new(config.monitoring_addr)?;
+/// Electrum server, which can also be embedded in another process (see [`Server::start`])
+pub struct Server {
+    config: Config,
+    rpc: Arc<Rpc>,
+    limits: Limits,
+    query_pool: Arc<QueryPool>, // shared with the HTTP server
+    server_rx: Receiver<Event>,
+    acceptors: Vec<(SocketAddr, thread::JoinHandle<()>)>,
+    #[cfg(feature = "http")]
+    http: Option<crate::http::HttpServer>,
+    health: Health,
+    state: StateWatch,
+    supervisor: Supervisor,
+    duration: Histogram,
+    server_batch_size: Histogram,
+}
 
-    let (server_tx, server_rx) = unbounded();
-    if !config.disable_electrum_rpc {
-        let listener = TcpListener::bind(config.electrum_rpc_addr)?;
-        info!("serving Electrum RPC on {}", listener.local_addr()?);
-        spawn("accept_loop", || accept_loop(listener, server_tx)); // detach accepting thread
-    };
+impl Server {
+    /// Start serving in a background thread, returning after the DB is opened and bitcoind is
+    /// connected (the index is synced in the background, see [`Handle::state`]).
+    ///
+    /// The process' signals are not handled - use [`Handle::shutdown`] to stop the server.
+    ///
+    /// ```no_run
+    /// use electrs::{Config, Server, State};
+    /// use std::time::Duration;
+    ///
+    /// # fn main() -> anyhow::Result<()> {
+    /// let config = Config::builder()
+    ///     .network(bitcoin::Network::Regtest)
+    ///     .db_dir("/tmp/electrs-db")
+    ///     .daemon_dir("/home/user/.bitcoin")
+    ///     .build()?;
+    /// let handle = Server::start(config)?;
+    /// assert!(handle.state().wait_for(State::Synced, Duration::from_secs(60)));
+    ///
+    /// let script = bitcoin::ScriptBuf::new_op_return([]);
+    /// let scripthash = electrs::ScriptHash::new(&script);
+    /// println!("balance: {:?}", handle.get_balance(scripthash)?);
+    /// println!("history: {:?}", handle.get_history(scripthash)?);
+    /// handle.shutdown()
+    /// # }
+    /// ```
+    pub fn start(config: Config) -> Result<Handle> {
+        let signal = Signal::manual();
+        let server = Server::new(config, signal)?;
+        let rpc = Arc::clone(&server.rpc);
+        let state = server.state.clone();
+        let thread = thread::Builder::new()
+            .name("electrs".to_owned())
+            .spawn(move || server.serve())
+            .context("failed to spawn server thread")?;
+        Ok(Handle { rpc, state, thread })
+    }
 
-    let server_batch_size = metrics.histogram_vec(
-        "server_batch_size",
-        "# of server events handled in a single batch",
-        "type",
-        metrics::default_size_buckets(),
-    );
-    let duration = metrics.histogram_vec(
-        "server_loop_duration",
-        "server loop duration",
-        "step",
-        metrics::default_duration_buckets(),
-    );
-    let mut rpc = Rpc::new(&config, metrics)?;
+    fn new(config: Config, signal: Signal) -> Result<Self> {
+        let metrics = Metrics::new(config.monitoring_addr)?;
+
+        let (server_tx, server_rx) = unbounded();
+        let limits = Limits::new(
+            config.max_connections,
+            config.max_connections_per_ip,
+            config.ban_file.clone(),
+        )?;
+        limits::check_fd_limit(config.max_connections);
+        let policies = config
+            .electrum_rpc_listeners
+            .iter()
+            .map(|listener| ListenerPolicy::new(listener, &config).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        let mut acceptors = vec![];
+        if !config.disable_electrum_rpc {
+            let listeners = bind(&config.electrum_rpc_addrs, &config.tcp)?; // fail if any of the addresses can't be bound
+            let send_limits = (config.max_send_queue_bytes, config.send_timeout);
+            let request_limits = (config.max_request_bytes, config.max_broadcast_bytes);
+            let shaper = Shaper::new(
+                config.max_upload_rate,
+                config.max_peer_upload_rate,
+                &metrics,
+            );
+            let acceptor = Acceptor::new(
+                server_tx,
+                limits.clone(),
+                send_limits,
+                request_limits,
+                config.tcp,
+                shaper,
+                signal.exit_flag().clone(),
+                &metrics,
+            );
+            for (listener, policy) in listeners.into_iter().zip(&policies) {
+                let addr = listener.local_addr()?;
+                info!("serving Electrum RPC on {}", addr);
+                let (acceptor, policy) = (acceptor.clone(), Arc::clone(policy));
+                let thread = spawn("accept_loop", || accept_loop(listener, acceptor, policy));
+                acceptors.push((addr, thread));
+            }
+        };
+        #[cfg(feature = "http")]
+        let http_server = config
+            .http_addr
+            .map(crate::http::HttpServer::bind)
+            .transpose()?;
+
+        let server_batch_size = metrics.histogram_vec(
+            "server_batch_size",
+            "# of server events handled in a single batch",
+            "type",
+            metrics::default_size_buckets(),
+        );
+        let duration = metrics.histogram_vec(
+            "server_loop_duration",
+            "server loop duration",
+            "step",
+            metrics::default_duration_buckets(),
+        );
+        let health = metrics.health().clone();
+        let query_pool = Arc::new(QueryPool::new(config.query_threads, &metrics)?);
+        let supervisor = Supervisor::new();
+        let rpc = Arc::new(Rpc::new(
+            &config,
+            metrics,
+            limits.clone(),
+            policies,
+            signal,
+            &supervisor,
+        )?);
+        #[cfg(feature = "http")]
+        let http = http_server.map(|server| {
+            let (rpc, query_pool) = (Arc::clone(&rpc), Arc::clone(&query_pool));
+            crate::http::HttpServer::start(
+                server,
+                Arc::new(move |addr, body| {
+                    query_pool.install(|| rpc.handle_stateless_request(addr, body))
+                }),
+                limits.clone(),
+                config.http_cors_origin.clone(),
+                (config.max_request_bytes, config.max_concurrent_requests),
+            )
+        });
+        Ok(Self {
+            config,
+            rpc,
+            limits,
+            query_pool,
+            server_rx,
+            acceptors,
+            #[cfg(feature = "http")]
+            http,
+            health,
+            state: StateWatch::default(),
+            supervisor,
+            duration,
+            server_batch_size,
+        })
+    }
 
-    let new_block_rx = rpc.new_block_notification();
-    let mut peers = HashMap::<usize, Peer>::new();
+    fn serve(self) -> Result<()> {
+        let rpc = &*self.rpc;
+        let mut peers = HashMap::<usize, Peer>::new();
+
+        // new blocks are indexed by a separate thread, so queries are served meanwhile
+        let result = thread::scope(|scope| {
+            let (sync_tx, sync_rx) = bounded(1);
+            let (stop_tx, stop_rx) = bounded::<()>(0);
+            let shadow_stop_rx = stop_rx.clone();
+            let maintenance_stop_rx = stop_rx.clone();
+            let (wait_duration, duration) = (self.config.wait_duration, &self.duration);
+            let (supervisor, health) = (&self.supervisor, &self.health);
+            let syncer = thread::Builder::new()
+                .name("sync".to_owned())
+                .spawn_scoped(scope, move || {
+                    supervisor.run("sync", || {
+                        sync_loop(rpc, health, wait_duration, duration, sync_tx, stop_rx)
+                    })
+                })
+                .expect("spawn failed");
+            // the DB slices are compacted off the sync thread, so new blocks are indexed meanwhile
+            let maintainer = self.config.maintenance_window.is_some().then(|| {
+                thread::Builder::new()
+                    .name("maintenance".to_owned())
+                    .spawn_scoped(scope, move || {
+                        supervisor.run("maintenance", || {
+                            maintenance_loop(rpc, wait_duration, maintenance_stop_rx)
+                        })
+                    })
+                    .expect("spawn failed")
+            });
+            // sampled queries are compared off the serving threads (see `shadow_db`)
+            let comparer = rpc.has_shadow_db().then(|| {
+                thread::Builder::new()
+                    .name("shadow_db".to_owned())
+                    .spawn_scoped(scope, move || {
+                        supervisor.run("shadow_db", || rpc.compare_shadow_db(&shadow_stop_rx))
+                    })
+                    .expect("spawn failed")
+            });
+            let result = serve_loop(&self, &mut peers, sync_rx);
+            rpc.signal().exit(); // interrupt the sync thread (if it is syncing)
+            drop(stop_tx); // stop the sync, maintenance and shadow_db threads (when they wait)
+            let sync_result = syncer.join().expect("sync thread panic");
+            let maintenance_result = maintainer
+                .map(|maintainer| maintainer.join().expect("maintenance thread panic"))
+                .unwrap_or(Ok(()));
+            if let Some(comparer) = comparer {
+                let _ = comparer.join().expect("shadow_db thread panic"); // comparisons don't fail
+            }
+            result.and(sync_result).and(maintenance_result)
+        });
+
+        // stop accepting new connections, and disconnect the existing ones
+        #[cfg(feature = "http")]
+        if let Some(http) = self.http {
+            http.stop();
+        }
+        for (addr, thread) in self.acceptors {
+            if wake(addr) {
+                let _ = thread.join(); // the listener is closed when the thread exits
+            }
+        }
+        peers.into_values().for_each(Peer::disconnect);
+        result
+    }
+}
+
+/// A running [`Server`], allowing to query it directly (without an Electrum RPC connection)
+pub struct Handle {
+    rpc: Arc<Rpc>,
+    state: StateWatch,
+    thread: thread::JoinHandle<Result<()>>,
+}
+
+impl Handle {
+    /// Wait until the server stops (e.g. on failure, or after the initial sync if `sync_once`
+    /// is set). The DB is closed when this method returns.
+    pub fn wait(self) -> Result<()> {
+        let Handle { rpc, thread, .. } = self;
+        let result = match thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("server thread panicked")),
+        };
+        drop(rpc);
+        ignore_exit(result)
+    }
+
+    /// Stop the server gracefully, and wait until it stops.
+    pub fn shutdown(self) -> Result<()> {
+        self.rpc.signal().exit();
+        self.wait()
+    }
+
+    /// The index state, updated after each sync
+    pub fn state(&self) -> StateWatch {
+        self.state.clone()
+    }
+
+    /// The indexed chain (a snapshot, which is not updated by the following syncs)
+    pub fn chain(&self) -> ChainView {
+        self.rpc.chain_view()
+    }
+
+    /// Scripthash history (as returned by `blockchain.scripthash.get_history`)
+    pub fn get_history(&self, scripthash: ScriptHash) -> Result<Vec<HistoryEntry>> {
+        self.rpc.get_history(scripthash)
+    }
+
+    /// Scripthash balance (as returned by `blockchain.scripthash.get_balance`)
+    pub fn get_balance(&self, scripthash: ScriptHash) -> Result<Balance> {
+        self.rpc.get_balance(scripthash)
+    }
+}
+
+/// The latest index state (`None` before the first sync)
+#[derive(Clone, Default)]
+pub struct StateWatch(Arc<(Mutex<Option<State>>, Condvar)>);
+
+impl StateWatch {
+    fn set(&self, state: State) {
+        let (current, cond) = &*self.0;
+        *current.lock() = Some(state);
+        cond.notify_all();
+    }
+
+    pub fn get(&self) -> Option<State> {
+        *self.0 .0.lock()
+    }
+
+    /// Wait until the given state is reached (returns `false` on timeout).
+    pub fn wait_for(&self, state: State, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (current, cond) = &*self.0;
+        let mut current = current.lock();
+        while *current != Some(state) {
+            if cond.wait_until(&mut current, deadline).timed_out() {
+                return *current == Some(state);
+            }
+        }
+        true
+    }
+}
+
+/// Unblock the accepting thread of `addr`, so it can exit (returns `false` on failure)
+fn wake(mut addr: SocketAddr) -> bool {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    match TcpStream::connect_timeout(&addr, REFUSAL_TIMEOUT) {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("failed to connect {}: {}", addr, e);
+            false
+        }
+    }
+}
+
+/// The subscriptions affected by a sync step (and the chain snapshot they refer to)
+struct SyncUpdate {
+    done: bool,
+    chain: Arc<Chain>,
+    affected: Affected,
+}
+
+/// Index new blocks and sync the mempool, sending an update to the main loop after each step.
+///
+/// A sync failing since bitcoind is temporarily unavailable (e.g. restarting) is retried, and
+/// the p2p connection is re-established if it was lost (meanwhile, `/readyz` reports bitcoind as
+/// unreachable). Other failures stop the sync (and are handled according to `on_index_failure`).
+fn sync_loop(
+    rpc: &Rpc,
+    health: &Health,
+    wait_duration: Duration,
+    duration: &Histogram,
+    sync_tx: Sender<SyncUpdate>,
+    stop_rx: Receiver<()>,
+) -> Result<()> {
+    let mut failures = 0;
     loop {
         // initial sync and compaction may take a few hours
-        while server_rx.is_empty() {
-            let done = duration.observe_duration("sync", || rpc.sync().context("sync failed"))?; // sync a batch of blocks
-            peers = duration.observe_duration("notify", || notify_peers(&rpc, peers)); // peers are disconnected on error
-            if !done {
-                continue; // more blocks to sync
+        let done = match duration.observe_duration("sync", || rpc.sync()) {
+            Ok(done) => {
+                health.daemon_synced();
+                done // synced a batch of blocks
             }
-            if config.sync_once {
-                return Ok(()); // exit after initial sync is done
+            Err(e) if daemon::is_transient(&e) => {
+                health.daemon_failed(&e);
+                let delay = sync_retry_delay(failures);
+                failures += 1;
+                warn!("sync failed, retrying in {:?}: {:#}", delay, e);
+                select! {
+                    recv(stop_rx) -> _ => return Ok(()),
+                    default(delay) => continue,
+                }
             }
-            break;
+            Err(e) => return Err(e.context("sync failed")),
+        };
+        if failures > 0 {
+            info!("sync recovered after {} failures", failures);
+            failures = 0;
+        }
+        let (chain, affected) = rpc.take_affected();
+        let tip_age = chain.tip_age(limits::unix_now());
+        let update = SyncUpdate {
+            done,
+            chain,
+            affected,
+        };
+        if sync_tx.send(update).is_err() {
+            return Ok(()); // the main loop has exited
         }
-        duration.observe_duration("select", || -> Result<()> {
+        if !done {
+            continue; // more blocks to sync
+        }
+        // during a shadow migration, its next blocks are indexed without waiting
+        let wait_duration = match rpc.is_migrating() {
+            true => Duration::ZERO,
+            false => poll_duration(wait_duration, tip_age),
+        };
+        // re-created after reconnecting (see `Daemon::reconnect_p2p()`)
+        let new_block_rx = rpc.new_block_notification();
+        select! {
+            // Handle new blocks' notifications
+            recv(new_block_rx) -> result => if result.is_err() {
+                warn!("p2p connection to bitcoind was lost, reconnecting");
+            },
+            recv(stop_rx) -> _ => return Ok(()),
+            default(wait_duration) => (), // sync and update
+        }
+    }
+}
+
+/// The delay before retrying a sync after `failures` consecutive failures
+fn sync_retry_delay(failures: u32) -> Duration {
+    MIN_SYNC_RETRY_DELAY
+        .saturating_mul(1 << failures.min(16))
+        .min(MAX_SYNC_RETRY_DELAY)
+}
+
+/// Compact the DB during the maintenance window (see `Index::maintain()`), a single slice every
+/// `wait_duration` (so the compactions' I/O doesn't starve the sync and the queries).
+fn maintenance_loop(rpc: &Rpc, wait_duration: Duration, stop_rx: Receiver<()>) -> Result<()> {
+    loop {
+        rpc.maintain();
+        select! {
+            recv(stop_rx) -> _ => return Ok(()),
+            default(wait_duration) => (),
+        }
+    }
+}
+
+/// The sync loop's polling interval, given the indexed tip's age (which is clamped to zero if the
+/// tip's timestamp is in the future, so it doesn't affect the polling).
+fn poll_duration(wait_duration: Duration, tip_age: u64) -> Duration {
+    match tip_age > CATCHUP_TIP_AGE {
+        true => wait_duration.min(CATCHUP_WAIT_DURATION),
+        false => wait_duration,
+    }
+}
+
+fn serve_loop(
+    server: &Server,
+    peers: &mut HashMap<usize, Peer>,
+    mut sync_rx: Receiver<SyncUpdate>,
+) -> Result<()> {
+    let Server {
+        config,
+        rpc,
+        limits,
+        server_rx,
+        health,
+        state,
+        supervisor,
+        duration,
+        ..
+    } = server;
+    let notifier = systemd::notifier();
+    // deferred while handling the previous batch, so they are handled in the next iteration
+    // (after polling the other channels, which would be starved by a busy server otherwise)
+    let mut deferred: Vec<Event> = vec![];
+    loop {
+        let timeout = match deferred.is_empty() {
+            true => config.wait_duration,
+            false => Duration::ZERO,
+        };
+        let keep_running = duration.observe_duration("select", || -> Result<bool> {
             select! {
                 // Handle signals for graceful shutdown
                 recv(rpc.signal().receiver()) -> result => {
                     result.context("signal channel disconnected")?;
                     rpc.signal().exit_flag().poll().context("RPC server interrupted")?;
+                    if rpc.signal().take_reload() {
+                        notifier.reloading(); // `READY=1` will be sent after the next sync
+                        info!("reloading");
+                        logger::reopen();
+                        rpc.reload();
+                    }
                 },
-                // Handle new blocks' notifications
-                recv(new_block_rx) -> result => match result {
-                    Ok(_) => (), // sync and update
-                    Err(_) => {
-                        info!("disconnected from bitcoind");
-                        return Ok(());
+                // Handle new blocks and mempool changes
+                recv(sync_rx) -> update => {
+                    let update = match update {
+                        Ok(update) => update,
+                        Err(_) => {
+                            // the sync thread has exited (its failure is handled below)
+                            sync_rx = never();
+                            return Ok(true);
+                        }
+                    };
+                    notifier.watchdog();
+                    let current = std::mem::take(peers);
+                    *peers = duration.observe_duration("notify", || {
+                        notify_peers(rpc, current, &update.chain, &update.affected) // peers are disconnected on error
+                    });
+                    rpc.set_connections(peers.len());
+                    health.update(|s| {
+                        s.state = Some(rpc.state());
+                        s.indexed_height = Some(update.chain.height());
+                    });
+                    state.set(rpc.state());
+                    limits.expire(limits::unix_now());
+                    if update.done {
+                        if config.sync_once {
+                            return Ok(false); // exit after initial sync is done
+                        }
+                        if rpc.state() == State::Synced {
+                            let status = format!("Synced at height {}", update.chain.height());
+                            notifier.ready(&status); // sent only once
+                            notifier.status(&status);
+                        }
+                    }
+                },
+                // Handle indexing (and bitcoind p2p) threads' failures
+                recv(supervisor.failures()) -> failure => {
+                    let Failure { name, error } = failure.expect("supervisor is owned by the server");
+                    match config.on_index_failure {
+                        OnIndexFailure::Exit => {
+                            return Err(error.context(format!("{} thread failed", name)));
+                        }
+                        OnIndexFailure::ServeStale => {
+                            error!("{} thread failed: {:#} (serving stale index)", name, error);
+                            health.update(|s| s.index_error = Some(format!("{} thread failed: {:#}", name, error)));
+                        }
                     }
                 },
                 // Handle Electrum RPC requests
                 recv(server_rx) -> event => {
                     let first = once(event.context("server disconnected")?);
                     let rest = server_rx.iter().take(server_rx.len());
-                    let events: Vec<Event> = first.chain(rest).collect();
-                    server_batch_size.observe("recv", events.len() as f64);
-                    duration.observe_duration("handle", || handle_events(&rpc, &mut peers, events));
+                    let events: Vec<Event> = deferred.drain(..).chain(first).chain(rest).collect();
+                    deferred = handle_batch(server, peers, events);
+                },
+                // Handle the deferred events (or ping the watchdog)
+                default(timeout) => if !deferred.is_empty() {
+                    let events = std::mem::take(&mut deferred);
+                    deferred = handle_batch(server, peers, events);
                 },
-                default(config.wait_duration) => (), // sync and update
             };
-            Ok(())
+            Ok(true)
         })?;
+        if !keep_running {
+            return Ok(());
+        }
+        notifier.watchdog();
     }
 }
 
-fn notify_peers(rpc: &Rpc, peers: HashMap<usize, Peer>) -> HashMap<usize, Peer> {
-    peers
+/// Handles a single batch of events, returning the ones deferred meanwhile.
+fn handle_batch(
+    server: &Server,
+    peers: &mut HashMap<usize, Peer>,
+    events: Vec<Event>,
+) -> Vec<Event> {
+    server
+        .server_batch_size
+        .observe("recv", events.len() as f64);
+    let deferred = server.duration.observe_duration("handle", || {
+        handle_events(
+            &server.rpc,
+            peers,
+            &server.limits,
+            &server.query_pool,
+            events,
+            &server.server_rx,
+        )
+    });
+    server.rpc.set_connections(peers.len());
+    deferred
+}
+
+/// The affected statuses of all peers are recomputed before any notification is sent, so the
+/// notified clients' follow-up requests find the new block's data (and the other peers' shared
+/// scripthashes' history) already cached.
+fn notify_peers(
+    rpc: &Rpc,
+    peers: HashMap<usize, Peer>,
+    chain: &Arc<Chain>,
+    affected: &Affected,
+) -> HashMap<usize, Peer> {
+    let updated: Vec<(Peer, Result<Vec<(Topic, String)>>)> = peers
         .into_par_iter()
-        .filter_map(|(_, mut peer)| match notify_peer(rpc, &mut peer) {
-            Ok(()) => Some((peer.id, peer)),
-            Err(e) => {
-                error!("failed to notify peer {}: {}", peer.id, e);
-                peer.disconnect();
-                None
+        .map(|(_, mut peer)| {
+            let notifications = rpc
+                .update_client(&mut peer.client, chain, affected)
+                .context("failed to generate notifications");
+            (peer, notifications)
+        })
+        .collect();
+    updated
+        .into_par_iter()
+        .filter_map(|(peer, notifications)| {
+            match notifications.and_then(|notifications| {
+                peer.notify(notifications)
+                    .context("failed to send notifications")
+            }) {
+                Ok(()) => Some((peer.id, peer)),
+                Err(e) => {
+                    error!(peer = peer.id; "failed to notify peer {}: {}", peer.id, e);
+                    peer.disconnect();
+                    None
+                }
             }
         })
         .collect()
 }
 
-fn notify_peer(rpc: &Rpc, peer: &mut Peer) -> Result<()> {
-    let notifications = rpc
-        .update_client(&mut peer.client)
-        .context("failed to generate notifications")?;
-    peer.send(notifications)
-        .context("failed to send notifications")
-}
-
 struct Event {
     peer_id: usize,
     msg: Message,
 }
 
+/// A new peer's connection, handed over to the main loop
+struct Connection {
+    stream: TcpStream,
+    pending: Receiver<()>,
+    outbox: Outbox,
+    policy: Arc<ListenerPolicy>, // of the listener which accepted the connection
+}
+
 enum Message {
-    New(TcpStream),
+    New(Connection),
     Request(String),
     Done,
 }
 
-fn handle_events(rpc: &Rpc, peers: &mut HashMap<usize, Peer>, events: Vec<Event>) {
+/// Worker threads handling Electrum RPC requests (all requests are handled before the pool is
+/// dropped, so it drains cleanly on shutdown)
+struct QueryPool {
+    pool: rayon::ThreadPool,
+    queued: AtomicUsize, // requests waiting for a worker
+    busy: AtomicUsize,   // workers handling a connection's requests
+    stats: Gauge,
+}
+
+impl QueryPool {
+    fn new(threads: usize, metrics: &Metrics) -> Result<Self> {
+        info!("handling Electrum RPC requests using {} threads", threads);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("query_{}", i))
+            .build()
+            .context("failed to create query thread pool")?;
+        Ok(Self {
+            pool,
+            queued: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+            stats: metrics.gauge(
+                "query_pool",
+                "# of Electrum RPC requests waiting for a worker (and of busy workers)",
+                "type",
+            ),
+        })
+    }
+
+    fn update_stats(&self) {
+        self.stats
+            .set("queued", self.queued.load(Ordering::Relaxed) as f64);
+        self.stats
+            .set("busy", self.busy.load(Ordering::Relaxed) as f64);
+    }
+
+    /// Run `func` using one of the pool's workers, blocking the calling thread (which must not be
+    /// a worker) - e.g. for HTTP requests, so they share the workers with Electrum RPC requests.
+    #[cfg(feature = "http")]
+    fn install<R, F>(&self, func: F) -> R
+    where
+        R: Send,
+        F: FnOnce() -> R + Send,
+    {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.update_stats();
+        let result = self.pool.install(|| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            self.busy.fetch_add(1, Ordering::Relaxed);
+            self.update_stats();
+            let result = panic::catch_unwind(AssertUnwindSafe(func));
+            self.busy.fetch_sub(1, Ordering::Relaxed);
+            self.update_stats();
+            result
+        });
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Run `func` on each item (weighted by its number of requests) using the pool's workers.
+    /// Meanwhile, the calling thread passes the events received via `events_rx` to `on_event`,
+    /// so cheap requests don't have to wait for the expensive ones.
+    fn run<T, R, F, E>(
+        &self,
+        items: Vec<(usize, T)>,
+        func: F,
+        events_rx: &Receiver<Event>,
+        mut on_event: E,
+    ) -> Vec<R>
+    where
+        T: Send,
+        R: Send,
+        F: Fn(T) -> R + Sync,
+        E: FnMut(Event),
+    {
+        let count = items.len();
+        let (tx, rx) = unbounded();
+        let func = &func;
+        self.pool.in_place_scope(|scope| {
+            for (requests, item) in items {
+                self.queued.fetch_add(requests, Ordering::Relaxed);
+                let tx = tx.clone();
+                scope.spawn(move |_| {
+                    self.queued.fetch_sub(requests, Ordering::Relaxed);
+                    self.busy.fetch_add(1, Ordering::Relaxed);
+                    self.update_stats();
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| func(item)));
+                    self.busy.fetch_sub(1, Ordering::Relaxed);
+                    self.update_stats();
+                    let _ = tx.send(result); // the receiver is dropped only after the scope ends
+                });
+            }
+            self.update_stats();
+            let mut results = Vec::with_capacity(count);
+            let never = never();
+            let mut events_rx = Some(events_rx); // `None` after disconnection
+            while results.len() < count {
+                select! {
+                    recv(rx) -> result => match result.expect("query pool channel disconnected") {
+                        Ok(result) => results.push(result),
+                        Err(payload) => panic::resume_unwind(payload), // the scope waits for the running tasks
+                    },
+                    recv(events_rx.unwrap_or(&never)) -> event => match event {
+                        Ok(event) => on_event(event),
+                        Err(_) => events_rx = None,
+                    },
+                }
+            }
+            results
+        })
+    }
+}
+
+/// Handle the given events, returning the events received meanwhile (to be handled next).
+///
+/// Each connection's requests are handled by the query pool, concurrently with the other
+/// connections' requests. Meanwhile, cheap requests (e.g. `server.ping`) are answered by the
+/// calling thread, and the rest are deferred.
+fn handle_events(
+    rpc: &Rpc,
+    peers: &mut HashMap<usize, Peer>,
+    limits: &Limits,
+    query_pool: &QueryPool,
+    events: Vec<Event>,
+    server_rx: &Receiver<Event>,
+) -> Vec<Event> {
     let mut events_by_peer = HashMap::<usize, Vec<Message>>::new();
     events
         .into_iter()
         .for_each(|e| events_by_peer.entry(e.peer_id).or_default().push(e.msg));
+    let mut batches = vec![];
     for (peer_id, messages) in events_by_peer {
-        handle_peer_events(rpc, peers, peer_id, messages);
+        let mut lines = vec![];
+        let mut done = false;
+        for msg in messages {
+            match msg {
+                Message::New(conn) => {
+                    match conn.stream.peer_addr() {
+                        Ok(addr) => debug!(peer = peer_id, addr:% = addr; "{}: connected", peer_id),
+                        Err(_) => debug!(peer = peer_id; "{}: connected", peer_id),
+                    }
+                    peers.insert(peer_id, Peer::new(rpc, peer_id, conn));
+                }
+                Message::Request(line) => lines.push(line),
+                Message::Done => {
+                    done = true;
+                    break;
+                }
+            }
+        }
+        if lines.is_empty() {
+            if done {
+                peers.remove(&peer_id); // already disconnected, just remove from peers' map
+            }
+            continue;
+        }
+        if let Some(peer) = peers.remove(&peer_id) {
+            batches.push((lines.len(), (peer, lines, done)));
+        }
     }
-}
 
-fn handle_peer_events(
-    rpc: &Rpc,
-    peers: &mut HashMap<usize, Peer>,
-    peer_id: usize,
-    messages: Vec<Message>,
-) {
-    let mut lines = vec![];
-    let mut done = false;
-    for msg in messages {
-        match msg {
-            Message::New(stream) => {
-                debug!("{}: connected", peer_id);
-                peers.insert(peer_id, Peer::new(peer_id, stream));
-            }
-            Message::Request(line) => lines.push(line),
-            Message::Done => {
-                done = true;
-                break;
+    // the peers being handled are removed from `peers`, so keep their outgoing queues
+    let lanes: HashMap<usize, (Outbox, Receiver<()>, Arc<ListenerPolicy>)> = batches
+        .iter()
+        .map(|(_, (peer, _, _))| {
+            let policy = Arc::clone(peer.client.policy());
+            (peer.id, (peer.outbox.clone(), peer.pending.clone(), policy))
+        })
+        .collect();
+    let mut tips = HashMap::<usize, SubscribedTip>::new();
+    let mut deferred = vec![];
+    let results = query_pool.run(
+        batches,
+        |(mut peer, lines, done)| {
+            let result = handle_peer_requests(rpc, &mut peer, limits, &lines);
+            (peer, result, done)
+        },
+        server_rx,
+        |event| {
+            if let Message::Request(line) = &event.msg {
+                let lane = match peers.get(&event.peer_id) {
+                    Some(peer) => Some((&peer.outbox, &peer.pending, peer.client.policy())),
+                    None => lanes.get(&event.peer_id).map(|(o, p, l)| (o, p, l)),
+                };
+                if let Some((outbox, pending, policy)) = lane {
+                    if let Some((response, tip)) = rpc.handle_cheap_request(line, policy) {
+                        if let Err(e) = outbox.push_response(response) {
+                            debug!(peer = event.peer_id; "{}: failed to respond: {}", event.peer_id, e);
+                        }
+                        let _ = pending.try_recv(); // allow `recv_loop` to read more requests
+                        if let Some(tip) = tip {
+                            tips.insert(event.peer_id, tip);
+                        }
+                        return;
+                    }
+                }
             }
-        }
+            deferred.push(event);
+        },
+    );
+
+    for (peer, result, done) in results {
+        if let Err(e) = result {
+            error!(peer = peer.id; "{}: disconnecting due to {}", peer.id, e);
+            peer.disconnect();
+        } else if !done {
+            peers.insert(peer.id, peer);
+        } // otherwise, already disconnected
     }
-    let result = match peers.get_mut(&peer_id) {
-        Some(peer) => {
-            let responses = rpc.handle_requests(&mut peer.client, &lines);
-            peer.send(responses)
+    for (peer_id, tip) in tips {
+        if let Some(peer) = peers.get_mut(&peer_id) {
+            peer.client.subscribe_headers(tip);
         }
-        None => return, // unknown peer
+    }
+    deferred
+}
+
+fn handle_peer_requests(
+    rpc: &Rpc,
+    peer: &mut Peer,
+    limits: &Limits,
+    lines: &[String],
+) -> Result<()> {
+    let result = rpc.handle_requests(&mut peer.client, lines, |response| {
+        peer.outbox.push_response(response)
+    });
+    for scripthash in peer.client.take_unsubscribed() {
+        peer.outbox.forget(Topic::ScriptHash(scripthash));
+    }
+    // allow `recv_loop` to read more requests
+    peer.pending.try_iter().take(lines.len()).for_each(drop);
+    let violations = peer.client.take_violations();
+    let banned = match peer.stream.peer_addr() {
+        Ok(addr) => limits.violations(addr.ip(), violations, limits::unix_now()),
+        Err(_) => false,
     };
-    if let Err(e) = result {
-        error!("{}: disconnecting due to {}", peer_id, e);
-        peers.remove(&peer_id).unwrap().disconnect();
-    } else if done {
-        peers.remove(&peer_id); // already disconnected, just remove from peers' map
+    result.and_then(|()| {
+        ensure!(!banned, "too many protocol violations");
+        ensure!(
+            !peer.client.auth_failed(),
+            "too many authentication failures"
+        );
+        Ok(())
+    })
+}
+
+fn bind(addrs: &[SocketAddr], tcp: &TcpOptions) -> Result<Vec<TcpListener>> {
+    addrs
+        .iter()
+        .map(|addr| {
+            socket::bind(addr, tcp)
+                .with_context(|| format!("failed to bind Electrum RPC on {}", addr))
+        })
+        .collect()
+}
+
+/// Accepts new connections (one thread per listening address) and forwards them to the main loop
+#[derive(Clone)]
+struct Acceptor {
+    server_tx: Sender<Event>,
+    limits: Limits,
+    connections: Gauge,
+    refused: Counter,
+    disconnected: Counter,
+    suppressed: Counter,
+    malformed: Counter,
+    max_send_queue: usize, // in bytes
+    send_timeout: Duration,
+    max_request: usize,   // in bytes
+    max_broadcast: usize, // in bytes (allowed only for broadcast requests)
+    tcp: TcpOptions,
+    shaper: Shaper,
+    recv_buffers: BufferStats,
+    exit_flag: ExitFlag,            // stop accepting new connections when set
+    next_peer_id: Arc<AtomicUsize>, // peer IDs are unique across all listeners
+}
+
+impl Acceptor {
+    fn new(
+        server_tx: Sender<Event>,
+        limits: Limits,
+        (max_send_queue, send_timeout): (usize, Duration),
+        (max_request, max_broadcast): (usize, usize),
+        tcp: TcpOptions,
+        shaper: Shaper,
+        exit_flag: ExitFlag,
+        metrics: &Metrics,
+    ) -> Self {
+        Self {
+            server_tx,
+            limits,
+            max_send_queue,
+            send_timeout,
+            max_request,
+            max_broadcast,
+            tcp,
+            shaper,
+            recv_buffers: BufferStats::new(metrics, "recv"),
+            exit_flag,
+            connections: metrics.gauge("connections", "# of Electrum RPC connections", "type"),
+            refused: metrics.counter(
+                "refused_connections",
+                "# of refused Electrum RPC connections",
+                "reason",
+            ),
+            disconnected: metrics.counter(
+                "disconnected_peers",
+                "# of Electrum RPC peers disconnected for not reading their messages (or sending too large requests, or not answering keepalive probes)",
+                "reason",
+            ),
+            suppressed: metrics.counter(
+                "suppressed_notifications",
+                "# of scripthash notifications not sent, since the peer has already received the same status",
+                "reason",
+            ),
+            malformed: metrics.counter(
+                "malformed_requests",
+                "# of Electrum RPC requests rejected before being parsed (e.g. invalid UTF-8)",
+                "reason",
+            ),
+            next_peer_id: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn update_stats(&self) {
+        self.connections.set("active", self.limits.total() as f64);
     }
 }
 
-fn accept_loop(listener: TcpListener, server_tx: Sender<Event>) -> Result<()> {
-    for (peer_id, conn) in listener.incoming().enumerate() {
+fn accept_loop(
+    listener: TcpListener,
+    acceptor: Acceptor,
+    policy: Arc<ListenerPolicy>,
+) -> Result<()> {
+    for conn in listener.incoming() {
+        if acceptor.exit_flag.poll().is_err() {
+            break; // the server has stopped (the listener is closed when dropped)
+        }
         let stream = conn.context("failed to accept")?;
-        let tx = server_tx.clone();
+        let peer_id = acceptor.next_peer_id.fetch_add(1, Ordering::Relaxed);
+        let ip = match stream.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(e) => {
+                warn!(peer = peer_id; "{}: failed to get peer address: {}", peer_id, e);
+                continue;
+            }
+        };
+        // the listener's own limits are enforced in addition to the global ones
+        let accepted = acceptor
+            .limits
+            .accept(ip, limits::unix_now())
+            .and_then(|()| {
+                policy
+                    .limits
+                    .accept(ip, limits::unix_now())
+                    .inspect_err(|_| acceptor.limits.release(ip))
+            });
+        if let Err(refusal) = accepted {
+            debug!(peer = peer_id; "{}: refused connection from {}: {:?}", peer_id, ip, refusal);
+            acceptor.refused.inc(refusal.label());
+            if refusal == Refusal::ServerFull {
+                // wallets handle an explicit error better than a connection left hanging
+                refuse(&stream);
+            }
+            continue; // the connection is closed when `stream` is dropped
+        }
+        if let Err(e) = socket::configure(&stream, &acceptor.tcp) {
+            warn!(peer = peer_id; "{}: failed to set TCP options: {}", peer_id, e);
+        }
+        acceptor.update_stats();
+        let (acceptor, policy) = (acceptor.clone(), Arc::clone(&policy));
         spawn("recv_loop", move || {
-            let result = recv_loop(peer_id, &stream, tx);
+            let result = recv_loop(peer_id, ip, &stream, &acceptor, &policy);
             if let Err(e) = stream.shutdown(Shutdown::Read) {
-                warn!("{}: failed to shutdown TCP receiving {}", peer_id, e)
+                warn!(peer = peer_id; "{}: failed to shutdown TCP receiving {}", peer_id, e)
             }
+            policy.limits.release(ip);
+            acceptor.limits.release(ip);
+            acceptor.update_stats();
             result
         });
     }
     Ok(())
 }
 
-fn recv_loop(peer_id: usize, stream: &TcpStream, server_tx: Sender<Event>) -> Result<()> {
-    let msg = Message::New(stream.try_clone()?);
-    server_tx.send(Event { peer_id, msg })?;
+fn refuse(mut stream: &TcpStream) {
+    let msg = electrum::server_full_msg().to_string() + "\n";
+    let result = stream
+        .set_write_timeout(Some(REFUSAL_TIMEOUT))
+        .and_then(|()| stream.write_all(msg.as_bytes()))
+        .and_then(|()| stream.shutdown(Shutdown::Both));
+    if let Err(e) = result {
+        debug!("failed to send refusal: {}", e);
+    }
+}
+
+/// Total size of the connections' buffers (exported by `electrs_connection_buffers` metric)
+#[derive(Clone)]
+struct BufferStats {
+    bytes: Arc<AtomicUsize>,
+    gauge: Gauge,
+    label: &'static str,
+}
+
+impl BufferStats {
+    fn new(metrics: &Metrics, label: &'static str) -> Self {
+        Self {
+            bytes: Arc::default(),
+            gauge: metrics.gauge(
+                "connection_buffers",
+                "Total size of Electrum RPC connections' buffers (in bytes)",
+                "type",
+            ),
+            label,
+        }
+    }
+
+    fn update(&self, old: usize, new: usize) {
+        let bytes = if new >= old {
+            self.bytes.fetch_add(new - old, Ordering::Relaxed) + (new - old)
+        } else {
+            self.bytes.fetch_sub(old - new, Ordering::Relaxed) - (old - new)
+        };
+        self.gauge.set(self.label, bytes as f64);
+    }
+}
+
+enum Received {
+    Line(String),
+    TooLarge(usize),    // # of bytes received before exceeding the limit
+    InvalidUtf8(usize), // # of bytes in the line
+    Disconnected,
+}
+
+/// Reads a peer's requests (one per line), limiting the size of a single line.
+///
+/// Only broadcast requests may exceed `max_bytes` (up to `max_broadcast` bytes), which is checked
+/// using their first `max_bytes` bytes - so other requests are never buffered beyond the limit.
+struct LineReader<R> {
+    reader: BufReader<R>,
+    line: Vec<u8>,
+    max_bytes: usize,
+    max_broadcast: usize,
+    broadcast: Option<bool>, // whether the current line is a broadcast request (once checked)
+    stats: BufferStats,
+    accounted: usize, // # of bytes reported to `stats`
+}
+
+impl<R: Read> LineReader<R> {
+    fn new(inner: R, (max_bytes, max_broadcast): (usize, usize), stats: BufferStats) -> Self {
+        let mut result = Self {
+            reader: BufReader::new(inner),
+            line: Vec::new(),
+            max_bytes,
+            max_broadcast: max_broadcast.max(max_bytes),
+            broadcast: None,
+            stats,
+            accounted: 0,
+        };
+        result.update_stats();
+        result
+    }
+
+    fn update_stats(&mut self) {
+        let bytes = self.reader.capacity() + self.line.capacity();
+        self.stats.update(self.accounted, bytes);
+        self.accounted = bytes;
+    }
+
+    /// Returns the next line (without its "\n" or "\r\n" suffix), like `BufRead::lines()`
+    fn recv(&mut self) -> io::Result<Received> {
+        self.line.clear();
+        self.broadcast = None;
+        let result = self.fill_line();
+        if self.line.capacity() > LINE_BUFFER_SHRINK_SIZE {
+            self.line = Vec::new(); // don't retain the memory of an unusually large request
+        }
+        self.update_stats();
+        result
+    }
+
+    fn fill_line(&mut self) -> io::Result<Received> {
+        loop {
+            let available = match self.reader.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                // the last line may be unterminated
+                return match self.line.is_empty() {
+                    true => Ok(Received::Disconnected),
+                    false => self.take_line(),
+                };
+            }
+            let (chunk, found) = match available.iter().position(|b| *b == b'\n') {
+                Some(i) => (&available[..i], true),
+                None => (available, false),
+            };
+            let total = self.line.len() + chunk.len();
+            let limit = match self.broadcast {
+                Some(true) => self.max_broadcast,
+                Some(false) | None => self.max_bytes,
+            };
+            if total > limit {
+                if self.broadcast.is_some() {
+                    return Ok(Received::TooLarge(total));
+                }
+                // receive the line up to `max_bytes`, and check whether it may exceed it
+                let prefix = self.max_bytes - self.line.len();
+                self.line.extend_from_slice(&chunk[..prefix]);
+                self.reader.consume(prefix);
+                self.broadcast = Some(electrum::is_broadcast_request(&self.line));
+                continue;
+            }
+            self.line.extend_from_slice(chunk);
+            let consumed = chunk.len() + usize::from(found);
+            self.reader.consume(consumed);
+            if self.reader.capacity() + self.line.capacity() != self.accounted {
+                self.update_stats(); // the line buffer has grown
+            }
+            if found {
+                return self.take_line();
+            }
+        }
+    }
+
+    fn take_line(&mut self) -> io::Result<Received> {
+        if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+        }
+        match std::str::from_utf8(&self.line) {
+            Ok(line) => Ok(Received::Line(line.to_owned())),
+            Err(_) => Ok(Received::InvalidUtf8(self.line.len())),
+        }
+    }
+}
+
+impl<R> Drop for LineReader<R> {
+    fn drop(&mut self) {
+        self.stats.update(self.accounted, 0);
+    }
+}
+
+fn recv_loop(
+    peer_id: usize,
+    ip: IpAddr,
+    stream: &TcpStream,
+    acceptor: &Acceptor,
+    policy: &Arc<ListenerPolicy>,
+) -> Result<()> {
+    let server_tx = &acceptor.server_tx;
+    let (pending_tx, pending) = bounded(MAX_PENDING_REQUESTS);
+    let outbox = Outbox::new(
+        acceptor.max_send_queue,
+        acceptor.disconnected.clone(),
+        acceptor.suppressed.clone(),
+    );
+    {
+        let outbox = outbox.clone();
+        let stream = stream.try_clone()?;
+        let timeout = acceptor.send_timeout;
+        let throttle = acceptor.shaper.throttle(ip);
+        spawn("send_loop", move || {
+            outbox.send_loop(peer_id, stream, timeout, throttle)
+        });
+    }
+    let conn = Connection {
+        stream: stream.try_clone()?,
+        pending,
+        outbox: outbox.clone(),
+        policy: Arc::clone(policy),
+    };
+    server_tx.send(Event {
+        peer_id,
+        msg: Message::New(conn),
+    })?;
 
     let mut first_line = true;
-    for line in BufReader::new(stream).lines() {
-        if let Err(e) = &line {
-            if first_line && e.kind() == std::io::ErrorKind::InvalidData {
-                warn!("InvalidData on first line may indicate client attempted to connect using SSL when server expects unencrypted communication.")
+    // only broadcast requests may exceed `max_request` (e.g. a large coinjoin transaction)
+    let max_line = (acceptor.max_request, acceptor.max_broadcast);
+    let mut reader = LineReader::new(stream, max_line, acceptor.recv_buffers.clone());
+    loop {
+        let received = match reader.recv() {
+            Ok(received) => received,
+            // e.g. a dead peer detected by TCP keepalive, handled like a disconnection
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                debug!(peer = peer_id; "{}: keepalive timed out: {}", peer_id, e);
+                acceptor.disconnected.inc("keepalive_timeout");
+                break;
+            }
+            Err(e) => return Err(e).with_context(|| format!("{}: recv failed", peer_id)),
+        };
+        let line = match received {
+            Received::Line(line) => line,
+            Received::TooLarge(bytes) => {
+                warn!(
+                    peer = peer_id;
+                    "{}: disconnecting due to a request larger than {} bytes ({} bytes received)",
+                    peer_id, acceptor.max_request, bytes
+                );
+                acceptor.disconnected.inc("request_too_large");
+                acceptor.malformed.inc("too_large");
+                let _ = outbox.push_response(electrum::parse_error_msg().to_string());
+                break;
             }
+            Received::InvalidUtf8(bytes) => {
+                acceptor.malformed.inc("invalid_utf8");
+                let _ = outbox.push_response(electrum::parse_error_msg().to_string());
+                if first_line {
+                    warn!(
+                        peer = peer_id;
+                        "{}: disconnecting due to a non-UTF-8 first line ({} bytes), the client may be using SSL while the server expects unencrypted communication",
+                        peer_id, bytes
+                    );
+                    acceptor.disconnected.inc("invalid_utf8");
+                    break;
+                }
+                warn!(peer = peer_id; "{}: non-UTF-8 request ({} bytes)", peer_id, bytes);
+                if acceptor.limits.violations(ip, 1, limits::unix_now()) {
+                    acceptor.disconnected.inc("banned");
+                    break;
+                }
+                continue;
+            }
+            Received::Disconnected => {
+                debug!(peer = peer_id; "{}: disconnected", peer_id);
+                break;
+            }
+        };
+        if electrum::is_sensitive_request(&line, first_line) {
+            debug!(peer = peer_id; "{}: recv <sensitive>", peer_id);
+        } else {
+            debug!(peer = peer_id; "{}: recv {}", peer_id, line);
         }
-        let line = line.with_context(|| format!("{}: recv failed", peer_id))?;
-        debug!("{}: recv {}", peer_id, line);
+        // blocks while too many requests are pending (e.g. when the peer doesn't read responses)
+        pending_tx
+            .send(())
+            .with_context(|| format!("{}: peer removed", peer_id))?;
         let msg = Message::Request(line);
         server_tx.send(Event { peer_id, msg })?;
         first_line = false;
     }
 
-    debug!("{}: disconnected", peer_id);
+    drop(reader); // release the buffers before the connection is removed
     let msg = Message::Done;
     server_tx.send(Event { peer_id, msg })?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        accept_loop, bind, poll_duration, refuse, sync_retry_delay, Acceptor, BufferStats, Event,
+        LineReader, Message, QueryPool, Received, CATCHUP_TIP_AGE, LINE_BUFFER_SHRINK_SIZE,
+        MAX_SYNC_RETRY_DELAY,
+    };
+    use crate::{
+        limits::Limits, listener::ListenerPolicy, metrics::Metrics, shaper::Shaper,
+        signals::ExitFlag, socket::TcpOptions, thread::spawn,
+    };
+    use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::SocketAddr;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{atomic::Ordering, Arc};
+    use std::time::Duration;
+
+    #[test]
+    fn test_poll_duration() {
+        let wait = Duration::from_secs(10);
+        assert_eq!(poll_duration(wait, 0), wait); // also a future-dated tip
+        assert_eq!(poll_duration(wait, CATCHUP_TIP_AGE), wait);
+        assert_eq!(
+            poll_duration(wait, CATCHUP_TIP_AGE + 1),
+            Duration::from_secs(1)
+        );
+        let short = Duration::from_millis(100);
+        assert_eq!(poll_duration(short, CATCHUP_TIP_AGE + 1), short);
+    }
+
+    #[test]
+    fn test_sync_retry_delay() {
+        assert_eq!(sync_retry_delay(0), Duration::from_secs(1));
+        assert_eq!(sync_retry_delay(1), Duration::from_secs(2));
+        assert_eq!(sync_retry_delay(5), Duration::from_secs(32));
+        assert_eq!(sync_retry_delay(6), MAX_SYNC_RETRY_DELAY);
+        assert_eq!(sync_retry_delay(u32::MAX), MAX_SYNC_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_multiple_listeners() {
+        let addrs = [
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+        ];
+        let listeners = bind(&addrs, &TcpOptions::default()).unwrap();
+        let addrs: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        assert_ne!(addrs[0], addrs[1]);
+
+        let (server_tx, server_rx) = unbounded();
+        let limits = Limits::new(None, None, None).unwrap();
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let send_limits = (1 << 20, Duration::from_secs(10));
+        let acceptor = Acceptor::new(
+            server_tx,
+            limits,
+            send_limits,
+            (1 << 20, 1 << 20),
+            TcpOptions::default(),
+            Shaper::new(None, None, &metrics),
+            ExitFlag::new(),
+            &metrics,
+        );
+        for listener in listeners {
+            let acceptor = acceptor.clone();
+            spawn("accept_loop", || {
+                accept_loop(listener, acceptor, Arc::default())
+            });
+        }
+
+        let _clients: Vec<TcpStream> = addrs
+            .iter()
+            .map(|addr| {
+                let mut client = TcpStream::connect(addr).unwrap();
+                writeln!(client, "request to {}", addr).unwrap();
+                client
+            })
+            .collect();
+
+        // both connections are handled by the same channel, using unique peer IDs
+        let mut requests = HashMap::<usize, String>::new();
+        let mut conns = vec![]; // keep the connections, so `recv_loop` continues reading
+        while requests.len() < 2 {
+            let Event { peer_id, msg } = server_rx.recv().unwrap();
+            match msg {
+                Message::New(conn) => {
+                    assert!(!requests.contains_key(&peer_id));
+                    assert!(conn.stream.nodelay().unwrap()); // TCP options are set on accept
+                    conns.push(conn);
+                }
+                Message::Request(line) => assert!(requests.insert(peer_id, line).is_none()),
+                Message::Done => panic!("unexpected disconnection"),
+            }
+        }
+        let mut lines: Vec<String> = requests.into_values().collect();
+        lines.sort();
+        let mut expected: Vec<String> = addrs
+            .iter()
+            .map(|addr| format!("request to {}", addr))
+            .collect();
+        expected.sort();
+        assert_eq!(lines, expected);
+
+        // binding an address in use fails, naming the address
+        let err = bind(&addrs[..1], &TcpOptions::default()).unwrap_err();
+        assert!(err.to_string().contains(&addrs[0].to_string()), "{}", err);
+    }
+
+    #[test]
+    fn test_listener_policies() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listeners = bind(&[addr, addr], &TcpOptions::default()).unwrap();
+        let addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let disabled =
+            |methods: &[&str]| -> Vec<String> { methods.iter().map(|m| m.to_string()).collect() };
+        let local = ListenerPolicy {
+            disabled_methods: disabled(&["server.peers.subscribe"]),
+            ..ListenerPolicy::default()
+        };
+        let public = ListenerPolicy {
+            limits: Limits::new(Some(1), None, None).unwrap(),
+            disabled_methods: disabled(&["blockchain.transaction.broadcast", "server.banner"]),
+            admin: false,
+            ..ListenerPolicy::default()
+        };
+
+        let (server_tx, server_rx) = unbounded();
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let acceptor = Acceptor::new(
+            server_tx,
+            Limits::new(None, None, None).unwrap(),
+            (1 << 20, Duration::from_secs(10)),
+            (1 << 20, 1 << 20),
+            TcpOptions::default(),
+            Shaper::new(None, None, &metrics),
+            ExitFlag::new(),
+            &metrics,
+        );
+        for (listener, policy) in listeners.into_iter().zip([local, public]) {
+            let acceptor = acceptor.clone();
+            spawn("accept_loop", || {
+                accept_loop(listener, acceptor, Arc::new(policy))
+            });
+        }
+
+        // each connection carries the policy of the listener which accepted it
+        let new_conn = || match server_rx.recv().unwrap().msg {
+            Message::New(conn) => conn,
+            _ => panic!("expected a new connection"),
+        };
+        let _local_client = TcpStream::connect(addrs[0]).unwrap();
+        let local_conn = new_conn();
+        let _public_client = TcpStream::connect(addrs[1]).unwrap();
+        let public_conn = new_conn();
+
+        let policy = &local_conn.policy;
+        assert!(policy.disables("server.peers.subscribe"));
+        assert!(!policy.disables("blockchain.transaction.broadcast"));
+        assert!(!policy.disables("server.banner"));
+        assert!(!policy.disables("electrs.bans.list"));
+
+        let policy = &public_conn.policy;
+        assert!(!policy.disables("server.peers.subscribe"));
+        assert!(policy.disables("blockchain.transaction.broadcast"));
+        assert!(policy.disables("server.banner"));
+        assert!(policy.disables("electrs.bans.list"));
+
+        // the public listener is full, while the local one still accepts connections
+        let public_client = TcpStream::connect(addrs[1]).unwrap();
+        let mut line = String::new();
+        BufReader::new(public_client).read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["error"]["code"], json!(-32000));
+        let _local_client = TcpStream::connect(addrs[0]).unwrap();
+        assert!(new_conn().policy.disables("server.peers.subscribe"));
+        assert_eq!(acceptor.limits.total(), 3);
+    }
+
+    fn start_acceptor(
+        max_request: usize,
+        max_broadcast: usize,
+    ) -> (SocketAddr, Acceptor, Receiver<Event>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server_tx, server_rx) = unbounded();
+        let limits = Limits::new(None, None, None).unwrap();
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let send_limits = (1 << 20, Duration::from_secs(10));
+        let acceptor = Acceptor::new(
+            server_tx,
+            limits,
+            send_limits,
+            (max_request, max_broadcast),
+            TcpOptions::default(),
+            Shaper::new(None, None, &metrics),
+            ExitFlag::new(),
+            &metrics,
+        );
+        {
+            let acceptor = acceptor.clone();
+            spawn("accept_loop", || {
+                accept_loop(listener, acceptor, Arc::default())
+            });
+        }
+        (addr, acceptor, server_rx)
+    }
+
+    #[test]
+    fn test_request_too_large() {
+        let (addr, acceptor, server_rx) = start_acceptor(1000, 1000);
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[b'x'; 2000]).unwrap(); // no newline is ever sent
+
+        let conn = match server_rx.recv().unwrap().msg {
+            Message::New(conn) => conn,
+            _ => panic!("expected a new connection"),
+        };
+        assert!(matches!(server_rx.recv().unwrap().msg, Message::Done));
+        assert_eq!(acceptor.recv_buffers.bytes.load(Ordering::Relaxed), 0);
+        conn.outbox.close(); // the peer is removed, and its queued messages are sent
+        drop(conn);
+
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response, parse_error());
+        // the connection is closed after the error is sent
+        assert_eq!(reader.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    fn parse_error() -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": {"code": -32700, "message": "parse error"}
+        })
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let (addr, _acceptor, server_rx) = start_acceptor(1000, 1000);
+        let new_conn = || match server_rx.recv().unwrap().msg {
+            Message::New(conn) => conn,
+            _ => panic!("expected a new connection"),
+        };
+        let request = || match server_rx.recv().unwrap().msg {
+            Message::Request(line) => line,
+            _ => panic!("expected a request"),
+        };
+
+        // a non-UTF-8 request is rejected, without disconnecting the peer
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"first\n\xff\xfe\nsecond\n").unwrap();
+        let _conn = new_conn();
+        assert_eq!(request(), "first");
+        assert_eq!(request(), "second");
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&line).unwrap(), parse_error());
+
+        // a non-UTF-8 first line (e.g. an SSL handshake) disconnects the peer
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"\x16\x03\x01\xff\n").unwrap();
+        let conn = new_conn();
+        assert!(matches!(server_rx.recv().unwrap().msg, Message::Done));
+        conn.outbox.close(); // the peer is removed, and its queued messages are sent
+        drop(conn);
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&line).unwrap(), parse_error());
+        assert_eq!(reader.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_random_bytes() {
+        use bitcoin::secp256k1::rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10 {
+            // random noise results in parse errors, bans or disconnections, but never in a panic
+            let (addr, _acceptor, server_rx) = start_acceptor(1000, 1000);
+            let data: Vec<u8> = (0..rng.gen_range(0..5000)).map(|_| rng.gen()).collect();
+            let mut client = TcpStream::connect(addr).unwrap();
+            let _ = client.write_all(&data); // the peer may be disconnected meanwhile
+            let _ = client.shutdown(std::net::Shutdown::Write);
+
+            let timeout = Duration::from_secs(10);
+            let conn = match server_rx.recv_timeout(timeout).unwrap().msg {
+                Message::New(conn) => conn,
+                _ => panic!("expected a new connection"),
+            };
+            loop {
+                match server_rx.recv_timeout(timeout).unwrap().msg {
+                    Message::Request(_) => conn.pending.try_iter().for_each(drop),
+                    Message::Done => break,
+                    Message::New(_) => panic!("unexpected connection"),
+                }
+            }
+            conn.outbox.close();
+            drop(conn);
+            for line in BufReader::new(client).lines() {
+                match line {
+                    Ok(line) => {
+                        assert_eq!(serde_json::from_str::<Value>(&line).unwrap(), parse_error())
+                    }
+                    Err(_) => break, // e.g. reset by the server
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_large_broadcast() {
+        let (addr, acceptor, server_rx) = start_acceptor(4 << 20, 4 << 20);
+        let mut client = TcpStream::connect(addr).unwrap();
+        let request = json!({
+            "id": 0,
+            "method": "blockchain.transaction.broadcast",
+            "params": ["00".repeat(1 << 20)], // a 1 MB transaction
+        })
+        .to_string();
+        let sender = {
+            let request = request.clone();
+            std::thread::spawn(move || {
+                writeln!(client, "{}", request).unwrap();
+                client
+            })
+        };
+
+        let _conn = match server_rx.recv().unwrap().msg {
+            Message::New(conn) => conn,
+            _ => panic!("expected a new connection"),
+        };
+        match server_rx.recv().unwrap().msg {
+            Message::Request(line) => assert!(line == request, "unexpected request"),
+            _ => panic!("expected a request"),
+        }
+        // the line buffer is released after an unusually large request
+        let bytes = acceptor.recv_buffers.bytes.load(Ordering::Relaxed);
+        assert!(
+            bytes > 0 && bytes < LINE_BUFFER_SHRINK_SIZE,
+            "{} bytes",
+            bytes
+        );
+
+        drop(sender.join().unwrap());
+        assert!(matches!(server_rx.recv().unwrap().msg, Message::Done));
+        assert_eq!(acceptor.recv_buffers.bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_broadcast_size_limit() {
+        let (addr, acceptor, server_rx) = start_acceptor(1000, 100_000);
+        let mut client = TcpStream::connect(addr).unwrap();
+        let request = |method: &str| {
+            json!({"id": 0, "method": method, "params": ["00".repeat(10_000)]}).to_string()
+        };
+        let broadcast = request("blockchain.transaction.broadcast");
+        writeln!(client, "{}", broadcast).unwrap();
+        writeln!(client, "{}", request("blockchain.transaction.get")).unwrap();
+
+        let conn = match server_rx.recv().unwrap().msg {
+            Message::New(conn) => conn,
+            _ => panic!("expected a new connection"),
+        };
+        match server_rx.recv().unwrap().msg {
+            Message::Request(line) => assert!(line == broadcast, "unexpected request"),
+            _ => panic!("expected a request"),
+        }
+        // other requests are still limited by `max_request`
+        assert!(matches!(server_rx.recv().unwrap().msg, Message::Done));
+        conn.outbox.close();
+        drop(conn);
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["error"]["code"], json!(-32700));
+        assert_eq!(acceptor.recv_buffers.bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_line_reader_limits() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let request = |method: &str| {
+            json!({"id": 0, "method": method, "params": ["00".repeat(50_000)]}).to_string()
+        };
+        let broadcast = request("blockchain.transaction.broadcast");
+        let other = request("blockchain.transaction.get");
+        let input = format!("{}\n{}\n", broadcast, other);
+        let stats = BufferStats::new(&metrics, "recv");
+        let mut reader = LineReader::new(std::io::Cursor::new(input), (1000, 200_000), stats);
+        match reader.recv().unwrap() {
+            Received::Line(line) => assert!(line == broadcast, "unexpected request"),
+            _ => panic!("expected a broadcast request"),
+        }
+        let received = reader.reader.get_ref().position() as usize;
+        assert!(matches!(reader.recv().unwrap(), Received::TooLarge(_)));
+        // other requests are refused without being buffered beyond the limit
+        assert!(reader.line.len() <= 1000);
+        let read = reader.reader.get_ref().position() as usize - received;
+        assert!(read <= 1000 + reader.reader.capacity(), "{}", read);
+    }
+
+    #[test]
+    fn test_refuse() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _addr) = listener.accept().unwrap();
+        refuse(&stream);
+
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            response,
+            json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": {"code": -32000, "message": "server full, try another server"}
+            })
+        );
+        // the connection is closed after the error is sent
+        assert_eq!(reader.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_query_pool_priority() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap();
+        let pool = QueryPool::new(2, &metrics).unwrap();
+        let (server_tx, server_rx) = unbounded();
+        // pings are sent while long histories are being computed
+        let sender = std::thread::spawn(move || {
+            for i in 0..10 {
+                std::thread::sleep(Duration::from_millis(20));
+                let msg = Message::Request("server.ping".to_owned());
+                server_tx.send(Event { peer_id: i, msg }).unwrap();
+            }
+        });
+        // the expensive requests hold both workers until all pings are answered (disconnecting
+        // `released`), so the pings can't be waiting for a worker
+        let (release_tx, released) = unbounded::<()>();
+        let mut release_tx = Some(release_tx);
+        let mut received = vec![];
+        let results = pool.run(
+            (0..4).map(|i| (1, i)).collect(),
+            |i| {
+                let result = released.recv_timeout(Duration::from_secs(10));
+                assert!(
+                    matches!(result, Err(RecvTimeoutError::Disconnected)),
+                    "request {} was not released by the pings",
+                    i
+                );
+                i
+            },
+            &server_rx,
+            |event| {
+                received.push(event.peer_id);
+                if received.len() == 10 {
+                    release_tx.take();
+                }
+            },
+        );
+        let mut results = results;
+        results.sort_unstable();
+        assert_eq!(results, vec![0, 1, 2, 3]);
+
+        sender.join().unwrap();
+        assert_eq!(received, (0..10).collect::<Vec<usize>>());
+        assert_eq!(pool.queued.load(std::sync::atomic::Ordering::Relaxed), 0);
+        assert_eq!(pool.busy.load(std::sync::atomic::Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use crossbeam_channel::{bounded, select, Receiver, Sender};
+use parking_lot::Mutex;
+use serde_json::Value;
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{
+    cache::Cache,
+    chain::Chain,
+    config::Config,
+    daemon::Node,
+    db::DBStore,
+    index::{Index, IndexOptions},
+    metrics::{Counter, Metrics},
+    status::{HistoryEntry, ScriptHashStatus},
+    tracker::Tracker,
+    types::ScriptHash,
+};
+
+/// The result of comparing a scripthash's answers (exported by `shadow_db_comparisons`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Match,
+    Mismatch,
+    /// The DBs' chains differ at their common height (e.g. the shadow DB is on a stale block)
+    Skipped,
+}
+
+impl Outcome {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Match => "match",
+            Self::Mismatch => "mismatch",
+            Self::Skipped => "skipped",
+        }
+    }
+}
+
+/// Differential testing (see `shadow_db`): the confirmed histories of sampled scripthash queries
+/// are recomputed by a background thread using both the served DB and a second (read-only) one,
+/// e.g. indexed by another electrs version or using another format. Mismatches are logged and
+/// counted, but the answers are always served using the primary DB.
+pub(crate) struct ShadowDb {
+    index: Index,
+    path: PathBuf,
+    sample_rate: u64, // 1 of every `sample_rate` queries is compared
+    max_per_sec: u32,
+    queries: AtomicU64,
+    window: Mutex<(Instant, u32)>, // the current second's start, and its # of sampled queries
+    tx: Sender<ScriptHash>,        // bounded, so comparisons never queue up
+    rx: Receiver<ScriptHash>,
+    comparisons: Counter,
+}
+
+impl ShadowDb {
+    /// Fails if the DB at `path` can't be opened (so electrs doesn't start without it).
+    pub(crate) fn open(config: &Config, path: &Path, metrics: &Metrics) -> Result<Self> {
+        let store =
+            DBStore::open_read_only(path, config.db_log_dir.as_deref(), config.db_parallelism)?;
+        store.check_network(config.network, config.genesis().block_hash())?;
+        let index_metrics = Metrics::unserved(); // not scraped
+        let index = Index::load(
+            store,
+            Chain::for_config(config),
+            &index_metrics,
+            &IndexOptions {
+                batch_size: config.index_batch_size,
+                lookup_limit: config.index_lookup_limit,
+                script_types: config.index_script_types.clone(),
+                recent_headers: None, // read-only DB
+                ..IndexOptions::default()
+            },
+        )
+        .context("failed to load shadow_db index")?;
+        warn!(
+            "comparing 1 of every {} scripthash queries using shadow_db {} (at height {})",
+            config.shadow_db_sample_rate,
+            path.display(),
+            index.chain().height()
+        );
+        Ok(Self::new(
+            index,
+            path.to_owned(),
+            config.shadow_db_sample_rate,
+            config.shadow_db_max_per_sec,
+            metrics,
+        ))
+    }
+
+    pub(crate) fn new(
+        index: Index,
+        path: PathBuf,
+        sample_rate: u64,
+        max_per_sec: u32,
+        metrics: &Metrics,
+    ) -> Self {
+        let (tx, rx) = bounded(max_per_sec.max(1) as usize);
+        Self {
+            index,
+            path,
+            sample_rate: sample_rate.max(1),
+            max_per_sec,
+            queries: AtomicU64::new(0),
+            window: Mutex::new((Instant::now(), 0)),
+            tx,
+            rx,
+            comparisons: metrics.counter(
+                "shadow_db_comparisons",
+                "# of scripthash queries compared using `shadow_db` (and of the dropped ones)",
+                "result",
+            ),
+        }
+    }
+
+    /// Called for each scripthash query (so it must be cheap): a sampled query is queued for
+    /// `run()`, unless `max_per_sec` queries were already sampled during the current second.
+    pub(crate) fn sample(&self, scripthash: ScriptHash) {
+        if self.queries.fetch_add(1, Ordering::Relaxed) % self.sample_rate != 0 {
+            return;
+        }
+        {
+            let mut window = self.window.lock();
+            let now = Instant::now();
+            if now.duration_since(window.0) >= Duration::from_secs(1) {
+                *window = (now, 0);
+            }
+            if window.1 >= self.max_per_sec {
+                self.comparisons.inc("dropped");
+                return;
+            }
+            window.1 += 1;
+        }
+        if self.tx.try_send(scripthash).is_err() {
+            self.comparisons.inc("dropped"); // the previous comparisons are still running
+        }
+    }
+
+    /// Compare the sampled queries' answers, until `stop` is disconnected. Failed comparisons
+    /// are logged (and counted), without stopping the server.
+    pub(crate) fn run(
+        &self,
+        tracker: &Tracker,
+        daemon: &dyn Node,
+        cache: &Cache,
+        stop: &Receiver<()>,
+    ) -> Result<()> {
+        loop {
+            let scripthash = select! {
+                recv(self.rx) -> scripthash => scripthash.expect("sender is owned"),
+                recv(stop) -> _ => return Ok(()),
+            };
+            match self.compare(tracker, daemon, cache, scripthash) {
+                Ok(outcome) => self.comparisons.inc(outcome.label()),
+                Err(e) => {
+                    warn!("shadow_db comparison of {} failed: {:#}", scripthash, e);
+                    self.comparisons.inc("failed");
+                }
+            }
+        }
+    }
+
+    /// Compare the confirmed histories of `scripthash` up to the DBs' common height, using the
+    /// served chain snapshot (so the shadow DB's rows above it are ignored).
+    pub(crate) fn compare(
+        &self,
+        tracker: &Tracker,
+        daemon: &dyn Node,
+        cache: &Cache,
+        scripthash: ScriptHash,
+    ) -> Result<Outcome> {
+        let primary = tracker.index();
+        let chain = primary.chain();
+        let shadow_chain = self.index.chain();
+        let height = chain.height().min(shadow_chain.height());
+        if chain.get_block_hash(height) != shadow_chain.get_block_hash(height) {
+            debug!(
+                "shadow_db {} is not on the served chain at height {}",
+                self.path.display(),
+                height
+            );
+            return Ok(Outcome::Skipped);
+        }
+        let history = |index: &Index| -> Result<Vec<Value>> {
+            let entries =
+                ScriptHashStatus::confirmed_history(scripthash, index, &chain, daemon, cache)?;
+            Ok(entries
+                .iter()
+                .filter(|entry| entry.height().is_some_and(|h| h <= height))
+                .map(HistoryEntry::to_value_with_pos)
+                .collect())
+        };
+        let expected = history(&primary).context("primary DB lookup failed")?;
+        let actual = history(&self.index).context("shadow_db lookup failed")?;
+        if expected == actual {
+            return Ok(Outcome::Match);
+        }
+        let missing = |entries: &[Value], other: &[Value]| -> Vec<Value> {
+            entries
+                .iter()
+                .filter(|entry| !other.contains(entry))
+                .cloned()
+                .collect()
+        };
+        warn!(
+            "shadow_db mismatch for {} up to height {}: {} entries in the primary DB, \
+            {} in shadow_db {} (only in the primary DB: {}, only in shadow_db: {})",
+            scripthash,
+            height,
+            expected.len(),
+            actual.len(),
+            self.path.display(),
+            Value::from(missing(&expected, &actual)),
+            Value::from(missing(&actual, &expected)),
+        );
+        Ok(Outcome::Mismatch)
+    }
+
+    /// Take the queued scripthashes, without comparing them
+    #[cfg(test)]
+    pub(crate) fn take_sampled(&self) -> Vec<ScriptHash> {
+        self.rx.try_iter().collect()
+    }
+}
@@ -0,0 +1,136 @@
+use parking_lot::Mutex;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{Counter, Metrics};
+
+/// Responses are sent in chunks of this size, so a large response can't exceed the budget in a
+/// single write (and the buckets' burst is limited to a single chunk).
+pub(crate) const CHUNK_SIZE: usize = 16 << 10;
+
+/// Messages up to this size (e.g. `server.ping` responses) are never delayed, although they are
+/// still accounted for.
+const EXEMPT_SIZE: usize = 1 << 10;
+
+/// A token bucket refilled at `rate` bytes per second, holding at most a single chunk.
+/// Tokens may be taken before they are available - the caller waits until the debt is repaid.
+struct Bucket {
+    rate: f64, // in bytes per second
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: usize) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: CHUNK_SIZE as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Returns the delay required before sending `bytes`
+    fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = self.updated.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate).min(CHUNK_SIZE as f64);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+}
+
+/// Limits the upload rate of Electrum RPC responses (globally and per connection).
+#[derive(Clone)]
+pub(crate) struct Shaper {
+    global: Option<Arc<Mutex<Bucket>>>,
+    peer_rate: Option<usize>, // in bytes per second
+    sent: Counter,
+}
+
+impl Shaper {
+    /// Rates are in bytes per second (`None` disables the limit)
+    pub(crate) fn new(
+        global_rate: Option<usize>,
+        peer_rate: Option<usize>,
+        metrics: &Metrics,
+    ) -> Self {
+        Self {
+            global: global_rate.map(|rate| Arc::new(Mutex::new(Bucket::new(rate)))),
+            peer_rate,
+            sent: metrics.counter(
+                "sent_bytes",
+                "# of bytes sent to Electrum RPC peers",
+                "class",
+            ),
+        }
+    }
+
+    /// Create a throttle for a new connection (its class is used for accounting)
+    pub(crate) fn throttle(&self, ip: IpAddr) -> Throttle {
+        Throttle {
+            global: self.global.clone(),
+            peer: self.peer_rate.map(Bucket::new),
+            sent: self.sent.clone(),
+            class: if ip.is_loopback() { "local" } else { "remote" },
+        }
+    }
+}
+
+/// Rate limits of a single connection
+pub(crate) struct Throttle {
+    global: Option<Arc<Mutex<Bucket>>>,
+    peer: Option<Bucket>,
+    sent: Counter,
+    class: &'static str,
+}
+
+impl Throttle {
+    /// Returns the delay required before sending a chunk of `bytes` (out of a message of `total`
+    /// bytes)
+    pub(crate) fn take(&mut self, bytes: usize, total: usize) -> Duration {
+        let now = Instant::now();
+        let global = self.global.as_ref().map(|b| b.lock().take(bytes, now));
+        let peer = self.peer.as_mut().map(|b| b.take(bytes, now));
+        if total <= EXEMPT_SIZE {
+            return Duration::ZERO;
+        }
+        global.into_iter().chain(peer).max().unwrap_or_default()
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.sent.inc_by(self.class, bytes as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bucket, CHUNK_SIZE};
+    use std::time::Duration;
+
+    fn millis(delay: Duration) -> u64 {
+        (delay.as_secs_f64() * 1000.0).round() as u64
+    }
+
+    #[test]
+    fn test_bucket() {
+        let mut bucket = Bucket::new(1000);
+        let start = bucket.updated;
+        // a single chunk may be sent without waiting
+        assert_eq!(millis(bucket.take(CHUNK_SIZE, start)), 0);
+        assert_eq!(millis(bucket.take(500, start)), 500);
+        // the debt is repaid over time
+        let later = start + Duration::from_millis(200);
+        assert_eq!(millis(bucket.take(500, later)), 800);
+        // the refill is limited to a single chunk
+        let idle = later + Duration::from_secs(3600);
+        assert_eq!(millis(bucket.take(CHUNK_SIZE, idle)), 0);
+        assert_eq!(millis(bucket.take(1, idle)), 1);
+        // an earlier timestamp doesn't add tokens
+        assert_eq!(millis(bucket.take(1, start)), 2);
+    }
+}
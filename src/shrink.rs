@@ -0,0 +1,72 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash};
+
+/// Collections at most this large are never shrunk (to avoid re-allocating small ones)
+pub(crate) const MIN_CAPACITY: usize = 64;
+
+/// Collections are shrunk when less than a quarter of their capacity is used
+const MAX_SPARSITY: usize = 4;
+
+/// Release the memory of collections which have grown and then mostly emptied (e.g. by a client
+/// subscribing and unsubscribing many scripthashes), since removing entries doesn't shrink them.
+///
+/// The capacity is reduced to twice the current length, so repeated removals shrink a collection
+/// only O(log n) times (and the amortized cost of `shrink()` after each removal is O(1)).
+pub(crate) trait Shrink {
+    fn shrink(&mut self);
+}
+
+fn is_sparse(len: usize, capacity: usize) -> bool {
+    capacity > MIN_CAPACITY && len.saturating_mul(MAX_SPARSITY) < capacity
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> Shrink for HashMap<K, V, S> {
+    fn shrink(&mut self) {
+        if is_sparse(self.len(), self.capacity()) {
+            self.shrink_to(self.len() * 2);
+        }
+    }
+}
+
+impl<T: Eq + Hash, S: BuildHasher> Shrink for HashSet<T, S> {
+    fn shrink(&mut self) {
+        if is_sparse(self.len(), self.capacity()) {
+            self.shrink_to(self.len() * 2);
+        }
+    }
+}
+
+impl<T> Shrink for VecDeque<T> {
+    fn shrink(&mut self) {
+        if is_sparse(self.len(), self.capacity()) {
+            self.shrink_to(self.len() * 2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Shrink, MIN_CAPACITY};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_shrink() {
+        let mut map: HashMap<usize, usize> = HashMap::new();
+        map.extend((0..100_000).map(|i| (i, i)));
+        let grown = map.capacity();
+
+        // removing most of the entries shrinks the map gradually
+        for i in 0..99_990 {
+            map.remove(&i);
+            map.shrink();
+            assert!(map.capacity() <= 4 * map.len() + 2 * MIN_CAPACITY);
+        }
+        assert!(map.capacity() < grown / 100);
+
+        // small collections are kept as they are
+        map.clear();
+        map.shrink();
+        assert!(map.capacity() > 0);
+        assert!(map.capacity() <= 2 * MIN_CAPACITY);
+    }
+}
@@ -1,6 +1,6 @@
 #[cfg(not(windows))]
 use anyhow::Context;
-use crossbeam_channel::{unbounded, Receiver};
+use crossbeam_channel::{unbounded, Receiver, Sender};
 #[cfg(not(windows))]
 use signal_hook::consts::signal::*;
 #[cfg(not(windows))]
@@ -32,7 +32,7 @@ pub(crate) struct ExitFlag {
 }
 
 impl ExitFlag {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         ExitFlag {
             flag: Arc::new(AtomicBool::new(false)),
         }
@@ -52,30 +52,44 @@ impl ExitFlag {
 }
 
 pub(crate) struct Signal {
+    tx: Sender<()>,
     rx: Receiver<()>,
     exit: ExitFlag,
+    reload: Arc<AtomicBool>,
 }
 
 impl Signal {
+    /// Notified only via [`Signal::exit`] (used when electrs is embedded in another process,
+    /// so the process' signals are left to the host).
+    pub fn manual() -> Signal {
+        let (tx, rx) = unbounded();
+        Signal {
+            tx,
+            rx,
+            exit: ExitFlag::new(),
+            reload: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
     #[cfg(not(windows))]
     pub fn new() -> Signal {
         let ids = vec![
             SIGINT, SIGTERM,
             SIGUSR1, // allow external triggering (e.g. via bitcoind `blocknotify`)
+            SIGHUP,  // reload
         ];
-        let (tx, rx) = unbounded();
-        let result = Signal {
-            rx,
-            exit: ExitFlag::new(),
-        };
+        let result = Signal::manual();
+        let tx = result.tx.clone();
 
         let exit_flag = result.exit.clone();
+        let reload = result.reload.clone();
         let mut signals = Signals::new(ids).expect("failed to register signal hook");
         spawn("signal", move || {
             for id in &mut signals {
                 info!("notified via SIG{}", id);
                 match id {
                     SIGUSR1 => (),
+                    SIGHUP => reload.store(true, Ordering::Relaxed),
                     _ => exit_flag.set(),
                 };
                 tx.send(()).context("failed to send signal")?;
@@ -87,11 +101,8 @@ impl Signal {
 
     #[cfg(windows)]
     pub fn new() -> Signal {
-        let (tx, rx) = unbounded();
-        let result = Signal {
-            rx,
-            exit: ExitFlag::new(),
-        };
+        let result = Signal::manual();
+        let tx = result.tx.clone();
 
         let exit_flag = result.exit.clone();
 
@@ -106,6 +117,12 @@ impl Signal {
         result
     }
 
+    /// Request a graceful shutdown (like SIGINT/SIGTERM)
+    pub fn exit(&self) {
+        self.exit.set();
+        let _ = self.tx.send(());
+    }
+
     pub fn receiver(&self) -> &Receiver<()> {
         &self.rx
     }
@@ -113,4 +130,9 @@ impl Signal {
     pub fn exit_flag(&self) -> &ExitFlag {
         &self.exit
     }
+
+    /// Returns `true` (once) if a reload was requested since the last call (via SIGHUP).
+    pub fn take_reload(&self) -> bool {
+        self.reload.swap(false, Ordering::Relaxed)
+    }
 }
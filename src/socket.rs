@@ -0,0 +1,217 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Keepalive probes of idle connections, so peers which silently went away (e.g. behind a NAT
+/// which dropped the connection) are detected and disconnected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    pub idle: Duration,     // before the first probe
+    pub interval: Duration, // between unanswered probes
+    pub count: u32,         // of unanswered probes, before disconnecting
+}
+
+/// TCP options of the Electrum RPC listeners and their accepted connections (see `tcp_*`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpOptions {
+    pub nodelay: bool,
+    pub keepalive: Option<Keepalive>,
+    pub backlog: u32,
+}
+
+/// Without keepalive probes (so the OS default is used)
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            backlog: 128,
+        }
+    }
+}
+
+/// Bind a listener using the configured backlog (of connections not accepted yet)
+pub(crate) fn bind(addr: &SocketAddr, options: &TcpOptions) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)?;
+    listen(&listener, options.backlog)?;
+    Ok(listener)
+}
+
+/// Apply the options to an accepted connection
+pub(crate) fn configure(stream: &TcpStream, options: &TcpOptions) -> io::Result<()> {
+    stream.set_nodelay(options.nodelay)?;
+    match &options.keepalive {
+        Some(keepalive) => set_keepalive(stream, keepalive),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use super::Keepalive;
+
+    use libc::{c_int, c_void, socklen_t};
+    use std::io;
+    use std::os::unix::io::RawFd;
+
+    pub(super) fn check(ret: c_int) -> io::Result<()> {
+        match ret {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub(super) fn setsockopt(fd: RawFd, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+        let len = std::mem::size_of::<c_int>() as socklen_t;
+        let ptr = &value as *const c_int as *const c_void;
+        // SAFETY: `value` outlives the call, and its size is passed as `len`
+        check(unsafe { libc::setsockopt(fd, level, name, ptr, len) })
+    }
+
+    #[cfg(all(test, target_os = "linux"))]
+    pub(super) fn getsockopt(fd: RawFd, level: c_int, name: c_int) -> io::Result<c_int> {
+        let mut value: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as socklen_t;
+        let ptr = &mut value as *mut c_int as *mut c_void;
+        // SAFETY: `value` outlives the call, and its size is passed as `len`
+        check(unsafe { libc::getsockopt(fd, level, name, ptr, &mut len) })?;
+        Ok(value)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    pub(super) const TCP_KEEPIDLE: c_int = libc::TCP_KEEPIDLE;
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub(super) const TCP_KEEPIDLE: c_int = libc::TCP_KEEPALIVE;
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "ios"
+    ))]
+    pub(super) fn set_keepalive_timing(fd: RawFd, keepalive: &Keepalive) -> io::Result<()> {
+        let secs = |duration: std::time::Duration| {
+            c_int::try_from(duration.as_secs().max(1)).unwrap_or(c_int::MAX)
+        };
+        let count = c_int::try_from(keepalive.count).unwrap_or(c_int::MAX);
+        setsockopt(fd, libc::IPPROTO_TCP, TCP_KEEPIDLE, secs(keepalive.idle))?;
+        setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPINTVL,
+            secs(keepalive.interval),
+        )?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count)
+    }
+
+    /// The system's keepalive timing is used
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "ios"
+    )))]
+    pub(super) fn set_keepalive_timing(_fd: RawFd, _keepalive: &Keepalive) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn listen(listener: &TcpListener, backlog: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let backlog = libc::c_int::try_from(backlog).unwrap_or(libc::c_int::MAX);
+    // SAFETY: listening again only updates the socket's backlog
+    sys::check(unsafe { libc::listen(listener.as_raw_fd(), backlog) })
+}
+
+/// The default backlog is used
+#[cfg(not(unix))]
+fn listen(_listener: &TcpListener, _backlog: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_keepalive(stream: &TcpStream, keepalive: &Keepalive) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let fd = stream.as_raw_fd();
+    sys::setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    sys::set_keepalive_timing(fd, keepalive)
+}
+
+/// Keepalive is not supported on this platform
+#[cfg(not(unix))]
+fn set_keepalive(_stream: &TcpStream, _keepalive: &Keepalive) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind, configure, Keepalive, TcpOptions};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    fn accept(options: &TcpOptions) -> (TcpStream, TcpStream) {
+        let listener = bind(&"127.0.0.1:0".parse().unwrap(), options).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _addr) = listener.accept().unwrap();
+        configure(&stream, options).unwrap();
+        (stream, client)
+    }
+
+    #[test]
+    fn test_nodelay() {
+        let (stream, _client) = accept(&TcpOptions::default());
+        assert!(stream.nodelay().unwrap());
+        let options = TcpOptions {
+            nodelay: false,
+            ..TcpOptions::default()
+        };
+        let (stream, _client) = accept(&options);
+        assert!(!stream.nodelay().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_keepalive() {
+        use super::sys::{getsockopt, TCP_KEEPIDLE};
+        use std::os::unix::io::AsRawFd;
+
+        let get =
+            |stream: &TcpStream, level, name| getsockopt(stream.as_raw_fd(), level, name).unwrap();
+        let (stream, _client) = accept(&TcpOptions::default());
+        assert_eq!(get(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+
+        let options = TcpOptions {
+            keepalive: Some(Keepalive {
+                idle: Duration::from_secs(120),
+                interval: Duration::from_secs(15),
+                count: 4,
+            }),
+            ..TcpOptions::default()
+        };
+        let (stream, _client) = accept(&options);
+        assert_eq!(get(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        assert_eq!(get(&stream, libc::IPPROTO_TCP, TCP_KEEPIDLE), 120);
+        assert_eq!(get(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 15);
+        assert_eq!(get(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 4);
+    }
+
+    #[test]
+    fn test_backlog() {
+        // the backlog is applied to a bound listener, which keeps accepting connections
+        let options = TcpOptions {
+            backlog: 1,
+            ..TcpOptions::default()
+        };
+        let listener: TcpListener = bind(&"127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).unwrap();
+        listener.accept().unwrap();
+
+        // binding an address in use fails
+        assert!(bind(&addr, &options).is_err());
+    }
+}
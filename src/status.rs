@@ -1,23 +1,26 @@
 use anyhow::Result;
 use bitcoin::{
-    consensus::serialize,
+    constants::COINBASE_MATURITY,
     hashes::{sha256, Hash, HashEngine},
     Amount, BlockHash, OutPoint, SignedAmount, Transaction, Txid,
 };
 use bitcoin_slices::{bsl, Visit, Visitor};
+use parking_lot::{Mutex, RwLock};
 use rayon::prelude::*;
 use serde::ser::{Serialize, Serializer};
+use serde_json::{json, Value};
 
 use std::convert::TryFrom;
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     ops::ControlFlow,
+    sync::Arc,
 };
 
 use crate::{
     cache::Cache,
     chain::Chain,
-    daemon::Daemon,
+    daemon::Node,
     index::Index,
     mempool::Mempool,
     types::{bsl_txid, ScriptHash, SerBlock, StatusHash},
@@ -26,6 +29,7 @@ use crate::{
 /// Given a scripthash, store relevant inputs and outputs of a specific transaction
 struct TxEntry {
     txid: Txid,
+    pos: usize,             // position within its block (unused for mempool transactions)
     outputs: Vec<TxOutput>, // relevant funded outputs and their amounts
     spent: Vec<OutPoint>,   // relevant spent outpoints
 }
@@ -37,9 +41,10 @@ struct TxOutput {
 }
 
 impl TxEntry {
-    fn new(txid: Txid) -> Self {
+    fn new(txid: Txid, pos: usize) -> Self {
         Self {
             txid,
+            pos,
             outputs: Vec::new(),
             spent: Vec::new(),
         }
@@ -54,6 +59,7 @@ impl TxEntry {
 // Confirmation height of a transaction or its mempool state:
 // https://electrum-protocol.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-history
 // https://electrum-protocol.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-mempool
+#[derive(Clone, Debug)]
 enum Height {
     Confirmed { height: usize },
     Unconfirmed { has_unconfirmed_inputs: bool },
@@ -91,8 +97,8 @@ impl std::fmt::Display for Height {
 // A single history entry:
 // https://electrum-protocol.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-history
 // https://electrum-protocol.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-mempool
-#[derive(Serialize)]
-pub(crate) struct HistoryEntry {
+#[derive(Clone, Debug, Serialize)]
+pub struct HistoryEntry {
     #[serde(rename = "tx_hash")]
     txid: Txid,
     height: Height,
@@ -101,21 +107,53 @@ pub(crate) struct HistoryEntry {
         with = "bitcoin::amount::serde::as_sat::opt"
     )]
     fee: Option<Amount>,
+    #[serde(skip)]
+    pos: usize, // position within block (for confirmed entries)
+}
+
+/// A history entry with its position within the block (see `HistoryEntry::with_pos`)
+#[derive(Serialize)]
+pub(crate) struct HistoryEntryWithPos<'a> {
+    #[serde(flatten)]
+    entry: &'a HistoryEntry,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx_pos: Option<usize>,
 }
 
 impl HistoryEntry {
     // Hash to compute ScriptHash status, as defined here:
     // https://electrum-protocol.readthedocs.io/en/latest/protocol-basics.html#status
     fn hash(&self, engine: &mut sha256::HashEngine) {
-        let s = format!("{}:{}:", self.txid, self.height);
-        engine.input(s.as_bytes());
+        engine.input(self.status_string().as_bytes());
+    }
+
+    fn status_string(&self) -> String {
+        format!("{}:{}:", self.txid, self.height)
+    }
+
+    pub fn txid(&self) -> Txid {
+        self.txid
     }
 
-    fn confirmed(txid: Txid, height: usize) -> Self {
+    /// Confirmation height (`None` for mempool transactions)
+    pub fn height(&self) -> Option<usize> {
+        match self.height {
+            Height::Confirmed { height } => Some(height),
+            Height::Unconfirmed { .. } => None,
+        }
+    }
+
+    /// Fee paid (only for mempool transactions)
+    pub fn fee(&self) -> Option<Amount> {
+        self.fee
+    }
+
+    pub(crate) fn confirmed(txid: Txid, height: usize, pos: usize) -> Self {
         Self {
             txid,
             height: Height::Confirmed { height },
             fee: None,
+            pos,
         }
     }
 
@@ -126,6 +164,37 @@ impl HistoryEntry {
                 has_unconfirmed_inputs,
             },
             fee: Some(fee),
+            pos: 0,
+        }
+    }
+
+    /// Serialize, adding the transaction's position within its block (for confirmed entries).
+    ///
+    /// It is a protocol extension (`tx_pos` in `server.features`), used only if requested.
+    pub(crate) fn to_value_with_pos(&self) -> Value {
+        json!(self.with_pos())
+    }
+
+    /// Serialized like `to_value_with_pos()`, without building a `Value`.
+    pub(crate) fn with_pos(&self) -> HistoryEntryWithPos<'_> {
+        let tx_pos = match self.height {
+            Height::Confirmed { .. } => Some(self.pos),
+            Height::Unconfirmed { .. } => None,
+        };
+        HistoryEntryWithPos {
+            entry: self,
+            tx_pos,
+        }
+    }
+
+    // Canonical history order: confirmed entries by height and position within block, followed by
+    // unconfirmed entries with confirmed inputs, and then with unconfirmed inputs (sorted by txid).
+    fn sort_key(&self) -> (u8, usize, usize, Txid) {
+        match self.height {
+            Height::Confirmed { height } => (0, height, self.pos, self.txid),
+            Height::Unconfirmed {
+                has_unconfirmed_inputs,
+            } => (1 + u8::from(has_unconfirmed_inputs), 0, 0, self.txid),
         }
     }
 }
@@ -134,20 +203,72 @@ impl HistoryEntry {
 pub struct ScriptHashStatus {
     scripthash: ScriptHash, // specific scripthash to be queried
     tip: BlockHash,         // used for skipping confirmed entries' sync
-    confirmed: HashMap<BlockHash, Vec<TxEntry>>, // confirmed entries, partitioned per block (may contain stale blocks)
+    confirmed: HashMap<BlockHash, Vec<TxEntry>>, // confirmed entries, partitioned per block (stale blocks are dropped on sync)
     mempool: Vec<TxEntry>,                       // unconfirmed entries
-    history: Vec<HistoryEntry>,                  // computed from confirmed and mempool entries
+    history: Arc<[HistoryEntry]>,                // computed from confirmed and mempool entries
     statushash: Option<StatusHash>,              // computed from history
+    allow_partial: bool, // truncate large histories (instead of failing the sync)
+    partial: bool,       // whether some confirmed entries were skipped
+    // the confirmed outpoints, computed once per chain tip (reset when the confirmed entries change)
+    confirmed_unspent: Mutex<Option<(BlockHash, Unspent)>>,
+    #[cfg(test)]
+    confirmed_unspent_computed: std::sync::atomic::AtomicUsize,
+}
+
+/// Specific scripthash usage statistics (`blockchain.scripthash.get_stats`)
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct Stats {
+    tx_count: usize,             // funding or spending transactions
+    funded_count: usize,         // transactions funding this scripthash
+    spent_count: usize,          // transactions spending this scripthash's outputs
+    first_height: Option<usize>, // of the confirmed transactions
+    last_height: Option<usize>,  // of the confirmed transactions
+    unconfirmed_count: usize,    // mempool transactions
+    partial: bool,               // if the history was truncated (due to `index_lookup_limit`)
+}
+
+impl Stats {
+    fn add(&mut self, entry: &TxEntry) {
+        self.tx_count += 1;
+        self.funded_count += usize::from(!entry.outputs.is_empty());
+        self.spent_count += usize::from(!entry.spent.is_empty());
+    }
 }
 
 /// Specific scripthash balance
 /// https://electrum-protocol.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-balance
-#[derive(Default, Eq, PartialEq, Serialize)]
-pub(crate) struct Balance {
+#[derive(Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Balance {
     #[serde(with = "bitcoin::amount::serde::as_sat", rename = "confirmed")]
     confirmed_balance: Amount,
     #[serde(with = "bitcoin::amount::serde::as_sat", rename = "unconfirmed")]
     mempool_delta: SignedAmount,
+    // immature coinbase outputs (not included in the confirmed balance)
+    #[serde(
+        with = "bitcoin::amount::serde::as_sat",
+        skip_serializing_if = "is_zero"
+    )]
+    immature: Amount,
+}
+
+fn is_zero(amount: &Amount) -> bool {
+    *amount == Amount::ZERO
+}
+
+impl Balance {
+    pub fn confirmed(&self) -> Amount {
+        self.confirmed_balance
+    }
+
+    /// Mempool transactions' effect on the balance (may be negative)
+    pub fn unconfirmed(&self) -> SignedAmount {
+        self.mempool_delta
+    }
+
+    /// Coinbase outputs which can't be spent yet (see `COINBASE_MATURITY`)
+    pub fn immature(&self) -> Amount {
+        self.immature
+    }
 }
 
 /// A single unspent transaction output entry
@@ -159,18 +280,25 @@ pub(crate) struct UnspentEntry {
     tx_pos: u32,
     #[serde(with = "bitcoin::amount::serde::as_sat")]
     value: Amount,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    immature: bool, // a coinbase output which can't be spent yet
 }
 
 #[derive(Default)]
 struct Unspent {
-    // mapping an outpoint to its value & confirmation height
-    outpoints: HashMap<OutPoint, (Amount, usize)>,
+    // mapping an outpoint to its value & confirmation height (and whether it is immature)
+    outpoints: HashMap<OutPoint, (Amount, usize, bool)>,
     balance: Balance,
+    tip_height: usize,
 }
 
 impl Unspent {
-    fn build(status: &ScriptHashStatus, chain: &Chain) -> Self {
-        let mut unspent = Unspent::default();
+    /// The confirmed outpoints (and balance), without the mempool's effect.
+    fn confirmed(status: &ScriptHashStatus, chain: &Chain) -> Self {
+        let mut unspent = Unspent {
+            tip_height: chain.height(),
+            ..Default::default()
+        };
         // First, add all relevant entries' funding outputs to the outpoints' map
         status
             .confirmed_height_entries(chain)
@@ -181,6 +309,12 @@ impl Unspent {
             .for_each(|e| unspent.remove(e));
 
         unspent.balance.confirmed_balance = unspent.balance();
+        unspent.balance.immature = unspent.immature();
+        unspent
+    }
+
+    fn build(status: &ScriptHashStatus, chain: &Chain) -> Self {
+        let mut unspent = Self::confirmed(status, chain);
         // Now, do the same over the mempool (first add funding outputs, and then remove spent ones)
         status.mempool.iter().for_each(|e| unspent.insert(e, 0)); // mempool height = 0
         status.mempool.iter().for_each(|e| unspent.remove(e));
@@ -191,32 +325,77 @@ impl Unspent {
         unspent
     }
 
+    /// Mempool transactions' effect on the confirmed balance (like `build()`, without modifying
+    /// the confirmed outpoints).
+    fn mempool_delta(&self, mempool: &[TxEntry]) -> SignedAmount {
+        let mut funded: HashMap<OutPoint, Amount> = mempool
+            .iter()
+            .flat_map(|e| make_outpoints(e.txid, &e.outputs).zip(e.outputs.iter()))
+            .map(|(outpoint, output)| (outpoint, output.value))
+            .collect();
+        let spent: HashSet<&OutPoint> = mempool.iter().flat_map(|e| &e.spent).collect();
+        let mut delta = SignedAmount::ZERO;
+        for outpoint in spent {
+            if funded.remove(outpoint).is_none() {
+                if let Some((value, _height, false)) = self.outpoints.get(outpoint) {
+                    delta -= value.to_signed().unwrap();
+                }
+            }
+        }
+        funded
+            .into_values()
+            .fold(delta, |acc, value| acc + value.to_signed().unwrap())
+    }
+
+    /// Sorted by confirmation height (mempool entries last), like other Electrum servers.
     fn into_entries(self) -> Vec<UnspentEntry> {
-        self.outpoints
+        let mut entries: Vec<UnspentEntry> = self
+            .outpoints
             .into_iter()
-            .map(|(outpoint, (value, height))| UnspentEntry {
+            .map(|(outpoint, (value, height, immature))| UnspentEntry {
                 height,
                 tx_hash: outpoint.txid,
                 tx_pos: outpoint.vout,
                 value,
+                immature,
             })
-            .collect()
+            .collect();
+        entries.sort_unstable_by_key(|e| (e.height == 0, e.height, e.tx_hash, e.tx_pos));
+        entries
     }
 
-    /// Total amount of unspent outputs
+    /// Total amount of unspent outputs (excluding immature ones)
     fn balance(&self) -> Amount {
         self.outpoints
             .values()
+            .filter(|v| !v.2)
             .fold(Amount::default(), |acc, v| acc + v.0)
     }
 
+    /// Total amount of immature coinbase outputs
+    fn immature(&self) -> Amount {
+        self.outpoints
+            .values()
+            .filter(|v| v.2)
+            .fold(Amount::default(), |acc, v| acc + v.0)
+    }
+
+    /// A coinbase transaction (the first one in its block) can be spent only by a block
+    /// `COINBASE_MATURITY` blocks after it, so the next block must be deep enough.
+    fn is_immature(&self, entry: &TxEntry, height: usize) -> bool {
+        let coinbase = height > 0 && entry.pos == 0; // mempool entries have no position
+        coinbase && self.tip_height + 1 - height < COINBASE_MATURITY as usize
+    }
+
     fn insert(&mut self, entry: &TxEntry, height: usize) {
+        let immature = self.is_immature(entry, height);
         for output in &entry.outputs {
             let outpoint = OutPoint {
                 txid: entry.txid,
                 vout: output.index,
             };
-            self.outpoints.insert(outpoint, (output.value, height));
+            self.outpoints
+                .insert(outpoint, (output.value, height, immature));
         }
     }
 
@@ -235,8 +414,22 @@ impl ScriptHashStatus {
             tip: BlockHash::all_zeros(),
             confirmed: HashMap::new(),
             mempool: Vec::new(),
-            history: Vec::new(),
+            history: Vec::new().into(),
             statushash: None,
+            allow_partial: false,
+            partial: false,
+            confirmed_unspent: Mutex::new(None),
+            #[cfg(test)]
+            confirmed_unspent_computed: Default::default(),
+        }
+    }
+
+    /// Return non-synced (empty) status, whose confirmed history is truncated on sync if it has
+    /// too many index entries (instead of failing). Used only for statistics.
+    pub(crate) fn new_partial(scripthash: ScriptHash) -> Self {
+        Self {
+            allow_partial: true,
+            ..Self::new(scripthash)
         }
     }
 
@@ -269,14 +462,58 @@ impl ScriptHashStatus {
             .collect()
     }
 
+    /// Collect all funded outpoints, including unconfirmed ones (as a set).
+    pub(crate) fn funded_outpoints(&self, chain: &Chain) -> HashSet<OutPoint> {
+        let mut outpoints = self.confirmed_outpoints(chain);
+        outpoints.extend(self.mempool.iter().flat_map(TxEntry::funding_outpoints));
+        outpoints
+    }
+
     /// Collect unspent transaction entries
     pub(crate) fn get_unspent(&self, chain: &Chain) -> Vec<UnspentEntry> {
         Unspent::build(self, chain).into_entries()
     }
 
-    /// Collect unspent transaction balance
+    /// Collect unspent transaction balance: its confirmed part is computed once per chain tip,
+    /// so only the mempool's effect is recomputed between blocks.
     pub(crate) fn get_balance(&self, chain: &Chain) -> Balance {
-        Unspent::build(self, chain).balance
+        let mut cached = self.confirmed_unspent.lock();
+        let tip = chain.tip();
+        let unspent = match &mut *cached {
+            Some((cached_tip, unspent)) if *cached_tip == tip => unspent,
+            cached => {
+                #[cfg(test)]
+                self.confirmed_unspent_computed
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                &mut cached.insert((tip, Unspent::confirmed(self, chain))).1
+            }
+        };
+        Balance {
+            mempool_delta: unspent.mempool_delta(&self.mempool),
+            ..unspent.balance
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn confirmed_unspent_computed(&self) -> usize {
+        self.confirmed_unspent_computed
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Count funding and spending transactions (without the transactions themselves)
+    pub(crate) fn get_stats(&self, chain: &Chain) -> Stats {
+        let mut stats = Stats {
+            partial: self.partial,
+            ..Default::default()
+        };
+        for (height, entries) in self.confirmed_height_entries(chain) {
+            entries.iter().for_each(|entry| stats.add(entry));
+            stats.first_height = Some(stats.first_height.map_or(height, |h| h.min(height)));
+            stats.last_height = Some(stats.last_height.map_or(height, |h| h.max(height)));
+        }
+        self.mempool.iter().for_each(|entry| stats.add(entry));
+        stats.unconfirmed_count = self.mempool.len();
+        stats
     }
 
     /// Collect transaction history entries
@@ -284,35 +521,37 @@ impl ScriptHashStatus {
         &self.history
     }
 
-    /// Collect all confirmed history entries (in block order).
-    fn get_confirmed_history(&self, chain: &Chain) -> Vec<HistoryEntry> {
+    /// The history (without copying it), e.g. to be serialized while it is sent
+    pub(crate) fn shared_history(&self) -> Arc<[HistoryEntry]> {
+        Arc::clone(&self.history)
+    }
+
+    /// Collect all confirmed history entries (unsorted).
+    fn get_confirmed_history<'a>(
+        &'a self,
+        chain: &'a Chain,
+    ) -> impl Iterator<Item = HistoryEntry> + 'a {
         self.confirmed_height_entries(chain)
-            .collect::<BTreeMap<usize, &[TxEntry]>>()
-            .into_iter()
             .flat_map(|(height, entries)| {
                 entries
                     .iter()
-                    .map(move |e| HistoryEntry::confirmed(e.txid, height))
+                    .map(move |e| HistoryEntry::confirmed(e.txid, height, e.pos))
             })
-            .collect()
     }
 
-    /// Collect all mempool history entries (keeping transactions with unconfirmed parents last).
-    fn get_mempool_history(&self, mempool: &Mempool) -> Vec<HistoryEntry> {
-        let mut entries = self
-            .mempool
+    /// Collect all mempool history entries (unsorted).
+    fn get_mempool_history<'a>(
+        &'a self,
+        mempool: &'a Mempool,
+    ) -> impl Iterator<Item = HistoryEntry> + 'a {
+        self.mempool
             .iter()
             .filter_map(|e| mempool.get(&e.txid))
-            .collect::<Vec<_>>();
-        entries.sort_by_key(|e| (e.has_unconfirmed_inputs, e.txid));
-        entries
-            .into_iter()
             .map(|e| HistoryEntry::unconfirmed(e.txid, e.has_unconfirmed_inputs, e.fee))
-            .collect()
     }
 
     /// Apply `func` only on the new blocks (to be fetched via p2p interface).
-    fn for_new_blocks<B, F>(&self, blockhashes: B, daemon: &Daemon, func: F) -> Result<()>
+    fn for_new_blocks<B, F>(&self, blockhashes: B, daemon: &dyn Node, mut func: F) -> Result<()>
     where
         B: IntoIterator<Item = BlockHash>,
         F: FnMut(BlockHash, SerBlock),
@@ -320,8 +559,9 @@ impl ScriptHashStatus {
         daemon.for_blocks(
             blockhashes
                 .into_iter()
-                .filter(|blockhash| !self.confirmed.contains_key(blockhash)),
-            func,
+                .filter(|blockhash| !self.confirmed.contains_key(blockhash))
+                .collect(),
+            &mut func,
         )
     }
 
@@ -330,20 +570,40 @@ impl ScriptHashStatus {
     fn sync_confirmed(
         &self,
         index: &Index,
-        daemon: &Daemon,
+        chain: &Chain,
+        daemon: &dyn Node,
         cache: &Cache,
         outpoints: &mut HashSet<OutPoint>,
+        truncated: &mut bool,
     ) -> Result<HashMap<BlockHash, Vec<TxEntry>>> {
         // Will be updated during the following block scans
         let mut result = HashMap::<BlockHash, HashMap<usize, TxEntry>>::new();
 
-        let funding_blockhashes = index.limit_result(index.filter_by_funding(self.scripthash))?;
+        let funding_rows = index.filter_by_funding_outputs(chain, self.scripthash);
+        let funding_blocks = if self.allow_partial {
+            let (blocks, skipped) = index.truncate_result(funding_rows);
+            *truncated |= skipped;
+            blocks
+        } else {
+            index.limit_result(funding_rows)?
+        };
+        let funding_blockhashes: Vec<BlockHash> = funding_blocks
+            .iter()
+            .map(|(blockhash, _)| *blockhash)
+            .collect();
+        let funding_vouts: HashMap<BlockHash, Option<Vec<u32>>> =
+            funding_blocks.into_iter().collect();
         self.for_new_blocks(funding_blockhashes, daemon, |blockhash, block| {
             let block_entries = result.entry(blockhash).or_default(); // the block may already exist
 
-            // extract relevant funding transactions
-            for filtered_outputs in filter_block_txs_outputs(block, self.scripthash) {
-                cache.add_tx(filtered_outputs.txid, move || filtered_outputs.tx_bytes);
+            // cached using the latest chain (in case a deep reorg was published meanwhile)
+            let latest = index.chain();
+            // extract relevant funding transactions (checking only the indexed output indices)
+            let vouts = funding_vouts.get(&blockhash).and_then(Option::as_deref);
+            for filtered_outputs in filter_block_txs_outputs(block, self.scripthash, vouts) {
+                cache.add_tx(&latest, blockhash, filtered_outputs.txid, move || {
+                    filtered_outputs.tx_bytes
+                });
                 // store funded outpoints (to check for spending later)
                 outpoints.extend(make_outpoints(
                     filtered_outputs.txid,
@@ -351,23 +611,26 @@ impl ScriptHashStatus {
                 ));
                 block_entries
                     .entry(filtered_outputs.pos) // the transaction may already exist
-                    .or_insert_with(|| TxEntry::new(filtered_outputs.txid))
+                    .or_insert_with(|| TxEntry::new(filtered_outputs.txid, filtered_outputs.pos))
                     .outputs = filtered_outputs.result;
             }
         })?;
         let spending_blockhashes: HashSet<BlockHash> = outpoints
             .par_iter() // use rayon for concurrent index lookups
-            .flat_map_iter(|outpoint| index.filter_by_spending(*outpoint))
+            .flat_map_iter(|outpoint| index.filter_by_spending(chain, *outpoint))
             .collect();
         self.for_new_blocks(spending_blockhashes, daemon, |blockhash, block| {
             let block_entries = result.entry(blockhash).or_default(); // the block may already exist
 
+            let latest = index.chain();
             // extract relevant spending transactions
             for filtered_inputs in filter_block_txs_inputs(&block, outpoints) {
-                cache.add_tx(filtered_inputs.txid, move || filtered_inputs.tx_bytes);
+                cache.add_tx(&latest, blockhash, filtered_inputs.txid, move || {
+                    filtered_inputs.tx_bytes
+                });
                 block_entries
                     .entry(filtered_inputs.pos) // the transaction may already exist
-                    .or_insert_with(|| TxEntry::new(filtered_inputs.txid))
+                    .or_insert_with(|| TxEntry::new(filtered_inputs.txid, filtered_inputs.pos))
                     .spent = filtered_inputs.result;
             }
         })?;
@@ -386,13 +649,7 @@ impl ScriptHashStatus {
     }
 
     /// Get funding and spending entries from current mempool.
-    /// Also cache relevant transactions.
-    fn sync_mempool(
-        &self,
-        mempool: &Mempool,
-        cache: &Cache,
-        outpoints: &mut HashSet<OutPoint>,
-    ) -> Vec<TxEntry> {
+    fn sync_mempool(&self, mempool: &Mempool, outpoints: &mut HashSet<OutPoint>) -> Vec<TxEntry> {
         let mut result = HashMap::<Txid, TxEntry>::new();
         // extract relevant funding transactions
         for entry in mempool.filter_by_funding(&self.scripthash) {
@@ -402,9 +659,8 @@ impl ScriptHashStatus {
             outpoints.extend(make_outpoints(entry.txid, &funding_outputs));
             result
                 .entry(entry.txid) // the transaction may already exist
-                .or_insert_with(|| TxEntry::new(entry.txid))
+                .or_insert_with(|| TxEntry::new(entry.txid, 0))
                 .outputs = funding_outputs;
-            cache.add_tx(entry.txid, || serialize(&entry.tx).into_boxed_slice());
         }
         for entry in outpoints
             .iter()
@@ -414,30 +670,70 @@ impl ScriptHashStatus {
             assert!(!spent_outpoints.is_empty());
             result
                 .entry(entry.txid) // the transaction may already exist
-                .or_insert_with(|| TxEntry::new(entry.txid))
+                .or_insert_with(|| TxEntry::new(entry.txid, 0))
                 .spent = spent_outpoints;
-            cache.add_tx(entry.txid, || serialize(&entry.tx).into_boxed_slice());
         }
         result.into_values().collect()
     }
 
+    /// Sync confirmed entries with the given chain snapshot (returning the funded outpoints).
+    /// Skipped if the new blocks are known not to touch this scripthash.
+    fn sync_chain(
+        &mut self,
+        index: &Index,
+        chain: &Chain,
+        daemon: &dyn Node,
+        cache: &Cache,
+        confirmed_touched: bool,
+    ) -> Result<HashSet<OutPoint>> {
+        let mut outpoints: HashSet<OutPoint> = self.confirmed_outpoints(chain);
+
+        let new_tip = chain.tip();
+        if !confirmed_touched {
+            self.tip = new_tip; // no new funding or spending rows
+        } else if self.tip != new_tip {
+            let mut truncated = false;
+            let update =
+                self.sync_confirmed(index, chain, daemon, cache, &mut outpoints, &mut truncated)?;
+            self.partial |= truncated;
+            // drop stale blocks (their transactions may be in the mempool or in a new block)
+            self.confirmed
+                .retain(|blockhash, _| chain.get_block_height(blockhash).is_some());
+            self.confirmed.extend(update); // add new blocks to the map
+            self.tip = new_tip;
+            *self.confirmed_unspent.get_mut() = None;
+        }
+        Ok(outpoints)
+    }
+
     /// Sync with currently confirmed txs and mempool, downloading non-cached transactions via p2p protocol.
     /// After a successful sync, scripthash status is updated.
+    /// Confirmed entries' sync is skipped if the new blocks are known not to touch this scripthash.
+    ///
+    /// `confirmed_touched` refers to the blocks of the given `chain` snapshot. The confirmed entries
+    /// are synced without locking the mempool, so new blocks may be published meanwhile (together
+    /// with their mempool update): in this case, the confirmed entries are synced again, so the
+    /// status always reflects a consistent chain and mempool.
     pub(crate) fn sync(
         &mut self,
         index: &Index,
-        mempool: &Mempool,
-        daemon: &Daemon,
+        mut chain: Arc<Chain>,
+        mempool: &RwLock<Mempool>,
+        daemon: &dyn Node,
         cache: &Cache,
+        confirmed_touched: bool,
     ) -> Result<()> {
-        let mut outpoints: HashSet<OutPoint> = self.confirmed_outpoints(index.chain());
-
-        let new_tip = index.chain().tip();
-        if self.tip != new_tip {
-            let update = self.sync_confirmed(index, daemon, cache, &mut outpoints)?;
-            self.confirmed.extend(update); // add new blocks to the map
-            self.tip = new_tip;
-        }
+        let mut outpoints = self.sync_chain(index, &chain, daemon, cache, confirmed_touched)?;
+        let mempool = loop {
+            let mempool = mempool.read();
+            let latest = index.chain();
+            if Arc::ptr_eq(&chain, &latest) {
+                break mempool; // new blocks are published only while the mempool is locked
+            }
+            drop(mempool);
+            chain = latest; // `confirmed_touched` doesn't cover the new blocks
+            outpoints = self.sync_chain(index, &chain, daemon, cache, true)?;
+        };
         if !self.confirmed.is_empty() {
             debug!(
                 "{} transactions from {} blocks",
@@ -445,24 +741,71 @@ impl ScriptHashStatus {
                 self.confirmed.len()
             );
         }
-        self.mempool = self.sync_mempool(mempool, cache, &mut outpoints);
+        self.mempool = self.sync_mempool(&mempool, &mut outpoints);
         if !self.mempool.is_empty() {
             debug!("{} mempool transactions", self.mempool.len());
         }
-        // update history entries and status hash
-        self.history.clear();
-        self.history
-            .extend(self.get_confirmed_history(index.chain()));
-        self.history.extend(self.get_mempool_history(mempool));
-
-        self.statushash = compute_status_hash(&self.history);
+        // update history entries and status hash (using the same canonical order)
+        let mut history: Vec<HistoryEntry> = self
+            .get_confirmed_history(&chain)
+            .chain(self.get_mempool_history(&mempool))
+            .collect();
+        sort_history(&mut history);
+        self.statushash = compute_status_hash(&history);
+        self.history = history.into();
         Ok(())
     }
 
+    /// The confirmed history (in canonical order) of the given chain snapshot, without the
+    /// mempool - e.g. to compare the answers of two DBs (see `ShadowDb`).
+    pub(crate) fn confirmed_history(
+        scripthash: ScriptHash,
+        index: &Index,
+        chain: &Chain,
+        daemon: &dyn Node,
+        cache: &Cache,
+    ) -> Result<Vec<HistoryEntry>> {
+        let mut status = Self::new(scripthash);
+        status.sync_chain(index, chain, daemon, cache, true)?;
+        let mut history: Vec<HistoryEntry> = status.get_confirmed_history(chain).collect();
+        sort_history(&mut history);
+        Ok(history)
+    }
+
     /// Get current status hash.
     pub fn statushash(&self) -> Option<StatusHash> {
         self.statushash
     }
+
+    /// The concatenated "txid:height:" string hashed into the status (`status_debug` extension)
+    pub(crate) fn status_preimage(&self) -> String {
+        status_preimage(&self.history)
+    }
+
+    /// Confirmation heights of the transactions funding this scripthash (and of the ones spending
+    /// its outputs), e.g. to find index rows of prefix collisions.
+    pub(crate) fn confirmed_heights(&self, chain: &Chain) -> (HashSet<usize>, HashSet<usize>) {
+        let mut funding = HashSet::new();
+        let mut spending = HashSet::new();
+        for (height, entries) in self.confirmed_height_entries(chain) {
+            if entries.iter().any(|e| !e.outputs.is_empty()) {
+                funding.insert(height);
+            }
+            if entries.iter().any(|e| !e.spent.is_empty()) {
+                spending.insert(height);
+            }
+        }
+        (funding, spending)
+    }
+
+    /// Return `true` if any of the given blocks contains a confirmed entry.
+    pub(crate) fn has_blocks(&self, blockhashes: &HashSet<BlockHash>) -> bool {
+        blockhashes.iter().any(|blockhash| {
+            self.confirmed
+                .get(blockhash)
+                .is_some_and(|entries| !entries.is_empty())
+        })
+    }
 }
 
 fn make_outpoints(txid: Txid, outputs: &[TxOutput]) -> impl Iterator<Item = OutPoint> + '_ {
@@ -500,6 +843,10 @@ fn filter_inputs(tx: &Transaction, outpoints: &HashSet<OutPoint>) -> Vec<OutPoin
         .collect()
 }
 
+fn sort_history(history: &mut [HistoryEntry]) {
+    history.sort_unstable_by_key(HistoryEntry::sort_key); // txids are unique
+}
+
 // See https://electrum-protocol.readthedocs.io/en/latest/protocol-basics.html#status for details
 fn compute_status_hash(history: &[HistoryEntry]) -> Option<StatusHash> {
     if history.is_empty() {
@@ -512,6 +859,10 @@ fn compute_status_hash(history: &[HistoryEntry]) -> Option<StatusHash> {
     Some(StatusHash::from_engine(engine))
 }
 
+fn status_preimage(history: &[HistoryEntry]) -> String {
+    history.iter().map(HistoryEntry::status_string).collect()
+}
+
 struct FilteredTx<T> {
     tx_bytes: Box<[u8]>,
     txid: Txid,
@@ -519,14 +870,21 @@ struct FilteredTx<T> {
     result: Vec<T>,
 }
 
-fn filter_block_txs_outputs(block: SerBlock, scripthash: ScriptHash) -> Vec<FilteredTx<TxOutput>> {
-    struct FindOutputs {
+/// Find the outputs funding `scripthash`, hashing only the outputs at `vouts` (sorted) if the
+/// block's funding rows store them.
+fn filter_block_txs_outputs(
+    block: SerBlock,
+    scripthash: ScriptHash,
+    vouts: Option<&[u32]>,
+) -> Vec<FilteredTx<TxOutput>> {
+    struct FindOutputs<'a> {
         scripthash: ScriptHash,
+        vouts: Option<&'a [u32]>,
         result: Vec<FilteredTx<TxOutput>>,
         buffer: Vec<TxOutput>,
         pos: usize,
     }
-    impl Visitor for FindOutputs {
+    impl Visitor for FindOutputs<'_> {
         // Called after all TxOuts are visited
         fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
             if !self.buffer.is_empty() {
@@ -542,6 +900,12 @@ fn filter_block_txs_outputs(block: SerBlock, scripthash: ScriptHash) -> Vec<Filt
         }
         // Keep only relevant outputs
         fn visit_tx_out(&mut self, vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+            let indexed = self
+                .vouts
+                .is_none_or(|vouts| vouts.binary_search(&(vout as u32)).is_ok());
+            if !indexed {
+                return ControlFlow::Continue(()); // funds another scripthash
+            }
             let current = ScriptHash::hash(tx_out.script_pubkey());
             if current == self.scripthash {
                 self.buffer.push(TxOutput {
@@ -554,6 +918,7 @@ fn filter_block_txs_outputs(block: SerBlock, scripthash: ScriptHash) -> Vec<Filt
     }
     let mut find_outputs = FindOutputs {
         scripthash,
+        vouts,
         result: vec![],
         buffer: vec![],
         pos: 0,
@@ -618,7 +983,10 @@ mod tests {
     use crate::types::ScriptHash;
 
     use super::HistoryEntry;
-    use bitcoin::{Address, Amount};
+    use bitcoin::{
+        hashes::{sha256, Hash},
+        Address, Amount,
+    };
     use bitcoin_test_data::blocks::mainnet_702861;
     use serde_json::json;
 
@@ -628,7 +996,7 @@ mod tests {
             .parse()
             .unwrap();
         assert_eq!(
-            json!(HistoryEntry::confirmed(txid, 123456)),
+            json!(HistoryEntry::confirmed(txid, 123456, 7)),
             json!({"tx_hash": "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b", "height": 123456})
         );
         assert_eq!(
@@ -645,6 +1013,81 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_history_order() {
+        let txid = |c: char| c.to_string().repeat(64).parse().unwrap();
+        let fee = Amount::from_sat(1000);
+        let mut history = vec![
+            HistoryEntry::unconfirmed(txid('d'), true, fee),
+            HistoryEntry::unconfirmed(txid('f'), false, fee),
+            HistoryEntry::confirmed(txid('b'), 101, 0),
+            HistoryEntry::confirmed(txid('a'), 100, 5),
+            HistoryEntry::unconfirmed(txid('e'), false, fee),
+            HistoryEntry::confirmed(txid('c'), 100, 1),
+        ];
+        let expected = json!([
+            {"tx_hash": "c".repeat(64), "height": 100},
+            {"tx_hash": "a".repeat(64), "height": 100},
+            {"tx_hash": "b".repeat(64), "height": 101},
+            {"tx_hash": "e".repeat(64), "height": 0, "fee": 1000},
+            {"tx_hash": "f".repeat(64), "height": 0, "fee": 1000},
+            {"tx_hash": "d".repeat(64), "height": -1, "fee": 1000},
+        ]);
+        // the order doesn't depend on the initial one
+        for _ in 0..history.len() {
+            history.rotate_left(1);
+            super::sort_history(&mut history);
+            assert_eq!(json!(history), expected);
+            assert_eq!(
+                super::compute_status_hash(&history).unwrap().to_string(),
+                "a0d957fda852d2debeefb673ccbb1ab957bb04b778481bf8c8a9f155ff2e2ff6"
+            );
+        }
+        // the status hash is computed from the preimage (as a client would)
+        let preimage = super::status_preimage(&history);
+        assert!(preimage.starts_with(&format!("{}:100:{}:100:", "c".repeat(64), "a".repeat(64))));
+        assert!(preimage.ends_with(&format!("{}:-1:", "d".repeat(64))));
+        assert_eq!(
+            sha256::Hash::hash(preimage.as_bytes()).to_byte_array(),
+            super::compute_status_hash(&history)
+                .unwrap()
+                .to_byte_array()
+        );
+        assert_eq!(super::status_preimage(&[]), "");
+    }
+
+    #[test]
+    fn test_history_tx_pos() {
+        let txid = "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            HistoryEntry::confirmed(txid, 123456, 7).to_value_with_pos(),
+            json!({"tx_hash": "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b", "height": 123456, "tx_pos": 7})
+        );
+        assert_eq!(
+            HistoryEntry::unconfirmed(txid, false, Amount::from_sat(123)).to_value_with_pos(),
+            json!({"tx_hash": "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b", "height": 0, "fee": 123})
+        );
+    }
+
+    #[test]
+    fn test_block_positions() {
+        let block_bytes = mainnet_702861().to_vec();
+        let block: bitcoin::Block = bitcoin::consensus::deserialize(&block_bytes).unwrap();
+        // positions follow the block's transactions order (starting with the coinbase at 0)
+        for pos in [0, 1, block.txdata.len() - 1] {
+            let tx = &block.txdata[pos];
+            let scripthash = ScriptHash::new(&tx.output[0].script_pubkey);
+            let result = super::filter_block_txs_outputs(block_bytes.clone(), scripthash, None);
+            let found = result
+                .iter()
+                .find(|filtered| filtered.txid == tx.compute_txid())
+                .unwrap();
+            assert_eq!(found.pos, pos);
+        }
+    }
+
     #[test]
     fn test_find_outputs() {
         let block = mainnet_702861().to_vec();
@@ -654,7 +1097,7 @@ mod tests {
             .assume_checked();
         let scripthash = ScriptHash::new(&addr.script_pubkey());
 
-        let result = &super::filter_block_txs_outputs(block, scripthash)[0];
+        let result = &super::filter_block_txs_outputs(block.clone(), scripthash, None)[0];
         assert_eq!(
             result.txid.to_string(),
             "7bcdcb44422da5a99daad47d6ba1c3d6f2e48f961a75e42c4fa75029d4b0ef49"
@@ -662,6 +1105,24 @@ mod tests {
         assert_eq!(result.pos, 8);
         assert_eq!(result.result[0].index, 0);
         assert_eq!(result.result[0].value.to_sat(), 709503);
+
+        // only the indexed output indices are checked
+        let found = |vouts: &[u32]| {
+            super::filter_block_txs_outputs(block.clone(), scripthash, Some(vouts))
+                .iter()
+                .map(|filtered| filtered.txid)
+                .collect::<Vec<_>>()
+        };
+        let all = super::filter_block_txs_outputs(block.clone(), scripthash, None);
+        for vouts in [&[0][..], &[1, 2], &[]] {
+            let expected: Vec<_> = all
+                .iter()
+                .filter(|filtered| filtered.result.iter().any(|o| vouts.contains(&o.index)))
+                .map(|filtered| filtered.txid)
+                .collect();
+            assert_eq!(found(vouts), expected);
+        }
+        assert!(found(&[0]).contains(&result.txid));
     }
 
     #[test]
@@ -0,0 +1,3288 @@
+//! Scriptable in-memory [`Node`] implementation, allowing tests to run without `bitcoind`.
+//!
+//! It serves a canned chain (which can be extended and reorged), a mempool and fee estimates,
+//! and can inject delays and errors (RPC warmup, HTTP 401, connection refused, missing blocks).
+//! The calls made by the code under test are recorded.
+
+use anyhow::{Context, Result};
+use bitcoin::{
+    absolute::LockTime,
+    blockdata::constants::genesis_block,
+    consensus::{encode::serialize_hex, serialize},
+    script::Builder,
+    transaction::Version,
+    Amount, Block, BlockHash, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+    Txid, Witness,
+};
+use bitcoincore_rpc::{
+    json,
+    jsonrpc::{self, error::RpcError, simple_http},
+};
+use parking_lot::{Mutex, MutexGuard};
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use crate::{
+    chain::{Chain, NewHeader},
+    daemon::{BlockNotAvailable, Node},
+    types::SerBlock,
+};
+
+/// An error to be returned by the next call of a specific method.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Failure {
+    Warmup,                 // RPC error -28 (e.g. while bitcoind is loading its block index)
+    Unauthorized,           // HTTP 401 (e.g. due to a stale cookie)
+    ConnectionRefused,      // bitcoind is down
+    Rejected(&'static str), // RPC error -26 (a transaction rejected by the mempool policy)
+}
+
+impl Failure {
+    fn to_error(self) -> bitcoincore_rpc::Error {
+        let err = match self {
+            Failure::Warmup => jsonrpc::Error::Rpc(RpcError {
+                code: -28,
+                message: "Loading block index...".to_owned(),
+                data: None,
+            }),
+            Failure::Rejected(reason) => jsonrpc::Error::Rpc(RpcError {
+                code: -26,
+                message: reason.to_owned(),
+                data: None,
+            }),
+            Failure::Unauthorized => {
+                jsonrpc::Error::Transport(Box::new(simple_http::Error::HttpErrorCode(401)))
+            }
+            Failure::ConnectionRefused => jsonrpc::Error::Transport(Box::new(
+                simple_http::Error::SocketError(std::io::ErrorKind::ConnectionRefused.into()),
+            )),
+        };
+        bitcoincore_rpc::Error::JsonRpc(err)
+    }
+}
+
+/// Returned by `getrawtransaction` for confirmed transactions, when `txindex` is disabled
+/// and no block hash is given.
+fn no_txindex_error() -> bitcoincore_rpc::Error {
+    bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(RpcError {
+        code: -5,
+        message: "No such mempool transaction. Use -txindex or provide a block hash to enable blockchain transaction queries. Use gettransaction for wallet transactions.".to_owned(),
+        data: None,
+    }))
+}
+
+struct MempoolTx {
+    tx: Transaction,
+    fee: Amount,
+    has_entry: bool, // `false` simulates a transaction replaced between the RPC calls
+}
+
+struct State {
+    blocks: HashMap<BlockHash, Block>, // including stale blocks
+    active: Vec<BlockHash>,            // best chain, starting from genesis
+    mempool: BTreeMap<Txid, MempoolTx>,
+    fee_rate: Option<Amount>,
+    relay_fee: Amount,
+    failures: HashMap<&'static str, VecDeque<Failure>>,
+    failed_blocks: HashSet<BlockHash>, // the p2p connection is lost before sending them
+    hidden_blocks: HashMap<BlockHash, Option<usize>>, // not available (the # of remaining times)
+    prune_height: Option<usize>,
+    delay: Duration,
+    calls: Vec<&'static str>,
+    nonce: u64,    // makes each mined coinbase (and block) unique
+    txindex: bool, // if disabled, confirmed transactions are found only given their block hash
+}
+
+pub(crate) struct StubNode {
+    network: Network,
+    state: Mutex<State>,
+}
+
+impl StubNode {
+    /// A chain containing only the genesis block.
+    pub(crate) fn new(network: Network) -> Self {
+        Self::with_genesis(network, genesis_block(network))
+    }
+
+    /// A chain containing only the given genesis block (e.g. of a custom chain).
+    pub(crate) fn with_genesis(network: Network, genesis: Block) -> Self {
+        let hash = genesis.block_hash();
+        Self {
+            network,
+            state: Mutex::new(State {
+                blocks: HashMap::from([(hash, genesis)]),
+                active: vec![hash],
+                mempool: BTreeMap::new(),
+                fee_rate: None,
+                relay_fee: Amount::from_sat(1000),
+                failures: HashMap::new(),
+                failed_blocks: HashSet::new(),
+                hidden_blocks: HashMap::new(),
+                prune_height: None,
+                delay: Duration::ZERO,
+                calls: vec![],
+                nonce: 0,
+                txindex: true,
+            }),
+        }
+    }
+
+    /// Mine a block on top of the best chain, paying its coinbase to `script` and confirming
+    /// the given transactions (which are removed from the mempool).
+    pub(crate) fn mine(&self, script: &ScriptBuf, txs: Vec<Transaction>) -> BlockHash {
+        let mut state = self.state.lock();
+        let tip = *state.active.last().unwrap();
+        let height = state.active.len();
+        state.nonce += 1;
+        let coinbase = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new()
+                    .push_int(height as i64)
+                    .push_int(state.nonce as i64)
+                    .into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_int_btc(50),
+                script_pubkey: script.clone(),
+            }],
+        };
+        for tx in &txs {
+            state.mempool.remove(&tx.compute_txid());
+        }
+        let mut block = Block {
+            header: state.blocks[&tip].header,
+            txdata: std::iter::once(coinbase).chain(txs).collect(),
+        };
+        block.header.prev_blockhash = tip;
+        block.header.time += height as u32;
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        // regtest's proof-of-work is valid for about half of the nonces
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+        let hash = block.block_hash();
+        state.blocks.insert(hash, block);
+        state.active.push(hash);
+        hash
+    }
+
+    /// Disconnect the last `depth` blocks, returning their transactions to the mempool.
+    pub(crate) fn invalidate(&self, depth: usize) {
+        let mut state = self.state.lock();
+        let height = state.active.len() - depth;
+        assert!(height > 0, "cannot invalidate genesis");
+        let stale: Vec<BlockHash> = state.active.drain(height..).collect();
+        for hash in stale {
+            let txs: Vec<Transaction> = state.blocks[&hash].txdata[1..].to_vec();
+            for tx in txs {
+                let mempool_tx = MempoolTx {
+                    tx,
+                    fee: Amount::ZERO,
+                    has_entry: true,
+                };
+                state
+                    .mempool
+                    .insert(mempool_tx.tx.compute_txid(), mempool_tx);
+            }
+        }
+    }
+
+    pub(crate) fn add_to_mempool(&self, tx: Transaction, fee: Amount) -> Txid {
+        let txid = tx.compute_txid();
+        let mempool_tx = MempoolTx {
+            tx,
+            fee,
+            has_entry: true,
+        };
+        self.state.lock().mempool.insert(txid, mempool_tx);
+        txid
+    }
+
+    /// Drop a transaction from the mempool (as if it was evicted or replaced).
+    pub(crate) fn remove_from_mempool(&self, txid: Txid) {
+        self.state.lock().mempool.remove(&txid);
+    }
+
+    /// `getmempoolentry` will fail for this transaction (as if it was just replaced).
+    pub(crate) fn hide_mempool_entry(&self, txid: Txid) {
+        if let Some(mempool_tx) = self.state.lock().mempool.get_mut(&txid) {
+            mempool_tx.has_entry = false;
+        }
+    }
+
+    pub(crate) fn set_txindex(&self, enabled: bool) {
+        self.state.lock().txindex = enabled;
+    }
+
+    pub(crate) fn set_fee_rate(&self, fee_rate: Option<Amount>) {
+        self.state.lock().fee_rate = fee_rate;
+    }
+
+    /// Fail the next call of `method` (failures are queued, so they can be chained).
+    pub(crate) fn fail(&self, method: &'static str, failure: Failure) {
+        let mut state = self.state.lock();
+        state.failures.entry(method).or_default().push_back(failure);
+    }
+
+    /// Lose the p2p connection before sending `blockhash` (once), after the preceding blocks of
+    /// the same request were sent.
+    pub(crate) fn fail_block(&self, blockhash: BlockHash) {
+        self.state.lock().failed_blocks.insert(blockhash);
+    }
+
+    /// Reply `notfound` for `blockhash` (as if it was pruned), the given number of times (or
+    /// until `show_block()` is called).
+    pub(crate) fn hide_block(&self, blockhash: BlockHash, times: Option<usize>) {
+        self.state.lock().hidden_blocks.insert(blockhash, times);
+    }
+
+    pub(crate) fn show_block(&self, blockhash: BlockHash) {
+        self.state.lock().hidden_blocks.remove(&blockhash);
+    }
+
+    pub(crate) fn set_prune_height(&self, prune_height: Option<usize>) {
+        self.state.lock().prune_height = prune_height;
+    }
+
+    /// Delay each of the following calls.
+    pub(crate) fn set_delay(&self, delay: Duration) {
+        self.state.lock().delay = delay;
+    }
+
+    /// The methods called so far (in order), clearing the record.
+    pub(crate) fn take_calls(&self) -> Vec<&'static str> {
+        std::mem::take(&mut self.state.lock().calls)
+    }
+
+    pub(crate) fn tip(&self) -> BlockHash {
+        *self.state.lock().active.last().unwrap()
+    }
+
+    fn call(&self, method: &'static str) -> Result<MutexGuard<'_, State>> {
+        let delay = {
+            let mut state = self.state.lock();
+            state.calls.push(method);
+            let failure = state.failures.get_mut(method).and_then(VecDeque::pop_front);
+            if let Some(failure) = failure {
+                return Err(failure.to_error()).with_context(|| format!("{} failed", method));
+            }
+            state.delay
+        };
+        std::thread::sleep(delay);
+        Ok(self.state.lock())
+    }
+}
+
+impl State {
+    /// Returns `true` if `blockhash` is hidden (counting this time)
+    fn is_hidden(&mut self, blockhash: &BlockHash) -> bool {
+        let times = match self.hidden_blocks.get_mut(blockhash) {
+            Some(times) => times,
+            None => return false,
+        };
+        match times {
+            Some(0) => {
+                self.hidden_blocks.remove(blockhash);
+                false
+            }
+            Some(n) => {
+                *n -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Like `getrawtransaction`: confirmed transactions require `txindex` (or their block hash)
+    fn get_transaction(
+        &self,
+        txid: &Txid,
+        blockhash: Option<BlockHash>,
+    ) -> Result<(&Transaction, Option<BlockHash>)> {
+        if let Some(blockhash) = blockhash {
+            let block = self.blocks.get(&blockhash).context("block not found")?;
+            let tx = block
+                .txdata
+                .iter()
+                .find(|tx| tx.compute_txid() == *txid)
+                .with_context(|| format!("transaction {} not found in block", txid))?;
+            return Ok((tx, Some(blockhash)));
+        }
+        if let Some(mempool_tx) = self.mempool.get(txid) {
+            return Ok((&mempool_tx.tx, None));
+        }
+        if !self.txindex {
+            return Err(no_txindex_error().into());
+        }
+        self.find_confirmed(txid)
+            .with_context(|| format!("transaction {} not found", txid))
+    }
+
+    fn find_confirmed(&self, txid: &Txid) -> Option<(&Transaction, Option<BlockHash>)> {
+        self.active.iter().find_map(|hash| {
+            self.blocks[hash]
+                .txdata
+                .iter()
+                .find(|tx| tx.compute_txid() == *txid)
+                .map(|tx| (tx, Some(*hash)))
+        })
+    }
+}
+
+impl Node for StubNode {
+    fn subversion(&self) -> &str {
+        "/Stub:0.0.0/"
+    }
+
+    fn estimate_fee(&self, _nblocks: u16) -> Result<Option<Amount>> {
+        Ok(self.call("estimatesmartfee")?.fee_rate)
+    }
+
+    fn get_relay_fee(&self) -> Result<Amount> {
+        Ok(self.call("getnetworkinfo")?.relay_fee)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        drop(self.call("sendrawtransaction")?);
+        Ok(self.add_to_mempool(tx.clone(), Amount::ZERO))
+    }
+
+    fn submitpackage(&self, txs: &[Transaction]) -> Result<Value> {
+        drop(self.call("submitpackage")?);
+        for tx in txs {
+            self.add_to_mempool(tx.clone(), Amount::ZERO);
+        }
+        Ok(json!({"package_msg": "success"}))
+    }
+
+    fn get_transaction_info(&self, txid: &Txid, blockhash: Option<BlockHash>) -> Result<Value> {
+        let state = self.call("getrawtransaction")?;
+        let (tx, found) = state.get_transaction(txid, blockhash)?;
+        let mut info = json!({"txid": txid, "hex": serialize_hex(tx), "blockhash": found});
+        if let Some(height) = found.and_then(|hash| state.active.iter().position(|h| *h == hash)) {
+            info["confirmations"] = json!(state.active.len() - height);
+        }
+        Ok(info)
+    }
+
+    fn get_transaction(&self, txid: &Txid, blockhash: Option<BlockHash>) -> Result<Transaction> {
+        let state = self.call("getrawtransaction")?;
+        let (tx, _found) = state.get_transaction(txid, blockhash)?;
+        Ok(tx.clone())
+    }
+
+    fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
+        let state = self.call("getblock")?;
+        let block = state.blocks.get(&blockhash).context("block not found")?;
+        Ok(block.txdata.iter().map(|tx| tx.compute_txid()).collect())
+    }
+
+    fn get_block(&self, blockhash: BlockHash) -> Result<SerBlock> {
+        let mut state = self.call("getblock")?;
+        if state.is_hidden(&blockhash) {
+            return Err(BlockNotAvailable(blockhash).into());
+        }
+        let block = state.blocks.get(&blockhash).context("block not found")?;
+        Ok(serialize(block))
+    }
+
+    fn get_block_hash(&self, height: usize) -> Result<Option<BlockHash>> {
+        Ok(self.call("getblockhash")?.active.get(height).copied())
+    }
+
+    fn get_best_block_hash(&self) -> Result<BlockHash> {
+        Ok(*self.call("getbestblockhash")?.active.last().unwrap())
+    }
+
+    fn get_mempool_info(&self) -> Result<json::GetMempoolInfoResult> {
+        let state = self.call("getmempoolinfo")?;
+        let info = json!({
+            "loaded": true,
+            "size": state.mempool.len(),
+            "bytes": state.mempool.values().map(|m| m.tx.vsize()).sum::<usize>(),
+            "usage": 0,
+            "maxmempool": 300_000_000,
+            "mempoolminfee": state.relay_fee.to_btc(),
+            "minrelaytxfee": state.relay_fee.to_btc(),
+        });
+        Ok(serde_json::from_value(info)?)
+    }
+
+    fn get_mempool_txids(&self) -> Result<Vec<Txid>> {
+        Ok(self
+            .call("getrawmempool")?
+            .mempool
+            .keys()
+            .copied()
+            .collect())
+    }
+
+    fn get_mempool_entries(
+        &self,
+        txids: &[Txid],
+    ) -> Result<Vec<Option<json::GetMempoolEntryResult>>> {
+        let state = self.call("getmempoolentry")?;
+        txids
+            .iter()
+            .map(|txid| {
+                let mempool_tx = match state.mempool.get(txid) {
+                    Some(mempool_tx) if mempool_tx.has_entry => mempool_tx,
+                    _ => return Ok(None),
+                };
+                let tx = &mempool_tx.tx;
+                let fee = mempool_tx.fee.to_btc();
+                let depends: Vec<Txid> = tx
+                    .input
+                    .iter()
+                    .map(|txi| txi.previous_output.txid)
+                    .filter(|txid| state.mempool.contains_key(txid))
+                    .collect();
+                let entry = json!({
+                    "vsize": tx.vsize(),
+                    "weight": tx.weight().to_wu(),
+                    "time": 0,
+                    "height": state.active.len() - 1,
+                    "descendantcount": 1,
+                    "descendantsize": tx.vsize(),
+                    "ancestorcount": 1,
+                    "ancestorsize": tx.vsize(),
+                    "wtxid": tx.compute_wtxid().to_string(),
+                    "fees": {"base": fee, "modified": fee, "ancestor": fee, "descendant": fee},
+                    "depends": depends,
+                    "spentby": [],
+                    "bip125-replaceable": false,
+                });
+                Ok(Some(serde_json::from_value(entry)?))
+            })
+            .collect()
+    }
+
+    fn get_mempool_transactions(&self, txids: &[Txid]) -> Result<Vec<Option<Transaction>>> {
+        let state = self.call("getrawtransaction")?;
+        Ok(txids
+            .iter()
+            .map(|txid| state.mempool.get(txid).map(|m| m.tx.clone()))
+            .collect())
+    }
+
+    fn get_prune_height(&self) -> Result<Option<usize>> {
+        Ok(self.call("getblockchaininfo")?.prune_height)
+    }
+
+    fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
+        let state = self.call("getheaders")?;
+        // find the last common block (like bitcoind does, using the chain's locator)
+        let fork_height = state
+            .active
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(height, hash)| chain.get_block_hash(*height) == Some(**hash))
+            .map(|(height, _hash)| height)
+            .with_context(|| format!("no common block with {} chain", self.network))?;
+        Ok(state.active[fork_height + 1..]
+            .iter()
+            .zip(fork_height + 1..)
+            .map(|(hash, height)| NewHeader::from((state.blocks[hash].header, height)))
+            .collect())
+    }
+
+    fn for_blocks(
+        &self,
+        blockhashes: Vec<BlockHash>,
+        func: &mut dyn FnMut(BlockHash, SerBlock),
+    ) -> Result<()> {
+        if blockhashes.is_empty() {
+            return Ok(()); // like `Daemon`, which doesn't send a request
+        }
+        let mut lost = None;
+        let mut missing = None;
+        let blocks: Vec<(BlockHash, SerBlock)> = {
+            let mut state = self.call("getdata")?;
+            let mut blocks = vec![];
+            for hash in blockhashes {
+                if state.failed_blocks.remove(&hash) {
+                    lost = Some(hash);
+                    break;
+                }
+                if state.is_hidden(&hash) {
+                    missing = Some(hash); // like `p2p::Connection`, on `notfound`
+                    break;
+                }
+                let block = state
+                    .blocks
+                    .get(&hash)
+                    .with_context(|| format!("block {} not found", hash))?;
+                blocks.push((hash, serialize(block)));
+            }
+            blocks
+        };
+        for (hash, block) in blocks {
+            func(hash, block);
+        }
+        match (lost, missing) {
+            // like `p2p::Connection`, when the connection is lost while receiving the blocks
+            (Some(hash), _) => Err(crossbeam_channel::RecvError)
+                .with_context(|| format!("failed to get block {}", hash)),
+            (None, Some(hash)) => Err(BlockNotAvailable(hash).into()),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Failure, StubNode};
+    use crate::{
+        blocks_dir::{self, BlocksDir, TIP_MARGIN},
+        broadcasts::Location,
+        cache::Cache,
+        chain::{Chain, Checkpoints, HeaderStore},
+        config::ConfigBuilder,
+        custom_chain::AddressDecoder,
+        daemon::{extract_bitcoind_error, is_transient, BlockNotAvailable, Node},
+        db::{DBStore, StoreOptions, WriteBatch},
+        dump::ScriptHashDump,
+        events::EventSink,
+        fees::FeeCache,
+        flush::FlushPolicy,
+        index::{Index, IndexOptions},
+        mempool::Mempool,
+        metrics::Metrics,
+        replay::{self, Record, ReplayLog},
+        signals::ExitFlag,
+        status::ScriptHashStatus,
+        subscriptions::Subscriptions,
+        tracker::Tracker,
+        types::{HeaderRow, PrefixLen, ScriptHash, ScriptTypes},
+    };
+
+    use bitcoin::{
+        absolute::LockTime, blockdata::constants::genesis_block, consensus::serialize,
+        hashes::Hash, hex::DisplayHex, transaction::Version, Amount, Network, OutPoint, ScriptBuf,
+        Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    };
+    use parking_lot::RwLock;
+    use serde_json::json;
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn script(n: u8) -> ScriptBuf {
+        ScriptBuf::from_bytes(vec![0x51, 0x01, n]) // unique per `n`
+    }
+
+    fn spend(prevout: OutPoint, to: &ScriptBuf, sats: u64) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: prevout,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(sats),
+                script_pubkey: to.clone(),
+            }],
+        }
+    }
+
+    fn coinbase_outpoint(node: &StubNode, blockhash: bitcoin::BlockHash) -> OutPoint {
+        let txid = node.get_block_txids(blockhash).unwrap()[0];
+        OutPoint::new(txid, 0)
+    }
+
+    fn metrics() -> Metrics {
+        Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    fn sync_index(index: &Index, node: &StubNode) {
+        let exit_flag = ExitFlag::new();
+        while !index.sync(node, &exit_flag).unwrap() {}
+        index.publish();
+    }
+
+    fn new_index(dir: &tempfile::TempDir, metrics: &Metrics) -> Index {
+        new_index_with(
+            dir,
+            metrics,
+            StoreOptions::default(),
+            IndexOptions::default(),
+        )
+    }
+
+    fn new_index_with(
+        dir: &tempfile::TempDir,
+        metrics: &Metrics,
+        store_options: StoreOptions,
+        options: IndexOptions,
+    ) -> Index {
+        let store = DBStore::open(dir.path(), &store_options).unwrap();
+        Index::load(store, Chain::new(Network::Regtest), metrics, &options).unwrap()
+    }
+
+    #[test]
+    fn test_index_sync_and_reorg() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..3 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        sync_index(&index, &node);
+        assert_eq!(index.chain().height(), 3);
+        assert_eq!(index.chain().tip(), node.tip());
+        let chain = index.chain();
+        let funding: Vec<_> = index
+            .filter_by_funding(&chain, ScriptHash::new(&script(1)))
+            .collect();
+        assert_eq!(funding, vec![index.chain().get_block_hash(2).unwrap()]);
+
+        index.take_touched();
+        node.mine(&script(3), vec![]);
+        sync_index(&index, &node);
+        let touched = index.take_touched().unwrap(); // extending the chain is tracked
+        assert_eq!(touched.funding.len(), 1); // the coinbase output of block 4
+
+        // replace the last 2 blocks
+        let stale = node.tip();
+        node.invalidate(2);
+        node.mine(&script(4), vec![]);
+        node.mine(&script(5), vec![]);
+        node.mine(&script(6), vec![]);
+        sync_index(&index, &node);
+        assert_eq!(index.chain().height(), 5);
+        assert_eq!(index.chain().tip(), node.tip());
+        assert_eq!(index.chain().get_block_height(&stale), None);
+        let touched = index.take_touched().unwrap();
+        assert_eq!(touched.stale_blocks.len(), 2);
+        assert!(touched.stale_blocks.contains(&stale));
+        assert_eq!(touched.funding.len(), 3); // the coinbase outputs of the new blocks
+        let chain = index.chain();
+        let funding: Vec<_> = index
+            .filter_by_funding(&chain, ScriptHash::new(&script(6)))
+            .collect();
+        assert_eq!(funding, vec![node.tip()]);
+    }
+
+    #[test]
+    fn test_bench_index() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..5 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        let mut headers = Chain::new(Network::Regtest);
+        headers.update(node.get_new_headers(&headers).unwrap());
+        let (timings, _elapsed) = index.bench(&node, &headers, 3).unwrap();
+        assert_eq!(timings.blocks, 3);
+        assert!(timings.bytes > 0);
+        // a header, a coinbase txid and a funding row per block
+        assert_eq!(timings.rows, 3 * 3);
+        assert_eq!(index.chain().height(), 0); // not published
+
+        let report = json!(timings.report(Duration::from_secs(2)));
+        let keys: Vec<&str> = report
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "blocks",
+                "blocks_per_sec",
+                "bytes",
+                "elapsed_secs",
+                "mb_per_sec",
+                "phases_secs",
+                "rows",
+                "rows_per_sec"
+            ]
+        );
+        assert_eq!(report["blocks"], 3);
+        assert_eq!(report["blocks_per_sec"], 1.5);
+        assert_eq!(report["rows_per_sec"], 4.5);
+        assert_eq!(report["elapsed_secs"], 2.0);
+        let phases = report["phases_secs"].as_object().unwrap();
+        let phases: Vec<&str> = phases.keys().map(String::as_str).collect();
+        assert_eq!(phases, ["fetch", "parse", "sort", "write"]);
+    }
+
+    #[test]
+    fn test_prefetch_blocks() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..5 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let mut index = new_index(&dir, &metrics);
+        index.set_prefetch_blocks(2);
+        sync_index(&index, &node);
+        // only the last blocks are retained (and taken once)
+        let chain = index.chain();
+        let recent: Vec<bitcoin::BlockHash> = index
+            .take_recent_blocks()
+            .into_iter()
+            .map(|(blockhash, _block)| blockhash)
+            .collect();
+        assert_eq!(recent, vec![chain.get_block_hash(4).unwrap(), node.tip()]);
+        assert!(index.take_recent_blocks().is_empty());
+
+        // retained blocks are taken only after they are published
+        node.mine(&script(5), vec![]);
+        let exit_flag = ExitFlag::new();
+        while !index.sync(&node, &exit_flag).unwrap() {}
+        assert!(index.take_recent_blocks().is_empty());
+        index.publish();
+        let recent = index.take_recent_blocks();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].0, node.tip());
+        let cache = Cache::new(&metrics);
+        assert!(cache.prefetch_block(&index.chain(), recent[0].0, &recent[0].1));
+        assert!(cache.get_block_txids(&index.chain(), &node.tip()).is_some());
+    }
+
+    #[test]
+    fn test_index_prefix_len() {
+        let node = StubNode::new(Network::Regtest);
+        let block1 = node.mine(&script(0), vec![]);
+        let block2 = node.mine(&script(1), vec![]);
+        let tx1 = spend(coinbase_outpoint(&node, block1), &script(2), 1_000);
+        let tx2 = spend(coinbase_outpoint(&node, block2), &script(2), 2_000);
+        let tx3 = spend(OutPoint::new(tx1.compute_txid(), 0), &script(3), 900);
+        node.mine(&script(0), vec![tx1.clone(), tx2.clone()]);
+        node.mine(&script(1), vec![tx3.clone()]);
+
+        let (metrics6, metrics10) = (metrics(), metrics());
+        let (dir6, dir10) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let index6 = new_index_with(
+            &dir6,
+            &metrics6,
+            StoreOptions {
+                prefix_len: PrefixLen::Six,
+                ..StoreOptions::default()
+            },
+            IndexOptions::default(),
+        );
+        let index10 = new_index_with(
+            &dir10,
+            &metrics10,
+            StoreOptions {
+                prefix_len: PrefixLen::Ten,
+                ..StoreOptions::default()
+            },
+            IndexOptions::default(),
+        );
+        for index in [&index6, &index10] {
+            index.take_touched();
+            sync_index(index, &node);
+        }
+        let (chain6, chain10) = (index6.chain(), index10.chain());
+        assert_eq!(chain6.tip(), chain10.tip());
+
+        // in-memory prefixes don't depend on the DB's prefix length
+        let (t6, t10) = (
+            index6.take_touched().unwrap(),
+            index10.take_touched().unwrap(),
+        );
+        assert_eq!(t6.funding, t10.funding);
+        assert_eq!(t6.spending, t10.spending);
+        assert!(!t6.funding.is_empty() && !t6.spending.is_empty());
+
+        for i in 0..5 {
+            let scripthash = ScriptHash::new(&script(i));
+            let funding6: Vec<_> = index6.filter_by_funding(&chain6, scripthash).collect();
+            let funding10: Vec<_> = index10.filter_by_funding(&chain10, scripthash).collect();
+            assert_eq!(funding6, funding10, "script {}", i);
+        }
+        let mut outpoints = vec![coinbase_outpoint(&node, block1)];
+        let mut txids = vec![];
+        for tx in [&tx1, &tx2, &tx3] {
+            let txid = tx.compute_txid();
+            outpoints.extend(tx.input.iter().map(|txin| txin.previous_output));
+            outpoints.push(OutPoint::new(txid, 0));
+            txids.push(txid);
+        }
+        for outpoint in outpoints {
+            let spending6: Vec<_> = index6.filter_by_spending(&chain6, outpoint).collect();
+            let spending10: Vec<_> = index10.filter_by_spending(&chain10, outpoint).collect();
+            assert_eq!(spending6, spending10, "{}", outpoint);
+        }
+        for txid in txids {
+            let found6: Vec<_> = index6.filter_by_txid(&chain6, txid).collect();
+            let found10: Vec<_> = index10.filter_by_txid(&chain10, txid).collect();
+            assert_eq!(found6, found10, "{}", txid);
+            assert_eq!(found6.len(), 1);
+        }
+
+        let mempool = RwLock::new(Mempool::new(&metrics6, ScriptTypes::all()));
+        let cache = Cache::new(&metrics6);
+        let status = |index: &Index, i: u8| {
+            let mut status = ScriptHashStatus::new(ScriptHash::new(&script(i)));
+            status
+                .sync(index, index.chain(), &mempool, &node, &cache, true)
+                .unwrap();
+            (json!(status.get_history()), status.statushash())
+        };
+        for i in 0..4 {
+            assert_eq!(status(&index6, i), status(&index10, i), "script {}", i);
+        }
+        assert_eq!(status(&index6, 2).0.as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_recover_inconsistent_tip() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..5 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        sync_index(&new_index(&dir, &metrics()), &node);
+        for i in 5..8 {
+            node.mine(&script(i), vec![]);
+        }
+        let mut full = Chain::new(Network::Regtest);
+        full.update(node.get_new_headers(&full).unwrap());
+        assert_eq!(full.height(), 8);
+
+        // store the headers of blocks 7 & 8 (but not 6), and point the tip at block 8
+        {
+            let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+            store.write(&WriteBatch {
+                tip_row: full.tip().to_byte_array(),
+                header_rows: (7..=8)
+                    .map(|h| HeaderRow::new(full.get_block_header(h).unwrap()).to_db_row())
+                    .collect(),
+                ..Default::default()
+            });
+        }
+
+        let metrics = metrics(); // a new registry, since the index metrics are registered again
+        let index = new_index(&dir, &metrics);
+        assert_eq!(index.chain().height(), 5); // rolled back to the highest consistent header
+        assert_eq!(index.chain().tip(), full.get_block_hash(5).unwrap());
+
+        sync_index(&index, &node); // the missing blocks are re-indexed
+        assert_eq!(index.chain().height(), 8);
+        assert_eq!(index.chain().tip(), node.tip());
+        let chain = index.chain();
+        let funding: Vec<_> = index
+            .filter_by_funding(&chain, ScriptHash::new(&script(5)))
+            .collect();
+        assert_eq!(funding, vec![full.get_block_hash(6).unwrap()]);
+    }
+
+    #[test]
+    fn test_header_sync() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..3 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let index = new_index(&dir, &metrics());
+        sync_index(&index, &node);
+        let failed = node.mine(&script(3), vec![]);
+        node.mine(&script(4), vec![]);
+
+        // the headers are synced (and served) before their blocks are indexed
+        node.fail_block(failed);
+        assert!(index.sync(&node, &ExitFlag::new()).is_err());
+        index.publish();
+        assert_eq!(index.chain().height(), 3);
+        assert_eq!(index.headers().height(), 5);
+        assert_eq!(index.headers().tip(), node.tip());
+
+        // the synced headers are restored after a restart
+        drop(index);
+        let mut index = new_index(&dir, &metrics());
+        assert_eq!(index.chain().height(), 3);
+        assert_eq!(index.headers().tip(), node.tip());
+        sync_index(&index, &node);
+        assert_eq!(index.chain().tip(), node.tip());
+
+        // a disconnected tip is dropped from the headers too
+        node.invalidate(1);
+        sync_index(&index, &node);
+        assert_eq!(index.chain().height(), 4);
+        assert_eq!(index.headers().tip(), node.tip());
+
+        // the headers of a refused reorg are not served
+        index.set_max_reorg_depth(Some(1), false);
+        node.invalidate(2);
+        for i in 0..3 {
+            node.mine(&script(10 + i), vec![]);
+        }
+        sync_index(&index, &node);
+        assert!(index.is_stalled());
+        assert_eq!(index.headers().height(), 4);
+        assert_eq!(index.headers().tip(), index.chain().tip());
+    }
+
+    #[test]
+    fn test_check_chain() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..150 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        sync_index(&index, &node);
+        let tracker = Tracker::from_index(index, metrics);
+        tracker.check_chain(&node).unwrap();
+
+        // bitcoind is running on another network
+        let testnet = StubNode::new(Network::Testnet);
+        let err = tracker.check_chain(&testnet).unwrap_err().to_string();
+        assert!(
+            err.starts_with("bitcoind is running on testnet (genesis block 000000000933ea01"),
+            "{}",
+            err
+        );
+        assert!(err.contains("but the index is of regtest"), "{}", err);
+
+        // another regtest chain (sharing the same genesis block)
+        let other = StubNode::new(Network::Regtest);
+        for i in 0..150 {
+            other.mine(&script(100 + i), vec![]); // different blocks
+        }
+        let err = tracker.check_chain(&other).unwrap_err().to_string();
+        assert!(err.contains("at height 50 differs"), "{}", err);
+
+        // checkpoints above bitcoind's tip are skipped (e.g. bitcoind is still syncing)
+        let short = StubNode::new(Network::Regtest);
+        short.mine(&script(0), vec![]);
+        tracker.check_chain(&short).unwrap();
+    }
+
+    #[test]
+    fn test_custom_chain() {
+        let mut genesis = genesis_block(Network::Regtest);
+        genesis.header.time += 1; // a made-up genesis block
+        let node = StubNode::with_genesis(Network::Regtest, genesis.clone());
+        for i in 0..10 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let config = ConfigBuilder::default()
+            .option("network", "custom")
+            .option("magic", "0a0b0c0d")
+            .option("custom_genesis_hash", genesis.block_hash().to_string())
+            .option(
+                "custom_genesis_header",
+                serialize(&genesis.header).to_lower_hex_string(),
+            )
+            .option("custom_daemon_rpc_port", "18888")
+            .option("custom_daemon_p2p_port", "18889")
+            .option("custom_p2pkh_prefix", "63")
+            .option("custom_p2sh_prefix", "125")
+            .option("custom_bech32_hrp", "nigiri")
+            .db_dir(dir.path())
+            .auth("user", "password")
+            .build()
+            .unwrap();
+
+        // the custom chain is refused by a regtest index
+        let regtest_dir = tempfile::tempdir().unwrap();
+        let regtest = Tracker::from_index(new_index(&regtest_dir, &metrics()), metrics());
+        let err = regtest.check_chain(&node).unwrap_err().to_string();
+        assert!(
+            err.starts_with(&format!(
+                "bitcoind is running on an unknown chain (genesis block {})",
+                genesis.block_hash()
+            )),
+            "{}",
+            err
+        );
+
+        // and served when configured
+        let store = DBStore::open(&config.db_path, &StoreOptions::default()).unwrap();
+        let metrics = metrics();
+        let index = Index::load(
+            store,
+            Chain::for_config(&config),
+            &metrics,
+            &IndexOptions::default(),
+        )
+        .unwrap();
+        sync_index(&index, &node);
+        assert_eq!(index.chain().height(), 10);
+        assert_eq!(index.chain().tip(), node.tip());
+        assert_eq!(index.chain().get_block_hash(0), Some(genesis.block_hash()));
+        let tracker = Tracker::from_index(index, metrics);
+        tracker.check_chain(&node).unwrap();
+    }
+
+    #[test]
+    fn test_mempool_sync() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let funding = coinbase_outpoint(&node, block);
+        let tx1 = spend(funding, &script(1), 1_000_000);
+        let txid1 = node.add_to_mempool(tx1.clone(), Amount::from_sat(2_000));
+        let tx2 = spend(OutPoint::new(txid1, 0), &script(2), 900_000);
+        let txid2 = node.add_to_mempool(tx2.clone(), Amount::from_sat(3_000));
+
+        let metrics = metrics();
+        let exit_flag = ExitFlag::new();
+        let mut mempool = Mempool::new(&metrics, ScriptTypes::all());
+        node.hide_mempool_entry(txid2);
+        mempool.sync(&node, &exit_flag);
+        let entry = mempool.get(&txid1).unwrap();
+        assert_eq!(entry.fee, Amount::from_sat(2_000));
+        assert!(!entry.has_unconfirmed_inputs);
+        assert!(mempool.get(&txid2).is_none()); // missing entry is skipped
+
+        // failures leave the mempool as-is, and are recovered on the next sync
+        node.add_to_mempool(tx2, Amount::from_sat(3_000)); // its entry is available again
+        node.fail("getrawmempool", Failure::Warmup);
+        node.fail("getmempoolinfo", Failure::ConnectionRefused);
+        mempool.sync(&node, &exit_flag);
+        mempool.sync(&node, &exit_flag);
+        assert!(mempool.get(&txid1).is_some());
+        assert!(mempool.get(&txid2).is_none());
+        mempool.sync(&node, &exit_flag);
+        let entry = mempool.get(&txid2).unwrap();
+        assert!(entry.has_unconfirmed_inputs);
+
+        // confirm the first transaction
+        node.mine(&script(0), vec![tx1]);
+        mempool.sync(&node, &exit_flag);
+        assert!(mempool.get(&txid1).is_none());
+        assert!(mempool.get(&txid2).is_some());
+    }
+
+    #[test]
+    fn test_mempool_stats() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let funding = coinbase_outpoint(&node, block);
+        let tx1 = spend(funding, &script(1), 1_000_000);
+        let txid1 = node.add_to_mempool(tx1.clone(), Amount::from_sat(2_000));
+        let tx2 = spend(OutPoint::new(txid1, 0), &script(2), 900_000);
+        let txid2 = node.add_to_mempool(tx2.clone(), Amount::from_sat(3_000));
+        let (vsize1, vsize2) = (tx1.vsize() as u64, tx2.vsize() as u64);
+
+        let metrics = metrics();
+        let exit_flag = ExitFlag::new();
+        let mut mempool = Mempool::new(&metrics, ScriptTypes::all());
+        let stats = mempool.stats();
+        assert_eq!((stats.count, stats.vsize, stats.fees), (0, 0, 0));
+        assert_eq!(stats.last_sync, None);
+        assert_eq!(stats.count_delta, None);
+
+        // a missing entry is reported as a discrepancy vs. bitcoind
+        node.hide_mempool_entry(txid2);
+        mempool.sync(&node, &exit_flag);
+        let stats = mempool.stats();
+        assert_eq!((stats.count, stats.vsize, stats.fees), (1, vsize1, 2_000));
+        assert!(stats.last_sync.is_some());
+        assert_eq!(stats.last_sync_full, Some(true));
+        assert_eq!(stats.daemon_count, Some(2));
+        assert_eq!(stats.count_delta, Some(-1));
+
+        node.add_to_mempool(tx2.clone(), Amount::from_sat(3_000));
+        mempool.sync(&node, &exit_flag);
+        let stats = mempool.stats();
+        assert_eq!(
+            (stats.count, stats.vsize, stats.fees),
+            (2, vsize1 + vsize2, 5_000)
+        );
+        assert_eq!(stats.last_sync_full, Some(false));
+        assert_eq!(stats.count_delta, Some(0));
+        let percentiles: Vec<u8> = stats.fee_rate_percentiles.iter().map(|(p, _)| *p).collect();
+        assert_eq!(percentiles, vec![10, 25, 50, 75, 90]);
+        assert!(stats
+            .fee_rate_percentiles
+            .iter()
+            .all(|(_, rate)| *rate >= 8));
+
+        // confirm both transactions (one at a time)
+        node.mine(&script(0), vec![tx1]);
+        mempool.sync(&node, &exit_flag);
+        let stats = mempool.stats();
+        assert_eq!((stats.count, stats.vsize, stats.fees), (1, vsize2, 3_000));
+        assert_eq!(stats.count_delta, Some(0));
+
+        node.mine(&script(0), vec![tx2]);
+        mempool.sync(&node, &exit_flag);
+        let stats = mempool.stats();
+        assert_eq!((stats.count, stats.vsize, stats.fees), (0, 0, 0));
+        assert_eq!(stats.fee_rate_percentiles[2], (50, 0));
+        assert_eq!(stats.daemon_count, Some(0));
+
+        // only the first sync is full (even if the mempool became empty)
+        let tx3 = spend(OutPoint::new(txid2, 0), &script(3), 800_000);
+        node.add_to_mempool(tx3, Amount::from_sat(4_000));
+        mempool.sync(&node, &exit_flag);
+        let stats = mempool.stats();
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.last_sync_full, Some(false));
+    }
+
+    #[test]
+    fn test_status_sync() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let tx1 = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        let txid1 = tx1.compute_txid();
+        node.mine(&script(0), vec![tx1]);
+        let tx2 = spend(OutPoint::new(txid1, 0), &script(2), 900);
+        let txid2 = node.add_to_mempool(tx2, Amount::from_sat(100));
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        sync_index(&index, &node);
+        let mempool = RwLock::new(Mempool::new(&metrics, ScriptTypes::all()));
+        mempool.write().sync(&node, &ExitFlag::new());
+        let cache = Cache::new(&metrics);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        status
+            .sync(&index, index.chain(), &mempool, &node, &cache, true)
+            .unwrap();
+        assert_eq!(
+            json!(status.get_history()),
+            json!([
+                {"tx_hash": txid1, "height": 2},
+                {"tx_hash": txid2, "height": 0, "fee": 100},
+            ])
+        );
+        assert!(status.get_unspent(&index.chain()).is_empty());
+        assert!(status.statushash().is_some());
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(3)));
+        status
+            .sync(&index, index.chain(), &mempool, &node, &cache, true)
+            .unwrap();
+        assert!(status.get_history().is_empty());
+        assert!(status.statushash().is_none());
+    }
+
+    #[test]
+    fn test_listunspent_daemon_calls() {
+        const BLOCKS: usize = 10;
+        const OUTPUTS: usize = 100; // per block
+        let node = StubNode::new(Network::Regtest);
+        let mut prevout = coinbase_outpoint(&node, node.mine(&script(0), vec![]));
+        for _ in 0..BLOCKS {
+            let mut tx = spend(prevout, &script(1), 1_000);
+            tx.output = vec![tx.output[0].clone(); OUTPUTS];
+            tx.output.push(TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: script(0), // change
+            });
+            prevout = OutPoint::new(tx.compute_txid(), OUTPUTS as u32);
+            node.mine(&script(0), vec![tx]);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        sync_index(&index, &node);
+        let mempool = RwLock::new(Mempool::new(&metrics, ScriptTypes::all()));
+        let cache = Cache::new(&metrics);
+        node.take_calls();
+
+        let scripthash = ScriptHash::new(&script(1));
+        let chain = index.chain();
+        // a row per funded output, and a single lookup per block (storing its output indices)
+        assert_eq!(index.funding_rows(scripthash).count(), BLOCKS * OUTPUTS);
+        let blocks: Vec<_> = index
+            .filter_by_funding_outputs(&chain, scripthash)
+            .collect();
+        assert_eq!(blocks.len(), BLOCKS);
+        let vouts: Vec<u32> = (0..OUTPUTS as u32).collect();
+        assert!(blocks
+            .iter()
+            .all(|(_, indexed)| indexed.as_deref() == Some(&vouts[..])));
+
+        let mut status = ScriptHashStatus::new(scripthash);
+        status
+            .sync(&index, chain.clone(), &mempool, &node, &cache, true)
+            .unwrap();
+        assert_eq!(status.get_unspent(&chain).len(), BLOCKS * OUTPUTS);
+        // a single request fetches the funding blocks (no transactions are fetched separately,
+        // and only the indexed outputs of each block are checked for the scripthash)
+        assert_eq!(node.take_calls(), vec!["getdata"]);
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(1), vec![]);
+        let coinbase = coinbase_outpoint(&node, block);
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        let mempool = RwLock::new(Mempool::new(&metrics, ScriptTypes::all()));
+        let cache = Cache::new(&metrics);
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        let sync = |status: &mut ScriptHashStatus| {
+            sync_index(&index, &node);
+            status
+                .sync(&index, index.chain(), &mempool, &node, &cache, true)
+                .unwrap();
+            (
+                json!(status.get_unspent(&index.chain())),
+                json!(status.get_balance(&index.chain())),
+            )
+        };
+
+        let (unspent, balance) = sync(&mut status);
+        let value = unspent[0]["value"].as_u64().unwrap();
+        assert_eq!(
+            unspent,
+            json!([{"height": 1, "tx_hash": coinbase.txid, "tx_pos": 0, "value": value, "immature": true}])
+        );
+        assert_eq!(
+            balance,
+            json!({"confirmed": 0, "unconfirmed": 0, "immature": value})
+        );
+
+        // the coinbase can be spent by the 100th block after it (i.e. after 99 more blocks)
+        for _ in 0..98 {
+            node.mine(&script(0), vec![]);
+        }
+        let (unspent, _balance) = sync(&mut status);
+        assert_eq!(unspent[0]["immature"], json!(true));
+        node.mine(&script(0), vec![]);
+        let (unspent, balance) = sync(&mut status);
+        assert_eq!(
+            unspent,
+            json!([{"height": 1, "tx_hash": coinbase.txid, "tx_pos": 0, "value": value}])
+        );
+        assert_eq!(balance, json!({"confirmed": value, "unconfirmed": 0}));
+    }
+
+    #[test]
+    fn test_balance_cache() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let funding = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        let funded = OutPoint::new(funding.compute_txid(), 0);
+        node.mine(&script(0), vec![funding]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        let mempool = RwLock::new(Mempool::new(&metrics, ScriptTypes::all()));
+        let cache = Cache::new(&metrics);
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+        let sync = |status: &mut ScriptHashStatus, confirmed_touched| {
+            sync_index(&index, &node);
+            mempool.write().sync(&node, &ExitFlag::new());
+            status
+                .sync(
+                    &index,
+                    index.chain(),
+                    &mempool,
+                    &node,
+                    &cache,
+                    confirmed_touched,
+                )
+                .unwrap();
+        };
+        let balance = |status: &ScriptHashStatus| json!(status.get_balance(&index.chain()));
+
+        sync(&mut status, true);
+        for _ in 0..3 {
+            assert_eq!(
+                balance(&status),
+                json!({"confirmed": 1_000, "unconfirmed": 0})
+            );
+        }
+        assert_eq!(status.confirmed_unspent_computed(), 1);
+
+        // mempool changes don't recompute the confirmed part
+        let spending = spend(funded, &script(2), 400);
+        let coinbase = coinbase_outpoint(&node, node.tip());
+        let mut txs = vec![spending.clone(), spend(coinbase, &script(1), 300)];
+        node.add_to_mempool(txs[0].clone(), Amount::from_sat(600));
+        node.add_to_mempool(txs[1].clone(), Amount::from_sat(100));
+        sync(&mut status, false);
+        assert_eq!(
+            balance(&status),
+            json!({"confirmed": 1_000, "unconfirmed": -700})
+        );
+        let funded = OutPoint::new(spending.compute_txid(), 0);
+        txs.push(spend(funded, &script(1), 200));
+        node.add_to_mempool(txs[2].clone(), Amount::from_sat(200));
+        sync(&mut status, false);
+        assert_eq!(
+            balance(&status),
+            json!({"confirmed": 1_000, "unconfirmed": -500})
+        );
+        assert_eq!(status.confirmed_unspent_computed(), 1);
+
+        // a new block does
+        node.mine(&script(0), txs);
+        sync(&mut status, true);
+        assert_eq!(
+            balance(&status),
+            json!({"confirmed": 500, "unconfirmed": 0})
+        );
+        assert_eq!(
+            balance(&status),
+            json!({"confirmed": 500, "unconfirmed": 0})
+        );
+        assert_eq!(status.confirmed_unspent_computed(), 2);
+    }
+
+    #[test]
+    fn test_empty_scripthashes() {
+        let node = StubNode::new(Network::Regtest);
+        let block1 = node.mine(&script(0), vec![]);
+        let block2 = node.mine(&script(0), vec![]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        let exit_flag = ExitFlag::new();
+        let sync = || {
+            while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        };
+        sync();
+        tracker.take_touched(); // as done by the server after each sync
+
+        let history = |scripthash| {
+            let status = tracker
+                .new_scripthash_status(scripthash, &node, &cache)
+                .unwrap();
+            json!(status.get_history())
+        };
+        let (scripthash1, scripthash2) = (ScriptHash::new(&script(1)), ScriptHash::new(&script(2)));
+        for scripthash in [scripthash1, scripthash2] {
+            assert_eq!(history(scripthash), json!([]));
+            assert!(tracker.is_known_empty(&scripthash));
+            assert_eq!(history(scripthash), json!([]));
+        }
+
+        // a new mempool transaction invalidates only its funded scripthash
+        let tx1 = spend(coinbase_outpoint(&node, block1), &script(1), 1_000);
+        let txid1 = node.add_to_mempool(tx1.clone(), Amount::from_sat(100));
+        sync();
+        assert!(!tracker.is_known_empty(&scripthash1));
+        assert!(tracker.is_known_empty(&scripthash2));
+        assert_eq!(
+            history(scripthash1),
+            json!([{"tx_hash": txid1, "height": 0, "fee": 100}])
+        );
+        assert!(!tracker.is_known_empty(&scripthash1));
+
+        // so does a new block
+        let tx2 = spend(coinbase_outpoint(&node, block2), &script(2), 1_000);
+        let txid2 = tx2.compute_txid();
+        node.mine(&script(0), vec![tx1, tx2]);
+        sync();
+        assert!(!tracker.is_known_empty(&scripthash2));
+        assert_eq!(
+            history(scripthash2),
+            json!([{"tx_hash": txid2, "height": 3}])
+        );
+    }
+
+    #[test]
+    fn test_status_stats() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let tx1 = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        let txid1 = tx1.compute_txid();
+        node.mine(&script(0), vec![tx1]);
+        let tx2 = spend(OutPoint::new(txid1, 0), &script(1), 900); // spending and funding
+        let txid2 = tx2.compute_txid();
+        node.mine(&script(0), vec![tx2]);
+        let tx3 = spend(OutPoint::new(txid2, 0), &script(2), 800);
+        let txid3 = node.add_to_mempool(tx3, Amount::from_sat(100));
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index_with(
+            &dir,
+            &metrics,
+            StoreOptions::default(),
+            IndexOptions {
+                lookup_limit: Some(1),
+                ..IndexOptions::default()
+            },
+        );
+        sync_index(&index, &node);
+        let mempool = RwLock::new(Mempool::new(&metrics, ScriptTypes::all()));
+        mempool.write().sync(&node, &ExitFlag::new());
+        let cache = Cache::new(&metrics);
+        let scripthash = ScriptHash::new(&script(1));
+
+        // the lookup limit fails the full sync, but not the partial one
+        let mut status = ScriptHashStatus::new(scripthash);
+        assert!(status
+            .sync(&index, index.chain(), &mempool, &node, &cache, true)
+            .is_err());
+        let mut status = ScriptHashStatus::new_partial(scripthash);
+        status
+            .sync(&index, index.chain(), &mempool, &node, &cache, true)
+            .unwrap();
+        assert_eq!(
+            json!(status.get_stats(&index.chain())),
+            json!({
+                "tx_count": 2, "funded_count": 1, "spent_count": 1, "first_height": 2,
+                "last_height": 3, "unconfirmed_count": 0, "partial": true,
+            })
+        );
+
+        // without a lookup limit (using a new registry, since index metrics are registered once)
+        let dir = tempfile::tempdir().unwrap();
+        let index = new_index(&dir, &self::metrics());
+        sync_index(&index, &node);
+        let mut status = ScriptHashStatus::new_partial(scripthash);
+        status
+            .sync(&index, index.chain(), &mempool, &node, &cache, true)
+            .unwrap();
+        assert_eq!(
+            json!(status.get_history()),
+            json!([
+                {"tx_hash": txid1, "height": 2},
+                {"tx_hash": txid2, "height": 3},
+                {"tx_hash": txid3, "height": 0, "fee": 100},
+            ])
+        );
+        assert_eq!(
+            json!(status.get_stats(&index.chain())),
+            json!({
+                "tx_count": 3, "funded_count": 2, "spent_count": 2, "first_height": 2,
+                "last_height": 3, "unconfirmed_count": 1, "partial": false,
+            })
+        );
+
+        let status = ScriptHashStatus::new(ScriptHash::new(&script(3)));
+        assert_eq!(
+            json!(status.get_stats(&index.chain())),
+            json!({
+                "tx_count": 0, "funded_count": 0, "spent_count": 0, "first_height": null,
+                "last_height": null, "unconfirmed_count": 0, "partial": false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_status_reorg() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let tx = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        let txid = tx.compute_txid();
+        let confirming = node.mine(&script(0), vec![tx.clone()]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        sync_index(&index, &node);
+        let mempool = RwLock::new(Mempool::new(&metrics, ScriptTypes::all()));
+        mempool.write().sync(&node, &ExitFlag::new());
+        let cache = Cache::new(&metrics);
+        let scripthash = ScriptHash::new(&script(1));
+        let mut status = ScriptHashStatus::new(scripthash);
+        status
+            .sync(&index, index.chain(), &mempool, &node, &cache, true)
+            .unwrap();
+        let confirmed = status.statushash();
+        assert_eq!(
+            json!(status.get_history()),
+            json!([{"tx_hash": txid, "height": 2}])
+        );
+
+        let subs = Subscriptions::default();
+        subs.subscribe(1, scripthash);
+        index.take_touched();
+        mempool.write().take_touched();
+
+        // orphan the confirming block (its transaction returns to the mempool)
+        node.invalidate(1);
+        sync_index(&index, &node);
+        assert_eq!(index.chain().height(), 1); // even without a replacing block
+        mempool.write().sync(&node, &ExitFlag::new());
+        let (block_touched, mempool_touched) =
+            (index.take_touched(), mempool.write().take_touched());
+        let affected = subs.affected(block_touched.as_ref(), mempool_touched.as_ref());
+        assert!(affected.is_reorged(&status));
+        status
+            .sync(&index, index.chain(), &mempool, &node, &cache, true)
+            .unwrap();
+        assert!(!status.has_blocks(&HashSet::from([confirming])));
+        assert_eq!(
+            json!(status.get_history()),
+            json!([{"tx_hash": txid, "height": 0, "fee": 0}])
+        );
+        assert_ne!(status.statushash(), confirmed);
+
+        // re-confirm it at a different height
+        node.mine(&script(2), vec![]);
+        node.mine(&script(2), vec![]);
+        node.mine(&script(2), vec![tx]);
+        sync_index(&index, &node);
+        mempool.write().sync(&node, &ExitFlag::new());
+        status
+            .sync(&index, index.chain(), &mempool, &node, &cache, true)
+            .unwrap();
+        assert_eq!(
+            json!(status.get_history()),
+            json!([{"tx_hash": txid, "height": 4}])
+        );
+    }
+
+    #[test]
+    fn test_deep_reorg() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..5 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let mut index = new_index(&dir, &metrics);
+        index.set_max_reorg_depth(Some(2), false);
+        sync_index(&index, &node);
+        let tip = node.tip();
+
+        // a fork replacing the last 3 blocks is refused (also when retried)
+        node.invalidate(3);
+        for i in 0..4 {
+            node.mine(&script(10 + i), vec![]);
+        }
+        for _ in 0..2 {
+            sync_index(&index, &node);
+            assert!(index.is_stalled());
+            assert_eq!(index.chain().tip(), tip);
+        }
+
+        // until the operator authorizes it
+        assert_eq!(index.allow_deep_reorg(), Some(3));
+        sync_index(&index, &node);
+        assert!(!index.is_stalled());
+        assert_eq!(index.chain().tip(), node.tip());
+        assert_eq!(index.chain().height(), 6);
+        assert_eq!(index.allow_deep_reorg(), None); // not stalled
+
+        // shallow reorgs are not limited
+        node.invalidate(2);
+        for i in 0..3 {
+            node.mine(&script(20 + i), vec![]);
+        }
+        sync_index(&index, &node);
+        assert!(!index.is_stalled());
+        assert_eq!(index.chain().tip(), node.tip());
+
+        // disconnecting the tip (without a replacing chain) is limited too
+        let tip = node.tip();
+        node.invalidate(3);
+        sync_index(&index, &node);
+        assert!(index.is_stalled());
+        assert_eq!(index.chain().tip(), tip);
+
+        // `--allow-deep-reorg` authorizes a single deep reorg
+        index.set_max_reorg_depth(Some(2), true);
+        sync_index(&index, &node);
+        assert!(!index.is_stalled());
+        assert_eq!(index.chain().tip(), node.tip());
+        assert_eq!(index.chain().height(), 4);
+
+        node.invalidate(3);
+        sync_index(&index, &node);
+        assert!(index.is_stalled());
+        assert_eq!(index.chain().height(), 4);
+    }
+
+    #[test]
+    fn test_allow_deep_reorg_once() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..5 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let mut index = new_index(&dir, &metrics);
+        // `--allow-deep-reorg` is set, but the next sync has no deep reorg to authorize
+        index.set_max_reorg_depth(Some(2), true);
+        sync_index(&index, &node);
+        let tip = node.tip();
+
+        // so a later deep reorg is refused
+        node.invalidate(3);
+        for i in 0..4 {
+            node.mine(&script(10 + i), vec![]);
+        }
+        sync_index(&index, &node);
+        assert!(index.is_stalled());
+        assert_eq!(index.chain().tip(), tip);
+    }
+
+    #[test]
+    fn test_missing_block() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..5 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        sync_index(&index, &node);
+        assert_eq!(index.missing_block(), None);
+
+        // a transient failure is retried (on each sync)
+        node.mine(&script(5), vec![]);
+        let missing = node.mine(&script(6), vec![]);
+        node.mine(&script(7), vec![]);
+        node.hide_block(missing, Some(2));
+        for failures in 1..=2 {
+            sync_index(&index, &node);
+            assert_eq!(index.chain().height(), 5);
+            assert!(!index.is_stalled());
+            let missing_block = index.missing_block().unwrap();
+            assert_eq!(missing_block.height, 7);
+            assert_eq!(missing_block.blockhash, missing);
+            assert_eq!(missing_block.failures, failures);
+        }
+        sync_index(&index, &node);
+        assert_eq!(index.chain().tip(), node.tip());
+        assert_eq!(index.missing_block(), None);
+
+        // a permanent failure stalls the index, until the block becomes available
+        let missing = node.mine(&script(8), vec![]);
+        node.hide_block(missing, None);
+        node.set_prune_height(Some(10));
+        for failures in 1..=5 {
+            sync_index(&index, &node);
+            assert_eq!(index.chain().height(), 8);
+            assert_eq!(index.missing_block().unwrap().failures, failures);
+            assert_eq!(index.is_stalled(), failures > 3);
+        }
+        let missing_block = index.missing_block().unwrap();
+        assert_eq!(missing_block.prune_height, Some(10));
+        assert_eq!(
+            missing_block.to_string(),
+            format!(
+                "block {} at height 9 is not available from bitcoind \
+                (bitcoind is pruned below height 10: electrs requires a non-pruned node)",
+                missing
+            )
+        );
+        node.set_prune_height(None);
+        node.show_block(missing);
+        sync_index(&index, &node);
+        assert!(!index.is_stalled());
+        assert_eq!(index.chain().tip(), node.tip());
+        assert_eq!(index.missing_block(), None);
+
+        // also on the RPC path
+        node.hide_block(missing, Some(1));
+        let err = node.get_block(missing).unwrap_err();
+        assert_eq!(BlockNotAvailable::find(&err), Some(missing));
+        assert!(node.get_block(missing).is_ok());
+    }
+
+    #[test]
+    fn test_totals_across_restarts() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..3 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let index = new_index(&dir, &metrics());
+            sync_index(&index, &node);
+            node.invalidate(1);
+            node.mine(&script(10), vec![]);
+            node.mine(&script(11), vec![]);
+            sync_index(&index, &node);
+            let totals = index.totals().values();
+            assert_eq!(totals["blocks_indexed"], 5);
+            assert_eq!(totals["reorgs"], 1);
+            assert_eq!(totals["restarts"], 0);
+        } // persisted on shutdown
+
+        // the counters continue after a restart (with fresh metrics)
+        let index = new_index(&dir, &metrics());
+        let totals = index.totals().values();
+        assert_eq!(totals["blocks_indexed"], 5);
+        assert_eq!(totals["reorgs"], 1);
+        assert_eq!(totals["restarts"], 1);
+        node.mine(&script(12), vec![]);
+        sync_index(&index, &node);
+        assert_eq!(index.totals().values()["blocks_indexed"], 6);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let node = StubNode::new(Network::Regtest);
+        let blocks: Vec<_> = (0..3).map(|i| node.mine(&script(i), vec![])).collect();
+        let checkpoint = blocks[1]; // at height 2
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = new_index(&dir, &metrics());
+        index.set_checkpoints(Checkpoints::new(vec![(2, checkpoint)]));
+        sync_index(&index, &node);
+        assert!(!index.is_stalled());
+        let tip = node.tip();
+
+        // a fork contradicting the checkpoint is refused (also when retried)
+        node.invalidate(2);
+        let fork = node.mine(&script(10), vec![]);
+        node.mine(&script(11), vec![]);
+        for _ in 0..2 {
+            sync_index(&index, &node);
+            assert!(index.is_stalled());
+            assert_eq!(index.chain().tip(), tip);
+            assert_eq!(index.headers().tip(), tip);
+        }
+
+        // a loaded chain contradicting the checkpoints is rolled back (and re-indexed)
+        drop(index);
+        let mut index = new_index(&dir, &metrics());
+        index.set_checkpoints(Checkpoints::new(vec![(2, fork)]));
+        assert_eq!(index.chain().height(), 1);
+        assert_eq!(index.headers().height(), 1);
+        sync_index(&index, &node);
+        assert!(!index.is_stalled());
+        assert_eq!(index.chain().tip(), node.tip());
+    }
+
+    #[test]
+    fn test_get_block() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let mut tx = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        tx.output[0].script_pubkey = ScriptBuf::from(vec![0x6a; 5 << 20]); // a >4 MB block
+        let large = node.mine(&script(2), vec![tx]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        while !tracker.sync(&node, &ExitFlag::new(), &cache).unwrap() {}
+        node.take_calls();
+
+        let get_block = || {
+            tracker
+                .get_block(&node, &cache, large, |block| block.to_vec())
+                .unwrap()
+        };
+        let block: bitcoin::Block = bitcoin::consensus::deserialize(&get_block()).unwrap();
+        assert_eq!(block.block_hash(), large);
+        assert!(serialize(&block).len() > 5 << 20);
+        assert_eq!(get_block(), serialize(&block)); // cached
+        assert_eq!(node.take_calls(), vec!["getblock"]);
+    }
+
+    #[test]
+    fn test_blocks_dir_sync() {
+        let node = StubNode::new(Network::Regtest);
+        let count = TIP_MARGIN + 30;
+        let blockhashes: Vec<_> = (0..count)
+            .map(|i| node.mine(&script(i as u8), vec![]))
+            .collect();
+        let other = StubNode::new(Network::Regtest);
+        let stale = other.get_block(other.mine(&script(0xFF), vec![])).unwrap();
+
+        let blocks: Vec<_> = blockhashes
+            .iter()
+            .map(|hash| node.get_block(*hash).unwrap())
+            .collect();
+        let files: [Vec<&[u8]>; 2] = [
+            blocks[..10].iter().map(Vec::as_slice).collect(),
+            blocks[10..].iter().map(Vec::as_slice).collect(),
+        ];
+        let blocks_path = tempfile::tempdir().unwrap();
+        let magic = Network::Regtest.magic();
+        for (i, mut file) in files.into_iter().enumerate() {
+            if i == 0 {
+                file.insert(5, &stale);
+            }
+            let path = blocks_path.path().join(format!("blk{:05}.dat", i));
+            blocks_dir::write_blk_file(&path, magic, None, &file).unwrap();
+        }
+        // the blocks before the tip region are read from the files
+        for hash in &blockhashes[..count - TIP_MARGIN] {
+            node.fail_block(*hash);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let mut index = new_index(&dir, &metrics);
+        index.set_blocks_dir(BlocksDir::new(blocks_path.path().to_owned(), magic));
+        sync_index(&index, &node);
+        assert_eq!(index.chain().height(), count);
+        assert_eq!(index.chain().tip(), node.tip());
+        let chain = index.chain();
+        for i in [0, 5, count - 1] {
+            let funding: Vec<_> = index
+                .filter_by_funding(&chain, ScriptHash::new(&script(i as u8)))
+                .collect();
+            assert_eq!(funding, vec![blockhashes[i]]);
+        }
+
+        // the following blocks are fetched from bitcoind
+        let hash = node.mine(&script(0xFE), vec![]);
+        node.fail_block(hash);
+        let exit_flag = ExitFlag::new();
+        assert!(index.sync(&node, &exit_flag).is_err());
+    }
+
+    #[test]
+    fn test_recent_headers_in_memory() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..40 {
+            node.mine(&script(i), vec![]);
+        }
+        let full_dir = tempfile::tempdir().unwrap();
+        let full = new_index(&full_dir, &metrics());
+        let dir = tempfile::tempdir().unwrap();
+        let index = new_index_with(
+            &dir,
+            &metrics(),
+            StoreOptions::default(),
+            IndexOptions {
+                recent_headers: Some(10),
+                ..IndexOptions::default()
+            },
+        );
+
+        // deep queries are served from the DB
+        let assert_same_chain = |index: &Index| {
+            sync_index(&full, &node);
+            let (chain, expected) = (index.chain(), full.chain());
+            assert_eq!(chain.tip(), node.tip());
+            for height in 0..=expected.height() {
+                let hash = expected.get_block_hash(height).unwrap();
+                assert_eq!(chain.get_block_hash(height), Some(hash));
+                assert_eq!(chain.get_block_height(&hash), Some(height));
+                let header = expected.get_block_header(height);
+                assert_eq!(chain.get_block_header(height), header);
+            }
+            let all = 0..expected.height() + 1;
+            assert_eq!(
+                chain.get_block_headers_hex(all.clone()),
+                expected.get_block_headers_hex(all)
+            );
+            let (proof, root) = chain.header_proof(3, 35).unwrap();
+            let (expected_proof, expected_root) = expected.header_proof(3, 35).unwrap();
+            assert_eq!(
+                (proof.to_hex(), root),
+                (expected_proof.to_hex(), expected_root)
+            );
+            let funding: Vec<_> = index
+                .filter_by_funding(&chain, ScriptHash::new(&script(2)))
+                .collect();
+            assert_eq!(funding, vec![chain.get_block_hash(3).unwrap()]);
+        };
+        sync_index(&index, &node);
+        assert_same_chain(&index);
+
+        // a reorg deeper than the in-memory headers
+        let stale = node.tip();
+        node.invalidate(15);
+        for i in 0..20 {
+            node.mine(&script(100 + i), vec![]);
+        }
+        sync_index(&index, &node);
+        assert_same_chain(&index);
+        assert_eq!(index.chain().get_block_height(&stale), None);
+
+        // only the recent headers are loaded
+        drop(index);
+        let index = new_index_with(
+            &dir,
+            &metrics(),
+            StoreOptions::default(),
+            IndexOptions {
+                recent_headers: Some(10),
+                ..IndexOptions::default()
+            },
+        );
+        assert_same_chain(&index);
+
+        // the headers are stored by height only if they may be evicted
+        assert!(index.store().has_height_rows());
+        assert!(!full.store().has_height_rows());
+        assert_eq!(full.store().header_at(1), None);
+        drop(index);
+        let index = new_index(&dir, &metrics());
+        assert!(!index.store().has_height_rows());
+        assert_eq!(index.store().header_at(1), None);
+        for i in 0..5 {
+            node.mine(&script(200 + i), vec![]);
+        }
+        sync_index(&index, &node);
+        drop(index);
+        let index = new_index_with(
+            &dir,
+            &metrics(),
+            StoreOptions::default(),
+            IndexOptions {
+                recent_headers: Some(10),
+                ..IndexOptions::default()
+            },
+        );
+        assert!(index.store().has_height_rows()); // written for the whole chain
+        assert_same_chain(&index);
+    }
+
+    #[test]
+    fn test_noop_sync() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let tx = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        node.add_to_mempool(tx.clone(), Amount::from_sat(100));
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        let generations = tracker.generations();
+        let histogram = tracker.fees_histogram();
+        assert_eq!(tracker.fees_histogram(), histogram);
+        assert_eq!(tracker.fees_histogram_computed(), 1);
+        let epoch = tracker.empty_epoch();
+
+        // neither the chain nor the mempool has changed: nothing is recomputed
+        for _ in 0..3 {
+            assert!(tracker.sync(&node, &exit_flag, &cache).unwrap());
+        }
+        assert_eq!(tracker.generations(), generations);
+        assert_eq!(tracker.fees_histogram(), histogram);
+        assert_eq!(tracker.fees_histogram_computed(), 1);
+        assert_eq!(tracker.empty_epoch(), epoch);
+
+        // a new mempool transaction
+        let tx2 = spend(OutPoint::new(tx.compute_txid(), 0), &script(2), 500);
+        node.add_to_mempool(tx2.clone(), Amount::from_sat(500));
+        assert!(tracker.sync(&node, &exit_flag, &cache).unwrap());
+        let next = tracker.generations();
+        assert_eq!(next.chain, generations.chain);
+        assert_eq!(next.mempool, generations.mempool + 1);
+        assert_ne!(tracker.fees_histogram(), histogram);
+        assert_eq!(tracker.fees_histogram_computed(), 2);
+        assert_eq!(tracker.empty_epoch(), epoch + 1);
+
+        // a new block (confirming the mempool transactions)
+        node.mine(&script(0), vec![tx, tx2]);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        let last = tracker.generations();
+        assert_eq!(last.chain, next.chain + 1);
+        assert_eq!(last.mempool, next.mempool + 1);
+        assert_eq!(tracker.fees_histogram(), json!([]));
+        assert_eq!(tracker.fees_histogram_computed(), 3);
+        assert_eq!(tracker.empty_epoch(), epoch + 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_event_socket() {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::net::UnixStream;
+
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+
+        let (dir, sockets) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let watched = ScriptHash::new(&script(1));
+        let watch_file = sockets.path().join("watched.txt");
+        std::fs::write(&watch_file, format!("# wallet\n{}\n", watched)).unwrap();
+        let path = sockets.path().join("events.sock");
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        let sink = EventSink::new(
+            &path,
+            Some(watch_file),
+            AddressDecoder::Network(Network::Regtest),
+            100,
+            tracker.metrics(),
+        )
+        .unwrap();
+        let exit_flag = ExitFlag::new();
+        let sync = || {
+            while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+            sink.update(&tracker);
+        };
+        sync(); // the initial chain is not reported
+
+        let consumer = UnixStream::connect(&path).unwrap();
+        consumer
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut lines = BufReader::new(consumer).lines();
+        let mut next = || -> serde_json::Value {
+            serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap()
+        };
+        assert_eq!(
+            next(),
+            json!({"event": "sync_state_changed", "state": "synced"})
+        );
+
+        // a mempool transaction funding a watched scripthash (reported once)
+        let tx = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        let txid = node.add_to_mempool(tx.clone(), Amount::from_sat(100));
+        sync();
+        sync();
+        let funding = json!({
+            "event": "mempool_tx_for_watched",
+            "scripthash": watched,
+            "txid": txid,
+            "value": 1_000,
+        });
+        assert_eq!(next(), funding);
+
+        // new blocks (confirming it)
+        let block2 = node.mine(&script(0), vec![tx]);
+        let block3 = node.mine(&script(0), vec![]);
+        sync();
+        assert_eq!(
+            next(),
+            json!({"event": "block_connected", "height": 2, "hash": block2})
+        );
+        assert_eq!(
+            next(),
+            json!({"event": "block_connected", "height": 3, "hash": block3})
+        );
+
+        // a reorg (returning the transaction to the mempool)
+        node.invalidate(2);
+        let blocks: Vec<_> = (0..3).map(|i| node.mine(&script(10 + i), vec![])).collect();
+        sync();
+        assert_eq!(next(), json!({"event": "reorg", "depth": 2}));
+        for (i, blockhash) in blocks.iter().enumerate() {
+            assert_eq!(
+                next(),
+                json!({"event": "block_connected", "height": 2 + i, "hash": blockhash})
+            );
+        }
+        assert_eq!(next(), funding);
+
+        // the socket is removed when the sink is dropped
+        drop(sink);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_replay_log() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+
+        let (dir, logs) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let path = logs.path().join("replay.log");
+        let magic = Network::Regtest.magic();
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        let log = ReplayLog::open(&path, 1 << 20, magic).unwrap();
+        let exit_flag = ExitFlag::new();
+        let sync = || {
+            while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+            log.record(&tracker);
+        };
+        sync();
+
+        // a transaction is confirmed, reorged out (back into the mempool) and confirmed again
+        let tx = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        node.add_to_mempool(tx.clone(), Amount::from_sat(100));
+        sync();
+        let block2 = node.mine(&script(0), vec![tx.clone()]);
+        let block3 = node.mine(&script(0), vec![]);
+        sync();
+        node.invalidate(2);
+        let block2b = node.mine(&script(2), vec![]);
+        sync();
+        node.invalidate(1);
+        let blocks: Vec<_> = (0..3).map(|i| node.mine(&script(10 + i), vec![])).collect();
+        let block5 = node.mine(&script(3), vec![tx]);
+        sync();
+
+        let records = replay::read(&path, magic).unwrap();
+        let steps: Vec<&Record> = records
+            .iter()
+            .filter(|record| matches!(record, Record::Blocks { .. }))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                &Record::Blocks {
+                    disconnected: vec![],
+                    connected: vec![block2, block3],
+                },
+                &Record::Blocks {
+                    disconnected: vec![block3, block2],
+                    connected: vec![block2b],
+                },
+                &Record::Blocks {
+                    disconnected: vec![block2b],
+                    connected: [blocks.as_slice(), &[block5]].concat(),
+                },
+            ]
+        );
+        let mempool_syncs = records.len() - steps.len() - 1; // following the `Start` record
+        assert_eq!(
+            records[0],
+            Record::Start {
+                height: 1,
+                tip: block
+            }
+        );
+
+        // the captured log is replayed into a new index, which is compared with a clean one
+        let (replayed_dir, clean_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (metrics1, metrics2) = (metrics(), metrics());
+        let replayed = new_index(&replayed_dir, &metrics1);
+        let clean = new_index(&clean_dir, &metrics2);
+        let report = replay::replay(
+            &records,
+            &node,
+            Chain::new(Network::Regtest),
+            &replayed,
+            &clean,
+            &exit_flag,
+        )
+        .unwrap();
+        report.check().unwrap();
+        assert_eq!(report.steps, 3);
+        assert_eq!(report.mempool_syncs, mempool_syncs);
+        assert_eq!((report.height, report.tip), (5, block5));
+        let funding = report
+            .column_families
+            .iter()
+            .find(|cf| cf.name == "funding")
+            .unwrap();
+        assert_eq!(funding.missing, 0);
+        assert!(funding.stale > 0); // the disconnected blocks' rows are kept
+        assert_ne!(funding.digest, funding.clean_digest);
+
+        // a log which doesn't match the node's chain is rejected
+        let err = replay::replay(
+            &records[1..],
+            &node,
+            Chain::new(Network::Regtest),
+            &new_index(&tempfile::tempdir().unwrap(), &metrics()),
+            &new_index(&tempfile::tempdir().unwrap(), &metrics()),
+            &exit_flag,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("doesn't start with"), "{}", err);
+    }
+
+    #[test]
+    fn test_replay_fixture() {
+        // the stub's mining is deterministic, so its blocks match the ones recorded in the log
+        let node = StubNode::new(Network::Regtest);
+        let block1 = node.mine(&script(0), vec![]);
+        let block2 = node.mine(&script(0), vec![]);
+        let block3 = node.mine(&script(0), vec![]);
+        node.invalidate(2);
+        let block2b = node.mine(&script(2), vec![]);
+        let block3b = node.mine(&script(2), vec![]);
+
+        // captured while the stub's chain was reorged by 2 blocks
+        let records =
+            replay::read(Path::new("src/tests/replay.log"), Network::Regtest.magic()).unwrap();
+        assert_eq!(
+            records,
+            [
+                Record::Start {
+                    height: 1,
+                    tip: block1
+                },
+                Record::Mempool {
+                    generation: 1,
+                    count: 0,
+                    vsize: 0
+                },
+                Record::Blocks {
+                    disconnected: vec![],
+                    connected: vec![block2, block3],
+                },
+                Record::Mempool {
+                    generation: 2,
+                    count: 1,
+                    vsize: 150
+                },
+                Record::Blocks {
+                    disconnected: vec![block3, block2],
+                    connected: vec![block2b, block3b],
+                },
+                Record::Mempool {
+                    generation: 3,
+                    count: 0,
+                    vsize: 0
+                },
+            ]
+        );
+        let (metrics1, metrics2) = (metrics(), metrics());
+        let report = replay::replay(
+            &records,
+            &node,
+            Chain::new(Network::Regtest),
+            &new_index(&tempfile::tempdir().unwrap(), &metrics1),
+            &new_index(&tempfile::tempdir().unwrap(), &metrics2),
+            &ExitFlag::new(),
+        )
+        .unwrap();
+        report.check().unwrap();
+        assert_eq!((report.steps, report.mempool_syncs), (2, 3));
+        assert_eq!((report.height, report.tip), (3, block3b));
+    }
+
+    #[test]
+    fn test_scripthash_dump() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let tx1 = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        let txid1 = tx1.compute_txid();
+        node.mine(&script(0), vec![tx1]);
+        let tx2 = spend(OutPoint::new(txid1, 0), &script(2), 900);
+        let txid2 = tx2.compute_txid();
+        node.mine(&script(0), vec![tx2]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        sync_index(&index, &node);
+        let chain = index.chain();
+        let scripthash = ScriptHash::new(&script(1));
+        let mut status = ScriptHashStatus::new(scripthash);
+        let mempool = RwLock::new(Mempool::new(&metrics, ScriptTypes::all()));
+        let cache = Cache::new(&metrics);
+        status
+            .sync(&index, chain.clone(), &mempool, &node, &cache, true)
+            .unwrap();
+
+        let dump =
+            ScriptHashDump::collect(&index, Ok(&node), scripthash, &self::metrics()).unwrap();
+        let value = json!(dump);
+        assert_eq!(value["tip"], json!({"height": 3, "blockhash": chain.tip()}));
+        let row = |key: String, height: usize| {
+            json!({
+                "key": key,
+                "height": height,
+                "blockhash": chain.get_block_hash(height),
+                "verified": true,
+            })
+        };
+        let decoded = |rows: &serde_json::Value| {
+            let rows = rows.as_array().unwrap();
+            rows.iter()
+                .map(|row| {
+                    let prefix = row["prefix"].as_str().unwrap();
+                    let height = row["height"].as_u64().unwrap() as u32;
+                    let mut raw =
+                        format!("{}{}", prefix, height.to_le_bytes().to_lower_hex_string());
+                    if let Some(vout) = row.get("vout") {
+                        let vout = vout.as_u64().unwrap() as u32;
+                        raw += &vout.to_le_bytes()[..3].to_lower_hex_string();
+                    }
+                    assert_eq!(row["row"], json!(raw));
+                    let mut row = row.clone();
+                    row.as_object_mut().unwrap().remove("row");
+                    row.as_object_mut().unwrap().remove("prefix");
+                    row
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut funding = row(scripthash.to_string(), 2);
+        funding["vout"] = json!(0); // only funding rows store the output index
+        assert_eq!(decoded(&value["funding"]), vec![funding]);
+        assert_eq!(
+            decoded(&value["spending"]),
+            vec![row(OutPoint::new(txid1, 0).to_string(), 3)]
+        );
+        assert_eq!(
+            decoded(&value["txid"]),
+            vec![row(txid1.to_string(), 2), row(txid2.to_string(), 3)]
+        );
+        assert_eq!(
+            value["verified"],
+            json!({
+                "history": [
+                    {"tx_hash": txid1, "height": 2, "tx_pos": 1},
+                    {"tx_hash": txid2, "height": 3, "tx_pos": 1},
+                ],
+                "balance": {"confirmed": 0, "unconfirmed": 0},
+                "status": status.statushash().unwrap().to_string(),
+            })
+        );
+        assert!(value.get("daemon_error").is_none());
+        let mut text = vec![];
+        dump.write_text(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("funding rows: 1\n"), "{}", text);
+        assert!(text.contains("history: 2 transactions\n"), "{}", text);
+
+        // without bitcoind, only the funding rows are found (and they are not verified)
+        let dump = ScriptHashDump::collect(
+            &index,
+            Err(anyhow::anyhow!("connection refused")),
+            scripthash,
+            &self::metrics(), // a new registry for each dump
+        )
+        .unwrap();
+        let value = json!(dump);
+        assert_eq!(value["funding"].as_array().unwrap().len(), 1);
+        assert!(value["funding"][0].get("verified").is_none());
+        assert_eq!(value["spending"], json!([]));
+        assert_eq!(value["txid"], json!([]));
+        assert_eq!(value["verified"], json!(null));
+        assert_eq!(value["daemon_error"], "connection refused");
+    }
+
+    #[test]
+    fn test_unrelated_mempool_change() {
+        let node = StubNode::new(Network::Regtest);
+        let block1 = node.mine(&script(0), vec![]);
+        let block2 = node.mine(&script(0), vec![]);
+        let tx1 = spend(coinbase_outpoint(&node, block1), &script(1), 1_000);
+        node.add_to_mempool(tx1, Amount::from_sat(100));
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+
+        let scripthash = ScriptHash::new(&script(1));
+        let mut status = ScriptHashStatus::new(scripthash);
+        let subs = Subscriptions::default();
+        subs.subscribe(1, scripthash);
+        assert!(tracker
+            .update_scripthash_status(&mut status, tracker.chain(), &node, &cache, true)
+            .unwrap());
+        tracker.take_touched();
+
+        // a mempool transaction which doesn't affect the subscribed scripthash
+        let tx2 = spend(coinbase_outpoint(&node, block2), &script(2), 1_000);
+        node.add_to_mempool(tx2, Amount::from_sat(100));
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        let (chain, confirmed, mempool) = tracker.take_touched();
+        let affected = subs.affected(confirmed.as_ref(), mempool.as_ref());
+        assert_eq!(affected.scripthashes(1), Some(&[][..]));
+
+        // even if recomputed, its status is unchanged (so no notification is sent)
+        assert!(!tracker
+            .update_scripthash_status(&mut status, chain, &node, &cache, true)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_locate_transaction() {
+        let node = StubNode::new(Network::Regtest);
+        let block1 = node.mine(&script(0), vec![]);
+        let block2 = node.mine(&script(0), vec![]);
+        let tx1 = spend(coinbase_outpoint(&node, block1), &script(1), 1_000);
+        let tx2 = spend(coinbase_outpoint(&node, block2), &script(2), 1_000);
+        let txid1 = node.add_to_mempool(tx1.clone(), Amount::from_sat(100));
+        let txid2 = node.add_to_mempool(tx2, Amount::from_sat(100));
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        let locate = |txid| tracker.locate_transaction(&node, txid).unwrap();
+        assert_eq!(locate(txid1), Location::Mempool);
+        assert_eq!(locate(txid2), Location::Mempool);
+
+        node.mine(&script(0), vec![tx1]);
+        node.remove_from_mempool(txid2);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        assert_eq!(locate(txid1), Location::Confirmed(3));
+        assert_eq!(locate(txid2), Location::Missing);
+    }
+
+    #[test]
+    fn test_get_tx_height() {
+        let node = StubNode::new(Network::Regtest);
+        let block1 = node.mine(&script(0), vec![]);
+        let block2 = node.mine(&script(0), vec![]);
+        let tx1 = spend(coinbase_outpoint(&node, block1), &script(1), 1_000);
+        let tx2 = spend(coinbase_outpoint(&node, block2), &script(2), 1_000);
+        let txid1 = tx1.compute_txid();
+        node.mine(&script(0), vec![tx1.clone()]);
+        let tx3 = spend(OutPoint::new(txid1, 0), &script(3), 900);
+        let txid2 = node.add_to_mempool(tx2, Amount::from_sat(100));
+        let txid3 = node.add_to_mempool(tx3, Amount::from_sat(100));
+        let unknown = Txid::from_byte_array([0x11; 32]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let registry = metrics();
+        let cache = Cache::new(&registry);
+        let tracker = Tracker::from_index(new_index(&dir, &registry), registry);
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        let height = |txid| tracker.get_tx_height(&node, &cache, txid).unwrap();
+        node.take_calls();
+        assert_eq!(height(txid1), Some(3));
+        assert_eq!(height(txid2), Some(0));
+        assert_eq!(height(txid3), Some(0)); // its parent is confirmed
+        assert_eq!(height(unknown), None);
+        // only the block's txids are fetched (and then cached)
+        assert_eq!(node.take_calls(), ["getblock"]);
+        assert_eq!(height(txid1), Some(3));
+        assert!(node.take_calls().is_empty());
+
+        // a cached transaction confirms its single candidate block
+        let tx_cache = Cache::new(&metrics());
+        tx_cache.add_tx(&tracker.chain(), node.tip(), txid1, || {
+            serialize(&tx1).into()
+        });
+        let height = |txid| tracker.get_tx_height(&node, &tx_cache, txid).unwrap();
+        assert_eq!(height(txid1), Some(3));
+        assert!(node.take_calls().is_empty());
+
+        // a reorged transaction returns to the mempool
+        let height = |txid| tracker.get_tx_height(&node, &cache, txid).unwrap();
+        node.invalidate(1);
+        node.remove_from_mempool(txid2);
+        node.mine(&script(0), vec![]);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        assert_eq!(height(txid1), Some(0));
+        assert_eq!(height(txid2), None);
+        assert_eq!(height(txid3), Some(-1)); // its parent is unconfirmed
+        node.remove_from_mempool(txid1);
+        node.remove_from_mempool(txid3);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        assert_eq!(height(txid1), None);
+    }
+
+    #[test]
+    fn test_query_latency_while_indexing() {
+        const DELAY: Duration = Duration::from_millis(500); // per node call (slow indexing)
+        const INTERVAL: Duration = Duration::from_millis(10); // between queries' scheduled times
+
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let tx1 = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        let txid1 = tx1.compute_txid();
+        node.mine(&script(0), vec![tx1]);
+        let tx2 = spend(OutPoint::new(txid1, 0), &script(2), 900);
+        let txid2 = node.add_to_mempool(tx2.clone(), Amount::from_sat(100));
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let index = new_index(&dir, &metrics);
+        let cache = Cache::new(&metrics);
+        let tracker = Tracker::from_index(index, metrics);
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+
+        node.set_delay(DELAY);
+        let indexing = AtomicBool::new(true);
+        let latencies = thread::scope(|scope| {
+            let readers: Vec<_> = (0..2)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut status = ScriptHashStatus::new(ScriptHash::new(&script(1)));
+                        let mut latencies = vec![];
+                        let mut scheduled = Instant::now();
+                        while indexing.load(Ordering::Relaxed) {
+                            thread::sleep(scheduled.saturating_duration_since(Instant::now()));
+                            tracker
+                                .update_scripthash_status(
+                                    &mut status,
+                                    tracker.chain(),
+                                    &node,
+                                    &cache,
+                                    true,
+                                )
+                                .unwrap();
+                            let history = json!(status.get_history());
+                            // measured since the scheduled time, so a blocked query delays the next ones
+                            latencies.push(scheduled.elapsed());
+                            scheduled += INTERVAL;
+                            // `tx2` is either in the mempool or confirmed (never both or neither)
+                            let history = history.as_array().unwrap();
+                            assert_eq!(history.len(), 2, "{:?}", history);
+                            assert_eq!(history[0], json!({"tx_hash": txid1, "height": 2}));
+                            assert_eq!(history[1]["tx_hash"], json!(txid2));
+                            assert!(matches!(history[1]["height"].as_u64(), Some(0 | 5)));
+                        }
+                        latencies
+                    })
+                })
+                .collect();
+            for i in 0..5 {
+                let txs = if i == 2 { vec![tx2.clone()] } else { vec![] };
+                node.mine(&script(0), txs);
+                while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+            }
+            indexing.store(false, Ordering::Relaxed);
+            let mut latencies: Vec<Duration> = readers
+                .into_iter()
+                .flat_map(|reader| reader.join().unwrap())
+                .collect();
+            latencies.sort();
+            latencies
+        });
+        assert_eq!(tracker.chain().height(), 7);
+        // queries waiting for sync steps would often wait for most of a node call, so half of it
+        // leaves enough room for a loaded machine's scheduling delays
+        let p99 = latencies[latencies.len() * 99 / 100];
+        assert!(
+            p99 < DELAY / 2,
+            "p99 = {:?} ({} queries)",
+            p99,
+            latencies.len()
+        );
+    }
+
+    #[test]
+    fn test_get_transaction_without_txindex() {
+        let node = StubNode::new(Network::Regtest);
+        node.set_txindex(false);
+        let block = node.mine(&script(0), vec![]);
+        let funding = coinbase_outpoint(&node, block);
+        let tx1 = spend(funding, &script(1), 1_000_000);
+        let txid1 = tx1.compute_txid();
+        let confirming = node.mine(&script(0), vec![tx1.clone()]);
+        let tx2 = spend(OutPoint::new(txid1, 0), &script(2), 900_000);
+        let txid2 = node.add_to_mempool(tx2.clone(), Amount::from_sat(100));
+
+        // bitcoind finds confirmed transactions only given their block hash
+        let err = node.get_transaction(&txid1, None).unwrap_err();
+        let rpc_err = err.downcast_ref::<bitcoincore_rpc::Error>().unwrap();
+        assert_eq!(extract_bitcoind_error(rpc_err).unwrap().code, -5);
+        assert_eq!(node.get_transaction(&txid1, Some(confirming)).unwrap(), tx1);
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        node.take_calls();
+
+        // confirmed transactions are found using the index (and fetched from their block)
+        let (blockhash, tx) = tracker.get_raw_transaction(&node, txid1).unwrap().unwrap();
+        assert_eq!(blockhash, Some(confirming));
+        assert_eq!(*tx, *serialize(&tx1));
+        assert_eq!(node.take_calls(), vec!["getdata"]);
+
+        // unconfirmed transactions are served from the tracked mempool
+        let (blockhash, tx) = tracker.get_raw_transaction(&node, txid2).unwrap().unwrap();
+        assert_eq!(blockhash, None);
+        assert_eq!(*tx, *serialize(&tx2));
+        assert!(node.take_calls().is_empty());
+
+        let unknown = Txid::from_byte_array([0xAB; 32]);
+        assert!(tracker
+            .get_raw_transaction(&node, unknown)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_transient_failures() {
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let funding = coinbase_outpoint(&node, block);
+        let tx1 = spend(funding, &script(1), 1_000_000);
+        let txid1 = tx1.compute_txid();
+        node.mine(&script(0), vec![tx1]);
+        let tx2 = spend(OutPoint::new(txid1, 0), &script(1), 900_000);
+        let txid2 = tx2.compute_txid();
+        let second = node.mine(&script(0), vec![tx2]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let cache = Cache::new(&metrics);
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        let scripthash = ScriptHash::new(&script(1));
+
+        // the connection is lost after the first block of the history was received
+        node.fail_block(second);
+        let err = match tracker.new_scripthash_status(scripthash, &node, &cache) {
+            Ok(_) => panic!("a partial history was returned"),
+            Err(err) => err,
+        };
+        assert!(is_transient(&err), "{:#}", err);
+        assert!(!tracker.is_known_empty(&scripthash));
+
+        let mut status = tracker
+            .new_scripthash_status(scripthash, &node, &cache)
+            .unwrap();
+        let history = json!([
+            {"tx_hash": txid1, "height": 2},
+            {"tx_hash": txid2, "height": 3},
+        ]);
+        assert_eq!(json!(status.get_history()), history);
+        let statushash = status.statushash();
+
+        // a failed update keeps the previous status (instead of a partial one)
+        let tx3 = spend(OutPoint::new(txid2, 0), &script(1), 800_000);
+        let txid3 = tx3.compute_txid();
+        let third = node.mine(&script(0), vec![tx3]);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        node.fail_block(third);
+        let err = tracker
+            .update_scripthash_status(&mut status, tracker.chain(), &node, &cache, true)
+            .unwrap_err();
+        assert!(is_transient(&err), "{:#}", err);
+        assert_eq!(json!(status.get_history()), history);
+        assert_eq!(status.statushash(), statushash);
+
+        assert!(tracker
+            .update_scripthash_status(&mut status, tracker.chain(), &node, &cache, true)
+            .unwrap());
+        assert_eq!(status.get_history().len(), 3);
+        assert_eq!(status.get_history()[2].txid(), txid3);
+
+        // failed mempool syncs keep the previous mempool (instead of dropping its transactions)
+        let tx4 = spend(OutPoint::new(txid3, 0), &script(1), 700_000);
+        let txid4 = node.add_to_mempool(tx4, Amount::from_sat(100));
+        node.fail("getrawtransaction", Failure::Warmup);
+        assert!(tracker.sync(&node, &exit_flag, &cache).is_ok());
+        assert!(tracker.get_raw_transaction(&node, txid4).unwrap().is_none());
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        let status = tracker
+            .new_scripthash_status(scripthash, &node, &cache)
+            .unwrap();
+        assert_eq!(status.get_history().len(), 4);
+
+        // unknown transactions are not transient failures
+        let unknown = Txid::from_byte_array([0xAB; 32]);
+        let err = node.get_transaction(&unknown, None).unwrap_err();
+        assert!(!is_transient(&err));
+    }
+
+    #[test]
+    fn test_daemon_cache() {
+        let node = StubNode::new(Network::Regtest);
+        node.set_txindex(false);
+        let block = node.mine(&script(0), vec![]);
+        let tx = spend(coinbase_outpoint(&node, block), &script(1), 1_000_000);
+        let txid = tx.compute_txid();
+        let confirming = node.mine(&script(0), vec![tx.clone()]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let cache = Cache::new(&metrics);
+        let index = new_index_with(
+            &dir,
+            &metrics,
+            StoreOptions::default(),
+            IndexOptions {
+                final_depth: Some(2),
+                ..IndexOptions::default()
+            },
+        );
+        let tracker = Tracker::from_index(index, metrics);
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        node.take_calls();
+
+        // repeated merkle proofs use the cached txids
+        let txids = tracker.get_block_txids(&node, &cache, confirming).unwrap();
+        assert_eq!(txids[1], txid);
+        assert_eq!(node.take_calls(), vec!["getblock"]);
+        assert_eq!(
+            tracker.get_block_txids(&node, &cache, confirming).unwrap(),
+            txids
+        );
+        assert!(node.take_calls().is_empty());
+
+        // repeated verbose gets use the cached response (and the raw transaction is cached too)
+        let info = tracker.get_transaction_info(&node, &cache, txid).unwrap();
+        assert_eq!(info["confirmations"], json!(1));
+        assert_eq!(node.take_calls(), vec!["getdata", "getrawtransaction"]);
+        assert_eq!(
+            tracker.get_transaction_info(&node, &cache, txid).unwrap(),
+            info
+        );
+        assert!(cache.get_tx(&tracker.chain(), &txid, |_| ()).is_some());
+        assert!(node.take_calls().is_empty());
+
+        // shallow entries are ignored after their block is reorged out
+        node.invalidate(1);
+        node.mine(&script(2), vec![]);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        node.take_calls();
+        let info = tracker.get_transaction_info(&node, &cache, txid).unwrap();
+        assert_eq!(info["blockhash"], json!(null)); // returned to the mempool
+                                                    // the stale index row points to the new block at the same height
+        assert_eq!(node.take_calls(), vec!["getdata", "getrawtransaction"]);
+        assert!(cache.get_tx(&tracker.chain(), &txid, |_| ()).is_none());
+        tracker.get_block_txids(&node, &cache, confirming).unwrap();
+        assert_eq!(node.take_calls(), vec!["getblock"]); // stale blocks are not cached
+
+        // the confirmations of cached responses are updated
+        let confirming = node.mine(&script(0), vec![tx]);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        node.take_calls();
+        let info = tracker.get_transaction_info(&node, &cache, txid).unwrap();
+        assert_eq!(info["blockhash"], json!(confirming));
+        assert_eq!(node.take_calls(), vec!["getdata", "getrawtransaction"]);
+        node.mine(&script(0), vec![]);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        node.take_calls();
+        let info = tracker.get_transaction_info(&node, &cache, txid).unwrap();
+        assert_eq!(info["confirmations"], json!(2));
+        assert!(node.take_calls().is_empty());
+    }
+
+    #[test]
+    fn test_final_depth() {
+        let node = StubNode::new(Network::Regtest);
+        let blocks: Vec<_> = (0..4).map(|i| node.mine(&script(i), vec![])).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let cache = Cache::new(&metrics);
+        let index = new_index_with(
+            &dir,
+            &metrics,
+            StoreOptions::default(),
+            IndexOptions {
+                final_depth: Some(2),
+                ..IndexOptions::default()
+            },
+        );
+        let tracker = Tracker::from_index(index, metrics);
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        for block in &blocks {
+            tracker.get_block_txids(&node, &cache, *block).unwrap();
+        }
+        node.take_calls();
+
+        // a shallow reorg keeps the entries of final blocks, and drops the reorged ones
+        node.invalidate(1);
+        node.mine(&script(10), vec![]);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        assert_eq!(tracker.deep_reorgs(), 0);
+        let chain = tracker.chain();
+        for block in &blocks[..2] {
+            assert!(chain.is_final(chain.get_block_height(block).unwrap()));
+            assert!(cache.get_block_txids(&chain, block).is_some());
+        }
+        assert!(cache.get_block_txids(&chain, &blocks[2]).is_some()); // anchored to its block
+        assert!(cache.get_block_txids(&chain, &blocks[3]).is_none()); // reorged out
+
+        // a reorg disconnecting final blocks is counted (so the cache is cleared)
+        node.invalidate(3);
+        for i in 0..4 {
+            node.mine(&script(20 + i), vec![]);
+        }
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        assert_eq!(tracker.deep_reorgs(), 1);
+        let chain = tracker.chain();
+        for block in &blocks[1..] {
+            assert_eq!(chain.get_block_height(block), None);
+        }
+        // the cache is cleared (including the entry of `blocks[0]`, which is still in the chain)
+        for block in &blocks {
+            assert!(cache.get_block_txids(&chain, block).is_none());
+        }
+        for height in 1..=chain.height() {
+            let block = chain.get_block_hash(height).unwrap();
+            tracker.get_block_txids(&node, &cache, block).unwrap();
+        }
+
+        // the new chain's entries are cached until the next deep reorg
+        node.invalidate(1);
+        node.mine(&script(30), vec![]);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        let chain = tracker.chain();
+        let final_block = chain.get_block_hash(1).unwrap();
+        assert!(cache.get_block_txids(&chain, &final_block).is_some());
+    }
+
+    #[test]
+    fn test_failures() {
+        let node = StubNode::new(Network::Regtest);
+        node.set_fee_rate(Some(Amount::from_sat(5_000)));
+        node.fail("estimatesmartfee", Failure::Warmup);
+        node.fail("estimatesmartfee", Failure::Unauthorized);
+
+        let err = node.estimate_fee(1).unwrap_err();
+        let rpc_err = err.downcast_ref::<bitcoincore_rpc::Error>().unwrap();
+        assert_eq!(extract_bitcoind_error(rpc_err).unwrap().code, -28);
+
+        let err = node.estimate_fee(1).unwrap_err();
+        let rpc_err = err.downcast_ref::<bitcoincore_rpc::Error>().unwrap();
+        assert!(extract_bitcoind_error(rpc_err).is_none());
+        assert!(format!("{:?}", rpc_err).contains("HttpErrorCode(401)"));
+
+        assert_eq!(node.estimate_fee(1).unwrap(), Some(Amount::from_sat(5_000)));
+
+        node.fail("getnetworkinfo", Failure::ConnectionRefused);
+        let err = node.get_relay_fee().unwrap_err();
+        let rpc_err = err.downcast_ref::<bitcoincore_rpc::Error>().unwrap();
+        assert!(format!("{:?}", rpc_err).contains("ConnectionRefused"));
+        assert_eq!(node.get_relay_fee().unwrap(), Amount::from_sat(1000));
+    }
+
+    #[test]
+    fn test_scheduled_maintenance() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..3 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let mut index = new_index(&dir, &metrics);
+        index.set_max_reorg_depth(Some(1), false);
+        index.set_maintenance_window("04:00-06:00".parse().unwrap());
+        let night = 5 * 60;
+
+        // skipped during the initial sync
+        assert!(!index.maintain_at(night, 1000));
+        sync_index(&index, &node);
+        assert!(!index.maintain_at(12 * 60, 1001)); // outside the window
+        assert!(index.maintain_at(night, 1002));
+        assert!(index.maintain_at(night + 1, 1003));
+
+        // skipped while a deep reorg is refused
+        node.invalidate(2);
+        for i in 0..3 {
+            node.mine(&script(10 + i), vec![]);
+        }
+        sync_index(&index, &node);
+        assert!(index.is_stalled());
+        assert!(!index.maintain_at(night + 2, 1004));
+        drop(index);
+
+        let store = DBStore::open(dir.path(), &StoreOptions::default()).unwrap();
+        assert_eq!(store.last_maintenance(), Some(1003));
+    }
+
+    #[test]
+    fn test_deferred_compaction() {
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..5 {
+            node.mine(&script(i), vec![]);
+        }
+        let policy = FlushPolicy {
+            blocks: Some(2),
+            ..FlushPolicy::default()
+        };
+        let night = 5 * 60;
+
+        // compacted during the maintenance window
+        let (metrics1, metrics2) = (metrics(), metrics());
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = new_index(&dir, &metrics1);
+        index.set_flush_policy(policy, true);
+        index.set_maintenance_window("04:00-06:00".parse().unwrap());
+        assert!(!index.is_compaction_deferred()); // the initial sync is not done yet
+        sync_index(&index, &node);
+        assert!(index.is_ready());
+        assert!(index.is_compaction_deferred());
+        assert!(!index.maintain_at(12 * 60, 1000)); // outside the window
+        assert!(index.is_compaction_deferred());
+        assert!(index.maintain_at(night, 1001));
+        assert!(!index.is_compaction_deferred());
+        assert!(!index.request_compaction()); // already compacted
+
+        // compacted by the next sync, when requested by the operator
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = new_index(&dir, &metrics2);
+        index.set_flush_policy(policy, true);
+        sync_index(&index, &node);
+        node.mine(&script(10), vec![]);
+        sync_index(&index, &node);
+        assert!(index.is_compaction_deferred());
+        assert!(index.request_compaction());
+        sync_index(&index, &node);
+        assert!(!index.is_compaction_deferred());
+        assert_eq!(index.chain().tip(), node.tip());
+    }
+
+    #[test]
+    fn test_sync_progress() {
+        use crate::electrum::subscribed_tip;
+
+        let node = StubNode::new(Network::Regtest);
+        for i in 0..3 {
+            node.mine(&script(i), vec![]);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        let exit_flag = ExitFlag::new();
+
+        // a client subscribing mid-sync gets the indexed tip, flagged with the sync progress
+        assert_eq!(tracker.sync_target(), Some(0));
+        let (_, header) = subscribed_tip(&tracker.chain(), tracker.sync_target());
+        assert_eq!(header["height"], json!(0));
+        assert_eq!(header["target_height"], json!(0)); // the headers are not synced yet
+
+        assert!(!tracker.sync(&node, &exit_flag, &cache).unwrap()); // the blocks are indexed
+        assert_eq!(tracker.sync_target(), Some(3));
+        let (syncing_tip, header) = subscribed_tip(&tracker.chain(), tracker.sync_target());
+        assert_eq!(header["height"], json!(3));
+        assert_eq!(header["syncing"], json!(true));
+        assert_eq!(header["target_height"], json!(3));
+
+        // the end of initial sync is notified, even though the tip is unchanged
+        assert!(tracker.sync(&node, &exit_flag, &cache).unwrap());
+        assert_eq!(tracker.sync_target(), None);
+        let chain = tracker.chain();
+        let (synced_tip, header) = subscribed_tip(&chain, tracker.sync_target());
+        assert_ne!(synced_tip, syncing_tip);
+        assert_eq!(header, chain.tip_header().to_json());
+        assert_eq!(header["height"], json!(3));
+    }
+
+    #[test]
+    fn test_shadow_migration() {
+        use crate::migration::{Layout, Migration};
+
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let tx = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        let txid = tx.compute_txid();
+        node.mine(&script(0), vec![tx]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let layout = Layout::new(&dir.path().join("regtest"));
+        let open = |path: &std::path::Path, metrics: &Metrics, prefix_len| {
+            let store = DBStore::open(
+                path,
+                &StoreOptions {
+                    prefix_len,
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
+            Index::load(
+                store,
+                Chain::new(Network::Regtest),
+                metrics,
+                &IndexOptions::default(),
+            )
+            .unwrap()
+        };
+        let (metrics, shadow_metrics) = (metrics(), metrics());
+        let cache = Cache::new(&metrics);
+        let index = open(&layout.recover().unwrap(), &metrics, PrefixLen::default());
+        let tracker = Tracker::from_index(index, metrics);
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+
+        // the shadow DB uses another prefix length (and its metrics are not exported)
+        let shadow_dir = layout.shadow().unwrap();
+        let shadow = open(&shadow_dir, &shadow_metrics, PrefixLen::Six);
+        tracker.set_migration(Migration {
+            index: shadow,
+            layout: layout.clone(),
+            dir: shadow_dir.clone(),
+        });
+        let query = || {
+            let scripthash = ScriptHash::new(&script(1));
+            let status = tracker
+                .new_scripthash_status(scripthash, &node, &cache)
+                .unwrap();
+            assert_eq!(
+                json!(status.get_history()),
+                json!([{"tx_hash": txid, "height": 2}])
+            );
+        };
+
+        let migrating = AtomicBool::new(true);
+        let queries = thread::scope(|scope| {
+            let reader = scope.spawn(|| {
+                let mut queries = 0;
+                loop {
+                    query(); // never fails, using either the previous index or the new one
+                    queries += 1;
+                    if !migrating.load(Ordering::Relaxed) {
+                        return queries;
+                    }
+                }
+            });
+            let mut syncs = 0;
+            while tracker.is_migrating() || tracker.migration_progress().is_some() {
+                if syncs == 1 {
+                    node.mine(&script(0), vec![]); // the served tip moves meanwhile
+                }
+                tracker.sync(&node, &exit_flag, &cache).unwrap();
+                syncs += 1;
+                assert!(syncs < 10, "shadow migration didn't converge");
+            }
+            migrating.store(false, Ordering::Relaxed);
+            reader.join().unwrap()
+        });
+        assert!(queries > 0);
+        assert_eq!(tracker.chain().height(), 3);
+        query();
+
+        // the previous DB is archived, and the new one is used after a restart
+        assert_eq!(layout.active().unwrap(), shadow_dir);
+        assert!(!dir.path().join("regtest").exists());
+        let archived = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("regtest.archived-")
+            })
+            .count();
+        assert_eq!(archived, 1);
+        drop(tracker);
+        let db_path = layout.recover().unwrap();
+        let store = DBStore::open(
+            &db_path,
+            &StoreOptions {
+                prefix_len: PrefixLen::Six,
+                ..StoreOptions::default()
+            },
+        )
+        .expect("the new DB requires re-indexing");
+        assert!(store.get_tip().is_some());
+    }
+
+    #[test]
+    fn test_shadow_db() {
+        use crate::shadow_db::{Outcome, ShadowDb};
+        use crate::types::ScriptType;
+
+        let node = StubNode::new(Network::Regtest);
+        let block = node.mine(&script(0), vec![]);
+        let tx1 = spend(coinbase_outpoint(&node, block), &script(1), 1_000);
+        let txid1 = tx1.compute_txid();
+        node.mine(&script(0), vec![tx1]);
+
+        // the shadow DBs are indexed up to height 2, and one of them lacks non-standard scripts
+        let shadow = |script_types: ScriptTypes| {
+            let dir = tempfile::tempdir().unwrap();
+            let store = DBStore::open(
+                dir.path(),
+                &StoreOptions {
+                    script_types: script_types.clone(),
+                    ..StoreOptions::default()
+                },
+            )
+            .unwrap();
+            let load = |store, metrics: &Metrics| {
+                Index::load(
+                    store,
+                    Chain::new(Network::Regtest),
+                    metrics,
+                    &IndexOptions {
+                        script_types: script_types.clone(),
+                        ..IndexOptions::default()
+                    },
+                )
+                .unwrap()
+            };
+            sync_index(&load(store, &metrics()), &node);
+            let store = DBStore::open_read_only(dir.path(), None, 1).unwrap();
+            (load(store, &metrics()), dir)
+        };
+        let (index, same_dir) = shadow(ScriptTypes::all());
+        let same = ShadowDb::new(index, same_dir.path().to_owned(), 1, 10, &metrics());
+        let (index, partial_dir) = shadow(ScriptTypes::only([ScriptType::P2wpkh]));
+        let partial = ShadowDb::new(index, partial_dir.path().to_owned(), 1, 10, &metrics());
+
+        // the served DB has another block, spending the funded output
+        let tx2 = spend(OutPoint::new(txid1, 0), &script(2), 900);
+        node.mine(&script(0), vec![tx2]);
+        let dir = tempfile::tempdir().unwrap();
+        let registry = metrics();
+        let cache = Cache::new(&registry);
+        let tracker = Tracker::from_index(new_index(&dir, &registry), registry);
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        assert_eq!(tracker.chain().height(), 3);
+
+        let compare = |shadow_db: &ShadowDb, n| {
+            let scripthash = ScriptHash::new(&script(n));
+            shadow_db
+                .compare(&tracker, &node, &cache, scripthash)
+                .unwrap()
+        };
+        // only the entries up to the common height are compared
+        assert_eq!(compare(&same, 1), Outcome::Match);
+        assert_eq!(compare(&same, 2), Outcome::Match);
+        assert_eq!(compare(&partial, 1), Outcome::Mismatch);
+        assert_eq!(compare(&partial, 9), Outcome::Match); // empty in both DBs
+
+        // 1 of every 2 queries is sampled, and at most 2 of them are queued per second
+        let (index, sampled_dir) = shadow(ScriptTypes::all());
+        let sampled = ShadowDb::new(index, sampled_dir.path().to_owned(), 2, 2, &metrics());
+        for n in 0..6 {
+            sampled.sample(ScriptHash::new(&script(n)));
+        }
+        let expected: Vec<_> = [0, 2].map(|n| ScriptHash::new(&script(n))).into();
+        assert_eq!(sampled.take_sampled(), expected);
+
+        // the shadow DB's block at the common height was reorged
+        node.invalidate(2);
+        node.mine(&script(5), vec![]);
+        node.mine(&script(5), vec![]);
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        assert_eq!(compare(&same, 1), Outcome::Skipped);
+    }
+
+    #[test]
+    fn test_fee_cache() {
+        let node = StubNode::new(Network::Regtest);
+        let fees = FeeCache::new(&metrics(), Duration::from_secs(600));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let fee = Amount::from_sat;
+
+        // nothing is cached yet
+        node.fail("estimatesmartfee", Failure::ConnectionRefused);
+        let err = fees.estimate_fee_at(&node, 2, at(0)).unwrap_err();
+        assert!(is_transient(&err), "{:#}", err);
+
+        node.set_fee_rate(Some(fee(5_000)));
+        assert_eq!(
+            fees.estimate_fee_at(&node, 2, at(0)).unwrap(),
+            Some(fee(5_000))
+        );
+
+        // during an outage, the cached estimate is served (until it becomes too stale)
+        node.fail("estimatesmartfee", Failure::ConnectionRefused);
+        node.fail("estimatesmartfee", Failure::Warmup);
+        assert_eq!(
+            fees.estimate_fee_at(&node, 2, at(300)).unwrap(),
+            Some(fee(5_000))
+        );
+        assert_eq!(
+            fees.estimate_fee_at(&node, 2, at(600)).unwrap(),
+            Some(fee(5_000))
+        );
+        node.fail("estimatesmartfee", Failure::ConnectionRefused);
+        assert!(fees.estimate_fee_at(&node, 2, at(601)).is_err());
+
+        // each confirmation target is cached separately
+        node.fail("estimatesmartfee", Failure::ConnectionRefused);
+        assert!(fees.estimate_fee_at(&node, 6, at(0)).is_err());
+
+        // other errors are not masked
+        node.fail("estimatesmartfee", Failure::Rejected("unexpected"));
+        let err = fees.estimate_fee_at(&node, 2, at(0)).unwrap_err();
+        assert!(!is_transient(&err), "{:#}", err);
+
+        // after bitcoind recovers, the new estimate is cached
+        node.set_fee_rate(Some(fee(7_000)));
+        assert_eq!(
+            fees.estimate_fee_at(&node, 2, at(700)).unwrap(),
+            Some(fee(7_000))
+        );
+        node.fail("estimatesmartfee", Failure::ConnectionRefused);
+        assert_eq!(
+            fees.estimate_fee_at(&node, 2, at(1000)).unwrap(),
+            Some(fee(7_000))
+        );
+
+        // the relay fee is served for much longer
+        assert_eq!(fees.relay_fee_at(&node, at(0)).unwrap(), fee(1_000));
+        node.fail("getnetworkinfo", Failure::ConnectionRefused);
+        assert_eq!(fees.relay_fee_at(&node, at(23 * 3600)).unwrap(), fee(1_000));
+        node.fail("getnetworkinfo", Failure::ConnectionRefused);
+        assert!(fees.relay_fee_at(&node, at(25 * 3600)).is_err());
+
+        // disabled
+        let fees = FeeCache::new(&metrics(), Duration::ZERO);
+        assert_eq!(
+            fees.estimate_fee_at(&node, 2, at(0)).unwrap(),
+            Some(fee(7_000))
+        );
+        assert_eq!(fees.relay_fee_at(&node, at(0)).unwrap(), fee(1_000));
+        node.fail("estimatesmartfee", Failure::ConnectionRefused);
+        node.fail("getnetworkinfo", Failure::ConnectionRefused);
+        assert!(fees.estimate_fee_at(&node, 2, at(1)).is_err());
+        assert!(fees.relay_fee_at(&node, at(1)).is_err());
+    }
+
+    #[test]
+    fn test_fees_during_sync_outage() {
+        let node = StubNode::new(Network::Regtest);
+        let fee = Amount::from_sat;
+        node.mine(&script(0), vec![]);
+        node.set_fee_rate(Some(fee(5_000)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = metrics();
+        let tracker = Tracker::from_index(new_index(&dir, &metrics), metrics);
+        let cache = Cache::new(tracker.metrics());
+        let fees = FeeCache::new(tracker.metrics(), Duration::from_secs(600));
+        let health = tracker.metrics().health();
+        let exit_flag = ExitFlag::new();
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        health.daemon_synced();
+        assert_eq!(fees.estimate_fee(&node, 2).unwrap(), Some(fee(5_000)));
+
+        // bitcoind goes down before a new block is synced: the failed syncs are retried by
+        // `sync_loop()`, while the estimates are served from the cache
+        node.mine(&script(1), vec![]);
+        for _ in 0..3 {
+            node.fail("getheaders", Failure::ConnectionRefused);
+            node.fail("estimatesmartfee", Failure::ConnectionRefused);
+            let err = tracker.sync(&node, &exit_flag, &cache).unwrap_err();
+            assert!(is_transient(&err), "{:#}", err);
+            health.daemon_failed(&err);
+            assert_eq!(fees.estimate_fee(&node, 2).unwrap(), Some(fee(5_000)));
+            assert_eq!(tracker.chain().height(), 1); // the existing index is served
+        }
+        assert_eq!(
+            health.respond("/readyz").unwrap().1["reason"],
+            "daemon unreachable"
+        );
+
+        // after bitcoind recovers, the new block is indexed and fresh estimates are served
+        node.set_fee_rate(Some(fee(7_000)));
+        while !tracker.sync(&node, &exit_flag, &cache).unwrap() {}
+        health.daemon_synced();
+        assert_eq!(tracker.chain().height(), 2);
+        assert_eq!(fees.estimate_fee(&node, 2).unwrap(), Some(fee(7_000)));
+        assert_ne!(
+            health.respond("/readyz").unwrap().1["reason"],
+            "daemon unreachable"
+        );
+    }
+
+    #[test]
+    fn test_calls_and_delay() {
+        let node = StubNode::new(Network::Regtest);
+        let tx = spend(
+            OutPoint::new(Txid::from_byte_array([1; 32]), 0),
+            &script(1),
+            1,
+        );
+        let txid = node.broadcast(&tx).unwrap();
+        assert_eq!(node.get_mempool_txids().unwrap(), vec![txid]);
+        assert_eq!(node.get_transaction(&txid, None).unwrap(), tx);
+        assert_eq!(
+            node.take_calls(),
+            vec!["sendrawtransaction", "getrawmempool", "getrawtransaction"]
+        );
+        assert!(node.take_calls().is_empty());
+
+        let delay = Duration::from_millis(50);
+        node.set_delay(delay);
+        let start = Instant::now();
+        node.get_mempool_info().unwrap();
+        assert!(start.elapsed() >= delay);
+    }
+}
@@ -0,0 +1,524 @@
+use anyhow::Result;
+use bitcoin::{BlockHash, OutPoint};
+use parking_lot::Mutex;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::{
+    outpoints::Spender,
+    shrink::Shrink,
+    status::ScriptHashStatus,
+    types::{HashPrefix, ScriptHash, ScriptHashRow, SpendingPrefixRow, Touched},
+};
+
+const SHARDS: usize = 16;
+
+#[derive(Default)]
+struct Subscribers {
+    peers: HashSet<usize>,
+    spending: HashSet<HashPrefix>, // spending prefixes of the scripthash's confirmed outpoints
+}
+
+// Subscribed scripthashes, grouped by their funding prefix
+type FundingShard = HashMap<HashPrefix, HashMap<ScriptHash, Subscribers>>;
+
+// Subscribed scripthashes, grouped by their outpoints' spending prefixes
+type SpendingShard = HashMap<HashPrefix, HashSet<ScriptHash>>;
+
+#[derive(Default)]
+struct Shards {
+    funding: [Mutex<FundingShard>; SHARDS],
+    spending: [Mutex<SpendingShard>; SHARDS],
+}
+
+fn shard(prefix: &HashPrefix) -> usize {
+    usize::from(prefix[0]) % SHARDS
+}
+
+/// Subscribed scripthashes of all peers, so the ones affected by new blocks can be found
+/// without iterating over all the subscriptions.
+///
+/// A funding shard lock may be held while locking a spending shard (never the opposite).
+///
+/// Peers are removed from the registry when they are dropped (by the main loop), so the returned
+/// peer IDs may belong to peers which have already disconnected - these should be skipped.
+#[derive(Clone, Default)]
+pub(crate) struct Subscriptions {
+    shards: Arc<Shards>,
+}
+
+impl Subscriptions {
+    pub(crate) fn subscribe(&self, peer: usize, scripthash: ScriptHash) {
+        let prefix = ScriptHashRow::scan_prefix(scripthash);
+        let mut funding = self.shards.funding[shard(&prefix)].lock();
+        funding
+            .entry(prefix)
+            .or_default()
+            .entry(scripthash)
+            .or_default()
+            .peers
+            .insert(peer);
+    }
+
+    pub(crate) fn unsubscribe(&self, peer: usize, scripthash: ScriptHash) {
+        let prefix = ScriptHashRow::scan_prefix(scripthash);
+        let mut funding = self.shards.funding[shard(&prefix)].lock();
+        let scripthashes = match funding.get_mut(&prefix) {
+            Some(scripthashes) => scripthashes,
+            None => return,
+        };
+        let subscribers = match scripthashes.get_mut(&scripthash) {
+            Some(subscribers) => subscribers,
+            None => return,
+        };
+        subscribers.peers.remove(&peer);
+        if subscribers.peers.is_empty() {
+            let subscribers = scripthashes.remove(&scripthash).unwrap();
+            scripthashes.shrink();
+            if scripthashes.is_empty() {
+                funding.remove(&prefix);
+                funding.shrink();
+            }
+            self.unwatch(scripthash, subscribers.spending.iter());
+        }
+    }
+
+    /// Remove all the subscriptions of a disconnected peer.
+    pub(crate) fn remove<'a>(
+        &self,
+        peer: usize,
+        scripthashes: impl Iterator<Item = &'a ScriptHash>,
+    ) {
+        for scripthash in scripthashes {
+            self.unsubscribe(peer, *scripthash);
+        }
+    }
+
+    /// Watch the spending of a subscribed scripthash's confirmed outpoints
+    /// (replacing the previously watched ones).
+    pub(crate) fn watch(&self, scripthash: ScriptHash, outpoints: impl Iterator<Item = OutPoint>) {
+        let prefix = ScriptHashRow::scan_prefix(scripthash);
+        let mut funding = self.shards.funding[shard(&prefix)].lock();
+        let subscribers = match funding
+            .get_mut(&prefix)
+            .and_then(|scripthashes| scripthashes.get_mut(&scripthash))
+        {
+            Some(subscribers) => subscribers,
+            None => return, // already unsubscribed
+        };
+        let spending: HashSet<HashPrefix> = outpoints.map(SpendingPrefixRow::scan_prefix).collect();
+        self.unwatch(scripthash, subscribers.spending.difference(&spending));
+        for spending_prefix in spending.difference(&subscribers.spending) {
+            self.shards.spending[shard(spending_prefix)]
+                .lock()
+                .entry(*spending_prefix)
+                .or_default()
+                .insert(scripthash);
+        }
+        subscribers.spending = spending;
+    }
+
+    // Should be called while holding the scripthash's funding shard lock
+    fn unwatch<'a>(&self, scripthash: ScriptHash, prefixes: impl Iterator<Item = &'a HashPrefix>) {
+        for spending_prefix in prefixes {
+            let mut spending = self.shards.spending[shard(spending_prefix)].lock();
+            if let Some(scripthashes) = spending.get_mut(spending_prefix) {
+                scripthashes.remove(&scripthash);
+                scripthashes.shrink();
+                if scripthashes.is_empty() {
+                    spending.remove(spending_prefix);
+                    spending.shrink();
+                }
+            }
+        }
+    }
+
+    // Find the subscribers of the scripthashes which may be affected by the touched prefixes.
+    // The work is proportional to the number of touched prefixes (not to the number of subscriptions).
+    fn lookup(&self, touched: &Touched) -> HashMap<ScriptHash, HashSet<usize>> {
+        let mut candidates: HashSet<ScriptHash> = HashSet::new();
+        for spending_prefix in &touched.spending {
+            let spending = self.shards.spending[shard(spending_prefix)].lock();
+            if let Some(scripthashes) = spending.get(spending_prefix) {
+                candidates.extend(scripthashes);
+            }
+        }
+        let mut result: HashMap<ScriptHash, HashSet<usize>> = HashMap::new();
+        for prefix in &touched.funding {
+            let funding = self.shards.funding[shard(prefix)].lock();
+            if let Some(scripthashes) = funding.get(prefix) {
+                for (scripthash, subscribers) in scripthashes {
+                    result.insert(*scripthash, subscribers.peers.clone());
+                }
+            }
+        }
+        for scripthash in candidates {
+            let prefix = ScriptHashRow::scan_prefix(scripthash);
+            let funding = self.shards.funding[shard(&prefix)].lock();
+            if let Some(subscribers) = funding
+                .get(&prefix)
+                .and_then(|scripthashes| scripthashes.get(&scripthash))
+            {
+                result.insert(scripthash, subscribers.peers.clone());
+            }
+        }
+        result
+    }
+
+    /// Find the subscriptions which may be affected by new blocks (`confirmed`) and mempool
+    /// changes (`mempool`), where `None` means that all of them may be affected.
+    pub(crate) fn affected(
+        &self,
+        confirmed: Option<&Touched>,
+        mempool: Option<&Touched>,
+    ) -> Affected {
+        let confirmed_stale_blocks = confirmed
+            .map(|touched| touched.stale_blocks.clone())
+            .unwrap_or_default();
+        let spending = match (confirmed, mempool) {
+            (Some(confirmed), Some(mempool)) => Some(
+                confirmed
+                    .spending
+                    .union(&mempool.spending)
+                    .copied()
+                    .collect(),
+            ),
+            _ => None,
+        };
+        let confirmed = confirmed.map(|touched| self.lookup(touched));
+        let mempool = mempool.map(|touched| self.lookup(touched));
+        let mut by_peer: HashMap<usize, Vec<ScriptHash>> = HashMap::new();
+        if let (Some(confirmed), Some(mempool)) = (&confirmed, &mempool) {
+            let scripthashes: HashMap<&ScriptHash, &HashSet<usize>> =
+                confirmed.iter().chain(mempool).collect();
+            for (scripthash, peers) in scripthashes {
+                for peer in peers {
+                    by_peer.entry(*peer).or_default().push(*scripthash);
+                }
+            }
+        }
+        Affected {
+            confirmed,
+            mempool,
+            by_peer,
+            stale_blocks: confirmed_stale_blocks,
+            spending,
+            spenders: Mutex::default(),
+        }
+    }
+}
+
+/// Subscriptions which may be affected by new blocks and mempool changes
+pub(crate) struct Affected {
+    confirmed: Option<HashMap<ScriptHash, HashSet<usize>>>, // `None` if all may be affected
+    mempool: Option<HashMap<ScriptHash, HashSet<usize>>>,   // `None` if all may be affected
+    by_peer: HashMap<usize, Vec<ScriptHash>>, // used only if both of the above are known
+    stale_blocks: HashSet<BlockHash>,         // disconnected by a reorg
+    spending: Option<HashSet<HashPrefix>>,    // touched spending prefixes (`None` if unknown)
+    spenders: Mutex<HashMap<OutPoint, Option<Spender>>>, // resolved during this update
+}
+
+impl Affected {
+    /// Return the peer's scripthashes which may be affected (or `None` if all of them may be).
+    pub(crate) fn scripthashes(&self, peer: usize) -> Option<&[ScriptHash]> {
+        if self.confirmed.is_none() || self.mempool.is_none() {
+            return None;
+        }
+        Some(self.by_peer.get(&peer).map_or(&[], Vec::as_slice))
+    }
+
+    /// Return `true` if the scripthash may have new (or removed) confirmed transactions.
+    pub(crate) fn is_confirmed_touched(&self, scripthash: &ScriptHash) -> bool {
+        self.confirmed
+            .as_ref()
+            .is_none_or(|confirmed| confirmed.contains_key(scripthash))
+    }
+
+    /// Return `true` if blocks were disconnected by a reorg (otherwise, no status is reorged).
+    pub(crate) fn has_stale_blocks(&self) -> bool {
+        !self.stale_blocks.is_empty()
+    }
+
+    /// Return `true` if the status has confirmed transactions in blocks disconnected by a reorg
+    /// (so they may have returned to the mempool, or may be confirmed at a different height).
+    pub(crate) fn is_reorged(&self, status: &ScriptHashStatus) -> bool {
+        !self.stale_blocks.is_empty() && status.has_blocks(&self.stale_blocks)
+    }
+
+    /// Return `true` if the outpoint may have been spent (or unspent) by new blocks or mempool
+    /// changes (see `blockchain.outpoint.subscribe`).
+    pub(crate) fn is_spending_touched(&self, outpoint: &OutPoint) -> bool {
+        self.spending
+            .as_ref()
+            .is_none_or(|spending| spending.contains(&SpendingPrefixRow::scan_prefix(*outpoint)))
+    }
+
+    /// Resolve the outpoint's spender at most once per update (and not once per subscribed
+    /// client), e.g. if all the outpoints are re-resolved since the touched prefixes are unknown.
+    pub(crate) fn resolve_spender(
+        &self,
+        outpoint: OutPoint,
+        resolve: impl FnOnce(OutPoint) -> Result<Option<Spender>>,
+    ) -> Result<Option<Spender>> {
+        if let Some(spender) = self.spenders.lock().get(&outpoint) {
+            return Ok(*spender);
+        }
+        let spender = resolve(outpoint)?; // without holding the lock (it may query bitcoind)
+        self.spenders.lock().insert(outpoint, spender);
+        Ok(spender)
+    }
+
+    /// Return `true` if the block was disconnected by a reorg.
+    pub(crate) fn is_stale(&self, blockhash: &BlockHash) -> bool {
+        self.stale_blocks.contains(blockhash)
+    }
+}
+
+/// Subscriptions split among `BENCH_PEERS` peers, and a block touching 100 of them (and 10k other
+/// scripthashes), used by `benches/subscriptions.rs`
+#[cfg(feature = "bench")]
+pub struct BenchSubscriptions {
+    subs: Subscriptions,
+    block: Touched,
+}
+
+#[cfg(feature = "bench")]
+const BENCH_PEERS: usize = 100;
+
+#[cfg(feature = "bench")]
+pub fn bench_subscriptions(subscribed: usize) -> BenchSubscriptions {
+    use bitcoin::{hashes::Hash, Txid};
+
+    let scripthash = |i: usize| ScriptHash::hash(&i.to_le_bytes());
+    let outpoint = |i: usize| OutPoint::new(Txid::hash(&i.to_le_bytes()), 0);
+    let subs = Subscriptions::default();
+    for i in 0..subscribed {
+        subs.subscribe(i % BENCH_PEERS, scripthash(i));
+        subs.watch(scripthash(i), [outpoint(i)].into_iter());
+    }
+    let mut block = Touched::default();
+    block.add_funding(
+        (0..50)
+            .chain(1_000_000..1_010_000)
+            .map(|i| ScriptHashRow::scan_prefix(scripthash(i))),
+    );
+    block.add_spending((50..100).map(|i| SpendingPrefixRow::scan_prefix(outpoint(i))));
+    BenchSubscriptions { subs, block }
+}
+
+/// Find the statuses to recompute after the block, returning their number (which shouldn't depend
+/// on the total number of subscriptions).
+#[cfg(feature = "bench")]
+pub fn bench_affected(subs: &BenchSubscriptions) -> usize {
+    let affected = subs
+        .subs
+        .affected(Some(&subs.block), Some(&Touched::default()));
+    (0..BENCH_PEERS)
+        .map(|peer| affected.scripthashes(peer).map_or(0, <[_]>::len))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Subscriptions, SHARDS};
+    use crate::outpoints::Spender;
+    use crate::shrink::MIN_CAPACITY;
+    use crate::types::{ScriptHash, ScriptHashRow, SpendingPrefixRow, Touched};
+    use bitcoin::{hashes::Hash, BlockHash, OutPoint, Txid};
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn scripthash(i: usize) -> ScriptHash {
+        ScriptHash::hash(&i.to_le_bytes())
+    }
+
+    fn outpoint(i: usize) -> OutPoint {
+        OutPoint::new(Txid::hash(&i.to_le_bytes()), 0)
+    }
+
+    fn touched(funding: &[ScriptHash], spending: &[OutPoint]) -> Touched {
+        Touched {
+            funding: funding
+                .iter()
+                .copied()
+                .map(ScriptHashRow::scan_prefix)
+                .collect(),
+            spending: spending
+                .iter()
+                .copied()
+                .map(SpendingPrefixRow::scan_prefix)
+                .collect(),
+            stale_blocks: HashSet::new(),
+        }
+    }
+
+    fn subscribe_many(subs: &Subscriptions, range: std::ops::Range<usize>, peers: usize) {
+        let threads = 8;
+        std::thread::scope(|scope| {
+            for t in 0..threads {
+                let range = range.clone();
+                scope.spawn(move || {
+                    for i in range.skip(t).step_by(threads) {
+                        subs.subscribe(i % peers, scripthash(i));
+                        subs.watch(scripthash(i), [outpoint(i)].into_iter());
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let subs = Subscriptions::default();
+        subs.subscribe(1, scripthash(1));
+        subs.subscribe(2, scripthash(1));
+        subs.subscribe(2, scripthash(2));
+        subs.watch(scripthash(2), [outpoint(2)].into_iter());
+
+        let found = subs.lookup(&touched(&[scripthash(1)], &[outpoint(2)]));
+        assert_eq!(found[&scripthash(1)], HashSet::from([1, 2]));
+        assert_eq!(found[&scripthash(2)], HashSet::from([2]));
+
+        // re-watching drops the previous outpoints
+        subs.watch(scripthash(2), [outpoint(3)].into_iter());
+        assert!(subs.lookup(&touched(&[], &[outpoint(2)])).is_empty());
+
+        // disconnected peers are removed
+        subs.remove(2, [scripthash(1), scripthash(2)].iter());
+        let found = subs.lookup(&touched(&[scripthash(1), scripthash(2)], &[outpoint(3)]));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[&scripthash(1)], HashSet::from([1]));
+        assert!(subs.shards.spending.iter().all(|s| s.lock().is_empty()));
+    }
+
+    fn capacity(subs: &Subscriptions) -> usize {
+        let funding: usize = subs
+            .shards
+            .funding
+            .iter()
+            .map(|s| s.lock().capacity())
+            .sum();
+        let spending: usize = subs
+            .shards
+            .spending
+            .iter()
+            .map(|s| s.lock().capacity())
+            .sum();
+        funding + spending
+    }
+
+    #[test]
+    fn test_subscriptions_shrink() {
+        let subs = Subscriptions::default();
+        subscribe_many(&subs, 0..100, 10);
+        let baseline = 2 * SHARDS * MIN_CAPACITY;
+        assert!(capacity(&subs) <= baseline);
+
+        // a peer subscribing (and then unsubscribing) many scripthashes
+        subscribe_many(&subs, 100..100_000, 1);
+        assert!(capacity(&subs) > 10 * baseline);
+        let scripthashes: Vec<ScriptHash> = (100..100_000).map(scripthash).collect();
+        subs.remove(0, scripthashes.iter());
+        assert!(subs.lookup(&touched(&[], &[outpoint(200)])).is_empty());
+        assert!(capacity(&subs) <= baseline); // the other subscriptions are kept
+        assert_eq!(subs.lookup(&touched(&[], &[outpoint(20)])).len(), 1);
+    }
+
+    #[test]
+    fn test_many_subscriptions() {
+        let subs = Subscriptions::default();
+        let (count, peers) = (100_000, 1000);
+        subscribe_many(&subs, 0..count, peers);
+
+        // a block touching a few subscribed scripthashes (and many unsubscribed ones)
+        let funding: Vec<ScriptHash> = (0..10).chain(count..2 * count).map(scripthash).collect();
+        let spending: Vec<OutPoint> = (20..30).chain(count..2 * count).map(outpoint).collect();
+        let found = subs.lookup(&touched(&funding, &spending));
+
+        let expected: HashSet<ScriptHash> = (0..10).chain(20..30).map(scripthash).collect();
+        let actual: HashSet<ScriptHash> = found.keys().copied().collect();
+        assert_eq!(actual, expected);
+        for i in (0..10).chain(20..30) {
+            assert_eq!(found[&scripthash(i)], HashSet::from([i % peers]));
+        }
+    }
+
+    #[test]
+    fn test_affected() {
+        let subs = Subscriptions::default();
+        subs.subscribe(1, scripthash(1));
+        subs.subscribe(2, scripthash(2));
+        let block = touched(&[scripthash(1)], &[]);
+        let mempool = touched(&[scripthash(2)], &[]);
+
+        let affected = subs.affected(Some(&block), Some(&mempool));
+        assert_eq!(affected.scripthashes(1), Some(&[scripthash(1)][..]));
+        assert_eq!(affected.scripthashes(2), Some(&[scripthash(2)][..]));
+        assert_eq!(affected.scripthashes(3), Some(&[][..]));
+        assert!(affected.is_confirmed_touched(&scripthash(1)));
+        assert!(!affected.is_confirmed_touched(&scripthash(2)));
+        assert!(!affected.is_spending_touched(&outpoint(1)));
+        assert!(!affected.has_stale_blocks());
+
+        // the subscribed statuses are scanned for reorged ones only after a reorg
+        let mut reorg = touched(&[scripthash(1)], &[]);
+        reorg.stale_blocks.insert(BlockHash::all_zeros());
+        let affected = subs.affected(Some(&reorg), Some(&mempool));
+        assert!(affected.has_stale_blocks());
+        assert!(affected.is_stale(&BlockHash::all_zeros()));
+
+        // outpoints' spending is looked up in both blocks and mempool changes
+        let spending = touched(&[], &[outpoint(1)]);
+        let affected = subs.affected(Some(&block), Some(&spending));
+        assert!(affected.is_spending_touched(&outpoint(1)));
+        assert!(!affected.is_spending_touched(&outpoint(2)));
+
+        // unknown changes (e.g. on startup) may affect all subscriptions
+        let affected = subs.affected(None, Some(&mempool));
+        assert_eq!(affected.scripthashes(1), None);
+        assert!(affected.is_confirmed_touched(&scripthash(2)));
+        assert!(affected.is_spending_touched(&outpoint(2)));
+        let affected = subs.affected(Some(&block), None);
+        assert_eq!(affected.scripthashes(1), None);
+        assert!(!affected.is_confirmed_touched(&scripthash(2)));
+    }
+
+    #[test]
+    fn test_resolve_spender() {
+        let subs = Subscriptions::default();
+        let affected = subs.affected(None, None);
+        let spender = Spender {
+            txid: Txid::hash(b"spender"),
+            block: None,
+        };
+        let (resolved, spent) = (AtomicUsize::new(0), outpoint(1));
+        let resolve = |outpoint: OutPoint| {
+            resolved.fetch_add(1, Ordering::Relaxed);
+            Ok((outpoint == spent).then_some(spender))
+        };
+        // e.g. 100 clients subscribed to the same outpoints
+        for _ in 0..100 {
+            assert_eq!(
+                affected.resolve_spender(outpoint(1), resolve).unwrap(),
+                Some(spender)
+            );
+            assert_eq!(
+                affected.resolve_spender(outpoint(2), resolve).unwrap(),
+                None
+            );
+        }
+        assert_eq!(resolved.load(Ordering::Relaxed), 2);
+
+        // failures are not cached
+        let err = affected.resolve_spender(outpoint(3), |_| anyhow::bail!("failed"));
+        assert!(err.is_err());
+        affected.resolve_spender(outpoint(3), resolve).unwrap();
+        assert_eq!(resolved.load(Ordering::Relaxed), 3);
+
+        // the next update resolves them again
+        let affected = subs.affected(None, None);
+        affected.resolve_spender(outpoint(1), resolve).unwrap();
+        assert_eq!(resolved.load(Ordering::Relaxed), 4);
+    }
+}
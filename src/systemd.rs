@@ -0,0 +1,207 @@
+//! Minimal `sd_notify` implementation (see https://www.freedesktop.org/software/systemd/man/sd_notify.html)
+//!
+//! The protocol is just a datagram sent to the `$NOTIFY_SOCKET` Unix socket, so there is no need
+//! for linking `libsystemd`. All notifications are no-ops when running outside of systemd.
+
+use parking_lot::Mutex;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    OnceLock,
+};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+type Socket = UnixDatagram;
+#[cfg(not(unix))]
+type Socket = std::convert::Infallible;
+
+pub(crate) struct Notifier {
+    socket: Option<Socket>,
+    watchdog: Option<Watchdog>,
+    ready: AtomicBool,
+    status: Mutex<String>,
+}
+
+struct Watchdog {
+    interval: Duration,
+    last_ping: Mutex<Option<Instant>>,
+}
+
+/// Returns the process-wide notifier, initialized from `$NOTIFY_SOCKET` and `$WATCHDOG_USEC`.
+pub(crate) fn notifier() -> &'static Notifier {
+    static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+    NOTIFIER.get_or_init(Notifier::from_env)
+}
+
+impl Notifier {
+    fn from_env() -> Self {
+        #[cfg(unix)]
+        let socket = std::env::var_os("NOTIFY_SOCKET").and_then(|path| {
+            connect(&path)
+                .map_err(|e| warn!("failed to connect to NOTIFY_SOCKET={:?}: {}", path, e))
+                .ok()
+        });
+        #[cfg(not(unix))]
+        let socket = None;
+
+        // Follow `sd_watchdog_enabled()` semantics: `$WATCHDOG_PID` (if set) must match our PID
+        let watchdog_pid_matches =
+            std::env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| socket.is_some() && watchdog_pid_matches)
+            .map(|usec| Duration::from_micros(usec) / 2); // ping twice per timeout period
+        if let Some(interval) = watchdog {
+            info!("systemd watchdog enabled: pinging every {:?}", interval);
+        }
+
+        Self::new(socket, watchdog)
+    }
+
+    fn new(socket: Option<Socket>, watchdog: Option<Duration>) -> Self {
+        Self {
+            socket,
+            watchdog: watchdog.map(|interval| Watchdog {
+                interval,
+                last_ping: Mutex::new(None),
+            }),
+            ready: AtomicBool::new(false),
+            status: Mutex::new(String::new()),
+        }
+    }
+
+    fn send(&self, state: &str) {
+        if let Some(socket) = &self.socket {
+            debug!("sd_notify: {:?}", state);
+            #[cfg(unix)]
+            if let Err(e) = socket.send(state.as_bytes()) {
+                warn!("failed to notify systemd: {}", e);
+            }
+            #[cfg(not(unix))]
+            match *socket {}
+        }
+    }
+
+    /// Notify that the service is ready (sent only once, unless reloading).
+    pub fn ready(&self, status: &str) {
+        if !self.ready.swap(true, Ordering::Relaxed) {
+            *self.status.lock() = status.to_owned();
+            self.send(&format!("READY=1\nSTATUS={}", status));
+        }
+    }
+
+    /// Update the free-form status line (skipped if unchanged).
+    pub fn status(&self, status: &str) {
+        let mut last = self.status.lock();
+        if *last != status {
+            *last = status.to_owned();
+            self.send(&format!("STATUS={}", status));
+        }
+    }
+
+    /// Notify that the service is reloading its configuration - `ready()` should follow.
+    pub fn reloading(&self) {
+        self.ready.store(false, Ordering::Relaxed);
+        self.send("RELOADING=1");
+    }
+
+    /// Notify that the service is shutting down.
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// Ping the watchdog (rate-limited to the configured interval).
+    pub fn watchdog(&self) {
+        if let Some(watchdog) = &self.watchdog {
+            let now = Instant::now();
+            let mut last_ping = watchdog.last_ping.lock();
+            if last_ping.is_none_or(|t| now.duration_since(t) >= watchdog.interval) {
+                *last_ping = Some(now);
+                self.send("WATCHDOG=1");
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect(path: &std::ffi::OsStr) -> std::io::Result<UnixDatagram> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        // Linux abstract namespace socket
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.connect_addr(&addr)?;
+        }
+        _ => socket.connect(path)?,
+    }
+    Ok(socket)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::Notifier;
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = [0u8; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        let notifier = Notifier::new(Some(super::connect(path.as_ref()).unwrap()), None);
+        notifier.status("indexing");
+        notifier.status("indexing"); // skipped
+        notifier.ready("synced");
+        notifier.ready("synced"); // skipped
+        notifier.watchdog(); // disabled
+        notifier.reloading();
+        notifier.ready("synced");
+        notifier.stopping();
+
+        assert_eq!(recv(&server), "STATUS=indexing");
+        assert_eq!(recv(&server), "READY=1\nSTATUS=synced");
+        assert_eq!(recv(&server), "RELOADING=1");
+        assert_eq!(recv(&server), "READY=1\nSTATUS=synced");
+        assert_eq!(recv(&server), "STOPPING=1");
+        assert!(server.recv(&mut [0u8; 16]).is_err()); // no more messages
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        let socket = super::connect(path.as_ref()).unwrap();
+        let notifier = Notifier::new(Some(socket), Some(Duration::from_secs(3600)));
+        notifier.watchdog();
+        notifier.watchdog(); // rate-limited
+        assert_eq!(recv(&server), "WATCHDOG=1");
+        assert!(server.recv(&mut [0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_no_socket() {
+        let notifier = Notifier::new(None, Some(Duration::ZERO));
+        notifier.ready("synced");
+        notifier.watchdog();
+        notifier.stopping();
+    }
+}
@@ -1,4 +1,10 @@
 use anyhow::Result;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::signals::ExitError;
 
 pub(crate) fn spawn<F>(name: &'static str, f: F) -> std::thread::JoinHandle<()>
 where
@@ -14,3 +20,303 @@ where
         })
         .expect("failed to spawn a thread")
 }
+
+/// A thread which failed (returned an error or panicked)
+pub(crate) struct Failure {
+    pub(crate) name: &'static str,
+    pub(crate) error: anyhow::Error,
+}
+
+/// Reports the failures of supervised threads to the main loop, so it can shut down (instead of
+/// running without them).
+#[derive(Clone)]
+pub(crate) struct Supervisor {
+    tx: Sender<Failure>,
+    rx: Receiver<Failure>,
+}
+
+impl Supervisor {
+    pub(crate) fn new() -> Self {
+        let (tx, rx) = unbounded();
+        Self { tx, rx }
+    }
+
+    pub(crate) fn failures(&self) -> &Receiver<Failure> {
+        &self.rx
+    }
+
+    /// Run `f` on the current thread, reporting its failure (exiting due to a signal is not
+    /// considered a failure).
+    pub(crate) fn run<F>(&self, name: &'static str, f: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let result = match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(result) => result,
+            Err(payload) => Err(anyhow!("panicked: {}", panic_message(&*payload))),
+        };
+        match result {
+            Err(e) if !e.chain().any(|cause| cause.is::<ExitError>()) => {
+                let error = anyhow!("{:#}", e);
+                let _ = self.tx.send(Failure { name, error }); // the main loop may have exited
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    /// Like [`spawn`], but reporting the thread's failure
+    pub(crate) fn spawn<F>(&self, name: &'static str, f: F) -> std::thread::JoinHandle<()>
+    where
+        F: 'static + Send + FnOnce() -> Result<()>,
+    {
+        let supervisor = self.clone();
+        spawn(name, move || supervisor.run(name, f))
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+/// How an item of `unordered_map` was completed
+enum Completion<T, R> {
+    Done(R),
+    Retry(T),
+    Panicked(Box<dyn Any + Send>),
+}
+
+/// An item of `unordered_map`, which may be completed after `func` returns (e.g. by the worker
+/// computing an identical item), so its worker isn't blocked meanwhile. If it is dropped without
+/// being completed, it is passed to `func` again.
+pub(crate) struct Deferred<T, R> {
+    item: Option<T>,
+    tx: Sender<Completion<T, R>>,
+}
+
+impl<T, R> Deferred<T, R> {
+    pub(crate) fn item(&self) -> &T {
+        self.item.as_ref().expect("completed item")
+    }
+
+    /// Complete the item with its result (computed by `func`)
+    pub(crate) fn complete(mut self, func: impl FnOnce(T) -> R) {
+        let item = self.item.take().expect("completed item");
+        let _ = self.tx.send(Completion::Done(func(item))); // unless `unordered_map` failed
+    }
+
+    /// Pass the item to `unordered_map`'s `func` again
+    pub(crate) fn retry(mut self) {
+        let item = self.item.take().expect("completed item");
+        let _ = self.tx.send(Completion::Retry(item));
+    }
+}
+
+impl<T, R> Drop for Deferred<T, R> {
+    fn drop(&mut self) {
+        // a panic (while completing it) is propagated by `unordered_map` instead
+        if !std::thread::panicking() {
+            if let Some(item) = self.item.take() {
+                let _ = self.tx.send(Completion::Retry(item));
+            }
+        }
+    }
+}
+
+/// Run `func` on each item using rayon's thread pool (with up to `limit` items in flight), and
+/// pass the results to `done` as soon as they are ready - so they may be passed out of order.
+/// `func` completes each item via its `Deferred`, possibly after returning.
+pub(crate) fn unordered_map<T, R, F, D>(
+    items: Vec<T>,
+    limit: usize,
+    func: F,
+    mut done: D,
+) -> Result<()>
+where
+    T: Send,
+    R: Send,
+    F: Fn(Deferred<T, R>) + Sync,
+    D: FnMut(R) -> Result<()>,
+{
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let func = &func;
+    // the calling thread only waits for results, so it doesn't have to be a rayon worker
+    rayon::in_place_scope(|scope| {
+        let spawn = |item| {
+            let tx: Sender<Completion<T, R>> = tx.clone();
+            scope.spawn(move |_| {
+                let deferred = Deferred {
+                    item: Some(item),
+                    tx: tx.clone(),
+                };
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| func(deferred))) {
+                    let _ = tx.send(Completion::Panicked(payload)); // the receiver is dropped only after the scope ends
+                }
+            });
+        };
+        let mut items = items.into_iter();
+        let mut running = 0;
+        loop {
+            while running < limit.max(1) {
+                let Some(item) = items.next() else { break };
+                spawn(item);
+                running += 1;
+            }
+            if running == 0 {
+                return Ok(());
+            }
+            match rx.recv().expect("unordered_map channel disconnected") {
+                Completion::Done(result) => {
+                    running -= 1;
+                    done(result)?; // the scope waits for the running tasks on error
+                }
+                Completion::Retry(item) => spawn(item),
+                Completion::Panicked(payload) => panic::resume_unwind(payload),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unordered_map, Deferred, Supervisor};
+    use crate::signals::ExitError;
+    use crossbeam_channel::unbounded;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    fn run(delays_ms: Vec<u64>, limit: usize) -> Vec<u64> {
+        // don't depend on the number of CPUs (the calling thread is blocked waiting for results)
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(limit + 1)
+            .build()
+            .unwrap();
+        let mut results = vec![];
+        pool.install(|| {
+            unordered_map(
+                delays_ms,
+                limit,
+                |deferred| {
+                    deferred.complete(|delay_ms| {
+                        std::thread::sleep(Duration::from_millis(delay_ms));
+                        delay_ms
+                    })
+                },
+                |delay_ms| {
+                    results.push(delay_ms);
+                    Ok(())
+                },
+            )
+        })
+        .unwrap();
+        results
+    }
+
+    #[test]
+    fn test_unordered_map() {
+        // a fast item is done before a slow one submitted earlier
+        assert_eq!(run(vec![500, 1], 2), vec![1, 500]);
+        // items are handled in order when running one at a time
+        assert_eq!(run(vec![100, 1, 50], 1), vec![100, 1, 50]);
+        assert!(run(vec![], 4).is_empty());
+    }
+
+    #[test]
+    fn test_unordered_map_error() {
+        let mut count = 0;
+        let result = unordered_map(
+            vec![1, 2, 3],
+            1,
+            |deferred| deferred.complete(|x| x),
+            |_| {
+                count += 1;
+                anyhow::bail!("failed")
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_unordered_map_deferred() {
+        // a single worker (the calling thread only waits for results)
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let (deferred_tx, deferred_rx) = unbounded::<Deferred<u32, u32>>();
+        let (done_tx, done_rx) = unbounded();
+        let completer = std::thread::spawn(move || {
+            let deferred = deferred_rx.recv().unwrap();
+            done_rx.recv().unwrap();
+            deferred.complete(|x| x * 10);
+        });
+        let retried = AtomicBool::new(false);
+        let mut results = vec![];
+        pool.install(|| {
+            unordered_map(
+                vec![1, 2, 3],
+                3,
+                |deferred| match *deferred.item() {
+                    // completed after the second item, which must not wait for a free worker
+                    1 => deferred_tx.send(deferred).unwrap(),
+                    2 => {
+                        deferred.complete(|x| x * 100);
+                        done_tx.send(()).unwrap();
+                    }
+                    // dropped items are passed to `func` again
+                    _ if !retried.swap(true, Ordering::SeqCst) => drop(deferred),
+                    _ => deferred.complete(|x| x * 100),
+                },
+                |result| {
+                    results.push(result);
+                    Ok(())
+                },
+            )
+        })
+        .unwrap();
+        completer.join().unwrap();
+        results.sort_unstable();
+        assert_eq!(results, vec![10, 200, 300]);
+    }
+
+    #[test]
+    fn test_supervisor() {
+        let supervisor = Supervisor::new();
+        supervisor.spawn("ok", || Ok(())).join().unwrap();
+        supervisor
+            .spawn("exit", || {
+                Err(anyhow::Error::new(ExitError).context("interrupted"))
+            })
+            .join()
+            .unwrap();
+        assert!(supervisor.failures().is_empty()); // not failures
+
+        supervisor
+            .spawn("error", || anyhow::bail!("failed"))
+            .join()
+            .unwrap();
+        let failure = supervisor.failures().try_recv().unwrap();
+        assert_eq!(failure.name, "error");
+        assert_eq!(failure.error.to_string(), "failed");
+
+        // the panic is caught, so the thread exits normally
+        supervisor
+            .spawn("panic", || panic!("injected panic at {}", 42))
+            .join()
+            .unwrap();
+        let failure = supervisor.failures().try_recv().unwrap();
+        assert_eq!(failure.name, "panic");
+        assert_eq!(failure.error.to_string(), "panicked: injected panic at 42");
+
+        let result = supervisor.run("scoped", || -> anyhow::Result<()> { panic!("injected") });
+        assert!(result.is_err());
+        assert_eq!(supervisor.failures().try_recv().unwrap().name, "scoped");
+    }
+}
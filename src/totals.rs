@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+
+use crate::metrics::{Metrics, Total};
+
+/// Monotonic counters which continue across restarts (instead of being reset, which breaks
+/// `rate()` queries spanning a restart): they are persisted in the DB periodically and on
+/// shutdown, and the persisted values are restored on startup.
+#[derive(Clone)]
+pub(crate) struct Totals {
+    pub(crate) blocks_indexed: Total,
+    pub(crate) reorgs: Total,
+    pub(crate) requests: Total,
+    restarts: Total,
+}
+
+impl Totals {
+    pub(crate) fn new(metrics: &Metrics) -> Self {
+        Self {
+            blocks_indexed: metrics.total(
+                "index_blocks_total",
+                "# of indexed blocks (including re-indexed ones)",
+            ),
+            reorgs: metrics.total("index_reorgs_total", "# of chain reorganizations"),
+            requests: metrics.total("rpc_requests_total", "# of Electrum RPC requests"),
+            restarts: metrics.total(
+                "process_restarts_total",
+                "# of times electrs was restarted (using the same DB)",
+            ),
+        }
+    }
+
+    fn named(&self) -> [(&'static str, &Total); 4] {
+        [
+            ("blocks_indexed", &self.blocks_indexed),
+            ("reorgs", &self.reorgs),
+            ("requests", &self.requests),
+            ("restarts", &self.restarts),
+        ]
+    }
+
+    /// Continue counting from the persisted values (this is a restart, if any were persisted).
+    /// Unknown names are ignored, and missing ones start from zero.
+    pub(crate) fn restore(&self, persisted: &BTreeMap<String, u64>) {
+        for (name, total) in self.named() {
+            total.inc_by(persisted.get(name).copied().unwrap_or(0));
+        }
+        if !persisted.is_empty() {
+            self.restarts.inc();
+        }
+    }
+
+    /// The current values, to be persisted
+    pub(crate) fn values(&self) -> BTreeMap<String, u64> {
+        self.named()
+            .into_iter()
+            .map(|(name, total)| (name.to_owned(), total.get()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Totals;
+    use crate::metrics::Metrics;
+
+    use std::collections::BTreeMap;
+
+    fn metrics() -> Metrics {
+        Metrics::new("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_restore() {
+        let totals = Totals::new(&metrics());
+        totals.restore(&BTreeMap::new()); // the first run
+        totals.blocks_indexed.inc_by(10);
+        totals.requests.inc();
+        let values = totals.values();
+        assert_eq!(values["blocks_indexed"], 10);
+        assert_eq!(values["requests"], 1);
+        assert_eq!(values["restarts"], 0);
+
+        let mut persisted = values;
+        persisted.insert("removed".to_owned(), 5); // e.g. by a newer version
+        persisted.remove("reorgs"); // e.g. by an older version
+        let totals = Totals::new(&metrics());
+        totals.restore(&persisted);
+        totals.blocks_indexed.inc_by(2);
+        let values = totals.values();
+        assert_eq!(values["blocks_indexed"], 12);
+        assert_eq!(values["requests"], 1);
+        assert_eq!(values["reorgs"], 0);
+        assert_eq!(values["restarts"], 1);
+        assert!(!values.contains_key("removed"));
+    }
+}
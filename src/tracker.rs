@@ -1,66 +1,463 @@
+use std::collections::HashSet;
 use std::ops::ControlFlow;
 
 use anyhow::{Context, Result};
-use bitcoin::{BlockHash, Txid};
+use bitcoin::{
+    blockdata::constants::genesis_block, consensus::serialize, Amount, BlockHash, Network,
+    OutPoint, Txid,
+};
 use bitcoin_slices::{bsl, Error::VisitBreak, Visit, Visitor};
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::{
-    cache::Cache,
+    blocks_dir::BlocksDir,
+    broadcasts::Location,
+    cache::{Cache, EmptyScriptHashes},
     chain::Chain,
     config::Config,
-    daemon::Daemon,
-    db::DBStore,
-    index::Index,
-    mempool::{FeeHistogram, Mempool},
-    metrics::Metrics,
+    daemon::Node,
+    db::{DBStore, StoreOptions},
+    index::{Index, IndexOptions, MissingBlock},
+    mempool::{Mempool, MempoolStats, MempoolSummary},
+    metrics::{Gauge, Metrics},
+    migration::{Layout, Migration, Progress},
+    outpoints::Spender,
     signals::ExitFlag,
-    status::{Balance, ScriptHashStatus, UnspentEntry},
-    types::bsl_txid,
+    status::{Balance, ScriptHashStatus, Stats, UnspentEntry},
+    totals::Totals,
+    types::{bsl_txid, PrefixLen, ScriptHash, ScriptTypes, SerBlock, SerTransaction, Touched},
 };
 
+/// Indexed blocks (below the tip) compared with bitcoind's chain at startup
+const CHECKPOINT_DEPTHS: [usize; 3] = [100, 1000, 10000];
+
+/// Maximal delay between the checks for the previous DB's users, after a shadow migration
+const MAX_SWITCH_DELAY: Duration = Duration::from_secs(1);
+
+/// Waiting longer for the previous DB's users (e.g. a long query) is logged
+const SWITCH_WARN_DURATION: Duration = Duration::from_secs(10);
+
+/// Network name, given its genesis block hash
+fn chain_name(genesis: BlockHash) -> String {
+    [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Testnet4,
+        Network::Signet,
+        Network::Regtest,
+    ]
+    .into_iter()
+    .find(|network| genesis_block(*network).block_hash() == genesis)
+    .map_or_else(
+        || "an unknown chain".to_owned(),
+        |network| network.to_string(),
+    )
+}
+
+/// Monotonic counters of the published chain and mempool changes: data derived from the same
+/// generations is still valid (e.g. after a sync which found no new blocks or transactions).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Generations {
+    pub chain: u64,   // incremented when a new tip is published
+    pub mempool: u64, // incremented when mempool transactions are added or removed
+}
+
+/// A value derived from the published state, recomputed only when its generations change
+struct Memo<T> {
+    cached: Mutex<Option<(Generations, T)>>,
+    #[cfg(test)]
+    computed: std::sync::atomic::AtomicUsize,
+}
+
+impl<T: Clone> Memo<T> {
+    fn new() -> Self {
+        Self {
+            cached: Mutex::new(None),
+            #[cfg(test)]
+            computed: Default::default(),
+        }
+    }
+
+    fn get(&self, generations: Generations, compute: impl FnOnce() -> T) -> T {
+        let mut cached = self.cached.lock();
+        match &*cached {
+            Some((cached_generations, value)) if *cached_generations == generations => {
+                value.clone()
+            }
+            _ => {
+                #[cfg(test)]
+                self.computed
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let value = compute();
+                *cached = Some((generations, value.clone()));
+                value
+            }
+        }
+    }
+}
+
+fn generations_gauge(metrics: &Metrics) -> Gauge {
+    metrics.gauge(
+        "sync_generation",
+        "# of published chain tips and mempool changes (see `Generations`)",
+        "type",
+    )
+}
+
+fn migration_gauge(metrics: &Metrics) -> Gauge {
+    metrics.gauge(
+        "index_migration_height",
+        "Shadow migration progress (`migrated` and `tip` block heights)",
+        "type",
+    )
+}
+
+/// Open the index at `path`, using the given DB settings.
+fn open_index(
+    config: &Config,
+    path: &Path,
+    metrics: &Metrics,
+    options: &StoreOptions,
+) -> Result<Index> {
+    let open = if config.repair_db {
+        DBStore::open_or_repair
+    } else {
+        DBStore::open
+    };
+    let mut store = open(path, options)?;
+    store.check_network(config.network, config.genesis().block_hash())?;
+    store.set_durability(config.db_durability);
+    let mut index = Index::load(
+        store,
+        Chain::for_config(config),
+        metrics,
+        &IndexOptions {
+            script_types: options.script_types.clone(),
+            ..IndexOptions::for_config(config)
+        },
+    )
+    .context("failed to open index")?;
+    index.set_max_reorg_depth(config.max_reorg_depth, config.allow_deep_reorg);
+    index.set_checkpoints(config.checkpoints.clone());
+    index.set_prefetch_blocks(config.prefetch_blocks);
+    index.set_flush_policy(config.flush_policy, config.defer_initial_compaction);
+    if let Some(window) = config.maintenance_window {
+        index.set_maintenance_window(window);
+    }
+    if let Some(dir) = &config.daemon_blocks_dir {
+        index.set_blocks_dir(BlocksDir::new(dir.clone(), config.magic));
+    }
+    Ok(index)
+}
+
+/// Start indexing into the shadow DB directory using the configured settings, while the
+/// authoritative DB (using `stored` settings) keeps serving.
+fn start_migration(
+    config: &Config,
+    layout: Layout,
+    stored: (ScriptTypes, PrefixLen),
+) -> Result<Migration> {
+    let dir = layout.shadow()?;
+    warn!(
+        "shadow migration: serving the current DB (index_script_types={}, index_prefix_len={}) \
+        while re-indexing it into {} (index_script_types={}, index_prefix_len={})",
+        stored.0,
+        stored.1,
+        dir.display(),
+        config.index_script_types,
+        config.index_prefix_len,
+    );
+    let metrics = Metrics::unserved(); // not scraped (until it is served)
+    let options = StoreOptions {
+        auto_reindex: true, // a leftover DB using other settings is re-indexed
+        ..StoreOptions::for_config(config)
+    };
+    let index =
+        open_index(config, &dir, &metrics, &options).context("failed to open shadow index")?;
+    Ok(Migration { index, layout, dir })
+}
+
 /// Electrum protocol subscriptions' tracker
 pub struct Tracker {
-    index: Index,
-    mempool: Mempool,
+    index: RwLock<Arc<Index>>, // replaced when a shadow migration is finished
+    mempool: RwLock<Mempool>,  // also locked while new blocks are published (see `sync()`)
     metrics: Metrics,
     ignore_mempool: bool,
+    empty: EmptyScriptHashes, // invalidated when new blocks and mempool changes are published
+    fees_histogram: Memo<Value>,
+    generations: Gauge,
+    migration: Mutex<Option<Migration>>, // used only by the sync thread
+    migration_progress: Mutex<Option<Progress>>,
+    migration_height: Gauge,
+    migrating: AtomicBool, // the shadow index has more blocks to index
 }
 
 pub(crate) enum Error {
     NotReady,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Initial block download and indexing is in progress
+    Syncing,
+    /// The index has caught up with bitcoind's tip
+    Synced,
+    /// A reorg deeper than `max_reorg_depth` was refused: the previous chain is served until the
+    /// operator authorizes it (or bitcoind's chain contradicts a checkpoint, or bitcoind doesn't
+    /// have a block to be indexed)
+    Stalled,
+}
+
 impl Tracker {
     pub fn new(config: &Config, metrics: Metrics) -> Result<Self> {
-        let store = DBStore::open(
-            &config.db_path,
-            config.db_log_dir.as_deref(),
-            config.auto_reindex,
-            config.db_parallelism,
-        )?;
-        let chain = Chain::new(config.network);
+        let layout = Layout::new(&config.db_path);
+        let db_path = layout.recover()?;
+        let configured = (config.index_script_types.clone(), config.index_prefix_len);
+        // with `shadow_migration`, a DB which requires re-indexing keeps serving meanwhile
+        let stored = match config.shadow_migration {
+            true => DBStore::stored_settings(
+                &db_path,
+                config.db_log_dir.as_deref(),
+                config.db_parallelism,
+            ),
+            false => None,
+        }
+        .filter(|stored| *stored != configured);
+        let (script_types, prefix_len) = stored.clone().unwrap_or(configured);
+        let options = StoreOptions {
+            script_types,
+            prefix_len,
+            ..StoreOptions::for_config(config)
+        };
+        let index = open_index(config, &db_path, &metrics, &options)?;
+        let migration = match stored {
+            Some(stored) => Some(start_migration(config, layout, stored)?),
+            None => None,
+        };
         Ok(Self {
-            index: Index::load(
-                store,
-                chain,
-                &metrics,
-                config.index_batch_size,
-                config.index_lookup_limit,
-                config.reindex_last_blocks,
-            )
-            .context("failed to open index")?,
-            mempool: Mempool::new(&metrics),
+            index: RwLock::new(Arc::new(index)),
+            mempool: RwLock::new(Mempool::new(&metrics, config.index_script_types.clone())),
+            empty: EmptyScriptHashes::new(&metrics),
+            fees_histogram: Memo::new(),
+            generations: generations_gauge(&metrics),
+            migrating: AtomicBool::new(migration.is_some()),
+            migration: Mutex::new(migration),
+            migration_progress: Mutex::new(None),
+            migration_height: migration_gauge(&metrics),
             metrics,
             ignore_mempool: config.ignore_mempool,
         })
     }
 
-    pub(crate) fn chain(&self) -> &Chain {
-        self.index.chain()
+    #[cfg(test)]
+    pub(crate) fn from_index(index: Index, metrics: Metrics) -> Self {
+        Self {
+            index: RwLock::new(Arc::new(index)),
+            mempool: RwLock::new(Mempool::new(&metrics, ScriptTypes::all())),
+            empty: EmptyScriptHashes::new(&metrics),
+            fees_histogram: Memo::new(),
+            generations: generations_gauge(&metrics),
+            migration: Mutex::new(None),
+            migration_progress: Mutex::new(None),
+            migration_height: migration_gauge(&metrics),
+            migrating: AtomicBool::new(false),
+            metrics,
+            ignore_mempool: false,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_migration(&self, migration: Migration) {
+        *self.migration.lock() = Some(migration);
+        self.migrating.store(true, Ordering::SeqCst);
+    }
+
+    /// The served index (replaced when a shadow migration is finished), to be used for a single
+    /// query (so the replaced index is closed soon afterwards).
+    pub(crate) fn index(&self) -> Arc<Index> {
+        Arc::clone(&self.index.read())
+    }
+
+    /// Make sure that bitcoind's chain contains the indexed blocks: its genesis block and a few
+    /// checkpoints below the indexed tip (the ones above bitcoind's tip are skipped), so an
+    /// index is not extended using another chain.
+    pub(crate) fn check_chain(&self, daemon: &dyn Node) -> Result<()> {
+        let chain = self.chain();
+        let genesis = chain.get_block_hash(0).expect("missing genesis block");
+        let daemon_genesis = daemon
+            .get_block_hash(0)?
+            .context("bitcoind has no genesis block")?;
+        ensure!(
+            daemon_genesis == genesis,
+            "bitcoind is running on {} (genesis block {}), but the index is of {} (genesis block {})",
+            chain_name(daemon_genesis),
+            daemon_genesis,
+            chain_name(genesis),
+            genesis,
+        );
+        let checkpoints = CHECKPOINT_DEPTHS
+            .iter()
+            .filter_map(|depth| chain.height().checked_sub(*depth))
+            .filter(|height| *height > 0);
+        for height in checkpoints {
+            let indexed = chain.get_block_hash(height).expect("missing indexed block");
+            if let Some(daemon_hash) = daemon.get_block_hash(height)? {
+                ensure!(
+                    daemon_hash == indexed,
+                    "bitcoind's block {} at height {} differs from the indexed block {}: \
+                    the index was built using another {} chain (remove it to re-index)",
+                    daemon_hash,
+                    height,
+                    indexed,
+                    chain_name(genesis),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// A snapshot of the indexed chain (not modified by concurrent syncs).
+    pub(crate) fn chain(&self) -> Arc<Chain> {
+        self.index().chain()
+    }
+
+    /// The indexed chain, extended by the headers whose blocks are not indexed yet.
+    pub(crate) fn headers(&self) -> Arc<Chain> {
+        self.index().headers()
     }
 
-    pub(crate) fn fees_histogram(&self) -> &FeeHistogram {
-        self.mempool.fees_histogram()
+    /// Returns the block's txids, using the DB (see `merkle_cache`) if possible (otherwise, they
+    /// are fetched from bitcoind and cached).
+    pub(crate) fn get_block_txids(
+        &self,
+        daemon: &dyn Node,
+        cache: &Cache,
+        blockhash: BlockHash,
+    ) -> Result<Vec<Txid>> {
+        if let Some(txids) = self.index().get_block_txids(blockhash) {
+            return Ok(txids);
+        }
+        if let Some(txids) = cache.get_block_txids(&self.chain(), &blockhash) {
+            return Ok(txids);
+        }
+        let txids = daemon.get_block_txids(blockhash)?;
+        cache.add_block_txids(&self.chain(), blockhash, &txids);
+        Ok(txids)
+    }
+
+    /// Call `f` on the serialized block (cached, since it may be requested in chunks).
+    pub(crate) fn get_block<T>(
+        &self,
+        daemon: &dyn Node,
+        cache: &Cache,
+        blockhash: BlockHash,
+        f: impl Fn(&[u8]) -> T,
+    ) -> Result<T> {
+        if let Some(result) = cache.get_block(&self.chain(), &blockhash, &f) {
+            return Ok(result);
+        }
+        let block = daemon.get_block(blockhash)?;
+        let result = f(&block);
+        cache.add_block(&self.chain(), blockhash, block);
+        Ok(result)
+    }
+
+    /// Returns bitcoind's verbose transaction (cached if confirmed), so it doesn't require
+    /// `txindex` on bitcoind.
+    pub(crate) fn get_transaction_info(
+        &self,
+        daemon: &dyn Node,
+        cache: &Cache,
+        txid: Txid,
+    ) -> Result<Value> {
+        if let Some(info) = cache.get_tx_info(&self.chain(), &txid) {
+            return Ok(info);
+        }
+        let blockhash = self
+            .lookup_transaction(daemon, txid)?
+            .map(|(blockhash, tx)| {
+                cache.add_tx(&self.chain(), blockhash, txid, || tx);
+                blockhash
+            });
+        let info = daemon.get_transaction_info(&txid, blockhash)?;
+        if let Some(blockhash) = blockhash {
+            cache.add_tx_info(&self.chain(), blockhash, txid, &info);
+        }
+        Ok(info)
+    }
+
+    /// The number of published reorgs deeper than `max_reorg_depth` (since startup).
+    pub(crate) fn deep_reorgs(&self) -> usize {
+        self.index().deep_reorgs()
+    }
+
+    /// The published chain and mempool generations (read together, since new blocks are
+    /// published while the mempool is locked).
+    pub(crate) fn generations(&self) -> Generations {
+        let mempool = self.mempool.read();
+        Generations {
+            chain: self.index().generation(),
+            mempool: mempool.generation(),
+        }
+    }
+
+    /// `mempool.get_fee_histogram` response (recomputed only after mempool changes)
+    pub(crate) fn fees_histogram(&self) -> Value {
+        let mempool = self.mempool.read();
+        let generations = Generations {
+            chain: 0, // doesn't depend on the chain
+            mempool: mempool.generation(),
+        };
+        self.fees_histogram
+            .get(generations, || json!(mempool.fees_histogram()))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn fees_histogram_computed(&self) -> usize {
+        self.fees_histogram
+            .computed
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    pub(crate) fn mempool_stats(&self) -> MempoolStats {
+        self.mempool.read().stats()
+    }
+
+    /// See `Index::take_recent_blocks()`
+    pub(crate) fn take_recent_blocks(&self) -> Vec<(BlockHash, SerBlock)> {
+        self.index().take_recent_blocks()
+    }
+
+    pub(crate) fn mempool_summary(&self) -> MempoolSummary {
+        self.mempool.read().summary()
+    }
+
+    /// The tracked mempool transactions funding any of `scripthashes` (with the funded amount)
+    pub(crate) fn mempool_funding(
+        &self,
+        scripthashes: &HashSet<ScriptHash>,
+    ) -> Vec<(ScriptHash, Txid, Amount)> {
+        let mempool = self.mempool.read();
+        let mut result = vec![];
+        for scripthash in scripthashes {
+            for entry in mempool.filter_by_funding(scripthash) {
+                let value = entry
+                    .tx
+                    .output
+                    .iter()
+                    .filter(|txo| ScriptHash::new(&txo.script_pubkey) == *scripthash)
+                    .map(|txo| txo.value)
+                    .sum();
+                result.push((*scripthash, entry.txid, value));
+            }
+        }
+        result
     }
 
     pub(crate) fn metrics(&self) -> &Metrics {
@@ -68,49 +465,399 @@ impl Tracker {
     }
 
     pub(crate) fn get_unspent(&self, status: &ScriptHashStatus) -> Vec<UnspentEntry> {
-        status.get_unspent(self.index.chain())
+        status.get_unspent(&self.index().chain())
     }
 
-    pub(crate) fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<bool> {
-        let done = self.index.sync(daemon, exit_flag)?;
-        if done && !self.ignore_mempool {
-            self.mempool.sync(daemon, exit_flag);
-            // TODO: double check tip - and retry on diff
+    /// Index new blocks and poll the mempool, while the previous state is used by queries.
+    /// When done, the new blocks and the mempool changes are published together, while holding
+    /// the mempool's write lock (so queries see either the old state or the new one).
+    /// `cache` is cleared before publishing a deep reorg (see `Index::deep_reorgs`), since its
+    /// final entries are not revalidated.
+    pub(crate) fn sync(
+        &self,
+        daemon: &dyn Node,
+        exit_flag: &ExitFlag,
+        cache: &Cache,
+    ) -> Result<bool> {
+        let index = self.index();
+        let done = index.sync(daemon, exit_flag)?;
+        let update = match done && !self.ignore_mempool {
+            true => self.mempool.read().poll(daemon, exit_flag),
+            false => None,
+        };
+        // during initial sync, the progress is published (queries are not served yet)
+        if done || !index.is_ready() {
+            let mut mempool = self.mempool.write();
+            let prev = Generations {
+                chain: index.generation(),
+                mempool: mempool.generation(),
+            };
+            if index.is_deep_reorg_pending() {
+                cache.clear();
+            }
+            index.publish();
+            if let Some(update) = update {
+                mempool.apply_sync_update(update);
+            }
+            let next = Generations {
+                chain: index.generation(),
+                mempool: mempool.generation(),
+            };
+            debug!(
+                "published chain_generation={} mempool_generation={}",
+                next.chain, next.mempool
+            );
+            self.generations.set("chain", next.chain as f64);
+            self.generations.set("mempool", next.mempool as f64);
+            // the published funding rows may belong to scripthashes known to be empty
+            // (nothing was published if the generations are unchanged)
+            if next != prev {
+                index.with_touched(|confirmed| self.empty.invalidate(confirmed, mempool.touched()));
+            }
+        }
+        if done {
+            self.migrate(daemon, exit_flag, index)?;
         }
         Ok(done)
     }
 
+    /// Index the next blocks into the shadow DB (see `shadow_migration`), and replace the
+    /// `serving` index once the shadow one has caught up with it.
+    fn migrate(&self, daemon: &dyn Node, exit_flag: &ExitFlag, serving: Arc<Index>) -> Result<()> {
+        let mut migration = self.migration.lock();
+        let shadow = match migration.as_mut() {
+            Some(migration) => migration,
+            None => return Ok(()),
+        };
+        let done = shadow.index.sync(daemon, exit_flag)?;
+        shadow.index.publish(); // not served yet
+        let (migrated, tip) = (shadow.index.chain(), serving.chain());
+        self.migration_height
+            .set("migrated", migrated.height() as f64);
+        self.migration_height.set("tip", tip.height() as f64);
+        *self.migration_progress.lock() = Some(Progress {
+            dir: shadow.dir.clone(),
+            migrated_height: migrated.height(),
+            tip_height: tip.height(),
+        });
+        self.migrating.store(!done, Ordering::SeqCst);
+        if !done || migrated.tip() != tip.tip() {
+            if !done {
+                info!(
+                    "shadow migration: {} of {} blocks indexed",
+                    migrated.height(),
+                    tip.height()
+                );
+            }
+            return Ok(()); // the tips may differ by a new block (synced on the next call)
+        }
+        let migration = migration.take().expect("missing migration");
+        self.switch(migration, serving, exit_flag)
+    }
+
+    /// Serve the caught-up shadow index instead of `serving`, whose DB is archived after being
+    /// closed. The switch is committed by `Layout::activate()`, so a crash afterwards is
+    /// recovered on startup (by archiving the previous DB).
+    fn switch(
+        &self,
+        mut migration: Migration,
+        serving: Arc<Index>,
+        exit_flag: &ExitFlag,
+    ) -> Result<()> {
+        migration.index.adopt(&serving);
+        migration.layout.activate(&migration.dir)?;
+        {
+            // queries see either the previous index or the new one (with the same chain)
+            let _mempool = self.mempool.write();
+            *self.index.write() = Arc::new(migration.index);
+            self.empty.invalidate(None, None);
+        }
+        *self.migration_progress.lock() = None;
+        self.migrating.store(false, Ordering::SeqCst);
+        // wait for the queries still using the previous index, so its DB is closed (on exit, it
+        // is archived by the next startup)
+        let mut serving = serving;
+        let mut delay = Duration::from_millis(10);
+        let mut warn_at = Some(Instant::now() + SWITCH_WARN_DURATION);
+        let prev = loop {
+            match Arc::try_unwrap(serving) {
+                Ok(prev) => break prev,
+                Err(index) => serving = index,
+            }
+            exit_flag
+                .poll()
+                .context("shadow migration: stopped waiting for the previous DB's queries")?;
+            if warn_at.is_some_and(|warn_at| Instant::now() > warn_at) {
+                warn_at = None; // logged once
+                warn!(
+                    "shadow migration: waiting for {} users of the previous DB",
+                    Arc::strong_count(&serving) - 1
+                );
+            }
+            thread::sleep(delay);
+            delay = std::cmp::min(delay * 2, MAX_SWITCH_DELAY);
+        };
+        drop(prev);
+        match migration.layout.archive() {
+            Ok(archived) => warn!(
+                "shadow migration finished: serving {} (the previous DB is archived as {})",
+                migration.dir.display(),
+                archived.map_or_else(|| "-".to_owned(), |path| path.display().to_string()),
+            ),
+            // retried on the next startup (see `Layout::recover()`)
+            Err(e) => warn!("shadow migration finished, but archiving failed: {:#}", e),
+        }
+        Ok(())
+    }
+
+    /// Returns `true` while the shadow index has more blocks to index (see `shadow_migration`),
+    /// so the next sync shouldn't wait for new blocks.
+    pub(crate) fn is_migrating(&self) -> bool {
+        self.migrating.load(Ordering::SeqCst)
+    }
+
+    /// The progress of a shadow migration (`None` if there is none, or before its first sync)
+    pub(crate) fn migration_progress(&self) -> Option<Progress> {
+        self.migration_progress.lock().clone()
+    }
+
+    pub(crate) fn state(&self) -> State {
+        let index = self.index();
+        if index.is_stalled() {
+            State::Stalled
+        } else if index.is_ready() {
+            State::Synced
+        } else {
+            State::Syncing
+        }
+    }
+
     pub(crate) fn status(&self) -> Result<(), Error> {
-        if self.index.is_ready() {
-            return Ok(());
+        // a stalled index still serves its current chain
+        match self.index().is_ready() {
+            true => Ok(()),
+            false => Err(Error::NotReady),
         }
-        Err(Error::NotReady)
+    }
+
+    /// The height of bitcoind's best header, while initial sync is in progress
+    pub(crate) fn sync_target(&self) -> Option<usize> {
+        let syncing = self.status().is_err();
+        syncing.then(|| self.index().headers().height())
+    }
+
+    /// See `Index::allow_deep_reorg()`
+    pub(crate) fn allow_deep_reorg(&self) -> Option<usize> {
+        self.index().allow_deep_reorg()
+    }
+
+    /// See `Index::request_compaction()`
+    pub(crate) fn request_compaction(&self) -> bool {
+        self.index().request_compaction()
+    }
+
+    /// See `Index::missing_block()`
+    pub(crate) fn missing_block(&self) -> Option<MissingBlock> {
+        self.index().missing_block()
+    }
+
+    /// See `Index::maintain()`
+    pub(crate) fn maintain(&self) -> bool {
+        self.index().maintain()
+    }
+
+    /// See `Index::totals()`
+    pub(crate) fn totals(&self) -> Totals {
+        self.index().totals().clone()
+    }
+
+    /// See `Index::db_stats()`
+    pub(crate) fn db_stats(&self) -> Result<Vec<(&'static str, u64, u64)>> {
+        self.index().db_stats()
+    }
+
+    /// Return the prefixes touched by new blocks and by mempool changes since the previous call
+    /// (`None` if unknown, so all subscriptions may be affected).
+    /// The touched sets refer to the returned chain snapshot.
+    pub(crate) fn take_touched(&self) -> (Arc<Chain>, Option<Touched>, Option<Touched>) {
+        let mut mempool = self.mempool.write(); // no blocks are published meanwhile
+        let index = self.index();
+        (index.chain(), index.take_touched(), mempool.take_touched())
+    }
+
+    /// Funded outpoints' spending may affect the status (so they should be watched).
+    pub(crate) fn get_outpoints(&self, status: &ScriptHashStatus) -> HashSet<OutPoint> {
+        status.funded_outpoints(&self.index().chain())
+    }
+
+    /// Returns a new (synced) status, without DB lookups if `scripthash` is known to be empty.
+    pub(crate) fn new_scripthash_status(
+        &self,
+        scripthash: ScriptHash,
+        daemon: &dyn Node,
+        cache: &Cache,
+    ) -> Result<ScriptHashStatus> {
+        let mut status = ScriptHashStatus::new(scripthash);
+        if self.empty.contains(&scripthash) {
+            return Ok(status); // its (empty) history is valid for the current chain and mempool
+        }
+        let epoch = self.empty.epoch();
+        self.update_scripthash_status(&mut status, self.chain(), daemon, cache, true)?;
+        if status.get_history().is_empty() {
+            self.empty.insert(scripthash, epoch);
+        }
+        Ok(status)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_known_empty(&self, scripthash: &ScriptHash) -> bool {
+        self.empty.contains(scripthash)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn empty_epoch(&self) -> u64 {
+        self.empty.epoch()
     }
 
     pub(crate) fn update_scripthash_status(
         &self,
         status: &mut ScriptHashStatus,
-        daemon: &Daemon,
+        chain: Arc<Chain>,
+        daemon: &dyn Node,
         cache: &Cache,
+        confirmed_touched: bool,
     ) -> Result<bool> {
         let prev_statushash = status.statushash();
-        status.sync(&self.index, &self.mempool, daemon, cache)?;
+        status.sync(
+            &self.index(),
+            chain,
+            &self.mempool,
+            daemon,
+            cache,
+            confirmed_touched,
+        )?;
         Ok(prev_statushash != status.statushash())
     }
 
     pub(crate) fn get_balance(&self, status: &ScriptHashStatus) -> Balance {
-        status.get_balance(self.chain())
+        status.get_balance(&self.chain())
+    }
+
+    pub(crate) fn get_stats(&self, status: &ScriptHashStatus) -> Stats {
+        status.get_stats(&self.chain())
+    }
+
+    /// Returns the transaction (and its block, if confirmed) using the tracked mempool and the
+    /// index, so it doesn't require `txindex` on bitcoind.
+    pub(crate) fn get_raw_transaction(
+        &self,
+        daemon: &dyn Node,
+        txid: Txid,
+    ) -> Result<Option<(Option<BlockHash>, SerTransaction)>> {
+        if let Some(entry) = self.mempool.read().get(&txid) {
+            return Ok(Some((None, serialize(&entry.tx).into_boxed_slice())));
+        }
+        let confirmed = self.lookup_transaction(daemon, txid)?;
+        Ok(confirmed.map(|(blockhash, tx)| (Some(blockhash), tx)))
+    }
+
+    /// Find a transaction in the tracked mempool or in the index (e.g. a tracked broadcast).
+    pub(crate) fn locate_transaction(&self, daemon: &dyn Node, txid: Txid) -> Result<Location> {
+        if self.mempool.read().get(&txid).is_some() {
+            return Ok(Location::Mempool);
+        }
+        let height = self
+            .lookup_transaction(daemon, txid)?
+            .and_then(|(blockhash, _tx)| self.chain().get_block_height(&blockhash));
+        Ok(height.map_or(Location::Missing, Location::Confirmed))
+    }
+
+    /// The confirmation height of a transaction (or `0`/`-1` if it is in the tracked mempool, as
+    /// in `blockchain.scripthash.get_history`), without fetching its block: the txid's candidate
+    /// blocks are checked using their txids (stored by `merkle_cache`, cached or fetched).
+    pub(crate) fn get_tx_height(
+        &self,
+        daemon: &dyn Node,
+        cache: &Cache,
+        txid: Txid,
+    ) -> Result<Option<i64>> {
+        let index = self.index();
+        let chain = index.chain();
+        let blockhashes: Vec<BlockHash> = index.filter_by_txid(&chain, txid).collect();
+        // cached transactions are confirmed, so a single candidate block must contain it
+        if let [blockhash] = blockhashes[..] {
+            if cache.get_tx(&chain, &txid, |_| ()).is_some() {
+                return Ok(chain.get_block_height(&blockhash).map(|h| h as i64));
+            }
+        }
+        // Note: there are two blocks with coinbase transactions having same txid (see BIP-30)
+        for blockhash in blockhashes {
+            if self
+                .get_block_txids(daemon, cache, blockhash)?
+                .contains(&txid)
+            {
+                return Ok(chain.get_block_height(&blockhash).map(|h| h as i64));
+            }
+        }
+        let mempool = self.mempool.read();
+        Ok(mempool
+            .get(&txid)
+            .map(|entry| if entry.has_unconfirmed_inputs { -1 } else { 0 }))
+    }
+
+    /// Find the transaction spending `outpoint` (in the index, or in the tracked mempool).
+    /// The spending rows' blocks are fetched, since their prefix may match other outpoints.
+    pub(crate) fn get_spender(
+        &self,
+        daemon: &dyn Node,
+        outpoint: OutPoint,
+    ) -> Result<Option<Spender>> {
+        let index = self.index();
+        let chain = index.chain();
+        let blockhashes = index.filter_by_spending(&chain, outpoint);
+        let mut result = None;
+        daemon.for_blocks(blockhashes.collect(), &mut |blockhash, block| {
+            if result.is_some() {
+                return; // an outpoint is spent by a single confirmed transaction
+            }
+            let mut visitor = FindSpender::new(outpoint);
+            let txid = match bsl::Block::visit(&block, &mut visitor) {
+                Ok(_) | Err(VisitBreak) => visitor.found,
+                Err(e) => panic!("core returned invalid block: {:?}", e),
+            };
+            result = txid.and_then(|txid| {
+                let height = chain.get_block_height(&blockhash)?;
+                Some(Spender {
+                    txid,
+                    block: Some((height, blockhash)),
+                })
+            });
+        })?;
+        if result.is_some() {
+            return Ok(result);
+        }
+        let mempool = self.mempool.read();
+        let spender = mempool
+            .filter_by_spending(&outpoint)
+            .first()
+            .map(|entry| Spender {
+                txid: entry.txid,
+                block: None,
+            });
+        Ok(spender)
     }
 
     pub(crate) fn lookup_transaction(
         &self,
-        daemon: &Daemon,
+        daemon: &dyn Node,
         txid: Txid,
-    ) -> Result<Option<(BlockHash, Box<[u8]>)>> {
+    ) -> Result<Option<(BlockHash, SerTransaction)>> {
         // Note: there are two blocks with coinbase transactions having same txid (see BIP-30)
-        let blockhashes = self.index.filter_by_txid(txid);
+        let index = self.index();
+        let chain = index.chain();
+        let blockhashes = index.filter_by_txid(&chain, txid);
         let mut result = None;
-        daemon.for_blocks(blockhashes, |blockhash, block| {
+        daemon.for_blocks(blockhashes.collect(), &mut |blockhash, block| {
             if result.is_some() {
                 return; // keep first matching transaction
             }
@@ -126,7 +873,7 @@ impl Tracker {
 
 pub struct FindTransaction {
     txid: bitcoin::Txid,
-    found: Option<Box<[u8]>>, // no need to deserialize
+    found: Option<SerTransaction>, // no need to deserialize
 }
 
 impl FindTransaction {
@@ -144,3 +891,37 @@ impl Visitor for FindTransaction {
         }
     }
 }
+
+struct FindSpender {
+    outpoint: OutPoint,
+    spends: bool, // the current transaction spends `outpoint`
+    found: Option<Txid>,
+}
+
+impl FindSpender {
+    fn new(outpoint: OutPoint) -> Self {
+        Self {
+            outpoint,
+            spends: false,
+            found: None,
+        }
+    }
+}
+
+impl Visitor for FindSpender {
+    // Called after all TxIns are visited
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        if self.spends {
+            self.found = Some(bsl_txid(tx));
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+
+    fn visit_tx_in(&mut self, _vin: usize, tx_in: &bsl::TxIn) -> ControlFlow<()> {
+        let prevout: OutPoint = tx_in.prevout().into();
+        self.spends |= prevout == self.outpoint;
+        ControlFlow::Continue(())
+    }
+}
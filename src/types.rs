@@ -1,12 +1,15 @@
 use anyhow::Result;
 
+use std::collections::{BTreeSet, HashSet};
 use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
 
 use bitcoin::blockdata::block::Header as BlockHeader;
 use bitcoin::{
     consensus::encode::{deserialize, Decodable, Encodable},
     hashes::{hash_newtype, sha256, Hash},
-    io, OutPoint, Script, Txid,
+    io, BlockHash, OutPoint, Script, Txid,
 };
 use bitcoin_slices::bsl;
 
@@ -37,42 +40,229 @@ macro_rules! impl_consensus_encoding {
     );
 }
 
+/// The length of the in-memory hash prefixes (e.g. of touched scripthashes)
 pub const HASH_PREFIX_LEN: usize = 8;
+const MAX_ROW_PREFIX_LEN: usize = 10;
 const HEIGHT_SIZE: usize = 4;
+/// Funding rows store the output index in 3 bytes (a transaction can't have more outputs, since
+/// each one takes at least 9 bytes of a block)
+const VOUT_SIZE: usize = 3;
+const MAX_VOUT: u32 = (1 << (8 * VOUT_SIZE)) - 1;
 
 pub(crate) type HashPrefix = [u8; HASH_PREFIX_LEN];
-pub(crate) type SerializedHashPrefixRow = [u8; HASH_PREFIX_ROW_SIZE];
 type Height = u32;
 pub(crate) type SerBlock = Vec<u8>;
+pub(crate) type SerTransaction = Box<[u8]>;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub(crate) struct HashPrefixRow {
-    prefix: HashPrefix,
-    height: Height, // transaction confirmed height
+/// Stop tracking touched prefixes above this limit (e.g. during initial sync)
+const MAX_TOUCHED_PREFIXES: usize = 1_000_000;
+
+/// Funding and spending prefixes of the transactions which were added (or removed)
+#[derive(Default)]
+pub(crate) struct Touched {
+    pub(crate) funding: HashSet<HashPrefix>,
+    pub(crate) spending: HashSet<HashPrefix>,
+    pub(crate) stale_blocks: HashSet<BlockHash>, // disconnected by a reorg
+}
+
+impl Touched {
+    pub(crate) fn add_funding(&mut self, prefixes: impl Iterator<Item = HashPrefix>) {
+        self.funding.extend(prefixes)
+    }
+
+    pub(crate) fn add_spending(&mut self, prefixes: impl Iterator<Item = HashPrefix>) {
+        self.spending.extend(prefixes)
+    }
+
+    pub(crate) fn add_stale_blocks(&mut self, blockhashes: impl Iterator<Item = BlockHash>) {
+        self.stale_blocks.extend(blockhashes)
+    }
+
+    pub(crate) fn merge(&mut self, other: Touched) {
+        self.funding.extend(other.funding);
+        self.spending.extend(other.spending);
+        self.stale_blocks.extend(other.stale_blocks);
+    }
+
+    /// Too many prefixes - it's cheaper to assume that everything was touched
+    pub(crate) fn is_full(&self) -> bool {
+        self.funding.len() + self.spending.len() > MAX_TOUCHED_PREFIXES
+    }
+}
+
+/// The length of the indexed rows' hash prefixes, selected when the index is built (changing it
+/// requires re-indexing): shorter prefixes reduce the DB size, while longer ones have fewer
+/// collisions (each one requiring an extra lookup).
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(try_from = "usize", into = "usize")]
+pub enum PrefixLen {
+    Six,
+    #[default]
+    Eight,
+    Ten,
+}
+
+impl PrefixLen {
+    pub(crate) fn bytes(self) -> usize {
+        match self {
+            PrefixLen::Six => 6,
+            PrefixLen::Eight => 8,
+            PrefixLen::Ten => 10,
+        }
+    }
+}
+
+impl TryFrom<usize> for PrefixLen {
+    type Error = String;
+
+    fn try_from(bytes: usize) -> std::result::Result<Self, Self::Error> {
+        match bytes {
+            6 => Ok(PrefixLen::Six),
+            8 => Ok(PrefixLen::Eight),
+            10 => Ok(PrefixLen::Ten),
+            _ => Err(format!(
+                "unsupported prefix length: {} (not 6, 8 or 10)",
+                bytes
+            )),
+        }
+    }
+}
+
+impl From<PrefixLen> for usize {
+    fn from(len: PrefixLen) -> usize {
+        len.bytes()
+    }
+}
+
+impl fmt::Display for PrefixLen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.bytes())
+    }
+}
+
+impl FromStr for PrefixLen {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        let bytes: usize = string
+            .parse()
+            .map_err(|_| format!("invalid prefix length: {:?}", string))?;
+        PrefixLen::try_from(bytes)
+    }
+}
+
+/// An indexed row's hash prefix (of the index's `PrefixLen`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct RowPrefix {
+    bytes: [u8; MAX_ROW_PREFIX_LEN], // zero-padded
+    len: PrefixLen,
+}
+
+impl RowPrefix {
+    pub(crate) fn new(hash: &[u8], len: PrefixLen) -> Self {
+        let mut bytes = [0; MAX_ROW_PREFIX_LEN];
+        bytes[..len.bytes()].copy_from_slice(&hash[..len.bytes()]);
+        Self { bytes, len }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len.bytes()]
+    }
+}
+
+const MAX_HASH_PREFIX_ROW_SIZE: usize = MAX_ROW_PREFIX_LEN + HEIGHT_SIZE + VOUT_SIZE;
+
+/// The size of a row's hash prefix (with and without an output index, the serialized rows have
+/// an odd and an even size, so they can't be confused)
+fn row_prefix_len(size: usize, has_vout: bool) -> Option<PrefixLen> {
+    let vout_size = if has_vout { VOUT_SIZE } else { 0 };
+    let len = size.checked_sub(HEIGHT_SIZE + vout_size)?;
+    PrefixLen::try_from(len).ok()
 }
 
-pub const HASH_PREFIX_ROW_SIZE: usize = HASH_PREFIX_LEN + HEIGHT_SIZE;
+/// A serialized `HashPrefixRow` (its size depends on the index's `PrefixLen`, and on whether it
+/// stores an output index)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct SerializedHashPrefixRow {
+    bytes: [u8; MAX_HASH_PREFIX_ROW_SIZE], // zero-padded (so rows of the same size are ordered)
+    size: u8,
+}
+
+impl SerializedHashPrefixRow {
+    /// Returns `None` if the key's size doesn't match any `PrefixLen`
+    pub(crate) fn from_key(key: &[u8]) -> Option<Self> {
+        row_prefix_len(key.len(), false).or_else(|| row_prefix_len(key.len(), true))?;
+        let mut bytes = [0; MAX_HASH_PREFIX_ROW_SIZE];
+        bytes[..key.len()].copy_from_slice(key);
+        let size = key.len() as u8;
+        Some(Self { bytes, size })
+    }
+}
+
+impl AsRef<[u8]> for SerializedHashPrefixRow {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.size)]
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct HashPrefixRow {
+    prefix: RowPrefix,
+    height: Height,    // transaction confirmed height
+    vout: Option<u32>, // the funded output's index (`None` for funding rows before format 3)
+}
 
 impl HashPrefixRow {
     pub(crate) fn to_db_row(&self) -> SerializedHashPrefixRow {
-        let mut row = [0; HASH_PREFIX_ROW_SIZE];
-        let len = self
-            .consensus_encode(&mut (&mut row as &mut [u8]))
-            .expect("in-memory writers don't error");
-        debug_assert_eq!(len, HASH_PREFIX_ROW_SIZE);
-        row
+        let len = self.prefix.len.bytes();
+        let mut bytes = [0; MAX_HASH_PREFIX_ROW_SIZE];
+        bytes[..len].copy_from_slice(self.prefix.as_bytes());
+        // little-endian, as in the consensus encoding
+        bytes[len..len + HEIGHT_SIZE].copy_from_slice(&self.height.to_le_bytes());
+        let mut size = len + HEIGHT_SIZE;
+        if let Some(vout) = self.vout {
+            bytes[size..size + VOUT_SIZE].copy_from_slice(&vout.to_le_bytes()[..VOUT_SIZE]);
+            size += VOUT_SIZE;
+        }
+        let size = size as u8;
+        SerializedHashPrefixRow { bytes, size }
     }
 
     pub(crate) fn from_db_row(row: SerializedHashPrefixRow) -> Self {
-        deserialize(&row).expect("bad HashPrefixRow")
+        let size = usize::from(row.size);
+        let (len, has_vout) = match row_prefix_len(size, false) {
+            Some(len) => (len, false),
+            None => (row_prefix_len(size, true).expect("bad HashPrefixRow"), true),
+        };
+        let (prefix, rest) = row.as_ref().split_at(len.bytes());
+        let (height, vout) = rest.split_at(HEIGHT_SIZE);
+        let vout = has_vout.then(|| {
+            let mut bytes = [0; 4];
+            bytes[..VOUT_SIZE].copy_from_slice(vout);
+            u32::from_le_bytes(bytes)
+        });
+        Self {
+            prefix: RowPrefix::new(prefix, len),
+            height: Height::from_le_bytes(height.try_into().expect("bad HashPrefixRow")),
+            vout,
+        }
     }
 
     pub fn height(&self) -> usize {
         usize::try_from(self.height).expect("invalid height")
     }
-}
 
-impl_consensus_encoding!(HashPrefixRow, prefix, height);
+    /// The funded output's index (stored only by funding rows, since format 3)
+    pub(crate) fn vout(&self) -> Option<u32> {
+        self.vout
+    }
+
+    pub(crate) fn prefix(&self) -> &[u8] {
+        self.prefix.as_bytes()
+    }
+}
 
 hash_newtype! {
     /// https://electrum-protocol.readthedocs.io/en/latest/protocol-basics.html#script-hashes
@@ -92,17 +282,191 @@ impl ScriptHash {
     }
 }
 
+/// Output script types (used to select which outputs are indexed)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    /// Bare multisig, future witness versions (v2 and above) and non-standard scripts - including
+    /// empty, malformed and oversized ones (which bitcoind treats as unspendable)
+    Other,
+    /// Provably unspendable outputs (starting with `OP_RETURN`), indexed only if selected
+    OpReturn,
+}
+
+impl ScriptType {
+    const ALL: [ScriptType; 8] = [
+        ScriptType::P2pk,
+        ScriptType::P2pkh,
+        ScriptType::P2sh,
+        ScriptType::P2wpkh,
+        ScriptType::P2wsh,
+        ScriptType::P2tr,
+        ScriptType::Other,
+        ScriptType::OpReturn,
+    ];
+
+    /// Classifies any script (of any size) by checking only its length and a few opcodes.
+    pub(crate) fn of(script: &Script) -> Self {
+        if script.is_op_return() {
+            ScriptType::OpReturn
+        } else if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_p2tr() {
+            ScriptType::P2tr
+        } else if script.is_p2pk() {
+            ScriptType::P2pk
+        } else {
+            ScriptType::Other
+        }
+    }
+
+    /// The script types whose outputs may be spent by an input, following the consensus rules:
+    /// native witness programs require an empty `scriptSig` (and a non-empty witness, except for
+    /// unknown witness versions), while non-witness outputs must be spent without a witness.
+    pub(crate) fn spent_by(empty_script_sig: bool, has_witness: bool) -> &'static [ScriptType] {
+        match (empty_script_sig, has_witness) {
+            (_, false) => &[
+                ScriptType::P2pk,
+                ScriptType::P2pkh,
+                ScriptType::P2sh,
+                ScriptType::Other,
+            ],
+            (true, true) => &[
+                ScriptType::P2wpkh,
+                ScriptType::P2wsh,
+                ScriptType::P2tr,
+                ScriptType::Other,
+            ],
+            (false, true) => &[ScriptType::P2sh, ScriptType::Other], // wrapped witness programs
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ScriptType::P2pk => "p2pk",
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::Other => "other",
+            ScriptType::OpReturn => "op_return",
+        }
+    }
+}
+
+impl fmt::Display for ScriptType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ScriptType {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        ScriptType::ALL
+            .into_iter()
+            .find(|t| t.name() == string)
+            .ok_or_else(|| format!("unknown script type: {:?}", string))
+    }
+}
+
+/// The output script types to be indexed (`None` means all of them, except for `OpReturn` -
+/// so the DBs indexed before it was added keep their meaning)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ScriptTypes(Option<BTreeSet<ScriptType>>);
+
+impl ScriptTypes {
+    /// All the spendable script types (the default)
+    pub(crate) fn all() -> Self {
+        ScriptTypes(None)
+    }
+
+    pub(crate) fn only(types: impl IntoIterator<Item = ScriptType>) -> Self {
+        let types: BTreeSet<ScriptType> = types.into_iter().collect();
+        let spendable = ScriptType::ALL
+            .into_iter()
+            .filter(|t| *t != ScriptType::OpReturn);
+        if types.iter().copied().eq(spendable) {
+            ScriptTypes::all()
+        } else {
+            ScriptTypes(Some(types))
+        }
+    }
+
+    pub(crate) fn is_all(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// Whether an output using `script` is indexed: every output is hashed (regardless of its
+    /// type or size), unless its type is not selected.
+    pub(crate) fn contains(&self, script: &Script) -> bool {
+        let script_type = ScriptType::of(script);
+        match &self.0 {
+            None => script_type != ScriptType::OpReturn,
+            Some(types) => types.contains(&script_type),
+        }
+    }
+
+    pub(crate) fn contains_any(&self, script_types: &[ScriptType]) -> bool {
+        match &self.0 {
+            None => true,
+            Some(types) => script_types.iter().any(|t| types.contains(t)),
+        }
+    }
+}
+
+impl fmt::Display for ScriptTypes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            None => f.write_str("all"),
+            Some(types) => {
+                let names: Vec<&str> = types.iter().map(|t| t.name()).collect();
+                f.write_str(&names.join(","))
+            }
+        }
+    }
+}
+
 pub(crate) struct ScriptHashRow;
 
 impl ScriptHashRow {
     pub(crate) fn scan_prefix(scripthash: ScriptHash) -> HashPrefix {
-        scripthash.0[..HASH_PREFIX_LEN].try_into().unwrap()
+        scripthash.prefix()
     }
 
-    pub(crate) fn row(scripthash: ScriptHash, height: usize) -> HashPrefixRow {
+    pub(crate) fn db_prefix(scripthash: ScriptHash, len: PrefixLen) -> RowPrefix {
+        RowPrefix::new(&scripthash.0[..], len)
+    }
+
+    pub(crate) fn row(
+        scripthash: ScriptHash,
+        height: usize,
+        vout: usize,
+        len: PrefixLen,
+    ) -> HashPrefixRow {
+        let vout = u32::try_from(vout)
+            .ok()
+            .filter(|vout| *vout <= MAX_VOUT)
+            .expect("invalid vout");
         HashPrefixRow {
-            prefix: scripthash.prefix(),
+            prefix: Self::db_prefix(scripthash, len),
             height: Height::try_from(height).expect("invalid height"),
+            vout: Some(vout),
         }
     }
 }
@@ -116,47 +480,50 @@ hash_newtype! {
 
 // ***************************************************************************
 
-fn spending_prefix(prev: OutPoint) -> HashPrefix {
-    let txid_prefix = HashPrefix::try_from(&prev.txid[..HASH_PREFIX_LEN]).unwrap();
-    let value = u64::from_be_bytes(txid_prefix);
-    let value = value.wrapping_add(prev.vout.into());
-    value.to_be_bytes()
+/// The txid's prefix plus the output index (as big-endian integers of the prefix's length)
+fn spending_prefix(prev: OutPoint, len: PrefixLen) -> RowPrefix {
+    let mut txid_prefix = [0u8; 16];
+    txid_prefix[16 - len.bytes()..].copy_from_slice(&prev.txid[..len.bytes()]);
+    let value = u128::from_be_bytes(txid_prefix);
+    let value = value.wrapping_add(prev.vout.into()).to_be_bytes();
+    RowPrefix::new(&value[16 - len.bytes()..], len) // the carry beyond the prefix is dropped
 }
 
 pub(crate) struct SpendingPrefixRow;
 
 impl SpendingPrefixRow {
     pub(crate) fn scan_prefix(outpoint: OutPoint) -> HashPrefix {
-        spending_prefix(outpoint)
+        let prefix = spending_prefix(outpoint, PrefixLen::Eight);
+        prefix.as_bytes().try_into().expect("invalid prefix length")
+    }
+
+    pub(crate) fn db_prefix(outpoint: OutPoint, len: PrefixLen) -> RowPrefix {
+        spending_prefix(outpoint, len)
     }
 
-    pub(crate) fn row(outpoint: OutPoint, height: usize) -> HashPrefixRow {
+    pub(crate) fn row(outpoint: OutPoint, height: usize, len: PrefixLen) -> HashPrefixRow {
         HashPrefixRow {
-            prefix: spending_prefix(outpoint),
+            prefix: spending_prefix(outpoint, len),
             height: Height::try_from(height).expect("invalid height"),
+            vout: None,
         }
     }
 }
 
 // ***************************************************************************
 
-fn txid_prefix(txid: &Txid) -> HashPrefix {
-    let mut prefix = [0u8; HASH_PREFIX_LEN];
-    prefix.copy_from_slice(&txid[..HASH_PREFIX_LEN]);
-    prefix
-}
-
 pub(crate) struct TxidRow;
 
 impl TxidRow {
-    pub(crate) fn scan_prefix(txid: Txid) -> HashPrefix {
-        txid_prefix(&txid)
+    pub(crate) fn db_prefix(txid: Txid, len: PrefixLen) -> RowPrefix {
+        RowPrefix::new(&txid[..], len)
     }
 
-    pub(crate) fn row(txid: Txid, height: usize) -> HashPrefixRow {
+    pub(crate) fn row(txid: Txid, height: usize, len: PrefixLen) -> HashPrefixRow {
         HashPrefixRow {
-            prefix: txid_prefix(&txid),
+            prefix: Self::db_prefix(txid, len),
             height: Height::try_from(height).expect("invalid height"),
+            vout: None,
         }
     }
 }
@@ -179,15 +546,24 @@ impl HeaderRow {
         Self { header }
     }
 
+    /// Serialized like the consensus encoding, but written directly into the row's array
     pub(crate) fn to_db_row(&self) -> SerializedHeaderRow {
+        let header = &self.header;
         let mut row = [0; HEADER_ROW_SIZE];
-        let len = self
-            .consensus_encode(&mut (&mut row as &mut [u8]))
-            .expect("in-memory writers don't error");
-        debug_assert_eq!(len, HEADER_ROW_SIZE);
+        row[0..4].copy_from_slice(&header.version.to_consensus().to_le_bytes());
+        row[4..36].copy_from_slice(header.prev_blockhash.as_byte_array());
+        row[36..68].copy_from_slice(header.merkle_root.as_byte_array());
+        row[68..72].copy_from_slice(&header.time.to_le_bytes());
+        row[72..76].copy_from_slice(&header.bits.to_consensus().to_le_bytes());
+        row[76..80].copy_from_slice(&header.nonce.to_le_bytes());
         row
     }
 
+    /// A block's raw (consensus-encoded) header is already a serialized row
+    pub(crate) fn raw_db_row(raw_header: &[u8]) -> SerializedHeaderRow {
+        raw_header.try_into().expect("invalid block header size")
+    }
+
     pub(crate) fn from_db_row(row: SerializedHeaderRow) -> Self {
         deserialize(&row).expect("bad HeaderRow")
     }
@@ -199,8 +575,14 @@ pub(crate) fn bsl_txid(tx: &bsl::Transaction) -> Txid {
 
 #[cfg(test)]
 mod tests {
-    use crate::types::{spending_prefix, HashPrefixRow, ScriptHash, ScriptHashRow, TxidRow};
-    use bitcoin::{Address, OutPoint, Txid};
+    use crate::types::{
+        spending_prefix, HashPrefixRow, HeaderRow, PrefixLen, ScriptHash, ScriptHashRow,
+        ScriptType, ScriptTypes, SerializedHashPrefixRow, TxidRow,
+    };
+    use bitcoin::{
+        block::Header as BlockHeader, consensus::deserialize, hashes::Hash, Address, OutPoint,
+        ScriptBuf, Txid,
+    };
     use hex_lit::hex;
     use serde_json::{from_str, json};
 
@@ -218,11 +600,81 @@ mod tests {
     fn test_scripthash_row() {
         let hex = "\"4b3d912c1523ece4615e91bf0d27381ca72169dbf6b1c2ffcc9f92381d4984a3\"";
         let scripthash: ScriptHash = from_str(hex).unwrap();
-        let row1 = ScriptHashRow::row(scripthash, 123456);
+        let row1 = ScriptHashRow::row(scripthash, 123456, 2, PrefixLen::Eight);
         let db_row = row1.to_db_row();
-        assert_eq!(db_row, hex!("a384491d38929fcc40e20100"));
+        assert_eq!(db_row.as_ref(), hex!("a384491d38929fcc40e20100020000"));
         let row2 = HashPrefixRow::from_db_row(db_row);
         assert_eq!(row1, row2);
+        assert_eq!(row2.vout(), Some(2));
+
+        let row = ScriptHashRow::row(scripthash, 123456, 0x10203, PrefixLen::Six);
+        assert_eq!(row.to_db_row().as_ref(), hex!("a384491d389240e20100030201"));
+        assert_eq!(row, HashPrefixRow::from_db_row(row.to_db_row()));
+        let row = ScriptHashRow::row(scripthash, 123456, 0, PrefixLen::Ten);
+        assert_eq!(
+            row.to_db_row().as_ref(),
+            hex!("a384491d38929fccffc240e20100000000")
+        );
+        assert_eq!(row, HashPrefixRow::from_db_row(row.to_db_row()));
+    }
+
+    #[test]
+    fn test_funding_row_round_trip() {
+        let scripthash = ScriptHash::hash(b"script");
+        for len in [PrefixLen::Six, PrefixLen::Eight, PrefixLen::Ten] {
+            for (height, vout) in [(0, 0), (1, 1), (840_000, 255), (840_000, 256), (1, 111_111)] {
+                let row = ScriptHashRow::row(scripthash, height, vout, len);
+                let db_row = row.to_db_row();
+                assert_eq!(db_row.as_ref().len(), len.bytes() + 4 + 3);
+                let key = SerializedHashPrefixRow::from_key(db_row.as_ref()).unwrap();
+                let decoded = HashPrefixRow::from_db_row(key);
+                assert_eq!(decoded, row);
+                assert_eq!(decoded.prefix(), &scripthash[..len.bytes()]);
+                assert_eq!(
+                    (decoded.height(), decoded.vout()),
+                    (height, Some(vout as u32))
+                );
+            }
+        }
+        // the rows of the same height are adjacent (only their output index differs)
+        let row = |height, vout| ScriptHashRow::row(scripthash, height, vout, PrefixLen::Eight);
+        let mut rows = vec![row(2, 1), row(1, 256), row(2, 0), row(1, 0)];
+        rows.sort_by_key(|row| row.to_db_row());
+        let heights: Vec<usize> = rows.iter().map(|row| row.height()).collect();
+        assert_eq!(heights, [1, 1, 2, 2]);
+
+        // funding rows written before format 3 are decoded without an output index
+        let old = hex!("a384491d38929fcc40e20100");
+        let row = HashPrefixRow::from_db_row(SerializedHashPrefixRow::from_key(&old).unwrap());
+        assert_eq!((row.height(), row.vout()), (123456, None));
+        assert_eq!(row.to_db_row().as_ref(), old);
+        // other sizes are not rows
+        for size in [0, 9, 11, 19] {
+            assert!(SerializedHashPrefixRow::from_key(&vec![0; size]).is_none());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid vout")]
+    fn test_funding_row_vout_overflow() {
+        ScriptHashRow::row(ScriptHash::hash(b"script"), 1, 1 << 24, PrefixLen::Eight);
+    }
+
+    #[test]
+    fn test_header_row() {
+        // the genesis block, and a block signalling with BIP-9 version bits
+        let genesis = hex!("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c");
+        let block = std::fs::read(
+            "src/tests/blocks/000000000000000002d249a3d89f63ef3fee203adcca7c24008c13fd854513f2",
+        )
+        .unwrap();
+        for raw in [&genesis[..], &block[..80]] {
+            let header: BlockHeader = deserialize(raw).unwrap();
+            let row = HeaderRow::new(header).to_db_row();
+            assert_eq!(row, raw);
+            assert_eq!(HeaderRow::raw_db_row(raw), row);
+            assert_eq!(HeaderRow::from_db_row(row).header, header);
+        }
     }
 
     #[test]
@@ -239,17 +691,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_random_script_types() {
+        use bitcoin::secp256k1::rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // opcodes starting standard (and non-standard) templates
+        const FIRST: [u8; 10] = [0x00, 0x21, 0x41, 0x4c, 0x51, 0x52, 0x60, 0x6a, 0x76, 0xa9];
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10_000 {
+            let len = match rng.gen_ratio(1, 100) {
+                true => rng.gen_range(10_000..100_000), // above bitcoind's `MAX_SCRIPT_SIZE`
+                false => rng.gen_range(0..80),
+            };
+            let mut bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            if let Some(first) = bytes.first_mut() {
+                *first = FIRST[rng.gen_range(0..FIRST.len())];
+            }
+            let script = ScriptBuf::from_bytes(bytes);
+            let script_type = ScriptType::of(&script);
+            assert_eq!(script.is_op_return(), script_type == ScriptType::OpReturn);
+            // every script has a single type, selecting whether it is indexed
+            for t in ScriptType::ALL {
+                assert_eq!(ScriptTypes::only([t]).contains(&script), t == script_type);
+            }
+            let spendable = script_type != ScriptType::OpReturn;
+            assert_eq!(ScriptTypes::all().contains(&script), spendable);
+            assert!(ScriptTypes::only(ScriptType::ALL).contains(&script));
+        }
+    }
+
     #[test]
     fn test_txid1_prefix() {
         // duplicate txids from BIP-30
         let hex = "d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599";
         let txid = Txid::from_str(hex).unwrap();
 
-        let row1 = TxidRow::row(txid, 91812);
-        let row2 = TxidRow::row(txid, 91842);
+        let row1 = TxidRow::row(txid, 91812, PrefixLen::Eight);
+        let row2 = TxidRow::row(txid, 91842, PrefixLen::Eight);
 
-        assert_eq!(row1.to_db_row(), hex!("9985d82954e10f22a4660100"));
-        assert_eq!(row2.to_db_row(), hex!("9985d82954e10f22c2660100"));
+        assert_eq!(row1.to_db_row().as_ref(), hex!("9985d82954e10f22a4660100"));
+        assert_eq!(row2.to_db_row().as_ref(), hex!("9985d82954e10f22c2660100"));
     }
 
     #[test]
@@ -258,12 +739,21 @@ mod tests {
         let hex = "e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468";
         let txid = Txid::from_str(hex).unwrap();
 
-        let row1 = TxidRow::row(txid, 91722);
-        let row2 = TxidRow::row(txid, 91880);
+        let row1 = TxidRow::row(txid, 91722, PrefixLen::Eight);
+        let row2 = TxidRow::row(txid, 91880, PrefixLen::Eight);
 
         // low-endian encoding => rows should be sorted according to block height
-        assert_eq!(row1.to_db_row(), hex!("68b45f58b674e94e4a660100"));
-        assert_eq!(row2.to_db_row(), hex!("68b45f58b674e94ee8660100"));
+        assert_eq!(row1.to_db_row().as_ref(), hex!("68b45f58b674e94e4a660100"));
+        assert_eq!(row2.to_db_row().as_ref(), hex!("68b45f58b674e94ee8660100"));
+        assert!(row1.to_db_row() < row2.to_db_row());
+
+        let row1 = TxidRow::row(txid, 91722, PrefixLen::Six);
+        let row2 = TxidRow::row(txid, 91880, PrefixLen::Ten);
+        assert_eq!(row1.to_db_row().as_ref(), hex!("68b45f58b6744a660100"));
+        assert_eq!(
+            row2.to_db_row().as_ref(),
+            hex!("68b45f58b674e94eb881e8660100")
+        );
     }
 
     #[test]
@@ -273,20 +763,33 @@ mod tests {
             .unwrap();
 
         assert_eq!(
-            spending_prefix(OutPoint { txid, vout: 0 }),
+            spending_prefix(OutPoint { txid, vout: 0 }, PrefixLen::Eight).as_bytes(),
             [31, 30, 29, 28, 27, 26, 25, 24]
         );
         assert_eq!(
-            spending_prefix(OutPoint { txid, vout: 10 }),
+            spending_prefix(OutPoint { txid, vout: 10 }, PrefixLen::Eight).as_bytes(),
             [31, 30, 29, 28, 27, 26, 25, 34]
         );
         assert_eq!(
-            spending_prefix(OutPoint { txid, vout: 255 }),
+            spending_prefix(OutPoint { txid, vout: 255 }, PrefixLen::Eight).as_bytes(),
             [31, 30, 29, 28, 27, 26, 26, 23]
         );
         assert_eq!(
-            spending_prefix(OutPoint { txid, vout: 256 }),
+            spending_prefix(OutPoint { txid, vout: 256 }, PrefixLen::Eight).as_bytes(),
             [31, 30, 29, 28, 27, 26, 26, 24]
         );
+
+        let prefix = |txid, vout, len| spending_prefix(OutPoint { txid, vout }, len);
+        assert_eq!(
+            prefix(txid, 256, PrefixLen::Six).as_bytes(),
+            [31, 30, 29, 28, 28, 26]
+        );
+        assert_eq!(
+            prefix(txid, 256, PrefixLen::Ten).as_bytes(),
+            [31, 30, 29, 28, 27, 26, 25, 24, 24, 22]
+        );
+        // the carry beyond the prefix is dropped
+        let txid = Txid::from_byte_array([0xff; 32]);
+        assert_eq!(prefix(txid, 1, PrefixLen::Six).as_bytes(), [0; 6]);
     }
 }
@@ -0,0 +1,123 @@
+//! Compatibility corpus: canned requests, with the responses other Electrum servers (ElectrumX
+//! and Fulcrum) return for the same regtest scenario. See `tests/compat/README.md`.
+
+use anyhow::{bail, Context, Result};
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::ElectrumClient;
+
+/// A single request, with its expected response (`{"result": ...}` or `{"error": ...}`)
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Case {
+    pub name: String,
+    pub method: String,
+    #[serde(default = "no_params")]
+    pub params: Value,
+    pub response: Value,
+    /// Where the expected response was taken from (e.g. the protocol spec, or a server's version)
+    #[serde(default)]
+    pub reference: String,
+    /// JSON pointers (into the response) of the documented deviations, with their reasons
+    #[serde(default)]
+    pub deviations: BTreeMap<String, String>,
+}
+
+fn no_params() -> Value {
+    Value::Array(vec![])
+}
+
+/// Load the cases from all `*.json` files in `dir` (each containing an array of cases).
+pub fn load(dir: &Path) -> Result<Vec<Case>> {
+    let mut paths = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to list {}", dir.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+    paths.sort();
+    let mut cases = vec![];
+    for path in paths {
+        let data = std::fs::read_to_string(&path)?;
+        let parsed: Vec<Case> = serde_json::from_str(&data)
+            .with_context(|| format!("invalid corpus file {}", path.display()))?;
+        cases.extend(parsed);
+    }
+    Ok(cases)
+}
+
+/// Replace `"$name"` strings by the scenario's variables (which may be of any JSON type).
+fn substitute(value: &Value, vars: &BTreeMap<&str, Value>) -> Result<Value> {
+    Ok(match value {
+        Value::String(s) if s.starts_with('$') => vars
+            .get(&s[1..])
+            .cloned()
+            .with_context(|| format!("unknown variable {}", s))?,
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|v| substitute(v, vars))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| Ok((k.clone(), substitute(v, vars)?)))
+                .collect::<Result<_>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+/// Exclude a deviation from the comparison: object fields are removed (so that extra or missing
+/// fields are allowed), and array elements are replaced by `null` (so the indices don't change).
+fn exclude(value: &mut Value, pointer: &str) {
+    let (parent, token) = match pointer.rsplit_once('/') {
+        Some(split) => split,
+        None => return,
+    };
+    let token = token.replace("~1", "/").replace("~0", "~");
+    match value.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.remove(&token);
+        }
+        Some(Value::Array(values)) => {
+            if let Some(v) = token.parse().ok().and_then(|i: usize| values.get_mut(i)) {
+                *v = Value::Null;
+            }
+        }
+        _ => (),
+    }
+}
+
+impl Case {
+    /// Send the request, and compare the response with the expected one (field-for-field, so
+    /// integers and floats differ, as do `null` and missing fields - but not the fields' order).
+    pub fn check(&self, client: &mut ElectrumClient, vars: &BTreeMap<&str, Value>) -> Result<()> {
+        let params = substitute(&self.params, vars)?;
+        let mut expected = substitute(&self.response, vars)?;
+        let mut actual = client.request(&self.method, params.clone())?;
+        if let Value::Object(msg) = &mut actual {
+            msg.remove("jsonrpc");
+            msg.remove("id");
+        }
+        for pointer in self.deviations.keys() {
+            exclude(&mut expected, pointer);
+            exclude(&mut actual, pointer);
+        }
+        if actual != expected {
+            bail!(
+                "{}: {}({}) returned {}, expected {} (reference: {})",
+                self.name,
+                self.method,
+                params,
+                actual,
+                expected,
+                self.reference
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,414 @@
+//! Regtest harness for end-to-end tests: runs `bitcoind` and `electrs` in temporary directories
+//! (using free local ports), and talks to them via their RPC interfaces.
+//!
+//! `bitcoind` is located via `BITCOIND_EXE` environment variable (or `PATH`).
+
+#![allow(dead_code)] // each test binary uses a subset of the harness
+
+pub mod compat;
+
+use anyhow::{bail, Context, Result};
+use bitcoin::{Address, Amount, BlockHash, Txid};
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use serde_json::{json, Value};
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Retry `func` until it returns `Some` (or fail after `TIMEOUT`).
+pub fn wait_for<T>(what: &str, mut func: impl FnMut() -> Option<T>) -> Result<T> {
+    let start = Instant::now();
+    loop {
+        if let Some(result) = func() {
+            return Ok(result);
+        }
+        if start.elapsed() > TIMEOUT {
+            bail!("timeout waiting for {}", what);
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// Allocate a free local port (by binding to port 0).
+pub fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("failed to allocate a local port")
+}
+
+fn bitcoind_exe() -> PathBuf {
+    std::env::var_os("BITCOIND_EXE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("bitcoind"))
+}
+
+/// A regtest `bitcoind` process (killed on drop)
+pub struct Bitcoind {
+    process: Child,
+    rpc: Client,
+    dir: TempDir,
+    rpc_addr: SocketAddr,
+    p2p_addr: SocketAddr,
+}
+
+impl Bitcoind {
+    pub fn start() -> Result<Self> {
+        Self::start_with_args(&[])
+    }
+
+    /// Start bitcoind using additional arguments (e.g. `-prune=1`).
+    pub fn start_with_args(args: &[&str]) -> Result<Self> {
+        let dir = TempDir::new()?;
+        let (rpc_addr, p2p_addr) = (free_addr(), free_addr());
+        let exe = bitcoind_exe();
+        let process = Command::new(&exe)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", dir.path().display()))
+            .arg(format!("-rpcport={}", rpc_addr.port()))
+            .arg(format!("-port={}", p2p_addr.port()))
+            .arg("-bind=127.0.0.1")
+            .arg("-listen=1")
+            .arg("-fallbackfee=0.0001")
+            .arg("-printtoconsole=0")
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| format!("failed to run {:?} (set BITCOIND_EXE)", exe))?;
+
+        let cookie = dir.path().join("regtest").join(".cookie");
+        let url = format!("http://{}", rpc_addr);
+        wait_for("bitcoind cookie", || cookie.exists().then_some(()))?;
+        let rpc = Client::new(&url, Auth::CookieFile(cookie))?;
+        wait_for("bitcoind RPC", || rpc.get_block_count().ok())?;
+        rpc.create_wallet("test", None, None, None, None)?;
+        Ok(Self {
+            process,
+            rpc,
+            dir,
+            rpc_addr,
+            p2p_addr,
+        })
+    }
+
+    pub fn rpc(&self) -> &Client {
+        &self.rpc
+    }
+
+    pub fn rpc_addr(&self) -> SocketAddr {
+        self.rpc_addr
+    }
+
+    pub fn p2p_addr(&self) -> SocketAddr {
+        self.p2p_addr
+    }
+
+    pub fn datadir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn new_address(&self) -> Result<Address> {
+        Ok(self.rpc.get_new_address(None, None)?.assume_checked())
+    }
+
+    /// Mine `count` blocks (to a wallet address).
+    pub fn mine(&self, count: u64) -> Result<Vec<BlockHash>> {
+        let address = self.new_address()?;
+        Ok(self.rpc.generate_to_address(count, &address)?)
+    }
+
+    pub fn send(&self, address: &Address, amount: Amount) -> Result<Txid> {
+        Ok(self
+            .rpc
+            .send_to_address(address, amount, None, None, None, None, None, None)?)
+    }
+
+    pub fn height(&self) -> Result<usize> {
+        Ok(self.rpc.get_block_count()? as usize)
+    }
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        let _ = self.rpc.stop();
+        if wait_for("bitcoind exit", || self.process.try_wait().ok().flatten()).is_err() {
+            let _ = self.process.kill();
+        }
+    }
+}
+
+/// An `electrs` process, connected to a [`Bitcoind`] (killed on drop)
+pub struct Electrs {
+    process: Child,
+    dir: TempDir,
+    electrum_addr: SocketAddr,
+    monitoring_addr: SocketAddr,
+}
+
+impl Electrs {
+    pub fn start(bitcoind: &Bitcoind, extra_args: &[&str]) -> Result<Self> {
+        let electrs = Self::spawn(bitcoind, extra_args)?;
+        electrs.wait_synced(bitcoind)?;
+        Ok(electrs)
+    }
+
+    /// Start electrs without waiting for its initial sync (e.g. if it is expected to fail).
+    pub fn spawn(bitcoind: &Bitcoind, extra_args: &[&str]) -> Result<Self> {
+        let dir = TempDir::new()?;
+        let (electrum_addr, monitoring_addr) = (free_addr(), free_addr());
+        let process = Self::command(dir.path(), bitcoind)
+            .arg(format!("--electrum-rpc-addr={}", electrum_addr))
+            .arg(format!("--monitoring-addr={}", monitoring_addr))
+            .arg("--wait-duration-secs=1")
+            .args(extra_args)
+            .env("RUST_LOG", "electrs=debug")
+            .stderr(Stdio::from(std::fs::File::create(
+                dir.path().join("electrs.log"),
+            )?))
+            .spawn()
+            .context("failed to run electrs")?;
+        Ok(Self {
+            process,
+            dir,
+            electrum_addr,
+            monitoring_addr,
+        })
+    }
+
+    /// `electrs` invocation using the given DB directory and bitcoind
+    fn command(db_dir: &Path, bitcoind: &Bitcoind) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_electrs"));
+        command
+            .arg("--skip-default-conf-files")
+            .arg("--network=regtest")
+            .arg(format!("--db-dir={}", db_dir.display()))
+            .arg(format!("--daemon-dir={}", bitcoind.datadir().display()))
+            .arg(format!("--daemon-rpc-addr={}", bitcoind.rpc_addr))
+            .arg(format!("--daemon-p2p-addr={}", bitcoind.p2p_addr));
+        command
+    }
+
+    /// Run an offline command (e.g. `scripthash-dump`) using this instance's DB, returning its
+    /// standard output.
+    pub fn run_command(&self, bitcoind: &Bitcoind, args: &[&str]) -> Result<String> {
+        let output = Self::command(self.dir.path(), bitcoind)
+            .args(args)
+            .output()
+            .context("failed to run electrs command")?;
+        if !output.status.success() {
+            bail!(
+                "electrs {:?} failed: {}",
+                args,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    pub fn connect(&self) -> Result<ElectrumClient> {
+        ElectrumClient::connect(self.electrum_addr)
+    }
+
+    /// Wait until the index reaches bitcoind's tip.
+    pub fn wait_synced(&self, bitcoind: &Bitcoind) -> Result<()> {
+        let height = bitcoind.height()?;
+        wait_for("electrs sync", || {
+            let mut client = self.connect().ok()?;
+            let tip = client
+                .call("blockchain.headers.subscribe", json!([]))
+                .ok()?;
+            (tip["height"].as_u64()? as usize == height).then_some(())
+        })
+    }
+
+    /// The current value of a Prometheus metric (e.g. `electrs_daemon_cache_misses{kind="tx"}`),
+    /// which is `0` if it was not reported yet.
+    pub fn metric(&self, name: &str) -> Result<f64> {
+        let mut stream = TcpStream::connect(self.monitoring_addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        write!(
+            stream,
+            "GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            self.monitoring_addr
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let prefix = format!("{} ", name);
+        match response.lines().find_map(|line| line.strip_prefix(&prefix)) {
+            Some(value) => Ok(value.parse()?),
+            None => Ok(0.0),
+        }
+    }
+
+    /// Contents of electrs' log file (useful for debugging failing tests).
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.dir.path().join("electrs.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for Electrs {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        if std::thread::panicking() {
+            eprintln!("electrs log:\n{}", self.log());
+        }
+    }
+}
+
+/// A minimal Electrum protocol client (line-delimited JSON-RPC over TCP)
+pub struct ElectrumClient {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    next_id: u64,
+    notifications: VecDeque<Value>,
+}
+
+impl ElectrumClient {
+    pub fn connect(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self {
+            stream,
+            reader,
+            next_id: 0,
+            notifications: VecDeque::new(),
+        })
+    }
+
+    fn recv(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        let n = self.reader.read_line(&mut line)?;
+        if n == 0 {
+            bail!("disconnected");
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Send a request and wait for its response message (queueing the notifications received
+    /// meanwhile), which may contain either a result or an error.
+    pub fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let request = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        writeln!(self.stream, "{}", request)?;
+        loop {
+            let msg = self.recv()?;
+            if msg.get("method").is_some() {
+                self.notifications.push_back(msg);
+                continue;
+            }
+            if msg["id"] != json!(id) {
+                bail!("unexpected response: {}", msg);
+            }
+            return Ok(msg);
+        }
+    }
+
+    /// Send a request and wait for its result (failing if an error is returned).
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let msg = self.request(method, params)?;
+        if let Some(error) = msg.get("error") {
+            bail!("{} failed: {}", method, error);
+        }
+        Ok(msg["result"].clone())
+    }
+
+    /// Wait for the next notification of the given method, returning its params.
+    pub fn wait_notification(&mut self, method: &str) -> Result<Value> {
+        loop {
+            let msg = match self.notifications.pop_front() {
+                Some(msg) => msg,
+                None => self.recv().context("no notification")?,
+            };
+            if msg["method"] == method {
+                return Ok(msg["params"].clone());
+            }
+        }
+    }
+}
+
+/// Send a JSON-RPC request (or batch) via HTTP POST, returning the response's status code and body.
+pub fn http_post(addr: SocketAddr, request: &Value) -> Result<(u16, Value)> {
+    let body = request.to_string();
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        addr,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("invalid HTTP response")?;
+    let code = head
+        .split(' ')
+        .nth(1)
+        .context("missing HTTP status")?
+        .parse()?;
+    Ok((code, serde_json::from_str(body)?))
+}
+
+/// Electrum scripthash of an address (hex-encoded, in reversed byte order).
+pub fn scripthash(address: &Address) -> String {
+    use bitcoin::hashes::{sha256, Hash};
+    let mut hash = sha256::Hash::hash(address.script_pubkey().as_bytes()).to_byte_array();
+    hash.reverse();
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `bitcoind` and `electrs` running on a fresh regtest chain (with a funded wallet)
+pub struct TestEnv {
+    pub bitcoind: Bitcoind,
+    pub electrs: Electrs,
+}
+
+impl TestEnv {
+    pub fn new() -> Result<Self> {
+        Self::with_args(&[])
+    }
+
+    pub fn with_args(electrs_args: &[&str]) -> Result<Self> {
+        let bitcoind = Bitcoind::start()?;
+        bitcoind.mine(101)?; // so the first coinbase is spendable
+        let electrs = Electrs::start(&bitcoind, electrs_args)?;
+        Ok(Self { bitcoind, electrs })
+    }
+
+    /// Mine blocks and wait for electrs to index them.
+    pub fn mine(&self, count: u64) -> Result<Vec<BlockHash>> {
+        let blockhashes = self.bitcoind.mine(count)?;
+        self.electrs.wait_synced(&self.bitcoind)?;
+        Ok(blockhashes)
+    }
+
+    /// Wait until electrs' mempool contains `txid` (in the history of `address`).
+    pub fn wait_mempool(&self, address: &Address, txid: Txid) -> Result<()> {
+        let mut client = self.electrs.connect()?;
+        let scripthash = scripthash(address);
+        wait_for("mempool sync", || {
+            let history = client
+                .call("blockchain.scripthash.get_mempool", json!([scripthash]))
+                .ok()?;
+            history
+                .as_array()?
+                .iter()
+                .any(|entry| entry["tx_hash"] == json!(txid))
+                .then_some(())
+        })
+    }
+}
@@ -0,0 +1,158 @@
+//! Memory usage tests, using a counting global allocator (in its own test binary, so the other
+//! tests use the default allocator):
+//! ```
+//! cargo test --features bench --test memory
+//! ```
+
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    absolute::LockTime, block, consensus::serialize, transaction::Version, Amount, Block, OutPoint,
+    ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Witness,
+};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Write;
+
+/// Counts the bytes allocated by the current thread (to measure a computation's memory usage)
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<(isize, isize)> = const { Cell::new((0, 0)) }; // (current, peak)
+}
+
+fn count_allocated(delta: isize) {
+    let _ = ALLOCATED.try_with(|allocated| {
+        let (current, peak) = allocated.get();
+        let current = current + delta;
+        allocated.set((current, peak.max(current)));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocated(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_allocated(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // the old and the new buffers may exist at the same time
+        count_allocated(new_size as isize);
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        count_allocated(-(layout.size() as isize));
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the result of `func`, and the peak number of bytes it allocated
+fn peak_allocated<T>(func: impl FnOnce() -> T) -> (T, usize) {
+    let (before, _peak) = ALLOCATED.with(|allocated| allocated.get());
+    ALLOCATED.with(|allocated| allocated.set((before, before)));
+    let result = func();
+    let (_current, peak) = ALLOCATED.with(|allocated| allocated.get());
+    (result, (peak - before) as usize)
+}
+
+/// Returns the result of `func`, and the number of bytes it allocated and didn't free
+fn retained_allocated<T>(func: impl FnOnce() -> T) -> (T, usize) {
+    let (before, _peak) = ALLOCATED.with(|allocated| allocated.get());
+    let result = func();
+    let (after, _peak) = ALLOCATED.with(|allocated| allocated.get());
+    (result, (after - before).max(0) as usize)
+}
+
+/// Counts the written bytes (without keeping them)
+#[derive(Default)]
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_large_response() {
+    let history = electrs::bench_history(100_000);
+    let mut out = Counter::default();
+    let (result, peak) =
+        peak_allocated(|| electrs::bench_history_response(history.clone(), &mut out));
+    result.unwrap();
+    // the entries are serialized in chunks, while the response is sent
+    assert!(out.0 > 8_000_000, "{} bytes sent", out.0);
+    assert!(peak < 1 << 17, "{} bytes allocated", peak);
+}
+
+#[test]
+fn test_index_large_scripts() {
+    let scripts = [
+        vec![0x61; 10_001],    // above bitcoind's `MAX_SCRIPT_SIZE`
+        vec![0x61; 3_000_000], // a multi-megabyte script
+        [0x6a].into_iter().chain([1; 20_000]).collect(), // `OP_RETURN`
+    ];
+    let coinbase = Transaction {
+        version: Version::ONE,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: ScriptBuf::from_bytes(vec![1, 2]),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: scripts
+            .into_iter()
+            .map(|script| TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::from_bytes(script),
+            })
+            .collect(),
+    };
+    let block = Block {
+        header: block::Header {
+            version: block::Version::ONE,
+            prev_blockhash: Hash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: bitcoin::CompactTarget::from_consensus(0),
+            nonce: 0,
+        },
+        txdata: vec![coinbase],
+    };
+    let data = serialize(&block);
+    let (rows, peak) = peak_allocated(|| electrs::bench_index_block(data));
+    assert!(rows > 0);
+    // the scripts are hashed without being copied
+    assert!(peak < 1 << 16, "{} bytes allocated", peak);
+}
+
+/// Run using: `cargo test --release --features bench --test memory -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench_headers_memory() {
+    let n = 900_000; // ~mainnet
+    let headers = electrs::bench_headers(n);
+    // the evicted headers are stored in the DB (so they are not measured)
+    let (_chain, full_bytes) = retained_allocated(|| electrs::bench_sync_headers(&headers, None));
+    let (_chain, evicted_bytes) =
+        retained_allocated(|| electrs::bench_sync_headers(&headers, Some(10_000)));
+    println!(
+        "{} headers: {:.1} MB in memory, {:.1} MB with the last 10k in memory",
+        n,
+        full_bytes as f64 / 1e6,
+        evicted_bytes as f64 / 1e6
+    );
+    assert!(evicted_bytes * 10 < full_bytes);
+}
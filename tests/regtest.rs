@@ -0,0 +1,1165 @@
+//! End-to-end tests, running `electrs` against a regtest `bitcoind`.
+//!
+//! Ignored by default, run using: `BITCOIND_EXE=/path/to/bitcoind cargo test -- --ignored`
+
+mod common;
+
+use anyhow::{Context, Result};
+use bitcoin::{
+    block::Header,
+    consensus::{deserialize, encode::serialize_hex},
+    hashes::{sha256, sha256d, Hash, HashEngine},
+    hex::{DisplayHex, FromHex},
+    Amount, BlockHash, MerkleBlock, Transaction, Txid,
+};
+use bitcoincore_rpc::{json::AddressType, RpcApi};
+use electrs::{Config, Server, State};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(feature = "http")]
+use common::http_post;
+use common::{compat, free_addr, scripthash, wait_for, Bitcoind, Electrs, ElectrumClient, TestEnv};
+
+#[test]
+#[ignore]
+fn test_headers_subscribe() -> Result<()> {
+    let env = TestEnv::new()?;
+    let mut client = env.electrs.connect()?;
+    let tip = client.call("blockchain.headers.subscribe", json!([]))?;
+    assert_eq!(tip["height"], 101);
+
+    env.bitcoind.mine(1)?;
+    let params = client.wait_notification("blockchain.headers.subscribe")?;
+    assert_eq!(params[0]["height"], 102);
+
+    let header = client.call("blockchain.block.header", json!([102]))?;
+    assert_eq!(header, params[0]["hex"]);
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_headers_subscribe_hex() -> Result<()> {
+    let env = TestEnv::new()?;
+    let raw_header = |blockhash| -> Result<serde_json::Value> {
+        let hex: String = env
+            .bitcoind
+            .rpc()
+            .call("getblockheader", &[json!(blockhash), json!(false)])?;
+        Ok(json!(hex))
+    };
+    let mut client = env.electrs.connect()?;
+    let tip = client.call("blockchain.headers.subscribe", json!([]))?;
+    let blockhash = env.bitcoind.rpc().get_best_block_hash()?;
+    assert_eq!(tip, json!({"height": 101, "hex": raw_header(blockhash)?}));
+
+    let blockhash = env.bitcoind.mine(1)?[0];
+    let params = client.wait_notification("blockchain.headers.subscribe")?;
+    assert_eq!(
+        params,
+        json!([{"height": 102, "hex": raw_header(blockhash)?}])
+    );
+
+    let headers = client.call("blockchain.block.headers", json!([101, 2]))?;
+    assert_eq!(headers["count"], 2);
+    let tip = params[0]["hex"].as_str().unwrap();
+    assert!(headers["hex"].as_str().unwrap().ends_with(tip));
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_scripthash_funding_and_spending() -> Result<()> {
+    let env = TestEnv::new()?;
+    let address = env.bitcoind.new_address()?;
+    let scripthash = scripthash(&address);
+    let mut client = env.electrs.connect()?;
+    let status = client.call("blockchain.scripthash.subscribe", json!([scripthash]))?;
+    assert!(status.is_null()); // no history
+
+    // funding (in mempool)
+    let funding_txid = env.bitcoind.send(&address, Amount::from_sat(1_000_000))?;
+    let params = client.wait_notification("blockchain.scripthash.subscribe")?;
+    assert_eq!(params[0], json!(scripthash));
+    let history = client.call("blockchain.scripthash.get_history", json!([scripthash]))?;
+    assert_eq!(history[0]["tx_hash"], json!(funding_txid));
+    assert_eq!(history[0]["height"], 0);
+    let balance = client.call("blockchain.scripthash.get_balance", json!([scripthash]))?;
+    assert_eq!(balance, json!({"confirmed": 0, "unconfirmed": 1_000_000}));
+
+    // funding (confirmed)
+    let blockhash = env.mine(1)?[0];
+    client.wait_notification("blockchain.scripthash.subscribe")?;
+    let history = client.call("blockchain.scripthash.get_history", json!([scripthash]))?;
+    assert_eq!(history, json!([{"tx_hash": funding_txid, "height": 102}]));
+    let unspent = client.call("blockchain.scripthash.listunspent", json!([scripthash]))?;
+    assert_eq!(unspent.as_array().unwrap().len(), 1);
+    assert_eq!(unspent[0]["tx_hash"], json!(funding_txid));
+    assert_eq!(unspent[0]["value"], 1_000_000);
+    let block = env.bitcoind.rpc().get_block(&blockhash)?;
+    assert!(block
+        .txdata
+        .iter()
+        .any(|tx| tx.compute_txid() == funding_txid));
+
+    // spending (wallet sends all its coins, including the funded output)
+    let other = env.bitcoind.new_address()?;
+    let balance = env.bitcoind.rpc().get_balance(None, None)?;
+    let spending_txid = env.bitcoind.rpc().send_to_address(
+        &other,
+        balance,
+        None,
+        None,
+        Some(true), // subtract fee
+        None,
+        None,
+        None,
+    )?;
+    client.wait_notification("blockchain.scripthash.subscribe")?;
+    let mempool = client.call("blockchain.scripthash.get_mempool", json!([scripthash]))?;
+    assert_eq!(mempool[0]["tx_hash"], json!(spending_txid));
+    let balance = client.call("blockchain.scripthash.get_balance", json!([scripthash]))?;
+    assert_eq!(
+        balance,
+        json!({"confirmed": 1_000_000, "unconfirmed": -1_000_000})
+    );
+
+    env.mine(1)?;
+    client.wait_notification("blockchain.scripthash.subscribe")?;
+    let history = client.call("blockchain.scripthash.get_history", json!([scripthash]))?;
+    assert_eq!(
+        history,
+        json!([
+            {"tx_hash": funding_txid, "height": 102},
+            {"tx_hash": spending_txid, "height": 103},
+        ])
+    );
+    let unspent = client.call("blockchain.scripthash.listunspent", json!([scripthash]))?;
+    assert_eq!(unspent, json!([]));
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_coinbase_maturity() -> Result<()> {
+    let env = TestEnv::new()?;
+    let address = env.bitcoind.new_address()?;
+    let scripthash = scripthash(&address);
+    let blockhash = env.bitcoind.rpc().generate_to_address(1, &address)?[0];
+    env.electrs.wait_synced(&env.bitcoind)?;
+    let coinbase = env.bitcoind.rpc().get_block(&blockhash)?.txdata[0].compute_txid();
+
+    let mut client = env.electrs.connect()?;
+    let unspent = client.call("blockchain.scripthash.listunspent", json!([scripthash]))?;
+    let value = unspent[0]["value"].as_u64().unwrap();
+    assert_eq!(
+        unspent,
+        json!([{"height": 102, "tx_hash": coinbase, "tx_pos": 0, "value": value, "immature": true}])
+    );
+    let balance = client.call("blockchain.scripthash.get_balance", json!([scripthash]))?;
+    assert_eq!(
+        balance,
+        json!({"confirmed": 0, "unconfirmed": 0, "immature": value})
+    );
+
+    // still immature after 98 more blocks (bitcoind's wallet can't spend it either)
+    env.mine(98)?;
+    let unspent = client.call("blockchain.scripthash.listunspent", json!([scripthash]))?;
+    assert_eq!(unspent[0]["immature"], json!(true));
+    let wallet = env
+        .bitcoind
+        .rpc()
+        .list_unspent(None, None, Some(&[&address]), None, None)?;
+    assert!(wallet.is_empty());
+
+    // spendable (by the next block) after 99 more blocks
+    env.mine(1)?;
+    let unspent = client.call("blockchain.scripthash.listunspent", json!([scripthash]))?;
+    assert_eq!(
+        unspent,
+        json!([{"height": 102, "tx_hash": coinbase, "tx_pos": 0, "value": value}])
+    );
+    let balance = client.call("blockchain.scripthash.get_balance", json!([scripthash]))?;
+    assert_eq!(balance, json!({"confirmed": value, "unconfirmed": 0}));
+    let wallet = env
+        .bitcoind
+        .rpc()
+        .list_unspent(None, None, Some(&[&address]), None, None)?;
+    assert_eq!(wallet[0].amount.to_sat(), value);
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_scripthash_dump() -> Result<()> {
+    let env = TestEnv::new()?;
+    let address = env.bitcoind.new_address()?;
+    let scripthash = scripthash(&address);
+    let txid = env.bitcoind.send(&address, Amount::from_sat(1_000_000))?;
+    env.mine(1)?;
+    let mut client = env.electrs.connect()?;
+    let status = client.call("blockchain.scripthash.subscribe", json!([scripthash]))?;
+
+    // the DB is read while electrs is running
+    let output = env
+        .electrs
+        .run_command(&env.bitcoind, &["scripthash-dump", &scripthash, "--json"])?;
+    let dump: serde_json::Value = serde_json::from_str(&output)?;
+    assert_eq!(dump["scripthash"], json!(scripthash));
+    assert_eq!(dump["tip"]["height"], 102);
+    let funding = dump["funding"].as_array().unwrap();
+    assert_eq!(funding.len(), 1);
+    assert_eq!(funding[0]["height"], 102);
+    assert_eq!(funding[0]["verified"], true);
+    assert!(funding[0]["row"]
+        .as_str()
+        .unwrap()
+        .starts_with(funding[0]["prefix"].as_str().unwrap()));
+    assert_eq!(dump["spending"], json!([]));
+    assert_eq!(dump["txid"][0]["key"], json!(txid));
+    assert_eq!(dump["verified"]["history"][0]["tx_hash"], json!(txid));
+    assert_eq!(dump["verified"]["history"][0]["height"], 102);
+    assert_eq!(
+        dump["verified"]["balance"],
+        json!({"confirmed": 1_000_000, "unconfirmed": 0})
+    );
+    assert_eq!(dump["verified"]["status"], status);
+
+    let output = env
+        .electrs
+        .run_command(&env.bitcoind, &["scripthash-dump", &scripthash])?;
+    assert!(output.contains("funding rows: 1\n"), "{}", output);
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_broadcast() -> Result<()> {
+    let env = TestEnv::new()?;
+    let rpc = env.bitcoind.rpc();
+    let address = env.bitcoind.new_address()?;
+    let raw: String = rpc.call(
+        "createrawtransaction",
+        &[json!([]), json!({address.to_string(): 1.0})],
+    )?;
+    let funded = rpc.fund_raw_transaction(raw, None, None)?;
+    let signed = rpc.sign_raw_transaction_with_wallet(&funded.hex, None, None)?;
+    assert!(signed.complete);
+    let tx_hex = signed.hex.to_lower_hex_string();
+
+    let mut client = env.electrs.connect()?;
+    let txid = client.call("blockchain.transaction.broadcast", json!([tx_hex]))?;
+    let txid: Txid = serde_json::from_value(txid)?;
+    assert!(rpc.get_raw_mempool()?.contains(&txid));
+    env.wait_mempool(&address, txid)?;
+
+    let tx = client.call("blockchain.transaction.get", json!([txid]))?;
+    assert_eq!(tx, json!(tx_hex));
+
+    // invalid transactions are rejected
+    assert!(client
+        .call("blockchain.transaction.broadcast", json!(["00"]))
+        .is_err());
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_merkle_proof() -> Result<()> {
+    let env = TestEnv::with_args(&["--merkle-cache"])?;
+    let address = env.bitcoind.new_address()?;
+    let txid = env.bitcoind.send(&address, Amount::from_sat(50_000))?;
+    let blockhash = env.mine(1)?[0];
+
+    let mut client = env.electrs.connect()?;
+    let proof = client.call("blockchain.transaction.get_merkle", json!([txid, 102]))?;
+    assert_eq!(proof["block_height"], 102);
+
+    // compute the merkle root from the proof, and compare it with bitcoind's
+    let mut pos = proof["pos"].as_u64().unwrap();
+    let mut hash = txid.to_raw_hash();
+    for node in proof["merkle"].as_array().unwrap() {
+        let node: Txid = serde_json::from_value(node.clone())?;
+        let mut engine = sha256d::Hash::engine();
+        let (left, right) = match pos % 2 {
+            0 => (hash, node.to_raw_hash()),
+            _ => (node.to_raw_hash(), hash),
+        };
+        engine.input(left.as_byte_array());
+        engine.input(right.as_byte_array());
+        hash = sha256d::Hash::from_engine(engine);
+        pos /= 2;
+    }
+    let merkle_block: MerkleBlock = deserialize(
+        &env.bitcoind
+            .rpc()
+            .get_tx_out_proof(&[txid], Some(&blockhash))?,
+    )?;
+    assert_eq!(
+        hash.to_byte_array(),
+        merkle_block.header.merkle_root.to_byte_array()
+    );
+
+    let result = client.call("blockchain.transaction.id_from_pos", json!([102, 0]))?;
+    let block = env.bitcoind.rpc().get_block(&blockhash)?;
+    assert_eq!(result, json!(block.txdata[0].compute_txid()));
+    Ok(())
+}
+
+/// The p99 latency of the first requests for a new block's transactions (their merkle proofs
+/// and raw transactions), using the given `prefetch_blocks`, and the daemon cache misses they
+/// caused (i.e. the block's data fetched from bitcoind).
+fn post_block_latency(prefetch_blocks: &str) -> Result<(Duration, f64)> {
+    let env = TestEnv::with_args(&["--prefetch-blocks", prefetch_blocks])?;
+    let address = env.bitcoind.new_address()?;
+    let txids = (0..50)
+        .map(|_| env.bitcoind.send(&address, Amount::from_sat(10_000)))
+        .collect::<Result<Vec<Txid>>>()?;
+    let height = env.bitcoind.height()? + 1;
+    env.mine(1)?;
+
+    let misses = || -> Result<f64> {
+        let metric = |kind| {
+            env.electrs
+                .metric(&format!("electrs_daemon_cache_misses{{kind=\"{}\"}}", kind))
+        };
+        Ok(metric("block_txids")? + metric("tx")?)
+    };
+    let misses_before = misses()?;
+    let mut client = env.electrs.connect()?;
+    let mut durations = vec![];
+    for txid in txids {
+        for (method, params) in [
+            ("blockchain.transaction.get_merkle", json!([txid, height])),
+            ("blockchain.transaction.get", json!([txid])),
+        ] {
+            let start = std::time::Instant::now();
+            client.call(method, params)?;
+            durations.push(start.elapsed());
+        }
+    }
+    durations.sort_unstable();
+    let p99 = durations[durations.len() * 99 / 100];
+    Ok((p99, misses()? - misses_before))
+}
+
+#[test]
+#[ignore]
+fn test_prefetch_blocks() -> Result<()> {
+    let (cold, cold_misses) = post_block_latency("0")?;
+    let (warm, warm_misses) = post_block_latency("1")?;
+    eprintln!(
+        "post-block p99 latency: {:?} without prefetching, {:?} with prefetch_blocks=1",
+        cold, warm
+    );
+    // the prefetched block is served from the cache, without fetching it from bitcoind
+    assert!(cold_misses > 0.0, "{} misses", cold_misses);
+    assert_eq!(warm_misses, 0.0);
+    // allow some slack, since the timings depend on the machine's load
+    assert!(warm <= cold * 3 / 2, "{:?} > {:?}", warm, cold);
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_reorg() -> Result<()> {
+    let env = TestEnv::new()?;
+    let address = env.bitcoind.new_address()?;
+    let scripthash = scripthash(&address);
+    let txid = env.bitcoind.send(&address, Amount::from_sat(100_000))?;
+    let stale = env.mine(1)?[0];
+
+    let mut client = env.electrs.connect()?;
+    client.call("blockchain.headers.subscribe", json!([]))?;
+    let history = client.call("blockchain.scripthash.subscribe", json!([scripthash]))?;
+    assert!(history.is_string());
+
+    // replace the block (its transaction returns to the mempool)
+    env.bitcoind.rpc().invalidate_block(&stale)?;
+    let new_tip = env.bitcoind.mine(2)?[1];
+    assert!(!env.bitcoind.rpc().get_raw_mempool()?.contains(&txid)); // re-mined
+    env.electrs.wait_synced(&env.bitcoind)?;
+
+    // electrs may have seen the intermediate tip (before the new blocks were mined)
+    while client.wait_notification("blockchain.headers.subscribe")?[0]["height"] != 103 {}
+    let get_header = |client: &mut common::ElectrumClient, height: usize| -> Result<Header> {
+        let hex = client.call("blockchain.block.header", json!([height]))?;
+        Ok(deserialize(&Vec::from_hex(hex.as_str().unwrap())?)?)
+    };
+    assert_eq!(get_header(&mut client, 103)?.block_hash(), new_tip);
+    assert_ne!(get_header(&mut client, 102)?.block_hash(), stale);
+
+    // the transaction is confirmed by the new block at the same height
+    let history = client.call("blockchain.scripthash.get_history", json!([scripthash]))?;
+    assert_eq!(history, json!([{"tx_hash": txid, "height": 102}]));
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_reorg_notification() -> Result<()> {
+    let env = TestEnv::new()?;
+    let address = env.bitcoind.new_address()?;
+    let scripthash = scripthash(&address);
+    let txid = env.bitcoind.send(&address, Amount::from_sat(100_000))?;
+    let confirming = env.mine(1)?[0];
+
+    let mut client = env.electrs.connect()?;
+    let confirmed = client.call("blockchain.scripthash.subscribe", json!([scripthash]))?;
+    let history = client.call("blockchain.scripthash.get_history", json!([scripthash]))?;
+    assert_eq!(history, json!([{"tx_hash": txid, "height": 102}]));
+
+    // orphan the confirming block (without mining a replacement yet)
+    env.bitcoind.rpc().invalidate_block(&confirming)?;
+    assert!(env.bitcoind.rpc().get_raw_mempool()?.contains(&txid));
+    let params = client.wait_notification("blockchain.scripthash.subscribe")?;
+    assert_eq!(params[0], json!(scripthash));
+    assert_ne!(params[1], confirmed);
+    env.wait_mempool(&address, txid)?;
+    let history = client.call("blockchain.scripthash.get_history", json!([scripthash]))?;
+    assert_eq!(history[0]["tx_hash"], json!(txid));
+    assert_eq!(history[0]["height"], 0);
+
+    // the transaction is confirmed again, by a new block
+    env.bitcoind.mine(2)?;
+    env.electrs.wait_synced(&env.bitcoind)?;
+    let history = loop {
+        client.wait_notification("blockchain.scripthash.subscribe")?;
+        let history = client.call("blockchain.scripthash.get_history", json!([scripthash]))?;
+        if history[0]["height"] != 0 {
+            break history;
+        }
+    };
+    assert_eq!(history, json!([{"tx_hash": txid, "height": 102}]));
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_index_script_types() -> Result<()> {
+    let env = TestEnv::with_args(&["--index-script-types=p2wpkh,p2tr"])?;
+    let segwit = env.bitcoind.new_address()?; // bech32 (by default)
+    let legacy = env
+        .bitcoind
+        .rpc()
+        .get_new_address(None, Some(AddressType::Legacy))?
+        .assume_checked();
+    let (segwit_scripthash, legacy_scripthash) = (scripthash(&segwit), scripthash(&legacy));
+
+    // a single transaction funding both script types
+    let amounts = json!({segwit.to_string(): 0.01, legacy.to_string(): 0.02});
+    let txid: Txid = env.bitcoind.rpc().call("sendmany", &[json!(""), amounts])?;
+    env.wait_mempool(&segwit, txid)?;
+    let mut client = env.electrs.connect()?;
+    let mempool = client.call(
+        "blockchain.scripthash.get_mempool",
+        json!([legacy_scripthash]),
+    )?;
+    assert_eq!(mempool, json!([]));
+
+    env.mine(1)?;
+    let history = client.call(
+        "blockchain.scripthash.get_history",
+        json!([segwit_scripthash]),
+    )?;
+    assert_eq!(history, json!([{"tx_hash": txid, "height": 102}]));
+    let history = client.call(
+        "blockchain.scripthash.get_history",
+        json!([legacy_scripthash]),
+    )?;
+    assert_eq!(history, json!([]));
+
+    // spending all the wallet's coins (from both script types)
+    let other = env.bitcoind.new_address()?;
+    let balance = env.bitcoind.rpc().get_balance(None, None)?;
+    let spending_txid = env.bitcoind.rpc().send_to_address(
+        &other,
+        balance,
+        None,
+        None,
+        Some(true), // subtract fee
+        None,
+        None,
+        None,
+    )?;
+    env.mine(1)?;
+    let history = client.call(
+        "blockchain.scripthash.get_history",
+        json!([segwit_scripthash]),
+    )?;
+    assert_eq!(
+        history,
+        json!([
+            {"tx_hash": txid, "height": 102},
+            {"tx_hash": spending_txid, "height": 103},
+        ])
+    );
+    let balance = client.call(
+        "blockchain.scripthash.get_balance",
+        json!([segwit_scripthash]),
+    )?;
+    assert_eq!(balance, json!({"confirmed": 0, "unconfirmed": 0}));
+    let history = client.call(
+        "blockchain.scripthash.get_history",
+        json!([legacy_scripthash]),
+    )?;
+    assert_eq!(history, json!([]));
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_embedded_server() -> Result<()> {
+    let bitcoind = Bitcoind::start()?;
+    bitcoind.mine(101)?;
+    let db_dir = tempfile::tempdir()?;
+    let electrum_addr = free_addr();
+    let config = || {
+        Config::builder()
+            .network(bitcoin::Network::Regtest)
+            .db_dir(db_dir.path())
+            .daemon_dir(bitcoind.datadir())
+            .daemon_rpc_addr(bitcoind.rpc_addr())
+            .daemon_p2p_addr(bitcoind.p2p_addr())
+            .electrum_rpc_addr(electrum_addr)
+            .monitoring_addr(free_addr())
+            .option("wait_duration_secs", "1")
+            .build()
+    };
+    let address = bitcoind.new_address()?;
+    let scripthash = electrs::ScriptHash::new(&address.script_pubkey());
+    let txid = bitcoind.send(&address, Amount::from_sat(1_000_000))?;
+    bitcoind.mine(1)?;
+
+    // the same DB and listening address are reused after the server is stopped
+    for _ in 0..2 {
+        let handle = Server::start(config()?)?;
+        assert!(handle
+            .state()
+            .wait_for(State::Synced, Duration::from_secs(60)));
+        let history = wait_for("embedded sync", || {
+            let history = handle.get_history(scripthash).ok()?;
+            (!history.is_empty()).then_some(history)
+        })?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].txid(), txid);
+        assert_eq!(history[0].height(), Some(102));
+        let balance = handle.get_balance(scripthash)?;
+        assert_eq!(balance.confirmed(), Amount::from_sat(1_000_000));
+
+        let mut client = ElectrumClient::connect(electrum_addr)?;
+        assert_eq!(client.call("server.ping", json!([]))?, json!(null));
+        handle.shutdown()?;
+    }
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_scripthash_subscribe_bulk() -> Result<()> {
+    const COUNT: usize = 500;
+    let env = TestEnv::with_args(&["--max-subscriptions=450"])?;
+    let addresses = (0..COUNT)
+        .map(|_| env.bitcoind.new_address())
+        .collect::<Result<Vec<_>>>()?;
+    let scripthashes: Vec<String> = addresses.iter().map(scripthash).collect();
+    let funding_txid = env
+        .bitcoind
+        .send(&addresses[1], Amount::from_sat(1_000_000))?;
+    env.wait_mempool(&addresses[1], funding_txid)?;
+
+    let mut client = env.electrs.connect()?;
+    let features = client.call("server.features", json!([]))?;
+    assert!(features["extensions"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("subscribe_bulk")));
+
+    let first = client.call("blockchain.scripthash.subscribe", json!([scripthashes[1]]))?;
+    assert!(first.is_string());
+    let statuses = client.call(
+        "blockchain.scripthash.subscribe_bulk",
+        json!([scripthashes]),
+    )?;
+    let statuses = statuses.as_array().unwrap();
+    assert_eq!(statuses.len(), COUNT);
+    assert!(statuses[0].is_null()); // no history
+    assert_eq!(statuses[1], first); // already subscribed
+    assert!(statuses[2..450].iter().all(|status| status.is_null()));
+    // the limit is reached after the first 450 scripthashes
+    for status in &statuses[450..] {
+        assert_eq!(status["code"], 1);
+        assert!(status["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("subscription limit exceeded"));
+    }
+
+    // subscribed scripthashes are notified as usual
+    let txid = env
+        .bitcoind
+        .send(&addresses[100], Amount::from_sat(1_000_000))?;
+    // the wallet may also spend the output funding `addresses[1]`
+    while client.wait_notification("blockchain.scripthash.subscribe")?[0]
+        != json!(scripthashes[100])
+    {}
+    let history = client.call(
+        "blockchain.scripthash.get_history",
+        json!([scripthashes[100]]),
+    )?;
+    assert_eq!(history, json!([{"tx_hash": txid, "height": 0}]));
+
+    // unsubscribing frees a slot
+    let removed = client.call(
+        "blockchain.scripthash.unsubscribe",
+        json!([scripthashes[0]]),
+    )?;
+    assert_eq!(removed, json!(true));
+    let statuses = client.call(
+        "blockchain.scripthash.subscribe_bulk",
+        json!([[scripthashes[450], scripthashes[451]]]),
+    )?;
+    assert!(statuses[0].is_null());
+    assert_eq!(statuses[1]["code"], 1);
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_scripthash_allowlist() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let bitcoind = Bitcoind::start()?;
+    let (allowed, denied) = (bitcoind.new_address()?, bitcoind.new_address()?);
+    let allowlist = dir.path().join("allowlist.txt");
+    // both an address and a hex scripthash may be listed
+    let other = bitcoind.new_address()?;
+    std::fs::write(
+        &allowlist,
+        format!("# my wallet\n{}\n{}\n", allowed, scripthash(&other)),
+    )?;
+    let arg = format!("--scripthash-allowlist-file={}", allowlist.display());
+    let electrs = Electrs::start(&bitcoind, &[&arg])?;
+    let mut client = electrs.connect()?;
+    let not_served = |result: Value| {
+        assert_eq!(
+            result["error"],
+            json!({"code": -32002, "message": "scripthash not served"})
+        )
+    };
+
+    for address in [&allowed, &other] {
+        let scripthash = json!([scripthash(address)]);
+        for method in [
+            "blockchain.scripthash.get_history",
+            "blockchain.scripthash.listunspent",
+            "blockchain.scripthash.subscribe",
+        ] {
+            client.call(method, scripthash.clone())?;
+        }
+    }
+    let denied = json!([scripthash(&denied)]);
+    for method in [
+        "blockchain.scripthash.get_history",
+        "blockchain.scripthash.listunspent",
+        "blockchain.scripthash.subscribe",
+    ] {
+        not_served(client.request(method, denied.clone())?);
+    }
+    // the bulk subscription refuses only the scripthashes outside the list
+    let statuses = client.call(
+        "blockchain.scripthash.subscribe_bulk",
+        json!([[scripthash(&allowed), denied[0]]]),
+    )?;
+    assert!(statuses[0].is_null());
+    assert_eq!(statuses[1]["code"], -32002);
+
+    // the refusals are counted per method
+    for (method, count) in [
+        ("get_history", 1.0),
+        ("listunspent", 1.0),
+        ("subscribe", 2.0),
+    ] {
+        let name = format!("electrs_rpc_not_served{{method=\"{}\"}}", method);
+        assert_eq!(electrs.metric(&name)?, count, "{}", name);
+    }
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_protocol_version() -> Result<()> {
+    let env = TestEnv::new()?;
+    let scripthash = scripthash(&env.bitcoind.new_address()?);
+    let features = env.electrs.connect()?.call("server.features", json!([]))?;
+    assert_eq!(features["protocol_min"], "1.4");
+    assert_eq!(features["protocol_max"], "1.4.2");
+
+    let unsubscribe = |client: &mut ElectrumClient| {
+        client.call("blockchain.scripthash.unsubscribe", json!([scripthash]))
+    };
+    let is_not_found = |result: Result<serde_json::Value>| {
+        let err = format!("{:#}", result.unwrap_err());
+        err.contains("method not found")
+    };
+
+    // requests sent before `server.version` may use any method
+    let mut client = env.electrs.connect()?;
+    client.call("blockchain.scripthash.subscribe", json!([scripthash]))?;
+    assert_eq!(unsubscribe(&mut client)?, json!(true));
+
+    let mut client = env.electrs.connect()?;
+    let version = client.call("server.version", json!(["test", "1.4"]))?;
+    assert_eq!(version[1], "1.4");
+    assert!(is_not_found(unsubscribe(&mut client)));
+
+    // the highest common version is negotiated
+    let mut client = env.electrs.connect()?;
+    let version = client.call("server.version", json!(["test", ["1.4", "1.6"]]))?;
+    assert_eq!(version[1], "1.4.2");
+    client.call("blockchain.scripthash.subscribe", json!([scripthash]))?;
+    assert_eq!(unsubscribe(&mut client)?, json!(true));
+
+    let mut client = env.electrs.connect()?;
+    assert!(client
+        .call("server.version", json!(["test", ["1.5", "1.6"]]))
+        .is_err());
+    Ok(())
+}
+
+#[test]
+#[ignore]
+#[cfg(feature = "http")]
+fn test_http_bridge() -> Result<()> {
+    let http_addr = free_addr();
+    let env = TestEnv::with_args(&[&format!("--http-addr={}", http_addr)])?;
+    let address = env.bitcoind.new_address()?;
+    let scripthash = scripthash(&address);
+    env.bitcoind.send(&address, Amount::from_sat(1_000_000))?;
+    env.mine(1)?;
+
+    let request = |id, method, params| json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+    let get_balance = request(1, "blockchain.scripthash.get_balance", json!([scripthash]));
+    let (code, response) = http_post(http_addr, &get_balance)?;
+    assert_eq!(code, 200);
+    assert_eq!(response["id"], 1);
+    assert_eq!(
+        response["result"],
+        json!({"confirmed": 1_000_000, "unconfirmed": 0})
+    );
+
+    // subscriptions are rejected (their notifications can't be sent), also in a batch
+    let subscribe = request(2, "blockchain.scripthash.subscribe", json!([scripthash]));
+    let (code, response) = http_post(http_addr, &json!([get_balance, subscribe]))?;
+    assert_eq!(code, 200);
+    assert_eq!(response[0]["result"]["confirmed"], 1_000_000);
+    assert_eq!(response[1]["id"], 2);
+    let msg = response[1]["error"]["message"].as_str().unwrap();
+    assert!(msg.contains("requires a persistent connection"));
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_block_get() -> Result<()> {
+    let env = TestEnv::with_args(&["--block-get-allow=127.0.0.0/8"])?;
+    let blockhash = env.mine(1)?[0];
+    let height = env.bitcoind.height()?;
+    let expected = env.bitcoind.rpc().get_block_hex(&blockhash)?;
+
+    let mut client = env.electrs.connect()?;
+    for block in [json!(height), json!(blockhash)] {
+        let result = client.call("blockchain.block.get", json!([block, true]))?;
+        assert_eq!(result["hash"], json!(blockhash));
+        assert_eq!(result["height"], height);
+        assert_eq!(result["chunks"], 1);
+        assert_eq!(result["hex"], expected);
+    }
+    let err = client
+        .call("blockchain.block.get", json!([height, true, 1]))
+        .unwrap_err();
+    assert!(err.to_string().contains("chunk 1 must be < 1"));
+
+    // disabled by default
+    let env = TestEnv::new()?;
+    let mut client = env.electrs.connect()?;
+    let err = client
+        .call("blockchain.block.get", json!([0, true]))
+        .unwrap_err();
+    assert!(err.to_string().contains("not allowed"));
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_status_debug() -> Result<()> {
+    let env = TestEnv::with_args(&["--status-debug-allow=127.0.0.0/8"])?;
+    let address = env.bitcoind.new_address()?;
+    let scripthash = scripthash(&address);
+    let confirmed = env.bitcoind.send(&address, Amount::from_sat(1_000_000))?;
+    env.mine(1)?;
+    let unconfirmed = env.bitcoind.send(&address, Amount::from_sat(50_000))?;
+    env.wait_mempool(&address, unconfirmed)?;
+
+    let mut client = env.electrs.connect()?;
+    let features = client.call("server.features", json!([]))?;
+    assert!(features["extensions"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("status_debug")));
+    let expected = format!("{}:102:{}:0:", confirmed, unconfirmed);
+    // the status is the preimage's hash (hex-encoded like the protocol's status)
+    let status = sha256::Hash::hash(expected.as_bytes())
+        .to_byte_array()
+        .to_lower_hex_string();
+
+    let result = client.call(
+        "blockchain.scripthash.get_history",
+        json!([scripthash, false, true]),
+    )?;
+    assert_eq!(result["preimage"], expected);
+    assert_eq!(result["status"], status);
+    assert_eq!(result["history"].as_array().unwrap().len(), 2);
+    let result = client.call("blockchain.scripthash.subscribe", json!([scripthash, true]))?;
+    assert_eq!(result, json!({"status": status, "preimage": expected}));
+    // without the parameter, the responses are unchanged
+    let result = client.call("blockchain.scripthash.subscribe", json!([scripthash]))?;
+    assert_eq!(result, status);
+
+    // disabled by default
+    let env = TestEnv::new()?;
+    let mut client = env.electrs.connect()?;
+    let features = client.call("server.features", json!([]))?;
+    assert!(!features["extensions"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("status_debug")));
+    for (method, params) in [
+        (
+            "blockchain.scripthash.get_history",
+            json!([scripthash, false, true]),
+        ),
+        ("blockchain.scripthash.subscribe", json!([scripthash, true])),
+    ] {
+        let err = client.call(method, params).unwrap_err();
+        assert!(err.to_string().contains("not allowed"));
+    }
+    let result = client.call(
+        "blockchain.scripthash.get_history",
+        json!([scripthash, false, false]),
+    )?;
+    assert!(result.is_array());
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_track_broadcasts() -> Result<()> {
+    let env = TestEnv::new()?;
+    let rpc = env.bitcoind.rpc();
+    let address = env.bitcoind.new_address()?;
+    let signed_tx = || -> Result<String> {
+        let raw: String = rpc.call(
+            "createrawtransaction",
+            &[
+                json!([]),
+                json!({address.to_string(): 1.0}),
+                json!(0),
+                json!(true),
+            ],
+        )?;
+        let funded = rpc.fund_raw_transaction(raw, None, None)?;
+        let signed = rpc.sign_raw_transaction_with_wallet(&funded.hex, None, None)?;
+        assert!(signed.complete);
+        Ok(signed.hex.to_lower_hex_string())
+    };
+
+    let mut client = env.electrs.connect()?;
+    assert_eq!(
+        client.call("blockchain.transaction.track_broadcasts", json!([]))?,
+        json!(true)
+    );
+    client.call(
+        "blockchain.scripthash.subscribe",
+        json!([scripthash(&address)]),
+    )?;
+
+    // confirmed
+    let txid = client.call("blockchain.transaction.broadcast", json!([signed_tx()?]))?;
+    client.wait_notification("blockchain.scripthash.subscribe")?;
+    env.mine(1)?;
+    let params = client.wait_notification("blockchain.transaction.status_changed")?;
+    let height = env.bitcoind.height()?;
+    assert_eq!(
+        params,
+        json!([{"txid": txid, "status": "confirmed", "height": height}])
+    );
+
+    // replaced (using RBF)
+    let txid = client.call("blockchain.transaction.broadcast", json!([signed_tx()?]))?;
+    env.wait_mempool(&address, serde_json::from_value(txid.clone())?)?;
+    let bumped: serde_json::Value = rpc.call("bumpfee", std::slice::from_ref(&txid))?;
+    let params = client.wait_notification("blockchain.transaction.status_changed")?;
+    assert_eq!(params, json!([{"txid": txid, "status": "evicted"}]));
+    env.wait_mempool(&address, serde_json::from_value(bumped["txid"].clone())?)?;
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_outpoint_subscribe() -> Result<()> {
+    let env = TestEnv::new()?;
+    let rpc = env.bitcoind.rpc();
+    let address = env.bitcoind.new_address()?;
+    let other = env.bitcoind.new_address()?;
+    let mut outpoints = vec![];
+    for _ in 0..2 {
+        let txid = env.bitcoind.send(&address, Amount::from_sat(1_000_000))?;
+        let tx = rpc.get_transaction(&txid, None)?.transaction()?;
+        let vout = tx
+            .output
+            .iter()
+            .position(|txo| txo.script_pubkey == address.script_pubkey())
+            .unwrap();
+        outpoints.push(json!([txid, vout]));
+    }
+    env.mine(1)?;
+    // a transaction spending `outpoint` (signed by the wallet, without broadcasting it)
+    let spend = |outpoint: &serde_json::Value, amount: f64| -> Result<String> {
+        let inputs = json!([{"txid": outpoint[0], "vout": outpoint[1]}]);
+        let outputs = json!({other.to_string(): amount});
+        let raw: String = rpc.call("createrawtransaction", &[inputs, outputs])?;
+        let signed = rpc.sign_raw_transaction_with_wallet(raw, None, None)?;
+        assert!(signed.complete);
+        Ok(signed.hex.to_lower_hex_string())
+    };
+    let params = |outpoint: &serde_json::Value, spender| json!([outpoint[0], outpoint[1], spender]);
+
+    let mut client = env.electrs.connect()?;
+    for outpoint in &outpoints {
+        let spender = client.call("blockchain.outpoint.subscribe", outpoint.clone())?;
+        assert!(spender.is_null());
+    }
+
+    // spent by a mempool transaction, which is then confirmed
+    let spending_txid = client.call(
+        "blockchain.transaction.broadcast",
+        json!([spend(&outpoints[0], 0.0099)?]),
+    )?;
+    let unconfirmed = json!({"spender_txid": spending_txid, "height": 0});
+    assert_eq!(
+        client.wait_notification("blockchain.outpoint.subscribe")?,
+        params(&outpoints[0], unconfirmed)
+    );
+    env.mine(1)?;
+    let confirmed = json!({"spender_txid": spending_txid, "height": 103});
+    assert_eq!(
+        client.wait_notification("blockchain.outpoint.subscribe")?,
+        params(&outpoints[0], confirmed.clone())
+    );
+    assert_eq!(
+        client.call("blockchain.outpoint.get_spender", outpoints[0].clone())?,
+        confirmed
+    );
+
+    // spent by a non-standard transaction (having a dust output), so it isn't returned to the
+    // mempool when its block is orphaned
+    let dust_tx = spend(&outpoints[1], 0.000001)?;
+    let block: serde_json::Value = rpc.call(
+        "generateblock",
+        &[json!(address.to_string()), json!([dust_tx])],
+    )?;
+    env.electrs.wait_synced(&env.bitcoind)?;
+    let notified = client.wait_notification("blockchain.outpoint.subscribe")?;
+    assert_eq!(notified[1], outpoints[1][1]);
+    assert_eq!(notified[2]["height"], 104);
+    let blockhash: BlockHash = serde_json::from_value(block["hash"].clone())?;
+    rpc.invalidate_block(&blockhash)?;
+    assert_eq!(
+        client.wait_notification("blockchain.outpoint.subscribe")?,
+        params(&outpoints[1], serde_json::Value::Null)
+    );
+    assert!(client
+        .call("blockchain.outpoint.unsubscribe", outpoints[1].clone())?
+        .as_bool()
+        .unwrap());
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_daemon_blocks_dir() -> Result<()> {
+    let bitcoind = Bitcoind::start()?;
+    bitcoind.mine(101)?;
+    let address = bitcoind.new_address()?;
+    let txid = bitcoind.send(&address, Amount::from_sat(1_000_000))?;
+    bitcoind.mine(200)?; // the funding block is read from the block files
+    let blocks_dir = bitcoind.datadir().join("regtest").join("blocks");
+    let arg = format!("--daemon-blocks-dir={}", blocks_dir.display());
+    let electrs = Electrs::start(&bitcoind, &[&arg])?;
+
+    let log = wait_for("initial sync", || {
+        let log = electrs.log();
+        log.contains("blocks were read from block files")
+            .then_some(log)
+    })?;
+    assert!(
+        !log.contains(" 0 blocks were read from block files"),
+        "{}",
+        log
+    );
+    let mut client = electrs.connect()?;
+    let history = client.call(
+        "blockchain.scripthash.get_history",
+        json!([scripthash(&address)]),
+    )?;
+    assert_eq!(history, json!([{"tx_hash": txid, "height": 102}]));
+
+    // the following blocks are fetched from bitcoind
+    let blockhash = bitcoind.mine(1)?[0];
+    electrs.wait_synced(&bitcoind)?;
+    let tip = client.call("blockchain.headers.subscribe", json!([]))?;
+    assert_eq!(tip["height"], 302);
+    let header: Header = deserialize(&Vec::<u8>::from_hex(tip["hex"].as_str().unwrap())?)?;
+    assert_eq!(header.block_hash(), blockhash);
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_pruned_bitcoind() -> Result<()> {
+    // small block files (so they can be pruned), and no disconnection of electrs' p2p connection
+    // when it requests pruned blocks
+    let args = ["-prune=1", "-fastprune", "-whitelist=noban@127.0.0.1"];
+    let bitcoind = Bitcoind::start_with_args(&args)?;
+    bitcoind.mine(1000)?;
+    let pruned = bitcoind.rpc().prune_blockchain(500)?;
+    assert!(pruned > 0, "no blocks were pruned");
+
+    // bitcoind doesn't reply to `getdata` for pruned blocks, so they are fetched via RPC (and
+    // reported as missing, instead of waiting for them forever)
+    let electrs = Electrs::spawn(&bitcoind, &[])?;
+    let log = wait_for("missing block", || {
+        let log = electrs.log();
+        log.contains("is not available from bitcoind")
+            .then_some(log)
+    })?;
+    assert!(log.contains("fetching it via RPC"), "{}", log);
+    assert!(
+        log.contains("electrs requires a non-pruned node"),
+        "{}",
+        log
+    );
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_sync_progress() -> Result<()> {
+    // the initial sync can't complete, since the pruned blocks can't be indexed
+    let args = ["-prune=1", "-fastprune", "-whitelist=noban@127.0.0.1"];
+    let bitcoind = Bitcoind::start_with_args(&args)?;
+    bitcoind.mine(1000)?;
+    assert!(bitcoind.rpc().prune_blockchain(500)? > 0);
+    let electrs = Electrs::spawn(&bitcoind, &[])?;
+    wait_for("missing block", || {
+        electrs
+            .log()
+            .contains("is not available from bitcoind")
+            .then_some(())
+    })?;
+
+    // the responses are flagged with the synced headers' height
+    let mut client = electrs.connect()?;
+    let tip = client.call("blockchain.headers.subscribe", json!([]))?;
+    assert!(tip["height"].as_u64().unwrap() < 1000, "{}", tip);
+    assert_eq!(tip["syncing"], true);
+    assert_eq!(tip["target_height"], 1000);
+    let features = client.call("server.features", json!([]))?;
+    assert_eq!(features["syncing"], true);
+    assert_eq!(features["target_height"], 1000);
+
+    // the target follows bitcoind's new headers
+    bitcoind.mine(1)?;
+    let tip = wait_for("new target", || {
+        let mut client = electrs.connect().ok()?;
+        let tip = client
+            .call("blockchain.headers.subscribe", json!([]))
+            .ok()?;
+        (tip["target_height"] == 1001).then_some(tip)
+    })?;
+    assert_eq!(tip["syncing"], true);
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn test_compat_corpus() -> Result<()> {
+    let env = TestEnv::new()?;
+    let rpc = env.bitcoind.rpc();
+    let address = env.bitcoind.new_address()?;
+    let funded_txid = env.bitcoind.send(&address, Amount::from_sat(1_000_000))?;
+    let blockhash = env.mine(1)?[0];
+    let mempool_txid = env.bitcoind.send(&address, Amount::from_sat(50_000))?;
+    env.wait_mempool(&address, mempool_txid)?;
+
+    let block = rpc.get_block(&blockhash)?;
+    assert_eq!(block.txdata.len(), 2); // coinbase and funding transactions
+    let funded_pos = 1;
+    let funded_tx = &block.txdata[funded_pos];
+    assert_eq!(funded_tx.compute_txid(), funded_txid);
+    let mempool_tx = rpc.get_raw_transaction(&mempool_txid, None)?;
+    let vout = |tx: &Transaction| {
+        tx.output
+            .iter()
+            .position(|txo| txo.script_pubkey == address.script_pubkey())
+            .context("missing funding output")
+    };
+    let tip_height = env.bitcoind.height()?;
+    let prev_header = rpc.get_block_header(&rpc.get_block_hash(tip_height as u64 - 1)?)?;
+    let status = format!("{}:{}:{}:0:", funded_txid, tip_height, mempool_txid);
+
+    let vars = BTreeMap::from([
+        ("genesis_hash", json!(rpc.get_block_hash(0)?)),
+        ("tip_height", json!(tip_height)),
+        ("tip_header", json!(serialize_hex(&block.header))),
+        (
+            "headers_101_2",
+            json!(serialize_hex(&prev_header) + &serialize_hex(&block.header)),
+        ),
+        (
+            "relay_fee",
+            json!(rpc.get_network_info()?.relay_fee.to_btc()),
+        ),
+        ("scripthash", json!(scripthash(&address))),
+        ("status", json!(sha256::Hash::hash(status.as_bytes()))),
+        (
+            "unused_scripthash",
+            json!(scripthash(&env.bitcoind.new_address()?)),
+        ),
+        ("funded_txid", json!(funded_txid)),
+        ("funded_tx_hex", json!(serialize_hex(funded_tx))),
+        ("funded_pos", json!(funded_pos)),
+        ("funded_vout", json!(vout(funded_tx)?)),
+        ("coinbase_txid", json!(block.txdata[0].compute_txid())),
+        ("mempool_txid", json!(mempool_txid)),
+        ("mempool_vout", json!(vout(&mempool_tx)?)),
+        (
+            "mempool_fee",
+            json!(rpc.get_mempool_entry(&mempool_txid)?.fees.base.to_sat()),
+        ),
+    ]);
+
+    let cases = compat::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compat"))?;
+    assert!(!cases.is_empty());
+    let mut client = env.electrs.connect()?;
+    let failures: Vec<String> = cases
+        .iter()
+        .filter_map(|case| case.check(&mut client, &vars).err())
+        .map(|err| format!("{:#}", err))
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+    Ok(())
+}
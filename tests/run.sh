@@ -53,6 +53,7 @@ electrs \
   --db-dir=data/electrs \
   --daemon-dir=data/bitcoin \
   --network=regtest \
+  --merkle-cache \
   2> data/electrs/regtest-debug.log &
 ELECTRS_PID=$!
 tail_log data/electrs/regtest-debug.log | grep -m1 "serving Electrum RPC"
@@ -103,6 +104,36 @@ test "`$EL getaddresshistory $NEW_ADDR | jq -c .`" == "[{\"height\":111,\"tx_has
 echo " * getbalance"
 test "`$EL getbalance | jq -c .`" == '{"confirmed":"599.999","unmatured":"4950.001"}'
 
+echo " * get_history (with tx_pos)"
+rpc() {
+  echo '{"jsonrpc": "2.0", "method": "'$1'", "params": '$2', "id": 0}' | nc -q1 localhost 60401 | jq -c .result
+}
+scripthash() {
+  python3 -c "import hashlib, sys; print(hashlib.sha256(bytes.fromhex(sys.argv[1])).digest()[::-1].hex())" `$BTC validateaddress $1 | jq -r .scriptPubKey`
+}
+BLOCK=`$BTC getblock $($BTC getblockhash 111)`
+TX_POS=`echo "$BLOCK" | jq '.tx | index("'$TXID'")'`
+test "`rpc blockchain.scripthash.get_history '["'$(scripthash $NEW_ADDR)'", true]'`" == "[{\"height\":111,\"tx_hash\":\"$TXID\",\"tx_pos\":$TX_POS}]"
+test "`rpc blockchain.transaction.get_merkle '["'$TXID'", 111, '$TX_POS']' | jq .pos`" == "$TX_POS"
+COINBASE=`echo "$BLOCK" | jq -r '.tx[0]'`
+test "`rpc blockchain.scripthash.get_history '["'$(scripthash $MINING_ADDR)'", true]' | jq '.[] | select(.tx_hash == "'$COINBASE'") | .tx_pos'`" == "0"
+
+echo " * get_merkle (using merkle_cache)"
+MERKLE=`echo '{"jsonrpc": "2.0", "method": "blockchain.transaction.get_merkle", "params": ["'$TXID'", 111], "id": 0}' | nc -q1 localhost 60401 | jq -c .result`
+TXOUTPROOF=`$BTC gettxoutproof '["'$TXID'"]'`
+python3 - "$TXID" "$MERKLE" "$TXOUTPROOF" <<'PYEOF'
+import hashlib, json, sys
+txid, merkle, txoutproof = sys.argv[1], json.loads(sys.argv[2]), bytes.fromhex(sys.argv[3])
+sha256d = lambda data: hashlib.sha256(hashlib.sha256(data).digest()).digest()
+node, pos = bytes.fromhex(txid)[::-1], merkle["pos"]
+for branch in merkle["merkle"]:
+    branch = bytes.fromhex(branch)[::-1]
+    node = sha256d(branch + node if pos & 1 else node + branch)
+    pos >>= 1
+# gettxoutproof starts with the block header, containing the merkle root at offset 36
+assert node == txoutproof[36:68], "merkle root mismatch"
+PYEOF
+
 echo "Electrum `$EL stop`"  # disconnect wallet
 wait $ELECTRUM_PID
 